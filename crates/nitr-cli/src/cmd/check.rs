@@ -3,12 +3,19 @@
 use anyhow::Context as _;
 use nitr::{Config, Server};
 
+#[cfg(not(feature = "db"))]
+use anyhow::bail;
+
 /// Validates the whole application by performing a real build: config
 /// parsing, builtins resolution, Lua syntax, route conflicts, template and
 /// database wiring. Note: the configuration script runs once (its side
 /// effects, e.g. migrations, happen).
-pub(crate) async fn check(cfg: Config) -> anyhow::Result<()> {
+///
+/// With `explain`, the queries found under `[database] explain_paths` are
+/// then run through `EXPLAIN QUERY PLAN` against the configured database.
+pub(crate) async fn check(cfg: Config, explain: bool) -> anyhow::Result<()> {
     let workers = cfg.workers;
+    let database = cfg.database.clone();
     let cfg = Config { workers: 1, ..cfg };
     Server::builder()
         .config(cfg)
@@ -16,5 +23,61 @@ pub(crate) async fn check(cfg: Config) -> anyhow::Result<()> {
         .await
         .context("check failed")?;
     println!("ok: configuration and scripts load cleanly ({workers} worker(s) configured)");
+    if explain {
+        explain_queries(database.as_ref())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "db"))]
+fn explain_queries(_database: Option<&nitr::DatabaseConfig>) -> anyhow::Result<()> {
+    bail!(
+        "this build has no database support: rebuild with the `db` Cargo \
+         feature (or `all`) to use `nitr check --explain`"
+    )
+}
+
+/// Prints each query's plan and flags the full table scans. A query SQLite
+/// cannot prepare is reported, not fatal: it is usually a fragment the
+/// script completes at run time.
+#[cfg(feature = "db")]
+fn explain_queries(database: Option<&nitr::DatabaseConfig>) -> anyhow::Result<()> {
+    use nitr::stdlib::explain;
+
+    let db = database.context("--explain needs a `[database]` section")?;
+    if db.explain_paths.is_empty() {
+        anyhow::bail!(
+            "--explain has nothing to search: list the files or directories holding \
+             your queries in `explain_paths` under [database]"
+        );
+    }
+    let path = db
+        .sqlite_path()
+        .context("--explain reads SQLite query plans: it is not available with [database] url")?;
+    let explained = explain::explain_paths(path, &db.explain_paths)?;
+    let (mut scans, mut failed) = (0, 0);
+    for query in &explained {
+        println!("\n{}\n  {}", query.source, query.sql);
+        match &query.plan {
+            Ok(plan) => {
+                if explain::is_full_scan(plan) {
+                    scans += 1;
+                    println!("  full table scan:");
+                }
+                for line in plan {
+                    println!("    {line}");
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                println!("  cannot prepare: {err}");
+            }
+        }
+    }
+    println!(
+        "\nexplained {} quer{}: {scans} with a full table scan, {failed} could not be prepared",
+        explained.len(),
+        if explained.len() == 1 { "y" } else { "ies" }
+    );
     Ok(())
 }
@@ -0,0 +1,61 @@
+//! `nitr db`: database maintenance outside the server.
+
+use std::path::Path;
+
+use nitr::Config;
+
+#[cfg(feature = "db")]
+use anyhow::Context as _;
+#[cfg(not(feature = "db"))]
+use anyhow::bail;
+
+/// Rebuilds the database from a `[database.replica]` directory.
+///
+/// Writes to `to` (the configured database path when unset) and refuses to
+/// overwrite anything: a restore that clobbered the one good copy left
+/// would be the worst possible failure mode for a recovery tool.
+#[cfg(not(feature = "db"))]
+pub(crate) fn restore(
+    _cfg: &Config,
+    _from: &Path,
+    _at: Option<&str>,
+    _to: Option<&Path>,
+) -> anyhow::Result<()> {
+    bail!(
+        "this build has no database support: rebuild with the `db` Cargo \
+         feature (or `all`) to use `nitr db restore`"
+    )
+}
+
+#[cfg(feature = "db")]
+pub(crate) fn restore(
+    cfg: &Config,
+    from: &Path,
+    at: Option<&str>,
+    to: Option<&Path>,
+) -> anyhow::Result<()> {
+    let to = match to {
+        Some(path) => path.to_path_buf(),
+        None => cfg
+            .database
+            .as_ref()
+            .and_then(|db| db.sqlite_path().cloned())
+            .context(
+                "no SQLite database is configured; pass --to <path> or add a \
+                     `[database]` section",
+            )?,
+    };
+    let at = at.map(nitr::stdlib::replicate::parse_at).transpose()?;
+    let restored = nitr::stdlib::replicate::restore(from, &to, at)?;
+    println!(
+        "ok: restored {} from {} ({} WAL segment(s) replayed)",
+        to.display(),
+        restored.generation.display(),
+        restored.segments
+    );
+    println!(
+        "  the database reflects the state shipped at {}",
+        restored.as_of()
+    );
+    Ok(())
+}
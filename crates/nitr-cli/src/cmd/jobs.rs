@@ -0,0 +1,131 @@
+//! `nitr jobs`: inspect and repair the `nitr.jobs` queue outside the
+//! server.
+
+use nitr::Config;
+
+#[cfg(feature = "db")]
+use anyhow::Context as _;
+use anyhow::bail;
+
+/// Which jobs `retry` queues again.
+#[cfg_attr(not(feature = "db"), allow(dead_code))]
+pub(crate) enum Retry {
+    /// These dead jobs.
+    Ids(Vec<i64>),
+    /// Every dead job.
+    All,
+}
+
+#[cfg(not(feature = "db"))]
+pub(crate) fn list(_cfg: &Config, _state: Option<&str>, _limit: usize) -> anyhow::Result<()> {
+    no_db()
+}
+
+#[cfg(not(feature = "db"))]
+pub(crate) fn retry(_cfg: &Config, _which: Retry) -> anyhow::Result<()> {
+    no_db()
+}
+
+#[cfg(not(feature = "db"))]
+pub(crate) fn purge(_cfg: &Config, _state: &str) -> anyhow::Result<()> {
+    no_db()
+}
+
+#[cfg(not(feature = "db"))]
+fn no_db() -> anyhow::Result<()> {
+    bail!(
+        "this build has no database support: rebuild with the `db` Cargo \
+         feature (or `all`) to use `nitr jobs`"
+    )
+}
+
+#[cfg(feature = "db")]
+fn open(cfg: &Config) -> anyhow::Result<nitr::stdlib::Jobs> {
+    cfg.open_jobs()?
+        .context("no job queue is configured; set `[jobs] path` or a SQLite `[database] path`")
+}
+
+/// Prints the queue, oldest job first, optionally only one state.
+#[cfg(feature = "db")]
+pub(crate) fn list(cfg: &Config, state: Option<&str>, limit: usize) -> anyhow::Result<()> {
+    use nitr::stdlib::jobs::JobState;
+
+    let state = state.map(str::parse::<JobState>).transpose()?;
+    let jobs = open(cfg)?;
+    let rows = jobs.list(state, limit)?;
+    if rows.is_empty() {
+        println!("no jobs");
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64);
+    for job in &rows {
+        let when = match job.state {
+            JobState::Queued => format!("due {}", relative(job.run_at - now)),
+            JobState::Running => format!("lease ends {}", relative(job.run_at - now)),
+            JobState::Dead => "no attempts left".to_owned(),
+        };
+        println!(
+            "  {:<8} {:>6}  {}  attempt {}/{}, {when}",
+            job.state.as_str(),
+            job.id,
+            job.name,
+            job.attempts,
+            job.max_attempts,
+        );
+        if let Some(key) = &job.unique_key {
+            println!("           unique: {key}");
+        }
+        if let Some(error) = &job.last_error {
+            println!("           last error: {error}");
+        }
+    }
+    println!(
+        "{} queued, {} running, {} dead",
+        jobs.count(JobState::Queued)?,
+        jobs.count(JobState::Running)?,
+        jobs.count(JobState::Dead)?,
+    );
+    Ok(())
+}
+
+/// A signed offset from now in milliseconds, as "in 5s" or "3s ago".
+#[cfg(feature = "db")]
+fn relative(ms: i64) -> String {
+    let secs = ms.unsigned_abs().div_ceil(1000);
+    if ms >= 0 {
+        format!("in {secs}s")
+    } else {
+        format!("{secs}s ago")
+    }
+}
+
+/// Queues dead jobs again, due now and with their attempts reset.
+#[cfg(feature = "db")]
+pub(crate) fn retry(cfg: &Config, which: Retry) -> anyhow::Result<()> {
+    let ids = match which {
+        Retry::Ids(ids) if ids.is_empty() => {
+            bail!("name the dead jobs to retry, or pass --all to retry every one")
+        }
+        Retry::Ids(ids) => ids,
+        Retry::All => Vec::new(),
+    };
+    let retried = open(cfg)?.retry(&ids)?;
+    println!("ok: queued {retried} dead job(s) again");
+    if !ids.is_empty() && retried < ids.len() {
+        println!(
+            "  {} were not dead, or their unique key is held by a live job",
+            ids.len() - retried
+        );
+    }
+    Ok(())
+}
+
+/// Deletes every job in `state`.
+#[cfg(feature = "db")]
+pub(crate) fn purge(cfg: &Config, state: &str) -> anyhow::Result<()> {
+    let state = state.parse::<nitr::stdlib::jobs::JobState>()?;
+    let purged = open(cfg)?.purge(state)?;
+    println!("ok: deleted {purged} {} job(s)", state.as_str());
+    Ok(())
+}
@@ -0,0 +1,27 @@
+//! `nitr keys`: manage the `[keys] dir` ring outside the server.
+
+use nitr::Config;
+
+use anyhow::Context as _;
+
+/// Writes the next key to the `[keys] dir`, numbered past the ids of
+/// `[keys] env` too, which signs from the next reload on, and deletes all
+/// but the `keep` newest keys when asked.
+pub(crate) fn rotate(cfg: &Config, keep: Option<usize>) -> anyhow::Result<()> {
+    let dir = cfg.keys.dir.as_deref().context(
+        "no `[keys] dir` is configured: `nitr keys rotate` writes key files there \
+         (keys from `[keys] env` are rotated by whoever sets the variable)",
+    )?;
+    let id = nitr::stdlib::keys::rotate(dir, cfg.keys.env.as_deref())?;
+    println!(
+        "wrote key {id} to {}",
+        dir.join(format!("{id}.key")).display()
+    );
+    if let Some(keep) = keep {
+        for retired in nitr::stdlib::keys::retire(dir, keep)? {
+            println!("deleted key {retired}: what it signed no longer verifies");
+        }
+    }
+    println!("run `nitr reload` (or restart) so running servers sign with key {id}");
+    Ok(())
+}
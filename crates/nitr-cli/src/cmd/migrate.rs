@@ -13,7 +13,7 @@ use anyhow::bail;
 /// means a rolling deployment has two instances racing to change the same
 /// schema, each believing it is alone.
 #[cfg(not(feature = "db"))]
-pub(crate) fn migrate(_cfg: &Config, _status_only: bool) -> anyhow::Result<()> {
+pub(crate) async fn migrate(_cfg: &Config, _status_only: bool) -> anyhow::Result<()> {
     bail!(
         "this build has no database support: rebuild with the `db` Cargo \
          feature (or `all`) to use `nitr migrate`"
@@ -21,51 +21,128 @@ pub(crate) fn migrate(_cfg: &Config, _status_only: bool) -> anyhow::Result<()> {
 }
 
 #[cfg(feature = "db")]
-pub(crate) fn migrate(cfg: &Config, status_only: bool) -> anyhow::Result<()> {
+pub(crate) async fn migrate(cfg: &Config, status_only: bool) -> anyhow::Result<()> {
+    use nitr::stdlib::migrate::State;
+
     let db = cfg
         .database
         .as_ref()
         .context("no database is configured; add a `[database]` section to nitr.toml")?;
-    let dir = db.migrations().context(
-        "no migrations directory found (looked for `migrations/`; set \
-         [database] migrations_dir to point elsewhere)",
-    )?;
+    if let Some(url) = &db.url {
+        #[cfg(feature = "postgres")]
+        return migrate_postgres(url, db, status_only).await;
+        #[cfg(not(feature = "postgres"))]
+        anyhow::bail!(
+            "[database] url = {url:?} needs Postgres support: rebuild with the \
+             `postgres` Cargo feature"
+        );
+    }
+    let indexes = db.search_indexes();
+    let dir = db.migrations();
+    if dir.is_none() && indexes.is_empty() {
+        anyhow::bail!(
+            "no migrations directory found (looked for `migrations/`; set \
+             [database] migrations_dir to point elsewhere)"
+        );
+    }
     let conn = nitr::stdlib::db_open(&db.path, &db.pragmas())?;
 
     if status_only {
-        let entries = nitr::stdlib::migrate::status(&conn, &dir)?;
-        if entries.is_empty() {
-            println!("no migrations in {}", dir.display());
-            return Ok(());
-        }
-        for (migration, state) in &entries {
+        let entries = match &dir {
+            Some(dir) => nitr::stdlib::migrate::status(&conn, dir)?,
+            None => Vec::new(),
+        };
+        print_status(&entries, dir.as_deref());
+        // A changed index definition is simply rebuilt: unlike a schema
+        // change, there is nothing in it to lose.
+        for (name, state) in nitr::stdlib::search::status(&conn, &indexes)? {
             let label = match state {
-                nitr::stdlib::migrate::State::Applied => "applied",
-                nitr::stdlib::migrate::State::Pending => "pending",
-                nitr::stdlib::migrate::State::Modified => "MODIFIED SINCE APPLIED",
+                State::Applied => "built",
+                State::Pending => "pending",
+                State::Modified => "stale",
             };
-            println!("  {:<10} {}", label, migration.name);
+            println!("  {label:<10} search index {name}");
         }
-        let count = |wanted: nitr::stdlib::migrate::State| {
-            entries.iter().filter(|(_, state)| *state == wanted).count()
+        return Ok(());
+    }
+
+    if let Some(dir) = &dir {
+        print_applied(nitr::stdlib::migrate::run(&conn, dir)?);
+    }
+    // After the migrations: an index needs its content table to exist.
+    let changed = nitr::stdlib::search::apply(&conn, &indexes)?;
+    if !changed.is_empty() {
+        println!("ok: updated {} search index(es)", changed.len());
+        for line in changed {
+            println!("  {line}");
+        }
+    }
+    Ok(())
+}
+
+/// `nitr migrate` against `[database] url`: the same files, recorded in a
+/// Postgres `_nitr_migrations`.
+#[cfg(feature = "postgres")]
+async fn migrate_postgres(
+    url: &str,
+    db: &nitr::DatabaseConfig,
+    status_only: bool,
+) -> anyhow::Result<()> {
+    let dir = db.migrations().context(
+        "no migrations directory found (looked for `migrations/`; set \
+         [database] migrations_dir to point elsewhere)",
+    )?;
+    let pool = nitr::stdlib::PgPool::connect(url, 1)?;
+    if status_only {
+        print_status(&pool.migration_status(&dir).await?, Some(&dir));
+        return Ok(());
+    }
+    print_applied(pool.migrate(&dir).await?);
+    Ok(())
+}
+
+#[cfg(feature = "db")]
+fn print_status(
+    entries: &[(
+        nitr::stdlib::migrate::Migration,
+        nitr::stdlib::migrate::State,
+    )],
+    dir: Option<&std::path::Path>,
+) {
+    use nitr::stdlib::migrate::State;
+
+    if entries.is_empty() {
+        match dir {
+            Some(dir) => println!("no migrations in {}", dir.display()),
+            None => println!("no migrations directory"),
+        }
+    }
+    for (migration, state) in entries {
+        let label = match state {
+            State::Applied => "applied",
+            State::Pending => "pending",
+            State::Modified => "MODIFIED SINCE APPLIED",
         };
-        let modified = count(nitr::stdlib::migrate::State::Modified);
+        println!("  {:<10} {}", label, migration.name);
+    }
+    let count = |wanted: State| entries.iter().filter(|(_, state)| *state == wanted).count();
+    let modified = count(State::Modified);
+    if !entries.is_empty() {
         println!(
             "{} applied, {} pending, {modified} modified",
-            count(nitr::stdlib::migrate::State::Applied),
-            count(nitr::stdlib::migrate::State::Pending),
+            count(State::Applied),
+            count(State::Pending),
         );
-        if modified > 0 {
-            // Not a warning to skim past: the database and the repository
-            // disagree about what the schema is.
-            println!(
-                "a modified migration will not be re-run; restore the file or write a new one"
-            );
-        }
-        return Ok(());
     }
+    if modified > 0 {
+        // Not a warning to skim past: the database and the repository
+        // disagree about what the schema is.
+        println!("a modified migration will not be re-run; restore the file or write a new one");
+    }
+}
 
-    let applied = nitr::stdlib::migrate::run(&conn, &dir)?;
+#[cfg(feature = "db")]
+fn print_applied(applied: Vec<String>) {
     if applied.is_empty() {
         println!("ok: the schema is up to date");
     } else {
@@ -74,5 +151,4 @@ pub(crate) fn migrate(cfg: &Config, status_only: bool) -> anyhow::Result<()> {
             println!("  {name}");
         }
     }
-    Ok(())
 }
@@ -2,5 +2,9 @@
 //! argument parsing, configuration loading, and dispatch.
 
 pub(crate) mod check;
+pub(crate) mod db;
+pub(crate) mod jobs;
+pub(crate) mod keys;
 pub(crate) mod migrate;
+pub(crate) mod schedule;
 pub(crate) mod test;
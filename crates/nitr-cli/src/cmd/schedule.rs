@@ -0,0 +1,65 @@
+//! `nitr schedule`: list the app's scheduled tasks, or run one now.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context as _;
+use nitr::{Config, Server};
+
+/// Builds the application the way `nitr check` does: one state, the
+/// configuration script run once.
+async fn build(cfg: Config) -> anyhow::Result<Server> {
+    let cfg = Config { workers: 1, ..cfg };
+    Server::builder()
+        .config(cfg)
+        .build()
+        .await
+        .context("cannot load the application")
+}
+
+/// Prints every `app:every` / `app:cron` schedule and when it is next due.
+pub(crate) async fn list(cfg: Config) -> anyhow::Result<()> {
+    let schedules = build(cfg).await?.schedules();
+    if schedules.is_empty() {
+        println!("no schedules: register them with app:every(...) or app:cron(...)");
+        return Ok(());
+    }
+    let width = schedules.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let now = SystemTime::now();
+    for schedule in &schedules {
+        let next = match schedule.next_run {
+            Some(at) => format!("next {}", until(at.duration_since(now).unwrap_or_default())),
+            None => "never again".to_owned(),
+        };
+        let spec = if schedule.spec == schedule.name {
+            String::new()
+        } else {
+            format!("  ({})", schedule.spec)
+        };
+        println!("  {:<width$}  {next}{spec}", schedule.name);
+    }
+    Ok(())
+}
+
+/// Runs schedule `name` once, in this process, and reports how it went.
+pub(crate) async fn run_now(cfg: Config, name: &str) -> anyhow::Result<()> {
+    let server = build(cfg).await?;
+    let started = std::time::Instant::now();
+    server
+        .run_schedule(name)
+        .await
+        .with_context(|| format!("schedule `{name}` failed"))?;
+    println!("ok: ran `{name}` in {:?}", started.elapsed());
+    Ok(())
+}
+
+/// A delay from now, as "in 2h 5m" or "in 30s".
+fn until(d: Duration) -> String {
+    let secs = d.as_secs() + u64::from(d.subsec_nanos() > 0);
+    let (days, hours, mins) = (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60);
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("in {secs}s"),
+        (0, 0, _) => format!("in {mins}m {}s", secs % 60),
+        (0, _, _) => format!("in {hours}h {mins}m"),
+        _ => format!("in {days}d {hours}h"),
+    }
+}
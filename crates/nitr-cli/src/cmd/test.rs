@@ -55,7 +55,17 @@ pub(crate) async fn run_tests(cfg: Config, filter: Option<&str>) -> anyhow::Resu
     let builtins = cfg.builtins()?;
     let env = BuiltinsEnv {
         templates_dir: cfg.templating.dir.clone(),
-        database: cfg.database.as_ref().map(|db| db.path.clone()),
+        database: cfg
+            .database
+            .as_ref()
+            .and_then(|db| db.sqlite_path().cloned()),
+        #[cfg(feature = "postgres")]
+        postgres: cfg
+            .database
+            .as_ref()
+            .and_then(|db| db.url.as_deref().map(|url| (url, db.pool_size)))
+            .map(|(url, size)| nitr::stdlib::PgPool::connect(url, size))
+            .transpose()?,
         sqlite: cfg
             .database
             .as_ref()
@@ -63,9 +73,38 @@ pub(crate) async fn run_tests(cfg: Config, filter: Option<&str>) -> anyhow::Resu
             .unwrap_or_default(),
         fetch: cfg.fetch.options(),
         env: cfg.env_options(),
+        // The server's keys: a token a test signs must verify there.
+        keys: cfg.open_keys()?,
+        app_dir: Some(cfg.app_dir()),
         // Tests get their own cache: a test file must not see entries a
         // previous one left behind.
         cache: Some(nitr::stdlib::Cache::new(cfg.cache_options())),
+        // The store is durable, so unlike the cache it is the one the
+        // server sees: tests share it the way they share the database.
+        #[cfg(feature = "db")]
+        kv: if builtins.contains(nitr::Builtins::KV) {
+            cfg.open_kv()?
+        } else {
+            None
+        },
+        // Tests can enqueue; nothing runs the jobs.
+        #[cfg(feature = "db")]
+        jobs: if builtins.contains(nitr::Builtins::JOBS) {
+            cfg.open_jobs()?
+        } else {
+            None
+        },
+        #[cfg(feature = "db")]
+        changes: None,
+        // Like the cache, a hub per run of the tests.
+        pubsub: Some(nitr::stdlib::PubSub::new(cfg.pubsub.options())),
+        sse: Some(nitr::stdlib::SseHub::new(cfg.sse.options())),
+        slow_query: cfg.database.as_ref().and_then(|db| db.slow_query()),
+        search: cfg
+            .database
+            .as_ref()
+            .map(|db| db.search_indexes())
+            .unwrap_or_default(),
     };
     let opts = cfg.runtime_opts()?;
 
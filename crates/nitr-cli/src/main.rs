@@ -1,5 +1,5 @@
-//! The `nitr` binary: serve, develop, check, test, migrate, build, and
-//! scaffold Nitr applications.
+//! The `nitr` binary: serve, develop, check, test, migrate, restore, build,
+//! scaffold Nitr applications, and tend their job queues and schedules.
 
 #![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
 
@@ -51,6 +51,10 @@ enum Command {
         /// flag layering — the answer to "which value actually won?".
         #[arg(long)]
         print_config: bool,
+        /// Print the `EXPLAIN QUERY PLAN` of every query found under
+        /// `[database] explain_paths`, flagging full table scans.
+        #[arg(long)]
+        explain: bool,
     },
     /// Run the Lua tests against an in-process server.
     Test {
@@ -64,6 +68,30 @@ enum Command {
         #[arg(long)]
         status: bool,
     },
+    /// Database maintenance: restoring from a replica.
+    Db {
+        #[command(subcommand)]
+        action: DbCommand,
+    },
+    /// Background jobs: list the queue, retry dead jobs, purge.
+    Jobs {
+        #[command(subcommand)]
+        action: JobsCommand,
+    },
+    /// Signing keys: add a new one to the `[keys] dir`.
+    Keys {
+        #[command(subcommand)]
+        action: KeysCommand,
+    },
+    /// Scheduled tasks: list them, or run one now for testing.
+    Schedule {
+        /// List every schedule and when it is next due (the default).
+        #[arg(long, conflicts_with = "run_now")]
+        list: bool,
+        /// Run this schedule once, now, in this process.
+        #[arg(long, value_name = "NAME")]
+        run_now: Option<String>,
+    },
     /// Scaffold a new Nitr application.
     Init {
         /// Directory to scaffold into (default: the current directory).
@@ -83,6 +111,62 @@ enum Command {
     Reload,
 }
 
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Rebuild the database from a `[database.replica]` directory.
+    Restore {
+        /// The replica directory to restore from.
+        #[arg(long, value_name = "DIR")]
+        from: PathBuf,
+        /// Point in time to restore to: RFC 3339 or Unix seconds
+        /// (default: the latest state shipped).
+        #[arg(long, value_name = "TIMESTAMP")]
+        at: Option<String>,
+        /// Where to write the database (default: the configured
+        /// `[database] path`). Must not exist yet.
+        #[arg(long, value_name = "PATH")]
+        to: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum JobsCommand {
+    /// List jobs, oldest first, with the last error of each.
+    List {
+        /// Only jobs in this state: queued, running or dead.
+        #[arg(long, value_name = "STATE")]
+        state: Option<String>,
+        /// Show at most this many jobs.
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Queue dead jobs again, due now and with their attempts reset.
+    Retry {
+        /// The ids of the dead jobs to retry.
+        ids: Vec<i64>,
+        /// Retry every dead job.
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+    /// Delete every job in a state.
+    Purge {
+        /// The state to purge: dead, queued or running.
+        #[arg(long, value_name = "STATE", default_value = "dead")]
+        state: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Write a fresh key that signs from the next reload on; older keys
+    /// keep verifying until deleted.
+    Rotate {
+        /// Then delete all but this many newest keys.
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+        keep: Option<u16>,
+    },
+}
+
 fn load_config(cli: &Cli) -> anyhow::Result<Config> {
     // A bundled executable carries its own application; the config file
     // and every path in it come from the extracted archive.
@@ -275,12 +359,15 @@ async fn run_main() -> anyhow::Result<()> {
             drop(pidfile);
             result?;
         }
-        Command::Check { print_config } => {
+        Command::Check {
+            print_config,
+            explain,
+        } => {
             if print_config {
                 print!("{}", cfg.effective_toml()?);
                 return Ok(());
             }
-            cmd::check::check(cfg).await?;
+            cmd::check::check(cfg, explain).await?;
         }
         Command::Test { filter } => {
             let failures = cmd::test::run_tests(cfg, filter.as_deref()).await?;
@@ -288,7 +375,29 @@ async fn run_main() -> anyhow::Result<()> {
                 std::process::exit(1);
             }
         }
-        Command::Migrate { status } => cmd::migrate::migrate(&cfg, status)?,
+        Command::Migrate { status } => cmd::migrate::migrate(&cfg, status).await?,
+        Command::Db {
+            action: DbCommand::Restore { from, at, to },
+        } => cmd::db::restore(&cfg, &from, at.as_deref(), to.as_deref())?,
+        Command::Jobs { action } => match action {
+            JobsCommand::List { state, limit } => cmd::jobs::list(&cfg, state.as_deref(), limit)?,
+            JobsCommand::Retry { ids, all } => {
+                let which = if all {
+                    cmd::jobs::Retry::All
+                } else {
+                    cmd::jobs::Retry::Ids(ids)
+                };
+                cmd::jobs::retry(&cfg, which)?;
+            }
+            JobsCommand::Purge { state } => cmd::jobs::purge(&cfg, &state)?,
+        },
+        Command::Keys {
+            action: KeysCommand::Rotate { keep },
+        } => cmd::keys::rotate(&cfg, keep.map(usize::from))?,
+        Command::Schedule { list: _, run_now } => match run_now {
+            Some(name) => cmd::schedule::run_now(cfg, &name).await?,
+            None => cmd::schedule::list(cfg).await?,
+        },
         Command::Build { output } => {
             let cfg_path = cli
                 .config
@@ -49,14 +49,17 @@ fn registered_paths(lua: &mlua::Lua) -> BTreeSet<String> {
 /// whatever feature set is actually being tested.
 fn compiled_builtins() -> nitr::Builtins {
     #[allow(unused_mut)]
-    let mut builtins = nitr::Builtins::minimal() | nitr::Builtins::DEBUG | nitr::Builtins::CACHE;
+    let mut builtins = nitr::Builtins::minimal()
+        | nitr::Builtins::DEBUG
+        | nitr::Builtins::CACHE
+        | nitr::Builtins::PUBSUB;
     #[cfg(feature = "fetch")]
     {
         builtins |= nitr::Builtins::FETCH;
     }
     #[cfg(feature = "db")]
     {
-        builtins |= nitr::Builtins::DATABASE;
+        builtins |= nitr::Builtins::DATABASE | nitr::Builtins::KV | nitr::Builtins::JOBS;
     }
     #[cfg(feature = "template")]
     {
@@ -66,9 +69,25 @@ fn compiled_builtins() -> nitr::Builtins {
     {
         builtins |= nitr::Builtins::CRYPTO;
     }
+    #[cfg(feature = "webauthn")]
+    {
+        builtins |= nitr::Builtins::WEBAUTHN;
+    }
     builtins
 }
 
+/// A one-key ring, so `nitr.keys` is registered and checked too.
+fn key_ring() -> nitr::stdlib::KeyRing {
+    let dir = std::env::temp_dir().join(format!("nitr-api-keys-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    nitr::stdlib::keys::rotate(&dir, None).expect("write a key");
+    nitr::stdlib::KeyRing::load(nitr::stdlib::KeySource {
+        dir: Some(dir),
+        env: None,
+    })
+    .expect("load the key ring")
+}
+
 #[test]
 fn every_registered_entry_is_described() {
     let api = apidef::parse().expect("parse nitr-api.toml");
@@ -81,6 +100,18 @@ fn every_registered_entry_is_described() {
     let env = nitr::BuiltinsEnv {
         templates_dir: Some(std::env::temp_dir()),
         database: Some(db.clone()),
+        pubsub: Some(nitr::stdlib::PubSub::new(Default::default())),
+        #[cfg(feature = "db")]
+        kv: Some(
+            nitr::stdlib::Kv::open(&db, &Default::default(), std::time::Duration::ZERO)
+                .expect("open the kv store"),
+        ),
+        #[cfg(feature = "db")]
+        jobs: Some(
+            nitr::stdlib::Jobs::open(&db, &Default::default(), Default::default())
+                .expect("open the job queue"),
+        ),
+        keys: Some(key_ring()),
         ..Default::default()
     };
     nitr::stdlib::register_builtins(&lua, compiled_builtins(), &env)
@@ -90,9 +121,10 @@ fn every_registered_entry_is_described() {
         .into_iter()
         .filter(|path| !known.contains(path))
         .collect();
-    // The state (and its SQLite connection) must close before the file is
-    // removed, or the delete races the WAL checkpoint.
+    // The state and the kv store (and their SQLite connections) must close
+    // before the file is removed, or the delete races the WAL checkpoint.
     drop(lua);
+    drop(env);
     for suffix in ["", "-wal", "-shm"] {
         std::fs::remove_file(std::path::Path::new(&format!("{}{suffix}", db.display()))).ok();
     }
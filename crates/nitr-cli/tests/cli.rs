@@ -1,5 +1,6 @@
 //! End-to-end tests for the `nitr` binary: version, effective-config
-//! printing, `nitr build` artifacts, and pidfile-based reload.
+//! printing, `nitr build` artifacts, `nitr keys rotate`, and pidfile-based
+//! reload.
 
 use std::path::PathBuf;
 use std::process::Command;
@@ -163,6 +164,66 @@ fn renamed_env_variables_fail_with_the_new_name() {
     std::fs::remove_dir_all(&dir).ok();
 }
 
+/// `nitr keys rotate` numbers key files upwards, keeps them private, and
+/// `--keep` deletes the oldest.
+#[test]
+fn keys_rotate_adds_keys_and_retires_the_oldest() {
+    require_runnable_binary!();
+    let dir = scaffold("keys", true);
+    let rotate = |extra: &[&str]| {
+        let out = nitr()
+            .current_dir(&dir)
+            .env("NITR_KEYS_DIR", "keys")
+            .args(["keys", "rotate"])
+            .args(extra)
+            .output()
+            .expect("run keys rotate");
+        assert!(
+            out.status.success(),
+            "rotate failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        String::from_utf8_lossy(&out.stdout).to_string()
+    };
+    assert!(rotate(&[]).contains("wrote key 1"));
+    assert!(rotate(&[]).contains("wrote key 2"));
+    let out = rotate(&["--keep", "2"]);
+    assert!(out.contains("wrote key 3"), "got: {out}");
+    assert!(out.contains("deleted key 1"), "got: {out}");
+
+    let mut files: Vec<String> = std::fs::read_dir(dir.join("keys"))
+        .expect("keys dir")
+        .map(|entry| {
+            entry
+                .expect("entry")
+                .file_name()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    files.sort();
+    assert_eq!(files, ["2.key", "3.key"]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        let mode = std::fs::metadata(dir.join("keys/3.key"))
+            .expect("key file")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o077, 0, "key files must be owner-only");
+    }
+
+    // Without a directory there is nowhere to write.
+    let out = nitr()
+        .current_dir(&dir)
+        .args(["keys", "rotate"])
+        .output()
+        .expect("run keys rotate");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("[keys] dir"));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
 /// The sectioned override follows the `NITR_<SECTION>_<OPTION>` scheme and
 /// lands in `[templating] dir`.
 #[test]
@@ -328,6 +389,49 @@ fn scaffolded_app_tests_pass_and_filter() {
     std::fs::remove_dir_all(&dir).ok();
 }
 
+/// `nitr check --explain` finds the scaffold's queries in its route
+/// modules and prints the plans SQLite chose for them.
+#[cfg(all(feature = "db", feature = "template"))]
+#[test]
+fn check_explain_prints_query_plans() {
+    require_runnable_binary!();
+    let dir = scaffold("explain", false);
+    let migrate = nitr()
+        .current_dir(&dir)
+        .arg("migrate")
+        .output()
+        .expect("run migrate");
+    assert!(migrate.status.success());
+
+    let toml = dir.join("nitr.toml");
+    let cfg = std::fs::read_to_string(&toml).expect("read nitr.toml");
+    let cfg = cfg.replacen(
+        "path = \"data/app.db\"",
+        "path = \"data/app.db\"\nexplain_paths = [\"routes\"]",
+        1,
+    );
+    std::fs::write(&toml, cfg).expect("write nitr.toml");
+
+    let out = nitr()
+        .current_dir(&dir)
+        .args(["check", "--explain"])
+        .output()
+        .expect("run check --explain");
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success(),
+        "check failed: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(stdout.contains("notes.lua:8"), "got: {stdout}");
+    assert!(stdout.contains("SCAN notes"), "got: {stdout}");
+    assert!(
+        stdout.contains("explained 3 queries: 2 with a full table scan, 0 could not be prepared"),
+        "got: {stdout}"
+    );
+    std::fs::remove_dir_all(&dir).ok();
+}
+
 #[cfg(unix)]
 #[test]
 fn pidfile_reload_and_cleanup() {
@@ -0,0 +1,375 @@
+//! Client-IP allow/deny lists: the server-wide `[access]` rules plus the
+//! `access` option of static mounts and routes.
+//!
+//! A list is a sequence of `allow`/`deny` rules over CIDR ranges; the
+//! first rule matching the client decides, and a client no rule matches
+//! is allowed. Every level a request falls under must allow it. Mount and
+//! route lists are looked up in a Rust-side copy of the app's route table,
+//! so a denied request is answered before any Lua state is checked out.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use hyper::Method;
+use matchit::Router;
+
+use crate::config::AccessConfig;
+use crate::static_files::StaticMount;
+use nitr_core::{Error, Result};
+
+/// An address range: `addr` with the leading `prefix` bits significant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses `10.0.0.0/8`, `2001:db8::/32`, or a bare address (a single
+    /// host). Bits set past the prefix are refused rather than masked: a
+    /// typo there usually means the range is not the one intended.
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("`{addr}` is not an IP address"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("`/{prefix}` is not a prefix length from 0 to {max}"))?,
+        };
+        let cidr = Self { addr, prefix };
+        let network = cidr.network();
+        if network != addr {
+            return Err(format!(
+                "`{text}` has bits set past the /{prefix} prefix (did you mean {network}/{prefix}?)"
+            ));
+        }
+        Ok(cidr)
+    }
+
+    /// The address with every bit past the prefix cleared.
+    fn network(&self) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(addr) => IpAddr::V4(mask_v4(u32::from(addr), self.prefix).into()),
+            IpAddr::V6(addr) => IpAddr::V6(mask_v6(u128::from(addr), self.prefix).into()),
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask_v4(u32::from(net) ^ u32::from(ip), self.prefix) == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask_v6(u128::from(net) ^ u128::from(ip), self.prefix) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    match prefix {
+        0 => 0,
+        p => bits & (u32::MAX << (32 - u32::from(p))),
+    }
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => bits & (u128::MAX << (128 - u32::from(p))),
+    }
+}
+
+/// One rule; `net: None` is `all`.
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+    allow: bool,
+    net: Option<Cidr>,
+}
+
+impl Rule {
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let (Some(action), Some(target), None) = (words.next(), words.next(), words.next()) else {
+            return Err(format!(
+                "access rule `{text}` must be `allow <cidr>`, `deny <cidr>`, `allow all` or `deny all`"
+            ));
+        };
+        let allow = match action {
+            "allow" => true,
+            "deny" => false,
+            other => {
+                return Err(format!(
+                    "access rule `{text}` starts with `{other}`: expected `allow` or `deny`"
+                ));
+            }
+        };
+        let net = match target {
+            "all" => None,
+            cidr => Some(Cidr::parse(cidr).map_err(|err| format!("access rule `{text}`: {err}"))?),
+        };
+        Ok(Self { allow, net })
+    }
+}
+
+/// An ordered allow/deny list.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AccessList(Vec<Rule>);
+
+impl AccessList {
+    /// Parses rules in the `allow <cidr>` / `deny <cidr>` syntax; the
+    /// error names the offending rule.
+    pub(crate) fn parse<S: AsRef<str>>(rules: &[S]) -> std::result::Result<Self, String> {
+        rules
+            .iter()
+            .map(|rule| Rule::parse(rule.as_ref().trim()))
+            .collect::<std::result::Result<_, _>>()
+            .map(Self)
+    }
+
+    /// The `[access]` list: the rules in `file` (read now) followed by
+    /// `rules`.
+    pub(crate) fn load(cfg: &AccessConfig) -> Result<Self> {
+        let mut list = match &cfg.file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|err| {
+                    Error::Config(format!("[access] file {}: {err}", path.display()))
+                })?;
+                let lines: Vec<&str> = text
+                    .lines()
+                    .map(|line| line.split_once('#').map_or(line, |(rule, _)| rule).trim())
+                    .filter(|line| !line.is_empty())
+                    .collect();
+                Self::parse(&lines).map_err(|err| {
+                    Error::Config(format!("[access] file {}: {err}", path.display()))
+                })?
+            }
+            None => Self::default(),
+        };
+        let rules = Self::parse(&cfg.rules)
+            .map_err(|err| Error::Config(format!("[access] rules: {err}")))?;
+        list.0.extend(rules.0);
+        Ok(list)
+    }
+
+    /// Whether `ip` gets through: the first matching rule decides, and no
+    /// match allows. An IPv4 client seen through a dual-stack socket
+    /// (`::ffff:a.b.c.d`) is matched as the IPv4 address it is.
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0
+            .iter()
+            .find(|rule| rule.net.is_none_or(|net| net.contains(ip)))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+/// Route patterns mapped to the access list of each method that has one.
+pub(crate) type RouteAccess = Router<HashMap<Method, Arc<AccessList>>>;
+
+/// The mount and route lists of one compiled app. Every state compiles the
+/// same script, so one copy serves the whole pool.
+#[derive(Debug, Default)]
+pub(crate) struct AppAccess {
+    /// Every route pattern, including those without a list: a path a
+    /// route matches never falls through to a static mount.
+    routes: Option<RouteAccess>,
+    /// The static mounts, longest first.
+    mounts: Vec<StaticMount>,
+}
+
+impl AppAccess {
+    /// Keeps the tables only when some route or mount carries a list, so
+    /// an app without any costs nothing per request.
+    pub(crate) fn new(routes: RouteAccess, route_lists: bool, mounts: &[StaticMount]) -> Self {
+        if !route_lists && mounts.iter().all(|m| m.access.is_none()) {
+            return Self::default();
+        }
+        let mut mounts = mounts.to_vec();
+        mounts.sort_by_key(|m| std::cmp::Reverse(m.mount.len()));
+        Self {
+            routes: Some(routes),
+            mounts,
+        }
+    }
+
+    /// The list the request falls under: its route's, or when no route
+    /// matches, that of the longest mount covering the path (the mount
+    /// `try_serve` tries first).
+    fn list(&self, method: &Method, path: &str) -> Option<&AccessList> {
+        if let Some(routes) = &self.routes
+            && let Ok(matched) = routes.at(path)
+        {
+            // `HEAD` is served by a `GET` route, so it answers to its list.
+            let list = matched.value.get(method).or_else(|| {
+                (*method == Method::HEAD)
+                    .then(|| matched.value.get(&Method::GET))
+                    .flatten()
+            });
+            return list.map(|list| &**list);
+        }
+        if self.mounts.is_empty() {
+            return None;
+        }
+        let decoded = percent_encoding::percent_decode_str(path)
+            .decode_utf8()
+            .ok()?;
+        self.mounts
+            .iter()
+            .find(|m| m.relative(&decoded).is_some())
+            .and_then(|m| m.access.as_deref())
+    }
+}
+
+/// The lists in force: the server-wide one and the current app's.
+#[derive(Debug, Default)]
+pub(crate) struct Access {
+    pub(crate) server: Arc<AccessList>,
+    pub(crate) app: Arc<AppAccess>,
+}
+
+impl Access {
+    /// Whether a request for `method path` from `ip` passes every list it
+    /// falls under.
+    pub(crate) fn allows(&self, method: &Method, path: &str, ip: IpAddr) -> bool {
+        self.server.allows(ip)
+            && self
+                .app
+                .list(method, path)
+                .is_none_or(|list| list.allows(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(rules: &[&str]) -> AccessList {
+        AccessList::parse(rules).expect("valid rules")
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().expect("ip")
+    }
+
+    #[test]
+    fn ranges_match_by_prefix_in_both_families() {
+        let office = list(&["allow 10.8.0.0/16", "allow 2001:db8::/32", "deny all"]);
+        assert!(office.allows(ip("10.8.255.1")));
+        assert!(!office.allows(ip("10.9.0.1")));
+        assert!(office.allows(ip("2001:db8:1::7")));
+        assert!(!office.allows(ip("2001:db9::1")));
+        // A dual-stack socket reports IPv4 clients as mapped addresses.
+        assert!(office.allows(ip("::ffff:10.8.0.3")));
+        // A bare address is one host; /0 is everyone in its family.
+        let host = list(&["deny 203.0.113.7", "deny ::/0"]);
+        assert!(!host.allows(ip("203.0.113.7")));
+        assert!(host.allows(ip("203.0.113.8")));
+        assert!(!host.allows(ip("::1")));
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides_and_no_match_allows() {
+        let rules = list(&[
+            "deny 203.0.113.0/24",
+            "allow 203.0.113.9",
+            "deny 198.51.100.0/24",
+        ]);
+        assert!(
+            !rules.allows(ip("203.0.113.9")),
+            "the earlier deny wins over the later allow"
+        );
+        assert!(!rules.allows(ip("198.51.100.1")));
+        assert!(rules.allows(ip("192.0.2.1")), "no rule matches");
+        assert!(AccessList::default().allows(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn malformed_rules_are_refused_with_the_rule_named() {
+        for bad in [
+            "permit 10.0.0.0/8",
+            "allow",
+            "allow 10.0.0.0/8 extra",
+            "deny 10.0.0.0/33",
+            "deny 300.0.0.1",
+            "deny fe80::/129",
+        ] {
+            let err = AccessList::parse(&[bad]).expect_err(bad);
+            assert!(err.contains(bad), "{err}");
+        }
+        let err = AccessList::parse(&["deny 10.1.2.3/8"]).expect_err("host bits");
+        assert!(err.contains("did you mean 10.0.0.0/8"), "{err}");
+    }
+
+    #[test]
+    fn the_rules_file_comes_first_and_ignores_comments() {
+        let path = std::env::temp_dir().join(format!("nitr-access-{}.rules", std::process::id()));
+        std::fs::write(
+            &path,
+            "# abusive range\ndeny 198.51.100.0/24  # since May\n\n",
+        )
+        .expect("write the rules file");
+        let cfg = AccessConfig {
+            rules: vec!["allow 198.51.100.0/24".into(), "deny all".into()],
+            file: Some(path.clone()),
+        };
+        let rules = AccessList::load(&cfg).expect("load");
+        assert!(
+            !rules.allows(ip("198.51.100.4")),
+            "the file's deny is first"
+        );
+        assert!(!rules.allows(ip("192.0.2.1")));
+
+        std::fs::write(&path, "allow bogus\n").expect("rewrite the rules file");
+        let err = AccessList::load(&cfg).expect_err("bad file").to_string();
+        assert!(
+            err.contains(".rules") && err.contains("allow bogus"),
+            "{err}"
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn routes_shadow_mounts_and_head_follows_get() {
+        let admin = Arc::new(list(&["allow 10.0.0.0/8", "deny all"]));
+        let mut routes = Router::new();
+        routes
+            .insert(
+                "/admin/{*rest}",
+                HashMap::from([(Method::GET, admin.clone())]),
+            )
+            .expect("admin route");
+        routes
+            .insert("/assets/app.js", HashMap::new())
+            .expect("asset route");
+        let mut assets = StaticMount::new("/assets", "public", false, None);
+        assets.access = Some(admin.clone());
+        let app = AppAccess::new(routes, true, &[assets]);
+        let access = Access {
+            server: Arc::new(list(&["deny 192.0.2.66"])),
+            app: Arc::new(app),
+        };
+        let outside = ip("192.0.2.1");
+        assert!(!access.allows(&Method::GET, "/admin/users", outside));
+        assert!(!access.allows(&Method::HEAD, "/admin/users", outside));
+        assert!(access.allows(&Method::POST, "/admin/users", outside));
+        assert!(access.allows(&Method::GET, "/admin/users", ip("10.1.1.1")));
+        // The mount's list covers what no route matches.
+        assert!(!access.allows(&Method::GET, "/assets/logo.png", outside));
+        assert!(access.allows(&Method::GET, "/assets/app.js", outside));
+        // The server-wide list applies everywhere.
+        assert!(!access.allows(&Method::GET, "/elsewhere", ip("192.0.2.66")));
+        assert!(access.allows(&Method::GET, "/elsewhere", outside));
+    }
+}
@@ -16,6 +16,9 @@ use std::sync::Arc;
 
 use nitr_core::{Error, Result, Runtime};
 
+use crate::access::{AccessList, AppAccess, RouteAccess};
+use crate::schedule::{Schedule, When};
+
 /// Named registry slot holding each state's compiled [`AppState`].
 const APP_STATE_KEY: &str = "nitr::app_state";
 
@@ -52,6 +55,13 @@ struct RouteDef {
     /// A per-route error handler (`{ on_error = fn }` options), overriding
     /// the app-wide `app:on_error`.
     error_fn: Option<Function>,
+    /// The trailing options table as given, handed to every middleware
+    /// factory composed for this route (`{ scopes = {...} }` for
+    /// `nitr.auth.jwt`, say).
+    options: Option<mlua::Table>,
+    /// Client-IP rules from the `access` option, enforced in Rust before
+    /// the request reaches a state.
+    access: Option<Arc<AccessList>>,
     /// Where the script registered this route (`source`, `line`), captured
     /// at registration so a duplicate can name both sites.
     site: Option<(String, u32)>,
@@ -82,6 +92,11 @@ struct AppDef {
     routes: Vec<RouteDef>,
     error_fn: Option<Function>,
     statics: Vec<crate::static_files::StaticMount>,
+    /// Background job handlers by name (`app:job(name, fn)`).
+    jobs: HashMap<String, Function>,
+    /// Scheduled tasks (`app:every(...)`, `app:cron(...)`), in
+    /// registration order.
+    schedules: Vec<(Schedule, Function)>,
 }
 
 /// The `nitr.app()` userdata handed to the handler script.
@@ -96,13 +111,17 @@ impl UserData for LuaApp {
                 // `middleware..., handler` optionally followed by an options
                 // table: `app:get(path, handler, { on_error = fn })`.
                 move |lua, this, (path, mut args): (String, Variadic<Value>)| {
-                    let error_fn = match args.last() {
+                    let (error_fn, access, options) = match args.last() {
                         Some(Value::Table(opts)) => {
-                            let error_fn = opts.get::<Option<Function>>("on_error")?;
+                            let opts = opts.clone();
                             args.pop();
-                            error_fn
+                            (
+                                opts.get::<Option<Function>>("on_error")?,
+                                access_option(&opts, || format!("app:{name}(\"{path}\", ...)"))?,
+                                Some(opts),
+                            )
                         }
-                        _ => None,
+                        _ => (None, None, None),
                     };
                     let fns: Vec<Function> = args
                         .into_iter()
@@ -131,6 +150,8 @@ impl UserData for LuaApp {
                         path,
                         fns,
                         error_fn,
+                        options,
+                        access,
                         site,
                     });
                     Ok(())
@@ -156,32 +177,149 @@ impl UserData for LuaApp {
             Ok(())
         });
 
+        // app:job(name, fn): the handler the job workers call for jobs
+        // enqueued under `name`, as fn(payload, job).
+        methods.add_method("job", |_, this, (name, f): (String, Function)| {
+            let mut def = lock(&this.0)?;
+            if def.jobs.contains_key(&name) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "app:job(\"{name}\", ...) is already registered"
+                )));
+            }
+            def.jobs.insert(name, f);
+            Ok(())
+        });
+
+        // app:every(interval, fn, opts?) and app:cron(expr, fn, opts?):
+        // fn(run) on a schedule; opts is { name = "...", jitter = "30s" }.
+        methods.add_method(
+            "every",
+            |_, this, (interval, f, opts): (Value, Function, Option<mlua::Table>)| {
+                let (spec, interval) = match interval {
+                    Value::String(spec) => {
+                        let spec = spec.to_str()?.to_string();
+                        let interval = crate::schedule::parse_interval(&spec).map_err(|err| {
+                            mlua::Error::RuntimeError(format!("app:every: {err}"))
+                        })?;
+                        (spec, interval)
+                    }
+                    Value::Integer(_) | Value::Number(_) => {
+                        let secs = interval.as_f64().unwrap_or_default();
+                        let interval = crate::schedule::seconds(secs).ok_or_else(|| {
+                            mlua::Error::RuntimeError(format!(
+                                "app:every: the interval must be positive, got {secs}"
+                            ))
+                        })?;
+                        (format!("{secs}s"), interval)
+                    }
+                    other => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "app:every: the interval must be a string like \"5m\" or a \
+                             number of seconds, got {}",
+                            other.type_name()
+                        )));
+                    }
+                };
+                add_schedule(this, "every", spec, When::Every(interval), f, opts)
+            },
+        );
+        methods.add_method(
+            "cron",
+            |_, this, (expr, f, opts): (String, Function, Option<mlua::Table>)| {
+                let cron = crate::schedule::Cron::parse(&expr)
+                    .map_err(|err| mlua::Error::RuntimeError(format!("app:cron: {err}")))?;
+                add_schedule(this, "cron", expr, When::Cron(Box::new(cron)), f, opts)
+            },
+        );
+
         // app:static(mount, dir, opts?): served entirely in Rust; opts is
-        // an optional table { spa = bool, cache_control = "..." }.
+        // an optional table { spa = bool, cache_control = "...",
+        // access = { "allow 10.0.0.0/8", "deny all" } }.
         methods.add_method(
             "static",
             |_, this, (mount, dir, opts): (String, String, Option<mlua::Table>)| {
-                let (spa, cache_control) = match opts {
+                let (spa, cache_control, access) = match opts {
                     Some(opts) => (
                         opts.get::<Option<bool>>("spa")?.unwrap_or(false),
                         opts.get::<Option<String>>("cache_control")?,
+                        access_option(&opts, || format!("app:static(\"{mount}\", ...)"))?,
                     ),
-                    None => (false, None),
+                    None => (false, None, None),
                 };
-                lock(&this.0)?
-                    .statics
-                    .push(crate::static_files::StaticMount::new(
-                        mount,
-                        dir,
-                        spa,
-                        cache_control,
-                    ));
+                let mut static_mount =
+                    crate::static_files::StaticMount::new(mount, dir, spa, cache_control);
+                static_mount.access = access;
+                lock(&this.0)?.statics.push(static_mount);
                 Ok(())
             },
         );
     }
 }
 
+/// Parses the `access` option of a route or static mount; `what` names the
+/// registration in the error.
+fn access_option(
+    opts: &mlua::Table,
+    what: impl FnOnce() -> String,
+) -> mlua::Result<Option<Arc<AccessList>>> {
+    let Some(rules) = opts.get::<Option<Vec<String>>>("access")? else {
+        return Ok(None);
+    };
+    AccessList::parse(&rules)
+        .map(|list| Some(Arc::new(list)))
+        .map_err(|err| mlua::Error::RuntimeError(format!("{}: {err}", what())))
+}
+
+/// Registers one schedule, named by its `name` option or its spec.
+fn add_schedule(
+    app: &LuaApp,
+    method: &str,
+    spec: String,
+    when: When,
+    f: Function,
+    opts: Option<mlua::Table>,
+) -> mlua::Result<()> {
+    let (name, jitter) = match &opts {
+        Some(opts) => (
+            opts.get::<Option<String>>("name")?,
+            opts.get::<Option<Value>>("jitter")?,
+        ),
+        None => (None, None),
+    };
+    let jitter = match jitter {
+        None => std::time::Duration::ZERO,
+        Some(Value::String(jitter)) => crate::schedule::parse_interval(&jitter.to_str()?)
+            .map_err(|err| mlua::Error::RuntimeError(format!("app:{method}: jitter: {err}")))?,
+        Some(jitter) => jitter
+            .as_f64()
+            .and_then(crate::schedule::seconds)
+            .ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "app:{method}: jitter must be an interval like \"30s\" or a positive \
+                     number of seconds"
+                ))
+            })?,
+    };
+    let name = name.unwrap_or_else(|| spec.clone());
+    let mut def = lock(&app.0)?;
+    if def.schedules.iter().any(|(s, _)| *s.name == *name) {
+        return Err(mlua::Error::RuntimeError(format!(
+            "app:{method}: a schedule named \"{name}\" is already registered \
+             (give one a {{ name = \"...\" }})"
+        )));
+    }
+    def.schedules.push((
+        Schedule {
+            name: name.into(),
+            spec: spec.into(),
+            when,
+            jitter,
+        },
+        f,
+    ));
+    Ok(())
+}
+
 /// The compiled dispatch target of a Lua state: the `nitr.app()` returned
 /// by the handler script — requests are routed in Rust and only matching
 /// ones reach the composed Lua chains.
@@ -194,6 +332,8 @@ pub(crate) struct Dispatch(pub(crate) Box<CompiledApp>);
 pub(crate) struct Chain {
     pub(crate) fns: Function,
     pub(crate) error_fn: Option<Function>,
+    /// The route pattern as registered, for the request span's `route`.
+    pub(crate) route: Arc<str>,
 }
 
 pub(crate) struct CompiledApp {
@@ -212,11 +352,53 @@ pub(crate) fn script_path(lua: &Lua) -> Option<std::path::PathBuf> {
         .and_then(|ud| ud.borrow::<AppState>().ok().map(|s| s.script.clone()))
 }
 
+/// The handler the compiled app in this state registered for job `name`.
+#[cfg_attr(not(feature = "db"), allow(dead_code))]
+pub(crate) fn job_handler(lua: &Lua, name: &str) -> Result<Option<Function>> {
+    let state = state(lua)?;
+    let state = state.borrow::<AppState>()?;
+    Ok(state.jobs.get(name).cloned())
+}
+
+/// The route and mount access lists of the compiled app in this state.
+pub(crate) fn access(lua: &Lua) -> Result<Arc<AppAccess>> {
+    let state = state(lua)?;
+    let state = state.borrow::<AppState>()?;
+    Ok(state.access.clone())
+}
+
+/// The schedules the compiled app in this state registered.
+pub(crate) fn schedules(lua: &Lua) -> Result<Vec<Schedule>> {
+    let state = state(lua)?;
+    let state = state.borrow::<AppState>()?;
+    Ok(state.schedules.iter().map(|(s, _)| s.clone()).collect())
+}
+
+/// The function the compiled app in this state registered for schedule
+/// `name`.
+pub(crate) fn schedule_handler(lua: &Lua, name: &str) -> Result<Option<Function>> {
+    let state = state(lua)?;
+    let state = state.borrow::<AppState>()?;
+    Ok(state
+        .schedules
+        .iter()
+        .find(|(s, _)| &*s.name == name)
+        .map(|(_, f)| f.clone()))
+}
+
 pub(crate) struct AppState {
     pub(crate) dispatch: Dispatch,
     /// Static mounts: the script's `app:static(...)` calls first, then the
     /// server-level `[static]` configuration.
     pub(crate) statics: Arc<Vec<crate::static_files::StaticMount>>,
+    /// Background job handlers, looked up by the job workers.
+    #[cfg_attr(not(feature = "db"), allow(dead_code))]
+    pub(crate) jobs: HashMap<String, Function>,
+    /// Scheduled tasks, read by the scheduler.
+    schedules: Vec<(Schedule, Function)>,
+    /// The route and mount access lists, shared with the server so they
+    /// are enforced before checkout.
+    access: Arc<AppAccess>,
     script: PathBuf,
 }
 
@@ -242,11 +424,16 @@ pub(crate) fn load(
     base_statics: &[crate::static_files::StaticMount],
 ) -> Result<()> {
     let value = rt.eval_script(script)?;
-    let (dispatch, mut statics) = compile(value, script)?;
+    let (dispatch, mut statics, jobs, schedules, (routes, route_lists)) =
+        compile(rt.lua(), value, script)?;
     statics.extend_from_slice(base_statics);
+    let access = Arc::new(AppAccess::new(routes, route_lists, &statics));
     let state = rt.lua().create_userdata(AppState {
         dispatch,
         statics: Arc::new(statics),
+        jobs,
+        schedules,
+        access,
         script: script.to_path_buf(),
     })?;
     rt.lua().set_named_registry_value(APP_STATE_KEY, state)?;
@@ -262,9 +449,16 @@ pub(crate) fn state(lua: &Lua) -> Result<AnyUserData> {
 /// Compiles the script's return value into a [`Dispatch`]: middleware
 /// factories are invoked once here (never per request), and the route set
 /// is validated so conflicts fail at startup instead of at request time.
-type Compiled = (Dispatch, Vec<crate::static_files::StaticMount>);
-
-fn compile(value: Value, script: &Path) -> Result<Compiled> {
+type Compiled = (
+    Dispatch,
+    Vec<crate::static_files::StaticMount>,
+    HashMap<String, Function>,
+    Vec<(Schedule, Function)>,
+    // The route table's access lists, and whether any route has one.
+    (RouteAccess, bool),
+);
+
+fn compile(lua: &Lua, value: Value, script: &Path) -> Result<Compiled> {
     let app_ud = match value {
         Value::UserData(ud) if ud.is::<LuaApp>() => ud,
         // Plain-function handlers (the pre-`nitr.app()` style) are gone:
@@ -279,9 +473,13 @@ fn compile(value: Value, script: &Path) -> Result<Compiled> {
     };
     let app = app_ud.borrow::<LuaApp>()?;
     let def = lock(&app.0)?;
-    if def.routes.is_empty() && def.statics.is_empty() {
+    if def.routes.is_empty()
+        && def.statics.is_empty()
+        && def.jobs.is_empty()
+        && def.schedules.is_empty()
+    {
         return Err(Error::Script(format!(
-            "the app returned by {} defines no routes or static mounts",
+            "the app returned by {} defines no routes, static mounts, jobs or schedules",
             script.display()
         )));
     }
@@ -294,8 +492,9 @@ fn compile(value: Value, script: &Path) -> Result<Compiled> {
     for route in &def.routes {
         let idx = chains.len();
         chains.push(Chain {
-            fns: compose(&def.middleware, route)?,
+            fns: compose(lua, &def.middleware, route)?,
             error_fn: route.error_fn.clone().or_else(|| def.error_fn.clone()),
+            route: route.path.as_str().into(),
         });
         let pattern = to_matchit(&route.path)?;
         let slot = match index.get(&pattern) {
@@ -321,8 +520,18 @@ fn compile(value: Value, script: &Path) -> Result<Compiled> {
         }
     }
 
+    // A copy of the route table carrying only the access lists, so the
+    // server can enforce them without a state.
+    let mut access = Router::new();
     let mut router = Router::new();
     for (pattern, methods) in patterns {
+        let lists: HashMap<Method, Arc<AccessList>> = methods
+            .iter()
+            .filter_map(|(method, &idx)| Some((method.clone(), def.routes[idx].access.clone()?)))
+            .collect();
+        // The same pattern set as the router below, so it cannot conflict
+        // where that one does not; the router's insert reports any error.
+        let _ = access.insert(&pattern, lists);
         router.insert(&pattern, methods).map_err(|err| {
             Error::Script(format!(
                 "invalid or conflicting route pattern `{pattern}` in {}: {err}",
@@ -334,12 +543,18 @@ fn compile(value: Value, script: &Path) -> Result<Compiled> {
     Ok((
         Dispatch(Box::new(CompiledApp { router, chains })),
         def.statics.clone(),
+        def.jobs.clone(),
+        def.schedules.clone(),
+        (access, def.routes.iter().any(|r| r.access.is_some())),
     ))
 }
 
 /// Composes `global middleware → route middleware → handler` into a single
-/// function by calling each middleware factory with its `next` link.
-fn compose(global: &[Function], route: &RouteDef) -> Result<Function> {
+/// function by calling each middleware factory with its `next` link and the
+/// route it wraps: `{ method, path, options }`, where `options` is the
+/// route's trailing options table (empty when it has none). Factories that
+/// only take `next` ignore the second argument.
+fn compose(lua: &Lua, global: &[Function], route: &RouteDef) -> Result<Function> {
     // Invariant: route registration refuses an empty function list, so a
     // compiled route always carries at least its handler.
     #[allow(clippy::expect_used)]
@@ -347,9 +562,17 @@ fn compose(global: &[Function], route: &RouteDef) -> Result<Function> {
         .fns
         .split_last()
         .expect("route registration requires at least a handler");
+    let info = lua.create_table()?;
+    info.set("method", route.method.as_str())?;
+    info.set("path", route.path.as_str())?;
+    let options = match &route.options {
+        Some(options) => options.clone(),
+        None => lua.create_table()?,
+    };
+    info.set("options", options)?;
     let mut chain = handler.clone();
     for mw in mws.iter().rev().chain(global.iter().rev()) {
-        chain = mw.call::<Function>(chain).map_err(|err| {
+        chain = mw.call::<Function>((chain, &info)).map_err(|err| {
             Error::Script(format!(
                 "middleware for route `{} {}` must return a function: {err}",
                 route.method, route.path
@@ -5,7 +5,7 @@ use std::path::{Path, PathBuf};
 
 use nitr_core::{Error, Result};
 
-use super::{Config, DatabaseConfig, LogFormat};
+use super::{Config, DatabaseConfig, LogFormat, RedisConfig};
 
 impl Config {
     /// Loads the configured dotenv-style file into the process environment.
@@ -45,11 +45,12 @@ impl Config {
     /// `NITR_HANDLER_SCRIPT`, `NITR_CONFIG_SCRIPT`, `NITR_WORKERS`,
     /// `NITR_MAX_STREAMS`, `NITR_DEV_MODE`, `NITR_PIDFILE`. A sectioned
     /// option is named `NITR_<SECTION>_<OPTION>`: `NITR_DATABASE_PATH`,
-    /// `NITR_TEMPLATING_DIR`,
-    /// `NITR_TESTING_DIR`, `NITR_ENV_FILE`, `NITR_LUA_MEMORY_LIMIT`,
-    /// `NITR_LUA_EXEC_TIMEOUT_MS`, `NITR_LIMITS_POOL_WAIT_MS`,
-    /// `NITR_SHUTDOWN_GRACE`, `NITR_COMPRESSION_ENABLED`,
-    /// `NITR_LOG_FORMAT`, `NITR_LOG_LEVEL`.
+    /// `NITR_DATABASE_URL`, `NITR_KV_PATH`, `NITR_JOBS_PATH`,
+    /// `NITR_SCHEDULE_ENABLED`, `NITR_SCHEDULE_PATH`,
+    /// `NITR_CACHE_REDIS_URL`, `NITR_TEMPLATING_DIR`, `NITR_TESTING_DIR`,
+    /// `NITR_ENV_FILE`, `NITR_KEYS_DIR`, `NITR_LUA_MEMORY_LIMIT`, `NITR_LUA_EXEC_TIMEOUT_MS`,
+    /// `NITR_LIMITS_POOL_WAIT_MS`, `NITR_SHUTDOWN_GRACE`,
+    /// `NITR_COMPRESSION_ENABLED`, `NITR_LOG_FORMAT`, `NITR_LOG_LEVEL`.
     pub fn apply_env(&mut self) -> Result {
         // Superseded names are refused with the rename spelled out:
         // silently ignoring them would turn a stale deployment manifest
@@ -85,6 +86,39 @@ impl Config {
                 None => self.database = Some(DatabaseConfig::new(v)),
             }
         }
+        if let Some(v) = env_var("NITR_DATABASE_URL") {
+            // Where the password usually lives. A URL switches the
+            // backend, so it replaces a configured SQLite path.
+            match &mut self.database {
+                Some(db) => {
+                    db.url = Some(v);
+                    db.path = PathBuf::new();
+                }
+                None => self.database = Some(DatabaseConfig::postgres(v)),
+            }
+        }
+        if let Some(v) = env_var("NITR_KV_PATH") {
+            self.kv.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("NITR_JOBS_PATH") {
+            self.jobs.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("NITR_SCHEDULE_ENABLED") {
+            self.schedule.enabled = parse_env("NITR_SCHEDULE_ENABLED", &v)?;
+        }
+        if let Some(v) = env_var("NITR_SCHEDULE_PATH") {
+            self.schedule.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("NITR_CACHE_REDIS_URL") {
+            // The password lives here too; the backend stays as configured.
+            match &mut self.cache.redis {
+                Some(redis) => redis.url = v,
+                None => self.cache.redis = Some(RedisConfig::new(v)),
+            }
+        }
+        if let Some(v) = env_var("NITR_KEYS_DIR") {
+            self.keys.dir = Some(PathBuf::from(v));
+        }
         if let Some(v) = env_var("NITR_TESTING_DIR") {
             self.testing.dir = PathBuf::from(v);
         }
@@ -1,6 +1,7 @@
 //! The per-section configuration structs of `nitr.toml` and their
 //! defaults; everything except `[database]`, which has its own module.
 
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -292,6 +293,9 @@ pub struct StaticConfig {
     pub spa: bool,
     /// `Cache-Control` header value for served files.
     pub cache_control: Option<String>,
+    /// Client-IP rules for the mount, in the `[access]` syntax; checked
+    /// after the server-wide ones.
+    pub access: Vec<String>,
 }
 
 /// Template rendering (`[templating]` section) for the `template`
@@ -339,6 +343,27 @@ pub struct EnvConfig {
     pub allow: Option<Vec<String>>,
 }
 
+/// The versioned signing and sealing keys (`[keys]` section), exposed as
+/// `nitr.keys`. Unset leaves scripts with their own secret strings.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Directory of `<id>.key` files, each one base64url key; the highest
+    /// id signs. `nitr keys rotate` adds the next one. Like the database,
+    /// it resolves against the working directory, not a bundle.
+    pub dir: Option<PathBuf>,
+    /// Environment variable holding keys as `id:key` pairs separated by
+    /// commas, for deployments that inject secrets that way.
+    pub env: Option<String>,
+}
+
+impl KeysConfig {
+    /// Whether any key source is configured.
+    pub fn is_set(&self) -> bool {
+        self.dir.is_some() || self.env.is_some()
+    }
+}
+
 /// The shared `nitr.cache` (`[cache]` section).
 ///
 /// Bounded and owned by Rust, so it is shared *data* rather than shared
@@ -347,6 +372,9 @@ pub struct EnvConfig {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct CacheConfig {
+    /// Where entries live: in this process, or on the Redis server named
+    /// by `[cache.redis]`, shared by every process pointed at it.
+    pub backend: CacheBackend,
     /// Maximum number of live entries; the least recently used is evicted
     /// past this.
     pub max_entries: usize,
@@ -355,18 +383,341 @@ pub struct CacheConfig {
     /// Seconds an entry lives when `set` does not say. `0` means no
     /// expiry, leaving eviction entirely to the size bounds.
     pub default_ttl: u64,
+    /// Snapshot file: written on graceful shutdown and read back at
+    /// startup, so a deploy does not start cold. A corrupt or oversized
+    /// snapshot is ignored with a warning.
+    pub persist: Option<PathBuf>,
+    /// Seconds between snapshots while serving, so a crash loses at most
+    /// this much. `0` writes on shutdown only.
+    pub persist_interval: u64,
+    /// The server behind `backend = "redis"` (`[cache.redis]`).
+    pub redis: Option<RedisConfig>,
 }
 
 impl Default for CacheConfig {
     fn default() -> Self {
         Self {
+            backend: CacheBackend::Memory,
             max_entries: 10_000,
             max_bytes: 32 * 1024 * 1024, // 32 MiB
             default_ttl: 300,
+            persist: None,
+            persist_interval: 0,
+            redis: None,
+        }
+    }
+}
+
+/// Where `nitr.cache` keeps its entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    /// In this process, bounded by `max_entries` and `max_bytes` (the
+    /// default).
+    #[default]
+    Memory,
+    /// On a Redis or Valkey server, shared by every process using it. The
+    /// server's own `maxmemory` policy bounds it; `max_bytes` still caps
+    /// one value.
+    Redis,
+}
+
+/// The Redis server behind the cache (`[cache.redis]` section).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisConfig {
+    /// `redis://[[user]:password@]host[:port][/db]`. Connections are
+    /// unencrypted (no TLS), so reach the server over a private network.
+    pub url: String,
+    /// Most connections open at once, shared by every worker.
+    #[serde(default = "default_redis_pool_size")]
+    pub pool_size: usize,
+    /// Milliseconds opening a connection may take.
+    #[serde(default = "default_redis_connect_timeout")]
+    pub connect_timeout_ms: u64,
+    /// Milliseconds one cache call may take, waiting for a pooled
+    /// connection included. A cache slower than this is worse than none.
+    #[serde(default = "default_redis_timeout")]
+    pub timeout_ms: u64,
+    /// Prepended to every key, so applications can share a server.
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+    /// While the server is unreachable: `"miss"` reads miss and skips
+    /// `set`, so requests run uncached; `"error"` raises from every call.
+    /// Removals and counters raise either way.
+    #[serde(default)]
+    pub fallback: RedisFallback,
+}
+
+/// What an unreachable Redis server means to scripts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisFallback {
+    /// Reads miss and `set` is skipped (the default).
+    #[default]
+    Miss,
+    /// Every cache call raises.
+    Error,
+}
+
+fn default_redis_pool_size() -> usize {
+    16
+}
+fn default_redis_connect_timeout() -> u64 {
+    1_000
+}
+fn default_redis_timeout() -> u64 {
+    250
+}
+fn default_redis_prefix() -> String {
+    "nitr:".into()
+}
+
+impl RedisConfig {
+    /// The defaults for a given URL.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            pool_size: default_redis_pool_size(),
+            connect_timeout_ms: default_redis_connect_timeout(),
+            timeout_ms: default_redis_timeout(),
+            prefix: default_redis_prefix(),
+            fallback: RedisFallback::Miss,
+        }
+    }
+
+    /// The options handed to the cache.
+    pub fn options(&self) -> nitr_std::RedisOptions {
+        nitr_std::RedisOptions {
+            url: self.url.clone(),
+            pool_size: self.pool_size.max(1),
+            connect_timeout: std::time::Duration::from_millis(self.connect_timeout_ms),
+            timeout: std::time::Duration::from_millis(self.timeout_ms),
+            prefix: self.prefix.clone(),
+            fallback: match self.fallback {
+                RedisFallback::Miss => nitr_std::CacheFallback::Miss,
+                RedisFallback::Error => nitr_std::CacheFallback::Error,
+            },
+        }
+    }
+}
+
+/// The durable `nitr.kv` store (`[kv]` section).
+///
+/// Unlike `nitr.cache` it survives restarts: the keys live in a Nitr-owned
+/// `_nitr_kv` table, by default inside the `[database]` file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KvConfig {
+    /// SQLite file holding the store. Unset uses the `[database]` file, so
+    /// an application on Postgres names one here.
+    pub path: Option<PathBuf>,
+    /// Milliseconds between sweeps of expired keys. Expired keys read as
+    /// absent either way; the sweep only reclaims their space. `0` never
+    /// sweeps.
+    pub sweep_interval_ms: u64,
+}
+
+impl Default for KvConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            sweep_interval_ms: 60_000,
+        }
+    }
+}
+
+/// The in-process `nitr.pubsub` hub (`[pubsub]` section).
+///
+/// Publishing never waits on a subscriber: each has a buffer of `buffer`
+/// messages, and one that falls further behind is handled by `on_full`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PubSubConfig {
+    /// Messages buffered per subscriber.
+    pub buffer: usize,
+    /// A subscriber with a full buffer: `"drop"` skips the message for it
+    /// and reports the gap, `"disconnect"` ends its subscription. A
+    /// subscription may choose for itself.
+    pub on_full: SlowConsumer,
+    /// Most subscriptions open at once; past this `subscribe` raises.
+    pub max_subscribers: usize,
+    /// The largest message, in bytes of JSON.
+    pub max_message_bytes: usize,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            buffer: 256,
+            on_full: SlowConsumer::Drop,
+            max_subscribers: 10_000,
+            max_message_bytes: 64 * 1024, // 64 KiB
+        }
+    }
+}
+
+impl PubSubConfig {
+    /// The hub's options.
+    pub fn options(&self) -> nitr_std::PubSubOptions {
+        nitr_std::PubSubOptions {
+            buffer: self.buffer.max(1),
+            on_full: match self.on_full {
+                SlowConsumer::Drop => nitr_std::SlowSubscriber::Drop,
+                SlowConsumer::Disconnect => nitr_std::SlowSubscriber::Disconnect,
+            },
+            max_subscribers: self.max_subscribers,
+            max_message_bytes: self.max_message_bytes,
+        }
+    }
+}
+
+/// Server-Sent Event channels (`[sse]` section): the streams
+/// `nitr.sse.channel` hands to Rust, which hold no Lua state and do not
+/// count against `max_streams`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SseConfig {
+    /// Events each channel keeps, replayed to a client reconnecting with
+    /// `Last-Event-ID`. `0` keeps none.
+    pub history: usize,
+    /// Events a client may fall behind before it is disconnected (it
+    /// reconnects and resumes from the history).
+    pub buffer: usize,
+    /// Milliseconds without an event before a keepalive comment is sent,
+    /// so proxies do not close an idle stream.
+    pub heartbeat_ms: u64,
+    /// Most channels held at once; past this, the longest-idle channel
+    /// without clients (and its history) is forgotten.
+    pub max_channels: usize,
+    /// Most clients connected at once, across every channel.
+    pub max_subscribers: usize,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        Self {
+            history: 100,
+            buffer: 64,
+            heartbeat_ms: 15_000,
+            max_channels: 1_000,
+            max_subscribers: 10_000,
+        }
+    }
+}
+
+impl SseConfig {
+    /// The channel hub's options.
+    pub fn options(&self) -> nitr_std::SseOptions {
+        nitr_std::SseOptions {
+            history: self.history,
+            buffer: self.buffer.max(1),
+            heartbeat: std::time::Duration::from_millis(self.heartbeat_ms.max(1)),
+            max_channels: self.max_channels.max(1),
+            max_subscribers: self.max_subscribers,
         }
     }
 }
 
+/// The durable `nitr.jobs` queue and the workers that run it (`[jobs]`
+/// section).
+///
+/// Jobs live in a Nitr-owned `_nitr_jobs` table, by default inside the
+/// `[database]` file, and run on pooled states like requests — under the
+/// same `[lua]` limits — but never on more than `workers` of them at once.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// SQLite file holding the queue. Unset uses the `[database]` file, so
+    /// an application on Postgres names one here.
+    pub path: Option<PathBuf>,
+    /// Jobs running at once in this process. Unset uses a quarter of the
+    /// pool (at least 1); it must stay below the pool size, so requests
+    /// always have a state jobs cannot take.
+    pub workers: Option<usize>,
+    /// Milliseconds between checks for due jobs. A job enqueued by this
+    /// process to run now starts without waiting for one.
+    pub poll_ms: u64,
+    /// Attempts a job gets before it is dead-lettered, unless enqueued
+    /// with its own `max_attempts`.
+    pub max_attempts: u32,
+    /// Milliseconds before the first retry; each further retry doubles it.
+    pub backoff_ms: u64,
+    /// The longest wait between two attempts, in milliseconds.
+    pub max_backoff_ms: u64,
+    /// Milliseconds a claimed job belongs to its worker. A job still
+    /// unfinished after this (its process died) is run again, so it must
+    /// exceed `[lua] exec_timeout_ms`.
+    pub lease_ms: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            workers: None,
+            poll_ms: 1_000,
+            max_attempts: 5,
+            backoff_ms: 1_000,
+            max_backoff_ms: 3_600_000,
+            lease_ms: 300_000,
+        }
+    }
+}
+
+impl JobsConfig {
+    /// The queue's options.
+    #[cfg(feature = "db")]
+    pub fn options(&self) -> nitr_std::JobsOptions {
+        nitr_std::JobsOptions {
+            max_attempts: self.max_attempts.max(1),
+            backoff: std::time::Duration::from_millis(self.backoff_ms),
+            max_backoff: std::time::Duration::from_millis(self.max_backoff_ms),
+            lease: std::time::Duration::from_millis(self.lease_ms),
+        }
+    }
+}
+
+/// Where the app's scheduled tasks run (`[schedule]` section).
+///
+/// `app:every(...)` and `app:cron(...)` schedules run in every server
+/// process unless a database file is shared: then a lease in a Nitr-owned
+/// `_nitr_leases` table picks the one process that runs them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Whether this process runs schedules at all; `false` leaves them to
+    /// other instances (`nitr schedule --run-now` still works).
+    pub enabled: bool,
+    /// SQLite file holding the lease. Unset uses the `[database]` file;
+    /// with neither, every process runs every schedule.
+    pub path: Option<PathBuf>,
+    /// Milliseconds the lease lasts without renewal: how long schedules
+    /// pause when the process holding it dies.
+    pub lease_ms: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            lease_ms: 30_000,
+        }
+    }
+}
+
+/// What `nitr.pubsub` does with a subscriber whose buffer is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumer {
+    /// Skip the message for that subscriber (the default).
+    #[default]
+    Drop,
+    /// End its subscription.
+    Disconnect,
+}
+
 /// Response compression (`[compression]` section).
 ///
 /// Off by default: compression turns a CPU-cheap server into a
@@ -431,6 +782,80 @@ pub struct CorsConfig {
     pub max_age: Option<u64>,
 }
 
+/// Security response headers (`[headers]` section).
+///
+/// Applied in Rust to every response — Lua, static files, and the 404s,
+/// 405s and errors Nitr answers itself — but not to the health probes. A
+/// header the response already carries is left alone, so a route can
+/// still set its own. Off until `enabled`; an empty string drops one of
+/// the defaults.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// Whether the headers are sent at all.
+    pub enabled: bool,
+    /// `Strict-Transport-Security`. Browsers ignore it over plain HTTP.
+    pub hsts: String,
+    /// `X-Content-Type-Options`.
+    pub content_type_options: String,
+    /// `X-Frame-Options`.
+    pub frame_options: String,
+    /// `Referrer-Policy`.
+    pub referrer_policy: String,
+    /// `Permissions-Policy`; unset by default.
+    pub permissions_policy: String,
+    /// `Content-Security-Policy`; unset by default. Each `{nonce}` becomes
+    /// a fresh per-request nonce, also readable as `req.csp_nonce` and as
+    /// `csp_nonce` in templates: `script-src 'self' 'nonce-{nonce}'`.
+    pub csp: String,
+    /// Further headers, by name.
+    pub extra: BTreeMap<String, String>,
+    /// Overrides for the paths under a prefix (`[[headers.mount]]`); the
+    /// longest matching prefix wins.
+    pub mount: Vec<HeadersMount>,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hsts: "max-age=31536000".into(),
+            content_type_options: "nosniff".into(),
+            frame_options: "DENY".into(),
+            referrer_policy: "strict-origin-when-cross-origin".into(),
+            permissions_policy: String::new(),
+            csp: String::new(),
+            extra: BTreeMap::new(),
+            mount: Vec::new(),
+        }
+    }
+}
+
+/// The `[headers]` values that differ under one path prefix
+/// (`[[headers.mount]]`). Unset fields inherit; an empty string drops the
+/// header there.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersMount {
+    /// The path prefix, matched on whole segments (`/docs` covers
+    /// `/docs/a`, not `/docsearch`).
+    pub path: String,
+    /// `Strict-Transport-Security`.
+    pub hsts: Option<String>,
+    /// `X-Content-Type-Options`.
+    pub content_type_options: Option<String>,
+    /// `X-Frame-Options`.
+    pub frame_options: Option<String>,
+    /// `Referrer-Policy`.
+    pub referrer_policy: Option<String>,
+    /// `Permissions-Policy`.
+    pub permissions_policy: Option<String>,
+    /// `Content-Security-Policy`, with the same `{nonce}` placeholder.
+    pub csp: Option<String>,
+    /// Further headers, merged over the section's own `extra`.
+    pub extra: BTreeMap<String, String>,
+}
+
 /// Per-client-IP fixed-window rate limiting (`[rate_limit]` section).
 /// Disabled by default; rejections answer 429 with a `Retry-After` header.
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -458,6 +883,25 @@ impl Default for RateLimitConfig {
     }
 }
 
+/// Client-IP allow/deny rules (`[access]` section).
+///
+/// Each rule is `allow <cidr>` or `deny <cidr>` (a bare address is a
+/// single host; `all` matches everyone), checked in order: the first match
+/// decides, and a client no rule matches is allowed. Denied requests get a
+/// fixed 403 before any Lua state is used. With `[rate_limit]
+/// trust_forwarded_for` the client address is the last `X-Forwarded-For`
+/// entry, the one the proxy appended.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// The rules, in order.
+    pub rules: Vec<String>,
+    /// A file of further rules, one per line (`#` starts a comment),
+    /// checked before `rules` and re-read on `SIGHUP`. Like the database,
+    /// it resolves against the working directory, not a bundle.
+    pub file: Option<PathBuf>,
+}
+
 /// Lua runtime settings (`[lua]` section).
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
@@ -29,6 +29,7 @@ enum Target {
         chain: Function,
         params: Vec<(String, String)>,
         error_fn: Option<Function>,
+        route: Arc<str>,
     },
     NotFound,
     /// An `OPTIONS` on a known path with no `options` route: answered with
@@ -38,7 +39,7 @@ enum Target {
 }
 
 /// Serves one request: protection checks, dispatch, and the `X-Request-ID`
-/// echo on every response.
+/// echo and `[headers]` policy on every response.
 ///
 /// The whole call is wrapped in a panic boundary: a panic in Rust code
 /// (Nitr's or an extension module's) becomes a 500 and recycles the Lua
@@ -55,7 +56,7 @@ enum Target {
 /// profile comment in the workspace `Cargo.toml`.
 pub(crate) async fn handle(
     pool: &RuntimePool,
-    req: LuaRequest,
+    mut req: LuaRequest,
     streams: Arc<Semaphore>,
     protection: Arc<Protection>,
 ) -> Result<HttpResponse> {
@@ -64,6 +65,13 @@ pub(crate) async fn handle(
     // Kept for the response phase, which runs after the request has been
     // moved into the Lua state.
     let head = RequestHead::of(&req);
+    // Chosen before dispatch: the handler and its templates need the
+    // nonce the CSP will name.
+    let policy = protection
+        .headers()
+        .map(|headers| headers.policy(req.req.uri().path()));
+    req.csp_nonce = policy.and_then(|policy| policy.nonce());
+    let nonce = req.csp_nonce.clone();
 
     let served = AssertUnwindSafe(handle_inner(pool, req, streams, protection.clone()))
         .catch_unwind()
@@ -88,6 +96,9 @@ pub(crate) async fn handle(
     if let Some(cors) = protection.cors() {
         cors.apply(&head.headers, resp.headers_mut());
     }
+    if let Some(policy) = policy {
+        policy.apply(nonce.as_deref(), resp.headers_mut());
+    }
     let encoding = protection.compression().negotiate(head.accept_encoding());
     resp = protection.compression().apply(resp, encoding);
     // Last: HEAD is defined as GET with the body removed, so it must see
@@ -192,6 +203,7 @@ async fn handle_inner(
     // calls can carry a `traceparent` derived from this request's id.
     nitr_std::reset_outbound_budget(rt.lua());
     nitr_std::set_trace_context(rt.lua(), &req.id);
+    nitr_std::set_csp_nonce(rt.lua(), req.csp_nonce.as_deref());
 
     let target = match resolve(&rt, &req, protection.compression()).await {
         Ok(target) => target,
@@ -223,7 +235,9 @@ async fn handle_inner(
             chain,
             params,
             error_fn,
+            route,
         } => {
+            tracing::Span::current().record("route", &*route);
             req.params = params;
             // Read before the request moves into Lua: the dev error page
             // honors `Accept` (a curl user does not want markup).
@@ -326,7 +340,8 @@ fn discard_body(req_ud: &AnyUserData) {
 
 /// Completes a successful handler call: a function body becomes a
 /// streaming response (moving the runtime into the producer task, subject
-/// to the `max_streams` cap); anything else converts as a static response.
+/// to the `max_streams` cap), a `nitr.sse.channel` body a channel stream;
+/// anything else converts as a static response.
 fn finish(
     rt: RuntimeGuard,
     lua_resp: LuaTable,
@@ -353,6 +368,18 @@ fn finish(
                 }
             }
         }
+        // `nitr.sse.channel`: the state goes back now, and Rust serves the
+        // stream outside `max_streams`.
+        Ok(LuaValue::UserData(body)) if body.is::<nitr_std::sse::ChannelRequest>() => {
+            discard_body(req_ud);
+            match stream::channel_response(rt, &lua_resp, &body) {
+                Ok(resp) => Ok(resp),
+                Err(err) => {
+                    tracing::error!("invalid channel response: {err}");
+                    error_response(&err, dev_mode)
+                }
+            }
+        }
         Ok(_) => {
             discard_body(req_ud);
             match to_response(lua_resp) {
@@ -405,6 +432,7 @@ async fn resolve(
                         // Resolved at compile time: the route's own handler
                         // first, the app-wide one as fallback.
                         error_fn: app.chains[idx].error_fn.clone(),
+                        route: app.chains[idx].route.clone(),
                     },
                     None if *method == Method::OPTIONS => {
                         Target::Options(matched.value.keys().cloned().collect())
@@ -0,0 +1,367 @@
+//! Security response headers, applied in Rust.
+//!
+//! The same reasoning as CORS: a Lua middleware only sees the requests
+//! that reach Lua, so static files and the 404s, 405s and errors Nitr
+//! answers itself would go out bare. Applied here, the policy covers
+//! every response but the health probes, which answer before a request
+//! is even dispatched.
+
+use hyper::HeaderMap;
+use hyper::header::{self, HeaderName, HeaderValue};
+
+use crate::config::{HeadersConfig, HeadersMount};
+use nitr_core::{Error, Result};
+
+/// The placeholder a CSP carries where the per-request nonce goes.
+const NONCE: &str = "{nonce}";
+
+/// Random bytes in a CSP nonce; the CSP spec asks for at least 128 bits.
+const NONCE_BYTES: usize = 16;
+
+/// The compiled `[headers]` policy. Absent when the section is disabled.
+#[derive(Debug)]
+pub(crate) struct SecurityHeaders {
+    base: Policy,
+    /// `[[headers.mount]]` prefixes with their merged policies, longest
+    /// prefix first so the first match is the most specific.
+    mounts: Vec<(String, Policy)>,
+}
+
+/// The headers sent under one path prefix.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Policy {
+    fixed: Vec<(HeaderName, HeaderValue)>,
+    /// The CSP, its `{nonce}` placeholders still in place.
+    csp: Option<String>,
+}
+
+/// One mount's values over the section's own, as `[headers]` strings:
+/// empty means the header is not sent.
+#[derive(Debug, Clone)]
+struct Values {
+    named: [(HeaderName, String); 5],
+    csp: String,
+    extra: std::collections::BTreeMap<String, String>,
+}
+
+impl Values {
+    fn of(cfg: &HeadersConfig) -> Self {
+        Self {
+            named: [
+                (header::STRICT_TRANSPORT_SECURITY, cfg.hsts.clone()),
+                (
+                    header::X_CONTENT_TYPE_OPTIONS,
+                    cfg.content_type_options.clone(),
+                ),
+                (header::X_FRAME_OPTIONS, cfg.frame_options.clone()),
+                (header::REFERRER_POLICY, cfg.referrer_policy.clone()),
+                (
+                    HeaderName::from_static("permissions-policy"),
+                    cfg.permissions_policy.clone(),
+                ),
+            ],
+            csp: cfg.csp.clone(),
+            extra: cfg.extra.clone(),
+        }
+    }
+
+    fn merged(&self, mount: &HeadersMount) -> Self {
+        let mut values = self.clone();
+        let overrides = [
+            &mount.hsts,
+            &mount.content_type_options,
+            &mount.frame_options,
+            &mount.referrer_policy,
+            &mount.permissions_policy,
+        ];
+        for ((_, value), over) in values.named.iter_mut().zip(overrides) {
+            if let Some(over) = over {
+                value.clone_from(over);
+            }
+        }
+        if let Some(csp) = &mount.csp {
+            values.csp.clone_from(csp);
+        }
+        values.extra.extend(mount.extra.clone());
+        values
+    }
+
+    fn compile(self, section: &str) -> Result<Policy> {
+        let invalid = |name: &str| {
+            Error::Config(format!(
+                "{section}: the `{name}` value is not a valid header value"
+            ))
+        };
+        let mut fixed = Vec::new();
+        for (name, value) in self.named {
+            if value.is_empty() {
+                continue;
+            }
+            let value = HeaderValue::from_str(&value).map_err(|_| invalid(name.as_str()))?;
+            fixed.push((name, value));
+        }
+        for (name, value) in self.extra {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                Error::Config(format!("{section}: `{name}` is not a valid header name"))
+            })?;
+            // Named after one of the headers above, `extra` wins.
+            fixed.retain(|(existing, _)| *existing != name);
+            if value.is_empty() {
+                continue;
+            }
+            let value = HeaderValue::from_str(&value).map_err(|_| invalid(name.as_str()))?;
+            fixed.push((name, value));
+        }
+        let csp = match self.csp.is_empty() {
+            true => None,
+            // Checked with a stand-in nonce: the real ones are hex, which
+            // cannot make a valid value invalid.
+            false => {
+                HeaderValue::from_str(&self.csp.replace(NONCE, "0")).map_err(|_| invalid("csp"))?;
+                Some(self.csp)
+            }
+        };
+        Ok(Policy { fixed, csp })
+    }
+}
+
+impl SecurityHeaders {
+    /// Compiles the configuration, or `None` when the section is off.
+    ///
+    /// A mount's unset fields inherit from the nearest mount enclosing it,
+    /// then from the section, so `/docs/api` only states what differs
+    /// from `/docs`.
+    pub(crate) fn new(cfg: &HeadersConfig) -> Result<Option<Self>> {
+        if !cfg.enabled {
+            return Ok(None);
+        }
+        let base = Values::of(cfg);
+        let mut mounts: Vec<&HeadersMount> = cfg.mount.iter().collect();
+        mounts.sort_by_key(|mount| mount.path.trim_end_matches('/').len());
+        // Shortest first, so each mount's parent is already merged.
+        let mut merged: Vec<(String, Values)> = Vec::with_capacity(mounts.len());
+        for mount in mounts {
+            if !mount.path.starts_with('/') {
+                return Err(Error::Config(format!(
+                    "[[headers.mount]] path = `{}` must start with `/`",
+                    mount.path
+                )));
+            }
+            let prefix = mount.path.trim_end_matches('/').to_string();
+            let parent = merged
+                .iter()
+                .rev()
+                .find(|(outer, _)| covers(outer, &prefix))
+                .map_or(&base, |(_, values)| values);
+            let values = parent.merged(mount);
+            merged.push((prefix, values));
+        }
+        let mut mounts = Vec::with_capacity(merged.len());
+        for (prefix, values) in merged.into_iter().rev() {
+            let section = format!("[[headers.mount]] path = `{prefix}`");
+            mounts.push((prefix, values.compile(&section)?));
+        }
+        Ok(Some(Self {
+            base: base.compile("[headers]")?,
+            mounts,
+        }))
+    }
+
+    /// The policy for a request path: the longest matching mount's, or
+    /// the section's own.
+    pub(crate) fn policy(&self, path: &str) -> &Policy {
+        self.mounts
+            .iter()
+            .find(|(prefix, _)| covers(prefix, path))
+            .map_or(&self.base, |(_, policy)| policy)
+    }
+}
+
+/// Whether a mount prefix (without its trailing `/`) covers a path, on
+/// whole segments.
+fn covers(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl Policy {
+    /// A fresh nonce when this policy's CSP asks for one.
+    pub(crate) fn nonce(&self) -> Option<String> {
+        let csp = self.csp.as_ref()?;
+        if !csp.contains(NONCE) {
+            return None;
+        }
+        let mut bytes = [0u8; NONCE_BYTES];
+        if let Err(err) = getrandom::getrandom(&mut bytes) {
+            // Without entropy there is no safe nonce; `apply` then sends
+            // the CSP with a placeholder no script can match.
+            tracing::error!("failed to generate a CSP nonce: {err}");
+            return None;
+        }
+        Some(bytes.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Adds the headers the response does not already carry.
+    pub(crate) fn apply(&self, nonce: Option<&str>, headers: &mut HeaderMap) {
+        for (name, value) in &self.fixed {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        if let Some(csp) = &self.csp
+            && !headers.contains_key(header::CONTENT_SECURITY_POLICY)
+        {
+            // An empty nonce matches nothing, so a failed one blocks the
+            // inline scripts rather than allowing them.
+            let csp = csp.replace(NONCE, nonce.unwrap_or_default());
+            if let Ok(value) = HeaderValue::from_str(&csp) {
+                headers.insert(header::CONTENT_SECURITY_POLICY, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(cfg: HeadersConfig) -> SecurityHeaders {
+        SecurityHeaders::new(&cfg).expect("valid").expect("enabled")
+    }
+
+    fn applied(headers: &SecurityHeaders, path: &str) -> HeaderMap {
+        let policy = headers.policy(path);
+        let nonce = policy.nonce();
+        let mut out = HeaderMap::new();
+        policy.apply(nonce.as_deref(), &mut out);
+        out
+    }
+
+    #[test]
+    fn the_defaults_apply_once_enabled_and_never_clobber_the_response() {
+        assert!(
+            SecurityHeaders::new(&HeadersConfig::default())
+                .expect("valid")
+                .is_none()
+        );
+        let headers = policy(HeadersConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        let out = applied(&headers, "/");
+        assert_eq!(out[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(out[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(out[header::STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+        assert!(!out.contains_key("permissions-policy"));
+        assert!(!out.contains_key(header::CONTENT_SECURITY_POLICY));
+
+        let mut own = HeaderMap::new();
+        own.insert(
+            header::X_FRAME_OPTIONS,
+            HeaderValue::from_static("SAMEORIGIN"),
+        );
+        headers.policy("/").apply(None, &mut own);
+        assert_eq!(own[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+    }
+
+    #[test]
+    fn every_nonce_placeholder_gets_the_same_fresh_nonce() {
+        let headers = policy(HeadersConfig {
+            enabled: true,
+            csp: "script-src 'nonce-{nonce}'; style-src 'nonce-{nonce}'".into(),
+            ..Default::default()
+        });
+        let first = applied(&headers, "/");
+        let csp = first[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .expect("csp");
+        let nonce = csp
+            .strip_prefix("script-src 'nonce-")
+            .and_then(|rest| rest.split_once('\''))
+            .map(|(nonce, _)| nonce)
+            .expect("nonce");
+        assert_eq!(nonce.len(), NONCE_BYTES * 2);
+        assert_eq!(
+            csp,
+            format!("script-src 'nonce-{nonce}'; style-src 'nonce-{nonce}'")
+        );
+        let second = applied(&headers, "/");
+        assert_ne!(
+            first[header::CONTENT_SECURITY_POLICY],
+            second[header::CONTENT_SECURITY_POLICY]
+        );
+        assert!(headers.policy("/").nonce().is_some());
+        assert!(
+            policy(HeadersConfig {
+                enabled: true,
+                csp: "default-src 'self'".into(),
+                ..Default::default()
+            })
+            .policy("/")
+            .nonce()
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn the_longest_mount_wins_on_whole_segments() {
+        let headers = policy(HeadersConfig {
+            enabled: true,
+            extra: [("Cross-Origin-Opener-Policy".into(), "same-origin".into())].into(),
+            mount: vec![
+                HeadersMount {
+                    path: "/embed".into(),
+                    frame_options: Some(String::new()),
+                    csp: Some("frame-ancestors *".into()),
+                    ..Default::default()
+                },
+                HeadersMount {
+                    path: "/embed/admin/".into(),
+                    frame_options: Some("SAMEORIGIN".into()),
+                    extra: [("Cross-Origin-Opener-Policy".into(), String::new())].into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+        let embed = applied(&headers, "/embed/widget");
+        assert!(!embed.contains_key(header::X_FRAME_OPTIONS));
+        assert_eq!(embed[header::CONTENT_SECURITY_POLICY], "frame-ancestors *");
+        assert_eq!(embed["cross-origin-opener-policy"], "same-origin");
+
+        let admin = applied(&headers, "/embed/admin");
+        assert_eq!(admin[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert!(!admin.contains_key("cross-origin-opener-policy"));
+        // Inherited from `/embed`, not the section: mounts nest.
+        assert_eq!(admin[header::CONTENT_SECURITY_POLICY], "frame-ancestors *");
+
+        let other = applied(&headers, "/embedded");
+        assert_eq!(other[header::X_FRAME_OPTIONS], "DENY");
+    }
+
+    #[test]
+    fn invalid_values_and_paths_are_refused_at_startup() {
+        for cfg in [
+            HeadersConfig {
+                enabled: true,
+                referrer_policy: "no-referrer\n".into(),
+                ..Default::default()
+            },
+            HeadersConfig {
+                enabled: true,
+                extra: [("bad name".into(), "x".into())].into(),
+                ..Default::default()
+            },
+            HeadersConfig {
+                enabled: true,
+                mount: vec![HeadersMount {
+                    path: "docs".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        ] {
+            assert!(SecurityHeaders::new(&cfg).is_err(), "{cfg:?}");
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! The job workers: claim due `nitr.jobs` jobs and run the handlers the
+//! app registered with `app:job(name, fn)` on pooled states.
+//!
+//! A job is handled like a request — a state checked out of the same
+//! pool, the same execution budget and sandbox — but at most `[jobs]
+//! workers` of them hold a state at once, so a backlog of jobs slows
+//! requests down without ever taking every state from them.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use mlua::LuaSerdeExt as _;
+use nitr_core::{ErrorInfo, RuntimePool};
+use nitr_std::Jobs;
+use nitr_std::jobs::{Failed, Job};
+use tokio::sync::{Semaphore, watch};
+use tracing::Instrument as _;
+
+use crate::app;
+use crate::server::current_pool;
+
+/// The running workers, stopped by [`Workers::stop`].
+pub(crate) struct Workers {
+    stop: watch::Sender<bool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Workers {
+    /// Starts claiming jobs, running up to `workers` at once on states of
+    /// whichever pool is current (a reload's handlers take over at once).
+    pub(crate) fn spawn(
+        pool: Arc<RwLock<Arc<RuntimePool>>>,
+        jobs: Jobs,
+        workers: usize,
+        poll: Duration,
+    ) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let task = tokio::spawn(run(pool, jobs, workers.max(1), poll, stopped));
+        Self { stop, task }
+    }
+
+    /// Stops claiming and resolves once the jobs already running finish.
+    /// A job cut off after that is run again when its lease lapses.
+    pub(crate) async fn stop(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+}
+
+async fn run(
+    pool: Arc<RwLock<Arc<RuntimePool>>>,
+    jobs: Jobs,
+    workers: usize,
+    poll: Duration,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let slots = Arc::new(Semaphore::new(workers));
+    loop {
+        // Invariant: the semaphore is local and never closed.
+        #[allow(clippy::expect_used)]
+        let first = tokio::select! {
+            permit = slots.clone().acquire_owned() => permit.expect("never closed"),
+            _ = stopped.changed() => break,
+        };
+        // Only this loop takes permits, so every free one stays free
+        // until it does.
+        let free = 1 + slots.available_permits();
+        let claimed = match jobs.claim(free).await {
+            Ok(claimed) => claimed,
+            Err(err) => {
+                tracing::error!("cannot claim jobs: {err}");
+                Vec::new()
+            }
+        };
+        if claimed.is_empty() {
+            drop(first);
+            tokio::select! {
+                () = jobs.wait(poll) => continue,
+                _ = stopped.changed() => break,
+            }
+        }
+        let mut permits = std::iter::once(first).chain(std::iter::from_fn(|| {
+            slots.clone().try_acquire_owned().ok()
+        }));
+        for job in claimed {
+            let Some(permit) = permits.next() else {
+                // Unreachable: no more jobs are claimed than slots are free.
+                break;
+            };
+            let (pool, jobs) = (pool.clone(), jobs.clone());
+            let span = tracing::info_span!("job", job.id = job.id, job.name = %job.name);
+            tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    finish(&jobs, &job, execute(&pool, &jobs, &job).await).await;
+                }
+                .instrument(span),
+            );
+        }
+    }
+    // Every permit back means every running job has finished.
+    let _ = slots.acquire_many(workers as u32).await;
+}
+
+/// Runs one attempt of `job`, returning why it failed.
+async fn execute(
+    pool: &Arc<RwLock<Arc<RuntimePool>>>,
+    jobs: &Jobs,
+    job: &Job,
+) -> std::result::Result<(), String> {
+    let pool = current_pool(pool);
+    let mut rt = pool.get().await;
+    // The lease runs from here, so the wait for a state is not taken out
+    // of the handler's time. A lease that lapsed during the wait belongs
+    // to another attempt by now.
+    if !jobs.start(job).await.map_err(|err| err.to_string())? {
+        return Err("its lease lapsed while it waited for a state".into());
+    }
+    nitr_std::reset_outbound_budget(rt.lua());
+    nitr_std::set_trace_context(rt.lua(), &format!("job-{}", job.id));
+
+    let handler = app::job_handler(rt.lua(), &job.name)
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("no handler is registered for job `{}` (app:job)", job.name))?;
+    let payload: serde_json::Value = serde_json::from_str(&job.payload)
+        .map_err(|err| format!("the stored payload is not JSON: {err}"))?;
+    let args = (|| {
+        let lua = rt.lua();
+        let info = lua.create_table()?;
+        info.set("id", job.id)?;
+        info.set("name", job.name.as_str())?;
+        info.set("attempt", job.attempt)?;
+        info.set("max_attempts", job.max_attempts)?;
+        Ok::<_, mlua::Error>((lua.to_value(&payload)?, info))
+    })()
+    .map_err(|err| err.to_string())?;
+
+    let started = std::time::Instant::now();
+    let result = rt.call_function::<()>(handler, args).await;
+    tracing::debug!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "job attempt finished"
+    );
+    result.map_err(|err| ErrorInfo::from_error(&err).message)
+}
+
+/// Records the outcome of an attempt: done, retried later, or dead.
+async fn finish(jobs: &Jobs, job: &Job, outcome: std::result::Result<(), String>) {
+    let error = match outcome {
+        Ok(()) => {
+            match jobs.complete(job).await {
+                Ok(true) => {}
+                Ok(false) => tracing::warn!(
+                    attempt = job.attempt,
+                    "job finished after its lease lapsed; another attempt owns it"
+                ),
+                Err(err) => tracing::error!("cannot record a finished job: {err}"),
+            }
+            return;
+        }
+        Err(error) => error,
+    };
+    match jobs.fail(job, &error).await {
+        Ok(Failed::Retry(delay)) => tracing::warn!(
+            attempt = job.attempt,
+            "job failed, retrying in {delay:?}: {error}"
+        ),
+        Ok(Failed::Dead) => tracing::error!(
+            attempts = job.attempt,
+            "job failed on its last attempt and is dead: {error}"
+        ),
+        Ok(Failed::LeaseLost) => tracing::warn!(
+            attempt = job.attempt,
+            "job failed after its lease lapsed; another attempt owns it: {error}"
+        ),
+        Err(err) => tracing::error!("cannot record a failed job ({error}): {err}"),
+    }
+}
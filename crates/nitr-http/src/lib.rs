@@ -8,17 +8,22 @@
 #![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+pub(crate) mod access;
 pub(crate) mod app;
 pub(crate) mod compress;
 pub(crate) mod config;
 pub(crate) mod cors;
 pub(crate) mod handler;
+pub(crate) mod headers;
 pub(crate) mod health;
+#[cfg(feature = "db")]
+pub(crate) mod jobs;
 #[cfg(feature = "multipart")]
 pub(crate) mod multipart;
 pub(crate) mod protect;
 pub(crate) mod range;
 pub(crate) mod request;
+pub(crate) mod schedule;
 pub(crate) mod server;
 pub(crate) mod static_files;
 pub(crate) mod stream;
@@ -39,8 +44,11 @@ pub mod fuzzing {
 pub mod service;
 
 pub use config::{
-    CacheConfig, CompressionConfig, Config, CorsConfig, DatabaseConfig, FetchConfig, HealthConfig,
-    LimitsConfig, LogConfig, LogFormat, LuaConfig, RateLimitConfig, ShutdownConfig, StaticConfig,
+    AccessConfig, CacheBackend, CacheConfig, CompressionConfig, Config, CorsConfig, DatabaseConfig,
+    FetchConfig, HeadersConfig, HeadersMount, HealthConfig, JobsConfig, KvConfig, LimitsConfig,
+    LogConfig, LogFormat, LuaConfig, PubSubConfig, RateLimitConfig, RedisConfig, RedisFallback,
+    ReplicaConfig, ScheduleConfig, SearchConfig, ShutdownConfig, SlowConsumer, StaticConfig,
     StdConfig,
 };
+pub use schedule::ScheduleInfo;
 pub use server::{Server, ServerBuilder};
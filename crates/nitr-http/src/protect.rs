@@ -1,16 +1,17 @@
-//! Rust-side protection enforced before a request reaches Lua: rate
-//! limiting and request-size limits. These are infrastructure concerns —
-//! implementing them in Lua would let the thing being protected against
-//! consume the resources first.
+//! Rust-side protection enforced before a request reaches Lua: client-IP
+//! access lists, rate limiting and request-size limits. These are
+//! infrastructure concerns — implementing them in Lua would let the thing
+//! being protected against consume the resources first.
 
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use std::time::{Duration, Instant};
 
 use hyper::StatusCode;
 use hyper::header::HeaderValue;
 
+use crate::access::{Access, AccessList, AppAccess};
 use crate::config::Config;
 use crate::handler::{HttpResponse, plain_response};
 use crate::request::LuaRequest;
@@ -31,6 +32,11 @@ pub(crate) struct Protection {
     body_read: Option<Duration>,
     /// How long a request may wait for a Lua state before it is shed.
     pool_wait: Duration,
+    /// The access lists in force, swapped whole on reload.
+    access: RwLock<Arc<Access>>,
+    /// Whether a proxy in front sets `X-Forwarded-For`
+    /// (`[rate_limit] trust_forwarded_for`).
+    trust_forwarded_for: bool,
     rate: Option<RateLimiter>,
     /// Body-parsing bounds handed to each request.
     form: crate::request::FormLimits,
@@ -38,11 +44,13 @@ pub(crate) struct Protection {
     cors: Option<crate::cors::Cors>,
     /// The compiled `[compression]` policy.
     compression: crate::compress::Compression,
+    /// The compiled `[headers]` policy; `None` when it is disabled.
+    headers: Option<crate::headers::SecurityHeaders>,
 }
 
 impl Protection {
-    pub(crate) fn new(cfg: &Config) -> Self {
-        Self {
+    pub(crate) fn new(cfg: &Config, app: Arc<AppAccess>) -> Result<Self> {
+        Ok(Self {
             max_body_bytes: cfg.limits.max_body_bytes,
             max_uri_bytes: cfg.limits.max_uri_bytes,
             trust_request_id: cfg.trust_request_id,
@@ -52,6 +60,11 @@ impl Protection {
                 ms => Some(Duration::from_millis(ms)),
             },
             pool_wait: Duration::from_millis(cfg.limits.pool_wait_ms),
+            access: RwLock::new(Arc::new(Access {
+                server: Arc::new(AccessList::load(&cfg.access)?),
+                app,
+            })),
+            trust_forwarded_for: cfg.rate_limit.trust_forwarded_for,
             rate: cfg.rate_limit.enabled.then(|| RateLimiter {
                 max: cfg.rate_limit.requests.max(1),
                 window: Duration::from_secs(cfg.rate_limit.window.max(1)),
@@ -65,7 +78,18 @@ impl Protection {
             },
             cors: crate::cors::Cors::new(&cfg.cors),
             compression: crate::compress::Compression::new(&cfg.compression),
-        }
+            headers: crate::headers::SecurityHeaders::new(&cfg.headers)?,
+        })
+    }
+
+    /// Swaps in a reload's lists: the re-read `[access]` one and those of
+    /// the rebuilt app.
+    pub(crate) fn set_access(&self, server: AccessList, app: Arc<AppAccess>) {
+        let access = Arc::new(Access {
+            server: Arc::new(server),
+            app,
+        });
+        *self.access.write().unwrap_or_else(PoisonError::into_inner) = access;
     }
 
     /// The compiled CORS policy, or `None` when CORS is not configured.
@@ -73,6 +97,12 @@ impl Protection {
         self.cors.as_ref()
     }
 
+    /// The compiled security-header policy, or `None` when it is
+    /// disabled.
+    pub(crate) fn headers(&self) -> Option<&crate::headers::SecurityHeaders> {
+        self.headers.as_ref()
+    }
+
     /// The compiled compression policy.
     pub(crate) fn compression(&self) -> &crate::compress::Compression {
         &self.compression
@@ -128,6 +158,17 @@ impl Protection {
 
     /// Runs the pre-Lua checks; `Some` is the rejection response.
     pub(crate) fn check(&self, req: &LuaRequest) -> Option<Result<HttpResponse>> {
+        let access = self
+            .access
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let ip = proxied_ip(req, self.trust_forwarded_for);
+        if !access.allows(req.req.method(), req.req.uri().path(), ip) {
+            tracing::debug!(peer = %req.peer_addr, client = %ip, "request denied by an access rule");
+            return Some(plain_response(StatusCode::FORBIDDEN, "Forbidden"));
+        }
+
         if let Some(rate) = &self.rate
             && let Err(retry_after) = rate.check(req)
         {
@@ -172,6 +213,46 @@ fn uri_len(req: &LuaRequest) -> usize {
     uri.path().len() + uri.query().map_or(0, |q| q.len() + 1)
 }
 
+/// The client address: the first `X-Forwarded-For` entry when explicitly
+/// trusted (behind a proxy), else the peer address.
+fn client_ip(req: &LuaRequest, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for
+        && let Some(ip) = req
+            .req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(|v| v.trim().parse().ok())
+    {
+        return ip;
+    }
+    req.peer_addr.ip()
+}
+
+/// The address an access rule is checked against: the last
+/// `X-Forwarded-For` entry when trusted, else the peer address.
+///
+/// Unlike [`client_ip`], never an entry the client wrote: everything left
+/// of the one the proxy appended came in with the request, and an
+/// allowlist that read it would let anyone claim an allowed address.
+fn proxied_ip(req: &LuaRequest, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for
+        && let Some(ip) = req
+            .req
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|v| v.trim().parse().ok())
+    {
+        return ip;
+    }
+    req.peer_addr.ip()
+}
+
 /// A fixed-window request counter per client IP.
 #[derive(Debug)]
 struct RateLimiter {
@@ -185,7 +266,7 @@ impl RateLimiter {
     /// Returns `Err(retry_after_seconds)` when the client exceeded its
     /// budget for the current window.
     fn check(&self, req: &LuaRequest) -> std::result::Result<(), u64> {
-        let ip = self.client_ip(req);
+        let ip = client_ip(req, self.trust_forwarded_for);
         let now = Instant::now();
         let mut buckets = match self.buckets.lock() {
             Ok(guard) => guard,
@@ -209,23 +290,6 @@ impl RateLimiter {
         }
         Ok(())
     }
-
-    /// The IP the budget is keyed by: the first `X-Forwarded-For` entry
-    /// when explicitly trusted (behind a proxy), else the peer address.
-    fn client_ip(&self, req: &LuaRequest) -> IpAddr {
-        if self.trust_forwarded_for
-            && let Some(ip) = req
-                .req
-                .headers()
-                .get("x-forwarded-for")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|v| v.split(',').next())
-                .and_then(|v| v.trim().parse().ok())
-        {
-            return ip;
-        }
-        req.peer_addr.ip()
-    }
 }
 
 #[cfg(test)]
@@ -261,6 +325,7 @@ mod tests {
             id: "test".into(),
             limits: Default::default(),
             cached_form: None,
+            csp_nonce: None,
         }
     }
 
@@ -312,4 +377,27 @@ mod tests {
         // A garbage header falls back to the peer address.
         assert!(rl.check(&request("10.0.0.9", Some("not-an-ip"))).is_ok());
     }
+
+    #[test]
+    fn access_rules_see_the_address_the_proxy_appended() {
+        let spoofed = request("10.0.0.9", Some("10.0.0.1, 203.0.113.7"));
+        assert_eq!(
+            proxied_ip(&spoofed, true),
+            "203.0.113.7".parse::<IpAddr>().expect("ip")
+        );
+        // Untrusted, the header is ignored altogether.
+        assert_eq!(
+            proxied_ip(&spoofed, false),
+            "10.0.0.9".parse::<IpAddr>().expect("ip")
+        );
+        // A client-sent header line comes before the proxy's.
+        let mut two = request("10.0.0.9", Some("10.0.0.1"));
+        two.req
+            .headers_mut()
+            .append("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(
+            proxied_ip(&two, true),
+            "203.0.113.7".parse::<IpAddr>().expect("ip")
+        );
+    }
 }
@@ -204,6 +204,9 @@ pub(crate) struct LuaRequest {
     /// middleware (e.g. `nitr.csrf`) and the handler can both read it —
     /// the body itself can only be consumed once.
     pub(crate) cached_form: Option<Vec<(String, String)>>,
+    /// This request's CSP nonce, when the `[headers]` policy for its path
+    /// has a `{nonce}` to fill.
+    pub(crate) csp_nonce: Option<String>,
 }
 
 /// Bounds applied while parsing a request body into Lua values.
@@ -307,6 +310,7 @@ impl UserData for LuaRequest {
             Ok(table)
         });
         fields.add_field_method_get("id", |_, req| Ok(req.id.clone()));
+        fields.add_field_method_get("csp_nonce", |_, req| Ok(req.csp_nonce.clone()));
         fields.add_field_method_get("params", |lua, req| {
             // Path parameters captured by the router, e.g. `id` for a route
             // registered as `/users/:id`.
@@ -335,6 +339,19 @@ impl UserData for LuaRequest {
             }
             Ok(table)
         });
+        // A per-request table the chain shares: middleware leaves what it
+        // learned (`nitr.auth.jwt` puts the verified claims at
+        // `ctx.claims`) for the handler to read. Kept as a user value, not
+        // a Rust-held reference, so a table that points back at the
+        // request is still collected with it.
+        fields.add_field_function_get("ctx", |lua, ud| {
+            if let Some(ctx) = ud.named_user_value::<Option<mlua::Table>>("ctx")? {
+                return Ok(ctx);
+            }
+            let ctx = lua.create_table()?;
+            ud.set_named_user_value("ctx", &ctx)?;
+            Ok(ctx)
+        });
         fields.add_field_method_get("cookies", |_, req| {
             // All `Cookie` headers, joined so multi-header clients work.
             let header = req
@@ -0,0 +1,654 @@
+//! Scheduled tasks: `app:every(interval, fn)` and `app:cron(expr, fn)`,
+//! run by the server itself on pooled states.
+//!
+//! A run is handled like a job — a state checked out of the pool, the
+//! execution budget and sandbox of a request — and a schedule whose
+//! previous run is still going skips its turn rather than piling up. When
+//! several processes share the database, only the one holding the
+//! `schedule` lease (`[schedule]`) runs anything; the others stand by and
+//! take over once it stops renewing.
+//!
+//! Cron expressions have the usual five fields, in UTC: minute, hour, day
+//! of month, month and day of week, each `*`, a value, a range `a-b`, a
+//! step `*/n` or `a-b/n`, or a comma-separated list of those. Months and
+//! days of the week also take their English three-letter names, and the
+//! `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` shorthands
+//! stand for the obvious expressions.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Datelike as _, NaiveDate, Timelike as _, Utc};
+use nitr_core::{ErrorInfo, RuntimePool};
+use tokio::sync::watch;
+use tracing::Instrument as _;
+
+use crate::app;
+use crate::server::current_pool;
+
+/// When a schedule fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum When {
+    /// Every so often, counted from when the server starts.
+    Every(Duration),
+    /// Whenever the UTC wall clock matches.
+    Cron(Box<Cron>),
+}
+
+/// One schedule the app registered, without its function (which lives in
+/// each state).
+#[derive(Debug, Clone)]
+pub(crate) struct Schedule {
+    /// Unique within the app; defaults to the spec.
+    pub(crate) name: Arc<str>,
+    /// The interval or expression as written.
+    pub(crate) spec: Arc<str>,
+    pub(crate) when: When,
+    /// Each run starts up to this much later than due, at random, so
+    /// instances sharing a clock do not all hit the database at once.
+    pub(crate) jitter: Duration,
+}
+
+/// A schedule as listed by [`Server::schedules`](crate::Server::schedules).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleInfo {
+    /// The schedule's name: its `name` option, else its spec.
+    pub name: String,
+    /// The interval (`every`) or cron expression (`cron`) as written.
+    pub spec: String,
+    /// When it would next be due if the server started now, before jitter.
+    pub next_run: Option<SystemTime>,
+}
+
+impl Schedule {
+    /// The first time this schedule is due after `now`.
+    pub(crate) fn first(&self, now: SystemTime) -> Option<SystemTime> {
+        match &self.when {
+            When::Every(interval) => now.checked_add(*interval),
+            When::Cron(cron) => cron.next_after(now),
+        }
+    }
+
+    /// The time after `due` this schedule is due again, never at or
+    /// before `now`: runs missed while the process was busy or standing
+    /// by are skipped, not made up.
+    fn following(&self, due: SystemTime, now: SystemTime) -> Option<SystemTime> {
+        match &self.when {
+            When::Every(interval) => match due.checked_add(*interval)? {
+                next if next > now => Some(next),
+                _ => now.checked_add(*interval),
+            },
+            When::Cron(cron) => cron.next_after(due.max(now)),
+        }
+    }
+
+    pub(crate) fn info(&self, now: SystemTime) -> ScheduleInfo {
+        ScheduleInfo {
+            name: self.name.to_string(),
+            spec: self.spec.to_string(),
+            next_run: self.first(now),
+        }
+    }
+}
+
+/// Parses an interval: `"200ms"`, `"30s"`, `"5m"`, `"1h"` or `"1d"`.
+pub(crate) fn parse_interval(spec: &str) -> Result<Duration, String> {
+    let spec = spec.trim();
+    let split = spec
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(spec.len());
+    let (number, unit) = spec.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{spec}` is not an interval like \"30s\", \"5m\" or \"1h\""))?;
+    let unit = match unit.trim() {
+        "ms" => 0.001,
+        "s" | "" => 1.0,
+        "m" => 60.0,
+        "h" => 3_600.0,
+        "d" => 86_400.0,
+        other => {
+            return Err(format!(
+                "unknown unit `{other}` in interval `{spec}` (use ms, s, m, h or d)"
+            ));
+        }
+    };
+    seconds(number * unit).ok_or_else(|| format!("the interval `{spec}` must be positive"))
+}
+
+/// A positive, finite number of seconds as a [`Duration`].
+pub(crate) fn seconds(secs: f64) -> Option<Duration> {
+    (secs.is_finite() && secs > 0.0)
+        .then(|| Duration::try_from_secs_f64(secs).ok())
+        .flatten()
+}
+
+/// A parsed cron expression: one bit per allowed value of each field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Cron {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    /// Whether the day-of-month / day-of-week field was a plain `*`. When
+    /// both are restricted a day matching either one fires, as in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Cron {
+    /// Parses a five-field expression, or one of the `@` shorthands.
+    pub(crate) fn parse(expr: &str) -> Result<Self, String> {
+        let expanded = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "the cron expression `{expr}` must have five fields \
+                 (minute hour day-of-month month day-of-week), got {}",
+                fields.len()
+            ));
+        };
+        let field = |text: &str, what: &str, min: u32, max: u32, names: &[&str]| {
+            parse_field(text, min, max, names)
+                .map_err(|err| format!("the {what} field of `{expr}` {err}"))
+        };
+        // Day of week 7 is Sunday too.
+        let weekdays = field(weekday, "day-of-week", 0, 7, WEEKDAYS)?;
+        let cron = Self {
+            minutes: field(minute, "minute", 0, 59, &[])?,
+            hours: field(hour, "hour", 0, 23, &[])? as u32,
+            days: field(day, "day-of-month", 1, 31, &[])? as u32,
+            months: field(month, "month", 1, 12, MONTHS)? as u16,
+            weekdays: ((weekdays | weekdays >> 7) & 0x7f) as u8,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        };
+        // Any eight years hold a February 29th, the rarest date an
+        // expression can name.
+        let start =
+            SystemTime::from(DateTime::<Utc>::from_timestamp(946_684_800, 0).unwrap_or_default());
+        if cron.next_after(start).is_none() {
+            return Err(format!("the cron expression `{expr}` never fires"));
+        }
+        Ok(cron)
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`.
+    pub(crate) fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let after = DateTime::<Utc>::from(after);
+        let start = after
+            .with_second(0)?
+            .with_nanosecond(0)?
+            .checked_add_signed(chrono::TimeDelta::minutes(1))?;
+        let mut date = start.date_naive();
+        let (mut hour, mut minute) = (start.hour(), start.minute());
+        for _ in 0..366 * 8 {
+            if self.months & 1 << date.month() != 0 && self.day_matches(date) {
+                for h in (hour..24).filter(|h| self.hours & 1 << h != 0) {
+                    let from = if h == hour { minute } else { 0 };
+                    if let Some(m) = (from..60).find(|m| self.minutes & 1 << m != 0) {
+                        let at = date.and_hms_opt(h, m, 0)?.and_utc();
+                        return Some(SystemTime::from(at));
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            (hour, minute) = (0, 0);
+        }
+        None
+    }
+}
+
+/// One cron field as a bitmask over `min..=max`.
+fn parse_field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |v: &str| -> Result<u32, String> {
+        let lower = v.to_ascii_lowercase();
+        let n = match names.iter().position(|name| *name == lower) {
+            // Month names count from 1, weekday names from 0 (`min`).
+            Some(i) => i as u32 + min,
+            None => v.parse().map_err(|_| format!("has `{v}`, not a number"))?,
+        };
+        if (min..=max).contains(&n) {
+            Ok(n)
+        } else {
+            Err(format!("has {n}, outside {min}-{max}"))
+        }
+    };
+    let mut mask = 0u64;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("has the step `{step}`, not a positive number")),
+            },
+            None => (item, 1),
+        };
+        let (lo, hi) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((lo, hi)) => (value(lo)?, value(hi)?),
+                // `a/n` runs from `a` to the end of the field.
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let v = value(range)?;
+                    (v, v)
+                }
+            },
+        };
+        if lo > hi {
+            return Err(format!("has the backwards range `{range}`"));
+        }
+        for v in (lo..=hi).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Ok(mask)
+}
+
+/// The runner, stopped by [`Scheduler::stop`].
+pub(crate) struct Scheduler {
+    stop: watch::Sender<bool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Scheduler {
+    /// Starts running the schedules in `schedules` — replaced by a reload —
+    /// on states of whichever pool is current, while `leader` says this
+    /// process may.
+    pub(crate) fn spawn(
+        pool: Arc<RwLock<Arc<RuntimePool>>>,
+        schedules: watch::Receiver<Arc<[Schedule]>>,
+        leader: Leader,
+    ) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let task = tokio::spawn(run(pool, schedules, leader, stopped));
+        Self { stop, task }
+    }
+
+    /// Stops firing and resolves once the runs already going finish, with
+    /// the lease given up so another process can take over at once.
+    pub(crate) async fn stop(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+}
+
+/// Whether this process is the one that runs schedules: always, unless a
+/// lease in a shared database file says otherwise.
+pub(crate) struct Leader {
+    #[cfg(feature = "db")]
+    lease: Option<nitr_std::Lease>,
+    holding: bool,
+}
+
+impl Leader {
+    /// A process that runs every schedule, with nobody to share them with.
+    pub(crate) fn alone() -> Self {
+        Self {
+            #[cfg(feature = "db")]
+            lease: None,
+            holding: true,
+        }
+    }
+
+    /// A process that runs schedules only while it holds `lease`.
+    #[cfg(feature = "db")]
+    pub(crate) fn leased(lease: nitr_std::Lease) -> Self {
+        Self {
+            lease: Some(lease),
+            holding: false,
+        }
+    }
+
+    /// How often the lease is renewed: well within its lifetime, so a
+    /// holder that is alive never lets it lapse.
+    fn renew_every(&self) -> Option<Duration> {
+        #[cfg(feature = "db")]
+        if let Some(lease) = &self.lease {
+            return Some((lease.ttl() / 3).max(Duration::from_millis(10)));
+        }
+        None
+    }
+
+    /// Acquires or extends the lease, logging when leadership changes.
+    async fn renew(&mut self) {
+        #[cfg(feature = "db")]
+        if let Some(lease) = self.lease.clone() {
+            let holding = match tokio::task::spawn_blocking(move || lease.acquire()).await {
+                Ok(Ok(holding)) => holding,
+                Ok(Err(err)) => {
+                    tracing::warn!("cannot renew the schedule lease: {err}");
+                    false
+                }
+                Err(err) => {
+                    tracing::warn!("cannot renew the schedule lease: {err}");
+                    false
+                }
+            };
+            match (self.holding, holding) {
+                (false, true) => tracing::info!("holding the schedule lease: running schedules"),
+                (true, false) => {
+                    tracing::warn!("lost the schedule lease: another process runs schedules")
+                }
+                _ => {}
+            }
+            self.holding = holding;
+        }
+    }
+
+    async fn release(self) {
+        #[cfg(feature = "db")]
+        if let Some(lease) = self.lease
+            && self.holding
+        {
+            let _ = tokio::task::spawn_blocking(move || lease.release()).await;
+        }
+    }
+}
+
+/// A schedule with its next due time, as the runner tracks it.
+struct Slot {
+    schedule: Schedule,
+    due: SystemTime,
+    /// `due` plus this turn's jitter.
+    fire: SystemTime,
+}
+
+impl Slot {
+    fn new(schedule: Schedule, due: SystemTime) -> Self {
+        let fire = due + jitter(schedule.jitter);
+        Self {
+            schedule,
+            due,
+            fire,
+        }
+    }
+}
+
+/// A random delay up to `max`.
+fn jitter(max: Duration) -> Duration {
+    let max_ms = max.as_millis() as u64;
+    if max_ms == 0 {
+        return Duration::ZERO;
+    }
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(u64::from_le_bytes(bytes) % (max_ms + 1))
+}
+
+/// Lines the runner's slots up with the schedules now registered: one
+/// whose name and spec are unchanged keeps its turn, so a reload does not
+/// restart every interval.
+fn sync(slots: &mut Vec<Slot>, schedules: &[Schedule], now: SystemTime) {
+    let mut old: HashMap<Arc<str>, Slot> = slots
+        .drain(..)
+        .map(|slot| (slot.schedule.name.clone(), slot))
+        .collect();
+    for schedule in schedules {
+        match old.remove(&schedule.name) {
+            Some(mut slot) if slot.schedule.when == schedule.when => {
+                slot.schedule = schedule.clone();
+                slots.push(slot);
+            }
+            _ => match schedule.first(now) {
+                Some(due) => slots.push(Slot::new(schedule.clone(), due)),
+                None => tracing::warn!(schedule = %schedule.name, "the schedule never fires again"),
+            },
+        }
+    }
+}
+
+async fn run(
+    pool: Arc<RwLock<Arc<RuntimePool>>>,
+    mut schedules: watch::Receiver<Arc<[Schedule]>>,
+    mut leader: Leader,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut slots = Vec::new();
+    sync(
+        &mut slots,
+        &schedules.borrow_and_update(),
+        SystemTime::now(),
+    );
+    let mut renewals = leader.renew_every().map(tokio::time::interval);
+    let mut running: HashMap<Arc<str>, tokio::task::JoinHandle<()>> = HashMap::new();
+    loop {
+        let next = slots.iter().map(|slot| slot.fire).min();
+        let wait = next.map(|fire| {
+            fire.duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+        });
+        tokio::select! {
+            () = async {
+                match wait {
+                    Some(wait) => tokio::time::sleep(wait).await,
+                    None => std::future::pending().await,
+                }
+            } => {}
+            _ = async {
+                match &mut renewals {
+                    Some(renewals) => renewals.tick().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                leader.renew().await;
+                continue;
+            }
+            changed = schedules.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                sync(&mut slots, &schedules.borrow_and_update(), SystemTime::now());
+                continue;
+            }
+            _ = stopped.changed() => break,
+        }
+
+        let now = SystemTime::now();
+        running.retain(|_, task| !task.is_finished());
+        slots.retain_mut(|slot| {
+            if slot.fire > now {
+                return true;
+            }
+            let name = slot.schedule.name.clone();
+            match running.entry(name.clone()) {
+                _ if !leader.holding => {
+                    tracing::debug!(schedule = %name, "not holding the schedule lease, skipping");
+                }
+                Entry::Occupied(_) => {
+                    tracing::warn!(schedule = %name, "the previous run is still going, skipping");
+                }
+                Entry::Vacant(vacant) => {
+                    let (pool, due) = (pool.clone(), slot.due);
+                    let span = tracing::info_span!("job", schedule = %name);
+                    vacant.insert(tokio::spawn(
+                        async move {
+                            if let Err(err) = execute(&pool, &name, due).await {
+                                tracing::error!("scheduled run failed: {err}");
+                            }
+                        }
+                        .instrument(span),
+                    ));
+                }
+            }
+            match slot.schedule.following(slot.due, now) {
+                Some(due) => {
+                    *slot = Slot::new(slot.schedule.clone(), due);
+                    true
+                }
+                // Only a cron expression runs out, past the dates chrono
+                // knows.
+                None => false,
+            }
+        });
+    }
+    for (_, task) in running {
+        let _ = task.await;
+    }
+    leader.release().await;
+}
+
+/// Runs schedule `name` once on a state of the current pool, returning why
+/// it failed.
+pub(crate) async fn execute(
+    pool: &Arc<RwLock<Arc<RuntimePool>>>,
+    name: &str,
+    due: SystemTime,
+) -> std::result::Result<(), String> {
+    let pool = current_pool(pool);
+    let mut rt = pool.get().await;
+    nitr_std::reset_outbound_budget(rt.lua());
+    nitr_std::set_trace_context(rt.lua(), &format!("schedule-{name}"));
+
+    let handler = app::schedule_handler(rt.lua(), name)
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("no schedule named `{name}` is registered"))?;
+    let info = (|| {
+        let info = rt.lua().create_table()?;
+        info.set("name", name)?;
+        let due_ms = due
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        info.set("scheduled_at", due_ms)?;
+        Ok::<_, mlua::Error>(info)
+    })()
+    .map_err(|err| err.to_string())?;
+
+    let started = std::time::Instant::now();
+    let result = rt.call_function::<()>(handler, info).await;
+    tracing::debug!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "scheduled run finished"
+    );
+    result.map_err(|err| ErrorInfo::from_error(&err).message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(rfc3339: &str) -> SystemTime {
+        SystemTime::from(
+            DateTime::parse_from_rfc3339(rfc3339)
+                .expect("a valid timestamp")
+                .with_timezone(&Utc),
+        )
+    }
+
+    fn next(expr: &str, after: &str) -> String {
+        let cron = Cron::parse(expr).expect("a valid expression");
+        let next = cron.next_after(at(after)).expect("a next run");
+        DateTime::<Utc>::from(next).to_rfc3339()
+    }
+
+    #[test]
+    fn intervals_parse_with_units() {
+        assert_eq!(parse_interval("200ms"), Ok(Duration::from_millis(200)));
+        assert_eq!(parse_interval("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_interval("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("1.5h"), Ok(Duration::from_secs(5_400)));
+        assert_eq!(parse_interval("1d"), Ok(Duration::from_secs(86_400)));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("5 weeks").is_err());
+        assert!(parse_interval("soon").is_err());
+    }
+
+    #[test]
+    fn cron_finds_the_next_matching_minute() {
+        assert_eq!(
+            next("0 3 * * *", "2026-03-01T02:59:30Z"),
+            "2026-03-01T03:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 3 * * *", "2026-03-01T03:00:00Z"),
+            "2026-03-02T03:00:00+00:00"
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2026-03-01T10:16:00Z"),
+            "2026-03-01T10:30:00+00:00"
+        );
+        assert_eq!(
+            next("30 9 * * mon-fri", "2026-03-06T10:00:00Z"),
+            "2026-03-09T09:30:00+00:00"
+        );
+        assert_eq!(
+            next("@monthly", "2026-12-15T00:00:00Z"),
+            "2027-01-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 29 feb *", "2026-03-01T00:00:00Z"),
+            "2028-02-29T00:00:00+00:00"
+        );
+        // Sunday as 7, and either day field matching when both are set.
+        assert_eq!(
+            next("0 12 * * 7", "2026-03-02T00:00:00Z"),
+            "2026-03-08T12:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 13 * fri", "2026-03-01T00:00:00Z"),
+            "2026-03-06T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn bad_cron_expressions_are_rejected() {
+        for (expr, needle) in [
+            ("* * * *", "five fields"),
+            ("60 * * * *", "outside 0-59"),
+            ("5-1 * * * *", "backwards"),
+            ("*/0 * * * *", "step"),
+            ("0 0 * foo *", "not a number"),
+            ("0 0 31 feb *", "never fires"),
+        ] {
+            let err = Cron::parse(expr).expect_err(expr);
+            assert!(err.contains(needle), "{expr}: {err}");
+        }
+    }
+
+    #[test]
+    fn missed_intervals_are_skipped_not_made_up() {
+        let schedule = Schedule {
+            name: "tick".into(),
+            spec: "1m".into(),
+            when: When::Every(Duration::from_secs(60)),
+            jitter: Duration::ZERO,
+        };
+        let due = at("2026-03-01T00:00:00Z");
+        assert_eq!(
+            schedule.following(due, due),
+            Some(at("2026-03-01T00:01:00Z"))
+        );
+        assert_eq!(
+            schedule.following(due, at("2026-03-01T00:05:30Z")),
+            Some(at("2026-03-01T00:06:30Z"))
+        );
+    }
+}
@@ -1,7 +1,7 @@
 //! The HTTP server and its builder: the main entrypoint for consuming Nitr.
 
 use std::future::Future;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
@@ -12,11 +12,13 @@ use hyper_util::server::graceful::GracefulShutdown;
 use mlua::AnyUserData;
 use nitr_core::ModuleFn;
 use tokio::net::TcpListener;
-use tokio::sync::Semaphore;
+use tokio::sync::{Semaphore, watch};
+use tracing::Instrument as _;
 
 use crate::app;
 use crate::config::Config;
 use crate::protect::Protection;
+use crate::schedule::{Schedule, ScheduleInfo};
 use crate::service::Svc;
 use nitr_core::{Error, Result};
 use nitr_core::{Runtime, RuntimePool};
@@ -54,34 +56,181 @@ pub struct Server {
     /// routing before requests begin to fail. Read by
     /// [`is_ready()`](Self::is_ready), which a readiness probe surfaces.
     ready: Arc<AtomicBool>,
-    /// The shared `nitr.cache`, held here so a reload hands the new pool
-    /// the same storage rather than starting cold.
+    /// State every pooled state shares, held here so a reload hands the
+    /// new pool the same storage rather than starting cold.
+    shared: Shared,
+    /// Ships the database's WAL to `[database.replica] dir`; stopped (after
+    /// a last copy) once the drain is over.
+    #[cfg(feature = "db")]
+    replicator: Option<nitr_std::replicate::Replicator>,
+    /// The schedules of the current pool's app, replaced by a reload and
+    /// watched by the scheduler.
+    schedules: watch::Sender<Arc<[Schedule]>>,
+    /// The lease deciding which process sharing the database runs the
+    /// schedules; `None` runs them here regardless.
+    #[cfg(feature = "db")]
+    schedule_lease: Option<nitr_std::Lease>,
+}
+
+/// What every pooled state shares, including the states a reload builds.
+/// Built once: anything here outlives the handler script.
+#[derive(Clone, Default)]
+struct Shared {
+    /// The `nitr.cache` storage — a cache that empties whenever the
+    /// handler script changes is a cache that never warms.
     cache: Option<nitr_std::Cache>,
+    /// The row-change feed behind `nitr.db:changes()`.
+    #[cfg(feature = "db")]
+    changes: Option<nitr_std::ChangeFeed>,
+    /// The `nitr.pubsub` hub: a reload must not cut subscribers off from
+    /// the publishers of the new script.
+    pubsub: Option<nitr_std::PubSub>,
+    /// The channels behind `nitr.sse.channel`, and their histories.
+    sse: Option<nitr_std::SseHub>,
+    /// The connection pool behind `[database] url`: one per process, not
+    /// one per state, or the server's connection count would scale with
+    /// `workers`.
+    #[cfg(feature = "postgres")]
+    postgres: Option<nitr_std::PgPool>,
+    /// The `nitr.kv` store: one connection and one sweeper per process.
+    #[cfg(feature = "db")]
+    kv: Option<nitr_std::Kv>,
+    /// The `nitr.jobs` queue, which the states enqueue into and the job
+    /// workers drain.
+    #[cfg(feature = "db")]
+    jobs: Option<nitr_std::Jobs>,
+    /// The `[keys]` ring; a reload re-reads it in place, so every state
+    /// signs with the newest key from then on.
+    keys: Option<nitr_std::KeyRing>,
+}
+
+impl Shared {
+    fn new(cfg: &Config, builtins: Builtins) -> Result<Self> {
+        Ok(Self {
+            cache: builtins
+                .contains(Builtins::CACHE)
+                .then(|| restore_cache(cfg))
+                .transpose()?,
+            #[cfg(feature = "db")]
+            changes: cfg
+                .database
+                .as_ref()
+                .filter(|db| db.changes)
+                .map(|_| nitr_std::ChangeFeed::new(CHANGE_FEED_CAPACITY)),
+            pubsub: builtins
+                .contains(Builtins::PUBSUB)
+                .then(|| nitr_std::PubSub::new(cfg.pubsub.options())),
+            sse: builtins
+                .contains(Builtins::HTTP)
+                .then(|| nitr_std::SseHub::new(cfg.sse.options())),
+            #[cfg(feature = "postgres")]
+            postgres: cfg
+                .database
+                .as_ref()
+                .and_then(|db| db.url.as_deref().map(|url| (url, db.pool_size)))
+                .map(|(url, size)| nitr_std::PgPool::connect(url, size))
+                .transpose()?,
+            #[cfg(feature = "db")]
+            kv: if builtins.contains(Builtins::KV) {
+                cfg.open_kv()?
+            } else {
+                None
+            },
+            #[cfg(feature = "db")]
+            jobs: if builtins.contains(Builtins::JOBS) {
+                cfg.open_jobs()?
+            } else {
+                None
+            },
+            keys: cfg.open_keys()?,
+        })
+    }
+}
+
+/// The shared cache, warmed from the `[cache] persist` snapshot when there
+/// is one. Restored while building, so the server never reports ready
+/// with a cold cache it could have warmed.
+fn restore_cache(cfg: &Config) -> Result<nitr_std::Cache> {
+    let cache = cfg.open_cache()?;
+    if let Some(path) = &cfg.cache.persist {
+        match cache.load_snapshot(path) {
+            Ok(0) => {}
+            Ok(n) => tracing::info!("restored {n} cache entries from {}", path.display()),
+            Err(err) => tracing::warn!("ignoring the cache snapshot: {err}"),
+        }
+    }
+    Ok(cache)
 }
 
-/// Refuses to start while a migration is pending.
+/// Writes the `[cache] persist` snapshot, off the async threads.
+async fn persist_cache(cache: &nitr_std::Cache, path: &Path) -> Result<usize> {
+    let (cache, path) = (cache.clone(), path.to_path_buf());
+    tokio::task::spawn_blocking(move || cache.save_snapshot(&path))
+        .await
+        .map_err(|err| Error::Config(format!("the cache snapshot task failed: {err}")))?
+}
+
+/// Changes buffered for the slowest `nitr.db:changes()` subscriber
+/// before it is told it lagged.
+#[cfg(feature = "db")]
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// Refuses to start while a migration is pending or a search index is out
+/// of date.
 ///
 /// The alternative — applying them at boot — is how two instances of a
 /// rolling deployment race to change the same schema, each believing it is
 /// the only one. Making it an explicit step means somebody chose when it
 /// happened.
 #[cfg(not(feature = "db"))]
-fn check_migrations(_cfg: &Config) -> Result {
+async fn check_migrations(_cfg: &Config, _shared: &Shared) -> Result {
     // Without the `db` builtin there is no connection to check against;
     // `register_builtins` is what reports the misconfiguration.
     Ok(())
 }
 
 #[cfg(feature = "db")]
-fn check_migrations(cfg: &Config) -> Result {
+async fn check_migrations(cfg: &Config, shared: &Shared) -> Result {
     let Some(db) = &cfg.database else {
         return Ok(());
     };
-    let Some(dir) = db.migrations() else {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = &shared.postgres {
+        // Also what fails the boot on a wrong URL or password, rather than
+        // the first request.
+        pool.check().await?;
+        let Some(dir) = db.migrations() else {
+            return Ok(());
+        };
+        return pending_error(pool.pending_migrations(&dir).await?);
+    }
+    #[cfg(not(feature = "postgres"))]
+    let _ = shared;
+    let indexes = db.search_indexes();
+    let dir = db.migrations();
+    if dir.is_none() && indexes.is_empty() {
         return Ok(());
-    };
+    }
     let conn = nitr_std::db_open(&db.path, &db.pragmas())?;
-    let pending = nitr_std::migrate::pending(&conn, &dir)?;
+    if let Some(dir) = dir {
+        pending_error(nitr_std::migrate::pending(&conn, &dir)?)?;
+    }
+    // An index missing or built from an older definition would answer
+    // searches wrongly (or not at all), exactly like a missing column.
+    let pending = nitr_std::search::pending(&conn, &indexes)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    Err(Error::Config(format!(
+        "{} search index(es) out of date ({}). Run `nitr migrate` first.",
+        pending.len(),
+        pending.join(", ")
+    )))
+}
+
+/// The boot error for pending migrations, if there are any.
+#[cfg(feature = "db")]
+fn pending_error(pending: Vec<String>) -> Result {
     if pending.is_empty() {
         return Ok(());
     }
@@ -92,6 +241,18 @@ fn check_migrations(cfg: &Config) -> Result {
     )))
 }
 
+/// Starts WAL shipping when `[database.replica]` is configured.
+#[cfg(feature = "db")]
+fn start_replicator(cfg: &Config) -> Result<Option<nitr_std::replicate::Replicator>> {
+    let Some(db) = &cfg.database else {
+        return Ok(None);
+    };
+    let Some(replica) = &db.replica else {
+        return Ok(None);
+    };
+    nitr_std::replicate::Replicator::start(&db.path, &db.pragmas(), replica.options()).map(Some)
+}
+
 /// Builder for [`Server`].
 ///
 /// Individual setters override values from [`config()`](Self::config).
@@ -132,33 +293,95 @@ impl Server {
         )
     }
 
+    /// The schedules the app registered (`app:every`, `app:cron`), with
+    /// when each would be due next if the server started now.
+    pub fn schedules(&self) -> Vec<ScheduleInfo> {
+        let now = std::time::SystemTime::now();
+        self.schedules
+            .borrow()
+            .iter()
+            .map(|schedule| schedule.info(now))
+            .collect()
+    }
+
+    /// Runs schedule `name` once, now, on a pooled state — whether or not
+    /// this process holds the schedule lease. Resolves when the run ends.
+    pub async fn run_schedule(&self, name: &str) -> Result {
+        if !self.schedules.borrow().iter().any(|s| &*s.name == name) {
+            return Err(Error::Config(format!(
+                "the app registers no schedule named `{name}`"
+            )));
+        }
+        let span = tracing::info_span!("job", schedule = %name);
+        crate::schedule::execute(&self.pool, name, std::time::SystemTime::now())
+            .instrument(span)
+            .await
+            .map_err(Error::Script)
+    }
+
     /// Builds a complete replacement pool (re-running the configuration
-    /// script) and atomically swaps it in; in-flight requests finish on
+    /// script) and atomically swaps it in, with the access lists of the new
+    /// app and the re-read `[access] file`; in-flight requests finish on
     /// the old pool, which is dropped when its last guard returns. On any
     /// error the old pool stays.
     async fn reload(&self) {
         tracing::info!("reload requested: rebuilding the runtime pool");
+        // Keys first: a rotated key is picked up by the states that keep
+        // running too, and a broken key source keeps the keys and the pool.
+        if let Some(keys) = &self.shared.keys {
+            match keys.reload() {
+                Ok(n) => tracing::info!("reloaded {n} keys"),
+                Err(err) => {
+                    tracing::error!("reload failed, keeping the current keys and pool: {err}");
+                    return;
+                }
+            }
+        }
+        // The `[access] file` is re-read; a broken one keeps the old lists.
+        let server_access = match crate::access::AccessList::load(&self.cfg.access) {
+            Ok(list) => list,
+            Err(err) => {
+                tracing::error!("reload failed, keeping the current access lists and pool: {err}");
+                return;
+            }
+        };
         match build_runtimes(
             &self.cfg,
             self.builtins,
             &self.setup_fns,
             &self.modules,
-            self.cache.as_ref(),
+            &self.shared,
         )
         .await
         {
             Ok(runtimes) => {
+                let app_access = match access_of(&runtimes) {
+                    Ok(access) => access,
+                    Err(err) => {
+                        tracing::error!("reload failed, keeping the current pool: {err}");
+                        return;
+                    }
+                };
+                let schedules = match schedules_of(&runtimes) {
+                    Ok(schedules) => schedules,
+                    Err(err) => {
+                        tracing::error!("reload failed, keeping the current pool: {err}");
+                        return;
+                    }
+                };
                 let fresh = Arc::new(new_pool(
                     runtimes,
                     &self.cfg,
                     self.builtins,
                     &self.setup_fns,
                     &self.modules,
-                    self.cache.clone(),
+                    self.shared.clone(),
                 ));
                 match self.pool.write() {
                     Ok(mut pool) => {
                         *pool = fresh;
+                        self.protection.set_access(server_access, app_access);
+                        self.schedules.send_replace(schedules);
                         tracing::info!("reload complete: new runtime pool is live");
                     }
                     Err(_) => tracing::error!("reload failed: pool lock is poisoned"),
@@ -170,6 +393,29 @@ impl Server {
         }
     }
 
+    /// Snapshots the cache every `[cache] persist_interval` seconds, when
+    /// both are set.
+    fn spawn_cache_persist(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let cache = self.shared.cache.clone()?;
+        let path = self.cfg.cache.persist.clone()?;
+        let every = Duration::from_secs(self.cfg.cache.persist_interval);
+        if every.is_zero() {
+            return None;
+        }
+        Some(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            // The first tick is immediate; the cache was only just restored.
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                match persist_cache(&cache, &path).await {
+                    Ok(n) => tracing::debug!("saved {n} cache entries to {}", path.display()),
+                    Err(err) => tracing::warn!("{err}"),
+                }
+            }
+        }))
+    }
+
     /// Serves until a shutdown signal arrives, then drains gracefully.
     ///
     /// The signal contract:
@@ -253,6 +499,26 @@ impl Server {
                 ready: self.ready.clone(),
             })
         });
+        let persist_task = self.spawn_cache_persist();
+        #[cfg(feature = "db")]
+        let job_workers = self.shared.jobs.clone().map(|jobs| {
+            crate::jobs::Workers::spawn(
+                self.pool.clone(),
+                jobs,
+                self.cfg.job_workers(),
+                Duration::from_millis(self.cfg.jobs.poll_ms.max(1)),
+            )
+        });
+        let scheduler = self.cfg.schedule.enabled.then(|| {
+            #[cfg(feature = "db")]
+            let leader = match self.schedule_lease.clone() {
+                Some(lease) => crate::schedule::Leader::leased(lease),
+                None => crate::schedule::Leader::alone(),
+            };
+            #[cfg(not(feature = "db"))]
+            let leader = crate::schedule::Leader::alone();
+            crate::schedule::Scheduler::spawn(self.pool.clone(), self.schedules.subscribe(), leader)
+        });
         let mut probe_task = None;
         let main_health = match (&health_state, self.cfg.health.bind) {
             (Some(state), Some(addr)) => {
@@ -346,6 +612,14 @@ impl Server {
         // a load balancer drains us on its own terms. Responses issued from
         // here on also carry `Connection: close`.
         self.ready.store(false, Ordering::Relaxed);
+        // Streaming bodies waiting on a subscription, and channel streams,
+        // end now rather than at the drain deadline.
+        if let Some(pubsub) = &self.shared.pubsub {
+            pubsub.close();
+        }
+        if let Some(sse) = &self.shared.sse {
+            sse.close();
+        }
 
         let grace = self.cfg.shutdown.grace();
         let total = self.cfg.shutdown.total_grace();
@@ -362,9 +636,28 @@ impl Server {
         // Step 6 follows from the drain: dropping the watcher (and with it
         // the last connection tasks) closes every Lua state, which
         // checkpoints the SQLite WAL of each connection.
+        // The job workers stop claiming now, and the jobs already running
+        // get the same grace as requests; one cut off is run again once
+        // its lease lapses. The scheduler stops firing the same way.
+        let jobs_done = async {
+            let schedules_done = async {
+                if let Some(scheduler) = scheduler {
+                    scheduler.stop().await;
+                }
+            };
+            #[cfg(feature = "db")]
+            let workers_done = async {
+                if let Some(workers) = job_workers {
+                    workers.stop().await;
+                }
+            };
+            #[cfg(not(feature = "db"))]
+            let workers_done = async {};
+            tokio::join!(schedules_done, workers_done);
+        };
         let deadline = drain_deadline(&self.streams, self.max_streams, grace, total);
         let drained = tokio::select! {
-            _ = graceful.shutdown() => true,
+            _ = async { tokio::join!(graceful.shutdown(), jobs_done) } => true,
             _ = deadline => false,
         };
 
@@ -372,6 +665,25 @@ impl Server {
             task.abort();
         }
 
+        // After the drain, so the snapshot holds what the last requests
+        // wrote.
+        if let Some(task) = persist_task {
+            task.abort();
+        }
+        if let (Some(cache), Some(path)) = (&self.shared.cache, &self.cfg.cache.persist) {
+            match persist_cache(cache, path).await {
+                Ok(n) => tracing::info!("saved {n} cache entries to {}", path.display()),
+                Err(err) => tracing::warn!("{err}"),
+            }
+        }
+
+        // Every state is closed (or abandoned) by now, so the last copy
+        // sees every commit the server will ever make.
+        #[cfg(feature = "db")]
+        if let Some(replicator) = self.replicator.take() {
+            let _ = tokio::task::spawn_blocking(move || replicator.stop()).await;
+        }
+
         if drained {
             tracing::info!("drained cleanly, shutting down");
             Ok(())
@@ -511,26 +823,33 @@ impl ServerBuilder {
         let setup_fns = Arc::new(self.setup_fns);
         let modules = Arc::new(self.modules);
 
+        // Built once and shared by every state, including states built by
+        // a later reload.
+        let shared = Shared::new(&cfg, builtins)?;
+
         // Pending migrations stop the boot. Applying them here instead
         // would mean two instances rolling out at once race to change the
         // schema, each believing it is alone.
-        check_migrations(&cfg)?;
+        check_migrations(&cfg, &shared).await?;
+        #[cfg(feature = "db")]
+        let replicator = start_replicator(&cfg)?;
 
-        // Built once and shared by every state, including states built by
-        // a later reload: a cache that empties whenever the handler script
-        // changes is a cache that never warms.
-        let cache = builtins
-            .contains(nitr_std::Builtins::CACHE)
-            .then(|| nitr_std::Cache::new(cfg.cache_options()));
-
-        let runtimes = build_runtimes(&cfg, builtins, &setup_fns, &modules, cache.as_ref()).await?;
+        let runtimes = build_runtimes(&cfg, builtins, &setup_fns, &modules, &shared).await?;
+        let (schedules, _) = watch::channel(schedules_of(&runtimes)?);
+        let protection = Arc::new(Protection::new(&cfg, access_of(&runtimes)?)?);
+        #[cfg(feature = "db")]
+        let schedule_lease = if cfg.schedule.enabled {
+            cfg.open_schedule_lease()?
+        } else {
+            None
+        };
         let pool = new_pool(
             runtimes,
             &cfg,
             builtins,
             &setup_fns,
             &modules,
-            cache.clone(),
+            shared.clone(),
         );
 
         // Streaming responses hold a pooled state for their lifetime; by
@@ -540,7 +859,7 @@ impl ServerBuilder {
             .unwrap_or_else(|| cfg.workers.max(1).saturating_sub(1).max(1));
 
         Ok(Server {
-            protection: Arc::new(Protection::new(&cfg)),
+            protection,
             cfg,
             builtins,
             setup_fns,
@@ -550,7 +869,12 @@ impl ServerBuilder {
             max_streams,
             listener: self.listener,
             ready: Arc::new(AtomicBool::new(true)),
-            cache,
+            shared,
+            #[cfg(feature = "db")]
+            replicator,
+            schedules,
+            #[cfg(feature = "db")]
+            schedule_lease,
         })
     }
 }
@@ -612,6 +936,24 @@ pub(crate) fn current_pool(pool: &Arc<RwLock<Arc<RuntimePool>>>) -> Arc<RuntimeP
         .unwrap_or_else(|e| e.into_inner().clone())
 }
 
+/// The schedules of a freshly built set of runtimes, read from the first:
+/// every state compiled the same app.
+fn schedules_of(runtimes: &[Runtime]) -> Result<Arc<[Schedule]>> {
+    match runtimes.first() {
+        Some(rt) => Ok(app::schedules(rt.lua())?.into()),
+        None => Ok(Arc::new([])),
+    }
+}
+
+/// The route and mount access lists of the compiled app; every state
+/// compiles the same script, so the first one speaks for all.
+fn access_of(runtimes: &[Runtime]) -> Result<Arc<crate::access::AppAccess>> {
+    match runtimes.first() {
+        Some(rt) => app::access(rt.lua()),
+        None => Ok(Arc::default()),
+    }
+}
+
 /// Wraps the runtimes in a pool that can recycle a damaged state.
 ///
 /// The rebuild closure reproduces exactly what `build_runtimes` produces for
@@ -624,7 +966,7 @@ fn new_pool(
     builtins: Builtins,
     setup_fns: &Arc<Vec<SetupFn>>,
     modules: &Arc<Vec<Module>>,
-    cache: Option<nitr_std::Cache>,
+    shared: Shared,
 ) -> RuntimePool {
     // A rebuilt state needs the same configuration snapshot the others got.
     let snapshot = runtimes
@@ -634,8 +976,8 @@ fn new_pool(
     let setup_fns = setup_fns.clone();
     let modules = modules.clone();
     RuntimePool::with_rebuild(runtimes, move || {
-        let base_statics = crate::static_files::base_mounts(&cfg);
-        let mut rt = new_runtime(&cfg, builtins, &setup_fns, &modules, cache.as_ref())?;
+        let base_statics = crate::static_files::base_mounts(&cfg)?;
+        let mut rt = new_runtime(&cfg, builtins, &setup_fns, &modules, &shared)?;
         if let Some(snapshot) = &snapshot {
             rt.set_cfg_snapshot(snapshot)?;
         }
@@ -654,14 +996,14 @@ async fn build_runtimes(
     builtins: Builtins,
     setup_fns: &[SetupFn],
     modules: &[Module],
-    cache: Option<&nitr_std::Cache>,
+    shared: &Shared,
 ) -> Result<Vec<Runtime>> {
     let workers = cfg.workers.max(1);
-    let base_statics = crate::static_files::base_mounts(cfg);
+    let base_statics = crate::static_files::base_mounts(cfg)?;
     let base_statics = base_statics.as_slice();
 
     // Bootstrap state: runs the configuration script exactly once.
-    let mut bootstrap = new_runtime(cfg, builtins, setup_fns, modules, cache)?;
+    let mut bootstrap = new_runtime(cfg, builtins, setup_fns, modules, shared)?;
     let snapshot = match &cfg.config_script {
         Some(conf_src) => {
             // Pass the database connection to the config script when available.
@@ -685,7 +1027,7 @@ async fn build_runtimes(
     let mut runtimes = Vec::with_capacity(workers);
     runtimes.push(bootstrap);
     for _ in 1..workers {
-        let mut rt = new_runtime(cfg, builtins, setup_fns, modules, cache)?;
+        let mut rt = new_runtime(cfg, builtins, setup_fns, modules, shared)?;
         if let Some(snapshot) = &snapshot {
             rt.set_cfg_snapshot(snapshot)?;
         }
@@ -701,12 +1043,17 @@ fn new_runtime(
     builtins: Builtins,
     setup_fns: &[SetupFn],
     modules: &[Module],
-    cache: Option<&nitr_std::Cache>,
+    shared: &Shared,
 ) -> Result<Runtime> {
     let rt = Runtime::new_with(cfg.runtime_opts()?)?;
     let env = nitr_std::BuiltinsEnv {
         templates_dir: cfg.templating.dir.clone(),
-        database: cfg.database.as_ref().map(|db| db.path.clone()),
+        database: cfg
+            .database
+            .as_ref()
+            .and_then(|db| db.sqlite_path().cloned()),
+        #[cfg(feature = "postgres")]
+        postgres: shared.postgres.clone(),
         sqlite: cfg
             .database
             .as_ref()
@@ -714,7 +1061,23 @@ fn new_runtime(
             .unwrap_or_default(),
         fetch: cfg.fetch.options(),
         env: cfg.env_options(),
-        cache: cache.cloned(),
+        keys: shared.keys.clone(),
+        app_dir: Some(cfg.app_dir()),
+        cache: shared.cache.clone(),
+        #[cfg(feature = "db")]
+        kv: shared.kv.clone(),
+        #[cfg(feature = "db")]
+        jobs: shared.jobs.clone(),
+        #[cfg(feature = "db")]
+        changes: shared.changes.clone(),
+        pubsub: shared.pubsub.clone(),
+        sse: shared.sse.clone(),
+        slow_query: cfg.database.as_ref().and_then(|db| db.slow_query()),
+        search: cfg
+            .database
+            .as_ref()
+            .map(|db| db.search_indexes())
+            .unwrap_or_default(),
     };
     nitr_std::register_builtins(rt.lua(), builtins, &env)?;
     app::register_nitr_app(rt.lua())?;
@@ -74,13 +74,15 @@ impl Service<Request<Incoming>> for Svc {
         let id = protection.request_id(&req);
         // The per-request span: every tracing event below it (including
         // Lua `log.*` calls) carries the request id, method, and path;
-        // `status` is recorded when the response is built, so the span's
-        // close line doubles as an access log.
+        // `route` is recorded once routing matched and `status` when the
+        // response is built, so the span's close line doubles as an access
+        // log.
         let span = tracing::info_span!(
             "request",
             id = %id,
             method = %req.method(),
             path = %req.uri().path(),
+            route = tracing::field::Empty,
             status = tracing::field::Empty,
         );
         let req = LuaRequest {
@@ -94,6 +96,8 @@ impl Service<Request<Incoming>> for Svc {
             // Replaced with the configured bounds by the handler.
             limits: Default::default(),
             cached_form: None,
+            // Set by the handler from the `[headers]` policy.
+            csp_nonce: None,
         };
 
         Box::pin(
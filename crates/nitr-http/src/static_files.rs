@@ -10,6 +10,7 @@
 
 use std::convert::Infallible;
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use http_body_util::{BodyExt as _, Empty, Full, StreamBody};
@@ -20,7 +21,7 @@ use hyper::{Method, Response, StatusCode};
 use crate::compress::{Compression, Encoding};
 use crate::handler::HttpResponse;
 use crate::request::LuaRequest;
-use nitr_core::Result;
+use nitr_core::{Error, Result};
 
 /// Chunk size for streamed file bodies.
 const FILE_CHUNK: usize = 64 * 1024;
@@ -39,6 +40,8 @@ pub(crate) struct StaticMount {
     pub(crate) spa: bool,
     /// Explicit `Cache-Control` header value for served files.
     pub(crate) cache_control: Option<String>,
+    /// Client-IP rules for the mount, checked before checkout.
+    pub(crate) access: Option<Arc<crate::access::AccessList>>,
 }
 
 impl StaticMount {
@@ -60,11 +63,12 @@ impl StaticMount {
             dir: dir.into(),
             spa,
             cache_control,
+            access: None,
         }
     }
 
     /// The request path relative to this mount, when it applies.
-    fn relative<'p>(&self, path: &'p str) -> Option<&'p str> {
+    pub(crate) fn relative<'p>(&self, path: &'p str) -> Option<&'p str> {
         if self.mount == "/" {
             return Some(path.trim_start_matches('/'));
         }
@@ -79,19 +83,22 @@ impl StaticMount {
 
 /// The `[static]` configuration expressed as mounts (empty when no `dir`
 /// is configured).
-pub(crate) fn base_mounts(cfg: &crate::config::Config) -> Vec<StaticMount> {
-    cfg.static_files
-        .dir
-        .as_ref()
-        .map(|dir| {
-            vec![StaticMount::new(
-                cfg.static_files.mount.clone().unwrap_or_else(|| "/".into()),
-                dir.clone(),
-                cfg.static_files.spa,
-                cfg.static_files.cache_control.clone(),
-            )]
-        })
-        .unwrap_or_default()
+pub(crate) fn base_mounts(cfg: &crate::config::Config) -> Result<Vec<StaticMount>> {
+    let Some(dir) = &cfg.static_files.dir else {
+        return Ok(Vec::new());
+    };
+    let mut mount = StaticMount::new(
+        cfg.static_files.mount.clone().unwrap_or_else(|| "/".into()),
+        dir.clone(),
+        cfg.static_files.spa,
+        cfg.static_files.cache_control.clone(),
+    );
+    if !cfg.static_files.access.is_empty() {
+        let list = crate::access::AccessList::parse(&cfg.static_files.access)
+            .map_err(|err| Error::Config(format!("[static] access: {err}")))?;
+        mount.access = Some(Arc::new(list));
+    }
+    Ok(vec![mount])
 }
 
 /// Tries to serve the request from the given mounts (first match on the
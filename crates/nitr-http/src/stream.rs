@@ -13,16 +13,19 @@
 //!
 //! The Lua state stays checked out of the pool for the stream's lifetime:
 //! the pool guard moves into the producer task and returns on completion.
+//! A `nitr.sse.channel` response is the exception: its events come from
+//! Rust, so the state is released before the first byte is sent.
 
 use std::convert::Infallible;
 
 use http_body_util::{BodyExt as _, StreamBody};
+use hyper::StatusCode;
 use hyper::body::{Bytes, Frame};
-use mlua::{Function, LuaString, Table as LuaTable, UserData, UserDataMethods, Value};
+use mlua::{AnyUserData, Function, LuaString, Table as LuaTable, UserData, UserDataMethods, Value};
 use tokio::sync::OwnedSemaphorePermit;
 use tracing::Instrument as _;
 
-use crate::handler::build_response;
+use crate::handler::{build_response, plain_response};
 use nitr_core::{DeadlineHandle, Result, RuntimeGuard};
 
 type ChunkSender = async_channel::Sender<std::result::Result<Frame<Bytes>, Infallible>>;
@@ -69,11 +72,14 @@ pub(crate) fn stream_response(
         deadline: deadline.clone(),
     })?;
     let resp = build_response(lua_resp, StreamBody::new(rx).boxed())?;
+    // Builtins that wait on events (`db:changes()`) grant the stream a
+    // fresh budget through this handle when an event arrives.
+    rt.lua().set_app_data(deadline.clone());
 
     tokio::spawn(
         async move {
             // Held for the stream's lifetime; releasing it frees a stream slot.
-            let _permit = permit;
+            let permit = permit;
             match rt
                 .call_function_streaming::<Value>(body_fn.clone(), &writer)
                 .await
@@ -114,10 +120,15 @@ pub(crate) fn stream_response(
                     }
                 }
             }
+            // The runtime and the stream slot go back first: a client that
+            // sees the body end and immediately asks again must find both
+            // free, not race this task for them.
+            rt.lua().remove_app_data::<DeadlineHandle>();
+            drop(rt);
+            drop(permit);
             // The writer userdata inside the Lua state still holds a sender
             // clone until the GC collects it, so close the channel explicitly —
-            // this is what ends the response body. The runtime returns to the
-            // pool when `rt` drops.
+            // this is what ends the response body.
             tx.close();
         }
         .instrument(tracing::Span::current()),
@@ -125,3 +136,27 @@ pub(crate) fn stream_response(
 
     Ok(resp)
 }
+
+/// Serves a `nitr.sse.channel` response: joins the channel, gives the
+/// runtime back, and streams whatever the channel sends from then on.
+/// A client past `[sse] max_subscribers` gets a 503 instead.
+pub(crate) fn channel_response(
+    rt: RuntimeGuard,
+    lua_resp: &LuaTable,
+    body: &AnyUserData,
+) -> Result<super::handler::HttpResponse> {
+    let joined = body.borrow::<nitr_std::sse::ChannelRequest>()?.subscribe();
+    drop(rt);
+    let channel = match joined {
+        Ok(channel) => channel,
+        Err(reason) => {
+            tracing::warn!("channel stream rejected: {reason}");
+            return plain_response(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable");
+        }
+    };
+    let frames = futures_util::stream::unfold(channel, |mut channel| async move {
+        let bytes = channel.next().await?;
+        Some((Ok::<_, Infallible>(Frame::data(bytes)), channel))
+    });
+    build_response(lua_resp, StreamBody::new(frames).boxed())
+}
@@ -86,6 +86,8 @@ impl TestClient {
             // Replaced with the configured bounds by the handler.
             limits: Default::default(),
             cached_form: None,
+            // Set by the handler from the `[headers]` policy.
+            csp_nonce: None,
         };
 
         let pool = current_pool(&self.pool);
@@ -0,0 +1,352 @@
+//! Bearer-token authentication middleware: `nitr.auth.jwt({ ... })` is a
+//! factory for the composition model (`app:use(nitr.auth.jwt(opts))`)
+//! that verifies the `Authorization: Bearer` JWT in Rust and answers the
+//! RFC 6750 challenges itself, so a protected handler only ever sees
+//! requests that passed.
+//!
+//! The verified claims land at `req.ctx.claims`. Routes can demand OAuth
+//! scopes through their options table (`{ scopes = { "orders:read" } }`),
+//! read once when the chain is composed.
+
+use std::sync::Arc;
+
+use mlua::{Function, Lua, LuaSerdeExt as _, ObjectLike as _, Table, Value};
+use serde_json::Value as Json;
+
+use crate::crypto::{authorization, verify_keys};
+use crate::http;
+use crate::jwt;
+
+/// Everything the middleware needs per request, resolved once when the
+/// factory runs.
+struct Config {
+    keys: jwt::Keys,
+    policy: jwt::Policy,
+    /// The `realm` of the `WWW-Authenticate` challenge, when configured.
+    realm: Option<String>,
+    /// Pass requests without a token through, unauthenticated.
+    optional: bool,
+}
+
+/// How the request failed authentication (RFC 6750 §3.1).
+enum Challenge {
+    /// No credentials at all: a bare challenge, no error code.
+    Missing,
+    /// `Authorization: Bearer` with nothing usable after it.
+    InvalidRequest,
+    /// The token was rejected, for this reason.
+    InvalidToken(&'static str),
+    /// A valid token without every scope the route requires.
+    InsufficientScope(Arc<[String]>),
+}
+
+impl Challenge {
+    fn status(&self) -> u16 {
+        match self {
+            Self::Missing | Self::InvalidToken(_) => 401,
+            Self::InvalidRequest => 400,
+            Self::InsufficientScope(_) => 403,
+        }
+    }
+
+    /// The `WWW-Authenticate` value. Every interpolated string is either
+    /// a fixed reason or validated free of quotes when the factory runs.
+    fn header(&self, realm: Option<&str>) -> String {
+        let mut params = Vec::new();
+        if let Some(realm) = realm {
+            params.push(format!("realm=\"{realm}\""));
+        }
+        match self {
+            Self::Missing => {}
+            Self::InvalidRequest => params.push("error=\"invalid_request\"".into()),
+            Self::InvalidToken(reason) => {
+                params.push("error=\"invalid_token\"".into());
+                params.push(format!("error_description=\"{reason}\""));
+            }
+            Self::InsufficientScope(scopes) => {
+                params.push("error=\"insufficient_scope\"".into());
+                params.push(format!("scope=\"{}\"", scopes.join(" ")));
+            }
+        }
+        if params.is_empty() {
+            "Bearer".into()
+        } else {
+            format!("Bearer {}", params.join(", "))
+        }
+    }
+
+    fn body(&self) -> &'static str {
+        match self {
+            Self::Missing => "Unauthorized: a bearer token is required",
+            Self::InvalidRequest => "Bad Request: malformed Authorization header",
+            Self::InvalidToken(_) => "Unauthorized: invalid bearer token",
+            Self::InsufficientScope(_) => "Forbidden: insufficient scope",
+        }
+    }
+
+    fn response(&self, lua: &Lua, realm: Option<&str>) -> mlua::Result<Value> {
+        let resp = http::response_table(lua, self.status())?;
+        let headers: Table = resp.get("headers")?;
+        headers.set("WWW-Authenticate", self.header(realm))?;
+        headers.set("Content-Type", "text/plain; charset=utf-8")?;
+        resp.set("body", self.body())?;
+        Ok(Value::Table(resp))
+    }
+}
+
+/// The scopes a token was granted: the space-separated `scope` claim
+/// (RFC 8693), or an `scp` string or list as some providers issue.
+fn granted(claims: &Json) -> Vec<&str> {
+    match claims.get("scope").or_else(|| claims.get("scp")) {
+        Some(Json::String(scope)) => scope.split_whitespace().collect(),
+        Some(Json::Array(list)) => list.iter().filter_map(Json::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The token, or the challenge when there is none to verify. A header
+/// with another scheme counts as no token: the client may be speaking to
+/// a different authentication layer.
+fn bearer_token(req: &Value) -> mlua::Result<Result<String, Challenge>> {
+    let Some(header) = authorization(req)? else {
+        return Ok(Err(Challenge::Missing));
+    };
+    let header = header.trim();
+    let (scheme, token) = header.split_once(' ').unwrap_or((header, ""));
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Ok(Err(Challenge::Missing));
+    }
+    let token = token.trim();
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Ok(Err(Challenge::InvalidRequest));
+    }
+    Ok(Ok(token.to_owned()))
+}
+
+/// The middleware handler around one request.
+async fn handle(
+    lua: Lua,
+    config: Arc<Config>,
+    scopes: Arc<[String]>,
+    next: Function,
+    req: Value,
+) -> mlua::Result<Value> {
+    let Value::UserData(ud) = &req else {
+        return Err(mlua::Error::RuntimeError(format!(
+            "nitr.auth.jwt middleware expects the request object, got {}",
+            req.type_name()
+        )));
+    };
+    let realm = config.realm.as_deref();
+    let token = match bearer_token(&req)? {
+        Ok(token) => token,
+        // Without a token an optional route proceeds anonymously — unless
+        // it demands scopes, which only a token can carry.
+        Err(Challenge::Missing) if config.optional && scopes.is_empty() => {
+            return next.call_async(&req).await;
+        }
+        Err(challenge) => return challenge.response(&lua, realm),
+    };
+    let claims = match jwt::verify(&token, &config.keys, &config.policy).await {
+        Ok(claims) => claims,
+        Err(reason) => return Challenge::InvalidToken(reason).response(&lua, realm),
+    };
+    let have = granted(&claims);
+    if !scopes.iter().all(|s| have.contains(&s.as_str())) {
+        return Challenge::InsufficientScope(scopes).response(&lua, realm);
+    }
+    ud.get::<Table>("ctx")?
+        .set("claims", lua.to_value(&claims)?)?;
+    next.call_async(&req).await
+}
+
+/// Scopes given as one space-separated string or a list of them.
+pub(crate) fn scope_list(value: Value, whose: &str) -> mlua::Result<Vec<String>> {
+    let scopes: Vec<String> = match value {
+        Value::Nil => Vec::new(),
+        Value::String(s) => s.to_str()?.split_whitespace().map(str::to_owned).collect(),
+        Value::Table(list) => list
+            .sequence_values::<String>()
+            .collect::<mlua::Result<_>>()?,
+        other => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "{whose} `scopes` must be a string or a list of strings, got {}",
+                other.type_name()
+            )));
+        }
+    };
+    // RFC 6749 §3.3 scope tokens: printable ASCII minus space, `"` and `\`
+    // — which also keeps them safe inside the quoted challenge.
+    if let Some(bad) = scopes.iter().find(|s| {
+        s.is_empty()
+            || !s
+                .bytes()
+                .all(|b| matches!(b, 0x21 | 0x23..=0x5b | 0x5d..=0x7e))
+    }) {
+        return Err(mlua::Error::RuntimeError(format!(
+            "{whose} scope `{bad}` is not a valid OAuth scope token"
+        )));
+    }
+    Ok(scopes)
+}
+
+/// Builds `nitr.auth.jwt(opts)`, the middleware factory.
+pub(crate) fn create_jwt_middleware_fn(lua: &Lua) -> mlua::Result<Function> {
+    lua.create_function(|lua, opts: Table| {
+        for pair in opts.pairs::<String, Value>() {
+            let (key, _) = pair?;
+            if ![
+                "jwks",
+                "key",
+                "algorithms",
+                "iss",
+                "aud",
+                "leeway",
+                "realm",
+                "optional",
+                "scopes",
+            ]
+            .contains(&key.as_str())
+            {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "nitr.auth.jwt: unknown option `{key}`"
+                )));
+            }
+        }
+        let keys = match opts.get::<Value>("jwks")? {
+            Value::Nil => opts.get::<Value>("key")?,
+            jwks => jwks,
+        };
+        if keys.is_nil() {
+            return Err(mlua::Error::RuntimeError(
+                "nitr.auth.jwt requires `jwks` (a nitr.crypto.jwt.keyset(...)) or `key`".into(),
+            ));
+        }
+        let keys = verify_keys(keys)?;
+        let policy = crate::crypto::policy(&opts)?;
+        policy.check(&keys).map_err(mlua::Error::RuntimeError)?;
+        let realm: Option<String> = opts.get("realm")?;
+        // The realm goes out as a quoted string: no quote or backslash to
+        // end it early, and no control character (CR/LF least of all) to
+        // break the header.
+        if let Some(realm) = &realm
+            && realm.contains(|c: char| c == '"' || c == '\\' || c.is_control())
+        {
+            return Err(mlua::Error::RuntimeError(
+                "nitr.auth.jwt `realm` cannot contain `\"`, `\\` or control characters".into(),
+            ));
+        }
+        let base = scope_list(opts.get("scopes")?, "nitr.auth.jwt")?;
+        let config = Arc::new(Config {
+            keys,
+            policy,
+            realm,
+            optional: opts.get::<Option<bool>>("optional")?.unwrap_or(false),
+        });
+
+        // The factory the router composes: factory(next, route) -> handler.
+        // The route's own `scopes` add to the middleware-wide ones.
+        lua.create_function(move |lua, (next, route): (Function, Option<Table>)| {
+            let mut scopes = base.clone();
+            if let Some(route) = route
+                && let Some(options) = route.get::<Option<Table>>("options")?
+            {
+                let path: String = route.get::<Option<String>>("path")?.unwrap_or_default();
+                for scope in scope_list(options.get("scopes")?, &format!("route `{path}`"))? {
+                    if !scopes.contains(&scope) {
+                        scopes.push(scope);
+                    }
+                }
+            }
+            let scopes: Arc<[String]> = scopes.into();
+            let config = config.clone();
+            lua.create_async_function(move |lua, req: Value| {
+                handle(lua, config.clone(), scopes.clone(), next.clone(), req)
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_follow_rfc_6750() {
+        let realm = Some("api");
+        assert_eq!(Challenge::Missing.header(None), "Bearer");
+        assert_eq!(Challenge::Missing.header(realm), "Bearer realm=\"api\"");
+        assert_eq!(
+            Challenge::InvalidToken("token expired").header(realm),
+            "Bearer realm=\"api\", error=\"invalid_token\", \
+             error_description=\"token expired\""
+        );
+        let scopes: Arc<[String]> = vec!["a:read".to_owned(), "b".to_owned()].into();
+        let challenge = Challenge::InsufficientScope(scopes);
+        assert_eq!(challenge.status(), 403);
+        assert_eq!(
+            challenge.header(None),
+            "Bearer error=\"insufficient_scope\", scope=\"a:read b\""
+        );
+        assert_eq!(Challenge::InvalidRequest.status(), 400);
+    }
+
+    #[test]
+    fn granted_scopes_come_from_scope_or_scp() {
+        let claims = serde_json::json!({ "scope": "a  b\tc" });
+        assert_eq!(granted(&claims), ["a", "b", "c"]);
+        let claims = serde_json::json!({ "scp": ["x", "y"] });
+        assert_eq!(granted(&claims), ["x", "y"]);
+        assert!(granted(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn the_factory_validates_its_options_up_front() {
+        let lua = Lua::new();
+        let factory = create_jwt_middleware_fn(&lua).expect("fn");
+        for (opts, needle) in [
+            ("{ algorithms = { 'HS256' } }", "requires `jwks`"),
+            ("{ key = 'k' }", "algorithms"),
+            ("{ key = 'k', algorithms = { 'RS256' } }", "HMAC secret"),
+            (
+                "{ key = 'k', algorithms = { 'HS256' }, scope = 'x' }",
+                "unknown option",
+            ),
+            (
+                "{ key = 'k', algorithms = { 'HS256' }, scopes = { 'a\"b' } }",
+                "scope token",
+            ),
+            (
+                "{ key = 'k', algorithms = { 'HS256' }, realm = 'a\"b' }",
+                "realm",
+            ),
+            (
+                "{ key = 'k', algorithms = { 'HS256' }, realm = 'a\\r\\nX-Injected: 1' }",
+                "control characters",
+            ),
+            (
+                "{ key = 'k', algorithms = { 'HS256' }, realm = 'a\\0b' }",
+                "control characters",
+            ),
+            (
+                "{ key = 'k', algorithms = { 'HS256' }, realm = 'a\\127b' }",
+                "control characters",
+            ),
+        ] {
+            let opts: Table = lua.load(opts).eval().expect("opts");
+            let err = factory.call::<Value>(opts).expect_err(needle);
+            assert!(err.to_string().contains(needle), "{needle}: {err}");
+        }
+        let opts: Table = lua
+            .load("{ key = 'k', algorithms = { 'HS256' } }")
+            .eval()
+            .expect("opts");
+        let mw: Function = factory.call(opts).expect("middleware");
+        let next = lua.create_function(|_, v: Value| Ok(v)).expect("next");
+        let route: Table = lua
+            .load("{ path = '/x', options = { scopes = 'a b' } }")
+            .eval()
+            .expect("route");
+        let _handler: Function = mw.call((next, route)).expect("handler");
+    }
+}
@@ -0,0 +1,1252 @@
+//! `nitr.cache`: a bounded, Rust-owned cache shared by every pooled state.
+//!
+//! This is deliberately *not* a hole in the isolation model. Values are
+//! serialized on the way in and rebuilt on the way out, so no Lua value,
+//! function, or userdata ever crosses between states, and one state can
+//! never reach another's heap through it. Rust owns the memory and the
+//! bound, so the cache cannot grow without limit. It is shared **data**,
+//! not shared **state** — the same relationship Lua already has with
+//! SQLite.
+//!
+//! Two properties applications must know, because building on the opposite
+//! assumption fails quietly:
+//!
+//! - It lives in the process. A restart empties it (unless `[cache]
+//!   persist` snapshots it across the restart), and two Nitr processes
+//!   behind a load balancer have two independent caches. `incr`, `add`
+//!   and `cas` are atomic across the states of one process, not across
+//!   processes: counters and locks that must be exact, or must survive a
+//!   restart, belong in `nitr.kv`. Sessions only with
+//!   `nitr.session(req, { store = "cache" })`, which accepts that a
+//!   restart logs everyone out. `[cache] backend = "redis"` lifts this:
+//!   see [`redis`](self::redis) for what changes.
+//! - It survives a reload. A cache that empties every time the handler
+//!   script changes is a cache that never warms, which is worse than not
+//!   having one in development.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mlua::{ExternalResult as _, Lua, LuaSerdeExt as _, Table, UserData, UserDataMethods, Value};
+use tokio::sync::watch;
+
+mod redis;
+mod resp;
+mod snapshot;
+
+pub use redis::{CacheFallback, RedisOptions};
+use redis::{Cond, Failure, Put, Redis};
+
+/// Limits and defaults for the shared cache.
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// Maximum live entries before the least recently used is evicted.
+    pub max_entries: usize,
+    /// Maximum total size of stored values, in bytes.
+    pub max_bytes: u64,
+    /// Seconds an entry lives when `set` does not say; `0` means no expiry.
+    pub default_ttl: u64,
+}
+
+/// Most tags one entry may carry: tags name families of entries, one per
+/// record they were derived from, not arbitrary metadata.
+const MAX_TAGS: usize = 32;
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_bytes: 32 * 1024 * 1024,
+            default_ttl: 300,
+        }
+    }
+}
+
+struct Entry {
+    /// The value as JSON bytes: plain data, never a Lua handle.
+    value: Vec<u8>,
+    /// `None` for an entry that never expires.
+    expires_at: Option<Instant>,
+    /// From here until `expires_at` the entry is stale: `remember` serves
+    /// it while one caller refreshes it. `None` when it is never stale.
+    stale_from: Option<Instant>,
+    /// Monotonic tick of the last read or write, for LRU eviction.
+    touched: u64,
+    /// The tags `invalidate_tag` drops this entry by.
+    tags: Box<[String]>,
+}
+
+impl Entry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|at| at > now)
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        self.stale_from.is_some_and(|at| at <= now)
+    }
+}
+
+/// When a stored entry goes stale and when it is gone.
+#[derive(Clone, Copy)]
+struct Lifetime {
+    expires_at: Option<Instant>,
+    stale_from: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// The keys carrying each tag. Every removal goes through [`Inner::take`]
+    /// or [`Inner::remove_where`], which keep this index in step.
+    tagged: HashMap<String, HashSet<String>>,
+    bytes: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    /// `remember` calls answered by another caller's computation.
+    coalesced: u64,
+}
+
+impl Inner {
+    /// The entry under `key` if it is live. An expired entry is dropped on
+    /// the way past rather than left to be evicted later: it is dead
+    /// weight against both bounds.
+    fn live(&mut self, key: &str, now: Instant) -> Option<&mut Entry> {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| !entry.is_live(now))
+        {
+            self.take(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// Removes `key`, settling the byte count and the tag index.
+    fn take(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        // Saturating (here and at every debit): if the hand-kept counter
+        // ever drifts, a wrap near `u64::MAX` would make eviction expel
+        // everything forever; flooring at zero merely over-admits until
+        // entries cycle out.
+        self.bytes = self.bytes.saturating_sub(entry.value.len() as u64);
+        untag(&mut self.tagged, key, &entry.tags);
+        Some(entry)
+    }
+
+    /// Removes every entry `doomed` selects, returning how many went.
+    fn remove_where(&mut self, doomed: impl Fn(&str, &Entry) -> bool) -> usize {
+        let Inner {
+            entries,
+            tagged,
+            bytes,
+            ..
+        } = self;
+        let before = entries.len();
+        entries.retain(|key, entry| {
+            if !doomed(key, entry) {
+                return true;
+            }
+            *bytes = bytes.saturating_sub(entry.value.len() as u64);
+            untag(tagged, key, &entry.tags);
+            false
+        });
+        before - entries.len()
+    }
+}
+
+/// Drops `key` from the index of each of its `tags`.
+fn untag(tagged: &mut HashMap<String, HashSet<String>>, key: &str, tags: &[String]) {
+    for tag in tags {
+        if let Some(keys) = tagged.get_mut(tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                tagged.remove(tag);
+            }
+        }
+    }
+}
+
+/// The shared cache. Cloning shares the same storage; the server builds one
+/// and hands it to every state.
+#[derive(Clone)]
+pub struct Cache {
+    inner: Arc<Mutex<Inner>>,
+    opts: Arc<CacheOptions>,
+    /// Supplies the LRU ordering. A counter rather than a timestamp: it is
+    /// cheaper and cannot go backwards when the wall clock does.
+    clock: Arc<AtomicU64>,
+    flights: Flights,
+    /// Held for a whole snapshot write, so two never share the temporary
+    /// file and the later one always lands last.
+    saving: Arc<Mutex<()>>,
+    /// The server holding the entries with `backend = "redis"`; `inner`
+    /// then only keeps the counters `stats` reports.
+    remote: Option<Arc<Redis>>,
+}
+
+/// The `remember` computations in progress, by key. A later caller for the
+/// same key waits on the first one's result instead of computing its own,
+/// so an entry expiring under load is recomputed once, not once per state.
+type Flights = Arc<Mutex<HashMap<String, watch::Receiver<Option<Landed>>>>>;
+
+/// What a `remember` computation handed its waiters.
+#[derive(Clone)]
+enum Landed {
+    Value(Arc<[u8]>),
+    /// The function returned nil, which is not cached.
+    Nil,
+}
+
+/// A `remember` computation this caller leads. Dropping it — landed,
+/// failed or cancelled by the execution budget — releases the key, and
+/// waiters left without a result try again.
+struct Flight {
+    flights: Flights,
+    key: String,
+    tx: watch::Sender<Option<Landed>>,
+}
+
+impl Flight {
+    fn land(&self, landed: Landed) {
+        self.tx.send_replace(Some(landed));
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        if let Ok(mut flights) = self.flights.lock() {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+enum Join {
+    Lead(Flight),
+    Wait(watch::Receiver<Option<Landed>>),
+}
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("max_entries", &self.opts.max_entries)
+            .field("max_bytes", &self.opts.max_bytes)
+            .field("redis", &self.remote.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cache {
+    /// Creates an empty cache bounded by the given limits.
+    pub fn new(opts: CacheOptions) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::default())),
+            opts: Arc::new(opts),
+            clock: Arc::new(AtomicU64::new(0)),
+            flights: Flights::default(),
+            saving: Arc::default(),
+            remote: None,
+        }
+    }
+
+    /// Creates a cache whose entries live on a Redis server, shared by
+    /// every process pointed at it. Fails only on a malformed URL: the
+    /// server is first contacted when the cache is used.
+    pub fn redis(opts: CacheOptions, redis: RedisOptions) -> nitr_core::Result<Self> {
+        let remote = Redis::new(redis, opts.max_bytes)?;
+        Ok(Self {
+            remote: Some(Arc::new(remote)),
+            ..Self::new(opts)
+        })
+    }
+
+    fn lock(&self) -> mlua::Result<std::sync::MutexGuard<'_, Inner>> {
+        self.inner
+            .lock()
+            .map_err(|_| mlua::Error::RuntimeError("the cache lock is poisoned".into()))
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) async fn get_raw(&self, key: &str) -> mlua::Result<Option<Vec<u8>>> {
+        Ok(self.lookup(key).await?.map(|(value, _)| value))
+    }
+
+    /// The live value under `key`, and whether it is stale.
+    async fn lookup(&self, key: &str) -> mlua::Result<Option<(Vec<u8>, bool)>> {
+        if let Some(remote) = &self.remote {
+            let found = remote.lookup(key).await;
+            let found = remote.settle("get", found, true, None)?;
+            let mut inner = self.lock()?;
+            match found {
+                Some(_) => inner.hits += 1,
+                None => inner.misses += 1,
+            }
+            return Ok(found);
+        }
+        let now = Instant::now();
+        let touched = self.tick();
+        let mut inner = self.lock()?;
+
+        match inner.live(key, now) {
+            Some(entry) => {
+                entry.touched = touched;
+                let found = (entry.value.clone(), entry.is_stale(now));
+                inner.hits += 1;
+                Ok(Some(found))
+            }
+            None => {
+                inner.misses += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) async fn set_raw(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<u64>,
+    ) -> mlua::Result<()> {
+        let opts = WriteOpts {
+            ttl,
+            ..WriteOpts::default()
+        };
+        self.set_with(key, value, opts).await
+    }
+
+    /// [`Cache::set_raw`] with tags as well as a ttl.
+    async fn set_with(&self, key: String, value: Vec<u8>, opts: WriteOpts) -> mlua::Result<()> {
+        self.check_size(&key, &value)?;
+        if let Some(remote) = &self.remote {
+            let written = remote
+                .write(&key, Cond::Always, Some(self.remote_put(&value, &opts)))
+                .await;
+            return remote.settle("set", written.map(drop), true, ());
+        }
+        let life = self.lifetime(&opts);
+        let mut inner = self.lock()?;
+        self.put(&mut inner, key, value, life, opts.tags);
+        Ok(())
+    }
+
+    fn check_size(&self, key: &str, value: &[u8]) -> mlua::Result<()> {
+        let size = value.len() as u64;
+        if size > self.opts.max_bytes {
+            return Err(mlua::Error::RuntimeError(format!(
+                "cache value for `{key}` is {size} bytes, larger than the whole \
+                 {} byte cache",
+                self.opts.max_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// The lifetime of an entry written now: fresh for `opts.ttl` (the
+    /// default when unset; `0` for ever), then stale for `opts.stale`.
+    fn lifetime(&self, opts: &WriteOpts) -> Lifetime {
+        let ttl = opts.ttl.unwrap_or(self.opts.default_ttl);
+        if ttl == 0 {
+            return Lifetime {
+                expires_at: None,
+                stale_from: None,
+            };
+        }
+        let fresh_until = Instant::now() + Duration::from_secs(ttl);
+        match opts.stale {
+            0 => Lifetime {
+                expires_at: Some(fresh_until),
+                stale_from: None,
+            },
+            stale => Lifetime {
+                expires_at: Some(fresh_until + Duration::from_secs(stale)),
+                stale_from: Some(fresh_until),
+            },
+        }
+    }
+
+    /// What the Redis backend stores for a write: the lifetime in
+    /// milliseconds, which the server counts down on its own clock.
+    fn remote_put<'a>(&self, value: &'a [u8], opts: &'a WriteOpts) -> Put<'a> {
+        let ttl = opts.ttl.unwrap_or(self.opts.default_ttl);
+        Put {
+            value,
+            fresh_ms: ttl.saturating_mul(1000),
+            stale_ms: if ttl == 0 {
+                0
+            } else {
+                opts.stale.saturating_mul(1000)
+            },
+            tags: &opts.tags,
+        }
+    }
+
+    /// Stores `value` under `key` with the lock held, replacing what was
+    /// there, then brings the cache back inside its bounds.
+    fn put(
+        &self,
+        inner: &mut Inner,
+        key: String,
+        value: Vec<u8>,
+        life: Lifetime,
+        tags: Box<[String]>,
+    ) {
+        inner.take(&key);
+        inner.bytes += value.len() as u64;
+        for tag in &tags {
+            inner
+                .tagged
+                .entry(tag.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        inner.entries.insert(
+            key,
+            Entry {
+                value,
+                expires_at: life.expires_at,
+                stale_from: life.stale_from,
+                touched: self.tick(),
+                tags,
+            },
+        );
+        self.evict(inner);
+    }
+
+    /// Adds `by` to the integer under `key` and returns the sum. A missing
+    /// key counts from 0 and expires after `ttl`; an existing one keeps its
+    /// expiry and tags, so a counter with a ttl is a fixed window.
+    async fn incr(&self, key: &str, by: i64, ttl: Option<u64>) -> mlua::Result<i64> {
+        if let Some(remote) = &self.remote {
+            let ttl_ms = ttl.unwrap_or(self.opts.default_ttl).saturating_mul(1000);
+            return match remote.incr(key, by, ttl_ms).await {
+                Err(Failure::Server(reason)) if reason.contains("not an integer") => {
+                    Err(mlua::Error::RuntimeError(format!(
+                        "cache:incr(`{key}`): the stored value is not an integer"
+                    )))
+                }
+                Err(Failure::Server(reason)) if reason.contains("overflow") => Err(
+                    mlua::Error::RuntimeError(format!("cache:incr(`{key}`) overflows")),
+                ),
+                counted => remote.settle("incr", counted, false, 0),
+            };
+        }
+        let fresh = self.lifetime(&WriteOpts {
+            ttl,
+            ..WriteOpts::default()
+        });
+        let mut inner = self.lock()?;
+        let (current, life, tags) = match inner.live(key, Instant::now()) {
+            Some(entry) => {
+                let current = std::str::from_utf8(&entry.value)
+                    .ok()
+                    .and_then(|text| text.parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        mlua::Error::RuntimeError(format!(
+                            "cache:incr(`{key}`): the stored value is not an integer"
+                        ))
+                    })?;
+                let life = Lifetime {
+                    expires_at: entry.expires_at,
+                    stale_from: entry.stale_from,
+                };
+                (current, life, entry.tags.clone())
+            }
+            None => (0, fresh, Box::default()),
+        };
+        let next = current
+            .checked_add(by)
+            .ok_or_else(|| mlua::Error::RuntimeError(format!("cache:incr(`{key}`) overflows")))?;
+        let value = next.to_string().into_bytes();
+        self.put(&mut inner, key.to_owned(), value, life, tags);
+        Ok(next)
+    }
+
+    /// Stores `value` only when `key` holds no live entry, returning
+    /// whether it did: the building block for a lock flag.
+    async fn add(&self, key: String, value: Vec<u8>, opts: WriteOpts) -> mlua::Result<bool> {
+        self.check_size(&key, &value)?;
+        if let Some(remote) = &self.remote {
+            let added = remote
+                .write(&key, Cond::Absent, Some(self.remote_put(&value, &opts)))
+                .await;
+            return Ok(remote.settle("add", added, false, 0)? == 1);
+        }
+        let life = self.lifetime(&opts);
+        let mut inner = self.lock()?;
+        if inner.live(&key, Instant::now()).is_some() {
+            return Ok(false);
+        }
+        self.put(&mut inner, key, value, life, opts.tags);
+        Ok(true)
+    }
+
+    /// Replaces the entry under `key` when its value equals `expected`
+    /// (`None`: when there is none), returning whether it did. A `new` of
+    /// `None` deletes the entry.
+    async fn cas(
+        &self,
+        key: String,
+        expected: Option<&serde_json::Value>,
+        new: Option<(Vec<u8>, WriteOpts)>,
+    ) -> mlua::Result<bool> {
+        if let Some((value, _)) = &new {
+            self.check_size(&key, value)?;
+        }
+        if let Some(remote) = &self.remote {
+            // Read, compare here, then write only if the bytes read are
+            // still there: a value changed in between is compared again.
+            loop {
+                let current = remote.value(&key).await;
+                let current = remote.settle("cas", current, false, None)?;
+                if !holds(current.as_deref(), expected) {
+                    return Ok(false);
+                }
+                let cond = match &current {
+                    Some(bytes) => Cond::Equal(bytes),
+                    None if new.is_none() => return Ok(true),
+                    None => Cond::Absent,
+                };
+                let put = new
+                    .as_ref()
+                    .map(|(value, opts)| self.remote_put(value, opts));
+                let swapped = remote.write(&key, cond, put).await;
+                if remote.settle("cas", swapped, false, 0)? != 0 {
+                    return Ok(true);
+                }
+            }
+        }
+        let mut inner = self.lock()?;
+        let current = inner
+            .live(&key, Instant::now())
+            .map(|entry| entry.value.as_slice());
+        if !holds(current, expected) {
+            return Ok(false);
+        }
+        match new {
+            Some((value, opts)) => {
+                let life = self.lifetime(&opts);
+                self.put(&mut inner, key, value, life, opts.tags);
+            }
+            None => {
+                inner.take(&key);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Joins the `remember` computation in progress for `key`, or starts
+    /// one led by the caller.
+    fn join(&self, key: &str) -> mlua::Result<Join> {
+        let mut flights = self
+            .flights
+            .lock()
+            .map_err(|_| mlua::Error::RuntimeError("the cache lock is poisoned".into()))?;
+        if let Some(rx) = flights.get(key) {
+            return Ok(Join::Wait(rx.clone()));
+        }
+        let (tx, rx) = watch::channel(None);
+        flights.insert(key.to_owned(), rx);
+        Ok(Join::Lead(Flight {
+            flights: self.flights.clone(),
+            key: key.to_owned(),
+            tx,
+        }))
+    }
+
+    /// Drops every entry tagged `tag`, returning how many went.
+    async fn invalidate_tag(&self, tag: &str) -> mlua::Result<usize> {
+        if let Some(remote) = &self.remote {
+            let dropped = remote.invalidate_tag(tag).await;
+            return Ok(remote.settle("invalidate_tag", dropped, false, 0)? as usize);
+        }
+        let mut inner = self.lock()?;
+        let Some(keys) = inner.tagged.remove(tag) else {
+            return Ok(0);
+        };
+        Ok(keys.iter().filter(|key| inner.take(key).is_some()).count())
+    }
+
+    /// Drops `key`, returning whether it was there.
+    pub(crate) async fn remove(&self, key: &str) -> mlua::Result<bool> {
+        if let Some(remote) = &self.remote {
+            let removed = remote.write(key, Cond::Always, None).await;
+            return Ok(remote.settle("delete", removed, false, 0)? == 1);
+        }
+        Ok(self.lock()?.take(key).is_some())
+    }
+
+    /// Drops every entry.
+    async fn clear(&self) -> mlua::Result<()> {
+        if let Some(remote) = &self.remote {
+            let cleared = remote.clear().await;
+            return remote.settle("clear", cleared.map(drop), false, ());
+        }
+        let mut inner = self.lock()?;
+        inner.entries.clear();
+        inner.tagged.clear();
+        inner.bytes = 0;
+        Ok(())
+    }
+
+    /// Drops every entry under `prefix` whose value `matches`, returning
+    /// how many went. A full scan, for rare whole-cache operations such as
+    /// revoking every session of one user.
+    pub(crate) async fn remove_matching(
+        &self,
+        prefix: &str,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> mlua::Result<usize> {
+        if let Some(remote) = &self.remote {
+            let keys = remote.keys(prefix).await;
+            let mut removed = 0;
+            for key in remote.settle("delete", keys, false, Vec::new())? {
+                let value = remote.value(&key).await;
+                let Some(value) = remote.settle("delete", value, false, None)? else {
+                    continue;
+                };
+                if matches(&value) {
+                    // Only if unchanged since it was matched.
+                    let gone = remote.write(&key, Cond::Equal(&value), None).await;
+                    removed += remote.settle("delete", gone, false, 0)? as usize;
+                }
+            }
+            return Ok(removed);
+        }
+        let mut inner = self.lock()?;
+        Ok(inner.remove_where(|key, entry| key.starts_with(prefix) && matches(&entry.value)))
+    }
+
+    /// Brings the cache back inside both bounds, dropping expired entries
+    /// first and then the least recently used.
+    fn evict(&self, inner: &mut Inner) {
+        let now = Instant::now();
+        inner.remove_where(|_, entry| !entry.is_live(now));
+
+        while inner.entries.len() > self.opts.max_entries || inner.bytes > self.opts.max_bytes {
+            let Some(victim) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.touched)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if inner.take(&victim).is_some() {
+                inner.evictions += 1;
+            }
+        }
+    }
+}
+
+impl UserData for Cache {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        // cache:get(key) -> value | nil
+        methods.add_async_method("get", |lua, cache, key: String| async move {
+            match cache.get_raw(&key).await? {
+                Some(bytes) => decode(&lua, &bytes),
+                None => Ok(Value::Nil),
+            }
+        });
+
+        // cache:set(key, value, opts?) — opts is { ttl = seconds, tags = {...} }.
+        methods.add_async_method(
+            "set",
+            |_, cache, (key, value, opts): (String, Value, Option<Table>)| async move {
+                let opts = write_opts(opts.as_ref())?;
+                let bytes = encode(&key, &value)?;
+                cache.set_with(key, bytes, opts).await
+            },
+        );
+
+        // cache:add(key, value, opts?) -> whether it stored: set only if
+        // absent, so exactly one state wins a lock flag.
+        methods.add_async_method(
+            "add",
+            |_, cache, (key, value, opts): (String, Value, Option<Table>)| async move {
+                let opts = write_opts(opts.as_ref())?;
+                let bytes = encode(&key, &value)?;
+                cache.add(key, bytes, opts).await
+            },
+        );
+
+        // cache:incr(key, by?, opts?) -> the new value. opts.ttl applies
+        // only when the key is created.
+        methods.add_async_method(
+            "incr",
+            |_, cache, (key, by, opts): (String, Option<i64>, Option<Table>)| async move {
+                let ttl = write_opts(opts.as_ref())?.ttl;
+                cache.incr(&key, by.unwrap_or(1), ttl).await
+            },
+        );
+
+        // cache:cas(key, expected, new, opts?) -> whether it swapped. An
+        // `expected` of nil means "absent"; a `new` of nil deletes.
+        methods.add_async_method(
+            "cas",
+            |_, cache, (key, expected, new, opts): (String, Value, Value, Option<Table>)| async move {
+                let expected = match expected {
+                    Value::Nil => None,
+                    expected => {
+                        crate::utils::check_json_depth(&expected)?;
+                        Some(serde_json::to_value(&expected).map_err(|err| {
+                            mlua::Error::RuntimeError(format!(
+                                "cache:cas expected value for `{key}` is not plain data: {err}"
+                            ))
+                        })?)
+                    }
+                };
+                let new = match new {
+                    Value::Nil => None,
+                    new => Some((encode(&key, &new)?, write_opts(opts.as_ref())?)),
+                };
+                cache.cas(key, expected.as_ref(), new).await
+            },
+        );
+
+        // cache:delete(key) -> whether it was there
+        methods.add_async_method("delete", |_, cache, key: String| async move {
+            cache.remove(&key).await
+        });
+
+        // cache:invalidate_tag(tag) -> how many entries went
+        methods.add_async_method("invalidate_tag", |_, cache, tag: String| async move {
+            cache.invalidate_tag(&tag).await
+        });
+
+        methods.add_async_method("clear", |_, cache, ()| async move { cache.clear().await });
+
+        // cache:remember(key, opts?, fn) — get, or compute and store.
+        //
+        // The common shape, worth having as one call: written by hand it is
+        // three lines that are easy to get subtly wrong (caching a nil,
+        // forgetting the TTL), and it cannot coalesce concurrent misses.
+        // With `stale`, an entry past its ttl is still served for that
+        // many seconds while one caller refreshes it; a refresh that fails
+        // serves the stale value too.
+        methods.add_async_method(
+            "remember",
+            |lua, cache, (key, arg, f): (String, Value, Option<mlua::Function>)| async move {
+                // remember(key, fn) and remember(key, opts, fn) both work.
+                let (opts, f) = match (arg, f) {
+                    (Value::Function(f), None) => (None, f),
+                    (Value::Table(opts), Some(f)) => (Some(opts), f),
+                    _ => {
+                        return Err(mlua::Error::RuntimeError(
+                            "cache:remember expects (key, fn) or (key, opts, fn)".into(),
+                        ));
+                    }
+                };
+                let mut write = write_opts(opts.as_ref())?;
+                if let Some(opts) = &opts {
+                    write.stale = opts.get::<Option<u64>>("stale")?.unwrap_or(0);
+                }
+                loop {
+                    let stale = match cache.lookup(&key).await? {
+                        Some((bytes, false)) => return decode(&lua, &bytes),
+                        Some((bytes, true)) => Some(bytes),
+                        None => None,
+                    };
+                    let flight = match cache.join(&key)? {
+                        Join::Lead(flight) => flight,
+                        Join::Wait(mut rx) => {
+                            // Another caller is refreshing; the stale value
+                            // serves meanwhile.
+                            if let Some(bytes) = &stale {
+                                return decode(&lua, bytes);
+                            }
+                            let landed = rx.wait_for(Option::is_some).await.map(|l| l.clone());
+                            // Without a result the computation failed or
+                            // was cancelled: look again, perhaps leading.
+                            let Ok(Some(landed)) = landed else {
+                                continue;
+                            };
+                            cache.lock()?.coalesced += 1;
+                            return match landed {
+                                Landed::Value(bytes) => decode(&lua, &bytes),
+                                Landed::Nil => Ok(Value::Nil),
+                            };
+                        }
+                    };
+                    let value: Value = match f.call_async(()).await {
+                        Ok(value) => value,
+                        Err(err) => match &stale {
+                            Some(bytes) => {
+                                tracing::warn!(
+                                    key = %key,
+                                    error = %err,
+                                    "cache:remember refresh failed; serving the stale value"
+                                );
+                                return decode(&lua, bytes);
+                            }
+                            None => return Err(err),
+                        },
+                    };
+                    // A nil result is not cached: it would be
+                    // indistinguishable from a miss on the way out, so
+                    // every later call would run the function anyway while
+                    // occupying an entry.
+                    if value.is_nil() {
+                        flight.land(Landed::Nil);
+                        return Ok(Value::Nil);
+                    }
+                    crate::utils::check_json_depth(&value)?;
+                    let bytes = serde_json::to_vec(&value).map_err(|err| {
+                        mlua::Error::RuntimeError(format!(
+                            "cache:remember value for `{key}` is not serializable: {err}"
+                        ))
+                    })?;
+                    cache.set_with(key, bytes.clone(), write).await?;
+                    flight.land(Landed::Value(bytes.into()));
+                    return Ok(value);
+                }
+            },
+        );
+
+        // cache:stats() — entries, bytes, hits, misses, evictions, coalesced.
+        // With Redis, the counters are this process's, and the server's own
+        // size and evictions are its business (`INFO`): `fallbacks` counts
+        // the calls answered without it instead.
+        methods.add_method("stats", |lua, cache, ()| {
+            let inner = cache.lock()?;
+            let table = lua.create_table()?;
+            table.set("hits", inner.hits)?;
+            table.set("misses", inner.misses)?;
+            table.set("coalesced", inner.coalesced)?;
+            table.set("max_bytes", cache.opts.max_bytes)?;
+            if let Some(remote) = &cache.remote {
+                table.set("backend", "redis")?;
+                table.set("fallbacks", remote.fallbacks.load(Ordering::Relaxed))?;
+                return Ok(table);
+            }
+            table.set("backend", "memory")?;
+            table.set("entries", inner.entries.len())?;
+            table.set("bytes", inner.bytes)?;
+            table.set("evictions", inner.evictions)?;
+            table.set("max_entries", cache.opts.max_entries)?;
+            Ok(table)
+        });
+    }
+}
+
+/// The `{ ttl?, tags? }` options the writing methods take.
+#[derive(Default)]
+struct WriteOpts {
+    /// Seconds to live; `None` for the configured default.
+    ttl: Option<u64>,
+    /// Seconds past `ttl` that `remember` still serves the entry while
+    /// refreshing it. Read by `remember` only.
+    stale: u64,
+    tags: Box<[String]>,
+}
+
+fn write_opts(opts: Option<&Table>) -> mlua::Result<WriteOpts> {
+    let Some(opts) = opts else {
+        return Ok(WriteOpts::default());
+    };
+    let tags = opts.get::<Option<Vec<String>>>("tags")?.unwrap_or_default();
+    if tags.len() > MAX_TAGS {
+        return Err(mlua::Error::RuntimeError(format!(
+            "a cache entry takes at most {MAX_TAGS} tags, got {}",
+            tags.len()
+        )));
+    }
+    Ok(WriteOpts {
+        ttl: opts.get("ttl")?,
+        stale: 0,
+        tags: tags.into_boxed_slice(),
+    })
+}
+
+/// Whether the stored bytes hold `expected` (`None`: nothing stored).
+/// Compared as JSON values, not bytes: a table's keys serialize in
+/// whatever order Lua iterates them.
+fn holds(current: Option<&[u8]>, expected: Option<&serde_json::Value>) -> bool {
+    match (current, expected) {
+        (None, None) => true,
+        (Some(bytes), Some(expected)) => serde_json::from_slice::<serde_json::Value>(bytes)
+            .is_ok_and(|current| current == *expected),
+        _ => false,
+    }
+}
+
+fn decode(lua: &Lua, bytes: &[u8]) -> mlua::Result<Value> {
+    let json: serde_json::Value = serde_json::from_slice(bytes).into_lua_err()?;
+    lua.to_value(&json)
+}
+
+/// Serializes a value for storage. This is what keeps states isolated,
+/// and why a function or userdata cannot be cached.
+fn encode(key: &str, value: &Value) -> mlua::Result<Vec<u8>> {
+    crate::utils::check_json_depth(value)?;
+    serde_json::to_vec(value).map_err(|err| {
+        mlua::Error::RuntimeError(format!(
+            "cache values must be plain data (a table, string, number or \
+             boolean); `{key}` is not serializable: {err}"
+        ))
+    })
+}
+
+/// Builds the `nitr.cache` handle for one state over the shared storage.
+pub(crate) fn create_cache(lua: &Lua, cache: Cache) -> mlua::Result<mlua::AnyUserData> {
+    lua.create_userdata(cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(opts: CacheOptions) -> Cache {
+        Cache::new(opts)
+    }
+
+    /// A chain of `depth` nested Lua tables (the root included).
+    fn deep_table(lua: &Lua, depth: usize) -> Value {
+        let root = lua.create_table().expect("table");
+        let mut cur = root.clone();
+        for _ in 1..depth {
+            let next = lua.create_table().expect("table");
+            cur.set("x", next.clone()).expect("set");
+            cur = next;
+        }
+        Value::Table(root)
+    }
+
+    /// `cache:set` serializes script values: without the depth guard a
+    /// deep chain recursed to a stack-overflow abort here too.
+    #[test]
+    fn set_rejects_values_nested_beyond_the_json_depth_bound() {
+        let lua = Lua::new();
+        let ud = lua
+            .create_userdata(cache(CacheOptions::default()))
+            .expect("ud");
+        lua.globals().set("cache", ud).expect("global");
+        lua.globals()
+            .set("deep", deep_table(&lua, 129))
+            .expect("global");
+        let err: String = lua
+            .load(
+                r#"local ok, err = pcall(function() cache:set("k", deep) end)
+                   assert(not ok)
+                   return tostring(err)"#,
+            )
+            .eval()
+            .expect("eval");
+        assert!(err.contains("nested deeper than 128 levels"), "got: {err}");
+    }
+
+    #[tokio::test]
+    async fn values_round_trip_and_count_against_the_bounds() {
+        let c = cache(CacheOptions::default());
+        c.set_raw("a".into(), b"{\"n\":1}".to_vec(), None)
+            .await
+            .expect("set");
+        assert_eq!(
+            c.get_raw("a").await.expect("get").as_deref(),
+            Some(&b"{\"n\":1}"[..])
+        );
+        assert_eq!(c.get_raw("missing").await.expect("get"), None);
+
+        let inner = c.inner.lock().expect("lock");
+        assert_eq!(inner.entries.len(), 1);
+        assert_eq!(inner.bytes, 7);
+        assert_eq!((inner.hits, inner.misses), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn overwriting_a_key_does_not_double_count_its_bytes() {
+        let c = cache(CacheOptions::default());
+        c.set_raw("k".into(), vec![b'x'; 100], None)
+            .await
+            .expect("set");
+        c.set_raw("k".into(), vec![b'y'; 10], None)
+            .await
+            .expect("overwrite");
+        let inner = c.inner.lock().expect("lock");
+        assert_eq!(inner.entries.len(), 1);
+        assert_eq!(inner.bytes, 10);
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_entry_is_evicted_first() {
+        let c = cache(CacheOptions {
+            max_entries: 2,
+            ..Default::default()
+        });
+        c.set_raw("a".into(), b"1".to_vec(), None)
+            .await
+            .expect("set a");
+        c.set_raw("b".into(), b"2".to_vec(), None)
+            .await
+            .expect("set b");
+        // Touch `a`, so `b` becomes the least recently used.
+        c.get_raw("a").await.expect("get a");
+        c.set_raw("c".into(), b"3".to_vec(), None)
+            .await
+            .expect("set c");
+
+        assert!(c.get_raw("a").await.expect("a").is_some());
+        assert!(
+            c.get_raw("b").await.expect("b").is_none(),
+            "b must be evicted"
+        );
+        assert!(c.get_raw("c").await.expect("c").is_some());
+    }
+
+    #[tokio::test]
+    async fn the_byte_bound_evicts_too() {
+        let c = cache(CacheOptions {
+            max_bytes: 100,
+            ..Default::default()
+        });
+        c.set_raw("a".into(), vec![b'x'; 60], None)
+            .await
+            .expect("set a");
+        c.set_raw("b".into(), vec![b'x'; 60], None)
+            .await
+            .expect("set b");
+
+        let inner = c.inner.lock().expect("lock");
+        assert!(inner.bytes <= 100, "bytes must stay within the bound");
+        assert_eq!(inner.entries.len(), 1);
+        assert_eq!(inner.evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn a_value_larger_than_the_whole_cache_is_refused() {
+        let c = cache(CacheOptions {
+            max_bytes: 10,
+            ..Default::default()
+        });
+        let err = c
+            .set_raw("big".into(), vec![b'x'; 11], None)
+            .await
+            .expect_err("must be refused");
+        assert!(err.to_string().contains("larger than"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn an_expired_entry_reads_as_a_miss_and_frees_its_bytes() {
+        let c = cache(CacheOptions::default());
+        // A zero TTL means "no expiry", so expiry is set directly here.
+        c.set_raw("k".into(), vec![b'x'; 50], Some(1))
+            .await
+            .expect("set");
+        {
+            let mut inner = c.inner.lock().expect("lock");
+            let entry = inner.entries.get_mut("k").expect("entry");
+            entry.expires_at = Some(Instant::now() - Duration::from_secs(1));
+        }
+        assert!(c.get_raw("k").await.expect("get").is_none());
+        assert_eq!(c.inner.lock().expect("lock").bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn incr_counts_from_zero_and_keeps_the_window() {
+        let c = cache(CacheOptions::default());
+        assert_eq!(c.incr("n", 1, Some(60)).await.expect("incr"), 1);
+        let window = c.inner.lock().expect("lock").entries["n"].expires_at;
+        assert_eq!(c.incr("n", 5, Some(1)).await.expect("incr"), 6);
+        assert_eq!(
+            c.inner.lock().expect("lock").entries["n"].expires_at,
+            window
+        );
+        assert_eq!(
+            c.get_raw("n").await.expect("get").as_deref(),
+            Some(&b"6"[..])
+        );
+
+        c.set_raw("s".into(), b"\"x\"".to_vec(), None)
+            .await
+            .expect("set");
+        let err = c.incr("s", 1, None).await.expect_err("not an integer");
+        assert!(err.to_string().contains("not an integer"), "{err}");
+        c.set_raw("max".into(), i64::MAX.to_string().into_bytes(), None)
+            .await
+            .expect("set");
+        assert!(c.incr("max", 1, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn add_and_cas_only_write_on_a_match() {
+        let c = cache(CacheOptions::default());
+        assert!(
+            c.add("lock".into(), b"1".to_vec(), WriteOpts::default())
+                .await
+                .expect("add")
+        );
+        assert!(
+            !c.add("lock".into(), b"2".to_vec(), WriteOpts::default())
+                .await
+                .expect("add")
+        );
+
+        // Table keys may serialize in any order; the comparison is by value.
+        c.set_raw("v".into(), br#"{"a":1,"b":2}"#.to_vec(), None)
+            .await
+            .expect("set");
+        let expected = serde_json::json!({ "b": 2, "a": 1 });
+        let swap = || Some((b"3".to_vec(), WriteOpts::default()));
+        assert!(
+            c.cas("v".into(), Some(&expected), swap())
+                .await
+                .expect("cas")
+        );
+        assert!(
+            !c.cas("v".into(), Some(&expected), swap())
+                .await
+                .expect("stale")
+        );
+        assert!(!c.cas("v".into(), None, swap()).await.expect("not absent"));
+        assert!(
+            c.cas("v".into(), Some(&serde_json::json!(3)), None)
+                .await
+                .expect("delete")
+        );
+        assert!(c.get_raw("v").await.expect("get").is_none());
+        assert!(c.cas("v".into(), None, swap()).await.expect("absent"));
+    }
+
+    #[tokio::test]
+    async fn tags_drop_their_entries_and_follow_every_removal() {
+        let c = cache(CacheOptions {
+            max_entries: 3,
+            ..Default::default()
+        });
+        let tags = |tags: &[&str]| WriteOpts {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..WriteOpts::default()
+        };
+        c.set_with("a".into(), b"1".to_vec(), tags(&["user:1"]))
+            .await
+            .expect("a");
+        c.set_with("b".into(), b"2".to_vec(), tags(&["user:1", "team:9"]))
+            .await
+            .expect("b");
+        c.set_with("c".into(), b"3".to_vec(), tags(&["team:9"]))
+            .await
+            .expect("c");
+
+        assert_eq!(c.invalidate_tag("user:1").await.expect("invalidate"), 2);
+        assert!(c.get_raw("a").await.expect("a").is_none());
+        assert!(c.get_raw("c").await.expect("c").is_some());
+        assert_eq!(c.invalidate_tag("user:1").await.expect("again"), 0);
+
+        // Overwriting, deleting and evicting all leave the index clean.
+        c.set_raw("c".into(), b"4".to_vec(), None)
+            .await
+            .expect("overwrite");
+        assert_eq!(c.invalidate_tag("team:9").await.expect("invalidate"), 0);
+        for key in ["d", "e", "f", "g"] {
+            c.set_with(key.into(), b"5".to_vec(), tags(&["x"]))
+                .await
+                .expect("set");
+        }
+        c.remove("g").await.expect("remove");
+        let inner = c.inner.lock().expect("lock");
+        let indexed: usize = inner.tagged.values().map(HashSet::len).sum();
+        let carried: usize = inner.entries.values().map(|e| e.tags.len()).sum();
+        assert_eq!(indexed, carried);
+    }
+
+    /// A state whose `compute(v)` sleeps 50ms, counts its runs in `runs`
+    /// and returns `v`, beside a `cache` global over `c`.
+    fn remember_state(c: &Cache, runs: &Arc<AtomicU64>) -> Lua {
+        let lua = Lua::new();
+        let runs = runs.clone();
+        let compute = lua
+            .create_async_function(move |_, value: Value| {
+                let runs = runs.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    Ok(value)
+                }
+            })
+            .expect("compute");
+        let globals = lua.globals();
+        globals.set("compute", compute).expect("global");
+        globals
+            .set("cache", lua.create_userdata(c.clone()).expect("ud"))
+            .expect("global");
+        lua
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_compute_once() {
+        let c = cache(CacheOptions::default());
+        let runs = Arc::new(AtomicU64::new(0));
+        let (a, b) = (remember_state(&c, &runs), remember_state(&c, &runs));
+        let code = r#"return cache:remember("k", { ttl = 60 }, function() return compute(7) end)"#;
+        let (x, y) = tokio::join!(
+            a.load(code).eval_async::<i64>(),
+            b.load(code).eval_async::<i64>()
+        );
+        assert_eq!((x.expect("a"), y.expect("b")), (7, 7));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(c.inner.lock().expect("lock").coalesced, 1);
+
+        // A nil result is handed over too, though it is not stored.
+        let code = r#"return cache:remember("nil", function() return compute(nil) end)"#;
+        let (x, y) = tokio::join!(
+            a.load(code).eval_async::<Value>(),
+            b.load(code).eval_async::<Value>()
+        );
+        assert!(x.expect("a").is_nil() && y.expect("b").is_nil());
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_entries_serve_while_one_caller_refreshes() {
+        let c = cache(CacheOptions::default());
+        let runs = Arc::new(AtomicU64::new(0));
+        let (a, b) = (remember_state(&c, &runs), remember_state(&c, &runs));
+        let remember = |v: i64| {
+            format!(
+                r#"return cache:remember("k", {{ ttl = 60, stale = 300 }}, function() return compute({v}) end)"#
+            )
+        };
+        assert_eq!(
+            a.load(remember(1)).eval_async::<i64>().await.expect("fill"),
+            1
+        );
+        let age = || {
+            let mut inner = c.inner.lock().expect("lock");
+            inner.entries.get_mut("k").expect("entry").stale_from = Some(Instant::now());
+        };
+
+        // One caller refreshes; the other is answered at once, stale.
+        age();
+        let (x, y) = tokio::join!(
+            a.load(remember(2)).eval_async::<i64>(),
+            b.load(remember(3)).eval_async::<i64>()
+        );
+        assert_eq!((x.expect("a"), y.expect("b")), (2, 1));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // A refresh that fails serves the stale value instead.
+        age();
+        let failing = r#"return cache:remember("k", { ttl = 60, stale = 300 }, function() error("down") end)"#;
+        assert_eq!(a.load(failing).eval_async::<i64>().await.expect("stale"), 2);
+    }
+
+    #[tokio::test]
+    async fn a_zero_ttl_never_expires() {
+        let c = cache(CacheOptions {
+            default_ttl: 0,
+            ..Default::default()
+        });
+        c.set_raw("k".into(), b"v".to_vec(), None)
+            .await
+            .expect("set");
+        assert!(
+            c.inner.lock().expect("lock").entries["k"]
+                .expires_at
+                .is_none()
+        );
+    }
+}
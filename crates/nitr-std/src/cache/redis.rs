@@ -0,0 +1,823 @@
+//! `[cache] backend = "redis"`: the same `nitr.cache` over a Redis (or
+//! Valkey) server, so every process behind a balancer shares one cache and
+//! one set of invalidations.
+//!
+//! An entry is a hash under `{prefix}k:{key}`: the value in `v`, the
+//! server time it goes stale in `s`, and its tags in `t`. Each tag is a set
+//! of entry keys under `{prefix}t:{tag}`. Every write is one script, so an
+//! entry and its tag bookkeeping change together and `add`/`cas` are atomic
+//! across processes. The scripts name keys they were not handed, which
+//! rules out Redis Cluster; one server, with or without replicas, is what
+//! this supports (Redis 5 or later).
+//!
+//! When the server cannot be reached, `fallback = "miss"` reads as a miss
+//! and skips `set`, so the application runs uncached instead of failing.
+//! Everything that removes or counts raises regardless: a skipped `delete`
+//! or `invalidate_tag` would leave stale data behind once the server is
+//! back, and no answer `incr`, `add` or `cas` could invent would be true.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use nitr_core::{Error, Result};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+use super::resp::{Conn, Reply};
+
+/// How the Redis cache behaves while its server is unreachable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheFallback {
+    /// Reads miss and `set` is skipped; removals and counters raise.
+    #[default]
+    Miss,
+    /// Every call raises.
+    Error,
+}
+
+/// Connection settings for the Redis cache backend.
+#[derive(Debug, Clone)]
+pub struct RedisOptions {
+    /// `redis://[[user]:password@]host[:port][/db]`.
+    pub url: String,
+    /// Most connections open at once, shared by every state.
+    pub pool_size: usize,
+    /// How long opening a connection may take.
+    pub connect_timeout: Duration,
+    /// How long one command may take, waiting for a pooled connection
+    /// included.
+    pub timeout: Duration,
+    /// Prepended to every key, so several applications can share a server.
+    pub prefix: String,
+    /// What an unreachable server means to the application.
+    pub fallback: CacheFallback,
+}
+
+impl Default for RedisOptions {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1:6379".into(),
+            pool_size: 16,
+            connect_timeout: Duration::from_secs(1),
+            timeout: Duration::from_millis(250),
+            prefix: "nitr:".into(),
+            fallback: CacheFallback::Miss,
+        }
+    }
+}
+
+/// After the server is found unreachable, calls fail at once for this
+/// long instead of each waiting out a timeout.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Keys fetched per `SCAN` round trip.
+const SCAN_COUNT: &[u8] = b"500";
+
+/// Reads an entry and whether it is stale, by the server's clock.
+const LOOKUP: &str = r"
+local e = redis.call('HMGET', KEYS[1], 'v', 's')
+if not e[1] then return false end
+local stale = 0
+if e[2] then
+  local t = redis.call('TIME')
+  if t[1] * 1000 + math.floor(t[2] / 1000) >= tonumber(e[2]) then stale = 1 end
+end
+return {e[1], stale}
+";
+
+/// Writes or deletes an entry when a condition holds, keeping the tag
+/// sets in step. ARGV: condition (`always`, `absent` or `equal`), the
+/// expected value, `set` or `del`, the value, fresh ms, stale ms, the tag
+/// key prefix, then the tags. Returns 0 when the condition failed, else 1
+/// (for `del`, whether there was an entry).
+const WRITE: &str = r"
+local key = KEYS[1]
+local current = redis.call('HGET', key, 'v')
+if ARGV[1] == 'absent' and current then return 0 end
+if ARGV[1] == 'equal' and current ~= ARGV[2] then return 0 end
+local old = redis.call('HGET', key, 't')
+if old then
+  for _, tag in ipairs(cjson.decode(old)) do redis.call('SREM', ARGV[7] .. tag, key) end
+end
+local existed = redis.call('DEL', key)
+if ARGV[3] == 'del' then return existed end
+redis.call('HSET', key, 'v', ARGV[4])
+local fresh, stale = tonumber(ARGV[5]), tonumber(ARGV[6])
+if fresh > 0 and stale > 0 then
+  local t = redis.call('TIME')
+  local from = t[1] * 1000 + math.floor(t[2] / 1000) + fresh
+  redis.call('HSET', key, 's', string.format('%.0f', from))
+end
+if fresh > 0 then redis.call('PEXPIRE', key, fresh + stale) end
+if #ARGV > 7 then
+  local tags = {}
+  for i = 8, #ARGV do
+    local set = ARGV[7] .. ARGV[i]
+    tags[#tags + 1] = ARGV[i]
+    -- A tag set lives as long as its longest-lived entry.
+    local left = redis.call('PTTL', set)
+    redis.call('SADD', set, key)
+    if fresh == 0 then
+      redis.call('PERSIST', set)
+    elseif left == -2 or (left >= 0 and left < fresh + stale) then
+      redis.call('PEXPIRE', set, fresh + stale)
+    end
+  end
+  redis.call('HSET', key, 't', cjson.encode(tags))
+end
+return 1
+";
+
+/// Adds to a counter, giving a new one its ttl. ARGV: the amount, ttl ms.
+const INCR: &str = r"
+local created = redis.call('EXISTS', KEYS[1]) == 0
+local n = redis.call('HINCRBY', KEYS[1], 'v', ARGV[1])
+if created and ARGV[2] ~= '0' then redis.call('PEXPIRE', KEYS[1], ARGV[2]) end
+return n
+";
+
+/// Deletes every entry of a tag and the tag itself. An entry that expired
+/// and was written again untagged may still be listed, and goes too: a
+/// spurious miss, never a stale read. ARGV: the tag key prefix.
+const INVALIDATE: &str = r"
+local n = 0
+for _, key in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+  local tags = redis.call('HGET', key, 't')
+  if tags then
+    for _, tag in ipairs(cjson.decode(tags)) do redis.call('SREM', ARGV[1] .. tag, key) end
+  end
+  n = n + redis.call('DEL', key)
+end
+redis.call('DEL', KEYS[1])
+return n
+";
+
+/// What a write is conditional on.
+pub(super) enum Cond<'a> {
+    Always,
+    Absent,
+    /// The entry holds exactly these bytes.
+    Equal(&'a [u8]),
+}
+
+/// A value to store: the bytes, fresh and stale milliseconds (`0` fresh
+/// for ever), and the tags.
+pub(super) struct Put<'a> {
+    pub(super) value: &'a [u8],
+    pub(super) fresh_ms: u64,
+    pub(super) stale_ms: u64,
+    pub(super) tags: &'a [String],
+}
+
+/// Why a call failed.
+pub(super) enum Failure {
+    /// The server could not be reached in time; the fallback applies.
+    Down(String),
+    /// The server answered with an error.
+    Server(String),
+}
+
+/// Where the server is and how to log in.
+#[derive(Debug)]
+struct Target {
+    /// `host:port`, as `TcpStream::connect` takes it.
+    addr: String,
+    user: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+/// A pooled client for one server.
+pub(super) struct Redis {
+    target: Target,
+    opts: RedisOptions,
+    idle: Mutex<Vec<Conn>>,
+    permits: Semaphore,
+    /// The largest reply accepted: the largest value plus room for the
+    /// rest of the reply.
+    reply_limit: usize,
+    /// Set while the server is unreachable: calls fail at once until then.
+    down_until: Mutex<Option<Instant>>,
+    /// Calls answered by the fallback, for `stats`.
+    pub(super) fallbacks: AtomicU64,
+}
+
+impl Redis {
+    /// A client for `opts.url`. Nothing connects until the first call, so
+    /// a server that is down at startup is a fallback, not a failed boot.
+    pub(super) fn new(opts: RedisOptions, max_value: u64) -> Result<Self> {
+        let target = parse_url(&opts.url)
+            .map_err(|reason| Error::Config(format!("[cache.redis] url is invalid: {reason}")))?;
+        Ok(Self {
+            target,
+            permits: Semaphore::new(opts.pool_size.max(1)),
+            idle: Mutex::new(Vec::new()),
+            reply_limit: usize::try_from(max_value)
+                .unwrap_or(usize::MAX)
+                .saturating_add(64 * 1024),
+            down_until: Mutex::new(None),
+            fallbacks: AtomicU64::new(0),
+            opts,
+        })
+    }
+
+    /// The value under `key` and whether it is stale.
+    pub(super) async fn lookup(
+        &self,
+        key: &str,
+    ) -> std::result::Result<Option<(Vec<u8>, bool)>, Failure> {
+        let key = self.entry_key(key);
+        match self.eval(LOOKUP, &[key.as_bytes()], &[], true).await? {
+            Reply::Array(mut pair) if pair.len() == 2 => {
+                let stale = matches!(pair.pop(), Some(Reply::Int(1)));
+                match pair.pop() {
+                    Some(Reply::Bulk(value)) => Ok(Some((value, stale))),
+                    _ => Err(unexpected()),
+                }
+            }
+            Reply::Nil => Ok(None),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// The stored bytes under `key`, stale or not.
+    pub(super) async fn value(&self, key: &str) -> std::result::Result<Option<Vec<u8>>, Failure> {
+        let key = self.entry_key(key);
+        match self.call(&[b"HGET", key.as_bytes(), b"v"], true).await? {
+            Reply::Bulk(value) => Ok(Some(value)),
+            Reply::Nil => Ok(None),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// Writes `put` under `key`, or deletes it when `put` is `None`, if
+    /// `cond` holds. Returns 0 when it did not, else 1 (for a delete,
+    /// whether there was an entry).
+    pub(super) async fn write(
+        &self,
+        key: &str,
+        cond: Cond<'_>,
+        put: Option<Put<'_>>,
+    ) -> std::result::Result<i64, Failure> {
+        let key = self.entry_key(key);
+        let tag_prefix = format!("{}t:", self.opts.prefix);
+        let (cond, expected): (&[u8], &[u8]) = match cond {
+            Cond::Always => (b"always", b""),
+            Cond::Absent => (b"absent", b""),
+            Cond::Equal(bytes) => (b"equal", bytes),
+        };
+        let (fresh, stale) = put
+            .as_ref()
+            .map_or((0, 0), |put| (put.fresh_ms, put.stale_ms));
+        let (fresh, stale) = (fresh.to_string(), stale.to_string());
+        let mut args: Vec<&[u8]> = vec![
+            cond,
+            expected,
+            if put.is_some() { b"set" } else { b"del" },
+            put.as_ref().map_or(&b""[..], |put| put.value),
+            fresh.as_bytes(),
+            stale.as_bytes(),
+            tag_prefix.as_bytes(),
+        ];
+        if let Some(put) = &put {
+            args.extend(put.tags.iter().map(String::as_bytes));
+        }
+        self.int(self.eval(WRITE, &[key.as_bytes()], &args, false).await?)
+    }
+
+    /// Adds `by` to the counter under `key`, creating it with `ttl_ms`.
+    pub(super) async fn incr(
+        &self,
+        key: &str,
+        by: i64,
+        ttl_ms: u64,
+    ) -> std::result::Result<i64, Failure> {
+        let key = self.entry_key(key);
+        let (by, ttl) = (by.to_string(), ttl_ms.to_string());
+        self.int(
+            self.eval(
+                INCR,
+                &[key.as_bytes()],
+                &[by.as_bytes(), ttl.as_bytes()],
+                false,
+            )
+            .await?,
+        )
+    }
+
+    /// Deletes the entries tagged `tag`, returning how many went.
+    pub(super) async fn invalidate_tag(&self, tag: &str) -> std::result::Result<i64, Failure> {
+        let set = format!("{}t:{tag}", self.opts.prefix);
+        let tag_prefix = format!("{}t:", self.opts.prefix);
+        self.int(
+            self.eval(
+                INVALIDATE,
+                &[set.as_bytes()],
+                &[tag_prefix.as_bytes()],
+                false,
+            )
+            .await?,
+        )
+    }
+
+    /// The cache keys (without the prefix) starting with `prefix`, a page
+    /// at a time: `SCAN`, so the server is never blocked on a full listing.
+    pub(super) async fn keys(&self, prefix: &str) -> std::result::Result<Vec<String>, Failure> {
+        let entries = self.entry_key("");
+        let pattern = format!("{}*", glob_escape(&format!("{entries}{prefix}")));
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+        loop {
+            let reply = self
+                .call(
+                    &[
+                        b"SCAN",
+                        &cursor,
+                        b"MATCH",
+                        pattern.as_bytes(),
+                        b"COUNT",
+                        SCAN_COUNT,
+                    ],
+                    true,
+                )
+                .await?;
+            let Reply::Array(mut page) = reply else {
+                return Err(unexpected());
+            };
+            let (Some(Reply::Array(found)), Some(Reply::Bulk(next))) = (page.pop(), page.pop())
+            else {
+                return Err(unexpected());
+            };
+            keys.extend(found.into_iter().filter_map(|key| {
+                match key {
+                    Reply::Bulk(key) => String::from_utf8(key)
+                        .ok()
+                        .and_then(|key| key.strip_prefix(&entries).map(str::to_owned)),
+                    _ => None,
+                }
+            }));
+            if next == b"0" {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Deletes every entry and tag under the prefix, returning how many
+    /// keys went. Other keys on the server are left alone.
+    pub(super) async fn clear(&self) -> std::result::Result<i64, Failure> {
+        let pattern = format!("{}*", glob_escape(&self.opts.prefix));
+        let mut cursor = b"0".to_vec();
+        let mut removed = 0;
+        loop {
+            let reply = self
+                .call(
+                    &[
+                        b"SCAN",
+                        &cursor,
+                        b"MATCH",
+                        pattern.as_bytes(),
+                        b"COUNT",
+                        SCAN_COUNT,
+                    ],
+                    true,
+                )
+                .await?;
+            let Reply::Array(mut page) = reply else {
+                return Err(unexpected());
+            };
+            let (Some(Reply::Array(found)), Some(Reply::Bulk(next))) = (page.pop(), page.pop())
+            else {
+                return Err(unexpected());
+            };
+            let mut args: Vec<&[u8]> = vec![b"UNLINK"];
+            args.extend(found.iter().filter_map(|key| match key {
+                Reply::Bulk(key) => Some(key.as_slice()),
+                _ => None,
+            }));
+            if args.len() > 1 {
+                removed += self.int(self.call(&args, false).await?)?;
+            }
+            if next == b"0" {
+                return Ok(removed);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Turns a failure into the error the script sees, or into `missing`
+    /// when the fallback allows it. `degrades` is false for the calls
+    /// that must never pretend.
+    pub(super) fn settle<T>(
+        &self,
+        op: &str,
+        result: std::result::Result<T, Failure>,
+        degrades: bool,
+        missing: T,
+    ) -> mlua::Result<T> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(Failure::Down(reason)) => {
+                if degrades && self.opts.fallback == CacheFallback::Miss {
+                    self.fallbacks.fetch_add(1, Ordering::Relaxed);
+                    return Ok(missing);
+                }
+                Err(mlua::Error::RuntimeError(format!(
+                    "cache:{op}: the Redis cache is unavailable: {reason}"
+                )))
+            }
+            Err(Failure::Server(reason)) => Err(mlua::Error::RuntimeError(format!(
+                "cache:{op}: Redis refused it: {reason}"
+            ))),
+        }
+    }
+
+    fn entry_key(&self, key: &str) -> String {
+        format!("{}k:{key}", self.opts.prefix)
+    }
+
+    fn int(&self, reply: Reply) -> std::result::Result<i64, Failure> {
+        match reply {
+            Reply::Int(n) => Ok(n),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn eval(
+        &self,
+        script: &str,
+        keys: &[&[u8]],
+        args: &[&[u8]],
+        read_only: bool,
+    ) -> std::result::Result<Reply, Failure> {
+        let count = keys.len().to_string();
+        let mut command: Vec<&[u8]> = vec![b"EVAL", script.as_bytes(), count.as_bytes()];
+        command.extend_from_slice(keys);
+        command.extend_from_slice(args);
+        self.call(&command, read_only).await
+    }
+
+    /// Runs one command on a pooled connection. `read_only` commands may
+    /// be sent twice; any other is resent only when the server cannot have
+    /// run it.
+    async fn call(&self, args: &[&[u8]], read_only: bool) -> std::result::Result<Reply, Failure> {
+        if let Some(until) = *self.down_until.lock().map_err(|_| poisoned())?
+            && Instant::now() < until
+        {
+            return Err(Failure::Down("it did not answer moments ago".into()));
+        }
+        let Ok(Ok(_permit)) = tokio::time::timeout(self.opts.timeout, self.permits.acquire()).await
+        else {
+            return Err(Failure::Down(format!(
+                "all {} connections stayed busy",
+                self.opts.pool_size
+            )));
+        };
+        let pooled = self.idle.lock().map_err(|_| poisoned())?.pop();
+        let attempt = async {
+            // A pooled connection may have been closed under it, by a
+            // server restart or an idle timeout: that is worth one more
+            // try on a fresh one before calling the server down. Only
+            // when the server cannot have run the command, though — a
+            // write sent twice could apply twice (an `incr` would count
+            // double) — so a connection that dies after taking the whole
+            // command ends the call, unless the command only reads.
+            if let Some(mut conn) = pooled.filter(|conn| !conn.is_closed())
+                && conn.send(args).await.is_ok()
+            {
+                match conn.receive().await {
+                    Ok(reply) => return Ok((conn, reply)),
+                    Err(err) if !read_only => return Err(err),
+                    Err(_) => {}
+                }
+            }
+            let mut conn = self.connect().await?;
+            let reply = conn.call(args).await?;
+            std::io::Result::Ok((conn, reply))
+        };
+        match tokio::time::timeout(self.opts.timeout, attempt).await {
+            Ok(Ok((conn, reply))) => {
+                self.idle.lock().map_err(|_| poisoned())?.push(conn);
+                self.mark_up()?;
+                match reply {
+                    Reply::Error(reason) => Err(Failure::Server(reason)),
+                    reply => Ok(reply),
+                }
+            }
+            Ok(Err(err)) => Err(self.mark_down(err.to_string())),
+            Err(_) => Err(self.mark_down(format!(
+                "no answer within {} ms",
+                self.opts.timeout.as_millis()
+            ))),
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<Conn> {
+        let stream = tokio::time::timeout(
+            self.opts.connect_timeout,
+            TcpStream::connect(&self.target.addr),
+        )
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+        stream.set_nodelay(true)?;
+        let mut conn = Conn::new(stream, self.reply_limit);
+        if let Some(password) = &self.target.password {
+            let mut auth: Vec<&[u8]> = vec![b"AUTH"];
+            auth.extend(self.target.user.as_deref().map(str::as_bytes));
+            auth.push(password.as_bytes());
+            expect_ok(conn.call(&auth).await?, "AUTH")?;
+        }
+        if self.target.db != 0 {
+            let db = self.target.db.to_string();
+            expect_ok(conn.call(&[b"SELECT", db.as_bytes()]).await?, "SELECT")?;
+        }
+        Ok(conn)
+    }
+
+    /// Records an unreachable server, logging when it was reachable before.
+    fn mark_down(&self, reason: String) -> Failure {
+        if let Ok(mut down) = self.down_until.lock() {
+            if down.is_none() {
+                tracing::warn!(
+                    addr = %self.target.addr,
+                    error = %reason,
+                    "the Redis cache is unreachable"
+                );
+            }
+            *down = Some(Instant::now() + RETRY_AFTER);
+        }
+        Failure::Down(reason)
+    }
+
+    fn mark_up(&self) -> std::result::Result<(), Failure> {
+        let mut down = self.down_until.lock().map_err(|_| poisoned())?;
+        if down.take().is_some() {
+            tracing::info!(addr = %self.target.addr, "the Redis cache is reachable again");
+        }
+        Ok(())
+    }
+}
+
+fn expect_ok(reply: Reply, command: &str) -> std::io::Result<()> {
+    match reply {
+        Reply::Status(_) => Ok(()),
+        Reply::Error(reason) => Err(std::io::Error::other(format!("{command}: {reason}"))),
+        _ => Err(std::io::Error::other(format!(
+            "{command}: unexpected reply"
+        ))),
+    }
+}
+
+fn unexpected() -> Failure {
+    Failure::Server("unexpected reply".into())
+}
+
+fn poisoned() -> Failure {
+    Failure::Server("the cache lock is poisoned".into())
+}
+
+/// `text` as a literal `SCAN MATCH` pattern.
+fn glob_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Parses `redis://[[user]:password@]host[:port][/db]`.
+fn parse_url(url: &str) -> std::result::Result<Target, String> {
+    let Some(rest) = url.strip_prefix("redis://") else {
+        if url.starts_with("rediss://") {
+            return Err("TLS (rediss://) is not supported; reach the server over a \
+                        private network"
+                .into());
+        }
+        return Err("expected a redis:// URL".into());
+    };
+    let (authority, db) = rest.split_once('/').unwrap_or((rest, ""));
+    let db = match db {
+        "" => 0,
+        db => db
+            .parse()
+            .map_err(|_| format!("`{db}` is not a database number"))?,
+    };
+    let (userinfo, host) = match authority.rsplit_once('@') {
+        Some((userinfo, host)) => (Some(userinfo), host),
+        None => (None, authority),
+    };
+    let (user, password) = match userinfo {
+        None => (None, None),
+        Some(userinfo) => {
+            let Some((user, password)) = userinfo.split_once(':') else {
+                return Err("put the password after a colon: redis://:password@host".into());
+            };
+            let decode = |text: &str| {
+                percent_encoding::percent_decode_str(text)
+                    .decode_utf8()
+                    .map(|text| text.into_owned())
+                    .map_err(|_| "the credentials are not valid UTF-8".to_string())
+            };
+            let user = (!user.is_empty()).then(|| decode(user)).transpose()?;
+            (user, Some(decode(password)?))
+        }
+    };
+    if host.is_empty() {
+        return Err("no host".into());
+    }
+    let has_port = match host.rfind(']') {
+        Some(bracket) => host[bracket..].contains(':'),
+        None => host.contains(':'),
+    };
+    Ok(Target {
+        addr: if has_port {
+            host.to_owned()
+        } else {
+            format!("{host}:6379")
+        },
+        user,
+        password,
+        db,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    #[test]
+    fn urls_parse_with_their_defaults() {
+        let target = parse_url("redis://cache.internal").expect("plain");
+        assert_eq!(target.addr, "cache.internal:6379");
+        assert_eq!((target.user, target.password, target.db), (None, None, 0));
+
+        let target = parse_url("redis://app:p%40ss@[::1]:6380/2").expect("full");
+        assert_eq!(target.addr, "[::1]:6380");
+        assert_eq!(target.user.as_deref(), Some("app"));
+        assert_eq!(target.password.as_deref(), Some("p@ss"));
+        assert_eq!(target.db, 2);
+
+        let target = parse_url("redis://:secret@10.0.0.5").expect("password only");
+        assert_eq!(target.user, None);
+        assert_eq!(target.password.as_deref(), Some("secret"));
+
+        for bad in [
+            "rediss://host",
+            "http://host",
+            "redis://host/x",
+            "redis://secret@host",
+            "redis://",
+        ] {
+            assert!(parse_url(bad).is_err(), "{bad}");
+        }
+    }
+
+    /// The server runs Lua 5.1, which parses everything these use the
+    /// same way the vendored interpreter does.
+    #[test]
+    fn the_scripts_compile() {
+        let lua = mlua::Lua::new();
+        for script in [LOOKUP, WRITE, INCR, INVALIDATE] {
+            lua.load(script).into_function().expect("compiles");
+        }
+    }
+
+    /// A port nothing listens on.
+    fn closed_url() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        drop(listener);
+        format!("redis://127.0.0.1:{port}")
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_server_misses_or_raises_by_policy() {
+        let opts = RedisOptions {
+            url: closed_url(),
+            ..RedisOptions::default()
+        };
+        let cache =
+            crate::Cache::redis(crate::CacheOptions::default(), opts.clone()).expect("cache");
+        assert_eq!(cache.get_raw("k").await.expect("a miss"), None);
+        cache
+            .set_raw("k".into(), b"1".to_vec(), None)
+            .await
+            .expect("skipped");
+        let err = cache
+            .remove("k")
+            .await
+            .expect_err("a delete must not pretend");
+        assert!(err.to_string().contains("unavailable"), "{err}");
+        assert!(cache.incr("n", 1, None).await.is_err());
+        let remote = cache.remote.as_ref().expect("remote");
+        assert_eq!(remote.fallbacks.load(Ordering::Relaxed), 2);
+
+        let strict = crate::Cache::redis(
+            crate::CacheOptions::default(),
+            RedisOptions {
+                fallback: CacheFallback::Error,
+                ..opts
+            },
+        )
+        .expect("cache");
+        let err = strict.get_raw("k").await.expect_err("raises");
+        assert!(err.to_string().contains("unavailable"), "{err}");
+    }
+
+    /// A server that answers every command with a nil reply, but closes
+    /// the connection instead of answering each `WRITE` script, and the
+    /// next `LOOKUP` once `drop_lookup` is set. Counts what it received.
+    async fn flaky_server() -> (String, Arc<[AtomicU64; 2]>, Arc<AtomicBool>) {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let url = format!("redis://{}", listener.local_addr().expect("addr"));
+        let seen: Arc<[AtomicU64; 2]> = Arc::default();
+        let drop_lookup = Arc::new(AtomicBool::new(false));
+        let (counts, drop_next) = (seen.clone(), drop_lookup.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (counts, drop_next) = (counts.clone(), drop_next.clone());
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    loop {
+                        let parsed = crate::cache::resp::parse(&buf, 1 << 20, 0).expect("parse");
+                        let Some((Reply::Array(command), used)) = parsed else {
+                            if stream.read_buf(&mut buf).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            continue;
+                        };
+                        buf.drain(..used);
+                        let script = match command.get(1) {
+                            Some(Reply::Bulk(script)) => script.as_slice(),
+                            _ => &[],
+                        };
+                        if script == WRITE.as_bytes() {
+                            counts[1].fetch_add(1, Ordering::SeqCst);
+                            return;
+                        }
+                        if script == LOOKUP.as_bytes() {
+                            counts[0].fetch_add(1, Ordering::SeqCst);
+                            if drop_next.swap(false, Ordering::SeqCst) {
+                                return;
+                            }
+                        }
+                        if stream.write_all(b"$-1\r\n").await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (url, seen, drop_lookup)
+    }
+
+    /// A connection lost after the server took the whole command: a read
+    /// is worth resending on a fresh connection, a write is not — the
+    /// server may already have run it.
+    #[tokio::test]
+    async fn only_reads_are_resent_after_the_connection_drops_mid_call() {
+        let (url, seen, drop_lookup) = flaky_server().await;
+        let redis = Redis::new(
+            RedisOptions {
+                url,
+                ..RedisOptions::default()
+            },
+            1 << 20,
+        )
+        .expect("client");
+
+        assert!(matches!(redis.lookup("k").await, Ok(None)));
+        drop_lookup.store(true, Ordering::SeqCst);
+        assert!(
+            matches!(redis.lookup("k").await, Ok(None)),
+            "a dropped read is resent"
+        );
+        assert_eq!(seen[0].load(Ordering::SeqCst), 3);
+
+        let put = Put {
+            value: b"1",
+            fresh_ms: 0,
+            stale_ms: 0,
+            tags: &[],
+        };
+        let written = redis.write("k", Cond::Always, Some(put)).await;
+        assert!(matches!(written, Err(Failure::Down(_))));
+        assert_eq!(seen[1].load(Ordering::SeqCst), 1, "the write was resent");
+    }
+
+    #[test]
+    fn scan_patterns_match_the_prefix_literally() {
+        assert_eq!(glob_escape("app[1]:*?\\"), "app\\[1\\]:\\*\\?\\\\");
+    }
+}
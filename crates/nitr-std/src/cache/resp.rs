@@ -0,0 +1,233 @@
+//! The RESP2 wire protocol, as much of it as the Redis cache needs:
+//! commands go out as arrays of bulk strings, and the five reply types
+//! come back.
+//!
+//! Replies are parsed from a buffer rather than straight off the socket,
+//! so the parser is plain synchronous code; the connection only reads
+//! until a whole reply is buffered. Both the buffer and every reply are
+//! bounded, so a misbehaving server cannot make the process allocate
+//! without limit.
+
+use std::io;
+
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+
+/// One reply.
+#[derive(Debug, PartialEq)]
+pub(super) enum Reply {
+    Status(String),
+    /// An error reply: the command failed, the connection is fine.
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+/// Arrays nest this deep at most. No reply the cache asks for nests past
+/// two levels (`SCAN`).
+const MAX_DEPTH: usize = 4;
+
+/// Bytes read from the socket per attempt.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// One connection to the server.
+pub(super) struct Conn {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// The largest reply accepted, in bytes.
+    limit: usize,
+}
+
+impl Conn {
+    pub(super) fn new(stream: TcpStream, limit: usize) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            limit,
+        }
+    }
+
+    /// Sends one command and reads its reply.
+    pub(super) async fn call(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        self.send(args).await?;
+        self.receive().await
+    }
+
+    /// Sends one command. On an error the server never received all of
+    /// it, so it cannot have run it.
+    pub(super) async fn send(&mut self, args: &[&[u8]]) -> io::Result<()> {
+        self.stream.write_all(&encode(args)).await
+    }
+
+    /// Reads the reply to the command sent last.
+    pub(super) async fn receive(&mut self) -> io::Result<Reply> {
+        loop {
+            if let Some((reply, used)) = parse(&self.buf, self.limit, 0)? {
+                self.buf.drain(..used);
+                return Ok(reply);
+            }
+            if self.buf.len() > self.limit {
+                return Err(protocol(format!(
+                    "a reply is larger than the {} byte limit",
+                    self.limit
+                )));
+            }
+            self.buf.reserve(READ_CHUNK);
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Whether the server has closed the connection (or sent what nothing
+    /// asked for) while it sat idle, checked without waiting.
+    pub(super) fn is_closed(&self) -> bool {
+        let mut probe = [0; 1];
+        !matches!(
+            self.stream.try_read(&mut probe),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock
+        )
+    }
+}
+
+/// A command as RESP: an array of bulk strings.
+pub(super) fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + args.iter().map(|arg| arg.len() + 16).sum::<usize>());
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// The first reply in `buf` and the bytes it took, or `None` when `buf`
+/// does not hold all of it yet.
+pub(super) fn parse(buf: &[u8], limit: usize, depth: usize) -> io::Result<Option<(Reply, usize)>> {
+    let Some(end) = buf.windows(2).position(|pair| pair == b"\r\n") else {
+        return Ok(None);
+    };
+    if end == 0 {
+        return Err(protocol("a reply has no type".into()));
+    }
+    let line = &buf[1..end];
+    let body = end + 2;
+    let reply = match buf[0] {
+        b'+' => Reply::Status(String::from_utf8_lossy(line).into_owned()),
+        b'-' => Reply::Error(String::from_utf8_lossy(line).into_owned()),
+        b':' => Reply::Int(number(line)?),
+        b'$' => {
+            let Ok(len) = usize::try_from(number(line)?) else {
+                return Ok(Some((Reply::Nil, body)));
+            };
+            if len > limit {
+                return Err(protocol(format!(
+                    "a {len} byte value is larger than the {limit} byte limit"
+                )));
+            }
+            let Some(value) = buf.get(body..body + len) else {
+                return Ok(None);
+            };
+            if buf.len() < body + len + 2 {
+                return Ok(None);
+            }
+            if &buf[body + len..body + len + 2] != b"\r\n" {
+                return Err(protocol("a value is not terminated".into()));
+            }
+            return Ok(Some((Reply::Bulk(value.to_vec()), body + len + 2)));
+        }
+        b'*' => {
+            let Ok(len) = usize::try_from(number(line)?) else {
+                return Ok(Some((Reply::Nil, body)));
+            };
+            if depth >= MAX_DEPTH {
+                return Err(protocol("replies nest too deeply".into()));
+            }
+            // Every element takes at least three bytes, which bounds the
+            // allocation by what was actually received.
+            let mut items = Vec::with_capacity(len.min(buf.len() / 3));
+            let mut at = body;
+            for _ in 0..len {
+                let Some((item, used)) = parse(&buf[at..], limit, depth + 1)? else {
+                    return Ok(None);
+                };
+                items.push(item);
+                at += used;
+            }
+            return Ok(Some((Reply::Array(items), at)));
+        }
+        other => {
+            return Err(protocol(format!(
+                "unexpected reply type {:?}",
+                char::from(other)
+            )));
+        }
+    };
+    Ok(Some((reply, body)))
+}
+
+fn number(line: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| protocol("a length or integer is not a number".into()))
+}
+
+fn protocol(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_arrays_of_bulk_strings() {
+        assert_eq!(
+            encode(&[b"SET", b"k", b"a\r\nb"]),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn every_reply_type_parses_and_partial_input_waits() {
+        let whole = b"*4\r\n+OK\r\n:-7\r\n$3\r\na\r\n\r\n*-1\r\n-ERR no\r\n";
+        let (reply, used) = parse(whole, 1024, 0).expect("parse").expect("whole");
+        for cut in 0..used {
+            assert!(
+                parse(&whole[..cut], 1024, 0).expect("parse").is_none(),
+                "cut at {cut}"
+            );
+        }
+        assert_eq!(
+            reply,
+            Reply::Array(vec![
+                Reply::Status("OK".into()),
+                Reply::Int(-7),
+                Reply::Bulk(b"a\r\n".to_vec()),
+                Reply::Nil,
+            ])
+        );
+        let (reply, _) = parse(&whole[used..], 1024, 0)
+            .expect("parse")
+            .expect("error");
+        assert_eq!(reply, Reply::Error("ERR no".into()));
+        assert_eq!(
+            parse(b"$-1\r\n", 1024, 0).expect("parse"),
+            Some((Reply::Nil, 5))
+        );
+    }
+
+    #[test]
+    fn oversized_deep_or_malformed_replies_are_refused() {
+        assert!(parse(b"$2048\r\n", 1024, 0).is_err());
+        assert!(parse(b"*1\r\n*1\r\n*1\r\n*1\r\n*1\r\n:1\r\n", 1024, 0).is_err());
+        assert!(parse(b"$1\r\nab\r\n", 1024, 0).is_err());
+        assert!(parse(b"?\r\n", 1024, 0).is_err());
+        assert!(parse(b":x\r\n", 1024, 0).is_err());
+        assert!(parse(b"\r\n", 1024, 0).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! `[cache] persist`: the cache written to disk and read back at startup,
+//! so a deploy does not start cold.
+//!
+//! The file is versioned and ends in the SHA-256 of everything before it.
+//! A snapshot failing either check, or larger than this cache could ever
+//! hold, is refused whole: a cold cache is slower, a half-read one is a
+//! bug. Expiry is stored as wall-clock time, so downtime counts against
+//! each entry's ttl.
+
+use std::io::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nitr_core::{Error, Result};
+use sha2::{Digest as _, Sha256};
+
+use super::{Cache, Lifetime};
+
+const MAGIC: &[u8] = b"NITRCACHE";
+
+/// Bumped whenever the layout changes; other versions are refused.
+const VERSION: u8 = 1;
+
+const DIGEST_LEN: usize = 32;
+
+/// Room for a key and its tags beside each value when bounding the file
+/// size: the cache's own byte bound counts values only.
+const ENTRY_OVERHEAD: u64 = 4096;
+
+/// One restored entry, checked in full before any is stored.
+struct Restored {
+    key: String,
+    value: Vec<u8>,
+    life: Lifetime,
+    tags: Box<[String]>,
+}
+
+impl Cache {
+    /// Writes the live entries to `path`, returning how many. The file is
+    /// written beside `path` and renamed over it, so a crash mid-write
+    /// leaves the previous snapshot in place.
+    pub fn save_snapshot(&self, path: &Path) -> Result<usize> {
+        let _saving = self
+            .saving
+            .lock()
+            .map_err(|_| Error::Config("the cache snapshot lock is poisoned".into()))?;
+        let (now, now_ms) = (Instant::now(), unix_ms());
+        let wall = |at: Instant| now_ms + at.saturating_duration_since(now).as_millis() as u64;
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        let count = {
+            let inner = self
+                .inner
+                .lock()
+                .map_err(|_| Error::Config("the cache lock is poisoned".into()))?;
+            let live: Vec<_> = inner
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.is_live(now))
+                .collect();
+            out.extend_from_slice(&(live.len() as u32).to_le_bytes());
+            for (key, entry) in &live {
+                put_bytes(&mut out, key.as_bytes());
+                put_bytes(&mut out, &entry.value);
+                // 0 stands for "never", which no real instant maps to.
+                out.extend_from_slice(&entry.expires_at.map_or(0, wall).to_le_bytes());
+                out.extend_from_slice(&entry.stale_from.map_or(0, wall).to_le_bytes());
+                out.extend_from_slice(&(entry.tags.len() as u32).to_le_bytes());
+                for tag in &entry.tags {
+                    put_bytes(&mut out, tag.as_bytes());
+                }
+            }
+            live.len()
+        };
+        let digest = Sha256::digest(&out);
+        out.extend_from_slice(&digest);
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(&out)?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, path)
+        };
+        write().map_err(|err| {
+            Error::Config(format!(
+                "cannot write the cache snapshot {}: {err}",
+                path.display()
+            ))
+        })?;
+        Ok(count)
+    }
+
+    /// Restores the entries of a snapshot written by
+    /// [`Cache::save_snapshot`], returning how many were still live. A
+    /// missing file restores nothing; a corrupt or oversized one is an
+    /// error and restores nothing either.
+    pub fn load_snapshot(&self, path: &Path) -> Result<usize> {
+        let bytes = match read_bounded(path, self.snapshot_limit()) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Ok(0),
+            Err(err) => {
+                return Err(Error::Config(format!(
+                    "cannot read the cache snapshot {}: {err}",
+                    path.display()
+                )));
+            }
+        };
+        let restored = parse(&bytes).map_err(|reason| {
+            Error::Config(format!(
+                "the cache snapshot {} is unusable: {reason}",
+                path.display()
+            ))
+        })?;
+
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| Error::Config("the cache lock is poisoned".into()))?;
+        let now = Instant::now();
+        let mut count = 0;
+        for entry in restored {
+            if entry.life.expires_at.is_some_and(|at| at <= now)
+                || entry.value.len() as u64 > self.opts.max_bytes
+            {
+                continue;
+            }
+            self.put(&mut inner, entry.key, entry.value, entry.life, entry.tags);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The largest snapshot this cache accepts: its value bound, plus room
+    /// for keys and tags.
+    fn snapshot_limit(&self) -> u64 {
+        let entries = self.opts.max_entries as u64;
+        self.opts
+            .max_bytes
+            .saturating_add(entries.saturating_mul(ENTRY_OVERHEAD))
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The file at `path`, or `None` when there is none. Checked against
+/// `limit` before it is read, not after.
+fn read_bounded(path: &Path, limit: u64) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match std::fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len > limit {
+        return Err(std::io::Error::other(format!(
+            "{len} bytes is more than this cache can hold ({limit})"
+        )));
+    }
+    std::fs::read(path).map(Some)
+}
+
+fn parse(bytes: &[u8]) -> std::result::Result<Vec<Restored>, String> {
+    let Some(split) = bytes.len().checked_sub(DIGEST_LEN) else {
+        return Err("truncated".into());
+    };
+    let (body, digest) = bytes.split_at(split);
+    if Sha256::digest(body).as_slice() != digest {
+        return Err("checksum mismatch".into());
+    }
+    let mut reader = Reader(body);
+    if reader.take(MAGIC.len()) != Some(MAGIC) {
+        return Err("not a cache snapshot".into());
+    }
+    match reader.take(1) {
+        Some([VERSION]) => {}
+        Some([other]) => return Err(format!("unsupported version {other}")),
+        _ => return Err("truncated".into()),
+    }
+
+    let (now, now_ms) = (Instant::now(), unix_ms());
+    // Already past is mapped to now, and dropped by the caller.
+    let instant = |ms: u64| now + Duration::from_millis(ms.saturating_sub(now_ms));
+    let at = |ms: u64| (ms != 0).then(|| instant(ms));
+    let truncated = || "truncated".to_string();
+    let count = reader.u32().ok_or_else(truncated)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let key = reader.string().ok_or_else(truncated)?;
+        let value = reader.bytes().ok_or_else(truncated)?.to_vec();
+        let expires_at = at(reader.u64().ok_or_else(truncated)?);
+        let stale_from = at(reader.u64().ok_or_else(truncated)?);
+        let tags = (0..reader.u32().ok_or_else(truncated)?)
+            .map(|_| reader.string().ok_or_else(truncated))
+            .collect::<std::result::Result<_, _>>()?;
+        entries.push(Restored {
+            key,
+            value,
+            life: Lifetime {
+                expires_at,
+                stale_from,
+            },
+            tags,
+        });
+    }
+    if !reader.0.is_empty() {
+        return Err("trailing bytes".into());
+    }
+    Ok(entries)
+}
+
+/// Reads the snapshot layout front to back; `None` when it runs out.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.0.len() {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CacheOptions;
+    use crate::cache::WriteOpts;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("nitr-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        dir.join(name)
+    }
+
+    #[tokio::test]
+    async fn live_entries_round_trip_with_their_lifetimes_and_tags() {
+        let path = temp_path("round-trip.snapshot");
+        let c = Cache::new(CacheOptions::default());
+        c.set_with(
+            "tagged".into(),
+            b"{\"n\":1}".to_vec(),
+            WriteOpts {
+                ttl: Some(60),
+                stale: 30,
+                tags: vec!["user:1".to_string()].into_boxed_slice(),
+            },
+        )
+        .await
+        .expect("set");
+        c.set_raw("forever".into(), b"1".to_vec(), Some(0))
+            .await
+            .expect("set");
+        c.set_raw("gone".into(), b"2".to_vec(), Some(60))
+            .await
+            .expect("set");
+        c.inner
+            .lock()
+            .expect("lock")
+            .entries
+            .get_mut("gone")
+            .expect("entry")
+            .expires_at = Some(Instant::now());
+        assert_eq!(c.save_snapshot(&path).expect("save"), 2);
+
+        let restored = Cache::new(CacheOptions::default());
+        assert_eq!(restored.load_snapshot(&path).expect("load"), 2);
+        assert_eq!(
+            restored.get_raw("tagged").await.expect("get").as_deref(),
+            Some(&b"{\"n\":1}"[..])
+        );
+        {
+            let inner = restored.inner.lock().expect("lock");
+            let tagged = &inner.entries["tagged"];
+            let left = tagged.expires_at.expect("expiry") - Instant::now();
+            assert!(left > Duration::from_secs(80) && left <= Duration::from_secs(90));
+            assert!(tagged.stale_from.is_some());
+            assert!(inner.entries["forever"].expires_at.is_none());
+        }
+        assert_eq!(restored.invalidate_tag("user:1").await.expect("tag"), 1);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn damaged_or_oversized_snapshots_restore_nothing() {
+        let path = temp_path("damaged.snapshot");
+        let c = Cache::new(CacheOptions::default());
+        assert_eq!(c.load_snapshot(&path).expect("missing is empty"), 0);
+
+        c.set_raw("k".into(), vec![b'x'; 100], None)
+            .await
+            .expect("set");
+        c.save_snapshot(&path).expect("save");
+        let mut bytes = std::fs::read(&path).expect("read");
+        bytes[20] ^= 1;
+        std::fs::write(&path, &bytes).expect("write");
+        let fresh = Cache::new(CacheOptions::default());
+        let err = fresh.load_snapshot(&path).expect_err("corrupt");
+        assert!(err.to_string().contains("checksum"), "{err}");
+        assert!(fresh.get_raw("k").await.expect("get").is_none());
+
+        c.save_snapshot(&path).expect("save");
+        let tiny = Cache::new(CacheOptions {
+            max_entries: 0,
+            max_bytes: 10,
+            ..Default::default()
+        });
+        let err = tiny.load_snapshot(&path).expect_err("oversized");
+        assert!(err.to_string().contains("more than"), "{err}");
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,163 @@
+//! Row-level change notifications shared by every pooled connection.
+//!
+//! Each connection records the rows its statements touch (SQLite's
+//! `update_hook`) and publishes them here once the transaction has
+//! committed — never before, so a subscriber cannot react to a write that
+//! later rolls back. The feed itself is a bounded broadcast channel: a
+//! subscriber that falls behind is told how many changes it missed instead
+//! of holding memory for it.
+//!
+//! Changes are hints, not a replication log. They carry the table, the
+//! operation and the rowid; a subscriber re-reads whatever it needs. Rows
+//! undone by `ROLLBACK TO` a savepoint inside a committed transaction may
+//! still be reported. SQLite reports nothing for tables without a rowid
+//! (`WITHOUT ROWID`), for rows an `ON CONFLICT REPLACE` removed, or for an
+//! unqualified `DELETE FROM t`, which it runs as a truncate.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use mlua::{Function, Lua, Value};
+use tokio::sync::broadcast;
+
+/// Changes one transaction may record individually; past this, each
+/// further table is reported once with `op = "many"`.
+pub(crate) const MAX_PENDING: usize = 10_000;
+
+/// One committed row change.
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// Table the row belongs to.
+    pub table: Arc<str>,
+    /// `"insert"`, `"update"`, `"delete"`, or `"many"` for a transaction
+    /// too large to report row by row.
+    pub op: &'static str,
+    /// The row's rowid; `None` for `"many"`.
+    pub rowid: Option<i64>,
+}
+
+/// The broadcast channel behind `nitr.db:changes()`. Built once by the
+/// server and handed to every state, like the cache.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    tx: broadcast::Sender<Arc<Change>>,
+}
+
+impl ChangeFeed {
+    /// A feed buffering up to `capacity` changes for its slowest
+    /// subscriber.
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        Self { tx }
+    }
+
+    /// Publishes committed changes. Having no subscriber is the common
+    /// case, not an error.
+    pub(crate) fn publish(&self, changes: impl IntoIterator<Item = Change>) {
+        for change in changes {
+            let _ = self.tx.send(Arc::new(change));
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<Change>> {
+        self.tx.subscribe()
+    }
+}
+
+/// What a subscriber receives next.
+enum Next {
+    Change(Arc<Change>),
+    /// The subscriber fell behind and this many changes were dropped.
+    Lagged(u64),
+    /// The feed is gone (the server is shutting down).
+    Closed,
+}
+
+/// Waits for the next change on one of `tables` (every table when empty).
+async fn next(rx: &mut broadcast::Receiver<Arc<Change>>, tables: &HashSet<String>) -> Next {
+    loop {
+        match rx.recv().await {
+            Ok(change) if tables.is_empty() || tables.contains(&*change.table) => {
+                return Next::Change(change);
+            }
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(missed)) => return Next::Lagged(missed),
+            Err(broadcast::error::RecvError::Closed) => return Next::Closed,
+        }
+    }
+}
+
+fn next_to_lua(lua: &Lua, next: Next) -> mlua::Result<Value> {
+    let table = lua.create_table()?;
+    match next {
+        Next::Change(change) => {
+            table.set("table", &*change.table)?;
+            table.set("op", change.op)?;
+            table.set("rowid", change.rowid)?;
+        }
+        Next::Lagged(missed) => {
+            table.set("op", "lagged")?;
+            table.set("missed", missed)?;
+        }
+        Next::Closed => return Ok(Value::Nil),
+    }
+    Ok(Value::Table(table))
+}
+
+/// Reads the table filter: a name, a list of names, or nothing for all.
+pub(crate) fn table_filter(value: Value) -> mlua::Result<HashSet<String>> {
+    match value {
+        Value::Nil => Ok(HashSet::new()),
+        Value::String(name) => Ok(HashSet::from([name.to_str()?.to_owned()])),
+        Value::Table(list) => list.sequence_values::<String>().collect(),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "expected a table name or a list of table names, got {}",
+            other.type_name()
+        ))),
+    }
+}
+
+/// `db:changes(tables?)`: an iterator for a generic `for`, subscribed from
+/// the moment it is created. Each call waits for the next change and
+/// returns `{ table, op, rowid }`; a subscriber that fell behind gets
+/// `{ op = "lagged", missed = n }` once and carries on.
+pub(crate) fn changes_iterator(
+    lua: &Lua,
+    feed: &ChangeFeed,
+    tables: HashSet<String>,
+) -> mlua::Result<Function> {
+    let rx = Arc::new(tokio::sync::Mutex::new(feed.subscribe()));
+    let tables = Arc::new(tables);
+    lua.create_async_function(move |lua, ()| {
+        let rx = rx.clone();
+        let tables = tables.clone();
+        async move {
+            let next = next(&mut *rx.lock().await, &tables).await;
+            // Waiting for a change is idle time, not handler work.
+            crate::utils::grant_budget(&lua);
+            next_to_lua(&lua, next)
+        }
+    })
+}
+
+/// `db:on_change(tables, fn)`: calls `fn(change)` for every change until
+/// it returns `false` or the feed closes.
+pub(crate) async fn on_change(
+    lua: &Lua,
+    feed: &ChangeFeed,
+    tables: HashSet<String>,
+    callback: Function,
+) -> mlua::Result<()> {
+    let mut rx = feed.subscribe();
+    loop {
+        let next = next(&mut rx, &tables).await;
+        crate::utils::grant_budget(lua);
+        let change = match next_to_lua(lua, next)? {
+            Value::Nil => return Ok(()),
+            change => change,
+        };
+        if let Value::Boolean(false) = callback.call_async::<Value>(change).await? {
+            return Ok(());
+        }
+    }
+}
@@ -7,6 +7,7 @@
 //! to enable the builtin, with a message saying so, instead of failing to
 //! recognize the configuration at all.
 
+use std::path::PathBuf;
 use std::time::Duration;
 
 /// Policy and limits applied to every outbound `fetch` request.
@@ -91,6 +92,10 @@ pub struct SqlitePragmas {
     pub foreign_keys: bool,
     /// `cache_size` per connection; negative values are KiB.
     pub cache_size: i64,
+    /// `wal_autocheckpoint` pragma, in pages. `0` turns automatic
+    /// checkpoints off, which is what a replicated database needs: the
+    /// replicator checkpoints itself, after it has copied the frames.
+    pub wal_autocheckpoint: u32,
 }
 
 impl Default for SqlitePragmas {
@@ -101,6 +106,89 @@ impl Default for SqlitePragmas {
             synchronous: "normal".into(),
             foreign_keys: true,
             cache_size: -2_000,
+            wal_autocheckpoint: 1_000,
         }
     }
 }
+
+/// Where and how often the WAL replicator ships a database.
+#[derive(Debug, Clone)]
+pub struct ReplicaOptions {
+    /// Directory receiving the generations: a second disk or a network
+    /// mount, never the directory holding the database itself.
+    pub dir: PathBuf,
+    /// How often new WAL frames are copied.
+    pub interval: Duration,
+    /// WAL size, in pages, past which the replicator checkpoints.
+    pub checkpoint_pages: u32,
+    /// Generations kept; older ones are deleted once a new one is complete.
+    pub retain: usize,
+}
+
+impl Default for ReplicaOptions {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("replica"),
+            interval: Duration::from_secs(1),
+            checkpoint_pages: 1_000,
+            retain: 2,
+        }
+    }
+}
+
+/// One full-text index over a content table (`[[database.search]]`).
+///
+/// Every name is interpolated into generated SQL, so [`validate`] only
+/// admits plain identifiers.
+///
+/// [`validate`]: SearchIndex::validate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchIndex {
+    /// Name of the FTS5 table, and what `nitr.db:search()` is called with.
+    pub name: String,
+    /// The content table the index follows.
+    pub table: String,
+    /// The content table's integer key (`rowid` when it has no
+    /// `INTEGER PRIMARY KEY`).
+    pub key: String,
+    /// Indexed text columns, in order.
+    pub columns: Vec<String>,
+    /// FTS5 `tokenize` option, e.g. `"porter unicode61"`; `None` keeps
+    /// FTS5's default (`unicode61`).
+    pub tokenize: Option<String>,
+}
+
+impl SearchIndex {
+    /// Rejects anything but plain identifiers, and an index with no
+    /// columns.
+    pub fn validate(&self) -> nitr_core::Result {
+        let ident = |what: &str, value: &str| {
+            let mut chars = value.chars();
+            let plain = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if plain {
+                Ok(())
+            } else {
+                Err(nitr_core::Error::Config(format!(
+                    "[[database.search]] {what} `{value}` must be a plain identifier \
+                     (letters, digits and `_`)"
+                )))
+            }
+        };
+        ident("name", &self.name)?;
+        ident("table", &self.table)?;
+        ident("key", &self.key)?;
+        if self.columns.is_empty() {
+            return Err(nitr_core::Error::Config(format!(
+                "[[database.search]] `{}` indexes no columns",
+                self.name
+            )));
+        }
+        for column in &self.columns {
+            ident("column", column)?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 //! Crypto and auth primitives for Lua handlers: `nitr.crypto` (hashing,
-//! HMAC, random bytes, constant-time comparison, argon2id passwords) and
-//! `nitr.auth` (Basic/Bearer `Authorization` header parsing).
+//! HMAC, random bytes, constant-time comparison, argon2id passwords, the
+//! public-key signatures and key agreement of [`crate::keypair`], and the
+//! one-time passwords of [`crate::otp`]) and `nitr.auth`
+//! (Basic/Bearer `Authorization` header parsing, plus the `jwt` middleware
+//! from [`crate::bearer`]).
 //!
 //! Primitives, not a framework: everything is implemented in Rust
 //! (RustCrypto), and scripts compose them into their own auth flows.
@@ -11,11 +14,19 @@ use base64::Engine as _;
 use base64::engine::general_purpose::{STANDARD as B64, URL_SAFE_NO_PAD as B64URL};
 use chacha20poly1305::aead::{Aead as _, Payload};
 use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::sync::Arc;
+
 use hmac::{Hmac, Mac as _};
-use mlua::{Lua, LuaString, ObjectLike as _, Table, Value};
-use sha2::{Digest as _, Sha256, Sha384, Sha512};
+use mlua::{Lua, LuaString, ObjectLike as _, Table, UserData, Value};
+use sha2::{Digest as _, Sha256};
 use subtle::ConstantTimeEq as _;
 
+use crate::jwt;
+use crate::keyfile::KeyFiles;
+use crate::keypair;
+use crate::otp;
+use crate::{FetchOptions, KeyRing};
+
 /// Upper bound for `nitr.crypto.random_bytes(n)`: large enough for any
 /// key/nonce/token, small enough that a script cannot use it as an
 /// allocation amplifier.
@@ -39,8 +50,13 @@ fn hex(bytes: &[u8]) -> String {
     out
 }
 
-/// Builds the `nitr.crypto` table.
-pub(crate) fn create_crypto_table(lua: &Lua) -> mlua::Result<Table> {
+/// Builds the `nitr.crypto` table. `fetch` is the policy JWKS key sets
+/// are fetched under.
+pub(crate) fn create_crypto_table(
+    lua: &Lua,
+    fetch: &FetchOptions,
+    files: &KeyFiles,
+) -> mlua::Result<Table> {
     let crypto = lua.create_table()?;
 
     // Digest and MAC results are lowercase hex strings: printable, easy to
@@ -87,26 +103,25 @@ pub(crate) fn create_crypto_table(lua: &Lua) -> mlua::Result<Table> {
 
     crypto.set(
         "password_hash",
-        lua.create_function(|_, password: LuaString| {
-            let mut salt = [0u8; 16];
-            getrandom::getrandom(&mut salt).map_err(rng_err)?;
-            let salt = SaltString::encode_b64(&salt).map_err(pw_err)?;
-            let hash = Argon2::default()
-                .hash_password(&password.as_bytes(), &salt)
-                .map_err(pw_err)?;
-            Ok(hash.to_string())
-        })?,
+        lua.create_function(|_, password: LuaString| password_hash(&password.as_bytes()))?,
     )?;
 
     // AEAD (XChaCha20-Poly1305): authenticated encryption for data handed
     // to a client. `seal` returns a printable token; `open` returns nil on
     // any tampering — with the ciphertext, the nonce, or the optional
-    // associated data.
+    // associated data. Under `nitr.keys` the token carries the id of the
+    // key that sealed it.
     crypto.set(
         "seal",
         lua.create_function(
-            |lua, (key, plaintext, aad): (LuaString, LuaString, Option<LuaString>)| {
-                let cipher = aead_cipher(&key)?;
+            |lua, (key, plaintext, aad): (SealKey, LuaString, Option<LuaString>)| {
+                let (id, cipher) = match &key {
+                    SealKey::Bytes(key) => (None, aead_cipher(&key.as_bytes())?),
+                    SealKey::Ring(ring) => {
+                        let (id, key) = ring.seal_key();
+                        (Some(id), aead_cipher(&key)?)
+                    }
+                };
                 let mut nonce = [0u8; 24];
                 getrandom::getrandom(&mut nonce).map_err(rng_err)?;
                 let nonce = XNonce::from(nonce);
@@ -125,7 +140,10 @@ pub(crate) fn create_crypto_table(lua: &Lua) -> mlua::Result<Table> {
                     .map_err(|_| mlua::Error::RuntimeError("encryption failed".into()))?;
                 let mut sealed = nonce.to_vec();
                 sealed.extend_from_slice(&ciphertext);
-                lua.create_string(B64URL.encode(sealed))
+                match id {
+                    Some(id) => lua.create_string(format!("{id}.{}", B64URL.encode(sealed))),
+                    None => lua.create_string(B64URL.encode(sealed)),
+                }
             },
         )?,
     )?;
@@ -133,13 +151,25 @@ pub(crate) fn create_crypto_table(lua: &Lua) -> mlua::Result<Table> {
     crypto.set(
         "open",
         lua.create_function(
-            |lua, (key, sealed, aad): (LuaString, LuaString, Option<LuaString>)| {
-                let cipher = aead_cipher(&key)?;
+            |lua, (key, sealed, aad): (SealKey, LuaString, Option<LuaString>)| {
+                let sealed = sealed.as_bytes();
+                let (cipher, sealed) = match &key {
+                    SealKey::Bytes(key) => (aead_cipher(&key.as_bytes())?, &*sealed),
+                    SealKey::Ring(ring) => {
+                        let Some((key, rest)) = std::str::from_utf8(&sealed)
+                            .ok()
+                            .and_then(|sealed| ring.open_key(sealed))
+                        else {
+                            return Ok(Value::Nil);
+                        };
+                        (aead_cipher(&key)?, rest.as_bytes())
+                    }
+                };
                 let aad = aad
                     .as_ref()
                     .map(|s| s.as_bytes().to_vec())
                     .unwrap_or_default();
-                let Ok(raw) = B64URL.decode(&*sealed.as_bytes()) else {
+                let Ok(raw) = B64URL.decode(sealed) else {
                     return Ok(Value::Nil);
                 };
                 if raw.len() < 24 {
@@ -160,28 +190,65 @@ pub(crate) fn create_crypto_table(lua: &Lua) -> mlua::Result<Table> {
         )?,
     )?;
 
-    crypto.set("jwt", create_jwt_table(lua)?)?;
+    crypto.set("jwt", create_jwt_table(lua, fetch, files)?)?;
 
     crypto.set(
         "password_verify",
         lua.create_function(|_, (password, hash): (LuaString, String)| {
-            let Ok(parsed) = PasswordHash::new(&hash) else {
-                return Ok(false);
-            };
-            Ok(Argon2::default()
-                .verify_password(&password.as_bytes(), &parsed)
-                .is_ok())
+            Ok(password_verify(&password.as_bytes(), &hash))
         })?,
     )?;
 
+    crypto.set("otp", otp::create_otp_table(lua)?)?;
+
+    keypair::add_functions(lua, &crypto, files)?;
+
     Ok(crypto)
 }
 
+/// An argon2id PHC string for `password`, under a fresh random salt.
+pub(crate) fn password_hash(password: &[u8]) -> mlua::Result<String> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).map_err(rng_err)?;
+    let salt = SaltString::encode_b64(&salt).map_err(pw_err)?;
+    let hash = Argon2::default()
+        .hash_password(password, &salt)
+        .map_err(pw_err)?;
+    Ok(hash.to_string())
+}
+
+/// Whether `password` matches the PHC string `hash`; a malformed hash
+/// matches nothing.
+pub(crate) fn password_verify(password: &[u8], hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password, &parsed).is_ok())
+}
+
+/// The key `seal`/`open` were given: key bytes or the `nitr.keys` ring.
+enum SealKey {
+    Bytes(LuaString),
+    Ring(KeyRing),
+}
+
+impl mlua::FromLua for SealKey {
+    fn from_lua(value: Value, _: &Lua) -> mlua::Result<Self> {
+        match value {
+            Value::String(key) => Ok(Self::Bytes(key)),
+            Value::UserData(ud) if ud.is::<KeyRing>() => {
+                Ok(Self::Ring(ud.borrow::<KeyRing>()?.clone()))
+            }
+            other => Err(mlua::Error::RuntimeError(format!(
+                "seal/open take a 32-byte key string or nitr.keys, got {}",
+                other.type_name()
+            ))),
+        }
+    }
+}
+
 /// Builds the AEAD cipher, insisting on a full-strength key. Deriving a
 /// key from a short passphrase here would hide the mistake; the error
 /// tells the caller how to make a real one.
-fn aead_cipher(key: &LuaString) -> mlua::Result<XChaCha20Poly1305> {
-    let key = key.as_bytes();
+fn aead_cipher(key: &[u8]) -> mlua::Result<XChaCha20Poly1305> {
     if key.len() != 32 {
         return Err(mlua::Error::RuntimeError(format!(
             "seal/open take a 32-byte key, got {} bytes — generate one with \
@@ -192,58 +259,151 @@ fn aead_cipher(key: &LuaString) -> mlua::Result<XChaCha20Poly1305> {
     // Fully qualified: importing `KeyInit` would make the `Hmac`
     // constructors ambiguous (`Mac` supplies its own `new_from_slice`).
     Ok(<XChaCha20Poly1305 as chacha20poly1305::KeyInit>::new(
-        key.as_ref().into(),
+        key.into(),
     ))
 }
 
-/// The HMAC algorithms `nitr.crypto.jwt` supports. Asymmetric algorithms
-/// (and `none`) are deliberately absent: a format needs a key-management
-/// story before RS256 helps anyone, and `alg: none` is the classic CVE.
-const JWT_ALGORITHMS: &[&str] = &["HS256", "HS384", "HS512"];
-
-/// Both callers validate `alg` against [`JWT_ALGORITHMS`] first, but the
-/// value originates in the attacker-supplied JWT header, so an unknown
-/// algorithm is an error here too — defense in depth, not a load-bearing
-/// invariant two hops away.
-fn jwt_mac(alg: &str, key: &[u8], data: &[u8]) -> mlua::Result<Vec<u8>> {
-    match alg {
-        "HS256" => {
-            let mut mac: Hmac<Sha256> = crate::utils::new_hmac(key);
-            mac.update(data);
-            Ok(mac.finalize().into_bytes().to_vec())
+/// `verify`'s failure path: `nil` plus a reason, so callers can
+/// distinguish "expired" from "forged" when deciding what to log.
+fn jwt_reject(lua: &Lua, reason: &str) -> mlua::Result<(Value, Value)> {
+    Ok((Value::Nil, Value::String(lua.create_string(reason)?)))
+}
+
+/// A parsed key set, from `nitr.crypto.jwt.keyset(...)`.
+struct LuaKeySet(jwt::Keys);
+
+impl UserData for LuaKeySet {}
+
+/// Builds a key set from `jwt.keyset`'s argument: key text (PEM, JWK or
+/// JWKS), a JWK/JWKS table, `{ file = path }` or `{ text = pem }` (either
+/// with an optional `kid` and `alg` for PEM keys), or `{ url = jwks_url,
+/// refresh? }`.
+fn keyset(source: Value, fetch: &FetchOptions, files: &KeyFiles) -> mlua::Result<jwt::Keys> {
+    let err = |msg: String| mlua::Error::RuntimeError(format!("jwt.keyset: {msg}"));
+    let parsed = |text: &str, kid: Option<&str>, alg: Option<&str>| {
+        jwt::KeySet::parse(text, kid, alg)
+            .map(|set| jwt::Keys::Static(Arc::new(set)))
+            .map_err(err)
+    };
+    let table = match source {
+        Value::String(text) => return parsed(&text.to_str()?, None, None),
+        Value::Table(table) => table,
+        other => {
+            return Err(err(format!(
+                "expected key text or a table, got {}",
+                other.type_name()
+            )));
         }
-        "HS384" => {
-            let mut mac: Hmac<Sha384> = crate::utils::new_hmac(key);
-            mac.update(data);
-            Ok(mac.finalize().into_bytes().to_vec())
+    };
+    if table.contains_key("keys")? || table.contains_key("kty")? {
+        let json = serde_json::to_string(&Value::Table(table))
+            .map_err(|e| err(format!("not a JWK: {e}")))?;
+        return parsed(&json, None, None);
+    }
+    for pair in table.pairs::<String, Value>() {
+        let (key, _) = pair?;
+        if !["file", "text", "url", "kid", "alg", "refresh"].contains(&key.as_str()) {
+            return Err(err(format!("unknown option `{key}`")));
         }
-        "HS512" => {
-            let mut mac: Hmac<Sha512> = crate::utils::new_hmac(key);
-            mac.update(data);
-            Ok(mac.finalize().into_bytes().to_vec())
+    }
+    let kid: Option<String> = table.get("kid")?;
+    let alg: Option<String> = table.get("alg")?;
+    let (file, text, url): (Option<String>, Option<String>, Option<String>) =
+        (table.get("file")?, table.get("text")?, table.get("url")?);
+    match (file, text, url) {
+        (Some(path), None, None) => {
+            let bytes = files.read(&path).map_err(err)?;
+            let text = std::str::from_utf8(&bytes)
+                .map_err(|_| err(format!("`{path}` is not UTF-8 text")))?;
+            parsed(text, kid.as_deref(), alg.as_deref()).map_err(|e| err(format!("`{path}`: {e}")))
         }
+        (None, Some(text), None) => parsed(&text, kid.as_deref(), alg.as_deref()),
+        (None, None, Some(url)) => remote_keyset(&url, table.get("refresh")?, fetch),
+        _ => Err(err("give exactly one of `file`, `text` and `url`".into())),
+    }
+}
+
+/// Default seconds between fetches of a JWKS URL.
+#[cfg(feature = "fetch")]
+const JWKS_REFRESH_SECS: f64 = 3600.0;
+
+#[cfg(feature = "fetch")]
+fn remote_keyset(url: &str, refresh: Option<f64>, fetch: &FetchOptions) -> mlua::Result<jwt::Keys> {
+    let err = |msg: String| mlua::Error::RuntimeError(format!("jwt.keyset: {msg}"));
+    let url = url::Url::parse(url).map_err(|e| err(format!("invalid url `{url}`: {e}")))?;
+    let refresh = refresh.unwrap_or(JWKS_REFRESH_SECS);
+    if !(refresh.is_finite() && refresh > 0.0) {
+        return Err(err(format!(
+            "`refresh` must be positive seconds, got {refresh}"
+        )));
+    }
+    Ok(jwt::Keys::Remote(jwt::RemoteKeySet::shared(
+        url,
+        std::time::Duration::from_secs_f64(refresh),
+        fetch,
+    )))
+}
+
+#[cfg(not(feature = "fetch"))]
+fn remote_keyset(_: &str, _: Option<f64>, _: &FetchOptions) -> mlua::Result<jwt::Keys> {
+    Err(mlua::Error::RuntimeError(
+        "jwt.keyset: JWKS URLs need the `fetch` Cargo feature; load the keys \
+         from a file instead"
+            .into(),
+    ))
+}
+
+/// The key `verify` was given: an HMAC secret string or a key set.
+pub(crate) fn verify_keys(key: Value) -> mlua::Result<jwt::Keys> {
+    match key {
+        Value::String(secret) => Ok(jwt::Keys::Static(Arc::new(jwt::KeySet::secret(
+            &secret.as_bytes(),
+        )))),
+        Value::UserData(ud) if ud.is::<LuaKeySet>() => Ok(ud.borrow::<LuaKeySet>()?.0.clone()),
         other => Err(mlua::Error::RuntimeError(format!(
-            "unsupported JWT algorithm `{other}` (supported: {})",
-            JWT_ALGORITHMS.join(", ")
+            "jwt.verify needs a secret string or a nitr.crypto.jwt.keyset(...), got {}",
+            other.type_name()
         ))),
     }
 }
 
-fn unix_now() -> f64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .map_or(0.0, |d| d.as_secs_f64())
+/// The checks `verify` options ask for: `{ algorithms, leeway?, iss?,
+/// aud? }`, shared with the `nitr.auth.jwt` middleware.
+pub(crate) fn policy(opts: &Table) -> mlua::Result<jwt::Policy> {
+    Ok(jwt::Policy {
+        algorithms: opts
+            .get::<Option<Vec<String>>>("algorithms")?
+            .ok_or_else(|| {
+                mlua::Error::RuntimeError(
+                    "jwt.verify requires an `algorithms` allow-list, e.g. \
+                 { algorithms = { \"HS256\" } }"
+                        .into(),
+                )
+            })?,
+        leeway: opts.get::<Option<f64>>("leeway")?.unwrap_or(0.0),
+        issuers: one_or_many(opts.get("iss")?, "iss")?,
+        audiences: one_or_many(opts.get("aud")?, "aud")?,
+    })
 }
 
-/// `verify`'s failure path: `nil` plus a reason, so callers can
-/// distinguish "expired" from "forged" when deciding what to log.
-fn jwt_reject(lua: &Lua, reason: &str) -> mlua::Result<(Value, Value)> {
-    Ok((Value::Nil, Value::String(lua.create_string(reason)?)))
+/// An option given as one string or a list of them.
+fn one_or_many(value: Value, name: &str) -> mlua::Result<Vec<String>> {
+    match value {
+        Value::Nil => Ok(Vec::new()),
+        Value::String(s) => Ok(vec![s.to_str()?.to_owned()]),
+        Value::Table(list) => list.sequence_values::<String>().collect(),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "jwt.verify `{name}` must be a string or a list of strings, got {}",
+            other.type_name()
+        ))),
+    }
 }
 
 /// Builds `nitr.crypto.jwt`: verification first, signing second.
-fn create_jwt_table(lua: &Lua) -> mlua::Result<Table> {
+fn create_jwt_table(lua: &Lua, fetch: &FetchOptions, files: &KeyFiles) -> mlua::Result<Table> {
     let jwt = lua.create_table()?;
+    let fetch = fetch.clone();
+    let files = files.clone();
 
     jwt.set(
         "sign",
@@ -255,10 +415,10 @@ fn create_jwt_table(lua: &Lua) -> mlua::Result<Table> {
                         .unwrap_or_else(|| "HS256".into()),
                     None => "HS256".into(),
                 };
-                if !JWT_ALGORITHMS.contains(&alg.as_str()) {
+                if !jwt::SIGN_ALGORITHMS.contains(&alg.as_str()) {
                     return Err(mlua::Error::RuntimeError(format!(
-                        "unsupported JWT algorithm `{alg}` (supported: {})",
-                        JWT_ALGORITHMS.join(", ")
+                        "jwt.sign cannot sign with `{alg}` (supported: {})",
+                        jwt::SIGN_ALGORITHMS.join(", ")
                     )));
                 }
                 let header = B64URL.encode(format!(r#"{{"alg":"{alg}","typ":"JWT"}}"#));
@@ -272,99 +432,68 @@ fn create_jwt_table(lua: &Lua) -> mlua::Result<Table> {
                     })
                     .map(|json| B64URL.encode(json))?;
                 let signing_input = format!("{header}.{payload}");
-                let sig = B64URL.encode(jwt_mac(&alg, &key.as_bytes(), signing_input.as_bytes())?);
+                let mac = jwt::jwt_mac(&alg, &key.as_bytes(), signing_input.as_bytes())
+                    .map_err(mlua::Error::RuntimeError)?;
+                let sig = B64URL.encode(mac);
                 lua.create_string(format!("{signing_input}.{sig}"))
             },
         )?,
     )?;
 
-    // jwt.verify(token, key, { algorithms = {...}, leeway? }) ->
-    //   claims, nil | nil, reason
+    // jwt.keyset(source) -> key set
+    //
+    // Public keys are parsed here, once, so a bad key file fails at
+    // startup rather than on the first request.
+    jwt.set(
+        "keyset",
+        lua.create_function(move |_, source: Value| {
+            Ok(LuaKeySet(keyset(source, &fetch, &files)?))
+        })?,
+    )?;
+
+    // jwt.verify(token, key, { algorithms = {...}, leeway?, iss?, aud? })
+    // jwt.verify(token, { jwks = keyset, algorithms = {...}, ... })
+    //   -> claims, nil | nil, reason
     //
     // The explicit `algorithms` allow-list is required, and the algorithm
     // named by the token's own header is honored only if the list contains
     // it — the two properties whose absence makes hand-rolled JWT
     // verification a recurring CVE. Expiry and not-before are checked by
-    // default.
+    // default; issuer and audience when given.
     jwt.set(
         "verify",
-        lua.create_function(|lua, (token, key, opts): (LuaString, LuaString, Table)| {
-            let allowed: Vec<String> =
-                opts.get::<Option<Vec<String>>>("algorithms")?
-                    .ok_or_else(|| {
-                        mlua::Error::RuntimeError(
-                            "jwt.verify requires an `algorithms` allow-list, e.g. \
-                             { algorithms = { \"HS256\" } }"
+        lua.create_async_function(
+            |lua, (token, key, opts): (LuaString, Value, Option<Table>)| async move {
+                let (keys, opts) = match (key, opts) {
+                    (key, Some(opts)) => (verify_keys(key)?, opts),
+                    (Value::Table(opts), None) => {
+                        let key = match opts.get::<Value>("jwks")? {
+                            Value::Nil => opts.get::<Value>("key")?,
+                            jwks => jwks,
+                        };
+                        (verify_keys(key)?, opts)
+                    }
+                    _ => {
+                        return Err(mlua::Error::RuntimeError(
+                            "jwt.verify takes (token, key, opts) or (token, { jwks = ..., \
+                             algorithms = ... })"
                                 .into(),
-                        )
-                    })?;
-            for alg in &allowed {
-                if !JWT_ALGORITHMS.contains(&alg.as_str()) {
-                    return Err(mlua::Error::RuntimeError(format!(
-                        "unsupported JWT algorithm `{alg}` in the allow-list \
-                             (supported: {})",
-                        JWT_ALGORITHMS.join(", ")
-                    )));
+                        ));
+                    }
+                };
+                let policy = policy(&opts)?;
+                policy.check(&keys).map_err(mlua::Error::RuntimeError)?;
+
+                let token = token.to_string_lossy();
+                match jwt::verify(&token, &keys, &policy).await {
+                    Ok(claims) => {
+                        use mlua::LuaSerdeExt as _;
+                        Ok((lua.to_value(&claims)?, Value::Nil))
+                    }
+                    Err(reason) => jwt_reject(&lua, reason),
                 }
-            }
-            let leeway: f64 = opts.get::<Option<f64>>("leeway")?.unwrap_or(0.0);
-
-            let token = token.to_string_lossy().to_string();
-            let mut parts = token.split('.');
-            let (Some(header), Some(payload), Some(sig), None) =
-                (parts.next(), parts.next(), parts.next(), parts.next())
-            else {
-                return jwt_reject(lua, "malformed token");
-            };
-
-            let Some(header_json) = B64URL
-                .decode(header)
-                .ok()
-                .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
-            else {
-                return jwt_reject(lua, "malformed header");
-            };
-            // The header's algorithm is checked against the caller's
-            // list, never trusted on its own.
-            let alg = header_json
-                .get("alg")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-            if !allowed.iter().any(|a| a == alg) {
-                return jwt_reject(lua, "algorithm not allowed");
-            }
-
-            let signing_input = format!("{header}.{payload}");
-            let expected = jwt_mac(alg, &key.as_bytes(), signing_input.as_bytes())?;
-            let ok = B64URL
-                .decode(sig)
-                .is_ok_and(|sig| sig.len() == expected.len() && bool::from(sig.ct_eq(&expected)));
-            if !ok {
-                return jwt_reject(lua, "invalid signature");
-            }
-
-            let Some(claims) = B64URL
-                .decode(payload)
-                .ok()
-                .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
-            else {
-                return jwt_reject(lua, "malformed claims");
-            };
-            let now = unix_now();
-            if let Some(exp) = claims.get("exp").and_then(|v| v.as_f64())
-                && now > exp + leeway
-            {
-                return jwt_reject(lua, "token expired");
-            }
-            if let Some(nbf) = claims.get("nbf").and_then(|v| v.as_f64())
-                && now < nbf - leeway
-            {
-                return jwt_reject(lua, "token not yet valid");
-            }
-
-            use mlua::LuaSerdeExt as _;
-            Ok((lua.to_value(&claims)?, Value::Nil))
-        })?,
+            },
+        )?,
     )?;
 
     Ok(jwt)
@@ -417,12 +546,15 @@ pub(crate) fn create_auth_table(lua: &Lua) -> mlua::Result<Table> {
         })?,
     )?;
 
+    // nitr.auth.jwt(opts): bearer-token middleware (see `bearer.rs`).
+    auth.set("jwt", crate::bearer::create_jwt_middleware_fn(lua)?)?;
+
     Ok(auth)
 }
 
 /// Extracts the `Authorization` header from a request-like value (userdata
 /// or table with a `headers` field) or accepts the header string directly.
-fn authorization(source: &Value) -> mlua::Result<Option<String>> {
+pub(crate) fn authorization(source: &Value) -> mlua::Result<Option<String>> {
     let headers: Option<Table> = match source {
         Value::String(s) => return Ok(Some(s.to_string_lossy().to_string())),
         Value::UserData(ud) => ud.get("headers").ok(),
@@ -462,7 +594,12 @@ mod tests {
     #[test]
     fn passwords_hash_and_verify() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let hash: String = crypto
             .get::<mlua::Function>("password_hash")
             .expect("fn")
@@ -483,7 +620,12 @@ mod tests {
     #[test]
     fn seal_and_open_round_trip_and_reject_tampering() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let seal: mlua::Function = crypto.get("seal").expect("fn");
         let open: mlua::Function = crypto.get("open").expect("fn");
         let key = "0123456789abcdef0123456789abcdef"; // 32 bytes
@@ -524,7 +666,12 @@ mod tests {
     #[test]
     fn jwt_signs_and_verifies_with_an_allow_list() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let jwt: Table = crypto.get("jwt").expect("jwt");
         let sign: mlua::Function = jwt.get("sign").expect("fn");
         let verify: mlua::Function = jwt.get("verify").expect("fn");
@@ -611,7 +758,12 @@ mod tests {
     #[test]
     fn jwt_time_claims_honor_nbf_and_leeway() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let jwt: Table = crypto.get("jwt").expect("jwt");
         let sign: mlua::Function = jwt.get("sign").expect("fn");
         let verify: mlua::Function = jwt.get("verify").expect("fn");
@@ -656,7 +808,12 @@ mod tests {
     #[test]
     fn jwt_survives_hostile_tokens_without_panicking() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let jwt: Table = crypto.get("jwt").expect("jwt");
         let verify: mlua::Function = jwt.get("verify").expect("fn");
         let allow: Table = lua
@@ -694,7 +851,12 @@ mod tests {
     #[test]
     fn seal_handles_degenerate_inputs() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let seal: mlua::Function = crypto.get("seal").expect("fn");
         let open: mlua::Function = crypto.get("open").expect("fn");
         let key = "0123456789abcdef0123456789abcdef";
@@ -717,10 +879,59 @@ mod tests {
         assert_eq!(opened.as_deref(), Some("ñandú 🦤"));
     }
 
+    #[test]
+    fn seal_under_the_key_ring_names_its_key() {
+        let dir = std::env::temp_dir().join(format!("nitr-seal-ring-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        crate::keys::rotate(&dir, None).expect("key");
+        let ring = KeyRing::load(crate::KeySource {
+            dir: Some(dir.clone()),
+            env: None,
+        })
+        .expect("ring");
+        let lua = Lua::new();
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
+        let seal: mlua::Function = crypto.get("seal").expect("fn");
+        let open: mlua::Function = crypto.get("open").expect("fn");
+
+        let old: String = seal.call((ring.clone(), "v1", Value::Nil)).expect("seal");
+        assert!(old.starts_with("1."), "got {old}");
+        crate::keys::rotate(&dir, None).expect("key");
+        ring.reload().expect("reload");
+        let new: String = seal.call((ring.clone(), "v2", Value::Nil)).expect("seal");
+        assert!(new.starts_with("2."), "got {new}");
+
+        // Either key opens its own box; a box without an id, or naming a
+        // key not in the ring, opens to nil.
+        for (sealed, plain) in [(&old, Some("v1")), (&new, Some("v2"))] {
+            let opened: Option<String> = open
+                .call((ring.clone(), sealed.as_str(), Value::Nil))
+                .expect("open");
+            assert_eq!(opened.as_deref(), plain);
+        }
+        let (_, body) = new.split_once('.').expect("id");
+        for sealed in [body.to_string(), format!("3.{body}")] {
+            let opened: Option<String> =
+                open.call((ring.clone(), sealed, Value::Nil)).expect("open");
+            assert_eq!(opened, None);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn digests_are_hex_and_deterministic() {
         let lua = Lua::new();
-        let crypto = create_crypto_table(&lua).expect("crypto table");
+        let crypto = create_crypto_table(
+            &lua,
+            &FetchOptions::default(),
+            &KeyFiles::new(&lua, None).expect("key files"),
+        )
+        .expect("crypto table");
         let sha256: mlua::Function = crypto.get("sha256").expect("fn");
         assert_eq!(
             sha256.call::<String>("abc").expect("digest"),
@@ -745,7 +956,7 @@ mod tests {
             pos in proptest::prelude::any::<proptest::sample::Index>(),
         ) {
             let lua = Lua::new();
-            let crypto = create_crypto_table(&lua).expect("crypto table");
+            let crypto = create_crypto_table(&lua, &FetchOptions::default(), &KeyFiles::new(&lua, None).expect("key files")).expect("crypto table");
             let seal: mlua::Function = crypto.get("seal").expect("fn");
             let open: mlua::Function = crypto.get("open").expect("fn");
             let key = "0123456789abcdef0123456789abcdef";
@@ -17,6 +17,7 @@ use mlua::{Function, Lua, MetaMethod, ObjectLike as _, Table, Value};
 use subtle::ConstantTimeEq as _;
 
 use crate::http;
+use crate::keys::Secret;
 
 /// The token issued for the request currently executing in this Lua state.
 ///
@@ -32,14 +33,14 @@ struct CsrfSlot {
 /// Everything the middleware needs per request, resolved once when the
 /// factory runs (at app compile time, not per request).
 struct Config {
-    secret: String,
+    secret: Secret,
     cookie: String,
     header: String,
     field: String,
     cookie_opts: Option<Table>,
 }
 
-fn new_token() -> mlua::Result<String> {
+pub(crate) fn new_token() -> mlua::Result<String> {
     let mut buf = [0u8; 32];
     getrandom::getrandom(&mut buf)
         .map_err(|err| mlua::Error::RuntimeError(format!("failed to read OS entropy: {err}")))?;
@@ -88,7 +89,9 @@ fn cookie_token(req: &Value, config: &Config) -> Option<String> {
     };
     let cookies = ud.get::<mlua::AnyUserData>("cookies").ok()?;
     let cookies = cookies.borrow::<http::RequestCookies>().ok()?;
-    http::verify(&config.cookie, cookies.get(&config.cookie)?, &config.secret)
+    config
+        .secret
+        .verify(&config.cookie, cookies.get(&config.cookie)?)
 }
 
 /// The middleware handler around one request.
@@ -148,7 +151,7 @@ async fn handle(lua: Lua, config: Arc<Config>, next: Function, req: Value) -> ml
             Some(opts) => opts.clone(),
             None => default_cookie_opts(&lua)?,
         };
-        let signed = http::sign(&config.cookie, &token, &config.secret);
+        let signed = config.secret.sign(&config.cookie, &token);
         http::attach_cookie(
             resp,
             http::build_cookie(&config.cookie, &signed, Some(&opts))?,
@@ -194,14 +197,7 @@ fn create_call_metatable(lua: &Lua) -> mlua::Result<Table> {
     meta.set(
         MetaMethod::Call.name(),
         lua.create_function(|lua, (_, opts): (Table, Table)| {
-            let secret: String = opts.get::<Option<String>>("secret")?.ok_or_else(|| {
-                mlua::Error::RuntimeError("nitr.csrf requires a `secret` option".into())
-            })?;
-            if secret.len() < 16 {
-                return Err(mlua::Error::RuntimeError(
-                    "nitr.csrf `secret` must be at least 16 bytes".into(),
-                ));
-            }
+            let secret = Secret::from_opts(&opts, "nitr.csrf")?;
             let config = Arc::new(Config {
                 secret,
                 cookie: opts
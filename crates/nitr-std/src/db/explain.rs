@@ -0,0 +1,475 @@
+//! `EXPLAIN QUERY PLAN` capture: the slow-query log and
+//! `nitr check --explain` both report a statement as its normalized text
+//! plus the plan SQLite chose for it, so a full table scan is obvious.
+//!
+//! Normalized means every literal replaced with `?`: the text identifies
+//! the statement without carrying the values some caller inlined into it.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, OpenFlags};
+
+use nitr_core::{Error, Result};
+
+/// The statement with string, blob and numeric literals replaced by `?`,
+/// comments dropped and whitespace collapsed.
+///
+/// SQLite reads a double-quoted token that names nothing as a string
+/// literal, so `"..."` is kept only when `is_name` recognizes it (see
+/// [`schema_names`]); anything else could be a value and becomes `?`.
+/// Backquoted and bracketed tokens are always names.
+pub fn normalize(sql: &str, is_name: impl Fn(&str) -> bool) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut space = false;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        let text = match c {
+            c if c.is_whitespace() => {
+                space = true;
+                continue;
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                space = true;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+                space = true;
+                continue;
+            }
+            '\'' => {
+                take_quoted(&mut chars, '\'');
+                "?".to_owned()
+            }
+            'x' | 'X' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                take_quoted(&mut chars, '\'');
+                "?".to_owned()
+            }
+            // `?NNN` is a parameter, not a literal: its number goes too.
+            '?' => {
+                while chars.next_if(char::is_ascii_digit).is_some() {}
+                "?".to_owned()
+            }
+            c if c.is_ascii_digit()
+                || (c == '.' && chars.peek().is_some_and(char::is_ascii_digit)) =>
+            {
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.')
+                    .is_some()
+                {}
+                "?".to_owned()
+            }
+            '"' => {
+                let rest = take_quoted(&mut chars, '"');
+                let name = rest
+                    .strip_suffix('"')
+                    .unwrap_or(&rest)
+                    .replace("\"\"", "\"");
+                if is_name(&name) {
+                    format!("\"{rest}")
+                } else {
+                    "?".to_owned()
+                }
+            }
+            // Backquoted identifiers are names, not values: kept verbatim.
+            '`' => format!("`{}", take_quoted(&mut chars, '`')),
+            '[' => {
+                let mut text = String::from('[');
+                for c in chars.by_ref() {
+                    text.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+                text
+            }
+            c if is_word(c) => {
+                let mut text = String::from(c);
+                while let Some(c) = chars.next_if(|c| is_word(*c)) {
+                    text.push(c);
+                }
+                text
+            }
+            c => c.to_string(),
+        };
+        if space && !out.is_empty() {
+            out.push(' ');
+        }
+        space = false;
+        out.push_str(&text);
+    }
+    out
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// The rest of a quoted run, closing quote included; a doubled quote is
+/// an escaped one.
+fn take_quoted(chars: &mut std::iter::Peekable<std::str::Chars<'_>>, quote: char) -> String {
+    let mut text = String::new();
+    while let Some(c) = chars.next() {
+        text.push(c);
+        if c == quote {
+            if chars.peek() == Some(&quote) {
+                text.push(quote);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+    }
+    text
+}
+
+/// The tables, views and columns of the connection's main schema, in
+/// lowercase (SQLite compares names without regard to ASCII case), for
+/// [`normalize`]. Empty when the schema cannot be read, which makes every
+/// double-quoted token a `?`.
+pub fn schema_names(conn: &Connection) -> HashSet<String> {
+    let read = || -> rusqlite::Result<HashSet<String>> {
+        let mut stmt = conn.prepare(
+            "SELECT lower(m.name) FROM sqlite_schema m WHERE m.type IN ('table', 'view')
+             UNION
+             SELECT lower(p.name) FROM sqlite_schema m, pragma_table_info(m.name) p
+             WHERE m.type IN ('table', 'view')",
+        )?;
+        stmt.query_map([], |row| row.get(0))?.collect()
+    };
+    read().unwrap_or_default()
+}
+
+/// Whether `name` is in `names` from [`schema_names`].
+pub fn is_schema_name(names: &HashSet<String>, name: &str) -> bool {
+    names.contains(&name.to_lowercase())
+}
+
+/// The plan SQLite chooses for `sql`, one line per step, indented by
+/// depth like the `sqlite3` shell prints it. Parameters stay unbound:
+/// the plan does not depend on their values.
+pub fn query_plan(conn: &Connection, sql: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+    let mut rows = stmt.raw_query();
+    let mut depth: HashMap<i64, usize> = HashMap::new();
+    let mut lines = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let parent: i64 = row.get(1)?;
+        let detail: String = row.get(3)?;
+        let level = depth.get(&parent).map_or(0, |d| d + 1);
+        depth.insert(id, level);
+        lines.push(format!("{}{detail}", "  ".repeat(level)));
+    }
+    Ok(lines)
+}
+
+/// Whether a plan has a `SCAN` step: every row of a table (or of one of
+/// its indexes) read, rather than a `SEARCH` through an index.
+pub fn is_full_scan(plan: &[String]) -> bool {
+    plan.iter().any(|line| {
+        let line = line.trim_start();
+        line.starts_with("SCAN ") && line != "SCAN CONSTANT ROW"
+    })
+}
+
+/// One statement found by [`explain_paths`].
+#[derive(Debug)]
+pub struct Explained {
+    /// `file:line` where the statement starts.
+    pub source: String,
+    /// The normalized statement.
+    pub sql: String,
+    /// The plan, or why SQLite could not prepare the statement (usually a
+    /// fragment the script completes at run time).
+    pub plan: std::result::Result<Vec<String>, String>,
+}
+
+/// Explains every query found under `paths` (files, or directories walked
+/// for `.sql` and `.lua` files) against the database at `db`, opened
+/// read-only.
+///
+/// `.sql` files contribute every statement; Lua files every string literal
+/// that starts like a query (`SELECT`, `INSERT`, `UPDATE`, `DELETE`,
+/// `WITH`, `REPLACE`).
+pub fn explain_paths(db: &Path, paths: &[PathBuf]) -> Result<Vec<Explained>> {
+    let conn =
+        Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|err| {
+            Error::Config(format!(
+                "cannot open the database {} to explain queries: {err}",
+                db.display()
+            ))
+        })?;
+    let mut files = Vec::new();
+    for path in paths {
+        collect_files(path, &mut files)?;
+    }
+    let names = schema_names(&conn);
+    let mut explained = Vec::new();
+    for file in files {
+        let text = std::fs::read_to_string(&file)
+            .map_err(|err| Error::Config(format!("cannot read {}: {err}", file.display())))?;
+        let found = if file.extension().is_some_and(|ext| ext == "sql") {
+            sql_statements(&text)
+        } else {
+            lua_queries(&text)
+        };
+        for (line, sql) in found {
+            explained.push(Explained {
+                source: format!("{}:{line}", file.display()),
+                sql: normalize(&sql, |name| is_schema_name(&names, name)),
+                plan: query_plan(&conn, &sql).map_err(|err| err.to_string()),
+            });
+        }
+    }
+    Ok(explained)
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let entries = std::fs::read_dir(path).map_err(|err| {
+        Error::Config(format!(
+            "cannot read {} (listed in `explain_paths`): {err}",
+            path.display()
+        ))
+    })?;
+    let mut entries: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_files(&entry, files)?;
+        } else if entry
+            .extension()
+            .is_some_and(|ext| ext == "sql" || ext == "lua")
+        {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// The statements of a `.sql` file with the line each starts on, split on
+/// `;` outside quotes and comments.
+fn sql_statements(text: &str) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    let mut current = String::new();
+    let mut start = 1;
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if current.trim().is_empty() {
+            start = line;
+        }
+        match c {
+            '\'' | '"' => {
+                current.push(c);
+                let quoted = take_quoted(&mut chars, c);
+                line += quoted.matches('\n').count();
+                current.push_str(&quoted);
+                continue;
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+                current.push('\n');
+                continue;
+            }
+            ';' => {
+                if !current.trim().is_empty() {
+                    found.push((start, current.trim().to_owned()));
+                }
+                current.clear();
+                continue;
+            }
+            '\n' => line += 1,
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        found.push((start, current.trim().to_owned()));
+    }
+    found
+}
+
+/// Lua string literals that look like queries, with their line.
+fn lua_queries(text: &str) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    let mut line = 1;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let start = line;
+        let (literal, consumed) = if let Some(tail) = rest.strip_prefix("--") {
+            // A comment, long or short: skipped, never searched.
+            match long_bracket(tail) {
+                Some((_, len)) => (None, 2 + len),
+                None => (None, 2 + tail.find('\n').unwrap_or(tail.len())),
+            }
+        } else if c == '"' || c == '\'' {
+            let (value, len) = short_string(&rest[1..], c);
+            (Some(value), 1 + len)
+        } else if let Some((value, len)) = long_bracket(rest) {
+            (Some(value), len)
+        } else {
+            (None, c.len_utf8())
+        };
+        line += rest[..consumed].matches('\n').count();
+        rest = &rest[consumed..];
+        if let Some(value) = literal
+            && looks_like_query(&value)
+        {
+            found.push((start, value.trim().to_owned()));
+        }
+    }
+    found
+}
+
+/// A `[[...]]` / `[==[...]==]` long bracket at the start of `text`: its
+/// contents and the bytes it spans.
+fn long_bracket(text: &str) -> Option<(String, usize)> {
+    let level = text.strip_prefix('[')?.find(|c| c != '=')?;
+    if !text[1 + level..].starts_with('[') {
+        return None;
+    }
+    let open = level + 2;
+    let close = format!("]{}]", "=".repeat(level));
+    match text[open..].find(&close) {
+        Some(end) => Some((text[open..open + end].to_owned(), open + end + close.len())),
+        None => Some((text[open..].to_owned(), text.len())),
+    }
+}
+
+/// A quoted Lua string after its opening quote: the value (common escapes
+/// decoded) and the bytes it spans, closing quote included.
+fn short_string(text: &str, quote: char) -> (String, usize) {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return (value, i + 1),
+            '\n' => return (value, i),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, c)) => value.push(c),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    (value, text.len())
+}
+
+fn looks_like_query(text: &str) -> bool {
+    let word: String = text
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    ["SELECT", "INSERT", "UPDATE", "DELETE", "WITH", "REPLACE"]
+        .iter()
+        .any(|kw| word.eq_ignore_ascii_case(kw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_drops_literals_and_keeps_names() {
+        let names = HashSet::from(["t1".to_owned()]);
+        let is_name = |name: &str| is_schema_name(&names, name);
+        assert_eq!(
+            normalize(
+                "SELECT  id, \"t1\".total FROM t1 -- note\n WHERE token = 'it''s secret' \
+                 AND n > 42 AND b = x'00ff' /* c */ AND f = -1.5e3",
+                is_name
+            ),
+            "SELECT id, \"t1\".total FROM t1 WHERE token = ? AND n > ? AND b = ? AND f = -?"
+        );
+        assert_eq!(normalize("select ?1, :name", is_name), "select ?, :name");
+    }
+
+    #[test]
+    fn double_quoted_values_are_dropped_unless_they_name_the_schema() {
+        let conn = Connection::open_in_memory().expect("open");
+        conn.execute_batch(
+            "CREATE TABLE \"Orders\" (id INTEGER PRIMARY KEY, \"my \"\"col\"\"\" TEXT)",
+        )
+        .expect("schema");
+        let names = schema_names(&conn);
+        let is_name = |name: &str| is_schema_name(&names, name);
+        // SQLite would read `"hunter2"` as a string: it names nothing.
+        assert_eq!(
+            normalize(
+                "SELECT \"ID\", \"my \"\"col\"\"\" FROM \"orders\" WHERE secret = \"hunter2\"",
+                is_name
+            ),
+            "SELECT \"ID\", \"my \"\"col\"\"\" FROM \"orders\" WHERE secret = ?"
+        );
+        // Without a schema every double-quoted token goes; backquotes stay.
+        assert_eq!(
+            normalize("SELECT `a` FROM \"t\"", |_| false),
+            "SELECT `a` FROM ?"
+        );
+    }
+
+    #[test]
+    fn plans_flag_full_scans() {
+        let conn = Connection::open_in_memory().expect("open");
+        conn.execute_batch(
+            "CREATE TABLE orders (id INTEGER PRIMARY KEY, customer TEXT, total INTEGER);
+             CREATE INDEX orders_customer ON orders (customer);",
+        )
+        .expect("schema");
+        let scan = query_plan(&conn, "SELECT * FROM orders WHERE total > ?").expect("plan");
+        assert!(is_full_scan(&scan), "{scan:?}");
+        let search = query_plan(&conn, "SELECT * FROM orders WHERE customer = ?").expect("plan");
+        assert!(!is_full_scan(&search), "{search:?}");
+        assert!(search[0].contains("orders_customer"), "{search:?}");
+    }
+
+    #[test]
+    fn queries_are_found_in_lua_and_sql_sources() {
+        let lua = "-- SELECT in a comment\nlocal a = nitr.db:query(\"SELECT * FROM t\")\n\
+                   local b = [[\n  update t set v = 1]] local c = 'not a query'\n";
+        assert_eq!(
+            lua_queries(lua),
+            vec![
+                (2, "SELECT * FROM t".into()),
+                (3, "update t set v = 1".into())
+            ]
+        );
+        let sql = "-- top\nSELECT 1;\n\nINSERT INTO t VALUES ('a;b');\n";
+        assert_eq!(
+            sql_statements(sql),
+            vec![
+                (2, "SELECT 1".into()),
+                (4, "INSERT INTO t VALUES ('a;b')".into())
+            ]
+        );
+    }
+}
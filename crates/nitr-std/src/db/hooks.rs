@@ -0,0 +1,202 @@
+//! Records one connection's row changes and publishes them after commit.
+//!
+//! `update_hook` reports each row as the statement touches it — before the
+//! transaction has decided anything. The rows wait in `pending`;
+//! `commit_hook` moves them to `committed`, `rollback_hook` drops both, and
+//! only once the connection is back in autocommit mode after a statement
+//! (the commit really happened) does [`Recorder::flush`] hand them to the
+//! feed. A commit that fails after its hook ran leaves the connection in
+//! its transaction, so nothing is published for it.
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+use rusqlite::hooks::Action;
+
+use crate::changes::{Change, ChangeFeed, MAX_PENDING};
+
+#[derive(Default)]
+struct Buffer {
+    rows: Vec<Change>,
+    /// Tables touched after `rows` reached [`MAX_PENDING`].
+    overflow: BTreeSet<Arc<str>>,
+}
+
+impl Buffer {
+    fn push(&mut self, change: Change) {
+        if self.rows.len() < MAX_PENDING {
+            self.rows.push(change);
+        } else {
+            self.overflow.insert(change.table);
+        }
+    }
+
+    fn append(&mut self, other: &mut Buffer) {
+        for change in other.rows.drain(..) {
+            self.push(change);
+        }
+        self.overflow.append(&mut other.overflow);
+    }
+
+    fn clear(&mut self) {
+        self.rows.clear();
+        self.overflow.clear();
+    }
+}
+
+/// The per-connection half of the change feed.
+pub(crate) struct Recorder {
+    feed: ChangeFeed,
+    pending: Arc<Mutex<Buffer>>,
+    committed: Arc<Mutex<Buffer>>,
+}
+
+impl Recorder {
+    /// Installs the hooks on `conn`. `hidden` names tables never reported:
+    /// the shadow tables behind full-text indexes, which SQLite writes
+    /// like any other.
+    pub(crate) fn install(
+        feed: &ChangeFeed,
+        conn: &Connection,
+        hidden: HashSet<String>,
+    ) -> rusqlite::Result<Self> {
+        let pending = Arc::new(Mutex::new(Buffer::default()));
+        let committed = Arc::new(Mutex::new(Buffer::default()));
+
+        let rows = pending.clone();
+        conn.update_hook(Some(
+            move |action: Action, _db: &str, table: &str, rowid: i64| {
+                // Nitr's own bookkeeping and SQLite's internals are nobody's
+                // business.
+                if table.starts_with("_nitr_")
+                    || table.starts_with("sqlite_")
+                    || hidden.contains(table)
+                {
+                    return;
+                }
+                let op = match action {
+                    Action::SQLITE_INSERT => "insert",
+                    Action::SQLITE_UPDATE => "update",
+                    Action::SQLITE_DELETE => "delete",
+                    _ => return,
+                };
+                lock(&rows).push(Change {
+                    table: table.into(),
+                    op,
+                    rowid: Some(rowid),
+                });
+            },
+        ))?;
+
+        let (from, to) = (pending.clone(), committed.clone());
+        conn.commit_hook(Some(move || {
+            lock(&to).append(&mut lock(&from));
+            // `false` lets the commit proceed.
+            false
+        }))?;
+
+        let (a, b) = (pending.clone(), committed.clone());
+        conn.rollback_hook(Some(move || {
+            lock(&a).clear();
+            lock(&b).clear();
+        }))?;
+
+        Ok(Self {
+            feed: feed.clone(),
+            pending,
+            committed,
+        })
+    }
+
+    /// Publishes what the last commit made durable. Called after every
+    /// statement; a no-op while a transaction is still open.
+    pub(crate) fn flush(&self, conn: &Connection) {
+        if !conn.is_autocommit() {
+            return;
+        }
+        let mut committed = lock(&self.committed);
+        if committed.rows.is_empty() && committed.overflow.is_empty() {
+            return;
+        }
+        self.feed.publish(committed.rows.drain(..));
+        self.feed.publish(
+            std::mem::take(&mut committed.overflow)
+                .into_iter()
+                .map(|table| Change {
+                    table,
+                    op: "many",
+                    rowid: None,
+                }),
+        );
+        // A statement that failed outside any transaction left nothing
+        // worth keeping either.
+        lock(&self.pending).clear();
+    }
+}
+
+fn lock(buffer: &Mutex<Buffer>) -> std::sync::MutexGuard<'_, Buffer> {
+    buffer.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv(rx: &mut tokio::sync::broadcast::Receiver<Arc<Change>>) -> Vec<(String, &'static str)> {
+        let mut seen = Vec::new();
+        while let Ok(change) = rx.try_recv() {
+            seen.push((change.table.to_string(), change.op));
+        }
+        seen
+    }
+
+    #[test]
+    fn only_committed_rows_are_published() {
+        let feed = ChangeFeed::new(64);
+        let mut rx = feed.subscribe();
+        let conn = Connection::open_in_memory().expect("open");
+        let recorder = Recorder::install(&feed, &conn, HashSet::new()).expect("hooks");
+        conn.execute_batch("CREATE TABLE orders (id INTEGER PRIMARY KEY, total INTEGER)")
+            .expect("schema");
+
+        conn.execute("INSERT INTO orders (total) VALUES (1)", [])
+            .expect("insert");
+        recorder.flush(&conn);
+        assert_eq!(recv(&mut rx), vec![("orders".into(), "insert")]);
+
+        // Nothing leaves an open transaction, and a rollback drops it.
+        conn.execute_batch("BEGIN; UPDATE orders SET total = 2")
+            .expect("begin");
+        recorder.flush(&conn);
+        assert!(recv(&mut rx).is_empty());
+        conn.execute_batch("ROLLBACK").expect("rollback");
+        recorder.flush(&conn);
+        assert!(recv(&mut rx).is_empty());
+
+        conn.execute_batch("BEGIN; DELETE FROM orders WHERE id = 1; COMMIT")
+            .expect("delete");
+        recorder.flush(&conn);
+        assert_eq!(recv(&mut rx), vec![("orders".into(), "delete")]);
+    }
+
+    #[test]
+    fn bookkeeping_and_hidden_tables_are_not_reported() {
+        let feed = ChangeFeed::new(64);
+        let mut rx = feed.subscribe();
+        let conn = Connection::open_in_memory().expect("open");
+        let recorder = Recorder::install(
+            &feed,
+            &conn,
+            HashSet::from(["notes_search_data".to_owned()]),
+        )
+        .expect("hooks");
+        conn.execute_batch(
+            "CREATE TABLE _nitr_internal (id INTEGER); INSERT INTO _nitr_internal VALUES (1);
+             CREATE TABLE notes_search_data (id INTEGER); INSERT INTO notes_search_data VALUES (1);",
+        )
+        .expect("write");
+        recorder.flush(&conn);
+        assert!(recv(&mut rx).is_empty());
+    }
+}
@@ -29,22 +29,27 @@ pub struct Migration {
     pub name: String,
     /// Full path of the migration file on disk.
     pub path: PathBuf,
-    sql: String,
+    pub(crate) sql: String,
 }
 
 impl Migration {
     /// Hex SHA-256 of the file contents.
-    fn checksum(&self) -> String {
-        use sha2::Digest as _;
-        let digest = sha2::Sha256::digest(self.sql.as_bytes());
-        digest.iter().fold(String::new(), |mut acc, byte| {
-            use std::fmt::Write as _;
-            let _ = write!(acc, "{byte:02x}");
-            acc
-        })
+    pub(crate) fn checksum(&self) -> String {
+        checksum(&self.sql)
     }
 }
 
+/// Hex SHA-256 of `text`: what the ledgers record to notice an edit.
+pub(crate) fn checksum(text: &str) -> String {
+    use sha2::Digest as _;
+    let digest = sha2::Sha256::digest(text.as_bytes());
+    digest.iter().fold(String::new(), |mut acc, byte| {
+        use std::fmt::Write as _;
+        let _ = write!(acc, "{byte:02x}");
+        acc
+    })
+}
+
 /// What `--status` reports about one migration.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum State {
@@ -148,7 +153,14 @@ fn applied(conn: &Connection) -> Result<std::collections::HashMap<i64, String>>
 
 /// The state of every discovered migration.
 pub fn status(conn: &Connection, dir: &Path) -> Result<Vec<(Migration, State)>> {
-    let applied = applied(conn)?;
+    classify(&applied(conn)?, dir)
+}
+
+/// Every discovered migration against a ledger's `version → checksum`.
+pub(crate) fn classify(
+    applied: &std::collections::HashMap<i64, String>,
+    dir: &Path,
+) -> Result<Vec<(Migration, State)>> {
     Ok(discover(dir)?
         .into_iter()
         .map(|migration| {
@@ -168,8 +180,13 @@ pub fn status(conn: &Connection, dir: &Path) -> Result<Vec<(Migration, State)>>
 /// not as pending: re-running it is as likely to be wrong as skipping it,
 /// so the only safe move is to stop and let a human decide.
 pub fn pending(conn: &Connection, dir: &Path) -> Result<Vec<String>> {
+    pending_names(status(conn, dir)?)
+}
+
+/// The pending names out of a [`status`] listing, refusing a modified one.
+pub(crate) fn pending_names(entries: Vec<(Migration, State)>) -> Result<Vec<String>> {
     let mut names = Vec::new();
-    for (migration, state) in status(conn, dir)? {
+    for (migration, state) in entries {
         match state {
             State::Pending => names.push(migration.name),
             State::Modified => {
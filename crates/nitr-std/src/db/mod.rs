@@ -2,6 +2,9 @@
 //! rusqlite calls run on the blocking thread pool; each Lua state owns its
 //! own connection, and requests are serialized per state, so a transaction
 //! never interleaves with other statements.
+//!
+//! With the `postgres` feature, `[database] url` swaps the connection for
+//! a shared Postgres pool behind the same methods (see [`postgres`]).
 
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -10,31 +13,70 @@ use mlua::{AnyUserData, Function, Lua, Table, UserData, UserDataMethods, Value};
 use rusqlite::Connection;
 use tracing::Instrument as _;
 
-use crate::db::types::{Conn, SqlValue, params_from_table, row_to_lua};
+use crate::db::types::{Conn, Output, SqlValue, params_from_table, row_to_lua};
 use nitr_core::Result;
 
 pub(crate) mod execute;
+pub mod explain;
+pub(crate) mod hooks;
 pub mod migrate;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod pragmas;
 pub(crate) mod query;
 pub(crate) mod query_one;
 pub(crate) mod query_row;
+pub mod replicate;
+pub mod search;
 pub(crate) mod types;
 
-use crate::config::SqlitePragmas;
+use crate::changes::ChangeFeed;
+use crate::db::types::Handle;
 
 /// Set while a transaction is open on the connection.
 type TxFlag = Arc<AtomicBool>;
 
+/// Where a statement runs: this state's SQLite connection or, with
+/// `[database] url`, the shared Postgres pool.
+#[derive(Clone)]
+pub(crate) enum Target {
+    Sqlite(Conn),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::PgConn),
+}
+
+impl Target {
+    /// The SQLite connection `db:search()` needs: the indexes are FTS5
+    /// tables.
+    fn search_conn(self) -> mlua::Result<Conn> {
+        match self {
+            Target::Sqlite(conn) => Ok(conn),
+            #[cfg(feature = "postgres")]
+            Target::Postgres(_) => Err(mlua::Error::RuntimeError(
+                "db:search() needs the SQLite backend: [[database.search]] indexes are \
+                 FTS5 tables"
+                    .into(),
+            )),
+        }
+    }
+}
+
 pub(crate) struct LuaDatabase {
-    conn: Conn,
+    conn: Target,
     in_transaction: TxFlag,
+    /// Writes to nitr's own tables held back until the open transaction
+    /// ends (see [`internal`]).
+    after_transaction: Arc<Mutex<Vec<PendingQuery>>>,
+    /// The shared change feed; `None` unless `[database] changes` is on.
+    changes: Option<ChangeFeed>,
+    /// The `[[database.search]]` indexes `db:search()` may query.
+    search: Arc<[crate::config::SearchIndex]>,
 }
 
 /// One (possibly nested) transaction scope handed to the Lua callback of
 /// `db:transaction(fn)` / `tx:transaction(fn)`.
 pub(crate) struct LuaTransaction {
-    conn: Conn,
+    conn: Target,
     /// Names nested savepoints uniquely within this scope.
     savepoints: AtomicUsize,
 }
@@ -59,27 +101,108 @@ where
     // decomposition is opt-in via the level filter.
     let span = tracing::debug_span!("db_query", kind, elapsed_ms = tracing::field::Empty);
     let started = std::time::Instant::now();
-    let result = tokio::task::spawn_blocking(move || {
+    let (result, slow) = tokio::task::spawn_blocking(move || {
         let conn = conn.lock().map_err(|_| {
             mlua::Error::RuntimeError("failed to lock the database connection".into())
         })?;
-        f(&conn, &sql, &params).map_err(|err| {
+        let ran = std::time::Instant::now();
+        let result = f(&conn, &sql, &params);
+        let slow = conn
+            .slow_query
+            .is_some_and(|threshold| ran.elapsed() >= threshold)
+            .then(|| SlowQuery::capture(&conn, kind, &sql, ran.elapsed()));
+        // After every statement, failed ones included: a failed COMMIT
+        // keeps its changes back, a failed autocommit statement drops them.
+        if let Some(changes) = &conn.changes {
+            changes.flush(&conn);
+        }
+        let result = result.map_err(|err| {
             mlua::Error::RuntimeError(format!("SQL statement `{sql}` failed: {err}"))
-        })
+        });
+        Ok::<_, mlua::Error>((result, slow))
     })
     .instrument(span.clone())
     .await
-    .map_err(mlua::Error::external)?;
+    .map_err(mlua::Error::external)??;
     span.record("elapsed_ms", started.elapsed().as_millis() as u64);
+    if let Some(slow) = slow {
+        slow.log(kind);
+    }
     result
 }
 
+/// A statement over `[database] slow_query_ms`, captured on the blocking
+/// thread and logged back inside the request's span, which supplies the
+/// request id and route.
+struct SlowQuery {
+    elapsed: std::time::Duration,
+    sql: String,
+    plan: String,
+}
+
+impl SlowQuery {
+    fn capture(conn: &Connection, kind: &str, sql: &str, elapsed: std::time::Duration) -> Self {
+        // Transaction control has no plan; a slow COMMIT is the disk.
+        let plan = match kind {
+            "tx" => String::new(),
+            _ => match explain::query_plan(conn, sql) {
+                Ok(lines) => lines.join("\n"),
+                Err(err) => format!("(no plan: {err})"),
+            },
+        };
+        let names = explain::schema_names(conn);
+        Self {
+            elapsed,
+            sql: explain::normalize(sql, |name| explain::is_schema_name(&names, name)),
+            plan,
+        }
+    }
+
+    fn log(&self, kind: &str) {
+        tracing::warn!(
+            kind,
+            elapsed_ms = self.elapsed.as_millis() as u64,
+            sql = %self.sql,
+            plan = %self.plan,
+            "slow query"
+        );
+    }
+}
+
 /// Executes a control statement (`BEGIN`, `COMMIT`, `SAVEPOINT ...`).
-async fn exec_batch(conn: Conn, sql: String) -> mlua::Result<()> {
-    run_blocking(conn, "tx", sql, Vec::new(), |conn, sql, _| {
-        conn.execute_batch(sql)
+async fn exec_batch(conn: Target, sql: String) -> mlua::Result<()> {
+    match conn {
+        Target::Sqlite(conn) => {
+            run_blocking(conn, "tx", sql, Vec::new(), |conn, sql, _| {
+                conn.execute_batch(sql)
+            })
+            .await
+        }
+        #[cfg(feature = "postgres")]
+        Target::Postgres(conn) => conn.batch(sql).await,
+    }
+}
+
+/// Runs one statement on this state's SQLite connection.
+async fn run_sqlite(
+    conn: Conn,
+    kind: QueryKind,
+    sql: String,
+    params: Vec<SqlValue>,
+) -> mlua::Result<Output> {
+    let name = kind.name();
+    Ok(match kind {
+        QueryKind::Execute => {
+            Output::Affected(run_blocking(conn, name, sql, params, execute::call).await? as u64)
+        }
+        QueryKind::QueryRow => {
+            Output::Row(run_blocking(conn, name, sql, params, query_row::call).await?)
+        }
+        QueryKind::QueryOne => {
+            Output::Row(run_blocking(conn, name, sql, params, query_one::call).await?)
+        }
+        QueryKind::Query => Output::Rows(run_blocking(conn, name, sql, params, query::call).await?),
     })
-    .await
 }
 
 /// Registers `execute`/`query`/`query_row`/`query_one` on a userdata type
@@ -87,67 +210,40 @@ async fn exec_batch(conn: Conn, sql: String) -> mlua::Result<()> {
 ///
 /// `conn_of` may refuse: the outer `nitr.db` handle does so while a
 /// transaction is open on the same connection.
-fn add_stmt_methods<T, M>(methods: &mut M, conn_of: fn(&T) -> mlua::Result<Conn>)
+fn add_stmt_methods<T, M>(methods: &mut M, conn_of: fn(&T) -> mlua::Result<Target>)
 where
     T: UserData + 'static,
     M: UserDataMethods<T>,
 {
-    // The connection is cloned out before each async block so no
-    // userdata borrow lives across an await point.
-    methods.add_async_method("execute", move |_, this, args: (String, Option<Table>)| {
-        let conn = conn_of(&this);
-        async move {
-            let (sql, params) = args;
-            let params = params_from_table(params.as_ref())?;
-            let affected = run_blocking(conn?, "execute", sql, params, execute::call).await?;
-            Ok(affected)
-        }
-    });
-
-    methods.add_async_method(
-        "query_row",
-        move |lua, this, args: (String, Option<Table>)| {
-            let conn = conn_of(&this);
-            async move {
-                let (sql, params) = args;
-                let params = params_from_table(params.as_ref())?;
-                let row = run_blocking(conn?, "query_row", sql, params, query_row::call).await?;
-                row_to_lua(&lua, row)
-            }
-        },
-    );
-
-    methods.add_async_method(
-        "query_one",
-        move |lua, this, args: (String, Option<Table>)| {
-            let conn = conn_of(&this);
-            async move {
-                let (sql, params) = args;
-                let params = params_from_table(params.as_ref())?;
-                let row = run_blocking(conn?, "query_one", sql, params, query_one::call).await?;
-                row_to_lua(&lua, row)
-            }
-        },
-    );
-
-    methods.add_async_method("query", move |lua, this, args: (String, Option<Table>)| {
-        let conn = conn_of(&this);
-        async move {
-            let (sql, params) = args;
-            let params = params_from_table(params.as_ref())?;
-            let rows = run_blocking(conn?, "query", sql, params, query::call).await?;
-            let table = lua.create_table()?;
-            for (i, row) in rows.into_iter().enumerate() {
-                table.raw_set(i + 1, row_to_lua(&lua, row)?)?;
-            }
-            Ok(table)
-        }
-    });
+    for kind in [
+        QueryKind::Execute,
+        QueryKind::QueryRow,
+        QueryKind::QueryOne,
+        QueryKind::Query,
+    ] {
+        // The connection is cloned out before each async block so no
+        // userdata borrow lives across an await point.
+        methods.add_async_method(
+            kind.name(),
+            move |lua, this, (sql, params): (String, Option<Table>)| {
+                let conn = conn_of(&this);
+                async move {
+                    let pending = PendingQuery {
+                        conn: conn?,
+                        kind,
+                        sql,
+                        params: params_from_table(params.as_ref())?,
+                    };
+                    pending.run(&lua).await
+                }
+            },
+        );
+    }
 }
 
 /// Which statement an unsent query will run.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum QueryKind {
+pub(crate) enum QueryKind {
     Query,
     QueryRow,
     QueryOne,
@@ -167,12 +263,22 @@ impl QueryKind {
             ))),
         }
     }
+
+    /// The method name, also the `kind` of the `db_query` span.
+    fn name(self) -> &'static str {
+        match self {
+            QueryKind::Query => "query",
+            QueryKind::QueryRow => "query_row",
+            QueryKind::QueryOne => "query_one",
+            QueryKind::Execute => "execute",
+        }
+    }
 }
 
 /// The work an unsent query represents, lifted out of the Lua handle so it
 /// can be awaited without holding a userdata borrow.
 pub struct PendingQuery {
-    conn: Conn,
+    conn: Target,
     kind: QueryKind,
     sql: String,
     params: Vec<SqlValue>,
@@ -187,21 +293,15 @@ impl PendingQuery {
             sql,
             params,
         } = self;
-        match kind {
-            QueryKind::Execute => {
-                let affected = run_blocking(conn, "execute", sql, params, execute::call).await?;
-                Ok(Value::Integer(affected as i64))
-            }
-            QueryKind::QueryRow => {
-                let row = run_blocking(conn, "query_row", sql, params, query_row::call).await?;
-                row_to_lua(lua, row).map(Value::Table)
-            }
-            QueryKind::QueryOne => {
-                let row = run_blocking(conn, "query_one", sql, params, query_one::call).await?;
-                row_to_lua(lua, row).map(Value::Table)
-            }
-            QueryKind::Query => {
-                let rows = run_blocking(conn, "query", sql, params, query::call).await?;
+        let output = match conn {
+            Target::Sqlite(conn) => run_sqlite(conn, kind, sql, params).await?,
+            #[cfg(feature = "postgres")]
+            Target::Postgres(conn) => conn.run(kind, sql, params).await?,
+        };
+        match output {
+            Output::Affected(affected) => Ok(Value::Integer(affected as i64)),
+            Output::Row(row) => row_to_lua(lua, row).map(Value::Table),
+            Output::Rows(rows) => {
                 let table = lua.create_table()?;
                 for (i, row) in rows.into_iter().enumerate() {
                     table.raw_set(i + 1, row_to_lua(lua, row)?)?;
@@ -246,7 +346,7 @@ impl UserData for LuaPendingQuery {
 }
 
 /// Registers `query_async` on a userdata type that exposes a connection.
-fn add_async_query_method<T, M>(methods: &mut M, conn_of: fn(&T) -> mlua::Result<Conn>)
+fn add_async_query_method<T, M>(methods: &mut M, conn_of: fn(&T) -> mlua::Result<Target>)
 where
     T: UserData + 'static,
     M: UserDataMethods<T>,
@@ -270,7 +370,7 @@ where
 /// after rolling back.
 async fn run_transaction(
     lua: &Lua,
-    conn: Conn,
+    conn: Target,
     f: Function,
     begin: String,
     commit: String,
@@ -303,17 +403,7 @@ impl UserData for LuaDatabase {
         // would roll back with it, and a read would see uncommitted rows.
         // Phase 6 documented this as a footgun; documenting a trap is not
         // the same as removing it.
-        add_stmt_methods(methods, |db: &LuaDatabase| {
-            if db.in_transaction.load(Ordering::Acquire) {
-                return Err(mlua::Error::RuntimeError(
-                    "a transaction is open on this connection: use the `tx` handle passed \
-                     to db:transaction(function(tx) ... end), not `nitr.db`. Statements on \
-                     the outer handle would join the transaction without saying so."
-                        .into(),
-                ));
-            }
-            Ok(db.conn.clone())
-        });
+        add_stmt_methods(methods, outer_conn);
         add_async_query_method(methods, |db: &LuaDatabase| {
             if db.in_transaction.load(Ordering::Acquire) {
                 return Err(mlua::Error::RuntimeError(
@@ -323,11 +413,59 @@ impl UserData for LuaDatabase {
             Ok(db.conn.clone())
         });
 
+        // db:search(index, text, opts?) -> ranked rows
+        methods.add_async_method(
+            "search",
+            |lua, db, (name, text, opts): (String, String, Option<Table>)| {
+                let conn = outer_conn(&db).and_then(Target::search_conn);
+                let index = db.search.iter().find(|i| i.name == name).cloned();
+                async move {
+                    let index = index.ok_or_else(|| {
+                        mlua::Error::RuntimeError(format!(
+                            "unknown search index `{name}`: declare it under \
+                             [[database.search]] and run `nitr migrate`"
+                        ))
+                    })?;
+                    let opts = search::SearchOptions::from_lua(&index, opts)?;
+                    let table = lua.create_table()?;
+                    let Some((sql, params)) = search::query(&index, &text, &opts) else {
+                        return Ok(table);
+                    };
+                    let rows = run_blocking(conn?, "search", sql, params, query::call).await?;
+                    for (i, mut row) in rows.into_iter().enumerate() {
+                        search::finish_row(&mut row, &opts);
+                        table.raw_set(i + 1, row_to_lua(&lua, row)?)?;
+                    }
+                    Ok(table)
+                }
+            },
+        );
+
+        // for change in db:changes({"orders"}) do ... end
+        methods.add_method("changes", |lua, db, tables: Value| {
+            let feed = change_feed(db)?;
+            crate::changes::changes_iterator(lua, feed, crate::changes::table_filter(tables)?)
+        });
+
+        // db:on_change(tables, function(change) ... end): runs until the
+        // callback returns false.
+        methods.add_async_method(
+            "on_change",
+            |lua, db, (tables, callback): (Value, Function)| {
+                let feed = change_feed(&db).cloned();
+                async move {
+                    let tables = crate::changes::table_filter(tables)?;
+                    crate::changes::on_change(&lua, &feed?, tables, callback).await
+                }
+            },
+        );
+
         // db:transaction(function(tx) ... end): commits when the function
         // returns, rolls back (and re-raises) when it errors.
         methods.add_async_method("transaction", |lua, db, f: Function| {
             let conn = db.conn.clone();
             let flag = db.in_transaction.clone();
+            let held = db.after_transaction.clone();
             async move {
                 if flag.swap(true, Ordering::AcqRel) {
                     return Err(mlua::Error::RuntimeError(
@@ -336,22 +474,122 @@ impl UserData for LuaDatabase {
                             .into(),
                     ));
                 }
-                let result = run_transaction(
-                    &lua,
-                    conn,
-                    f,
-                    "BEGIN".into(),
-                    "COMMIT".into(),
-                    "ROLLBACK".into(),
-                )
-                .await;
+                let result = match conn {
+                    Target::Sqlite(_) => {
+                        run_transaction(
+                            &lua,
+                            conn,
+                            f,
+                            "BEGIN".into(),
+                            "COMMIT".into(),
+                            "ROLLBACK".into(),
+                        )
+                        .await
+                    }
+                    // A transaction holds one pooled connection for its
+                    // whole length.
+                    #[cfg(feature = "postgres")]
+                    Target::Postgres(conn) => postgres::transaction(&lua, conn, f).await,
+                };
                 flag.store(false, Ordering::Release);
+                let held = std::mem::take(&mut *lock_held(&held)?);
+                for write in held {
+                    if let Err(err) = write.run(&lua).await {
+                        // The transaction's own outcome wins; a failed
+                        // session write after it is still reported.
+                        if result.is_ok() {
+                            return Err(err);
+                        }
+                        tracing::error!("write held back by a transaction failed: {err}");
+                    }
+                }
                 result
             }
         });
     }
 }
 
+/// The outer handle's connection, refused while a transaction is open on
+/// it (see `LuaDatabase::add_methods`).
+fn outer_conn(db: &LuaDatabase) -> mlua::Result<Target> {
+    if db.in_transaction.load(Ordering::Acquire) {
+        return Err(mlua::Error::RuntimeError(
+            "a transaction is open on this connection: use the `tx` handle passed \
+             to db:transaction(function(tx) ... end), not `nitr.db`. Statements on \
+             the outer handle would join the transaction without saying so."
+                .into(),
+        ));
+    }
+    Ok(db.conn.clone())
+}
+
+/// The writes [`internal`] held back, for pushing or draining.
+fn lock_held(
+    held: &Mutex<Vec<PendingQuery>>,
+) -> mlua::Result<std::sync::MutexGuard<'_, Vec<PendingQuery>>> {
+    held.lock()
+        .map_err(|_| mlua::Error::RuntimeError("the held-back write queue is poisoned".into()))
+}
+
+/// When [`internal`] runs a statement while a transaction is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum When {
+    /// At once: reads, and the setup the reads need.
+    Now,
+    /// Once the transaction ends, committed or rolled back.
+    AfterTransaction,
+}
+
+/// Runs a statement on one of nitr's own tables (the session store)
+/// through the `nitr.db` handle `db`.
+///
+/// Unlike the Lua methods it does not refuse while a transaction is open:
+/// a session write is not part of the transaction's work, so
+/// [`When::AfterTransaction`] holds it back until the transaction ends
+/// and returns `None` in place of its result.
+pub(crate) async fn internal(
+    lua: &Lua,
+    db: &AnyUserData,
+    when: When,
+    kind: QueryKind,
+    sql: String,
+    params: Vec<SqlValue>,
+) -> mlua::Result<Option<Value>> {
+    let pending = {
+        let db = db.borrow::<LuaDatabase>()?;
+        let pending = PendingQuery {
+            conn: db.conn.clone(),
+            kind,
+            sql,
+            params,
+        };
+        if when == When::AfterTransaction && db.in_transaction.load(Ordering::Acquire) {
+            lock_held(&db.after_transaction)?.push(pending);
+            return Ok(None);
+        }
+        pending
+    };
+    pending.run(lua).await.map(Some)
+}
+
+/// Whether a transaction is open on the `nitr.db` handle `db`.
+pub(crate) fn in_transaction(db: &AnyUserData) -> mlua::Result<bool> {
+    Ok(db
+        .borrow::<LuaDatabase>()?
+        .in_transaction
+        .load(Ordering::Acquire))
+}
+
+/// The feed behind `db:changes()`/`db:on_change()`, or the error naming
+/// the setting that turns it on.
+fn change_feed(db: &LuaDatabase) -> mlua::Result<&ChangeFeed> {
+    db.changes.as_ref().ok_or_else(|| {
+        mlua::Error::RuntimeError(
+            "change notifications are off: set `changes = true` in the [database] section".into(),
+        )
+    })
+}
+
 impl UserData for LuaTransaction {
     fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
         add_stmt_methods(methods, |tx: &LuaTransaction| Ok(tx.conn.clone()));
@@ -379,15 +617,34 @@ impl UserData for LuaTransaction {
 }
 
 /// Opens this state's SQLite connection and builds the `nitr.db` handle.
+/// With a change feed, the connection records its committed changes into
+/// it.
 pub(crate) fn create_database_fn(
     lua: &Lua,
     path: &std::path::Path,
-    pragmas: &SqlitePragmas,
+    env: &crate::BuiltinsEnv,
 ) -> Result<AnyUserData> {
-    let conn = Arc::new(Mutex::new(pragmas::open(path, pragmas)?));
+    let changes = env.changes.as_ref();
+    let conn = pragmas::open(path, &env.sqlite)?;
+    let recorder = changes
+        .map(|feed| hooks::Recorder::install(feed, &conn, search::shadow_tables(&env.search)))
+        .transpose()
+        .map_err(|err| {
+            nitr_core::Error::Config(format!(
+                "cannot install the change hooks on {}: {err}",
+                path.display()
+            ))
+        })?;
     let value = lua.create_userdata(LuaDatabase {
-        conn,
+        conn: Target::Sqlite(Arc::new(Mutex::new(Handle {
+            conn,
+            changes: recorder,
+            slow_query: env.slow_query,
+        }))),
         in_transaction: Arc::new(AtomicBool::new(false)),
+        after_transaction: Arc::default(),
+        changes: changes.cloned(),
+        search: env.search.clone().into(),
     })?;
     Ok(value)
 }
@@ -410,4 +667,23 @@ mod tests {
         let err = QueryKind::parse(Some("insert")).expect_err("unknown kind");
         assert!(err.to_string().contains("insert"), "{err}");
     }
+
+    #[test]
+    fn slow_queries_carry_the_plan_but_no_literals() {
+        let conn = Connection::open_in_memory().expect("open");
+        conn.execute_batch("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT)")
+            .expect("schema");
+        let elapsed = std::time::Duration::from_millis(250);
+        let slow = SlowQuery::capture(
+            &conn,
+            "query",
+            "SELECT id FROM users WHERE email = 'alice@example.com'",
+            elapsed,
+        );
+        assert_eq!(slow.sql, "SELECT id FROM users WHERE email = ?");
+        assert!(slow.plan.starts_with("SCAN users"), "{}", slow.plan);
+
+        let slow = SlowQuery::capture(&conn, "tx", "COMMIT", elapsed);
+        assert!(slow.plan.is_empty());
+    }
 }
@@ -0,0 +1,150 @@
+//! The Postgres flavour of the `_nitr_migrations` ledger.
+//!
+//! The same files and the same rules as on SQLite (see
+//! [`crate::migrate`]): numeric order, one transaction per migration, a
+//! checksum that turns an edited file into an error. Two things differ.
+//! The ledger uses Postgres types, and a run holds an advisory lock, so two
+//! `nitr migrate` processes against one server take turns instead of both
+//! applying the same file.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use deadpool_postgres::ClientWrapper;
+
+use super::{PgPool, describe};
+use crate::migrate::{Migration, State, classify, discover, pending_names};
+use nitr_core::{Error, Result};
+
+/// Table recording what has been applied.
+const TABLE: &str = "_nitr_migrations";
+
+/// The `pg_advisory_lock` key held while migrating: "nitr" in ASCII.
+const LOCK_KEY: i64 = 0x6e69_7472;
+
+impl PgPool {
+    /// The state of every discovered migration.
+    pub async fn migration_status(&self, dir: &Path) -> Result<Vec<(Migration, State)>> {
+        let client = self.client().await?;
+        classify(&applied(&client).await?, dir)
+    }
+
+    /// Names of the migrations that have not run yet; a migration changed
+    /// since it was applied is an error, as on SQLite.
+    pub async fn pending_migrations(&self, dir: &Path) -> Result<Vec<String>> {
+        pending_names(self.migration_status(dir).await?)
+    }
+
+    /// Applies every pending migration in order, returning their names.
+    pub async fn migrate(&self, dir: &Path) -> Result<Vec<String>> {
+        let client = self.client().await?;
+        let lock = |sql: &'static str| {
+            let client = &client;
+            async move {
+                client.execute(sql, &[&LOCK_KEY]).await.map_err(|err| {
+                    Error::Config(format!("cannot lock {TABLE}: {}", describe(&err)))
+                })
+            }
+        };
+        lock("SELECT pg_advisory_lock($1)").await?;
+        let result = run(&client, dir).await;
+        lock("SELECT pg_advisory_unlock($1)").await?;
+        result
+    }
+}
+
+/// `version → checksum` for everything already applied. A missing ledger
+/// is an empty one: only [`run`] creates it, so checking at boot writes
+/// nothing.
+async fn applied(client: &ClientWrapper) -> Result<HashMap<i64, String>> {
+    let fail = |err: tokio_postgres::Error| {
+        Error::Config(format!("cannot read {TABLE}: {}", describe(&err)))
+    };
+    let exists = client
+        .query_one(&format!("SELECT to_regclass('{TABLE}') IS NOT NULL"), &[])
+        .await
+        .map_err(fail)?;
+    if !exists.get::<_, bool>(0) {
+        return Ok(HashMap::new());
+    }
+    let rows = client
+        .query(&format!("SELECT version, checksum FROM {TABLE}"), &[])
+        .await
+        .map_err(fail)?;
+    Ok(rows
+        .iter()
+        .map(|row| (row.get::<_, i64>(0), row.get::<_, String>(1)))
+        .collect())
+}
+
+async fn run(client: &ClientWrapper, dir: &Path) -> Result<Vec<String>> {
+    client
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {TABLE} (
+                 version    BIGINT PRIMARY KEY,
+                 name       TEXT NOT NULL,
+                 checksum   TEXT NOT NULL,
+                 applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+             )"
+        ))
+        .await
+        .map_err(|err| {
+            Error::Config(format!(
+                "cannot create the {TABLE} table: {}",
+                describe(&err)
+            ))
+        })?;
+    // Read under the lock: another process may have just applied some.
+    let applied = applied(client).await?;
+    let mut done = Vec::new();
+
+    for migration in discover(dir)? {
+        match applied.get(&migration.version) {
+            Some(recorded) if *recorded == migration.checksum() => continue,
+            Some(_) => {
+                return Err(Error::Config(format!(
+                    "migration `{}` has changed since it was applied; refusing to continue",
+                    migration.name
+                )));
+            }
+            None => {}
+        }
+        apply(client, &migration).await.map_err(|err| {
+            Error::Config(format!(
+                "migration `{}` failed: {}",
+                migration.name,
+                describe(&err)
+            ))
+        })?;
+        tracing::info!("applied migration `{}`", migration.name);
+        done.push(migration.name);
+    }
+    Ok(done)
+}
+
+/// One migration and its ledger row, in one transaction: Postgres DDL is
+/// transactional, so a failure leaves nothing of it behind.
+async fn apply(client: &ClientWrapper, migration: &Migration) -> Result<(), tokio_postgres::Error> {
+    client.batch_execute("BEGIN").await?;
+    let result = async {
+        client.batch_execute(&migration.sql).await?;
+        client
+            .execute(
+                &format!("INSERT INTO {TABLE} (version, name, checksum) VALUES ($1, $2, $3)"),
+                &[&migration.version, &migration.name, &migration.checksum()],
+            )
+            .await?;
+        client.batch_execute("COMMIT").await
+    }
+    .await;
+    if result.is_err()
+        && let Err(rollback) = client.batch_execute("ROLLBACK").await
+    {
+        tracing::error!(
+            "failed to roll back migration `{}`: {}",
+            migration.name,
+            describe(&rollback)
+        );
+    }
+    result
+}
@@ -0,0 +1,436 @@
+//! The Postgres side of the [`SqlValue`] boundary: parameters go out in
+//! text format and rows come back as the same values the SQLite driver
+//! produces, so handler code cannot tell the backends apart.
+
+use std::error::Error as StdError;
+use std::fmt::Write as _;
+
+use bytes::BytesMut;
+use tokio_postgres::types::{Format, FromSql, IsNull, Kind, ToSql, Type, to_sql_checked};
+
+use crate::db::types::SqlValue;
+
+type BoxError = Box<dyn StdError + Sync + Send>;
+
+/// Parameters are sent as text and parsed by the server into whatever type
+/// it inferred for the placeholder. Lua has four scalar types; Postgres has
+/// dozens, and `WHERE created_at > ?` or `id = ANY(?::int[])` would
+/// otherwise need a Rust-side encoder for every one of them.
+impl ToSql for SqlValue {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        let ty = base_type(ty);
+        match self {
+            SqlValue::Null => return Ok(IsNull::Yes),
+            // SQLite binds a boolean as 0/1; an integer column wants that.
+            SqlValue::Bool(b) if is_integer(ty) => {
+                out.extend_from_slice(if *b { b"1" } else { b"0" })
+            }
+            SqlValue::Bool(b) => out.extend_from_slice(if *b { b"true" } else { b"false" }),
+            SqlValue::Int(i) => out.extend_from_slice(i.to_string().as_bytes()),
+            SqlValue::Real(f) => out.extend_from_slice(real_text(*f).as_bytes()),
+            SqlValue::Text(bytes) | SqlValue::Blob(bytes) if *ty == Type::BYTEA => {
+                out.extend_from_slice(b"\\x");
+                for byte in bytes {
+                    out.extend_from_slice(format!("{byte:02x}").as_bytes());
+                }
+            }
+            SqlValue::Text(bytes) | SqlValue::Blob(bytes) => out.extend_from_slice(bytes),
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _: &Type) -> Format {
+        Format::Text
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for SqlValue {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        decode(base_type(ty), raw)
+    }
+
+    fn from_sql_null(_: &Type) -> Result<Self, BoxError> {
+        Ok(SqlValue::Null)
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+/// A domain decodes as the type it constrains.
+fn base_type(ty: &Type) -> &Type {
+    match ty.kind() {
+        Kind::Domain(inner) => base_type(inner),
+        _ => ty,
+    }
+}
+
+fn is_integer(ty: &Type) -> bool {
+    [Type::INT2, Type::INT4, Type::INT8, Type::OID].contains(ty)
+}
+
+/// `f64` as Postgres spells it: Rust writes `inf` where it wants
+/// `Infinity`.
+fn real_text(f: f64) -> String {
+    if f.is_infinite() {
+        if f > 0.0 { "Infinity" } else { "-Infinity" }.into()
+    } else {
+        f.to_string()
+    }
+}
+
+/// One binary-format column value as a [`SqlValue`].
+///
+/// Numbers come back as numbers, text and JSON as strings, `bytea` as a
+/// binary string, and dates and timestamps as UTC text in SQLite's
+/// `datetime()` shape. Anything else is refused with the cast that reads
+/// it.
+fn decode(ty: &Type, raw: &[u8]) -> Result<SqlValue, BoxError> {
+    let text = || SqlValue::Text(raw.to_vec());
+    Ok(if *ty == Type::BOOL {
+        SqlValue::Bool(bool::from_sql(ty, raw)?)
+    } else if *ty == Type::INT2 {
+        SqlValue::Int(i16::from_sql(ty, raw)?.into())
+    } else if *ty == Type::INT4 {
+        SqlValue::Int(i32::from_sql(ty, raw)?.into())
+    } else if *ty == Type::INT8 {
+        SqlValue::Int(i64::from_sql(ty, raw)?)
+    } else if *ty == Type::OID {
+        SqlValue::Int(u32::from_sql(ty, raw)?.into())
+    } else if *ty == Type::FLOAT4 {
+        SqlValue::Real(f32::from_sql(ty, raw)?.into())
+    } else if *ty == Type::FLOAT8 {
+        SqlValue::Real(f64::from_sql(ty, raw)?)
+    } else if *ty == Type::NUMERIC {
+        numeric(&numeric_text(raw)?)
+    } else if *ty == Type::BYTEA {
+        SqlValue::Blob(raw.to_vec())
+    } else if *ty == Type::JSONB {
+        // A version byte, then the JSON text.
+        match raw.split_first() {
+            Some((1, json)) => SqlValue::Text(json.to_vec()),
+            _ => return Err("unsupported jsonb version".into()),
+        }
+    } else if *ty == Type::UUID {
+        SqlValue::Text(uuid_text(raw)?.into_bytes())
+    } else if *ty == Type::DATE {
+        SqlValue::Text(date_text(i32::from_sql(ty, raw)?).into_bytes())
+    } else if *ty == Type::TIME {
+        SqlValue::Text(time_text(i64::from_sql(&Type::INT8, raw)?).into_bytes())
+    } else if *ty == Type::TIMESTAMP || *ty == Type::TIMESTAMPTZ {
+        SqlValue::Text(timestamp_text(i64::from_sql(&Type::INT8, raw)?).into_bytes())
+    } else if [
+        Type::TEXT,
+        Type::VARCHAR,
+        Type::BPCHAR,
+        Type::NAME,
+        Type::CHAR,
+        Type::JSON,
+        Type::XML,
+        Type::UNKNOWN,
+    ]
+    .contains(ty)
+        || matches!(ty.kind(), Kind::Enum(_))
+        || ty.name() == "citext"
+    {
+        text()
+    } else {
+        return Err(format!(
+            "nitr.db cannot read a `{}` column: cast it in the query, e.g. `column::text`",
+            ty.name()
+        )
+        .into());
+    })
+}
+
+/// SQLite's NUMERIC affinity: an integral value that fits is an integer,
+/// anything else a float. (Cast to `text` to keep every digit.)
+fn numeric(text: &str) -> SqlValue {
+    let integral = match text.split_once('.') {
+        Some((int, frac)) if frac.bytes().all(|b| b == b'0') => int,
+        Some(_) => "",
+        None => text,
+    };
+    match integral.parse::<i64>() {
+        Ok(i) => SqlValue::Int(i),
+        Err(_) => SqlValue::Real(text.parse().unwrap_or(f64::NAN)),
+    }
+}
+
+/// Renders the binary `numeric` format (base-10000 digit groups) as
+/// decimal text.
+fn numeric_text(raw: &[u8]) -> Result<String, BoxError> {
+    let word = |at: usize| -> Result<u16, BoxError> {
+        raw.get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| "truncated numeric value".into())
+    };
+    let ndigits = usize::from(word(0)?);
+    let weight = i32::from(word(2)? as i16);
+    let sign = word(4)?;
+    let dscale = usize::from(word(6)?);
+    let digits = (0..ndigits)
+        .map(|i| word(8 + 2 * i))
+        .collect::<Result<Vec<_>, _>>()?;
+    match sign {
+        0xC000 => return Ok("NaN".into()),
+        0xD000 => return Ok("Infinity".into()),
+        0xF000 => return Ok("-Infinity".into()),
+        _ => {}
+    }
+    let group = |index: i32| -> u16 {
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| digits.get(i).copied())
+            .unwrap_or(0)
+    };
+    let mut out = String::new();
+    if sign == 0x4000 {
+        out.push('-');
+    }
+    if weight < 0 {
+        out.push('0');
+    }
+    for index in 0..=weight {
+        let _ = match index {
+            0 => write!(out, "{}", group(index)),
+            _ => write!(out, "{:04}", group(index)),
+        };
+    }
+    if dscale > 0 {
+        let mut frac = String::new();
+        for index in 0..dscale.div_ceil(4) as i32 {
+            let _ = write!(frac, "{:04}", group(weight + 1 + index));
+        }
+        frac.truncate(dscale);
+        out.push('.');
+        out.push_str(&frac);
+    }
+    Ok(out)
+}
+
+fn uuid_text(raw: &[u8]) -> Result<String, BoxError> {
+    if raw.len() != 16 {
+        return Err("invalid uuid value".into());
+    }
+    let mut out = String::with_capacity(36);
+    for (i, byte) in raw.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            out.push('-');
+        }
+        let _ = write!(out, "{byte:02x}");
+    }
+    Ok(out)
+}
+
+/// Postgres counts dates and timestamps from 2000-01-01.
+fn epoch() -> chrono::NaiveDateTime {
+    chrono::NaiveDate::from_ymd_opt(2000, 1, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .unwrap_or_default()
+}
+
+fn date_text(days: i32) -> String {
+    match days {
+        i32::MAX => "infinity".into(),
+        i32::MIN => "-infinity".into(),
+        _ => epoch()
+            .checked_add_signed(chrono::TimeDelta::days(days.into()))
+            .map(|t| t.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn time_text(micros: i64) -> String {
+    epoch()
+        .checked_add_signed(chrono::TimeDelta::microseconds(micros))
+        .map(|t| t.format("%H:%M:%S%.f").to_string())
+        .unwrap_or_default()
+}
+
+/// `timestamptz` arrives in UTC already; both kinds render as
+/// `YYYY-MM-DD HH:MM:SS[.ffffff]`, which is what `datetime('now')` stores
+/// on SQLite.
+fn timestamp_text(micros: i64) -> String {
+    match micros {
+        i64::MAX => "infinity".into(),
+        i64::MIN => "-infinity".into(),
+        _ => epoch()
+            .checked_add_signed(chrono::TimeDelta::microseconds(micros))
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S%.f").to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Rewrites SQLite placeholders (`?`, `?NNN`) into Postgres ones (`$N`),
+/// numbering bare `?`s the way SQLite does: one past the largest number
+/// used so far. String literals, quoted identifiers, comments and
+/// dollar-quoted bodies are copied untouched, as is an existing `$N`.
+pub(crate) fn placeholders(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len() + 8);
+    let mut last = 0u32;
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        i = match bytes[i] {
+            b'\'' => {
+                // `E'...'` strings take backslash escapes.
+                let escapes = i > 0
+                    && matches!(bytes[i - 1], b'E' | b'e')
+                    && (i < 2 || !is_ident(bytes[i - 2]));
+                skip_quoted(bytes, i, b'\'', escapes)
+            }
+            b'"' => skip_quoted(bytes, i, b'"', false),
+            b'-' if bytes.get(i + 1) == Some(&b'-') => bytes[i..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(bytes.len(), |n| i + n + 1),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => skip_block_comment(bytes, i),
+            b'$' if i == 0 || !is_ident(bytes[i - 1]) => skip_dollar_quoted(bytes, i),
+            b'?' => {
+                let digits = bytes[i + 1..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                let n = match sql[i + 1..i + 1 + digits].parse::<u32>() {
+                    Ok(n) => n,
+                    Err(_) => last + 1,
+                };
+                last = last.max(n);
+                out.push_str(&sql[copied..i]);
+                let _ = write!(out, "${n}");
+                copied = i + 1 + digits;
+                copied
+            }
+            _ => i + 1,
+        };
+    }
+    out.push_str(&sql[copied..]);
+    out
+}
+
+fn is_ident(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$' || byte >= 0x80
+}
+
+/// The index just past the quoted run opening at `start`; a doubled quote
+/// is an escaped one.
+fn skip_quoted(bytes: &[u8], start: usize, quote: u8, backslash: bool) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if backslash && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) != Some(&quote) {
+                return i + 1;
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Postgres block comments nest.
+fn skip_block_comment(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut i = start;
+    while i + 1 < bytes.len() {
+        match (bytes[i], bytes[i + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', b'/') => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// `$tag$ ... $tag$`; a `$` that opens no quote (`$1`) is skipped alone.
+fn skip_dollar_quoted(bytes: &[u8], start: usize) -> usize {
+    let tag_len = bytes[start + 1..]
+        .iter()
+        .take_while(|&&b| b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80)
+        .count();
+    let opens = bytes.get(start + 1 + tag_len) == Some(&b'$')
+        && !bytes.get(start + 1).is_some_and(u8::is_ascii_digit);
+    if !opens {
+        return start + 1;
+    }
+    let tag = &bytes[start..start + tag_len + 2];
+    let body = start + tag.len();
+    bytes[body..]
+        .windows(tag.len())
+        .position(|window| window == tag)
+        .map_or(bytes.len(), |n| body + n + tag.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_numbered_like_sqlite_outside_quotes() {
+        assert_eq!(
+            placeholders("SELECT * FROM t WHERE a = ? AND b = ?"),
+            "SELECT * FROM t WHERE a = $1 AND b = $2"
+        );
+        assert_eq!(placeholders("VALUES (?2, ?, ?1)"), "VALUES ($2, $3, $1)");
+        assert_eq!(
+            placeholders("SELECT '?', \"a?\", E'\\'?', $x$ ? $x$ -- ?\n, ? /* ? /* ? */ */"),
+            "SELECT '?', \"a?\", E'\\'?', $x$ ? $x$ -- ?\n, $1 /* ? /* ? */ */"
+        );
+        assert_eq!(
+            placeholders("WHERE id = $1 OR x = ?"),
+            "WHERE id = $1 OR x = $1"
+        );
+    }
+
+    #[test]
+    fn numerics_render_exactly_and_map_like_sqlite_affinity() {
+        // 12345.678: weight 1, digit groups 1 2345 6780, dscale 3.
+        let raw: Vec<u8> = [3u16, 1, 0, 3, 1, 2345, 6780]
+            .iter()
+            .flat_map(|w| w.to_be_bytes())
+            .collect();
+        assert_eq!(numeric_text(&raw).expect("numeric"), "12345.678");
+        // -0.0005: weight -1, one group 5, dscale 4.
+        let raw: Vec<u8> = [1u16, 0xFFFF, 0x4000, 4, 5]
+            .iter()
+            .flat_map(|w| w.to_be_bytes())
+            .collect();
+        assert_eq!(numeric_text(&raw).expect("numeric"), "-0.0005");
+
+        assert!(matches!(numeric("42.00"), SqlValue::Int(42)));
+        assert!(matches!(numeric("2.5"), SqlValue::Real(f) if f == 2.5));
+        assert!(matches!(numeric("99999999999999999999"), SqlValue::Real(_)));
+    }
+
+    #[test]
+    fn dates_and_times_render_as_utc_text() {
+        assert_eq!(date_text(0), "2000-01-01");
+        assert_eq!(
+            timestamp_text(86_400_000_000 + 1_500_000),
+            "2000-01-02 00:00:01.500"
+        );
+        assert_eq!(time_text(3_600_000_000), "01:00:00");
+        assert_eq!(timestamp_text(i64::MAX), "infinity");
+    }
+}
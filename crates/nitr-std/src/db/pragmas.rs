@@ -79,6 +79,8 @@ impl SqlitePragmas {
             .map_err(|err| context("the foreign_keys pragma", err))?;
         conn.pragma_update(None, "cache_size", self.cache_size)
             .map_err(|err| context("the cache size", err))?;
+        conn.pragma_update(None, "wal_autocheckpoint", self.wal_autocheckpoint)
+            .map_err(|err| context("the WAL autocheckpoint", err))?;
         Ok(())
     }
 }
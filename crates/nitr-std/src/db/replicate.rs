@@ -0,0 +1,835 @@
+//! Continuous WAL shipping to a replica directory, and restoring from it.
+//!
+//! A single-node deployment loses everything since its last copy when the
+//! disk goes. The replicator narrows that window to about one `interval`:
+//! it copies every committed WAL frame to a second directory (another
+//! disk, an NFS mount) as it appears, without stopping writers.
+//!
+//! # Layout
+//!
+//! ```text
+//! <dir>/generations/<started_ms>/snapshot.db
+//! <dir>/generations/<started_ms>/wal/<cycle>-<offset>-<captured_ms>.wal
+//! ```
+//!
+//! A *generation* is a page-exact copy of the database file plus every WAL
+//! frame written after it. Within a generation the WAL restarts from its
+//! header after each full checkpoint; each such pass over the file is a
+//! *cycle*, and a segment holds the bytes `[offset, offset + len)` of one
+//! cycle's WAL. Concatenating a cycle's segments rebuilds a WAL file SQLite
+//! recovers on its own, checksums included.
+//!
+//! # Why the replicator owns checkpoints
+//!
+//! A checkpoint moves frames into the database file and lets the next
+//! writer overwrite the WAL. Frames checkpointed before they were copied
+//! would be lost to the replica, so replicated databases run with
+//! `wal_autocheckpoint = 0` and the replicator checkpoints itself — while
+//! holding the write lock, right after a final copy. Anything it cannot
+//! account for (another process checkpointing, the WAL restarting behind
+//! its back) starts a new generation rather than shipping a gap.
+//!
+//! Restoring picks the newest generation that started at or before the
+//! requested time and replays its segments captured up to then, so the
+//! result is the database as of the last copy before that instant.
+
+use std::fs;
+use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::Connection;
+
+use crate::config::{ReplicaOptions, SqlitePragmas};
+use nitr_core::{Error, Result};
+
+/// WAL header size.
+const WAL_HEADER: u64 = 32;
+/// WAL frame header size; the page follows it.
+const FRAME_HEADER: u64 = 24;
+/// WAL magic numbers: the low bit selects big-endian checksums.
+const WAL_MAGIC: u32 = 0x377f_0682;
+
+/// Ships a database's WAL to a replica directory from a background thread.
+///
+/// Dropping it (or calling [`stop`](Self::stop)) runs a last copy, so a
+/// clean shutdown loses nothing.
+pub struct Replicator {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Replicator {
+    /// Starts a new generation and the background copy loop.
+    ///
+    /// The first generation is written before this returns, so a
+    /// misconfigured `dir` fails the boot instead of a log line later.
+    pub fn start(db: &Path, pragmas: &SqlitePragmas, opts: ReplicaOptions) -> Result<Self> {
+        let mut shipper = Shipper::open(db, pragmas, opts)?;
+        shipper.sync()?;
+
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("nitr-replicate".into())
+            .spawn(move || shipper.run(&signal))
+            .map_err(|err| Error::Config(format!("cannot start the replicator: {err}")))?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Copies whatever is left and stops the loop.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (flag, wake) = &*self.stop;
+        *flag.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Replicator {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl std::fmt::Debug for Replicator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Replicator").finish_non_exhaustive()
+    }
+}
+
+/// Where the current generation stands in the WAL file.
+struct Position {
+    dir: PathBuf,
+    /// Salts of the WAL header being followed; `None` until one is seen.
+    salts: Option<(u32, u32)>,
+    cycle: u32,
+    /// Bytes of the current cycle already shipped.
+    offset: u64,
+    /// Running checksum after the last shipped frame.
+    checksum: (u32, u32),
+    big_endian: bool,
+    page_size: u64,
+    /// Set after a checkpoint backfilled every shipped frame: the next
+    /// writer may restart the WAL, and that restart is not a gap.
+    restart_ok: bool,
+}
+
+/// The replicator proper; owned by the background thread.
+struct Shipper {
+    db: PathBuf,
+    wal: PathBuf,
+    /// Runs the checkpoints.
+    conn: Connection,
+    /// Holds the write lock (`BEGIN IMMEDIATE`) while a checkpoint or a
+    /// snapshot needs writers to stand still.
+    lock: Connection,
+    opts: ReplicaOptions,
+    pos: Option<Position>,
+}
+
+impl Shipper {
+    fn open(db: &Path, pragmas: &SqlitePragmas, opts: ReplicaOptions) -> Result<Self> {
+        let pragmas = SqlitePragmas {
+            wal_autocheckpoint: 0,
+            ..pragmas.clone()
+        };
+        let conn = super::pragmas::open(db, &pragmas)?;
+        let mode: String = conn
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .map_err(|err| replica_err("cannot read the journal mode", err))?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            return Err(Error::Config(format!(
+                "[database.replica] needs journal_mode = \"wal\"; {} uses `{mode}`",
+                db.display()
+            )));
+        }
+        let lock = super::pragmas::open(db, &pragmas)?;
+        fs::create_dir_all(opts.dir.join("generations")).map_err(|err| {
+            Error::Config(format!(
+                "cannot create the replica directory {}: {err}",
+                opts.dir.display()
+            ))
+        })?;
+        let mut wal = db.as_os_str().to_owned();
+        wal.push("-wal");
+        Ok(Self {
+            db: db.to_path_buf(),
+            wal: PathBuf::from(wal),
+            conn,
+            lock,
+            opts,
+            pos: None,
+        })
+    }
+
+    fn run(mut self, stop: &(Mutex<bool>, Condvar)) {
+        let (flag, wake) = stop;
+        loop {
+            let stopping = {
+                let guard = flag.lock().unwrap_or_else(|e| e.into_inner());
+                let (guard, _) = wake
+                    .wait_timeout_while(guard, self.opts.interval, |stop| !*stop)
+                    .unwrap_or_else(|e| e.into_inner());
+                *guard
+            };
+            if let Err(err) = self.sync() {
+                tracing::error!("replicating {} failed: {err}", self.db.display());
+                // Whatever went wrong, the next pass must not trust the
+                // position it left behind.
+                self.pos = None;
+            }
+            if stopping {
+                return;
+            }
+        }
+    }
+
+    /// One pass: ship new frames, and checkpoint once the WAL is large.
+    fn sync(&mut self) -> Result {
+        if self.pos.is_none() {
+            self.start_generation()?;
+        }
+        if !self.ship()? {
+            tracing::warn!(
+                "the WAL of {} restarted without the replicator; starting a new generation",
+                self.db.display()
+            );
+            return self.start_generation();
+        }
+        let frames = self.pos.as_ref().map_or(0, |pos| {
+            pos.offset.saturating_sub(WAL_HEADER) / (FRAME_HEADER + pos.page_size)
+        });
+        if frames >= u64::from(self.opts.checkpoint_pages.max(1)) {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the database file and the WAL as it stands into a fresh
+    /// generation. Writers are held off for the duration: the file only
+    /// changes on checkpoint, which nobody else runs, and the WAL only
+    /// grows under the write lock.
+    fn start_generation(&mut self) -> Result {
+        let started = now_ms();
+        let dir = self
+            .opts
+            .dir
+            .join("generations")
+            .join(format!("{started:016}"));
+        fs::create_dir_all(dir.join("wal"))
+            .map_err(|err| io_err(&format!("cannot create {}", dir.display()), err))?;
+
+        self.lock_writers()?;
+        let result = (|| -> Result {
+            copy_synced(&self.db, &dir.join("snapshot.db"))?;
+            self.pos = Some(Position {
+                dir: dir.clone(),
+                salts: None,
+                cycle: 0,
+                offset: 0,
+                checksum: (0, 0),
+                big_endian: false,
+                page_size: 0,
+                restart_ok: true,
+            });
+            self.ship().map(|_| ())
+        })();
+        self.unlock_writers();
+        if let Err(err) = result {
+            self.pos = None;
+            let _ = fs::remove_dir_all(&dir);
+            return Err(err);
+        }
+        tracing::info!(
+            "replica generation {started:016} started in {}",
+            dir.display()
+        );
+        self.prune();
+        Ok(())
+    }
+
+    /// Copies the committed frames past the current position. `false`
+    /// means the WAL no longer continues what was shipped.
+    fn ship(&mut self) -> Result<bool> {
+        let Some(pos) = self.pos.as_mut() else {
+            return Ok(false);
+        };
+        let mut file = match fs::File::open(&self.wal) {
+            Ok(file) => file,
+            // No WAL yet (nothing written since the last close): nothing
+            // to ship, and nothing was lost.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(pos.salts.is_none() || pos.restart_ok);
+            }
+            Err(err) => return Err(io_err("cannot open the WAL", err)),
+        };
+        let mut header = [0u8; WAL_HEADER as usize];
+        if file.read_exact(&mut header).is_err() {
+            // Empty or mid-creation: nothing committed there yet.
+            return Ok(pos.salts.is_none() || pos.restart_ok);
+        }
+        let Some(wal) = WalHeader::parse(&header) else {
+            // A header still being written; the next pass sees it whole.
+            return Ok(true);
+        };
+
+        if pos.salts != Some(wal.salts) {
+            if pos.salts.is_some() && !pos.restart_ok {
+                return Ok(false);
+            }
+            // A new cycle: the WAL restarted from its header.
+            if pos.salts.is_some() {
+                pos.cycle += 1;
+            }
+            pos.salts = Some(wal.salts);
+            pos.offset = 0;
+            pos.checksum = wal.checksum;
+            pos.big_endian = wal.big_endian;
+            pos.page_size = wal.page_size;
+        }
+
+        let frame_len = FRAME_HEADER + pos.page_size;
+        let start = pos.offset.max(WAL_HEADER);
+        file.seek(SeekFrom::Start(start))
+            .map_err(|err| io_err("cannot read the WAL", err))?;
+        let mut frames = Vec::new();
+        file.read_to_end(&mut frames)
+            .map_err(|err| io_err("cannot read the WAL", err))?;
+
+        // Walk the frames the way SQLite's recovery does: a frame counts
+        // only if its salts match the header and its checksum continues the
+        // chain; a transaction counts only once its commit frame does.
+        let mut checksum = pos.checksum;
+        let mut committed = 0u64;
+        let mut committed_checksum = checksum;
+        for (index, frame) in frames.chunks_exact(frame_len as usize).enumerate() {
+            if be32(frame, 8) != wal.salts.0 || be32(frame, 12) != wal.salts.1 {
+                break;
+            }
+            checksum = wal_checksum(checksum, &frame[..8], pos.big_endian);
+            checksum = wal_checksum(checksum, &frame[FRAME_HEADER as usize..], pos.big_endian);
+            if checksum != (be32(frame, 16), be32(frame, 20)) {
+                break;
+            }
+            if be32(frame, 4) != 0 {
+                committed = (index as u64 + 1) * frame_len;
+                committed_checksum = checksum;
+            }
+        }
+        if committed == 0 && pos.offset != 0 {
+            return Ok(true);
+        }
+
+        let mut segment = Vec::with_capacity(committed as usize + WAL_HEADER as usize);
+        if pos.offset == 0 {
+            segment.extend_from_slice(&header);
+        }
+        segment.extend_from_slice(&frames[..committed as usize]);
+        let name = format!("{:08}-{:016x}-{}.wal", pos.cycle, pos.offset, now_ms());
+        write_synced(&pos.dir.join("wal").join(name), &segment)?;
+        pos.offset = start + committed;
+        pos.checksum = committed_checksum;
+        if committed > 0 {
+            pos.restart_ok = false;
+        }
+        Ok(true)
+    }
+
+    /// Ships the tail and checkpoints with writers held off, so nothing
+    /// can land in the WAL between the copy and the checkpoint.
+    fn checkpoint(&mut self) -> Result {
+        self.lock_writers()?;
+        let result = (|| -> Result<bool> {
+            if !self.ship()? {
+                return Ok(false);
+            }
+            let (busy, log, done): (i64, i64, i64) = self
+                .conn
+                .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .map_err(|err| replica_err("checkpoint failed", err))?;
+            if let Some(pos) = self.pos.as_mut() {
+                let shipped =
+                    pos.offset.saturating_sub(WAL_HEADER) / (FRAME_HEADER + pos.page_size.max(1));
+                // Only a checkpoint covering exactly what was shipped
+                // makes a WAL restart safe to follow; a reader pinning
+                // an older snapshot leaves it partial, which is fine —
+                // the WAL simply keeps growing until the next attempt.
+                pos.restart_ok = busy == 0 && log == done && log as u64 == shipped;
+            }
+            Ok(true)
+        })();
+        self.unlock_writers();
+        if !result? {
+            return self.start_generation();
+        }
+        Ok(())
+    }
+
+    fn lock_writers(&self) -> Result {
+        self.lock
+            .execute_batch("BEGIN IMMEDIATE")
+            .map_err(|err| replica_err("cannot take the write lock", err))
+    }
+
+    fn unlock_writers(&self) {
+        if let Err(err) = self.lock.execute_batch("ROLLBACK") {
+            tracing::warn!("releasing the replicator's write lock failed: {err}");
+        }
+    }
+
+    /// Deletes the generations past `retain`, oldest first.
+    fn prune(&self) {
+        let Ok(mut generations) = list_generations(&self.opts.dir) else {
+            return;
+        };
+        let keep = self.opts.retain.max(1);
+        if generations.len() <= keep {
+            return;
+        }
+        generations.sort();
+        for (_, dir) in &generations[..generations.len() - keep] {
+            if let Err(err) = fs::remove_dir_all(dir) {
+                tracing::warn!(
+                    "cannot delete old replica generation {}: {err}",
+                    dir.display()
+                );
+            }
+        }
+    }
+}
+
+/// What [`restore`] rebuilt.
+#[derive(Debug, Clone)]
+pub struct Restored {
+    /// Directory of the generation used.
+    pub generation: PathBuf,
+    /// WAL segments replayed on top of its snapshot.
+    pub segments: usize,
+    /// Capture time of the last segment replayed (or of the snapshot),
+    /// in milliseconds since the Unix epoch: the instant the restored
+    /// database reflects.
+    pub as_of_ms: u64,
+}
+
+impl Restored {
+    /// [`as_of_ms`](Self::as_of_ms) as RFC 3339, for messages.
+    pub fn as_of(&self) -> String {
+        i64::try_from(self.as_of_ms)
+            .ok()
+            .and_then(chrono::DateTime::from_timestamp_millis)
+            .map_or_else(|| self.as_of_ms.to_string(), |at| at.to_rfc3339())
+    }
+}
+
+/// Rebuilds a database from a replica directory into `to`.
+///
+/// `at` (milliseconds since the Unix epoch) picks a point in time; `None`
+/// restores the latest state shipped. `to` must not exist yet: a restore
+/// never overwrites a database.
+pub fn restore(from: &Path, to: &Path, at: Option<u64>) -> Result<Restored> {
+    if to.exists() {
+        return Err(Error::Config(format!(
+            "{} already exists; restore into a new path and swap it in once checked",
+            to.display()
+        )));
+    }
+    let at = at.unwrap_or(u64::MAX);
+    let mut generations = list_generations(from)?;
+    generations.sort();
+    let Some((started, dir)) = generations.into_iter().rev().find(|(ms, _)| *ms <= at) else {
+        return Err(Error::Config(format!(
+            "no replica generation in {} starts at or before the requested time",
+            from.display()
+        )));
+    };
+
+    // (cycle, offset, captured, path), applied in WAL order and cut at
+    // the first segment captured after `at`.
+    let mut segments = Vec::new();
+    let wal_dir = dir.join("wal");
+    let entries = fs::read_dir(&wal_dir)
+        .map_err(|err| io_err(&format!("cannot read {}", wal_dir.display()), err))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(parsed) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_segment_name)
+        else {
+            continue;
+        };
+        segments.push((parsed.0, parsed.1, parsed.2, path));
+    }
+    segments.sort();
+    let cut = segments
+        .iter()
+        .position(|(_, _, captured, _)| *captured > at)
+        .unwrap_or(segments.len());
+    segments.truncate(cut);
+
+    copy_synced(&dir.join("snapshot.db"), to)?;
+    let result = (|| -> Result<u64> {
+        let mut as_of = started;
+        let mut index = 0;
+        while index < segments.len() {
+            let cycle = segments[index].0;
+            let mut wal = Vec::new();
+            while index < segments.len() && segments[index].0 == cycle {
+                let (_, offset, captured, path) = &segments[index];
+                if *offset != wal.len() as u64 {
+                    return Err(Error::Config(format!(
+                        "replica segment {} does not follow the one before it; the \
+                         generation is incomplete",
+                        path.display()
+                    )));
+                }
+                let bytes = fs::read(path)
+                    .map_err(|err| io_err(&format!("cannot read {}", path.display()), err))?;
+                wal.extend_from_slice(&bytes);
+                as_of = *captured;
+                index += 1;
+            }
+            apply_wal(to, &wal)?;
+        }
+        Ok(as_of)
+    })();
+    match result {
+        Ok(as_of_ms) => Ok(Restored {
+            generation: dir,
+            segments: segments.len(),
+            as_of_ms,
+        }),
+        Err(err) => {
+            let _ = fs::remove_file(to);
+            Err(err)
+        }
+    }
+}
+
+/// Parses a `--at` value: RFC 3339 (`2024-05-01T12:00:00Z`) or Unix
+/// seconds. Returns milliseconds since the epoch.
+pub fn parse_at(value: &str) -> Result<u64> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs.saturating_mul(1000));
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .and_then(|at| u64::try_from(at.timestamp_millis()).ok())
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "cannot read `{value}` as a time: use RFC 3339 (2024-05-01T12:00:00Z) \
+                 or Unix seconds"
+            ))
+        })
+}
+
+/// Replays one cycle's WAL into the database at `db`.
+fn apply_wal(db: &Path, wal: &[u8]) -> Result {
+    let mut wal_path = db.as_os_str().to_owned();
+    wal_path.push("-wal");
+    write_synced(Path::new(&wal_path), wal)?;
+    let conn = Connection::open(db).map_err(|err| replica_err("cannot open the restore", err))?;
+    // Opening recovers the WAL; the checkpoint folds it into the file.
+    let (busy, _, _): (i64, i64, i64) = conn
+        .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .map_err(|err| replica_err("replaying the WAL failed", err))?;
+    if busy != 0 {
+        return Err(Error::Config(
+            "replaying the WAL failed: the restored database is in use".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// The parts of a WAL header the replicator needs.
+struct WalHeader {
+    big_endian: bool,
+    page_size: u64,
+    salts: (u32, u32),
+    checksum: (u32, u32),
+}
+
+impl WalHeader {
+    /// `None` for anything that is not a complete, self-consistent header.
+    fn parse(header: &[u8; WAL_HEADER as usize]) -> Option<Self> {
+        let magic = be32(header, 0);
+        if magic & !1 != WAL_MAGIC {
+            return None;
+        }
+        let big_endian = magic & 1 == 1;
+        let checksum = wal_checksum((0, 0), &header[..24], big_endian);
+        if checksum != (be32(header, 24), be32(header, 28)) {
+            return None;
+        }
+        let page_size = match be32(header, 8) {
+            1 => 65_536,
+            size => u64::from(size),
+        };
+        Some(Self {
+            big_endian,
+            page_size,
+            salts: (be32(header, 16), be32(header, 20)),
+            checksum,
+        })
+    }
+}
+
+/// SQLite's WAL checksum, continued from `(s1, s2)` over `data`.
+fn wal_checksum((mut s1, mut s2): (u32, u32), data: &[u8], big_endian: bool) -> (u32, u32) {
+    let word = |chunk: &[u8]| {
+        let bytes = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    for pair in data.chunks_exact(8) {
+        s1 = s1.wrapping_add(word(&pair[..4])).wrapping_add(s2);
+        s2 = s2.wrapping_add(word(&pair[4..])).wrapping_add(s1);
+    }
+    (s1, s2)
+}
+
+fn be32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// `cycle-offset-captured.wal` → its three numbers.
+fn parse_segment_name(name: &str) -> Option<(u32, u64, u64)> {
+    let mut parts = name.strip_suffix(".wal")?.splitn(3, '-');
+    let cycle = parts.next()?.parse().ok()?;
+    let offset = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let captured = parts.next()?.parse().ok()?;
+    Some((cycle, offset, captured))
+}
+
+/// The generations in a replica directory with their start times.
+fn list_generations(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let root = dir.join("generations");
+    let entries = fs::read_dir(&root).map_err(|err| {
+        io_err(
+            &format!("cannot read the replica directory {}", root.display()),
+            err,
+        )
+    })?;
+    Ok(entries
+        .flatten()
+        .filter_map(|entry| {
+            let started = entry.file_name().to_str()?.parse().ok()?;
+            let path = entry.path();
+            path.join("snapshot.db")
+                .is_file()
+                .then_some((started, path))
+        })
+        .collect())
+}
+
+/// Writes `bytes` to `path` through a temporary name, so a reader (or a
+/// crash) never sees half a segment.
+fn write_synced(path: &Path, bytes: &[u8]) -> Result {
+    let tmp = path.with_extension("tmp");
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    };
+    write().map_err(|err| io_err(&format!("cannot write {}", path.display()), err))
+}
+
+fn copy_synced(from: &Path, to: &Path) -> Result {
+    let tmp = to.with_extension("tmp");
+    let copy = || -> std::io::Result<()> {
+        fs::copy(from, &tmp)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, to)
+    };
+    copy().map_err(|err| io_err(&format!("cannot copy {}", from.display()), err))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn io_err(what: &str, err: std::io::Error) -> Error {
+    Error::Config(format!("replica: {what}: {err}"))
+}
+
+fn replica_err(what: &str, err: rusqlite::Error) -> Error {
+    Error::Config(format!("replica: {what}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const TEST_INTERVAL: Duration = Duration::from_millis(20);
+
+    fn scratch(name: &str) -> PathBuf {
+        static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("nitr-replica-{name}-{}-{id}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("scratch dir");
+        dir
+    }
+
+    fn pragmas() -> SqlitePragmas {
+        SqlitePragmas {
+            wal_autocheckpoint: 0,
+            ..Default::default()
+        }
+    }
+
+    fn count(path: &Path) -> i64 {
+        let conn = Connection::open(path).expect("open restore");
+        conn.query_row("SELECT count(*) FROM notes", [], |row| row.get(0))
+            .expect("count")
+    }
+
+    #[test]
+    fn a_restore_sees_every_commit_shipped() {
+        let dir = scratch("ship");
+        let db = dir.join("app.db");
+        let app = super::super::pragmas::open(&db, &pragmas()).expect("open");
+        app.execute_batch("CREATE TABLE notes (body TEXT)")
+            .expect("schema");
+
+        let opts = ReplicaOptions {
+            dir: dir.join("replica"),
+            interval: TEST_INTERVAL,
+            checkpoint_pages: 4,
+            retain: 2,
+        };
+        let replicator = Replicator::start(&db, &pragmas(), opts).expect("start");
+        for n in 0..50 {
+            app.execute(
+                "INSERT INTO notes (body) VALUES (?1)",
+                [format!("note {n}")],
+            )
+            .expect("insert");
+        }
+        // Stopping runs the last copy, so every commit above is shipped.
+        replicator.stop();
+
+        let restored = dir.join("restored.db");
+        let report = restore(&dir.join("replica"), &restored, None).expect("restore");
+        assert!(report.segments > 0);
+        assert_eq!(count(&restored), 50);
+        let conn = Connection::open(&restored).expect("open");
+        let ok: String = conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .expect("integrity");
+        assert_eq!(ok, "ok");
+    }
+
+    #[test]
+    fn a_point_in_time_restore_stops_at_the_requested_instant() {
+        let dir = scratch("pitr");
+        let db = dir.join("app.db");
+        let app = super::super::pragmas::open(&db, &pragmas()).expect("open");
+        app.execute_batch("CREATE TABLE notes (body TEXT)")
+            .expect("schema");
+        let mut shipper = Shipper::open(
+            &db,
+            &pragmas(),
+            ReplicaOptions {
+                dir: dir.join("replica"),
+                ..Default::default()
+            },
+        )
+        .expect("open shipper");
+        shipper.sync().expect("generation");
+
+        app.execute("INSERT INTO notes (body) VALUES ('first')", [])
+            .expect("insert");
+        shipper.sync().expect("sync");
+        std::thread::sleep(Duration::from_millis(5));
+        let between = now_ms();
+        std::thread::sleep(Duration::from_millis(5));
+        app.execute("INSERT INTO notes (body) VALUES ('second')", [])
+            .expect("insert");
+        shipper.sync().expect("sync");
+
+        let early = dir.join("early.db");
+        restore(&dir.join("replica"), &early, Some(between)).expect("restore early");
+        assert_eq!(count(&early), 1);
+        let late = dir.join("late.db");
+        restore(&dir.join("replica"), &late, None).expect("restore late");
+        assert_eq!(count(&late), 2);
+    }
+
+    #[test]
+    fn checkpoints_start_new_cycles_without_losing_frames() {
+        let dir = scratch("cycles");
+        let db = dir.join("app.db");
+        let app = super::super::pragmas::open(&db, &pragmas()).expect("open");
+        app.execute_batch("CREATE TABLE notes (body TEXT)")
+            .expect("schema");
+        let mut shipper = Shipper::open(
+            &db,
+            &pragmas(),
+            ReplicaOptions {
+                dir: dir.join("replica"),
+                checkpoint_pages: 2,
+                ..Default::default()
+            },
+        )
+        .expect("open shipper");
+        for n in 0..20 {
+            app.execute(
+                "INSERT INTO notes (body) VALUES (?1)",
+                [format!("note {n}")],
+            )
+            .expect("insert");
+            shipper.sync().expect("sync");
+        }
+        let pos = shipper.pos.as_ref().expect("position");
+        assert!(pos.cycle > 0, "the WAL should have restarted");
+        let generations = list_generations(&dir.join("replica")).expect("list");
+        assert_eq!(generations.len(), 1, "a restart we caused is not a gap");
+
+        let restored = dir.join("restored.db");
+        restore(&dir.join("replica"), &restored, None).expect("restore");
+        assert_eq!(count(&restored), 20);
+    }
+
+    #[test]
+    fn restoring_over_an_existing_file_is_refused() {
+        let dir = scratch("exists");
+        fs::write(dir.join("taken.db"), b"x").expect("write");
+        let err = restore(&dir, &dir.join("taken.db"), None).expect_err("must refuse");
+        assert!(err.to_string().contains("already exists"), "{err}");
+    }
+
+    #[test]
+    fn at_accepts_rfc3339_and_unix_seconds() {
+        assert_eq!(parse_at("1700000000").expect("secs"), 1_700_000_000_000);
+        assert_eq!(
+            parse_at("2023-11-14T22:13:20Z").expect("rfc3339"),
+            1_700_000_000_000
+        );
+        assert!(parse_at("yesterday").is_err());
+    }
+}
@@ -0,0 +1,561 @@
+//! Full-text search over SQLite FTS5: the index manifest and
+//! `nitr.db:search()`.
+//!
+//! Each `[[database.search]]` entry becomes an external-content FTS5 table
+//! plus three triggers keeping it in step with its content table, so the
+//! text is stored once and a write needs no extra code. `nitr migrate`
+//! builds them (and backfills existing rows), recording each definition's
+//! checksum in `_nitr_search`: a changed definition is dropped and rebuilt,
+//! which is always safe — an index holds nothing the content table does
+//! not. An index removed from the manifest is dropped the same way.
+//!
+//! Queries are plain user text by default: every word is quoted, so
+//! `"`, `-`, `AND` or `NEAR(` in a search box are words to look for, never
+//! FTS5 syntax (and never a syntax error). A trailing `*` keeps its prefix
+//! meaning.
+
+use std::collections::HashMap;
+
+use mlua::{Table, Value};
+use rusqlite::Connection;
+
+use crate::config::SearchIndex;
+use crate::db::migrate::{State, checksum};
+use crate::db::types::{SqlRow, SqlValue};
+use nitr_core::{Error, Result};
+
+/// Table recording which definitions are built.
+const TABLE: &str = "_nitr_search";
+
+/// Marks the start and end of a match in `snippet()`/`highlight()` output
+/// until the text is escaped and the caller's markers go in.
+const OPEN: &str = "\u{2}";
+const CLOSE: &str = "\u{3}";
+
+/// The DDL creating `index`: the FTS5 table and its sync triggers.
+fn create_sql(index: &SearchIndex) -> String {
+    let SearchIndex {
+        name,
+        table,
+        key,
+        columns,
+        tokenize,
+    } = index;
+    let cols = columns.join(", ");
+    let new = prefixed("new", columns);
+    let old = prefixed("old", columns);
+    let tokenize = tokenize
+        .as_ref()
+        .map(|t| format!(", tokenize = '{}'", t.replace('\'', "''")))
+        .unwrap_or_default();
+    format!(
+        "CREATE VIRTUAL TABLE {name} USING fts5({cols}, content = '{table}', \
+             content_rowid = '{key}'{tokenize});
+         CREATE TRIGGER {name}_ai AFTER INSERT ON {table} BEGIN
+             INSERT INTO {name} (rowid, {cols}) VALUES (new.{key}, {new});
+         END;
+         CREATE TRIGGER {name}_ad AFTER DELETE ON {table} BEGIN
+             INSERT INTO {name} ({name}, rowid, {cols}) VALUES ('delete', old.{key}, {old});
+         END;
+         CREATE TRIGGER {name}_au AFTER UPDATE ON {table} BEGIN
+             INSERT INTO {name} ({name}, rowid, {cols}) VALUES ('delete', old.{key}, {old});
+             INSERT INTO {name} (rowid, {cols}) VALUES (new.{key}, {new});
+         END;"
+    )
+}
+
+/// The tables FTS5 keeps behind each index, written on every indexed
+/// change.
+pub(crate) fn shadow_tables(indexes: &[SearchIndex]) -> std::collections::HashSet<String> {
+    indexes
+        .iter()
+        .flat_map(|index| {
+            ["data", "idx", "docsize", "config", "content"]
+                .map(|suffix| format!("{}_{suffix}", index.name))
+        })
+        .collect()
+}
+
+fn prefixed(prefix: &str, columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|c| format!("{prefix}.{c}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn drop_sql(name: &str) -> String {
+    format!(
+        "DROP TRIGGER IF EXISTS {name}_ai;
+         DROP TRIGGER IF EXISTS {name}_ad;
+         DROP TRIGGER IF EXISTS {name}_au;
+         DROP TABLE IF EXISTS {name};"
+    )
+}
+
+fn ensure_table(conn: &Connection) -> Result {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {TABLE} (
+             name       TEXT PRIMARY KEY,
+             checksum   TEXT NOT NULL,
+             built_at   TEXT NOT NULL DEFAULT (datetime('now'))
+         )"
+    ))
+    .map_err(|err| Error::Config(format!("cannot create the {TABLE} table: {err}")))
+}
+
+/// `name → checksum` for every built index.
+fn built(conn: &Connection) -> Result<HashMap<String, String>> {
+    ensure_table(conn)?;
+    let read = |err: rusqlite::Error| Error::Config(format!("cannot read {TABLE}: {err}"));
+    let mut stmt = conn
+        .prepare(&format!("SELECT name, checksum FROM {TABLE}"))
+        .map_err(read)?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(read)?;
+    rows.collect::<rusqlite::Result<_>>().map_err(read)
+}
+
+/// The state of every declared index: `Modified` means the definition
+/// changed since it was built, and `nitr migrate` will rebuild it.
+pub fn status(conn: &Connection, indexes: &[SearchIndex]) -> Result<Vec<(String, State)>> {
+    let built = built(conn)?;
+    Ok(indexes
+        .iter()
+        .map(|index| {
+            let state = match built.get(&index.name) {
+                None => State::Pending,
+                Some(sum) if *sum == checksum(&create_sql(index)) => State::Applied,
+                Some(_) => State::Modified,
+            };
+            (index.name.clone(), state)
+        })
+        .collect())
+}
+
+/// Indexes `nitr migrate` still has to build, rebuild or drop.
+pub fn pending(conn: &Connection, indexes: &[SearchIndex]) -> Result<Vec<String>> {
+    let mut names: Vec<String> = status(conn, indexes)?
+        .into_iter()
+        .filter(|(_, state)| *state != State::Applied)
+        .map(|(name, _)| name)
+        .collect();
+    names.extend(removed(conn, indexes)?);
+    Ok(names)
+}
+
+/// Built indexes no longer in the manifest.
+fn removed(conn: &Connection, indexes: &[SearchIndex]) -> Result<Vec<String>> {
+    let mut names: Vec<String> = built(conn)?
+        .into_keys()
+        .filter(|name| !indexes.iter().any(|index| index.name == *name))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Builds, rebuilds and drops indexes until the database matches the
+/// manifest, returning one line per change. Each runs in its own
+/// transaction, backfill included.
+pub fn apply(conn: &Connection, indexes: &[SearchIndex]) -> Result<Vec<String>> {
+    let mut done = Vec::new();
+    for name in removed(conn, indexes)? {
+        in_transaction(conn, &name, || {
+            conn.execute_batch(&drop_sql(&name))?;
+            conn.execute(&format!("DELETE FROM {TABLE} WHERE name = ?1"), [&name])?;
+            Ok(())
+        })?;
+        tracing::info!("dropped search index `{name}`");
+        done.push(format!("dropped {name}"));
+    }
+    for (index, (_, state)) in indexes.iter().zip(status(conn, indexes)?) {
+        let verb = match state {
+            State::Applied => continue,
+            State::Pending => "built",
+            State::Modified => "rebuilt",
+        };
+        let name = &index.name;
+        let sql = create_sql(index);
+        in_transaction(conn, name, || {
+            conn.execute_batch(&drop_sql(name))?;
+            conn.execute_batch(&sql)?;
+            // Backfill from the content table.
+            conn.execute(
+                &format!("INSERT INTO {name} ({name}) VALUES ('rebuild')"),
+                [],
+            )?;
+            conn.execute(
+                &format!("INSERT OR REPLACE INTO {TABLE} (name, checksum) VALUES (?1, ?2)"),
+                [name, &checksum(&sql)],
+            )?;
+            Ok(())
+        })?;
+        tracing::info!("{verb} search index `{name}`");
+        done.push(format!("{verb} {name}"));
+    }
+    Ok(done)
+}
+
+fn in_transaction(
+    conn: &Connection,
+    name: &str,
+    f: impl FnOnce() -> rusqlite::Result<()>,
+) -> Result {
+    let fail = |err: rusqlite::Error| Error::Config(format!("search index `{name}`: {err}"));
+    conn.execute_batch("BEGIN").map_err(fail)?;
+    match f().and_then(|()| conn.execute_batch("COMMIT")) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            if let Err(rollback) = conn.execute_batch("ROLLBACK") {
+                tracing::error!("failed to roll back search index `{name}`: {rollback}");
+            }
+            Err(fail(err))
+        }
+    }
+}
+
+/// Turns user text into an FTS5 query matching all of its words: each word
+/// quoted (inner quotes doubled), a trailing `*` kept as a prefix search.
+/// `None` when nothing searchable is left.
+pub(crate) fn sanitize(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .filter_map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(stem) => (stem.trim_end_matches('*'), "*"),
+                None => (word, ""),
+            };
+            let word: String = word.chars().filter(|c| !c.is_control()).collect();
+            (!word.is_empty()).then(|| format!("\"{}\"{prefix}", word.replace('"', "\"\"")))
+        })
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Where the matched terms go in `snippet`/`highlight` output.
+struct Marks {
+    column: i64,
+    open: String,
+    close: String,
+}
+
+/// `nitr.db:search()` options, read from Lua.
+pub(crate) struct SearchOptions {
+    limit: i64,
+    offset: i64,
+    raw: bool,
+    html: bool,
+    columns: Vec<String>,
+    snippet: Option<(Marks, String, i64)>,
+    highlight: Option<Marks>,
+}
+
+impl SearchOptions {
+    pub(crate) fn from_lua(index: &SearchIndex, opts: Option<Table>) -> mlua::Result<Self> {
+        let Some(opts) = opts else {
+            return Ok(Self {
+                limit: 20,
+                offset: 0,
+                raw: false,
+                html: true,
+                columns: Vec::new(),
+                snippet: None,
+                highlight: None,
+            });
+        };
+        let column_of = |name: &str| {
+            index
+                .columns
+                .iter()
+                .position(|c| c == name)
+                .map(|i| i as i64)
+                .ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "search index `{}` has no column `{name}` (it indexes {})",
+                        index.name,
+                        index.columns.join(", ")
+                    ))
+                })
+        };
+        // `true`, a column name, or `{ column, open, close, ... }`.
+        let marks = |value: Value| -> mlua::Result<Option<(Marks, Option<Table>)>> {
+            let (column, spec) = match value {
+                Value::Nil | Value::Boolean(false) => return Ok(None),
+                Value::Boolean(true) => (None, None),
+                Value::String(name) => (Some(name.to_str()?.to_owned()), None),
+                Value::Table(spec) => (spec.get::<Option<String>>("column")?, Some(spec)),
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "expected true, a column name or an options table, got {}",
+                        other.type_name()
+                    )));
+                }
+            };
+            let get = |key: &str, default: &str| -> mlua::Result<String> {
+                match &spec {
+                    Some(spec) => Ok(spec
+                        .get::<Option<String>>(key)?
+                        .unwrap_or_else(|| default.into())),
+                    None => Ok(default.into()),
+                }
+            };
+            let marks = Marks {
+                column: column.as_deref().map(column_of).transpose()?.unwrap_or(-1),
+                open: get("open", "<mark>")?,
+                close: get("close", "</mark>")?,
+            };
+            Ok(Some((marks, spec)))
+        };
+
+        let snippet = match marks(opts.get("snippet")?)? {
+            Some((marks, spec)) => {
+                let (ellipsis, tokens) = match spec {
+                    Some(spec) => (
+                        spec.get::<Option<String>>("ellipsis")?
+                            .unwrap_or_else(|| "…".into()),
+                        spec.get::<Option<i64>>("tokens")?.unwrap_or(16),
+                    ),
+                    None => ("…".into(), 16),
+                };
+                Some((marks, ellipsis, tokens.clamp(1, 64)))
+            }
+            None => None,
+        };
+        let highlight = match marks(opts.get("highlight")?)? {
+            Some((marks, _)) if marks.column < 0 => {
+                return Err(mlua::Error::RuntimeError(
+                    "highlight needs a column: highlight = \"title\"".into(),
+                ));
+            }
+            other => other.map(|(marks, _)| marks),
+        };
+        let columns: Vec<String> = opts
+            .get::<Option<Vec<String>>>("columns")?
+            .unwrap_or_default();
+        for column in &columns {
+            column_of(column)?;
+        }
+        Ok(Self {
+            limit: opts.get::<Option<i64>>("limit")?.unwrap_or(20).max(0),
+            offset: opts.get::<Option<i64>>("offset")?.unwrap_or(0).max(0),
+            raw: opts.get::<Option<bool>>("raw")?.unwrap_or(false),
+            html: opts.get::<Option<bool>>("html")?.unwrap_or(true),
+            columns,
+            snippet,
+            highlight,
+        })
+    }
+}
+
+/// The ranked query for `text`, or `None` when the text holds nothing to
+/// search for.
+pub(crate) fn query(
+    index: &SearchIndex,
+    text: &str,
+    opts: &SearchOptions,
+) -> Option<(String, Vec<SqlValue>)> {
+    let SearchIndex {
+        name, table, key, ..
+    } = index;
+    let mut text = if opts.raw {
+        Some(text.trim().to_owned()).filter(|t| !t.is_empty())?
+    } else {
+        sanitize(text)?
+    };
+    if !opts.columns.is_empty() {
+        text = format!("{{{}}} : ({text})", opts.columns.join(" "));
+    }
+    let text_param = |s: &str| SqlValue::Text(s.as_bytes().to_vec());
+    let mut extra = String::new();
+    let mut params = Vec::new();
+    if let Some((marks, ellipsis, tokens)) = &opts.snippet {
+        extra.push_str(&format!(
+            ", snippet({name}, {}, ?, ?, ?, {tokens}) AS snippet",
+            marks.column
+        ));
+        params.extend([text_param(OPEN), text_param(CLOSE), text_param(ellipsis)]);
+    }
+    if let Some(marks) = &opts.highlight {
+        extra.push_str(&format!(
+            ", highlight({name}, {}, ?, ?) AS highlight",
+            marks.column
+        ));
+        params.extend([text_param(OPEN), text_param(CLOSE)]);
+    }
+    params.extend([
+        text_param(&text),
+        SqlValue::Int(opts.limit),
+        SqlValue::Int(opts.offset),
+    ]);
+    let sql = format!(
+        "SELECT c.*, {name}.rank AS rank{extra}
+         FROM {name} JOIN {table} AS c ON c.{key} = {name}.rowid
+         WHERE {name} MATCH ?
+         ORDER BY {name}.rank
+         LIMIT ? OFFSET ?"
+    );
+    Some((sql, params))
+}
+
+/// Escapes (unless `html = false`) the `snippet`/`highlight` text and puts
+/// the caller's markers around the matches.
+pub(crate) fn finish_row(row: &mut SqlRow, opts: &SearchOptions) {
+    for (column, value) in row.iter_mut() {
+        let marks = match column.as_str() {
+            "snippet" => opts.snippet.as_ref().map(|(marks, ..)| marks),
+            "highlight" => opts.highlight.as_ref(),
+            _ => None,
+        };
+        let (Some(marks), SqlValue::Text(bytes)) = (marks, &mut *value) else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(bytes);
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '\u{2}' => out.push_str(&marks.open),
+                '\u{3}' => out.push_str(&marks.close),
+                '&' if opts.html => out.push_str("&amp;"),
+                '<' if opts.html => out.push_str("&lt;"),
+                '>' if opts.html => out.push_str("&gt;"),
+                '"' if opts.html => out.push_str("&quot;"),
+                '\'' if opts.html => out.push_str("&#39;"),
+                c => out.push(c),
+            }
+        }
+        *bytes = out.into_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes_index() -> SearchIndex {
+        SearchIndex {
+            name: "notes_search".into(),
+            table: "notes".into(),
+            key: "id".into(),
+            columns: vec!["title".into(), "body".into()],
+            tokenize: Some("porter unicode61".into()),
+        }
+    }
+
+    fn seeded() -> Connection {
+        let conn = Connection::open_in_memory().expect("open");
+        conn.execute_batch(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+             INSERT INTO notes (title, body) VALUES ('Groceries', 'buy apples and <b>pears</b>');",
+        )
+        .expect("seed");
+        conn
+    }
+
+    fn search(conn: &Connection, index: &SearchIndex, text: &str) -> Vec<SqlRow> {
+        let opts = SearchOptions {
+            limit: 10,
+            offset: 0,
+            raw: false,
+            html: true,
+            columns: Vec::new(),
+            snippet: Some((
+                Marks {
+                    column: 1,
+                    open: "[".into(),
+                    close: "]".into(),
+                },
+                "…".into(),
+                8,
+            )),
+            highlight: None,
+        };
+        let Some((sql, params)) = query(index, text, &opts) else {
+            return Vec::new();
+        };
+        let mut rows = crate::db::query::call(conn, &sql, &params).expect("search");
+        for row in &mut rows {
+            finish_row(row, &opts);
+        }
+        rows
+    }
+
+    fn column<'a>(row: &'a SqlRow, name: &str) -> &'a SqlValue {
+        &row.iter().find(|(c, _)| c == name).expect(name).1
+    }
+
+    #[test]
+    fn user_text_never_reaches_fts5_as_syntax() {
+        assert_eq!(sanitize("  "), None);
+        assert_eq!(sanitize(" ** \u{7} "), None);
+        assert_eq!(
+            sanitize("say \"hi NEAR( -x app*"),
+            Some(r#""say" """hi" "NEAR(" "-x" "app"*"#.into())
+        );
+    }
+
+    #[test]
+    fn indexes_backfill_follow_writes_and_rebuild_on_change() {
+        let conn = seeded();
+        let mut index = notes_index();
+        assert_eq!(
+            pending(&conn, std::slice::from_ref(&index)).expect("pending"),
+            vec!["notes_search".to_owned()]
+        );
+        assert_eq!(
+            apply(&conn, std::slice::from_ref(&index)).expect("apply"),
+            vec!["built notes_search".to_owned()]
+        );
+        assert!(
+            pending(&conn, std::slice::from_ref(&index))
+                .expect("pending")
+                .is_empty()
+        );
+
+        // Backfilled, stemmed (porter), and the snippet is escaped around
+        // the markers.
+        let rows = search(&conn, &index, "apple");
+        assert_eq!(rows.len(), 1);
+        let SqlValue::Text(snippet) = column(&rows[0], "snippet") else {
+            panic!("snippet is text");
+        };
+        assert_eq!(
+            String::from_utf8_lossy(snippet),
+            "buy [apples] and &lt;b&gt;pears&lt;/b&gt;"
+        );
+        // A stray quote is a word, not an error.
+        assert!(search(&conn, &index, "\"").is_empty());
+        assert!(search(&conn, &index, "pears\"").len() == 1);
+
+        // The triggers keep the index in step.
+        conn.execute_batch(
+            "UPDATE notes SET body = 'oranges' WHERE id = 1;
+             INSERT INTO notes (title, body) VALUES ('Trip', 'pack apples');",
+        )
+        .expect("write");
+        assert!(search(&conn, &index, "pears").is_empty());
+        assert_eq!(search(&conn, &index, "apples").len(), 1);
+        conn.execute_batch("DELETE FROM notes WHERE title = 'Trip'")
+            .expect("delete");
+        assert!(search(&conn, &index, "apples").is_empty());
+
+        // A changed definition is rebuilt; a removed one is dropped.
+        index.columns.truncate(1);
+        assert_eq!(
+            apply(&conn, std::slice::from_ref(&index)).expect("rebuild"),
+            vec!["rebuilt notes_search".to_owned()]
+        );
+        assert_eq!(
+            apply(&conn, &[]).expect("drop"),
+            vec!["dropped notes_search".to_owned()]
+        );
+        let left: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM sqlite_master WHERE name LIKE 'notes_search%'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("count");
+        assert_eq!(left, 0);
+    }
+}
@@ -4,7 +4,23 @@ use rusqlite::{Connection, ToSql};
 
 use std::sync::{Arc, Mutex};
 
-pub(crate) type Conn = Arc<Mutex<Connection>>;
+pub(crate) type Conn = Arc<Mutex<Handle>>;
+
+/// One state's connection, plus the recorder publishing its committed
+/// changes when `[database] changes` is on and the slow-query threshold.
+pub(crate) struct Handle {
+    pub(crate) conn: Connection,
+    pub(crate) changes: Option<super::hooks::Recorder>,
+    pub(crate) slow_query: Option<std::time::Duration>,
+}
+
+impl std::ops::Deref for Handle {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
 
 /// A plain, `Send` SQL value: the boundary type between the Lua state (async
 /// thread) and rusqlite (blocking thread), so no Lua handle ever crosses
@@ -62,6 +78,16 @@ impl SqlValue {
 /// column order.
 pub(crate) type SqlRow = Vec<(String, SqlValue)>;
 
+/// What a statement produced, whichever backend ran it.
+pub(crate) enum Output {
+    /// `execute`: the number of affected rows.
+    Affected(u64),
+    /// `query_row` / `query_one`.
+    Row(SqlRow),
+    /// `query`.
+    Rows(Vec<SqlRow>),
+}
+
 /// Converts a result row into a Lua table keyed by column name.
 pub(crate) fn row_to_lua(lua: &Lua, row: SqlRow) -> mlua::Result<Table> {
     let table = lua.create_table()?;
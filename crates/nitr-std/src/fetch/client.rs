@@ -15,7 +15,7 @@ use bytes::Bytes;
 use mlua::{
     AnyUserData, ExternalResult, Function, Lua, Table, UserData, UserDataMethods, Value, Variadic,
 };
-use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, LOCATION};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, LOCATION};
 use reqwest::{Client as HttpClient, Method as HttpMethod, StatusCode, Url, redirect};
 use tracing::Instrument as _;
 
@@ -291,6 +291,34 @@ async fn execute(
     }
 }
 
+/// Performs one request under the fetch policy on behalf of the server
+/// itself (a JWKS document, an OAuth token exchange), returning the status
+/// and the whole body. Not counted against any request's outbound budget:
+/// no script asked for it.
+pub(crate) async fn request(
+    method: HttpMethod,
+    url: Url,
+    mut headers: HeaderMap,
+    body: Option<Bytes>,
+    opts: &FetchOptions,
+) -> mlua::Result<(StatusCode, Bytes)> {
+    let client = client_for(opts)?;
+    headers
+        .entry(ACCEPT)
+        .or_insert(HeaderValue::from_static("application/json"));
+    let spec = RequestSpec {
+        method,
+        url,
+        headers,
+        body,
+        timeout: None,
+        retry: None,
+    };
+    let mut resp = execute(&client, spec, opts).await?;
+    let body = crate::fetch::response::collect_body(&mut resp).await?;
+    Ok((resp.status(), body))
+}
+
 /// Returns the HTTP client for a connect policy, building it on first use.
 ///
 /// Keyed on the policy rather than process-wide because the DNS resolver
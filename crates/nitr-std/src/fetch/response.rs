@@ -20,7 +20,7 @@ impl LuaResponse {
 }
 
 /// Reads the whole body into memory, enforcing the response-size cap.
-async fn collect_body(resp: &mut LuaResponse) -> mlua::Result<bytes::Bytes> {
+pub(crate) async fn collect_body(resp: &mut LuaResponse) -> mlua::Result<bytes::Bytes> {
     let len = resp.resp.content_length().unwrap_or_default() as usize;
     let mut buf = BytesMut::with_capacity(len.min(resp.max_bytes as usize));
     while let Some(chunk) = resp.resp.chunk().await.into_lua_err()? {
@@ -18,6 +18,8 @@ use mlua::{
 };
 use sha2::Sha256;
 
+use crate::keys::Secret;
+
 type HmacSha256 = Hmac<Sha256>;
 
 /// Builds the skeleton of a helper response table: status, empty headers,
@@ -36,8 +38,13 @@ fn set_content_type(table: &Table, value: &str) -> mlua::Result<()> {
 
 /// Registers the HTTP ergonomics helpers on the `nitr` namespace table:
 /// `nitr.text`, `nitr.html`, `nitr.redirect`, `nitr.status`,
-/// `nitr.negotiate`, `nitr.sse`, and `nitr.error`.
-pub(crate) fn register(lua: &Lua, nitr: &Table) -> mlua::Result<()> {
+/// `nitr.negotiate`, `nitr.sse` (with its Rust-held channels when the
+/// server provides a hub), and `nitr.error`.
+pub(crate) fn register(
+    lua: &Lua,
+    nitr: &Table,
+    sse_hub: Option<crate::sse::SseHub>,
+) -> mlua::Result<()> {
     nitr.set(
         "text",
         lua.create_function(|lua, (body, status): (mlua::LuaString, Option<u16>)| {
@@ -74,10 +81,14 @@ pub(crate) fn register(lua: &Lua, nitr: &Table) -> mlua::Result<()> {
 
     // Server-Sent Events: `sse(function(send) ... end)` builds a streaming
     // response whose body hands the user function a `send(event, data)`
-    // formatter over the raw stream writer.
-    nitr.set(
-        "sse",
-        lua.create_function(|lua, handler: Function| {
+    // formatter over the raw stream writer. The table also carries
+    // `sse.channel`/`sse.publish`, whose streams Rust serves alone.
+    let sse = lua.create_table()?;
+    crate::sse::register(lua, &sse, sse_hub)?;
+    let meta = lua.create_table()?;
+    meta.set(
+        MetaMethod::Call.name(),
+        lua.create_function(|lua, (_, handler): (Table, Function)| {
             let table = response_table(lua, 200)?;
             let headers = table.get::<Table>("headers")?;
             headers.set("Content-Type", "text/event-stream")?;
@@ -102,6 +113,8 @@ pub(crate) fn register(lua: &Lua, nitr: &Table) -> mlua::Result<()> {
             Ok(table)
         })?,
     )?;
+    sse.set_metatable(Some(meta))?;
+    nitr.set("sse", sse)?;
 
     nitr.set(
         "negotiate",
@@ -174,17 +187,22 @@ pub(crate) fn register(lua: &Lua, nitr: &Table) -> mlua::Result<()> {
     Ok(())
 }
 
+/// The text of an event's data: a string verbatim, anything else as JSON.
+pub(crate) fn event_data(data: Value) -> mlua::Result<String> {
+    match data {
+        Value::String(s) => Ok(s.to_string_lossy().to_string()),
+        other => {
+            crate::utils::check_json_depth(&other)?;
+            serde_json::to_string(&other).into_lua_err()
+        }
+    }
+}
+
 /// Formats one Server-Sent Event: string data is taken verbatim (split
 /// into one `data:` line per newline, per the SSE wire format); any other
 /// value is JSON-encoded.
 fn format_event(event: &str, data: Value) -> mlua::Result<String> {
-    let data = match data {
-        Value::String(s) => s.to_string_lossy().to_string(),
-        other => {
-            crate::utils::check_json_depth(&other)?;
-            serde_json::to_string(&other).into_lua_err()?
-        }
-    };
+    let data = event_data(data)?;
     let mut out = format!("event: {event}\n");
     for line in data.split('\n') {
         out.push_str("data: ");
@@ -319,11 +337,12 @@ impl UserData for RequestCookies {
 
         // Returns the verified value of a signed cookie, or nil when the
         // cookie is missing, malformed, or its signature does not match.
+        // The secret is a string or the `nitr.keys` ring.
         methods.add_method(
             "verify",
-            |lua, this, (name, secret): (String, String)| match this
+            |lua, this, (name, secret): (String, Secret)| match this
                 .get(&name)
-                .and_then(|raw| verify(&name, raw, &secret))
+                .and_then(|raw| secret.verify(&name, raw))
             {
                 Some(v) => Ok(Value::String(lua.create_string(v)?)),
                 None => Ok(Value::Nil),
@@ -366,10 +385,10 @@ impl UserData for ResponseCookies {
         // secret)` can authenticate it on later requests.
         methods.add_method(
             "set_signed",
-            |_, this, (name, value, secret, opts): (String, String, String, Option<Table>)| {
+            |_, this, (name, value, secret, opts): (String, String, Secret, Option<Table>)| {
                 this.push(build_cookie(
                     &name,
-                    &sign(&name, &value, &secret),
+                    &secret.sign(&name, &value),
                     opts.as_ref(),
                 )?)
             },
@@ -444,35 +463,39 @@ pub(crate) fn build_cookie(name: &str, value: &str, opts: Option<&Table>) -> mlu
 /// cookie name bound into the MAC so values cannot be swapped between
 /// cookies.
 pub fn sign(name: &str, value: &str, secret: &str) -> String {
-    let payload = B64.encode(value);
-    format!(
-        "{payload}.{}",
-        B64.encode(mac_bytes(name, &payload, secret))
-    )
+    sign_with_key(name, value, secret.as_bytes())
 }
 
 /// Verifies a value produced by [`sign()`]; the MAC comparison is
 /// constant-time (`hmac::Mac::verify_slice`).
 pub fn verify(name: &str, signed: &str, secret: &str) -> Option<String> {
+    verify_with_key(name, signed, secret.as_bytes())
+}
+
+/// [`sign()`] under raw key bytes, for the keys of a [`crate::KeyRing`].
+pub(crate) fn sign_with_key(name: &str, value: &str, key: &[u8]) -> String {
+    let payload = B64.encode(value);
+    format!("{payload}.{}", B64.encode(mac_bytes(name, &payload, key)))
+}
+
+/// [`verify()`] under raw key bytes.
+pub(crate) fn verify_with_key(name: &str, signed: &str, key: &[u8]) -> Option<String> {
     let (payload, sig) = signed.rsplit_once('.')?;
     let sig = B64.decode(sig).ok()?;
-    new_mac(name, payload, secret).verify_slice(&sig).ok()?;
+    new_mac(name, payload, key).verify_slice(&sig).ok()?;
     String::from_utf8(B64.decode(payload).ok()?).ok()
 }
 
-fn new_mac(name: &str, payload: &str, secret: &str) -> HmacSha256 {
-    let mut mac: HmacSha256 = crate::utils::new_hmac(secret.as_bytes());
+fn new_mac(name: &str, payload: &str, key: &[u8]) -> HmacSha256 {
+    let mut mac: HmacSha256 = crate::utils::new_hmac(key);
     mac.update(name.as_bytes());
     mac.update(b"=");
     mac.update(payload.as_bytes());
     mac
 }
 
-fn mac_bytes(name: &str, payload: &str, secret: &str) -> Vec<u8> {
-    new_mac(name, payload, secret)
-        .finalize()
-        .into_bytes()
-        .to_vec()
+fn mac_bytes(name: &str, payload: &str, key: &[u8]) -> Vec<u8> {
+    new_mac(name, payload, key).finalize().into_bytes().to_vec()
 }
 
 #[cfg(test)]
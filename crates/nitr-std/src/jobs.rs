@@ -0,0 +1,799 @@
+//! `nitr.jobs`: a durable background job queue in a Nitr-owned SQLite
+//! table.
+//!
+//! A request enqueues a job by name with a plain-data payload and returns;
+//! the server claims due jobs and runs the handler the application
+//! registered with `app:job(name, fn)` on a pooled state. This module owns
+//! the queue itself — enqueueing, claiming, retry scheduling and the
+//! dead-letter state — and knows nothing of handlers.
+//!
+//! A claimed job is leased, not removed: its `run_at` moves to the end of
+//! the lease, and a job whose process died mid-run is claimed again once
+//! the lease lapses. Each claim counts an attempt, and an attempt records
+//! its outcome only while the job is still on that attempt, so one that
+//! outlived its lease cannot undo the attempt that replaced it. A finished
+//! job is deleted; a job out of attempts stays
+//! in the table as `dead`, with its last error, until it is retried or
+//! purged (`nitr jobs`).
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mlua::{Lua, Table, Value};
+use rusqlite::{Connection, TransactionBehavior, params};
+
+use crate::SqlitePragmas;
+use nitr_core::{Error, Result};
+
+/// Ceiling on a job name; names identify handlers, nothing more.
+const MAX_NAME_BYTES: usize = 128;
+
+/// Ceiling on a unique key, like a `nitr.kv` key.
+const MAX_UNIQUE_BYTES: usize = 1024;
+
+/// Ceiling on one serialized payload. Jobs carry references (an id, an
+/// address), not the data itself.
+const MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS _nitr_jobs (
+        id           INTEGER PRIMARY KEY,
+        name         TEXT NOT NULL,
+        payload      TEXT NOT NULL,
+        state        TEXT NOT NULL DEFAULT 'queued',
+        run_at       INTEGER NOT NULL,
+        attempts     INTEGER NOT NULL DEFAULT 0,
+        max_attempts INTEGER NOT NULL,
+        unique_key   TEXT,
+        last_error   TEXT,
+        created_at   INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS _nitr_jobs_due ON _nitr_jobs (run_at, id)
+        WHERE state != 'dead';
+    CREATE UNIQUE INDEX IF NOT EXISTS _nitr_jobs_unique ON _nitr_jobs (unique_key)
+        WHERE unique_key IS NOT NULL AND state != 'dead';
+";
+
+/// How the queue retries and leases jobs.
+#[derive(Debug, Clone)]
+pub struct JobsOptions {
+    /// Attempts a job gets before it is dead-lettered, unless it was
+    /// enqueued with its own `max_attempts`.
+    pub max_attempts: u32,
+    /// The delay before the first retry; each further retry doubles it.
+    pub backoff: Duration,
+    /// The longest delay between two attempts.
+    pub max_backoff: Duration,
+    /// How long a claimed job belongs to its worker. One still running
+    /// past this is presumed lost with its process and claimed again, so
+    /// it must comfortably exceed the handler time limit.
+    pub lease: Duration,
+}
+
+impl Default for JobsOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3600),
+            lease: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Where a job is in its life. Finished jobs are deleted, so they have
+/// no state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting for its `run_at`, or for a free worker.
+    Queued,
+    /// Claimed by a worker, whose lease ends at `run_at`.
+    Running,
+    /// Out of attempts; kept for inspection until retried or purged.
+    Dead,
+}
+
+impl JobState {
+    /// The name stored in the table and printed by `nitr jobs`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Dead => "dead",
+        }
+    }
+}
+
+impl FromStr for JobState {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "queued" => Ok(JobState::Queued),
+            "running" => Ok(JobState::Running),
+            "dead" => Ok(JobState::Dead),
+            other => Err(Error::Config(format!(
+                "unknown job state `{other}`: expected \"queued\", \"running\" or \"dead\""
+            ))),
+        }
+    }
+}
+
+/// A claimed job, as handed to its handler.
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// The job's row id.
+    pub id: i64,
+    /// The handler it is for.
+    pub name: String,
+    /// The payload as JSON.
+    pub payload: String,
+    /// This attempt, counting from 1.
+    pub attempt: u32,
+    /// The attempts it gets before it is dead-lettered.
+    pub max_attempts: u32,
+}
+
+/// What [`Jobs::fail`] made of a failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failed {
+    /// Queued again, due after this backoff.
+    Retry(Duration),
+    /// That was its last attempt: the job is dead.
+    Dead,
+    /// The lease lapsed first and the job moved on — claimed again,
+    /// dead-lettered or purged — so nothing was recorded.
+    LeaseLost,
+}
+
+/// A row of the queue, as `nitr jobs list` shows it.
+#[derive(Debug, Clone)]
+pub struct JobInfo {
+    /// The job's row id.
+    pub id: i64,
+    /// The handler it is for.
+    pub name: String,
+    /// Where it is in its life.
+    pub state: JobState,
+    /// Attempts started so far.
+    pub attempts: u32,
+    /// The attempts it gets before it is dead-lettered.
+    pub max_attempts: u32,
+    /// When a queued job is due, or a running job's lease ends
+    /// (milliseconds since the epoch).
+    pub run_at: i64,
+    /// The key it was enqueued with, if any.
+    pub unique_key: Option<String>,
+    /// Why its last attempt failed.
+    pub last_error: Option<String>,
+    /// When it was enqueued (milliseconds since the epoch).
+    pub created_at: i64,
+}
+
+/// What [`Jobs::enqueue`] is asked to do beyond name and payload.
+#[derive(Debug, Clone, Default)]
+pub struct Enqueue {
+    /// How long from now before the job is due.
+    pub delay: Duration,
+    /// While a job with this key is queued or running, enqueueing another
+    /// returns the existing one instead.
+    pub unique: Option<String>,
+    /// Overrides [`JobsOptions::max_attempts`] for this job.
+    pub max_attempts: Option<u32>,
+}
+
+/// The shared queue. Cloning shares the connection; the server opens one
+/// and hands it to every state and to its workers.
+#[derive(Clone)]
+pub struct Jobs {
+    conn: Arc<Mutex<Connection>>,
+    opts: Arc<JobsOptions>,
+    /// Wakes this process's workers when a job is enqueued due now, so
+    /// they need not wait for the next poll.
+    wake: Arc<tokio::sync::Notify>,
+}
+
+impl std::fmt::Debug for Jobs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Jobs")
+            .field("opts", &self.opts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Jobs {
+    /// Opens the queue in the SQLite file at `path`, creating its table.
+    pub fn open(path: &Path, pragmas: &SqlitePragmas, opts: JobsOptions) -> Result<Self> {
+        let conn = crate::db::pragmas::open(path, pragmas)?;
+        conn.execute_batch(SCHEMA).map_err(|err| {
+            Error::Config(format!(
+                "cannot create the nitr.jobs table in {}: {err}",
+                path.display()
+            ))
+        })?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            opts: Arc::new(opts),
+            wake: Arc::new(tokio::sync::Notify::new()),
+        })
+    }
+
+    /// Runs `f` on the connection. Blocks: async callers go through
+    /// [`with`](Self::with).
+    fn lock<T>(&self, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> Result<T> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| Error::Config("the nitr.jobs lock is poisoned".into()))?;
+        f(&mut conn).map_err(|err| Error::Config(format!("nitr.jobs: {err}")))
+    }
+
+    /// Runs `f` on the connection, off the async threads.
+    async fn with<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Jobs) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let jobs = self.clone();
+        tokio::task::spawn_blocking(move || f(&jobs))
+            .await
+            .map_err(|err| Error::Config(format!("nitr.jobs task failed: {err}")))?
+    }
+
+    /// Adds a job, returning its id and whether it is new: with a unique
+    /// key already queued or running, nothing is added and the existing
+    /// job's id comes back.
+    pub fn enqueue(&self, name: &str, payload: &str, opts: &Enqueue) -> Result<(i64, bool)> {
+        let now = now_ms();
+        let run_at = now.saturating_add(opts.delay.as_millis() as i64);
+        let max_attempts = opts.max_attempts.unwrap_or(self.opts.max_attempts).max(1);
+        let (id, created) = self.lock(|conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            // `OR IGNORE` only has the partial unique index to trip over.
+            let added = tx.execute(
+                "INSERT OR IGNORE INTO _nitr_jobs
+                     (name, payload, run_at, max_attempts, unique_key, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![name, payload, run_at, max_attempts, opts.unique, now],
+            )?;
+            let found = if added > 0 {
+                (tx.last_insert_rowid(), true)
+            } else {
+                let id = tx.query_row(
+                    "SELECT id FROM _nitr_jobs WHERE unique_key = ?1 AND state != 'dead'",
+                    [&opts.unique],
+                    |row| row.get(0),
+                )?;
+                (id, false)
+            };
+            tx.commit()?;
+            Ok(found)
+        })?;
+        if created && opts.delay.is_zero() {
+            self.wake.notify_one();
+        }
+        Ok((id, created))
+    }
+
+    /// Deletes a job that has not started, returning whether there was
+    /// one. A running job is left to finish.
+    pub fn cancel(&self, id: i64) -> Result<bool> {
+        self.lock(|conn| {
+            conn.execute(
+                "DELETE FROM _nitr_jobs WHERE id = ?1 AND state = 'queued'",
+                [id],
+            )
+        })
+        .map(|deleted| deleted > 0)
+    }
+
+    /// Claims up to `limit` due jobs, oldest first, leasing each to the
+    /// caller. Jobs whose lease lapsed with no attempt left are
+    /// dead-lettered instead of claimed.
+    pub async fn claim(&self, limit: usize) -> Result<Vec<Job>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        self.with(move |jobs| {
+            let lease_end = now_ms().saturating_add(jobs.opts.lease.as_millis() as i64);
+            jobs.lock(|conn| {
+                let now = now_ms();
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let lost = tx.execute(
+                    "UPDATE _nitr_jobs SET state = 'dead',
+                         last_error = 'the worker running it stopped before it finished'
+                     WHERE state = 'running' AND run_at <= ?1 AND attempts >= max_attempts",
+                    [now],
+                )?;
+                if lost > 0 {
+                    tracing::warn!(
+                        jobs = lost,
+                        "nitr.jobs dead-lettered jobs whose lease lapsed"
+                    );
+                }
+                let mut claimed = {
+                    let mut stmt = tx.prepare_cached(
+                        "UPDATE _nitr_jobs SET state = 'running', attempts = attempts + 1,
+                             run_at = ?2
+                         WHERE id IN (
+                             SELECT id FROM _nitr_jobs
+                             WHERE state != 'dead' AND run_at <= ?1
+                             ORDER BY run_at, id LIMIT ?3
+                         )
+                         RETURNING id, name, payload, attempts, max_attempts",
+                    )?;
+                    stmt.query_map(params![now, lease_end, limit as i64], |row| {
+                        Ok(Job {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            payload: row.get(2)?,
+                            attempt: row.get(3)?,
+                            max_attempts: row.get(4)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?
+                };
+                tx.commit()?;
+                // `RETURNING` yields rows in no particular order.
+                claimed.sort_by_key(|job| job.id);
+                Ok(claimed)
+            })
+        })
+        .await
+    }
+
+    /// Restarts the lease of a claimed job from now, once its worker has a
+    /// state to run it on, so waiting for one does not eat into the time
+    /// the handler has. `false` means the lease lapsed during the wait and
+    /// the job moved on: this attempt must not run.
+    pub async fn start(&self, job: &Job) -> Result<bool> {
+        let (id, attempt) = (job.id, job.attempt);
+        self.with(move |jobs| {
+            let lease_end = now_ms().saturating_add(jobs.opts.lease.as_millis() as i64);
+            jobs.lock(|conn| {
+                conn.execute(
+                    "UPDATE _nitr_jobs SET run_at = ?3
+                     WHERE id = ?1 AND state = 'running' AND attempts = ?2",
+                    params![id, attempt, lease_end],
+                )
+            })
+        })
+        .await
+        .map(|renewed| renewed > 0)
+    }
+
+    /// Removes a job its handler finished. `false` means the lease lapsed
+    /// first and the job moved on, so it was left alone.
+    pub async fn complete(&self, job: &Job) -> Result<bool> {
+        let (id, attempt) = (job.id, job.attempt);
+        self.with(move |jobs| {
+            jobs.lock(|conn| {
+                conn.execute(
+                    "DELETE FROM _nitr_jobs WHERE id = ?1 AND state = 'running' AND attempts = ?2",
+                    params![id, attempt],
+                )
+            })
+        })
+        .await
+        .map(|deleted| deleted > 0)
+    }
+
+    /// Records a failed attempt: the job is queued again after its
+    /// backoff, or dead-lettered when that was its last attempt.
+    pub async fn fail(&self, job: &Job, error: &str) -> Result<Failed> {
+        let retry_in = (job.attempt < job.max_attempts).then(|| self.backoff(job.attempt));
+        let (id, attempt, error) = (job.id, job.attempt, error.to_owned());
+        let recorded = self
+            .with(move |jobs| {
+                jobs.lock(|conn| match retry_in {
+                    Some(delay) => conn.execute(
+                        "UPDATE _nitr_jobs SET state = 'queued', run_at = ?3, last_error = ?4
+                         WHERE id = ?1 AND state = 'running' AND attempts = ?2",
+                        params![
+                            id,
+                            attempt,
+                            now_ms().saturating_add(delay.as_millis() as i64),
+                            error
+                        ],
+                    ),
+                    None => conn.execute(
+                        "UPDATE _nitr_jobs SET state = 'dead', last_error = ?3
+                         WHERE id = ?1 AND state = 'running' AND attempts = ?2",
+                        params![id, attempt, error],
+                    ),
+                })
+            })
+            .await?;
+        Ok(match retry_in {
+            _ if recorded == 0 => Failed::LeaseLost,
+            Some(delay) => Failed::Retry(delay),
+            None => Failed::Dead,
+        })
+    }
+
+    /// The delay after failed attempt `attempt`: `backoff`, doubling per
+    /// attempt, capped at `max_backoff`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.opts
+            .backoff
+            .saturating_mul(factor)
+            .min(self.opts.max_backoff)
+    }
+
+    /// Waits until a job is enqueued due now in this process, or `poll`
+    /// has passed — jobs that come due later, or are enqueued by another
+    /// process, are found by polling.
+    pub async fn wait(&self, poll: Duration) {
+        let _ = tokio::time::timeout(poll, self.wake.notified()).await;
+    }
+
+    /// Lists jobs, oldest first, optionally only those in `state`.
+    pub fn list(&self, state: Option<JobState>, limit: usize) -> Result<Vec<JobInfo>> {
+        self.lock(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, name, state, attempts, max_attempts, run_at, unique_key,
+                        last_error, created_at
+                 FROM _nitr_jobs WHERE ?1 IS NULL OR state = ?1
+                 ORDER BY id LIMIT ?2",
+            )?;
+            stmt.query_map(params![state.map(JobState::as_str), limit as i64], |row| {
+                let state: String = row.get(2)?;
+                Ok(JobInfo {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    state: state.parse().unwrap_or(JobState::Dead),
+                    attempts: row.get(3)?,
+                    max_attempts: row.get(4)?,
+                    run_at: row.get(5)?,
+                    unique_key: row.get(6)?,
+                    last_error: row.get(7)?,
+                    created_at: row.get(8)?,
+                })
+            })?
+            .collect()
+        })
+    }
+
+    /// Queues dead jobs again, due now and with their attempts reset:
+    /// those in `ids`, or every dead job when `ids` is empty. A job whose
+    /// unique key is held by a live job stays dead. Returns how many were
+    /// queued.
+    pub fn retry(&self, ids: &[i64]) -> Result<usize> {
+        let retried = self.lock(|conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = "UPDATE OR IGNORE _nitr_jobs SET state = 'queued', attempts = 0, run_at = ?1
+                       WHERE state = 'dead' AND (?2 IS NULL OR id = ?2)";
+            let now = now_ms();
+            let mut retried = 0;
+            if ids.is_empty() {
+                retried += tx.execute(sql, params![now, None::<i64>])?;
+            }
+            for id in ids {
+                retried += tx.execute(sql, params![now, id])?;
+            }
+            tx.commit()?;
+            Ok(retried)
+        })?;
+        if retried > 0 {
+            self.wake.notify_one();
+        }
+        Ok(retried)
+    }
+
+    /// Deletes every job in `state`, returning how many. Purging running
+    /// jobs does not stop them; their results are simply not recorded.
+    pub fn purge(&self, state: JobState) -> Result<usize> {
+        self.lock(|conn| conn.execute("DELETE FROM _nitr_jobs WHERE state = ?1", [state.as_str()]))
+    }
+
+    /// The number of jobs in `state`.
+    pub fn count(&self, state: JobState) -> Result<u64> {
+        self.lock(|conn| {
+            conn.query_row(
+                "SELECT count(*) FROM _nitr_jobs WHERE state = ?1",
+                [state.as_str()],
+                |row| row.get::<_, i64>(0).map(|n| n as u64),
+            )
+        })
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// The arguments of `nitr.jobs.enqueue`, checked before touching the table.
+fn enqueue_args(
+    name: &str,
+    payload: &Value,
+    opts: Option<&Table>,
+) -> mlua::Result<(String, Enqueue)> {
+    if name.is_empty() || name.len() > MAX_NAME_BYTES {
+        return Err(mlua::Error::RuntimeError(format!(
+            "nitr.jobs job names are 1 to {MAX_NAME_BYTES} bytes, got {}",
+            name.len()
+        )));
+    }
+    crate::utils::check_json_depth(payload)?;
+    let json = serde_json::to_string(payload).map_err(|err| {
+        mlua::Error::RuntimeError(format!(
+            "nitr.jobs payloads must be plain data (a table, string, number or boolean); \
+             the one for `{name}` is not serializable: {err}"
+        ))
+    })?;
+    if json.len() > MAX_PAYLOAD_BYTES {
+        return Err(mlua::Error::RuntimeError(format!(
+            "nitr.jobs payload for `{name}` is {} bytes serialized, over the \
+             {MAX_PAYLOAD_BYTES} byte limit",
+            json.len()
+        )));
+    }
+    let mut enqueue = Enqueue::default();
+    if let Some(opts) = opts {
+        if let Some(delay) = opts.get::<Option<f64>>("delay")? {
+            if !(delay >= 0.0 && delay.is_finite()) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "nitr.jobs `delay` must be a non-negative number of seconds, got {delay}"
+                )));
+            }
+            enqueue.delay = Duration::from_secs_f64(delay);
+        }
+        if let Some(key) = opts.get::<Option<String>>("unique")? {
+            if key.len() > MAX_UNIQUE_BYTES {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "nitr.jobs unique keys are at most {MAX_UNIQUE_BYTES} bytes, got {}",
+                    key.len()
+                )));
+            }
+            enqueue.unique = Some(key);
+        }
+        if let Some(max) = opts.get::<Option<u32>>("max_attempts")? {
+            if max == 0 {
+                return Err(mlua::Error::RuntimeError(
+                    "nitr.jobs `max_attempts` must be at least 1".into(),
+                ));
+            }
+            enqueue.max_attempts = Some(max);
+        }
+    }
+    Ok((json, enqueue))
+}
+
+fn lua_err(err: Error) -> mlua::Error {
+    mlua::Error::RuntimeError(err.to_string())
+}
+
+/// Builds the `nitr.jobs` table for one state over the shared queue.
+pub(crate) fn create_jobs_table(lua: &Lua, jobs: Jobs) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+
+    // nitr.jobs.enqueue(name, payload?, opts?) -> id, created. opts:
+    // `delay` (seconds), `unique` (a key) and `max_attempts`.
+    let queue = jobs.clone();
+    table.set(
+        "enqueue",
+        lua.create_async_function(
+            move |_, (name, payload, opts): (String, Value, Option<Table>)| {
+                let queue = queue.clone();
+                let args = enqueue_args(&name, &payload, opts.as_ref());
+                async move {
+                    let (json, enqueue) = args?;
+                    queue
+                        .with(move |jobs| jobs.enqueue(&name, &json, &enqueue))
+                        .await
+                        .map_err(lua_err)
+                }
+            },
+        )?,
+    )?;
+
+    // nitr.jobs.cancel(id) -> whether a queued job was removed
+    table.set(
+        "cancel",
+        lua.create_async_function(move |_, id: i64| {
+            let queue = jobs.clone();
+            async move {
+                queue
+                    .with(move |jobs| jobs.cancel(id))
+                    .await
+                    .map_err(lua_err)
+            }
+        })?,
+    )?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(name: &str, opts: JobsOptions) -> Jobs {
+        static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("nitr-jobs-{}-{id}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        Jobs::open(&dir.join(name), &SqlitePragmas::default(), opts).expect("open")
+    }
+
+    #[tokio::test]
+    async fn jobs_are_claimed_once_and_in_order() {
+        let jobs = open("order.db", JobsOptions::default());
+        let lua = Lua::new();
+        lua.globals()
+            .set("jobs", create_jobs_table(&lua, jobs.clone()).expect("jobs"))
+            .expect("global");
+        let (first, second, later, cancelled): (i64, i64, i64, bool) = lua
+            .load(
+                r#"
+                local first = jobs.enqueue("mail", { to = "ada@example.com" })
+                local second = jobs.enqueue("resize", "cat.png")
+                local later = jobs.enqueue("mail", nil, { delay = 60 })
+                return first, second, later, jobs.cancel(later)
+                "#,
+            )
+            .eval_async()
+            .await
+            .expect("enqueue");
+        assert!(first < second && second < later);
+        assert!(cancelled);
+
+        let claimed = jobs.claim(10).await.expect("claim");
+        let names: Vec<_> = claimed.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, ["mail", "resize"]);
+        assert_eq!(claimed[0].payload, r#"{"to":"ada@example.com"}"#);
+        assert_eq!(claimed[0].attempt, 1);
+        assert!(jobs.claim(10).await.expect("claim").is_empty(), "leased");
+        assert!(!jobs.cancel(first).expect("cancel"), "a running job stays");
+
+        assert!(jobs.complete(&claimed[0]).await.expect("complete"));
+        assert_eq!(jobs.count(JobState::Running).expect("count"), 1);
+    }
+
+    #[tokio::test]
+    async fn failures_back_off_then_dead_letter() {
+        let jobs = open(
+            "retry.db",
+            JobsOptions {
+                backoff: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+        let (id, _) = jobs
+            .enqueue(
+                "flaky",
+                "null",
+                &Enqueue {
+                    max_attempts: Some(2),
+                    ..Default::default()
+                },
+            )
+            .expect("enqueue");
+        let job = jobs.claim(1).await.expect("claim").remove(0);
+        assert_eq!(
+            jobs.fail(&job, "boom").await.expect("fail"),
+            Failed::Retry(Duration::ZERO)
+        );
+        let job = jobs.claim(1).await.expect("claim").remove(0);
+        assert_eq!((job.id, job.attempt), (id, 2));
+        assert_eq!(
+            jobs.fail(&job, "boom again").await.expect("fail"),
+            Failed::Dead
+        );
+        assert!(jobs.claim(1).await.expect("claim").is_empty());
+
+        let dead = jobs.list(Some(JobState::Dead), 10).expect("list");
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].last_error.as_deref(), Some("boom again"));
+
+        assert_eq!(jobs.retry(&[]).expect("retry"), 1);
+        let job = jobs.claim(1).await.expect("claim").remove(0);
+        assert_eq!(job.attempt, 1, "a retried job starts over");
+        jobs.fail(&job, "boom").await.expect("fail");
+        let job = jobs.claim(1).await.expect("claim").remove(0);
+        jobs.fail(&job, "boom").await.expect("fail");
+        assert_eq!(jobs.purge(JobState::Dead).expect("purge"), 1);
+        assert!(jobs.list(None, 10).expect("list").is_empty());
+    }
+
+    /// An attempt that outlived its lease finds the job claimed again: it
+    /// may neither start nor record an outcome over the new attempt.
+    #[tokio::test]
+    async fn a_lapsed_lease_hands_the_job_to_the_next_attempt() {
+        let jobs = open(
+            "lease.db",
+            JobsOptions {
+                lease: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+        let (id, _) = jobs
+            .enqueue("slow", "null", &Enqueue::default())
+            .expect("enqueue");
+        let stale = jobs.claim(1).await.expect("claim").remove(0);
+        let current = jobs.claim(1).await.expect("lapsed").remove(0);
+        assert_eq!((current.id, current.attempt), (id, 2));
+
+        assert!(!jobs.start(&stale).await.expect("start"));
+        assert!(!jobs.complete(&stale).await.expect("complete"));
+        assert_eq!(
+            jobs.fail(&stale, "late").await.expect("fail"),
+            Failed::LeaseLost
+        );
+        let running = jobs.list(Some(JobState::Running), 10).expect("list");
+        assert_eq!((running.len(), running[0].attempts), (1, 2));
+        assert_eq!(running[0].last_error, None);
+
+        assert!(jobs.start(&current).await.expect("start"));
+        assert!(jobs.complete(&current).await.expect("complete"));
+        assert!(jobs.list(None, 10).expect("list").is_empty());
+    }
+
+    #[tokio::test]
+    async fn unique_keys_hold_while_the_job_is_live() {
+        let jobs = open(
+            "unique.db",
+            JobsOptions {
+                lease: Duration::ZERO,
+                max_attempts: 1,
+                ..Default::default()
+            },
+        );
+        let unique = Enqueue {
+            unique: Some("welcome:42".into()),
+            ..Default::default()
+        };
+        let (id, created) = jobs.enqueue("welcome", "42", &unique).expect("enqueue");
+        assert!(created);
+        assert_eq!(
+            jobs.enqueue("welcome", "42", &unique).expect("again"),
+            (id, false)
+        );
+
+        // A lease that lapsed on the last attempt dead-letters the job,
+        // which frees its key.
+        assert_eq!(jobs.claim(1).await.expect("claim").len(), 1);
+        assert!(jobs.claim(1).await.expect("claim").is_empty());
+        let dead = jobs.list(Some(JobState::Dead), 10).expect("list");
+        assert!(
+            dead[0]
+                .last_error
+                .as_deref()
+                .is_some_and(|e| e.contains("stopped"))
+        );
+        let (fresh, created) = jobs.enqueue("welcome", "42", &unique).expect("fresh");
+        assert!(created && fresh != id);
+        assert_eq!(jobs.retry(&[id]).expect("retry"), 0, "the key is taken");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let jobs = open(
+            "backoff.db",
+            JobsOptions {
+                backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(5),
+                ..Default::default()
+            },
+        );
+        let delays: Vec<_> = (1..=5).map(|n| jobs.backoff(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        assert_eq!(jobs.backoff(200), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn enqueue_arguments_are_checked() {
+        let lua = Lua::new();
+        assert!(enqueue_args("", &Value::Nil, None).is_err());
+        let f = Value::Function(lua.create_function(|_, ()| Ok(())).expect("fn"));
+        assert!(enqueue_args("job", &f, None).is_err());
+        let opts = lua.create_table().expect("table");
+        opts.set("delay", -1).expect("set");
+        let err = enqueue_args("job", &Value::Nil, Some(&opts)).expect_err("delay");
+        assert!(err.to_string().contains("non-negative"), "got: {err}");
+        assert!("paused".parse::<JobState>().is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Key files named by scripts: `jwt.keyset({ file = path })` and the
+//! `{ file = path }` source of `crypto.private_key` / `public_key`.
+//!
+//! A path is read only from inside the app directory, the way `require`
+//! is confined to it: it must be relative, made of plain components (no
+//! `..`), and its canonicalized target must stay inside the canonicalized
+//! directory — so a symlink cannot lead out either. A script, or a request
+//! value that reaches one, cannot read `/etc/shadow` or `../.env`.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use mlua::{Function, Lua, Value};
+use p256::elliptic_curve::zeroize::Zeroizing;
+
+/// Where key files may be read from, shared by every function that takes
+/// a `{ file = path }`.
+#[derive(Clone)]
+pub(crate) struct KeyFiles {
+    dir: Arc<Path>,
+    /// `coroutine.running`, captured before any script runs so a script
+    /// cannot shadow it. `None` when the `coroutine` library is left out.
+    running: Option<Function>,
+}
+
+impl KeyFiles {
+    /// Confines reads to `dir`, or to the working directory without one.
+    pub(crate) fn new(lua: &Lua, dir: Option<&Path>) -> mlua::Result<Self> {
+        let running = match lua.globals().get::<Value>("coroutine")? {
+            Value::Table(coroutine) => coroutine.get::<Option<Function>>("running")?,
+            _ => None,
+        };
+        Ok(Self {
+            dir: dir.unwrap_or_else(|| Path::new(".")).into(),
+            running,
+        })
+    }
+
+    /// Reads `file` on the calling thread, or says why it may not be read:
+    /// for functions that run while the app loads.
+    pub(crate) fn read(&self, file: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+        read_confined(&self.dir, file)
+    }
+
+    /// Reads `file` on the blocking pool when called from a handler, so a
+    /// slow disk does not stall the worker. At the top level of the
+    /// handler script, which cannot yield, it reads inline instead: that
+    /// runs once, while the app loads.
+    pub(crate) async fn read_async(
+        &self,
+        file: String,
+    ) -> mlua::Result<Result<Zeroizing<Vec<u8>>, String>> {
+        if !self.can_yield()? {
+            return Ok(self.read(&file));
+        }
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || read_confined(&dir, &file))
+            .await
+            .map_err(mlua::Error::external)
+    }
+
+    /// Whether the running code is inside a coroutine. Without the
+    /// `coroutine` library there is no telling, so it reads inline.
+    fn can_yield(&self) -> mlua::Result<bool> {
+        match &self.running {
+            Some(running) => {
+                let (_, main): (Value, bool) = running.call(())?;
+                Ok(!main)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Resolves `file` under `dir` and reads it, refusing any path that is
+/// absolute, climbs out with `..`, or links out of `dir`.
+fn read_confined(dir: &Path, file: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    let outside = || format!("`{file}` is outside the app directory");
+    let mut path = PathBuf::from(dir);
+    for component in Path::new(file).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(outside());
+            }
+        }
+    }
+    if path.as_path() == dir {
+        return Err("`file` must name a file".into());
+    }
+    let cannot_read = |err: std::io::Error| format!("cannot read `{file}`: {err}");
+    let root = dir.canonicalize().map_err(cannot_read)?;
+    let target = path.canonicalize().map_err(cannot_read)?;
+    if !target.starts_with(&root) {
+        return Err(outside());
+    }
+    std::fs::read(&target)
+        .map(Zeroizing::new)
+        .map_err(cannot_read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nitr-keyfile-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn only_files_inside_the_directory_are_read() {
+        let tmp = temp_dir("confined");
+        let app = tmp.join("app");
+        std::fs::create_dir_all(app.join("keys")).expect("mkdir");
+        std::fs::write(app.join("keys/signing.pem"), "key").expect("write");
+        std::fs::write(tmp.join("secret"), "secret").expect("write");
+
+        for ok in ["keys/signing.pem", "./keys/signing.pem"] {
+            let bytes = read_confined(&app, ok).expect(ok);
+            assert_eq!(bytes.as_slice(), b"key");
+        }
+
+        let absolute = tmp.join("secret");
+        for escape in [
+            "../secret",
+            "keys/../../secret",
+            absolute.to_str().expect("utf-8"),
+        ] {
+            let err = read_confined(&app, escape).expect_err(escape);
+            assert!(err.contains("outside the app directory"), "{escape}: {err}");
+        }
+        assert!(read_confined(&app, "").is_err());
+        assert!(read_confined(&app, "missing.pem").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_cannot_lead_out() {
+        let tmp = temp_dir("symlink");
+        let app = tmp.join("app");
+        std::fs::create_dir_all(&app).expect("mkdir");
+        std::fs::write(tmp.join("secret"), "secret").expect("write");
+        std::os::unix::fs::symlink(tmp.join("secret"), app.join("link.pem")).expect("symlink");
+
+        let err = read_confined(&app, "link.pem").expect_err("symlink");
+        assert!(err.contains("outside the app directory"), "{err}");
+    }
+}
@@ -0,0 +1,570 @@
+//! Key rings: the versioned keys of `[keys]`, exposed to scripts as
+//! `nitr.keys` and accepted wherever a secret string is — signed cookies,
+//! `nitr.session`, `nitr.csrf` — and as the key of `nitr.crypto.seal`.
+//!
+//! Signing and sealing use the newest key and prefix its id to the result
+//! (`3.<token>`); verifying and opening look the id up, so a token made
+//! under any key still in the ring stays valid. Rotating is adding a key
+//! (`nitr keys rotate`), reloading, and deleting the old key once the
+//! longest-lived token it signed has expired.
+//!
+//! A key is at least 32 random bytes, base64url-encoded: one per file in
+//! the `[keys] dir` (`3.key`, the file name being its id), or a list of
+//! `id:key` pairs in the variable `[keys] env` names. A master key is never
+//! used as is: the signing and sealing keys are derived from it with
+//! HMAC-SHA256 under fixed labels, so no key serves two algorithms.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use hmac::{Hmac, Mac as _};
+use mlua::{FromLua, Lua, MetaMethod, Table, UserData, UserDataFields, UserDataMethods, Value};
+use sha2::Sha256;
+
+use crate::http;
+use nitr_core::{Error, Result};
+
+/// Shortest master key accepted: a 256-bit key, like the seal key.
+const MIN_KEY_BYTES: usize = 32;
+
+/// Where a ring's keys come from; kept so a reload re-reads the same place.
+#[derive(Debug, Clone, Default)]
+pub struct KeySource {
+    /// Directory of `<id>.key` files.
+    pub dir: Option<PathBuf>,
+    /// Environment variable holding `id:key` pairs, comma-separated.
+    pub env: Option<String>,
+}
+
+/// One key of the ring, as the keys derived from its master key.
+struct Key {
+    id: u64,
+    sign: [u8; 32],
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    seal: [u8; 32],
+}
+
+impl Key {
+    fn derive(id: u64, master: &[u8]) -> Self {
+        let derive = |label: &[u8]| -> [u8; 32] {
+            let mut mac: Hmac<Sha256> = crate::utils::new_hmac(master);
+            mac.update(label);
+            mac.finalize().into_bytes().into()
+        };
+        Self {
+            id,
+            sign: derive(b"nitr.keys.sign"),
+            seal: derive(b"nitr.keys.seal"),
+        }
+    }
+}
+
+/// The shared key ring. Cloning shares the keys, so a
+/// [`reload()`](Self::reload) reaches every state at once; the server
+/// loads one and hands it to every state.
+#[derive(Clone)]
+pub struct KeyRing {
+    source: Arc<KeySource>,
+    /// Never empty; newest (highest id) first.
+    keys: Arc<RwLock<Vec<Key>>>,
+}
+
+impl std::fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyRing")
+            .field("ids", &self.ids())
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyRing {
+    /// Reads the keys of `source`; a source holding none is an error.
+    pub fn load(source: KeySource) -> Result<Self> {
+        let keys = read_keys(&source)?;
+        Ok(Self {
+            source: Arc::new(source),
+            keys: Arc::new(RwLock::new(keys)),
+        })
+    }
+
+    /// Re-reads the source and swaps the new keys in, returning how many
+    /// there are. On any error the current keys stay.
+    pub fn reload(&self) -> Result<usize> {
+        let keys = read_keys(&self.source)?;
+        let count = keys.len();
+        // The keys are replaced in one assignment, so a poisoned lock
+        // still guards a whole ring.
+        *self.keys.write().unwrap_or_else(PoisonError::into_inner) = keys;
+        Ok(count)
+    }
+
+    /// The key ids, newest first.
+    pub fn ids(&self) -> Vec<u64> {
+        self.read(|keys| keys.iter().map(|key| key.id).collect())
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&[Key]) -> T) -> T {
+        f(&self.keys.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Runs `f` on the newest key.
+    fn newest<T>(&self, f: impl FnOnce(&Key) -> T) -> T {
+        // Invariant: `read_keys` never returns an empty ring.
+        #[allow(clippy::indexing_slicing)]
+        self.read(|keys| f(&keys[0]))
+    }
+
+    /// Runs `f` on the key whose id prefixes `token`, with the rest of it.
+    fn lookup<T>(&self, token: &str, f: impl FnOnce(&Key, &str) -> Option<T>) -> Option<T> {
+        let (id, rest) = token.split_once('.')?;
+        let id = parse_id(id)?;
+        self.read(|keys| f(keys.iter().find(|key| key.id == id)?, rest))
+    }
+
+    /// [`http::sign()`] under the newest key, prefixed with its id.
+    pub(crate) fn sign(&self, name: &str, value: &str) -> String {
+        self.newest(|key| format!("{}.{}", key.id, http::sign_with_key(name, value, &key.sign)))
+    }
+
+    /// Verifies a value from [`sign()`](Self::sign) under the key it names.
+    pub(crate) fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        self.lookup(signed, |key, rest| {
+            http::verify_with_key(name, rest, &key.sign)
+        })
+    }
+
+    /// The newest key's id and sealing key.
+    #[cfg(feature = "crypto")]
+    pub(crate) fn seal_key(&self) -> (u64, [u8; 32]) {
+        self.newest(|key| (key.id, key.seal))
+    }
+
+    /// The sealing key named by the id prefixing `sealed`, with the rest
+    /// of the token.
+    #[cfg(feature = "crypto")]
+    pub(crate) fn open_key<'a>(&self, sealed: &'a str) -> Option<([u8; 32], &'a str)> {
+        let (id, rest) = sealed.split_once('.')?;
+        let id = parse_id(id)?;
+        self.read(|keys| {
+            keys.iter()
+                .find(|key| key.id == id)
+                .map(|key| (key.seal, rest))
+        })
+    }
+}
+
+/// A key id: decimal digits only, so one token has one spelling.
+fn parse_id(text: &str) -> Option<u64> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// `nitr.keys`: the ring itself, passed where a secret goes. Scripts see
+/// the ids, never the key material.
+impl UserData for KeyRing {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("current", |_, ring| Ok(ring.newest(|key| key.id)));
+        fields.add_field_method_get("ids", |_, ring| Ok(ring.ids()));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::ToString, |_, ring, ()| {
+            let ids = ring.ids();
+            Ok(format!(
+                "nitr.keys (current {}, {} key{})",
+                ids.first().copied().unwrap_or_default(),
+                ids.len(),
+                if ids.len() == 1 { "" } else { "s" }
+            ))
+        });
+    }
+}
+
+/// A signing secret as scripts pass it: a string, or the `nitr.keys`
+/// ring.
+pub(crate) enum Secret {
+    Plain(Vec<u8>),
+    Ring(KeyRing),
+}
+
+impl FromLua for Secret {
+    fn from_lua(value: Value, _: &Lua) -> mlua::Result<Self> {
+        match value {
+            Value::String(secret) => Ok(Self::Plain(secret.as_bytes().to_vec())),
+            Value::UserData(ud) if ud.is::<KeyRing>() => {
+                Ok(Self::Ring(ud.borrow::<KeyRing>()?.clone()))
+            }
+            other => Err(mlua::Error::RuntimeError(format!(
+                "expected a secret string or nitr.keys, got {}",
+                other.type_name()
+            ))),
+        }
+    }
+}
+
+impl Secret {
+    /// The `secret` option of `what`'s options table, which must be there.
+    /// A string must be at least 16 bytes; a ring's keys always are.
+    pub(crate) fn from_opts(opts: &Table, what: &str) -> mlua::Result<Self> {
+        let secret = opts.get::<Option<Self>>("secret")?.ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("{what} requires a `secret` option"))
+        })?;
+        if let Self::Plain(bytes) = &secret
+            && bytes.len() < 16
+        {
+            return Err(mlua::Error::RuntimeError(format!(
+                "{what} `secret` must be at least 16 bytes"
+            )));
+        }
+        Ok(secret)
+    }
+
+    pub(crate) fn sign(&self, name: &str, value: &str) -> String {
+        match self {
+            Self::Plain(secret) => http::sign_with_key(name, value, secret),
+            Self::Ring(ring) => ring.sign(name, value),
+        }
+    }
+
+    pub(crate) fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        match self {
+            Self::Plain(secret) => http::verify_with_key(name, signed, secret),
+            Self::Ring(ring) => ring.verify(name, signed),
+        }
+    }
+}
+
+/// Reads every key of `source`, newest first.
+fn read_keys(source: &KeySource) -> Result<Vec<Key>> {
+    let mut keys = Vec::new();
+    if let Some(dir) = &source.dir {
+        for (id, path) in key_files(dir)? {
+            let text = std::fs::read_to_string(&path).map_err(|err| {
+                Error::Config(format!(
+                    "cannot read the key file {}: {err}",
+                    path.display()
+                ))
+            })?;
+            keys.push(Key::derive(
+                id,
+                &decode_key(&text, &path.display().to_string())?,
+            ));
+        }
+    }
+    if let Some(var) = &source.env {
+        let value = std::env::var(var).map_err(|_| {
+            Error::Config(format!(
+                "`[keys] env` names the variable {var}, which is not set"
+            ))
+        })?;
+        keys.extend(env_keys(var, &value)?);
+    }
+    keys.sort_by_key(|key| std::cmp::Reverse(key.id));
+    if let Some(pair) = keys.windows(2).find(|pair| pair[0].id == pair[1].id) {
+        return Err(Error::Config(format!(
+            "key id {} appears twice in [keys]",
+            pair[0].id
+        )));
+    }
+    if keys.is_empty() {
+        return Err(Error::Config(
+            "[keys] is configured but holds no keys: create one with `nitr keys rotate`".into(),
+        ));
+    }
+    Ok(keys)
+}
+
+/// Parses the `id:key` pairs of the variable `var`.
+fn env_keys(var: &str, value: &str) -> Result<Vec<Key>> {
+    let mut keys = Vec::new();
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (id, text) = pair
+            .split_once(':')
+            .and_then(|(id, text)| Some((parse_id(id.trim())?, text)))
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "{var} must list `id:key` pairs separated by commas, with numeric ids"
+                ))
+            })?;
+        keys.push(Key::derive(
+            id,
+            &decode_key(text, &format!("{var} key {id}"))?,
+        ));
+    }
+    Ok(keys)
+}
+
+/// The `<id>.key` files of `dir`, by id; other files are left alone.
+fn key_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let entries = std::fs::read_dir(dir).map_err(|err| {
+        Error::Config(format!(
+            "cannot read the [keys] dir {}: {err}",
+            dir.display()
+        ))
+    })?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| Error::Config(format!("cannot list {}: {err}", dir.display())))?
+            .path();
+        if path.extension().is_none_or(|ext| ext != "key") {
+            continue;
+        }
+        let id = path
+            .file_stem()
+            .and_then(|stem| parse_id(&stem.to_string_lossy()))
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "the key file {} must be named after its numeric id, like `1.key`",
+                    path.display()
+                ))
+            })?;
+        files.push((id, path));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Decodes one base64url master key; `origin` names it in errors.
+fn decode_key(text: &str, origin: &str) -> Result<Vec<u8>> {
+    let key = B64
+        .decode(text.trim().trim_end_matches('='))
+        .map_err(|_| Error::Config(format!("{origin} is not a base64url key")))?;
+    if key.len() < MIN_KEY_BYTES {
+        return Err(Error::Config(format!(
+            "{origin} holds {} bytes; keys must be at least {MIN_KEY_BYTES} random bytes",
+            key.len()
+        )));
+    }
+    Ok(key)
+}
+
+/// Writes a fresh random key to `dir` as the newest, creating the
+/// directory if needed, and returns its id. The file is readable by its
+/// owner only.
+///
+/// The id follows the newest of the whole ring: with `env` naming the
+/// `[keys] env` variable, its ids count too, so the new key never takes
+/// an id the variable already holds (which would stop the ring loading).
+/// The variable must then be set where this runs.
+pub fn rotate(dir: &Path, env: Option<&str>) -> Result<u64> {
+    let taken = match env {
+        Some(var) => {
+            let value = std::env::var(var).map_err(|_| {
+                Error::Config(format!(
+                    "`[keys] env` names the variable {var}, which is not set: without \
+                     its ids, the new key could take one of them"
+                ))
+            })?;
+            env_keys(var, &value)?.iter().map(|key| key.id).collect()
+        }
+        None => Vec::new(),
+    };
+    write_next(dir, &taken)
+}
+
+/// Writes a key to `dir` under the id after the newest of its files and
+/// of `taken`.
+fn write_next(dir: &Path, taken: &[u64]) -> Result<u64> {
+    std::fs::create_dir_all(dir).map_err(|err| {
+        Error::Config(format!(
+            "cannot create the [keys] dir {}: {err}",
+            dir.display()
+        ))
+    })?;
+    let newest = key_files(dir)?
+        .last()
+        .map(|(id, _)| *id)
+        .into_iter()
+        .chain(taken.iter().copied())
+        .max()
+        .unwrap_or(0);
+    let id = newest + 1;
+    let mut key = [0u8; MIN_KEY_BYTES];
+    getrandom::getrandom(&mut key)
+        .map_err(|err| Error::Config(format!("failed to read OS entropy: {err}")))?;
+    let path = dir.join(format!("{id}.key"));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = |options: &std::fs::OpenOptions| -> std::io::Result<()> {
+        use std::io::Write as _;
+        options
+            .open(&path)?
+            .write_all(format!("{}\n", B64.encode(key)).as_bytes())
+    };
+    write(&options).map_err(|err| {
+        Error::Config(format!(
+            "cannot write the key file {}: {err}",
+            path.display()
+        ))
+    })?;
+    Ok(id)
+}
+
+/// Deletes all but the `keep` newest key files of `dir`, returning the
+/// ids removed. At least one key always stays.
+pub fn retire(dir: &Path, keep: usize) -> Result<Vec<u64>> {
+    let files = key_files(dir)?;
+    let cut = files.len().saturating_sub(keep.max(1));
+    let mut retired = Vec::with_capacity(cut);
+    for (id, path) in files.into_iter().take(cut) {
+        std::fs::remove_file(&path).map_err(|err| {
+            Error::Config(format!(
+                "cannot delete the key file {}: {err}",
+                path.display()
+            ))
+        })?;
+        retired.push(id);
+    }
+    Ok(retired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nitr-keys-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn rotation_signs_with_the_newest_key_and_verifies_with_any() {
+        let dir = temp_dir("rotate");
+        assert_eq!(rotate(&dir, None).expect("first key"), 1);
+        let ring = KeyRing::load(KeySource {
+            dir: Some(dir.clone()),
+            env: None,
+        })
+        .expect("ring");
+        let old = ring.sign("sid", "user-1");
+        assert!(old.starts_with("1."), "got {old}");
+
+        assert_eq!(rotate(&dir, None).expect("second key"), 2);
+        assert_eq!(ring.reload().expect("reload"), 2);
+        assert_eq!(ring.ids(), [2, 1]);
+        let new = ring.sign("sid", "user-1");
+        assert!(new.starts_with("2."), "got {new}");
+        for token in [&old, &new] {
+            assert_eq!(ring.verify("sid", token).as_deref(), Some("user-1"));
+        }
+
+        // Retiring key 1 ends the tokens it signed.
+        assert_eq!(retire(&dir, 1).expect("retire"), [1]);
+        ring.reload().expect("reload");
+        assert_eq!(ring.verify("sid", &old), None);
+        assert_eq!(ring.verify("sid", &new).as_deref(), Some("user-1"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rotation_skips_the_ids_of_the_env_keys() {
+        let dir = temp_dir("rotate-env");
+        let master = B64.encode([5u8; 32]);
+        let env = env_keys("APP_KEYS", &format!("1:{master},4:{master}")).expect("keys");
+        let taken: Vec<u64> = env.iter().map(|key| key.id).collect();
+        assert_eq!(write_next(&dir, &taken).expect("after env"), 5);
+        assert_eq!(write_next(&dir, &[2]).expect("after dir"), 6);
+
+        let err = rotate(&dir, Some("NITR_TEST_KEYS_UNSET")).expect_err("unset variable");
+        assert!(err.to_string().contains("not set"), "{err}");
+        assert_eq!(key_files(&dir).expect("files").len(), 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn ring_tokens_reject_tampering_and_plain_secrets() {
+        let master = B64.encode([7u8; 32]);
+        let ring = KeyRing {
+            source: Arc::new(KeySource::default()),
+            keys: Arc::new(RwLock::new(
+                env_keys("APP_KEYS", &format!("1:{master}")).expect("keys"),
+            )),
+        };
+        let signed = ring.sign("sid", "v");
+        let (_, rest) = signed.split_once('.').expect("id prefix");
+
+        for bad in [
+            rest.to_string(),
+            format!("2.{rest}"),
+            format!("+1.{rest}"),
+            format!("{signed}x"),
+        ] {
+            assert_eq!(ring.verify("sid", &bad), None, "{bad}");
+        }
+        assert_eq!(ring.verify("other", &signed), None);
+        // The derived key is not the master key used as a plain secret.
+        assert_eq!(http::verify_with_key("sid", rest, &[7u8; 32]), None);
+    }
+
+    #[test]
+    fn bad_key_sources_fail_with_a_reason() {
+        let dir = temp_dir("bad");
+        std::fs::create_dir_all(&dir).expect("dir");
+        let load = || {
+            KeyRing::load(KeySource {
+                dir: Some(dir.clone()),
+                env: None,
+            })
+            .expect_err("bad ring")
+            .to_string()
+        };
+        assert!(load().contains("nitr keys rotate"), "empty dir");
+
+        std::fs::write(dir.join("1.key"), B64.encode([1u8; 16])).expect("write");
+        assert!(load().contains("at least 32"), "short key");
+
+        std::fs::write(dir.join("1.key"), "not base64!").expect("write");
+        assert!(load().contains("base64url"), "garbage key");
+
+        std::fs::remove_file(dir.join("1.key")).expect("remove");
+        std::fs::write(dir.join("current.key"), B64.encode([1u8; 32])).expect("write");
+        assert!(load().contains("numeric id"), "bad name");
+        std::fs::remove_dir_all(&dir).ok();
+
+        let err = env_keys("APP_KEYS", "one:abc").err().expect("bad id");
+        assert!(err.to_string().contains("id:key"), "{err}");
+        let missing = KeyRing::load(KeySource {
+            dir: None,
+            env: Some("NITR_TEST_KEYS_UNSET".into()),
+        })
+        .expect_err("unset variable");
+        assert!(missing.to_string().contains("not set"), "{missing}");
+    }
+
+    #[test]
+    fn lua_sees_ids_but_never_key_material() {
+        let dir = temp_dir("lua");
+        rotate(&dir, None).expect("key");
+        rotate(&dir, None).expect("key");
+        let ring = KeyRing::load(KeySource {
+            dir: Some(dir.clone()),
+            env: None,
+        })
+        .expect("ring");
+        let lua = Lua::new();
+        lua.globals().set("keys", ring).expect("global");
+        let (current, ids, text): (u64, Vec<u64>, String) = lua
+            .load("return keys.current, keys.ids, tostring(keys)")
+            .eval()
+            .expect("fields");
+        assert_eq!((current, ids), (2, vec![2, 1]));
+        assert_eq!(text, "nitr.keys (current 2, 2 keys)");
+
+        let secret: Secret = lua.load("return keys").eval().expect("ring secret");
+        assert!(matches!(secret, Secret::Ring(_)));
+        let table = Value::Table(lua.create_table().expect("table"));
+        let Err(err) = Secret::from_lua(table, &lua) else {
+            panic!("a table is no secret");
+        };
+        assert!(err.to_string().contains("nitr.keys"), "{err}");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
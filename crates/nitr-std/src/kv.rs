@@ -0,0 +1,576 @@
+//! `nitr.kv`: a durable key-value store in a Nitr-owned SQLite table.
+//!
+//! Where `nitr.cache` forgets everything on a restart, `nitr.kv` is for
+//! small state that must survive one: feature toggles, idempotency keys,
+//! login-attempt counters. The application defines no schema — the
+//! `_nitr_kv` table is created when the store opens — and values are plain
+//! data, serialized on the way in like cache values.
+//!
+//! Every state shares one connection. `incr` and `cas` run in an
+//! immediate transaction, so they stay atomic against a second Nitr
+//! process on the same file, not only against the other states. Expired
+//! keys read as absent at once and are deleted by a background sweep.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mlua::{Lua, LuaSerdeExt as _, Table, UserData, UserDataMethods, Value};
+use rusqlite::{Connection, OptionalExtension as _, TransactionBehavior, params};
+
+use crate::SqlitePragmas;
+use nitr_core::{Error, Result};
+
+/// Ceiling on a key; keys are for lookups, not for data.
+const MAX_KEY_BYTES: usize = 1024;
+
+/// Ceiling on one serialized value.
+const MAX_VALUE_BYTES: usize = 1024 * 1024;
+
+/// Entries `scan` returns when `limit` is not given, and the most it ever
+/// returns: a prefix can match the whole table.
+const SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS _nitr_kv (
+        key        TEXT PRIMARY KEY,
+        value      TEXT NOT NULL,
+        expires_at INTEGER
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS _nitr_kv_expires ON _nitr_kv (expires_at)
+        WHERE expires_at IS NOT NULL;
+";
+
+/// The condition selecting keys that have not expired, `?1` being now.
+const LIVE: &str = "(expires_at IS NULL OR expires_at > ?1)";
+
+/// The shared store. Cloning shares the connection; the server opens one
+/// and hands it to every state.
+#[derive(Clone)]
+pub struct Kv {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl std::fmt::Debug for Kv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Kv").finish_non_exhaustive()
+    }
+}
+
+impl Kv {
+    /// Opens the store in the SQLite file at `path`, creating its table,
+    /// and starts deleting expired keys every `sweep_every` (never when
+    /// zero). The sweep stops once the last clone is dropped.
+    pub fn open(path: &Path, pragmas: &SqlitePragmas, sweep_every: Duration) -> Result<Self> {
+        let conn = crate::db::pragmas::open(path, pragmas)?;
+        conn.execute_batch(SCHEMA).map_err(|err| {
+            Error::Config(format!(
+                "cannot create the nitr.kv table in {}: {err}",
+                path.display()
+            ))
+        })?;
+        let conn = Arc::new(Mutex::new(conn));
+        if !sweep_every.is_zero() {
+            let weak = Arc::downgrade(&conn);
+            std::thread::Builder::new()
+                .name("nitr-kv-sweep".into())
+                .spawn(move || sweep_loop(&weak, sweep_every))
+                .map_err(|err| Error::Config(format!("cannot start the nitr.kv sweep: {err}")))?;
+        }
+        Ok(Self { conn })
+    }
+
+    /// Runs `f` on the connection, off the async threads.
+    async fn with<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> std::result::Result<T, Failure> + Send + 'static,
+    ) -> mlua::Result<T> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| mlua::Error::RuntimeError("the nitr.kv lock is poisoned".into()))?;
+            f(&mut conn).map_err(|err| match err {
+                Failure::Sql(err) => mlua::Error::RuntimeError(format!("nitr.kv: {err}")),
+                Failure::Value(message) => mlua::Error::RuntimeError(message),
+            })
+        })
+        .await
+        .map_err(|err| mlua::Error::RuntimeError(format!("nitr.kv task failed: {err}")))?
+    }
+}
+
+/// Why an operation failed: SQLite, or the stored value itself.
+enum Failure {
+    Sql(rusqlite::Error),
+    Value(String),
+}
+
+impl From<rusqlite::Error> for Failure {
+    fn from(err: rusqlite::Error) -> Self {
+        Failure::Sql(err)
+    }
+}
+
+fn sweep_loop(conn: &Weak<Mutex<Connection>>, every: Duration) {
+    loop {
+        std::thread::sleep(every);
+        let Some(conn) = conn.upgrade() else {
+            return;
+        };
+        let Ok(conn) = conn.lock() else {
+            return;
+        };
+        match conn.execute("DELETE FROM _nitr_kv WHERE expires_at <= ?1", [now_ms()]) {
+            Ok(0) => {}
+            Ok(swept) => tracing::debug!(swept, "nitr.kv swept expired keys"),
+            Err(err) => tracing::warn!("nitr.kv sweep failed: {err}"),
+        }
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+fn check_key(key: &str) -> mlua::Result<()> {
+    if key.len() > MAX_KEY_BYTES {
+        return Err(mlua::Error::RuntimeError(format!(
+            "nitr.kv keys are at most {MAX_KEY_BYTES} bytes, got {}",
+            key.len()
+        )));
+    }
+    Ok(())
+}
+
+/// A value as stored: JSON with sorted object keys, so `cas` compares
+/// equal tables as equal whatever order Lua iterates them in.
+fn encode(key: &str, value: &Value) -> mlua::Result<String> {
+    if value.is_nil() {
+        return Err(mlua::Error::RuntimeError(format!(
+            "nitr.kv cannot store nil under `{key}`: use kv:delete"
+        )));
+    }
+    crate::utils::check_json_depth(value)?;
+    let json = serde_json::to_value(value)
+        .map(|json| json.to_string())
+        .map_err(|err| {
+            mlua::Error::RuntimeError(format!(
+                "nitr.kv values must be plain data (a table, string, number or boolean); \
+                 `{key}` is not serializable: {err}"
+            ))
+        })?;
+    if json.len() > MAX_VALUE_BYTES {
+        return Err(mlua::Error::RuntimeError(format!(
+            "nitr.kv value for `{key}` is {} bytes serialized, over the {MAX_VALUE_BYTES} \
+             byte limit",
+            json.len()
+        )));
+    }
+    Ok(json)
+}
+
+fn decode(lua: &Lua, json: &str) -> mlua::Result<Value> {
+    let json: serde_json::Value = serde_json::from_str(json)
+        .map_err(|err| mlua::Error::RuntimeError(format!("nitr.kv: corrupt value: {err}")))?;
+    lua.to_value(&json)
+}
+
+/// The `ttl` of an options table, as an absolute expiry in milliseconds.
+fn expiry(opts: Option<&Table>) -> mlua::Result<Option<i64>> {
+    let Some(opts) = opts else {
+        return Ok(None);
+    };
+    ttl_expiry(opts.get("ttl")?)
+}
+
+fn ttl_expiry(ttl: Option<f64>) -> mlua::Result<Option<i64>> {
+    match ttl {
+        None => Ok(None),
+        Some(ttl) if ttl > 0.0 && ttl.is_finite() => Ok(Some(now_ms() + (ttl * 1000.0) as i64)),
+        Some(ttl) => Err(mlua::Error::RuntimeError(format!(
+            "nitr.kv `ttl` must be a positive number of seconds, got {ttl}"
+        ))),
+    }
+}
+
+/// The live value under `key`, as stored.
+fn live(conn: &Connection, key: &str, now: i64) -> rusqlite::Result<Option<(String, Option<i64>)>> {
+    conn.query_row(
+        &format!("SELECT value, expires_at FROM _nitr_kv WHERE key = ?2 AND {LIVE}"),
+        params![now, key],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+fn upsert(
+    conn: &Connection,
+    key: &str,
+    value: &str,
+    expires_at: Option<i64>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO _nitr_kv (key, value, expires_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+        params![key, value, expires_at],
+    )
+    .map(drop)
+}
+
+impl UserData for Kv {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        // kv:get(key) -> value | nil
+        methods.add_async_method("get", |lua, kv, key: String| {
+            let kv = kv.clone();
+            async move {
+                let stored = kv.with(move |conn| Ok(live(conn, &key, now_ms())?)).await?;
+                match stored {
+                    Some((json, _)) => decode(&lua, &json),
+                    None => Ok(Value::Nil),
+                }
+            }
+        });
+
+        // kv:set(key, value, opts?) — opts is { ttl = seconds }; without a
+        // ttl the key never expires.
+        methods.add_async_method(
+            "set",
+            |_, kv, (key, value, opts): (String, Value, Option<Table>)| {
+                let kv = kv.clone();
+                let args = check_key(&key)
+                    .and_then(|()| Ok((encode(&key, &value)?, expiry(opts.as_ref())?)));
+                async move {
+                    let (json, expires_at) = args?;
+                    kv.with(move |conn| Ok(upsert(conn, &key, &json, expires_at)?))
+                        .await
+                }
+            },
+        );
+
+        // kv:delete(key) -> whether it was there
+        methods.add_async_method("delete", |_, kv, key: String| {
+            let kv = kv.clone();
+            async move {
+                kv.with(move |conn| {
+                    Ok(conn.execute(
+                        &format!("DELETE FROM _nitr_kv WHERE key = ?2 AND {LIVE}"),
+                        params![now_ms(), key],
+                    )?)
+                })
+                .await
+                .map(|deleted| deleted > 0)
+            }
+        });
+
+        // kv:incr(key, by?, opts?) -> the new value. A missing key counts
+        // from 0 and takes opts.ttl; an existing one keeps its expiry, so a
+        // counter with a ttl is a fixed window.
+        methods.add_async_method(
+            "incr",
+            |_, kv, (key, by, opts): (String, Option<i64>, Option<Table>)| {
+                let kv = kv.clone();
+                let args = check_key(&key).and_then(|()| expiry(opts.as_ref()));
+                async move {
+                    let fresh_expiry = args?;
+                    let by = by.unwrap_or(1);
+                    kv.with(move |conn| {
+                        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                        let (current, expires_at) = match live(&tx, &key, now_ms())? {
+                            Some((json, expires_at)) => {
+                                let current = json.parse::<i64>().map_err(|_| {
+                                    Failure::Value(format!(
+                                        "kv:incr(`{key}`): the stored value is not an integer"
+                                    ))
+                                })?;
+                                (current, expires_at)
+                            }
+                            None => (0, fresh_expiry),
+                        };
+                        let next = current
+                            .checked_add(by)
+                            .ok_or_else(|| Failure::Value(format!("kv:incr(`{key}`) overflows")))?;
+                        upsert(&tx, &key, &next.to_string(), expires_at)?;
+                        tx.commit()?;
+                        Ok(next)
+                    })
+                    .await
+                }
+            },
+        );
+
+        // kv:cas(key, expected, new, opts?) -> whether it swapped. An
+        // `expected` of nil means "absent"; a `new` of nil deletes.
+        methods.add_async_method(
+            "cas",
+            |_, kv, (key, expected, new, opts): (String, Value, Value, Option<Table>)| {
+                let kv = kv.clone();
+                let args = check_key(&key).and_then(|()| {
+                    let expected = match expected {
+                        Value::Nil => None,
+                        expected => Some(encode(&key, &expected)?),
+                    };
+                    let new = match new {
+                        Value::Nil => None,
+                        new => Some(encode(&key, &new)?),
+                    };
+                    Ok((expected, new, expiry(opts.as_ref())?))
+                });
+                async move {
+                    let (expected, new, expires_at) = args?;
+                    kv.with(move |conn| {
+                        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                        let current = live(&tx, &key, now_ms())?.map(|(json, _)| json);
+                        if current != expected {
+                            return Ok(false);
+                        }
+                        match new {
+                            Some(json) => upsert(&tx, &key, &json, expires_at)?,
+                            None => {
+                                tx.execute("DELETE FROM _nitr_kv WHERE key = ?1", [&key])?;
+                            }
+                        }
+                        tx.commit()?;
+                        Ok(true)
+                    })
+                    .await
+                }
+            },
+        );
+
+        // kv:expire(key, ttl?) -> whether the key exists. A nil ttl makes
+        // the key permanent.
+        methods.add_async_method("expire", |_, kv, (key, ttl): (String, Option<f64>)| {
+            let kv = kv.clone();
+            let expires_at = ttl_expiry(ttl);
+            async move {
+                let expires_at = expires_at?;
+                kv.with(move |conn| {
+                    Ok(conn.execute(
+                        &format!("UPDATE _nitr_kv SET expires_at = ?3 WHERE key = ?2 AND {LIVE}"),
+                        params![now_ms(), key, expires_at],
+                    )?)
+                })
+                .await
+                .map(|updated| updated > 0)
+            }
+        });
+
+        // kv:scan(prefix, opts?) -> { { key = ..., value = ... }, ... } in
+        // key order. opts: `limit` (100, at most 1000) and `after`, the
+        // last key of the previous page.
+        methods.add_async_method(
+            "scan",
+            |lua, kv, (prefix, opts): (String, Option<Table>)| {
+                let kv = kv.clone();
+                let args = (|| {
+                    let Some(opts) = &opts else {
+                        return Ok((SCAN_LIMIT, None));
+                    };
+                    let limit = opts.get::<Option<usize>>("limit")?.unwrap_or(SCAN_LIMIT);
+                    Ok::<_, mlua::Error>((
+                        limit.min(MAX_SCAN_LIMIT),
+                        opts.get::<Option<String>>("after")?,
+                    ))
+                })();
+                async move {
+                    let (limit, after) = args?;
+                    let rows = kv
+                        .with(move |conn| {
+                            let mut stmt = conn.prepare_cached(&format!(
+                                "SELECT key, value FROM _nitr_kv
+                                 WHERE key >= ?2 AND substr(key, 1, length(?2)) = ?2
+                                   AND (?3 IS NULL OR key > ?3) AND {LIVE}
+                                 ORDER BY key LIMIT ?4"
+                            ))?;
+                            let rows = stmt
+                                .query_map(params![now_ms(), prefix, after, limit as i64], |row| {
+                                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                                })?
+                                .collect::<rusqlite::Result<Vec<_>>>()?;
+                            Ok(rows)
+                        })
+                        .await?;
+                    let out = lua.create_table_with_capacity(rows.len(), 0)?;
+                    for (key, json) in rows {
+                        let entry = lua.create_table()?;
+                        entry.set("key", key)?;
+                        entry.set("value", decode(&lua, &json)?)?;
+                        out.push(entry)?;
+                    }
+                    Ok(out)
+                }
+            },
+        );
+    }
+}
+
+/// Builds the `nitr.kv` handle for one state over the shared store.
+pub(crate) fn create_kv(lua: &Lua, kv: Kv) -> mlua::Result<mlua::AnyUserData> {
+    lua.create_userdata(kv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(name: &str) -> Kv {
+        open_with(name, Duration::ZERO)
+    }
+
+    fn open_with(name: &str, sweep_every: Duration) -> Kv {
+        static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("nitr-kv-{}-{id}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        Kv::open(&dir.join(name), &SqlitePragmas::default(), sweep_every).expect("open")
+    }
+
+    async fn eval<T: mlua::FromLuaMulti>(lua: &Lua, kv: &Kv, code: &str) -> T {
+        lua.globals()
+            .set("kv", create_kv(lua, kv.clone()).expect("kv"))
+            .expect("global");
+        lua.load(code).eval_async().await.expect(code)
+    }
+
+    #[tokio::test]
+    async fn values_round_trip_and_expire() {
+        let lua = Lua::new();
+        let kv = open("values.db");
+        let (name, flags, missing): (String, Table, Value) = eval(
+            &lua,
+            &kv,
+            r#"
+                kv:set("user:1", "ada")
+                kv:set("flags", { beta = true, limit = 3 })
+                return kv:get("user:1"), kv:get("flags"), kv:get("nope")
+            "#,
+        )
+        .await;
+        assert_eq!(name, "ada");
+        assert!(flags.get::<bool>("beta").expect("beta"));
+        assert_eq!(flags.get::<i64>("limit").expect("limit"), 3);
+        assert!(missing.is_nil());
+
+        // An expired key reads as absent before any sweep ran.
+        let (expired, kept, gone): (Value, bool, bool) = eval(
+            &lua,
+            &kv,
+            r#"
+                kv:set("short", 1, { ttl = 0.001 })
+                kv:set("long", 1, { ttl = 60 })
+                local t = os.clock() while os.clock() - t < 0.01 do end
+                return kv:get("short"), kv:expire("long", nil), kv:delete("short")
+            "#,
+        )
+        .await;
+        assert!(expired.is_nil());
+        assert!(kept);
+        assert!(!gone, "an expired key is already gone");
+    }
+
+    #[tokio::test]
+    async fn incr_and_cas_are_read_modify_write() {
+        let lua = Lua::new();
+        let kv = open("atomic.db");
+        let (first, second, not_int): (i64, i64, String) = eval(
+            &lua,
+            &kv,
+            r#"
+                local first = kv:incr("hits")
+                local second = kv:incr("hits", 10)
+                kv:set("name", "ada")
+                local _, err = pcall(kv.incr, kv, "name")
+                return first, second, tostring(err)
+            "#,
+        )
+        .await;
+        assert_eq!((first, second), (1, 11));
+        assert!(not_int.contains("not an integer"), "got: {not_int}");
+
+        let (claimed, again, swapped, stale, deleted): (bool, bool, bool, bool, Value) = eval(
+            &lua,
+            &kv,
+            r#"
+                local claimed = kv:cas("lock", nil, { owner = "a", n = 1 })
+                local again = kv:cas("lock", nil, { owner = "b" })
+                -- Tables compare by content, not by Lua's iteration order.
+                local swapped = kv:cas("lock", { n = 1, owner = "a" }, { owner = "b" })
+                local stale = kv:cas("lock", { owner = "a" }, nil)
+                kv:cas("lock", { owner = "b" }, nil)
+                return claimed, again, swapped, stale, kv:get("lock")
+            "#,
+        )
+        .await;
+        assert!(claimed && !again && swapped && !stale);
+        assert!(deleted.is_nil());
+    }
+
+    #[tokio::test]
+    async fn scan_pages_through_a_prefix() {
+        let lua = Lua::new();
+        let kv = open("scan.db");
+        let (page, next, other): (Vec<String>, Vec<String>, usize) = eval(
+            &lua,
+            &kv,
+            r#"
+                for i = 1, 5 do kv:set("job:" .. i, i) end
+                kv:set("jobs", "not under the prefix")
+                kv:set("job:%", "literal")
+                local function keys(rows)
+                    local out = {}
+                    for _, row in ipairs(rows) do out[#out + 1] = row.key end
+                    return out
+                end
+                local page = kv:scan("job:", { limit = 3 })
+                local next = kv:scan("job:", { limit = 3, after = page[#page].key })
+                return keys(page), keys(next), #kv:scan("nothing")
+            "#,
+        )
+        .await;
+        assert_eq!(page, ["job:%", "job:1", "job:2"]);
+        assert_eq!(next, ["job:3", "job:4", "job:5"]);
+        assert_eq!(other, 0);
+    }
+
+    #[tokio::test]
+    async fn the_sweep_deletes_expired_rows() {
+        let lua = Lua::new();
+        let kv = open_with("sweep.db", Duration::from_millis(10));
+        let () = eval(
+            &lua,
+            &kv,
+            r#"kv:set("old", 1, { ttl = 0.001 }) kv:set("kept", 1)"#,
+        )
+        .await;
+        let rows = || {
+            let conn = kv.conn.lock().expect("lock");
+            conn.query_row("SELECT count(*) FROM _nitr_kv", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .expect("count")
+        };
+        for _ in 0..100 {
+            if rows() == 1 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the expired row was never swept");
+    }
+
+    #[test]
+    fn values_and_keys_are_bounded() {
+        let lua = Lua::new();
+        assert!(check_key(&"k".repeat(MAX_KEY_BYTES + 1)).is_err());
+        let err = encode("k", &Value::Nil).expect_err("nil");
+        assert!(err.to_string().contains("kv:delete"), "got: {err}");
+        let f = Value::Function(lua.create_function(|_, ()| Ok(())).expect("fn"));
+        assert!(encode("k", &f).is_err());
+        let err = ttl_expiry(Some(-1.0)).expect_err("negative ttl");
+        assert!(err.to_string().contains("positive"), "got: {err}");
+    }
+}
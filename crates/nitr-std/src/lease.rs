@@ -0,0 +1,145 @@
+//! Named leases in a Nitr-owned SQLite table: which of several processes
+//! sharing a database file gets to do something only one of them should.
+//!
+//! A lease is held until it expires; the holder extends it by acquiring it
+//! again before then. A process that dies simply stops renewing, and the
+//! lease passes to the next process that asks once it lapses.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, TransactionBehavior, params};
+
+use crate::SqlitePragmas;
+use nitr_core::{Error, Result};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS _nitr_leases (
+        name       TEXT PRIMARY KEY,
+        holder     TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    ) WITHOUT ROWID;
+";
+
+/// One named lease, as seen by this process.
+#[derive(Clone)]
+pub struct Lease {
+    conn: Arc<Mutex<Connection>>,
+    name: Arc<str>,
+    /// Who this process is in the table; unique per [`Lease::open`].
+    holder: Arc<str>,
+    ttl: Duration,
+}
+
+impl std::fmt::Debug for Lease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lease")
+            .field("name", &self.name)
+            .field("holder", &self.holder)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Lease {
+    /// Opens the lease `name` in the SQLite file at `path`, creating its
+    /// table. Nothing is acquired yet.
+    pub fn open(path: &Path, pragmas: &SqlitePragmas, name: &str, ttl: Duration) -> Result<Self> {
+        let conn = crate::db::pragmas::open(path, pragmas)?;
+        conn.execute_batch(SCHEMA).map_err(|err| {
+            Error::Config(format!(
+                "cannot create the lease table in {}: {err}",
+                path.display()
+            ))
+        })?;
+        let mut nonce = [0u8; 8];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|err| Error::Config(format!("no randomness for a lease holder: {err}")))?;
+        let nonce: String = nonce.iter().map(|b| format!("{b:02x}")).collect();
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            name: name.into(),
+            holder: format!("{}-{nonce}", std::process::id()).into(),
+            ttl,
+        })
+    }
+
+    /// Acquires the lease, or extends it when this process already holds
+    /// it. Returns whether this process holds it now.
+    pub fn acquire(&self) -> Result<bool> {
+        let now = now_ms();
+        let expires_at = now.saturating_add(self.ttl.as_millis() as i64);
+        self.with(|conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let taken = tx.execute(
+                "INSERT INTO _nitr_leases (name, holder, expires_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE
+                     SET holder = excluded.holder, expires_at = excluded.expires_at
+                     WHERE holder = excluded.holder OR expires_at <= ?4",
+                params![&*self.name, &*self.holder, expires_at, now],
+            )?;
+            tx.commit()?;
+            Ok(taken > 0)
+        })
+    }
+
+    /// Gives the lease up if this process holds it, so another can take it
+    /// at once rather than after it expires.
+    pub fn release(&self) -> Result {
+        self.with(|conn| {
+            conn.execute(
+                "DELETE FROM _nitr_leases WHERE name = ?1 AND holder = ?2",
+                params![&*self.name, &*self.holder],
+            )
+            .map(drop)
+        })
+    }
+
+    /// How long an acquisition lasts.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> Result<T> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| Error::Config("the lease lock is poisoned".into()))?;
+        f(&mut conn).map_err(|err| Error::Config(format!("lease `{}`: {err}", self.name)))
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_holder_at_a_time_until_it_lapses_or_is_released() {
+        let dir = std::env::temp_dir().join(format!("nitr-lease-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        let path = dir.join("lease.db");
+        let open = |ttl| Lease::open(&path, &SqlitePragmas::default(), "scheduler", ttl);
+        let a = open(Duration::from_millis(50)).expect("a");
+        let b = open(Duration::from_millis(50)).expect("b");
+
+        assert!(a.acquire().expect("a acquires"));
+        assert!(a.acquire().expect("a renews"));
+        assert!(!b.acquire().expect("b waits"));
+
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(b.acquire().expect("b takes the lapsed lease"));
+        assert!(!a.acquire().expect("a lost it"));
+
+        b.release().expect("release");
+        assert!(a.acquire().expect("a takes the released lease"));
+        drop((a, b));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -17,7 +17,11 @@ use std::path::PathBuf;
 use nitr_core::Result;
 
 pub(crate) mod base64;
+#[cfg(feature = "crypto")]
+pub(crate) mod bearer;
 pub mod cache;
+#[cfg(feature = "db")]
+pub mod changes;
 pub(crate) mod config;
 #[cfg(feature = "crypto")]
 pub(crate) mod crypto;
@@ -28,22 +32,47 @@ pub(crate) mod env;
 #[cfg(feature = "fetch")]
 pub(crate) mod fetch;
 pub(crate) mod http;
+#[cfg(feature = "db")]
+pub mod jobs;
 pub(crate) mod json;
+#[cfg(feature = "crypto")]
+pub(crate) mod jwt;
+#[cfg(feature = "crypto")]
+pub(crate) mod keyfile;
+#[cfg(feature = "crypto")]
+pub(crate) mod keypair;
+pub mod keys;
+#[cfg(feature = "db")]
+pub mod kv;
+#[cfg(feature = "db")]
+pub mod lease;
 pub(crate) mod log;
+#[cfg(all(feature = "crypto", feature = "fetch"))]
+pub(crate) mod oidc;
+#[cfg(feature = "crypto")]
+pub(crate) mod otp;
 pub(crate) mod path;
+pub mod pubsub;
 pub(crate) mod session;
+pub mod sse;
 #[cfg(feature = "template")]
 pub(crate) mod template;
 pub(crate) mod time;
 pub(crate) mod url;
 pub(crate) mod utils;
 pub(crate) mod validate;
+#[cfg(feature = "webauthn")]
+pub(crate) mod webauthn;
+pub(crate) mod webhook;
 
-pub use cache::{Cache, CacheOptions};
+pub use cache::{Cache, CacheFallback, CacheOptions, RedisOptions};
 // The configuration types are always available: `nitr.toml` has one shape
 // regardless of which builtins this build compiled in.
-pub use config::{EnvOptions, FetchOptions, SqlitePragmas};
+pub use config::{EnvOptions, FetchOptions, ReplicaOptions, SearchIndex, SqlitePragmas};
 pub use http::{RequestCookies, ResponseCookies, best_match};
+pub use keys::{KeyRing, KeySource};
+pub use pubsub::{PubSub, PubSubOptions, SlowSubscriber};
+pub use sse::{SseHub, SseOptions};
 pub use utils::error_lua_value;
 
 /// Internal functions exposed for the fuzz targets in `fuzz/` only.
@@ -57,12 +86,30 @@ pub mod fuzzing {
     };
 }
 
+#[cfg(feature = "db")]
+pub use changes::ChangeFeed;
+#[cfg(feature = "db")]
+pub use db::explain;
 #[cfg(feature = "db")]
 pub use db::migrate;
+#[cfg(feature = "postgres")]
+pub use db::postgres::PgPool;
 #[cfg(feature = "db")]
 pub use db::pragmas::open as db_open;
+#[cfg(feature = "db")]
+pub use db::replicate;
+#[cfg(feature = "db")]
+pub use db::search;
 #[cfg(feature = "fetch")]
 pub use fetch::{reset_outbound_budget, set_trace_context};
+#[cfg(feature = "db")]
+pub use jobs::{Jobs, JobsOptions};
+#[cfg(feature = "db")]
+pub use kv::Kv;
+#[cfg(feature = "db")]
+pub use lease::Lease;
+#[cfg(feature = "template")]
+pub use template::set_csp_nonce;
 
 /// Resets the per-request outbound budget. A no-op without the `fetch`
 /// feature, so the server can call it unconditionally.
@@ -74,6 +121,11 @@ pub fn reset_outbound_budget(_lua: &mlua::Lua) {}
 #[cfg(not(feature = "fetch"))]
 pub fn set_trace_context(_lua: &mlua::Lua, _request_id: &str) {}
 
+/// Records the request's CSP nonce for `nitr.template`. A no-op without
+/// the `template` feature.
+#[cfg(not(feature = "template"))]
+pub fn set_csp_nonce(_lua: &mlua::Lua, _nonce: Option<&str>) {}
+
 bitflags::bitflags! {
     /// Built-in `nitr.*` standard library modules that can be exposed to
     /// Lua scripts.
@@ -97,8 +149,8 @@ bitflags::bitflags! {
         const HTTP = 1 << 5;
         /// `nitr.log.debug/info/warn/error(msg, fields?)` structured logging.
         const LOG = 1 << 6;
-        /// `nitr.crypto` primitives (hashing, HMAC, passwords, AEAD, JWT)
-        /// and the `nitr.auth` header parsers.
+        /// `nitr.crypto` primitives (hashing, HMAC, passwords, AEAD, JWT),
+        /// the `nitr.auth` header parsers and, with `fetch`, `nitr.oidc`.
         const CRYPTO = 1 << 7;
         /// `nitr.cache`: the bounded cache shared by every pooled state.
         const CACHE = 1 << 8;
@@ -121,6 +173,18 @@ bitflags::bitflags! {
         /// (`get`/`has`/`number`/`bool`), filtered by `[env] allow` and
         /// never exposing `NITR_*` internals.
         const ENV = 1 << 14;
+        /// `nitr.kv`: the durable key-value store in a Nitr-owned SQLite
+        /// table, shared by every pooled state.
+        const KV = 1 << 15;
+        /// `nitr.pubsub`: the in-process publish/subscribe hub shared by
+        /// every pooled state.
+        const PUBSUB = 1 << 16;
+        /// `nitr.jobs`: the durable background job queue in a Nitr-owned
+        /// SQLite table, run by the server's job workers.
+        const JOBS = 1 << 17;
+        /// `nitr.webauthn`: passkey registration and login ceremonies,
+        /// verified in Rust.
+        const WEBAUTHN = 1 << 18;
     }
 }
 
@@ -163,6 +227,10 @@ impl Builtins {
             Builtins::PATH => Some("path"),
             Builtins::URL => Some("url"),
             Builtins::ENV => Some("env"),
+            Builtins::KV => Some("kv"),
+            Builtins::PUBSUB => Some("pubsub"),
+            Builtins::JOBS => Some("jobs"),
+            Builtins::WEBAUTHN => Some("webauthn"),
             _ => None,
         }
     }
@@ -186,6 +254,10 @@ impl Builtins {
             "path" => Some(Builtins::PATH),
             "url" => Some(Builtins::URL),
             "env" => Some(Builtins::ENV),
+            "kv" => Some(Builtins::KV),
+            "pubsub" => Some(Builtins::PUBSUB),
+            "jobs" => Some(Builtins::JOBS),
+            "webauthn" => Some(Builtins::WEBAUTHN),
             _ => None,
         }
     }
@@ -201,14 +273,48 @@ pub struct BuiltinsEnv {
     pub database: Option<PathBuf>,
     /// Connection pragmas applied to that database.
     pub sqlite: SqlitePragmas,
+    /// The Postgres pool behind `[database] url`. When set, `db` uses it
+    /// instead of `database`.
+    #[cfg(feature = "postgres")]
+    pub postgres: Option<PgPool>,
     /// The shared cache backing `nitr.cache`. Built once by the server and
     /// handed to every state, so it survives a pool rebuild — a cache that
     /// empties on every reload is a cache that never warms.
     pub cache: Option<Cache>,
+    /// The durable store backing `nitr.kv`, opened once by the server.
+    #[cfg(feature = "db")]
+    pub kv: Option<Kv>,
+    /// The queue behind `nitr.jobs`, opened once by the server, whose
+    /// workers run what the states enqueue.
+    #[cfg(feature = "db")]
+    pub jobs: Option<Jobs>,
+    /// The row-change feed behind `nitr.db:changes()`, shared by every
+    /// state's connection. `None` leaves change notifications off.
+    #[cfg(feature = "db")]
+    pub changes: Option<ChangeFeed>,
+    /// The hub behind `nitr.pubsub`, shared like the cache so a
+    /// subscriber hears what any state publishes.
+    pub pubsub: Option<PubSub>,
+    /// The channels behind `nitr.sse.channel`, whose streams the server
+    /// serves without holding a state. `None` leaves `channel` and
+    /// `publish` raising.
+    pub sse: Option<SseHub>,
+    /// Statements running at least this long are logged with their
+    /// normalized SQL and query plan. `None` logs none.
+    pub slow_query: Option<std::time::Duration>,
+    /// The full-text indexes `nitr.db:search()` may query.
+    pub search: Vec<SearchIndex>,
     /// Outbound-request policy for the `fetch` builtin.
     pub fetch: FetchOptions,
     /// Read policy for the `nitr.env` builtin.
     pub env: EnvOptions,
+    /// The `[keys]` ring, shared like the cache so rotating it on a reload
+    /// reaches every state. Registered as `nitr.keys` when set.
+    pub keys: Option<KeyRing>,
+    /// The directory key files named by scripts (`{ file = path }`) are
+    /// read from; paths that leave it are refused. `None` confines them
+    /// to the working directory.
+    pub app_dir: Option<PathBuf>,
 }
 
 /// Registers the selected builtins as fields of the global `nitr`
@@ -223,7 +329,8 @@ pub struct BuiltinsEnv {
     feature = "crypto",
     feature = "db",
     feature = "fetch",
-    feature = "template"
+    feature = "template",
+    feature = "webauthn"
 )))]
 fn not_compiled_in(name: &str) -> nitr_core::Error {
     nitr_core::Error::Config(format!(
@@ -263,13 +370,18 @@ pub fn register_builtins(lua: &mlua::Lua, builtins: Builtins, env: &BuiltinsEnv)
             Builtins::TEMPLATE => return Err(not_compiled_in("template")),
             Builtins::JSON => nitr.set("json", json::create_json_fn(lua)?)?,
             // Registers the response helpers (`nitr.text`, `nitr.html`,
-            // `nitr.redirect`, `nitr.status`, `nitr.negotiate`, `nitr.sse`),
+            // `nitr.redirect`, `nitr.status`, `nitr.negotiate`, `nitr.sse` and its channels),
             // `nitr.error`, and the signed-cookie ergonomics built on them:
-            // `nitr.csrf` and `nitr.session`.
+            // `nitr.csrf` and `nitr.session`, plus webhook signatures
+            // (`nitr.webhook`).
             Builtins::HTTP => {
-                http::register(lua, &nitr)?;
+                http::register(lua, &nitr, env.sse.clone())?;
                 nitr.set("csrf", csrf::create_csrf_table(lua)?)?;
-                nitr.set("session", session::create_session_fn(lua)?)?;
+                nitr.set(
+                    "session",
+                    session::create_session_table(lua, env.cache.clone())?,
+                )?;
+                nitr.set("webhook", webhook::create_webhook_table(lua)?)?;
             }
             Builtins::LOG => nitr.set("log", log::create_log_table(lua)?)?,
             Builtins::TIME => nitr.set("time", time::create_time_table(lua)?)?,
@@ -278,18 +390,31 @@ pub fn register_builtins(lua: &mlua::Lua, builtins: Builtins, env: &BuiltinsEnv)
             Builtins::PATH => nitr.set("path", path::create_path_table(lua)?)?,
             Builtins::URL => nitr.set("url", url::create_url_table(lua)?)?,
             Builtins::ENV => nitr.set("env", env::create_env_table(lua, &env.env)?)?,
-            // Registers both `nitr.crypto` and `nitr.auth`.
+            // Registers `nitr.crypto` and `nitr.auth`, plus `nitr.oidc` when
+            // the HTTP client is compiled in.
             #[cfg(feature = "crypto")]
             Builtins::CRYPTO => {
-                nitr.set("crypto", crypto::create_crypto_table(lua)?)?;
+                let files = keyfile::KeyFiles::new(lua, env.app_dir.as_deref())?;
+                nitr.set(
+                    "crypto",
+                    crypto::create_crypto_table(lua, &env.fetch, &files)?,
+                )?;
                 nitr.set("auth", crypto::create_auth_table(lua)?)?;
+                #[cfg(feature = "fetch")]
+                nitr.set("oidc", oidc::create_oidc_fn(lua, &env.fetch)?)?;
             }
             #[cfg(not(feature = "crypto"))]
             Builtins::CRYPTO => return Err(not_compiled_in("crypto")),
 
+            #[cfg(feature = "postgres")]
+            Builtins::DATABASE if env.postgres.is_some() => {
+                if let Some(pool) = &env.postgres {
+                    nitr.set("db", db::postgres::create_database_fn(lua, pool, env)?)?;
+                }
+            }
             #[cfg(feature = "db")]
             Builtins::DATABASE => match &env.database {
-                Some(path) => nitr.set("db", db::create_database_fn(lua, path, &env.sqlite)?)?,
+                Some(path) => nitr.set("db", db::create_database_fn(lua, path, env)?)?,
                 None => {
                     tracing::warn!("skipping builtin `db`: `database` is not configured");
                 }
@@ -302,9 +427,58 @@ pub fn register_builtins(lua: &mlua::Lua, builtins: Builtins, env: &BuiltinsEnv)
                     tracing::warn!("skipping builtin `cache`: no shared cache was provided");
                 }
             },
+            #[cfg(feature = "db")]
+            Builtins::KV => match &env.kv {
+                Some(kv) => nitr.set("kv", kv::create_kv(lua, kv.clone())?)?,
+                None => {
+                    tracing::warn!("skipping builtin `kv`: no store was provided");
+                }
+            },
+            // The store is a SQLite table, so it comes with the `db` feature.
+            #[cfg(not(feature = "db"))]
+            Builtins::KV => {
+                return Err(nitr_core::Error::Config(
+                    "the `kv` builtin is configured but was not compiled into this binary: \
+                     rebuild with the `db` Cargo feature (or `all`), or drop it from \
+                     `[std] features`"
+                        .into(),
+                ));
+            }
+            #[cfg(feature = "db")]
+            Builtins::JOBS => match &env.jobs {
+                Some(jobs) => nitr.set("jobs", jobs::create_jobs_table(lua, jobs.clone())?)?,
+                None => {
+                    tracing::warn!("skipping builtin `jobs`: no queue was provided");
+                }
+            },
+            // The queue is a SQLite table, like the `kv` store.
+            #[cfg(not(feature = "db"))]
+            Builtins::JOBS => {
+                return Err(nitr_core::Error::Config(
+                    "the `jobs` builtin is configured but was not compiled into this binary: \
+                     rebuild with the `db` Cargo feature (or `all`), or drop it from \
+                     `[std] features`"
+                        .into(),
+                ));
+            }
+            #[cfg(feature = "webauthn")]
+            Builtins::WEBAUTHN => nitr.set("webauthn", webauthn::create_webauthn_fn(lua)?)?,
+            #[cfg(not(feature = "webauthn"))]
+            Builtins::WEBAUTHN => return Err(not_compiled_in("webauthn")),
+            Builtins::PUBSUB => match &env.pubsub {
+                Some(hub) => nitr.set("pubsub", pubsub::create_pubsub_table(lua, hub.clone())?)?,
+                None => {
+                    tracing::warn!("skipping builtin `pubsub`: no hub was provided");
+                }
+            },
             _ => continue,
         };
     }
+    // The ring is configuration, not a capability to opt into: with
+    // `[keys]` set, `nitr.keys` is there for whichever builtins sign.
+    if let Some(keys) = &env.keys {
+        nitr.set("keys", keys.clone())?;
+    }
     // Always registered, independent of the configured builtins: turning a
     // caught error into its structured form is part of the error model,
     // not an optional capability.
@@ -333,6 +507,10 @@ mod tests {
             ("path", Builtins::PATH),
             ("url", Builtins::URL),
             ("env", Builtins::ENV),
+            ("kv", Builtins::KV),
+            ("pubsub", Builtins::PUBSUB),
+            ("jobs", Builtins::JOBS),
+            ("webauthn", Builtins::WEBAUTHN),
         ] {
             assert_eq!(Builtins::from_config_name(name), Some(flag));
         }
@@ -0,0 +1,718 @@
+//! `nitr.oidc`: the OpenID Connect authorization-code flow with PKCE, for
+//! "Sign in with X".
+//!
+//! `nitr.oidc({ issuer, client_id, ... })` describes one provider.
+//! `provider:authorize(session)` builds the redirect to the provider and
+//! remembers a fresh `state`, `nonce` and PKCE verifier in the session;
+//! `provider:callback(req, session)` checks the returned `state`, exchanges
+//! the code at the token endpoint, verifies the ID token against the
+//! provider's JWKS (signature, `iss`, `aud`, `exp`, `nonce`) and returns
+//! the user's claims.
+//!
+//! The endpoints come from the provider's discovery document, fetched under
+//! the `[fetch]` policy once per process. Both methods change the session;
+//! the handler saves it, as with any other session change. A `state` is
+//! spent by its callback; a replayed copy of an older cookie-only session
+//! still holds it, and is stopped by the provider redeeming each code once.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD as B64, URL_SAFE_NO_PAD as B64URL};
+use mlua::{Lua, LuaSerdeExt as _, ObjectLike as _, Table, UserData, UserDataMethods, Value};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue};
+use serde_json::Value as Json;
+use sha2::{Digest as _, Sha256};
+use url::Url;
+use url::form_urlencoded;
+
+use crate::FetchOptions;
+use crate::jwt;
+
+/// The session key holding logins in progress, by `state`.
+const PENDING_KEY: &str = "_oidc";
+
+/// Logins in progress kept per session; starting another drops the oldest,
+/// so abandoned attempts cannot grow the session cookie.
+const MAX_PENDING: usize = 3;
+
+/// Seconds a user has to come back from the provider.
+const LOGIN_TTL: f64 = 600.0;
+
+/// Seconds between fetches of the provider's JWKS.
+const JWKS_REFRESH: Duration = Duration::from_secs(3600);
+
+/// Authorization parameters `authorize` sets itself, which `params` may
+/// not override.
+const RESERVED_PARAMS: &[&str] = &[
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "nonce",
+    "code_challenge",
+    "code_challenge_method",
+];
+
+/// The error codes a provider may send (RFC 6749 §4.1.2.1 and §5.2,
+/// OpenID Connect Core §3.1.2.6). Any other `error` is reported as
+/// `unknown`, so a reason a script logs or shows never carries text from
+/// the callback URL.
+const ERROR_CODES: &[&str] = &[
+    "invalid_request",
+    "unauthorized_client",
+    "access_denied",
+    "unsupported_response_type",
+    "invalid_scope",
+    "server_error",
+    "temporarily_unavailable",
+    "invalid_client",
+    "invalid_grant",
+    "unsupported_grant_type",
+    "interaction_required",
+    "login_required",
+    "account_selection_required",
+    "consent_required",
+    "invalid_request_uri",
+    "invalid_request_object",
+    "request_not_supported",
+    "request_uri_not_supported",
+    "registration_not_supported",
+];
+
+/// Standard claims copied to the top level of `callback`'s result, when
+/// the ID token carries them.
+const PROFILE_CLAIMS: &[&str] = &[
+    "sub",
+    "email",
+    "email_verified",
+    "name",
+    "given_name",
+    "family_name",
+    "preferred_username",
+    "picture",
+    "locale",
+];
+
+/// One provider as configured by `nitr.oidc(...)`.
+struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    /// The `scope` parameter, `openid` first.
+    scope: String,
+    /// ID-token algorithms to accept; the provider's advertised asymmetric
+    /// algorithms when not configured.
+    algorithms: Option<Vec<String>>,
+    leeway: f64,
+    fetch: FetchOptions,
+}
+
+/// What the provider's discovery document says, validated.
+struct Discovery {
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    keys: jwt::Keys,
+    /// `id_token_signing_alg_values_supported` that Nitr can verify.
+    algorithms: Vec<String>,
+    /// Whether the client secret goes in the form body rather than Basic
+    /// auth: only when the provider supports nothing else.
+    secret_in_body: bool,
+}
+
+/// A login started by `authorize`, as kept in the session.
+struct Pending {
+    nonce: String,
+    verifier: String,
+    started: f64,
+}
+
+#[derive(Clone)]
+struct LuaProvider(Arc<Provider>);
+
+impl Provider {
+    /// The discovery document, fetched once per issuer and process. A
+    /// failed fetch is not cached: the next login tries again.
+    async fn discovery(&self) -> Result<Arc<Discovery>, String> {
+        type Registry = Mutex<HashMap<String, Arc<tokio::sync::OnceCell<Arc<Discovery>>>>>;
+        static DISCOVERED: OnceLock<Registry> = OnceLock::new();
+        let cell = DISCOVERED
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(self.issuer.clone())
+            .or_default()
+            .clone();
+        cell.get_or_try_init(|| self.discover())
+            .await
+            .cloned()
+            .map_err(|err| format!("provider discovery failed: {err}"))
+    }
+
+    async fn discover(&self) -> Result<Arc<Discovery>, String> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        );
+        let url = Url::parse(&url).map_err(|err| format!("invalid issuer: {err}"))?;
+        let (status, body) = crate::fetch::client::request(
+            reqwest::Method::GET,
+            url,
+            HeaderMap::new(),
+            None,
+            &self.fetch,
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            return Err(format!("the issuer answered {status}"));
+        }
+        let doc: Json =
+            serde_json::from_slice(&body).map_err(|err| format!("not a JSON document: {err}"))?;
+        // OpenID Connect Discovery §4.3: the document must name the issuer
+        // it was fetched for, exactly, or its keys could vouch for another.
+        if doc.get("issuer").and_then(Json::as_str) != Some(self.issuer.as_str()) {
+            return Err(format!("the document's `issuer` is not `{}`", self.issuer));
+        }
+        let endpoint = |name: &str| -> Result<Url, String> {
+            let url = doc
+                .get(name)
+                .and_then(Json::as_str)
+                .ok_or_else(|| format!("the document has no `{name}`"))?;
+            let url = Url::parse(url).map_err(|err| format!("invalid `{name}`: {err}"))?;
+            if self.issuer.starts_with("https:") && url.scheme() != "https" {
+                return Err(format!("`{name}` is not https"));
+            }
+            Ok(url)
+        };
+        let strings = |name: &str| -> Option<Vec<&str>> {
+            doc.get(name)
+                .and_then(Json::as_array)
+                .map(|list| list.iter().filter_map(Json::as_str).collect())
+        };
+        if strings("code_challenge_methods_supported").is_some_and(|m| !m.contains(&"S256")) {
+            return Err("the provider does not support PKCE with S256".into());
+        }
+        let algorithms = strings("id_token_signing_alg_values_supported")
+            .unwrap_or_else(|| vec!["RS256"])
+            .into_iter()
+            .filter(|alg| !alg.starts_with("HS") && jwt::JWT_ALGORITHMS.contains(alg))
+            .map(str::to_owned)
+            .collect();
+        let secret_in_body = strings("token_endpoint_auth_methods_supported").is_some_and(|m| {
+            m.contains(&"client_secret_post") && !m.contains(&"client_secret_basic")
+        });
+        Ok(Arc::new(Discovery {
+            authorization_endpoint: endpoint("authorization_endpoint")?,
+            token_endpoint: endpoint("token_endpoint")?,
+            keys: jwt::Keys::Remote(jwt::RemoteKeySet::shared(
+                endpoint("jwks_uri")?,
+                JWKS_REFRESH,
+                &self.fetch,
+            )),
+            algorithms,
+            secret_in_body,
+        }))
+    }
+
+    /// The authorization URL for a login with these secrets.
+    fn authorization_url(
+        &self,
+        discovery: &Discovery,
+        state: &str,
+        pending: &Pending,
+        params: &[(String, String)],
+    ) -> String {
+        let challenge = B64URL.encode(Sha256::digest(pending.verifier.as_bytes()));
+        let mut url = discovery.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", &self.scope)
+            .append_pair("state", state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256")
+            .extend_pairs(params);
+        url.into()
+    }
+
+    /// The token request's headers and form body for `code`, with the
+    /// client authenticated the way the provider supports.
+    fn token_request(
+        &self,
+        discovery: &Discovery,
+        code: &str,
+        verifier: &str,
+    ) -> Result<(HeaderMap, String), String> {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("code_verifier", verifier);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        match &self.client_secret {
+            Some(secret) if discovery.secret_in_body => {
+                form.append_pair("client_id", &self.client_id)
+                    .append_pair("client_secret", secret);
+            }
+            Some(secret) => {
+                // RFC 6749 §2.3.1: both halves are form-encoded first.
+                let encode =
+                    |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+                let basic = B64.encode(format!("{}:{}", encode(&self.client_id), encode(secret)));
+                let value = HeaderValue::from_str(&format!("Basic {basic}"))
+                    .map_err(|_| "the client credentials are not a valid header".to_owned())?;
+                headers.insert(AUTHORIZATION, value);
+            }
+            None => {
+                form.append_pair("client_id", &self.client_id);
+            }
+        }
+        Ok((headers, form.finish()))
+    }
+
+    /// Exchanges `code` at the token endpoint; the token response's JSON.
+    async fn exchange(
+        &self,
+        discovery: &Discovery,
+        code: &str,
+        verifier: &str,
+    ) -> Result<Json, String> {
+        let (headers, form) = self.token_request(discovery, code, verifier)?;
+        let (status, body) = crate::fetch::client::request(
+            reqwest::Method::POST,
+            discovery.token_endpoint.clone(),
+            headers,
+            Some(form.into()),
+            &self.fetch,
+        )
+        .await
+        .map_err(|err| format!("token exchange failed: {err}"))?;
+        let json: Option<Json> = serde_json::from_slice(&body).ok();
+        if !status.is_success() {
+            let error = json
+                .as_ref()
+                .and_then(|j| j.get("error"))
+                .and_then(Json::as_str)
+                .map_or_else(|| status.to_string(), |error| error_code(error).to_owned());
+            return Err(format!("token exchange failed: {error}"));
+        }
+        json.filter(Json::is_object)
+            .ok_or_else(|| "token exchange failed: not a JSON object".to_owned())
+    }
+
+    /// Verifies the ID token from the token response and returns its
+    /// claims.
+    async fn id_claims(
+        &self,
+        discovery: &Discovery,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Json, String> {
+        let policy = jwt::Policy {
+            algorithms: self
+                .algorithms
+                .clone()
+                .unwrap_or_else(|| discovery.algorithms.clone()),
+            leeway: self.leeway,
+            issuers: vec![self.issuer.clone()],
+            audiences: vec![self.client_id.clone()],
+        };
+        if policy.algorithms.is_empty() {
+            return Err("invalid id token: the provider signs with no supported algorithm".into());
+        }
+        let claims = jwt::verify(id_token, &discovery.keys, &policy)
+            .await
+            .map_err(|reason| format!("invalid id token: {reason}"))?;
+        if claims.get("nonce").and_then(Json::as_str) != Some(nonce) {
+            return Err("invalid id token: nonce mismatch".into());
+        }
+        // OpenID Connect Core §3.1.3.7: an `azp` must be this client.
+        if claims
+            .get("azp")
+            .is_some_and(|azp| azp.as_str() != Some(self.client_id.as_str()))
+        {
+            return Err("invalid id token: authorized party mismatch".into());
+        }
+        if !claims.get("sub").is_some_and(Json::is_string) {
+            return Err("invalid id token: no subject".into());
+        }
+        Ok(claims)
+    }
+}
+
+impl UserData for LuaProvider {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        // provider:authorize(session, params?) -> url | nil, reason
+        methods.add_async_method(
+            "authorize",
+            |lua, provider, (session, params): (Table, Option<Table>)| {
+                let provider = provider.0.clone();
+                let params = extra_params(params);
+                async move {
+                    let params = params?;
+                    let discovery = match provider.discovery().await {
+                        Ok(discovery) => discovery,
+                        Err(reason) => return reject(&lua, &reason),
+                    };
+                    let state = crate::csrf::new_token()?;
+                    let pending = Pending {
+                        nonce: crate::csrf::new_token()?,
+                        verifier: crate::csrf::new_token()?,
+                        started: unix_now(),
+                    };
+                    let url = provider.authorization_url(&discovery, &state, &pending, &params);
+                    remember(&lua, &session, &state, &pending)?;
+                    Ok((Value::String(lua.create_string(url)?), Value::Nil))
+                }
+            },
+        );
+
+        // provider:callback(req, session) -> user | nil, reason
+        methods.add_async_method(
+            "callback",
+            |lua, provider, (req, session): (Value, Table)| {
+                let provider = provider.0.clone();
+                async move {
+                    let query: Table = match &req {
+                        Value::UserData(ud) => ud.get("query")?,
+                        Value::Table(t) => t.get("query")?,
+                        other => {
+                            return Err(mlua::Error::RuntimeError(format!(
+                                "oidc callback expects the request, got {}",
+                                other.type_name()
+                            )));
+                        }
+                    };
+                    let param = |name: &str| -> mlua::Result<Option<String>> {
+                        Ok(match query.get::<Value>(name)? {
+                            Value::String(s) => Some(s.to_str()?.to_owned()),
+                            _ => None,
+                        })
+                    };
+                    // The state is spent whatever happens next: a callback
+                    // is never replayed.
+                    let Some(pending) = param("state")?
+                        .map(|state| take(&session, &state))
+                        .transpose()?
+                        .flatten()
+                    else {
+                        return reject(&lua, "state mismatch");
+                    };
+                    if let Some(error) = param("error")? {
+                        return reject(&lua, &format!("provider error: {}", error_code(&error)));
+                    }
+                    if unix_now() - pending.started > LOGIN_TTL {
+                        return reject(&lua, "login expired");
+                    }
+                    let Some(code) = param("code")? else {
+                        return reject(&lua, "missing code");
+                    };
+                    match provider.login(&code, &pending).await {
+                        Ok((claims, tokens)) => {
+                            Ok((Value::Table(user(&lua, claims, tokens)?), Value::Nil))
+                        }
+                        Err(reason) => reject(&lua, &reason),
+                    }
+                }
+            },
+        );
+    }
+}
+
+impl Provider {
+    /// The code exchange and ID token checks: the claims and the token
+    /// response.
+    async fn login(&self, code: &str, pending: &Pending) -> Result<(Json, Json), String> {
+        let discovery = self.discovery().await?;
+        let tokens = self.exchange(&discovery, code, &pending.verifier).await?;
+        let Some(id_token) = tokens.get("id_token").and_then(Json::as_str) else {
+            return Err("token exchange failed: no id_token".into());
+        };
+        let claims = self.id_claims(&discovery, id_token, &pending.nonce).await?;
+        Ok((claims, tokens))
+    }
+}
+
+/// `nil` plus a reason, like `jwt.verify`, so the handler decides what the
+/// user sees.
+fn reject(lua: &Lua, reason: &str) -> mlua::Result<(Value, Value)> {
+    Ok((Value::Nil, Value::String(lua.create_string(reason)?)))
+}
+
+/// A provider's `error` as one of [`ERROR_CODES`], or `unknown`.
+fn error_code(error: &str) -> &'static str {
+    ERROR_CODES
+        .iter()
+        .find(|code| **code == error)
+        .copied()
+        .unwrap_or("unknown")
+}
+
+/// `authorize`'s extra parameters (`prompt`, `login_hint`, ...).
+fn extra_params(params: Option<Table>) -> mlua::Result<Vec<(String, String)>> {
+    let Some(params) = params else {
+        return Ok(Vec::new());
+    };
+    let mut out = Vec::new();
+    for pair in params.pairs::<String, String>() {
+        let (name, value) = pair?;
+        if RESERVED_PARAMS.contains(&name.as_str()) {
+            return Err(mlua::Error::RuntimeError(format!(
+                "oidc authorize: `{name}` is set by nitr.oidc itself"
+            )));
+        }
+        out.push((name, value));
+    }
+    out.sort();
+    Ok(out)
+}
+
+/// Records a new login in the session, dropping expired and surplus ones.
+fn remember(lua: &Lua, session: &Table, state: &str, pending: &Pending) -> mlua::Result<()> {
+    let all = match session.raw_get::<Value>(PENDING_KEY)? {
+        Value::Table(all) => all,
+        _ => lua.create_table()?,
+    };
+    let now = unix_now();
+    let mut live = Vec::new();
+    for pair in all.pairs::<String, Table>() {
+        let (key, entry) = pair?;
+        let started: f64 = entry.get("at")?;
+        if now - started <= LOGIN_TTL {
+            live.push((started, key));
+        } else {
+            all.raw_set(key, Value::Nil)?;
+        }
+    }
+    live.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let surplus = (live.len() + 1).saturating_sub(MAX_PENDING);
+    for (_, key) in live.into_iter().take(surplus) {
+        all.raw_set(key, Value::Nil)?;
+    }
+    let entry = lua.create_table()?;
+    entry.set("nonce", pending.nonce.as_str())?;
+    entry.set("verifier", pending.verifier.as_str())?;
+    entry.set("at", pending.started)?;
+    all.raw_set(state, entry)?;
+    session.raw_set(PENDING_KEY, all)
+}
+
+/// Removes and returns the login started with `state`, if any.
+fn take(session: &Table, state: &str) -> mlua::Result<Option<Pending>> {
+    let Value::Table(all) = session.raw_get::<Value>(PENDING_KEY)? else {
+        return Ok(None);
+    };
+    let Value::Table(entry) = all.raw_get::<Value>(state)? else {
+        return Ok(None);
+    };
+    all.raw_set(state, Value::Nil)?;
+    if all.pairs::<Value, Value>().next().is_none() {
+        session.raw_set(PENDING_KEY, Value::Nil)?;
+    }
+    Ok(Some(Pending {
+        nonce: entry.get("nonce")?,
+        verifier: entry.get("verifier")?,
+        started: entry.get("at")?,
+    }))
+}
+
+/// `callback`'s result: the profile claims at the top level, every claim
+/// under `claims`, and the token response under `tokens`.
+fn user(lua: &Lua, claims: Json, tokens: Json) -> mlua::Result<Table> {
+    let user = lua.create_table()?;
+    for name in PROFILE_CLAIMS {
+        if let Some(value) = claims.get(*name) {
+            user.set(*name, lua.to_value(value)?)?;
+        }
+    }
+    let out = lua.create_table()?;
+    for name in [
+        "access_token",
+        "token_type",
+        "expires_in",
+        "refresh_token",
+        "id_token",
+        "scope",
+    ] {
+        if let Some(value) = tokens.get(name) {
+            out.set(name, lua.to_value(value)?)?;
+        }
+    }
+    user.set("claims", lua.to_value(&claims)?)?;
+    user.set("tokens", out)?;
+    Ok(user)
+}
+
+/// Builds `nitr.oidc(opts)`.
+pub(crate) fn create_oidc_fn(lua: &Lua, fetch: &FetchOptions) -> mlua::Result<mlua::Function> {
+    let fetch = fetch.clone();
+    lua.create_function(move |_, opts: Table| {
+        let err = |msg: String| mlua::Error::RuntimeError(format!("nitr.oidc: {msg}"));
+        for pair in opts.pairs::<String, Value>() {
+            let (key, _) = pair?;
+            if ![
+                "issuer",
+                "client_id",
+                "client_secret",
+                "redirect_uri",
+                "scopes",
+                "algorithms",
+                "leeway",
+            ]
+            .contains(&key.as_str())
+            {
+                return Err(err(format!("unknown option `{key}`")));
+            }
+        }
+        let required = |name: &str| -> mlua::Result<String> {
+            opts.get::<Option<String>>(name)?
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| err(format!("`{name}` is required")))
+        };
+        let issuer = required("issuer")?;
+        let issuer_url = Url::parse(&issuer).map_err(|e| err(format!("invalid `issuer`: {e}")))?;
+        if !matches!(issuer_url.scheme(), "https" | "http")
+            || issuer_url.query().is_some()
+            || issuer_url.fragment().is_some()
+        {
+            return Err(err(
+                "`issuer` must be an http(s) URL without a query or fragment".into(),
+            ));
+        }
+        let redirect_uri = required("redirect_uri")?;
+        Url::parse(&redirect_uri).map_err(|e| err(format!("invalid `redirect_uri`: {e}")))?;
+        let mut scopes = vec!["openid".to_owned()];
+        let requested = match opts.get::<Value>("scopes")? {
+            Value::Nil => vec!["email".into(), "profile".into()],
+            value => crate::bearer::scope_list(value, "nitr.oidc")?,
+        };
+        for scope in requested {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        let algorithms: Option<Vec<String>> = opts.get("algorithms")?;
+        if let Some(algorithms) = &algorithms {
+            if algorithms.is_empty() {
+                return Err(err("the `algorithms` allow-list is empty".into()));
+            }
+            // ID tokens are verified against the provider's public keys; an
+            // HMAC algorithm would need the client secret as the key.
+            if let Some(alg) = algorithms
+                .iter()
+                .find(|alg| alg.starts_with("HS") || !jwt::JWT_ALGORITHMS.contains(&alg.as_str()))
+            {
+                return Err(err(format!(
+                    "unsupported ID token algorithm `{alg}` (supported: {})",
+                    jwt::JWT_ALGORITHMS
+                        .iter()
+                        .filter(|alg| !alg.starts_with("HS"))
+                        .copied()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        }
+        let leeway = opts.get::<Option<f64>>("leeway")?.unwrap_or(0.0);
+        if !(leeway.is_finite() && leeway >= 0.0) {
+            return Err(err(format!(
+                "`leeway` must be non-negative seconds, got {leeway}"
+            )));
+        }
+        Ok(LuaProvider(Arc::new(Provider {
+            issuer,
+            client_id: required("client_id")?,
+            client_secret: opts.get("client_secret")?,
+            redirect_uri,
+            scope: scopes.join(" "),
+            algorithms,
+            leeway,
+            fetch: fetch.clone(),
+        })))
+    })
+}
+
+fn unix_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(started: f64) -> Pending {
+        Pending {
+            nonce: "n".into(),
+            verifier: "v".into(),
+            started,
+        }
+    }
+
+    #[test]
+    fn logins_in_progress_are_bounded_expired_and_spent_once() {
+        let lua = Lua::new();
+        let session = lua.create_table().expect("session");
+        let now = unix_now();
+        remember(&lua, &session, "stale", &pending(now - LOGIN_TTL - 1.0)).expect("remember");
+        for (i, state) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            remember(&lua, &session, state, &pending(now + i as f64)).expect("remember");
+        }
+        let all: Table = session.get(PENDING_KEY).expect("pending logins");
+        let mut kept: Vec<String> = all
+            .pairs::<String, Value>()
+            .map(|p| p.expect("pair").0)
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["b", "c", "d"]);
+
+        assert!(take(&session, "a").expect("take").is_none());
+        let spent = take(&session, "b").expect("take").expect("pending");
+        assert_eq!((spent.nonce.as_str(), spent.verifier.as_str()), ("n", "v"));
+        assert!(take(&session, "b").expect("take").is_none());
+        take(&session, "c").expect("take");
+        take(&session, "d").expect("take");
+        assert!(session.get::<Value>(PENDING_KEY).expect("get").is_nil());
+    }
+
+    #[test]
+    fn extra_params_cannot_override_the_flow() {
+        let lua = Lua::new();
+        let params = lua.create_table().expect("params");
+        params.set("prompt", "login").expect("set");
+        assert_eq!(
+            extra_params(Some(params.clone())).expect("allowed"),
+            [("prompt".to_owned(), "login".to_owned())]
+        );
+        params.set("state", "mine").expect("set");
+        let err = extra_params(Some(params)).expect_err("reserved");
+        assert!(err.to_string().contains("`state`"), "{err}");
+    }
+
+    #[test]
+    fn provider_errors_are_reported_as_standard_codes() {
+        assert_eq!(error_code("access_denied"), "access_denied");
+        assert_eq!(error_code("login_required"), "login_required");
+        for sent in [
+            "Access_Denied",
+            "access_denied ",
+            "<script>alert(1)</script>",
+            "call +1-555-0100 to unlock your account",
+            "",
+        ] {
+            assert_eq!(error_code(sent), "unknown", "{sent}");
+        }
+    }
+}
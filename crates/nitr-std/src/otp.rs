@@ -0,0 +1,560 @@
+//! `nitr.crypto.otp`: one-time passwords for two-factor login. TOTP
+//! (RFC 6238) and HOTP (RFC 4226) codes from a base32 shared secret, the
+//! `otpauth://` URI authenticator apps enrol from, and recovery codes
+//! stored as argon2id hashes.
+//!
+//! Replay protection needs state Nitr does not keep: `verify` returns the
+//! time step it matched and refuses any step at or before the `last_step`
+//! it is given, so the caller stores one number per user (in `nitr.kv` or
+//! its own table) to make every code single-use.
+
+use hmac::Hmac;
+use mlua::{Lua, LuaString, Table, Value};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use subtle::ConstantTimeEq as _;
+
+/// RFC 4648 base32, the alphabet every authenticator app reads.
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Bytes in a generated secret: 160 bits, RFC 4226's recommendation.
+const SECRET_BYTES: usize = 20;
+
+/// The shortest secret accepted: 80 bits, what older enrolments used.
+const MIN_SECRET_BYTES: usize = 10;
+
+/// Steps either side of now (or counters ahead) `verify` checks at most.
+const MAX_WINDOW: u64 = 10;
+
+/// Recovery codes generated by default, and at most.
+const RECOVERY_CODES: usize = 10;
+const MAX_RECOVERY_CODES: usize = 32;
+
+fn otp_err(msg: impl std::fmt::Display) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("otp: {msg}"))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA256",
+            Self::Sha512 => "SHA512",
+        }
+    }
+
+    fn mac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        fn run<M: hmac::Mac + hmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut mac: M = crate::utils::new_hmac(key);
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+        match self {
+            Self::Sha1 => run::<Hmac<sha1::Sha1>>(key, data),
+            Self::Sha256 => run::<Hmac<sha2::Sha256>>(key, data),
+            Self::Sha512 => run::<Hmac<sha2::Sha512>>(key, data),
+        }
+    }
+}
+
+/// The code parameters shared by generation, verification and the URI.
+/// The defaults are what authenticator apps assume when the URI is silent.
+#[derive(Clone, Copy)]
+struct Params {
+    algorithm: Algorithm,
+    digits: u32,
+    period: u64,
+}
+
+impl Params {
+    fn from_opts(opts: Option<&Table>) -> mlua::Result<Self> {
+        let mut params = Self {
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        };
+        let Some(opts) = opts else {
+            return Ok(params);
+        };
+        if let Some(name) = opts.get::<Option<String>>("algorithm")? {
+            params.algorithm = match name.to_ascii_uppercase().as_str() {
+                "SHA1" => Algorithm::Sha1,
+                "SHA256" => Algorithm::Sha256,
+                "SHA512" => Algorithm::Sha512,
+                _ => {
+                    return Err(otp_err(format!(
+                        "unsupported algorithm `{name}` (supported: SHA1, SHA256, SHA512)"
+                    )));
+                }
+            };
+        }
+        if let Some(digits) = opts.get::<Option<u32>>("digits")? {
+            if !(6..=8).contains(&digits) {
+                return Err(otp_err(format!("`digits` must be 6, 7 or 8, got {digits}")));
+            }
+            params.digits = digits;
+        }
+        if let Some(period) = opts.get::<Option<u64>>("period")? {
+            if !(1..=3600).contains(&period) {
+                return Err(otp_err(format!(
+                    "`period` must be 1 to 3600 seconds, got {period}"
+                )));
+            }
+            params.period = period;
+        }
+        Ok(params)
+    }
+
+    /// The HOTP value for `counter` (RFC 4226 §5.3), zero-padded.
+    fn code(&self, key: &[u8], counter: u64) -> String {
+        let mac = self.algorithm.mac(key, &counter.to_be_bytes());
+        let offset = usize::from(mac[mac.len() - 1] & 0x0f);
+        let binary = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(char::from(BASE32[(buffer >> bits) as usize & 31]));
+        }
+    }
+    if bits > 0 {
+        out.push(char::from(BASE32[(buffer << (5 - bits)) as usize & 31]));
+    }
+    out
+}
+
+/// Decodes a secret as users paste it: any case, spaces and padding
+/// ignored.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in text.bytes().filter(|c| !matches!(c, b' ' | b'-' | b'=')) {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// The key bytes of a base32 secret.
+fn secret_key(secret: &str) -> mlua::Result<Vec<u8>> {
+    let key = base32_decode(secret).ok_or_else(|| otp_err("the secret is not base32"))?;
+    if key.len() < MIN_SECRET_BYTES {
+        return Err(otp_err(format!(
+            "the secret is {} bytes; at least {MIN_SECRET_BYTES} are required",
+            key.len()
+        )));
+    }
+    Ok(key)
+}
+
+fn random(n: usize) -> mlua::Result<Vec<u8>> {
+    let mut buf = vec![0u8; n];
+    getrandom::getrandom(&mut buf)
+        .map_err(|err| otp_err(format!("failed to read OS entropy: {err}")))?;
+    Ok(buf)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The TOTP step for `opts.time` (seconds, for tests and backfills) or now.
+fn step(opts: Option<&Table>, params: &Params) -> mlua::Result<u64> {
+    let time = match opts {
+        Some(opts) => opts.get::<Option<u64>>("time")?,
+        None => None,
+    };
+    Ok(time.unwrap_or_else(unix_now) / params.period)
+}
+
+/// `otpauth://` enrolment URI for a QR code.
+fn uri(secret: &str, opts: &Table) -> mlua::Result<String> {
+    secret_key(secret)?;
+    let params = Params::from_opts(Some(opts))?;
+    let label = |name: &str| -> mlua::Result<String> {
+        let value: String = opts
+            .get::<Option<String>>(name)?
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| otp_err(format!("uri: `{name}` is required")))?;
+        // The label is `issuer:account`; a colon inside either is ambiguous.
+        if value.contains(':') {
+            return Err(otp_err(format!("uri: `{name}` cannot contain `:`")));
+        }
+        Ok(utf8_percent_encode(&value, NON_ALPHANUMERIC).to_string())
+    };
+    let (issuer, account) = (label("issuer")?, label("account")?);
+    let counter: Option<u64> = opts.get("counter")?;
+    let kind = if counter.is_some() { "hotp" } else { "totp" };
+    let secret: String = secret
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '='))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let mut uri = format!("otpauth://{kind}/{issuer}:{account}?secret={secret}&issuer={issuer}");
+    if params.algorithm != Algorithm::Sha1 {
+        uri.push_str("&algorithm=");
+        uri.push_str(params.algorithm.name());
+    }
+    if params.digits != 6 {
+        uri.push_str(&format!("&digits={}", params.digits));
+    }
+    match counter {
+        Some(counter) => uri.push_str(&format!("&counter={counter}")),
+        None if params.period != 30 => uri.push_str(&format!("&period={}", params.period)),
+        None => {}
+    }
+    Ok(uri)
+}
+
+/// Checks `code`: the TOTP step (or, with `opts.counter`, the HOTP counter)
+/// it matches, or why it was refused.
+fn verify(key: &[u8], code: &str, opts: Option<&Table>) -> mlua::Result<Result<u64, &'static str>> {
+    let params = Params::from_opts(opts)?;
+    let (window, last_step, counter) = match opts {
+        Some(opts) => (
+            opts.get::<Option<u64>>("window")?.unwrap_or(1),
+            opts.get::<Option<u64>>("last_step")?,
+            opts.get::<Option<u64>>("counter")?,
+        ),
+        None => (1, None, None),
+    };
+    if window > MAX_WINDOW {
+        return Err(otp_err(format!(
+            "`window` must be at most {MAX_WINDOW}, got {window}"
+        )));
+    }
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != params.digits as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(Err("invalid code"));
+    }
+    // HOTP looks ahead only: the device's counter runs ahead of ours when
+    // the user pressed the button without logging in.
+    let candidates = match counter {
+        Some(counter) => counter..=counter.saturating_add(window),
+        None => {
+            let now = step(opts, &params)?;
+            now.saturating_sub(window)..=now.saturating_add(window)
+        }
+    };
+    // Every candidate is computed and compared, so the time taken does
+    // not say which step (if any) matched.
+    let mut matched = None;
+    for candidate in candidates {
+        let hit: bool = params
+            .code(key, candidate)
+            .as_bytes()
+            .ct_eq(code.as_bytes())
+            .into();
+        if hit && matched.is_none() {
+            matched = Some(candidate);
+        }
+    }
+    Ok(match matched {
+        None => Err("invalid code"),
+        Some(step) if counter.is_none() && last_step.is_some_and(|last| step <= last) => {
+            Err("code already used")
+        }
+        Some(step) => Ok(step),
+    })
+}
+
+/// A recovery code: ten base32 characters (50 bits), as `xxxxx-xxxxx`.
+fn recovery_code() -> mlua::Result<String> {
+    let raw = random(10)?;
+    let chars: String = raw
+        .iter()
+        .map(|b| char::from(BASE32[usize::from(b & 31)]).to_ascii_lowercase())
+        .collect();
+    Ok(format!("{}-{}", &chars[..5], &chars[5..]))
+}
+
+/// A recovery code as typed, reduced to what was hashed.
+fn normalize_recovery(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Builds `nitr.crypto.otp`.
+pub(crate) fn create_otp_table(lua: &Lua) -> mlua::Result<Table> {
+    let otp = lua.create_table()?;
+
+    // otp.secret(bytes?) -> a new base32 secret, 20 bytes by default.
+    otp.set(
+        "secret",
+        lua.create_function(|_, bytes: Option<usize>| {
+            let bytes = bytes.unwrap_or(SECRET_BYTES);
+            if !(16..=64).contains(&bytes) {
+                return Err(otp_err(format!(
+                    "secret(bytes) takes 16 to 64 bytes, got {bytes}"
+                )));
+            }
+            Ok(base32_encode(&random(bytes)?))
+        })?,
+    )?;
+
+    otp.set(
+        "uri",
+        lua.create_function(|_, (secret, opts): (String, Table)| uri(&secret, &opts))?,
+    )?;
+
+    // otp.totp(secret, opts?) -> the current code; opts.time overrides now.
+    otp.set(
+        "totp",
+        lua.create_function(|_, (secret, opts): (String, Option<Table>)| {
+            let key = secret_key(&secret)?;
+            let params = Params::from_opts(opts.as_ref())?;
+            Ok(params.code(&key, step(opts.as_ref(), &params)?))
+        })?,
+    )?;
+
+    otp.set(
+        "hotp",
+        lua.create_function(|_, (secret, counter, opts): (String, u64, Option<Table>)| {
+            let key = secret_key(&secret)?;
+            Ok(Params::from_opts(opts.as_ref())?.code(&key, counter))
+        })?,
+    )?;
+
+    // otp.verify(secret, code, opts?) -> step | nil, reason. Store the
+    // step and pass it back as `last_step` next time.
+    otp.set(
+        "verify",
+        lua.create_function(
+            |lua, (secret, code, opts): (String, LuaString, Option<Table>)| {
+                let key = secret_key(&secret)?;
+                match verify(&key, &code.to_string_lossy(), opts.as_ref())? {
+                    Ok(step) => Ok((Value::Integer(step as i64), Value::Nil)),
+                    Err(reason) => Ok((Value::Nil, Value::String(lua.create_string(reason)?))),
+                }
+            },
+        )?,
+    )?;
+
+    // otp.recovery_codes(n?) -> codes, hashes: show the codes once, store
+    // only the hashes. Each is an argon2 hash, so they are made on the
+    // blocking pool rather than the worker.
+    otp.set(
+        "recovery_codes",
+        lua.create_async_function(|lua, n: Option<usize>| async move {
+            let n = n.unwrap_or(RECOVERY_CODES);
+            if !(1..=MAX_RECOVERY_CODES).contains(&n) {
+                return Err(otp_err(format!(
+                    "recovery_codes(n) takes 1 to {MAX_RECOVERY_CODES}, got {n}"
+                )));
+            }
+            let pairs = tokio::task::spawn_blocking(move || {
+                (0..n)
+                    .map(|_| {
+                        let code = recovery_code()?;
+                        let hash =
+                            crate::crypto::password_hash(normalize_recovery(&code).as_bytes())?;
+                        Ok((code, hash))
+                    })
+                    .collect::<mlua::Result<Vec<_>>>()
+            })
+            .await
+            .map_err(mlua::Error::external)??;
+            let (codes, hashes) = (lua.create_table()?, lua.create_table()?);
+            for (code, hash) in pairs {
+                codes.push(code)?;
+                hashes.push(hash)?;
+            }
+            Ok((codes, hashes))
+        })?,
+    )?;
+
+    // otp.verify_recovery(code, hashes) -> the index of the matching hash,
+    // which the caller then deletes, or nil. A miss checks every hash, so
+    // the list is capped like `recovery_codes` and checked off the worker.
+    otp.set(
+        "verify_recovery",
+        lua.create_async_function(|_, (code, hashes): (String, Vec<String>)| async move {
+            if hashes.len() > MAX_RECOVERY_CODES {
+                return Err(otp_err(format!(
+                    "verify_recovery takes at most {MAX_RECOVERY_CODES} hashes, got {}",
+                    hashes.len()
+                )));
+            }
+            let code = normalize_recovery(&code);
+            if code.is_empty() {
+                return Ok(None);
+            }
+            tokio::task::spawn_blocking(move || {
+                hashes
+                    .iter()
+                    .position(|hash| crate::crypto::password_verify(code.as_bytes(), hash))
+                    .map(|i| i + 1)
+            })
+            .await
+            .map_err(mlua::Error::external)
+        })?,
+    )?;
+
+    Ok(otp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 Appendix B: the same time vectors under each algorithm,
+    /// with the ASCII seed sized to the hash.
+    #[test]
+    fn totp_matches_the_rfc_6238_vectors() {
+        let cases: &[(u64, &str, &str, &str)] = &[
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        let seeds = [
+            (Algorithm::Sha1, b"12345678901234567890".to_vec()),
+            (
+                Algorithm::Sha256,
+                b"12345678901234567890123456789012".to_vec(),
+            ),
+            (
+                Algorithm::Sha512,
+                b"1234567890123456789012345678901234567890123456789012345678901234".to_vec(),
+            ),
+        ];
+        for &(time, sha1, sha256, sha512) in cases {
+            for ((algorithm, seed), want) in seeds.iter().zip([sha1, sha256, sha512]) {
+                let params = Params {
+                    algorithm: *algorithm,
+                    digits: 8,
+                    period: 30,
+                };
+                assert_eq!(params.code(seed, time / 30), want, "t={time}");
+            }
+        }
+    }
+
+    #[test]
+    fn hotp_matches_the_rfc_4226_vectors() {
+        let params = Params {
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        };
+        let want = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, want) in want.iter().enumerate() {
+            assert_eq!(params.code(b"12345678901234567890", counter as u64), *want);
+        }
+    }
+
+    #[test]
+    fn base32_round_trips_and_forgives_formatting() {
+        for len in 0..=21 {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            assert_eq!(
+                base32_decode(&base32_encode(&bytes)).expect("round trip"),
+                bytes
+            );
+        }
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(
+            base32_decode("mzxw 6ytb oi======").expect("lenient decode"),
+            b"foobar"
+        );
+        assert!(base32_decode("MZXW1").is_none());
+    }
+
+    #[test]
+    fn verify_honors_the_window_and_refuses_replays() {
+        let lua = Lua::new();
+        let key = b"12345678901234567890";
+        let params = Params::from_opts(None).expect("default params");
+        let opts = |pairs: &[(&str, u64)]| {
+            let t = lua.create_table().expect("opts table");
+            for (k, v) in pairs {
+                t.set(*k, *v).expect("set opt");
+            }
+            t
+        };
+        let now = 1_700_000_000;
+        let code = params.code(key, now / 30 - 1);
+        let step = verify(key, &code, Some(&opts(&[("time", now)]))).expect("verify");
+        assert_eq!(step, Ok(now / 30 - 1));
+        assert_eq!(
+            verify(key, &code, Some(&opts(&[("time", now), ("window", 0)]))).expect("verify"),
+            Err("invalid code")
+        );
+        assert_eq!(
+            verify(
+                key,
+                &code,
+                Some(&opts(&[("time", now), ("last_step", now / 30 - 1)]))
+            )
+            .expect("verify"),
+            Err("code already used")
+        );
+        assert_eq!(
+            verify(key, "12a456", Some(&opts(&[("time", now)]))).expect("verify"),
+            Err("invalid code")
+        );
+        // HOTP: a counter a little ahead of ours is accepted.
+        let ahead = params.code(key, 7);
+        assert_eq!(
+            verify(key, &ahead, Some(&opts(&[("counter", 5), ("window", 2)]))).expect("verify"),
+            Ok(7)
+        );
+        assert!(verify(key, &code, Some(&opts(&[("window", 11)]))).is_err());
+    }
+
+    #[test]
+    fn uris_carry_only_non_default_parameters() {
+        let lua = Lua::new();
+        let opts = lua.create_table().expect("opts table");
+        opts.set("issuer", "Acme Co").expect("set issuer");
+        opts.set("account", "ada@example.com").expect("set account");
+        assert_eq!(
+            uri("jbsw y3dp ehpk 3pxp", &opts).expect("totp uri"),
+            "otpauth://totp/Acme%20Co:ada%40example%2Ecom?secret=JBSWY3DPEHPK3PXP&issuer=Acme%20Co"
+        );
+        opts.set("digits", 8).expect("set digits");
+        opts.set("counter", 3).expect("set counter");
+        assert!(
+            uri("JBSWY3DPEHPK3PXP", &opts)
+                .expect("hotp uri")
+                .starts_with("otpauth://hotp/")
+        );
+        opts.set("account", "a:b").expect("set account");
+        assert!(uri("JBSWY3DPEHPK3PXP", &opts).is_err());
+    }
+}
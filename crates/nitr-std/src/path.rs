@@ -99,7 +99,8 @@ fn components(rest: &str) -> Vec<&str> {
 fn root_is_anchor(root: &str) -> bool {
     // A bare drive (`C:`) is drive-relative, not absolute: it resolves
     // against that drive's current directory, so it is no floor.
-    !root.is_empty() && !(root.len() == 2 && root.ends_with(':'))
+    let bare_drive = root.len() == 2 && root.ends_with(':');
+    !root.is_empty() && !bare_drive
 }
 
 /// Whether the path is absolute (`/x`, `C:\x`, or UNC).
@@ -0,0 +1,701 @@
+//! `nitr.pubsub`: an in-process publish/subscribe hub shared by every
+//! pooled state.
+//!
+//! Pooled states are isolated, so a streaming handler cannot otherwise
+//! hear what another request did. The hub is owned by Rust, like the
+//! cache: `publish` serializes the payload to JSON once and every
+//! subscriber decodes its own copy, so no Lua value crosses states.
+//!
+//! Topics are dot-separated (`chat.room42`). A subscription names
+//! patterns, where `*` stands for one segment and a trailing `**` for one
+//! or more (`chat.*`, `orders.**`). Each subscriber has its own bounded
+//! buffer; when it is full the subscriber is slow, and the hub either
+//! drops the message for it (it is told how many it missed) or
+//! disconnects it (its iterator ends). Publishing never waits on a
+//! subscriber.
+//!
+//! Delivery is at most once and in-process only: a message published
+//! while nobody listens is gone, and another process never sees it.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mlua::{Lua, LuaSerdeExt as _, MetaMethod, Table, UserData, UserDataMethods, Value};
+use tokio::sync::mpsc;
+
+/// The longest topic or pattern, in bytes.
+const MAX_TOPIC_BYTES: usize = 256;
+
+/// Most patterns one subscription may name.
+const MAX_PATTERNS: usize = 64;
+
+/// What the hub does with a subscriber whose buffer is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowSubscriber {
+    /// Drop the message for that subscriber, which is told how many it
+    /// missed when it catches up (the default).
+    #[default]
+    Drop,
+    /// Unsubscribe it: its iterator ends after the buffered messages.
+    Disconnect,
+}
+
+impl SlowSubscriber {
+    fn parse(name: &str) -> mlua::Result<Self> {
+        match name {
+            "drop" => Ok(Self::Drop),
+            "disconnect" => Ok(Self::Disconnect),
+            other => Err(mlua::Error::RuntimeError(format!(
+                "on_full must be \"drop\" or \"disconnect\", got \"{other}\""
+            ))),
+        }
+    }
+}
+
+/// Limits for the hub.
+#[derive(Debug, Clone)]
+pub struct PubSubOptions {
+    /// Messages buffered per subscriber before it counts as slow.
+    pub buffer: usize,
+    /// The default policy for a slow subscriber.
+    pub on_full: SlowSubscriber,
+    /// Most subscriptions open at once, across every state.
+    pub max_subscribers: usize,
+    /// The largest payload, in bytes of JSON.
+    pub max_message_bytes: usize,
+}
+
+impl Default for PubSubOptions {
+    fn default() -> Self {
+        Self {
+            buffer: 256,
+            on_full: SlowSubscriber::Drop,
+            max_subscribers: 10_000,
+            max_message_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// One published message; subscribers share it.
+#[derive(Debug)]
+struct Message {
+    topic: Arc<str>,
+    /// The payload as JSON.
+    data: Box<str>,
+}
+
+/// One segment of a pattern.
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(Box<str>),
+    /// `*`: exactly one segment.
+    One,
+    /// A trailing `**`: one or more segments.
+    Rest,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern(Box<[Segment]>);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        check_length(pattern)?;
+        let parts: Vec<&str> = pattern.split('.').collect();
+        let last = parts.len() - 1;
+        parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| match *part {
+                "" => Err(format!("`{pattern}` has an empty segment")),
+                "*" => Ok(Segment::One),
+                "**" if i == last => Ok(Segment::Rest),
+                "**" => Err(format!("`{pattern}`: `**` may only be the last segment")),
+                part if part.contains('*') => Err(format!(
+                    "`{pattern}`: `*` must be a whole segment, not part of one"
+                )),
+                part => Ok(Segment::Literal(part.into())),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn matches(&self, topic: &str) -> bool {
+        let mut parts = topic.split('.');
+        for segment in &self.0 {
+            match (segment, parts.next()) {
+                (_, None) => return false,
+                (Segment::Rest, Some(_)) => return true,
+                (Segment::One, Some(_)) => {}
+                (Segment::Literal(want), Some(part)) if **want == *part => {}
+                (Segment::Literal(_), Some(_)) => return false,
+            }
+        }
+        parts.next().is_none()
+    }
+}
+
+fn check_length(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("a topic cannot be empty".into());
+    }
+    if name.len() > MAX_TOPIC_BYTES {
+        return Err(format!(
+            "topics are at most {MAX_TOPIC_BYTES} bytes, got {}",
+            name.len()
+        ));
+    }
+    Ok(())
+}
+
+/// A topic a message is published to: a pattern without wildcards.
+fn check_topic(topic: &str) -> Result<(), String> {
+    check_length(topic)?;
+    if topic.contains('*') {
+        return Err(format!(
+            "`{topic}`: messages are published to a topic, not a pattern"
+        ));
+    }
+    if topic.split('.').any(str::is_empty) {
+        return Err(format!("`{topic}` has an empty segment"));
+    }
+    Ok(())
+}
+
+struct Subscriber {
+    patterns: Box<[Pattern]>,
+    tx: mpsc::Sender<Arc<Message>>,
+    /// Messages dropped for this subscriber since it last heard.
+    missed: Arc<AtomicU64>,
+    on_full: SlowSubscriber,
+}
+
+#[derive(Default)]
+struct Counters {
+    published: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+struct Hub {
+    opts: PubSubOptions,
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+    next_id: AtomicU64,
+    /// Set once the server drains: subscriptions end, new ones are refused.
+    closed: AtomicBool,
+    counters: Counters,
+}
+
+/// The hub behind `nitr.pubsub`. Built once by the server and handed to
+/// every state, so subscribers and publishers in different states meet.
+#[derive(Clone)]
+pub struct PubSub {
+    hub: Arc<Hub>,
+}
+
+impl std::fmt::Debug for PubSub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PubSub")
+            .field("opts", &self.hub.opts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PubSub {
+    /// An empty hub.
+    pub fn new(opts: PubSubOptions) -> Self {
+        Self {
+            hub: Arc::new(Hub {
+                opts,
+                subscribers: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                closed: AtomicBool::new(false),
+                counters: Counters::default(),
+            }),
+        }
+    }
+
+    /// Publishes `data`, already JSON, to `topic`, returning how many
+    /// subscribers it was queued for.
+    pub fn publish(&self, topic: &str, data: String) -> Result<usize, String> {
+        check_topic(topic)?;
+        let max = self.hub.opts.max_message_bytes;
+        if data.len() > max {
+            return Err(format!(
+                "a message to `{topic}` is {} bytes serialized, over the {max} byte limit",
+                data.len()
+            ));
+        }
+        let message = Arc::new(Message {
+            topic: topic.into(),
+            data: data.into_boxed_str(),
+        });
+        let counters = &self.hub.counters;
+        counters.published.fetch_add(1, Ordering::Relaxed);
+        let mut delivered = 0;
+        let mut subscribers = self.subscribers();
+        subscribers.retain(|_, sub| {
+            if !sub.patterns.iter().any(|pattern| pattern.matches(topic)) {
+                return true;
+            }
+            match sub.tx.try_send(message.clone()) {
+                Ok(()) => {
+                    delivered += 1;
+                    true
+                }
+                // Its subscription was collected without closing.
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+                Err(mpsc::error::TrySendError::Full(_)) => match sub.on_full {
+                    SlowSubscriber::Drop => {
+                        sub.missed.fetch_add(1, Ordering::Relaxed);
+                        counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        true
+                    }
+                    SlowSubscriber::Disconnect => {
+                        tracing::warn!(
+                            "pubsub: disconnected a subscriber {} messages behind on `{topic}`",
+                            self.hub.opts.buffer
+                        );
+                        counters.disconnected.fetch_add(1, Ordering::Relaxed);
+                        false
+                    }
+                },
+            }
+        });
+        drop(subscribers);
+        counters
+            .delivered
+            .fetch_add(delivered as u64, Ordering::Relaxed);
+        Ok(delivered)
+    }
+
+    /// Ends every subscription and refuses new ones, so streaming bodies
+    /// waiting for a message finish instead of holding up a drain.
+    pub fn close(&self) {
+        self.hub.closed.store(true, Ordering::Release);
+        self.subscribers().clear();
+    }
+
+    fn subscribe(
+        &self,
+        patterns: Box<[Pattern]>,
+        on_full: SlowSubscriber,
+    ) -> mlua::Result<Subscription> {
+        let mut subscribers = self.subscribers();
+        if self.hub.closed.load(Ordering::Acquire) {
+            return Err(mlua::Error::RuntimeError(
+                "nitr.pubsub is closed: the server is shutting down".into(),
+            ));
+        }
+        let max = self.hub.opts.max_subscribers;
+        if subscribers.len() >= max {
+            return Err(mlua::Error::RuntimeError(format!(
+                "nitr.pubsub already has {max} subscribers (`[pubsub] max_subscribers`)"
+            )));
+        }
+        let (tx, rx) = mpsc::channel(self.hub.opts.buffer.max(1));
+        let missed = Arc::new(AtomicU64::new(0));
+        let id = self.hub.next_id.fetch_add(1, Ordering::Relaxed);
+        subscribers.insert(
+            id,
+            Subscriber {
+                patterns,
+                tx,
+                missed: missed.clone(),
+                on_full,
+            },
+        );
+        Ok(Subscription {
+            hub: self.clone(),
+            id,
+            rx: Arc::new(tokio::sync::Mutex::new(rx)),
+            missed,
+        })
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.subscribers().remove(&id);
+    }
+
+    /// The subscriber table. Nothing panics while holding it, so a
+    /// poisoned lock still guards consistent data.
+    fn subscribers(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Subscriber>> {
+        self.hub
+            .subscribers
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn stats(&self, lua: &Lua) -> mlua::Result<Table> {
+        let counters = &self.hub.counters;
+        let table = lua.create_table()?;
+        table.set("subscribers", self.subscribers().len())?;
+        table.set("published", counters.published.load(Ordering::Relaxed))?;
+        table.set("delivered", counters.delivered.load(Ordering::Relaxed))?;
+        table.set("dropped", counters.dropped.load(Ordering::Relaxed))?;
+        table.set(
+            "disconnected",
+            counters.disconnected.load(Ordering::Relaxed),
+        )?;
+        Ok(table)
+    }
+}
+
+/// What a subscriber receives next.
+enum Next {
+    Message(Arc<Message>),
+    /// This many messages were dropped because the buffer was full.
+    Lagged(u64),
+    /// The subscription ended: closed, disconnected, or the hub is gone.
+    Closed,
+}
+
+/// The handle `nitr.pubsub.subscribe` returns. Dropping it (or collecting
+/// it) unsubscribes.
+struct Subscription {
+    hub: PubSub,
+    id: u64,
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Arc<Message>>>>,
+    missed: Arc<AtomicU64>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(self.id);
+    }
+}
+
+impl Subscription {
+    /// The next message, waiting for one. A gap is reported where it
+    /// happened: after the messages buffered before it.
+    async fn next(
+        rx: &mut mpsc::Receiver<Arc<Message>>,
+        missed: &AtomicU64,
+        timeout: Option<Duration>,
+    ) -> Option<Next> {
+        match rx.try_recv() {
+            Ok(message) => return Some(Next::Message(message)),
+            Err(mpsc::error::TryRecvError::Disconnected) => return Some(Next::Closed),
+            Err(mpsc::error::TryRecvError::Empty) => {}
+        }
+        let lagged = missed.swap(0, Ordering::Relaxed);
+        if lagged > 0 {
+            return Some(Next::Lagged(lagged));
+        }
+        let next = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, rx.recv()).await.ok()?,
+            None => rx.recv().await,
+        };
+        Some(next.map_or(Next::Closed, Next::Message))
+    }
+}
+
+fn next_to_lua(lua: &Lua, next: Option<Next>) -> mlua::Result<Value> {
+    let table = lua.create_table()?;
+    match next {
+        Some(Next::Message(message)) => {
+            let data: serde_json::Value = serde_json::from_str(&message.data)
+                .map_err(|err| mlua::Error::RuntimeError(format!("nitr.pubsub: {err}")))?;
+            table.set("topic", &*message.topic)?;
+            table.set("data", lua.to_value(&data)?)?;
+        }
+        Some(Next::Lagged(missed)) => table.set("lagged", missed)?,
+        Some(Next::Closed) | None => return Ok(Value::Nil),
+    }
+    Ok(Value::Table(table))
+}
+
+/// Waits on a subscription from Lua: `sub:next(timeout?)` and the
+/// iterator call share it.
+async fn receive(lua: Lua, sub: &Subscription, timeout: Option<Duration>) -> mlua::Result<Value> {
+    let (rx, missed) = (sub.rx.clone(), sub.missed.clone());
+    let next = Subscription::next(&mut *rx.lock().await, &missed, timeout).await;
+    // Waiting for a message is idle time, not handler work.
+    crate::utils::grant_budget(&lua);
+    next_to_lua(&lua, next)
+}
+
+impl UserData for Subscription {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("next", |lua, this, timeout: Option<f64>| async move {
+            let timeout = timeout
+                .map(|secs| {
+                    Duration::try_from_secs_f64(secs).map_err(|_| {
+                        mlua::Error::RuntimeError(format!(
+                            "timeout must be a non-negative number of seconds, got {secs}"
+                        ))
+                    })
+                })
+                .transpose()?;
+            receive(lua, &this, timeout).await
+        });
+        // Stops new messages; those already buffered are still returned.
+        methods.add_method("close", |_, this, ()| {
+            this.hub.unsubscribe(this.id);
+            this.rx.try_lock().map(|mut rx| rx.close()).ok();
+            Ok(())
+        });
+        // `for msg in sub do ... end`: the generic `for` calls the
+        // subscription for each message, and stops at nil.
+        methods.add_async_meta_method(
+            MetaMethod::Call,
+            |lua, this, _: mlua::MultiValue| async move { receive(lua, &this, None).await },
+        );
+    }
+}
+
+/// Reads the patterns: one, or a list.
+fn patterns(value: Value) -> mlua::Result<Box<[Pattern]>> {
+    let names: Vec<String> = match value {
+        Value::String(name) => vec![name.to_str()?.to_owned()],
+        Value::Table(list) => list
+            .sequence_values::<String>()
+            .collect::<mlua::Result<_>>()?,
+        other => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "expected a topic pattern or a list of them, got {}",
+                other.type_name()
+            )));
+        }
+    };
+    if names.is_empty() || names.len() > MAX_PATTERNS {
+        return Err(mlua::Error::RuntimeError(format!(
+            "a subscription names 1 to {MAX_PATTERNS} patterns, got {}",
+            names.len()
+        )));
+    }
+    names
+        .iter()
+        .map(|name| Pattern::parse(name).map_err(mlua::Error::RuntimeError))
+        .collect()
+}
+
+/// Builds the `nitr.pubsub` table for one state over the shared hub.
+pub(crate) fn create_pubsub_table(lua: &Lua, pubsub: PubSub) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+
+    let hub = pubsub.clone();
+    table.set(
+        "publish",
+        lua.create_function(move |_, (topic, data): (String, Value)| {
+            crate::utils::check_json_depth(&data)?;
+            let json = serde_json::to_string(&data).map_err(|err| {
+                mlua::Error::RuntimeError(format!(
+                    "pubsub messages must be plain data (a table, string, number or \
+                     boolean); the one to `{topic}` is not serializable: {err}"
+                ))
+            })?;
+            hub.publish(&topic, json).map_err(mlua::Error::RuntimeError)
+        })?,
+    )?;
+
+    let hub = pubsub.clone();
+    table.set(
+        "subscribe",
+        lua.create_function(move |lua, (topics, opts): (Value, Option<Table>)| {
+            let on_full = match opts
+                .map(|o| o.get::<Option<String>>("on_full"))
+                .transpose()?
+            {
+                Some(Some(name)) => SlowSubscriber::parse(&name)?,
+                _ => hub.hub.opts.on_full,
+            };
+            let sub = hub.subscribe(patterns(topics)?, on_full)?;
+            lua.create_userdata(sub)
+        })?,
+    )?;
+
+    table.set(
+        "stats",
+        lua.create_function(move |lua, ()| pubsub.stats(lua))?,
+    )?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mlua::AnyUserData;
+
+    fn hub(buffer: usize, on_full: SlowSubscriber) -> PubSub {
+        PubSub::new(PubSubOptions {
+            buffer,
+            on_full,
+            ..Default::default()
+        })
+    }
+
+    fn lua_with(pubsub: &PubSub) -> Lua {
+        let lua = Lua::new();
+        lua.globals()
+            .set(
+                "pubsub",
+                create_pubsub_table(&lua, pubsub.clone()).expect("pubsub"),
+            )
+            .expect("global");
+        lua
+    }
+
+    #[test]
+    fn patterns_match_whole_segments() {
+        let cases = [
+            ("chat.room1", "chat.room1", true),
+            ("chat.room1", "chat.room2", false),
+            ("chat.*", "chat.room1", true),
+            ("chat.*", "chat", false),
+            ("chat.*", "chat.room1.typing", false),
+            ("*.created", "orders.created", true),
+            ("orders.**", "orders.42.paid", true),
+            ("orders.**", "orders.42", true),
+            ("orders.**", "orders", false),
+            ("**", "anything.at.all", true),
+        ];
+        for (pattern, topic, expected) in cases {
+            let parsed = Pattern::parse(pattern).expect(pattern);
+            assert_eq!(parsed.matches(topic), expected, "{pattern} vs {topic}");
+        }
+        for bad in [
+            "",
+            "chat..room",
+            "chat.**.typing",
+            "chat.ro*m",
+            &"x".repeat(300),
+        ] {
+            assert!(Pattern::parse(bad).is_err(), "{bad:?} should be refused");
+        }
+        for bad in ["chat.*", "chat.", ".chat"] {
+            assert!(check_topic(bad).is_err(), "{bad:?} is not a topic");
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_in_other_states_receive_decoded_copies() {
+        let pubsub = hub(8, SlowSubscriber::Drop);
+        let (reader, writer) = (lua_with(&pubsub), lua_with(&pubsub));
+        let sub: AnyUserData = reader
+            .load(r#"return pubsub.subscribe({ "chat.*", "alerts" })"#)
+            .eval()
+            .expect("subscribe");
+        reader.globals().set("sub", &sub).expect("global");
+
+        let delivered: (usize, usize) = writer
+            .load(
+                r#"return pubsub.publish("chat.room1", { text = "hi", n = 1 }),
+                          pubsub.publish("orders.created", 42)"#,
+            )
+            .eval()
+            .expect("publish");
+        assert_eq!(delivered, (1, 0));
+        writer
+            .load(r#"pubsub.publish("alerts", "disk")"#)
+            .exec()
+            .expect("publish");
+
+        let (topic, text, alert, idle): (String, String, String, Value) = reader
+            .load(
+                r#"
+                local first
+                for msg in sub do
+                    if first then return first.topic, first.data.text, msg.data, sub:next(0.01) end
+                    first = msg
+                end
+                "#,
+            )
+            .eval_async()
+            .await
+            .expect("receive");
+        assert_eq!(
+            (topic.as_str(), text.as_str(), alert.as_str()),
+            ("chat.room1", "hi", "disk")
+        );
+        assert!(idle.is_nil(), "a timeout yields nil");
+
+        let err = writer
+            .load(r#"pubsub.publish("chat.room1", function() end)"#)
+            .exec()
+            .expect_err("a function is not data");
+        assert!(err.to_string().contains("plain data"), "{err}");
+        let err = writer
+            .load(r#"pubsub.publish("chat.*", 1)"#)
+            .exec()
+            .expect_err("a pattern is not a topic");
+        assert!(err.to_string().contains("not a pattern"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_lose_messages_or_their_subscription() {
+        let pubsub = hub(2, SlowSubscriber::Drop);
+        let lua = lua_with(&pubsub);
+        lua.load(
+            r#"
+            lagging = pubsub.subscribe("feed")
+            cut = pubsub.subscribe("feed", { on_full = "disconnect" })
+            for i = 1, 5 do pubsub.publish("feed", i) end
+            "#,
+        )
+        .exec()
+        .expect("publish");
+
+        let (a, b, gap, after): (i64, i64, u64, Value) = lua
+            .load(
+                r#"
+                local a, b, gap = lagging:next(), lagging:next(), lagging:next()
+                return a.data, b.data, gap.lagged, lagging:next(0.01)
+                "#,
+            )
+            .eval_async()
+            .await
+            .expect("drop");
+        assert_eq!((a, b, gap), (1, 2, 3));
+        assert!(after.is_nil());
+
+        // The disconnected one keeps what was buffered, then ends.
+        let seen: Vec<i64> = lua
+            .load("local seen = {} for msg in cut do seen[#seen + 1] = msg.data end return seen")
+            .eval_async()
+            .await
+            .expect("disconnect");
+        assert_eq!(seen, [1, 2]);
+
+        let stats: Table = lua.load("return pubsub.stats()").eval().expect("stats");
+        assert_eq!(stats.get::<u64>("subscribers").expect("n"), 1);
+        assert_eq!(stats.get::<u64>("dropped").expect("n"), 3);
+        assert_eq!(stats.get::<u64>("disconnected").expect("n"), 1);
+    }
+
+    #[tokio::test]
+    async fn closing_ends_subscriptions_and_collecting_them_unsubscribes() {
+        let pubsub = hub(8, SlowSubscriber::Drop);
+        let lua = lua_with(&pubsub);
+        let sub: AnyUserData = lua
+            .load(r#"return pubsub.subscribe("feed")"#)
+            .eval()
+            .expect("subscribe");
+        assert_eq!(pubsub.subscribers().len(), 1);
+        drop(sub);
+        lua.gc_collect().expect("gc");
+        lua.gc_collect().expect("gc");
+        assert_eq!(pubsub.subscribers().len(), 0);
+
+        lua.load(r#"sub = pubsub.subscribe("feed")"#)
+            .exec()
+            .expect("subscribe");
+        let waiting = lua.load("return sub:next()").eval_async::<Value>();
+        let closer = async {
+            tokio::task::yield_now().await;
+            pubsub.close();
+        };
+        let (ended, ()) = tokio::join!(waiting, closer);
+        assert!(
+            ended.expect("next").is_nil(),
+            "a closed hub ends the iterator"
+        );
+        let err = lua
+            .load(r#"pubsub.subscribe("feed")"#)
+            .exec()
+            .expect_err("closed");
+        assert!(err.to_string().contains("shutting down"), "{err}");
+    }
+}
@@ -0,0 +1,562 @@
+//! Signed-cookie sessions: `nitr.session(req, { secret = ... })` loads the
+//! session table, fields are plain Lua assignments, and
+//! `session:save(resp)` writes the session back.
+//!
+//! By default the entire session lives in the cookie — no store to
+//! provision, no eviction, no coherence problem across processes. The
+//! consequences are stated plainly rather than glossed over: the session is
+//! bounded to a few kilobytes, and it cannot be invalidated server-side
+//! before its cookie expires (rotate the secret to invalidate everything at
+//! once). `secret = nitr.keys` signs with the `[keys]` ring instead, so a
+//! key rotation keeps the sessions signed under the keys still listed.
+//!
+//! `store = "db"` or `store = "cache"` trades that for a stored session: the
+//! cookie carries only a signed random token, the data lives server-side
+//! with an idle TTL, and `nitr.session.revoke(id)` /
+//! `nitr.session.revoke_all(user)` end sessions before their cookie does.
+//! The Lua API is the same in both modes.
+
+use std::sync::{Arc, Mutex};
+
+use mlua::{Lua, MetaMethod, ObjectLike as _, Table, Value};
+
+use crate::keys::Secret;
+use crate::{Cache, http};
+
+mod store;
+
+use store::{Record, Store};
+
+/// Ceiling on the serialized session, chosen so the signed, base64-encoded
+/// cookie stays under the ~4 KiB browsers enforce per cookie.
+const MAX_SESSION_JSON: usize = 2800;
+
+/// Ceiling on a stored session: no cookie to fit, but still one row read
+/// on every request.
+const MAX_STORED_JSON: usize = 64 * 1024;
+
+/// Idle lifetime of a stored session when `ttl` is not given: a week.
+const DEFAULT_TTL: i64 = 7 * 24 * 60 * 60;
+
+/// Method names reserved on the session object; data under these names
+/// would shadow the methods, so they are rejected rather than saved.
+const RESERVED: &[&str] = &["save", "clear", "regenerate", "id"];
+
+/// Cookie attributes for a session cookie: HttpOnly always (a session is
+/// server state, scripts in the page have no business reading it),
+/// site-wide, SameSite=Lax; the caller's `cookie` options may extend but
+/// not un-HttpOnly it.
+fn cookie_opts(lua: &Lua, base: Option<&Table>, max_age: Option<i64>) -> mlua::Result<Table> {
+    let opts = lua.create_table()?;
+    opts.set("path", "/")?;
+    opts.set("same_site", "Lax")?;
+    if let Some(base) = base {
+        for pair in base.pairs::<Value, Value>() {
+            let (k, v) = pair?;
+            opts.set(k, v)?;
+        }
+    }
+    opts.set("http_only", true)?;
+    if let Some(max_age) = max_age {
+        opts.set("max_age", max_age)?;
+    }
+    Ok(opts)
+}
+
+/// Copies the verified cookie payload (a JSON object) into `session`.
+fn load_into(lua: &Lua, session: &Table, payload: &str) -> mlua::Result<()> {
+    let Ok(serde_json::Value::Object(map)) = serde_json::from_str::<serde_json::Value>(payload)
+    else {
+        // A cookie that verifies but does not decode was produced by an
+        // older secret sharing the name, or by tooling; start empty.
+        return Ok(());
+    };
+    for (key, value) in map {
+        use mlua::LuaSerdeExt as _;
+        session.set(key, lua.to_value(&value)?)?;
+    }
+    Ok(())
+}
+
+/// Serializes the session's own fields to JSON, rejecting values that
+/// cannot be stored, names that would shadow the methods, and sessions over
+/// their mode's size ceiling.
+fn serialize(session: &Table, stored: bool) -> mlua::Result<String> {
+    for reserved in RESERVED {
+        if !session.raw_get::<Value>(*reserved)?.is_nil() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "`{reserved}` is reserved on a session (it is a method name)"
+            )));
+        }
+    }
+    let session = Value::Table(session.clone());
+    crate::utils::check_json_depth(&session)?;
+    let json = serde_json::to_string(&session).map_err(|err| {
+        mlua::Error::RuntimeError(format!(
+            "session values must be JSON-serializable (strings, numbers, booleans, tables): {err}"
+        ))
+    })?;
+    if stored && json.len() > MAX_STORED_JSON {
+        return Err(mlua::Error::RuntimeError(format!(
+            "the session is {} bytes serialized; stored sessions are bounded to \
+             {MAX_STORED_JSON} bytes — keep large data in its own table",
+            json.len()
+        )));
+    }
+    if !stored && json.len() > MAX_SESSION_JSON {
+        return Err(mlua::Error::RuntimeError(format!(
+            "the session is {} bytes serialized; the whole session travels in a signed \
+             cookie, which bounds it to {MAX_SESSION_JSON} bytes — store a key here and \
+             the data in the database, or use `store = \"db\"`",
+            json.len()
+        )));
+    }
+    Ok(json)
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// A user id as `revoke_all` matches it: strings and integers alike.
+fn user_key(value: &Value) -> mlua::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.to_str()?.to_owned()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Number(n) if n.fract() == 0.0 => Ok((*n as i64).to_string()),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "a session user must be a string or an integer, got {}",
+            other.type_name()
+        ))),
+    }
+}
+
+/// Everything a stored session tracks besides its fields.
+#[derive(Default)]
+struct Stored {
+    /// The cookie token; `None` until the first save mints one.
+    token: Option<String>,
+    /// The owner bound by `regenerate(user)`.
+    user: Option<String>,
+    /// The key a `regenerate()` retired, deleted on the next save.
+    retired: Option<String>,
+}
+
+impl Stored {
+    fn key(&self) -> Option<String> {
+        self.token.as_deref().map(store::key_of)
+    }
+}
+
+/// The per-call settings shared by the session's methods.
+struct Settings {
+    name: String,
+    secret: Secret,
+    max_age: Option<i64>,
+    cookie: Option<Table>,
+    /// `None` for a cookie session.
+    store: Option<Store>,
+    ttl: i64,
+    sliding: bool,
+}
+
+/// Loads the stored session the request's cookie points at, extending its
+/// expiry when `sliding` is on and a tenth of the TTL has passed since the
+/// last extension — a write per request would be the price otherwise.
+async fn load_stored(
+    lua: &Lua,
+    settings: &Settings,
+    store: &Store,
+    session: &Table,
+    token: String,
+) -> mlua::Result<Stored> {
+    let now = unix_now();
+    let key = store::key_of(&token);
+    let Some(mut record) = store.load(lua, &key, now).await? else {
+        // Unknown or expired: a fresh session under a fresh token, never
+        // one under the token the client chose.
+        return Ok(Stored::default());
+    };
+    load_into(lua, session, &record.data)?;
+    if settings.sliding && record.expires_at - now < settings.ttl - settings.ttl / 10 {
+        record.expires_at = now + settings.ttl;
+        store.put(lua, &key, &record, now).await?;
+    }
+    Ok(Stored {
+        token: Some(token),
+        user: record.user,
+        retired: None,
+    })
+}
+
+async fn save(
+    lua: &Lua,
+    settings: &Settings,
+    stored: &Mutex<Stored>,
+    session: Table,
+    resp: Value,
+) -> mlua::Result<()> {
+    let Value::Table(resp) = resp else {
+        return Err(mlua::Error::RuntimeError(format!(
+            "session:save(resp) takes the response table, got {}",
+            resp.type_name()
+        )));
+    };
+    let json = serialize(&session, settings.store.is_some())?;
+    let empty = json == "{}" || json == "[]";
+    let value = match &settings.store {
+        None => (!empty).then(|| json.clone()),
+        Some(store) => {
+            let now = unix_now();
+            let (token, user, retired, fresh) = {
+                let mut state = stored.lock().map_err(|_| poisoned())?;
+                let fresh = state.token.is_none();
+                if fresh && !empty {
+                    state.token = Some(crate::csrf::new_token()?);
+                }
+                let token = if empty {
+                    state.token.take()
+                } else {
+                    state.token.clone()
+                };
+                (token, state.user.clone(), state.retired.take(), fresh)
+            };
+            if let Some(retired) = retired {
+                store.remove(lua, &retired).await?;
+            }
+            match token {
+                // An empty session is a logout: its row goes with the cookie.
+                Some(token) if empty => {
+                    store.remove(lua, &store::key_of(&token)).await?;
+                    None
+                }
+                Some(token) => {
+                    if fresh {
+                        store.purge(lua, now).await?;
+                    }
+                    let record = Record {
+                        user,
+                        data: json,
+                        expires_at: now + settings.ttl,
+                    };
+                    store.put(lua, &store::key_of(&token), &record, now).await?;
+                    Some(token)
+                }
+                None => None,
+            }
+        }
+    };
+    let cookie = match value {
+        Some(value) => http::build_cookie(
+            &settings.name,
+            &settings.secret.sign(&settings.name, &value),
+            Some(&cookie_opts(
+                lua,
+                settings.cookie.as_ref(),
+                settings.max_age,
+            )?),
+        )?,
+        // An empty session deletes its cookie.
+        None => http::build_cookie(
+            &settings.name,
+            "",
+            Some(&cookie_opts(lua, settings.cookie.as_ref(), Some(0))?),
+        )?,
+    };
+    http::attach_cookie(&resp, cookie)
+}
+
+fn poisoned() -> mlua::Error {
+    mlua::Error::RuntimeError("the session state lock is poisoned".into())
+}
+
+/// `nitr.session(req, opts)`: loads the request's session.
+async fn open(lua: Lua, cache: Option<Cache>, req: Value, opts: Table) -> mlua::Result<Table> {
+    let secret = Secret::from_opts(&opts, "nitr.session")?;
+    let store = match opts.get::<Option<String>>("store")? {
+        Some(name) => Store::from_name(&name, cache.as_ref())?,
+        None => None,
+    };
+    let ttl = opts.get::<Option<i64>>("ttl")?.unwrap_or(DEFAULT_TTL);
+    if ttl <= 0 {
+        return Err(mlua::Error::RuntimeError(
+            "nitr.session `ttl` must be a positive number of seconds".into(),
+        ));
+    }
+    let settings = Arc::new(Settings {
+        name: opts
+            .get::<Option<String>>("name")?
+            .unwrap_or_else(|| "session".into()),
+        secret,
+        max_age: opts.get("max_age")?,
+        cookie: opts.get("cookie")?,
+        store,
+        ttl,
+        sliding: opts.get::<Option<bool>>("sliding")?.unwrap_or(true),
+    });
+
+    let session = lua.create_table()?;
+    let mut stored = Stored::default();
+    if let Value::UserData(ud) = &req
+        && let Ok(cookies) = ud.get::<mlua::AnyUserData>("cookies")
+        && let Some(payload) = cookies
+            .borrow::<http::RequestCookies>()
+            .ok()
+            .and_then(|cookies| {
+                let raw = cookies.get(&settings.name)?;
+                settings.secret.verify(&settings.name, raw)
+            })
+    {
+        match &settings.store {
+            None => load_into(&lua, &session, &payload)?,
+            Some(store) => {
+                stored = load_stored(&lua, &settings, store, &session, payload).await?;
+            }
+        }
+    }
+    let stored = Arc::new(Mutex::new(stored));
+
+    // Methods live on the metatable, so `pairs(session)` (and the
+    // serializer) see only data.
+    let methods = lua.create_table()?;
+    {
+        let (settings, stored) = (settings.clone(), stored.clone());
+        methods.set(
+            "save",
+            lua.create_async_function(move |lua, (session, resp): (Table, Value)| {
+                let (settings, stored) = (settings.clone(), stored.clone());
+                async move { save(&lua, &settings, &stored, session, resp).await }
+            })?,
+        )?;
+    }
+    methods.set(
+        "clear",
+        lua.create_function(|_, session: Table| {
+            let keys: Vec<Value> = session
+                .pairs::<Value, Value>()
+                .map(|pair| pair.map(|(k, _)| k))
+                .collect::<mlua::Result<_>>()?;
+            for key in keys {
+                session.set(key, Value::Nil)?;
+            }
+            Ok(())
+        })?,
+    )?;
+    // Rotation on login, against session fixation: a new token (and id)
+    // now, the old one deleted on save. A cookie session has no id to
+    // fixate, so there it does nothing.
+    {
+        let (settings, stored) = (settings.clone(), stored.clone());
+        methods.set(
+            "regenerate",
+            lua.create_function(move |_, (_, user): (Table, Value)| {
+                if settings.store.is_none() {
+                    return Ok(());
+                }
+                let user = match user {
+                    Value::Nil => None,
+                    user => Some(user_key(&user)?),
+                };
+                let mut state = stored.lock().map_err(|_| poisoned())?;
+                if let Some(key) = state.key() {
+                    state.retired.get_or_insert(key);
+                }
+                state.token = Some(crate::csrf::new_token()?);
+                if user.is_some() {
+                    state.user = user;
+                }
+                Ok(())
+            })?,
+        )?;
+    }
+    methods.set(
+        "id",
+        lua.create_function(move |_, _: Table| Ok(stored.lock().map_err(|_| poisoned())?.key()))?,
+    )?;
+
+    let meta = lua.create_table()?;
+    meta.set("__index", methods)?;
+    session.set_metatable(Some(meta))?;
+    Ok(session)
+}
+
+/// The store `revoke` and `revoke_all` act on: `opts.store`, by default
+/// the database.
+fn revoke_store(opts: Option<Table>, cache: Option<&Cache>) -> mlua::Result<Store> {
+    let name = match opts {
+        Some(opts) => opts.get::<Option<String>>("store")?,
+        None => None,
+    };
+    Store::from_name(name.as_deref().unwrap_or("db"), cache)?.ok_or_else(|| {
+        mlua::Error::RuntimeError(
+            "cookie sessions cannot be revoked: only the secret ends them early — use a \
+             stored session (`store = \"db\"`)"
+                .into(),
+        )
+    })
+}
+
+/// Builds the `nitr.session` table: callable to load a session, with
+/// `revoke(id, opts?)` and `revoke_all(user, opts?)` ending stored ones.
+pub(crate) fn create_session_table(lua: &Lua, cache: Option<Cache>) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    {
+        let cache = cache.clone();
+        table.set(
+            "revoke",
+            lua.create_async_function(move |lua, (id, opts): (String, Option<Table>)| {
+                let store = revoke_store(opts, cache.as_ref());
+                async move { store?.remove(&lua, &id).await }
+            })?,
+        )?;
+    }
+    {
+        let cache = cache.clone();
+        table.set(
+            "revoke_all",
+            lua.create_async_function(move |lua, (user, opts): (Value, Option<Table>)| {
+                let target = revoke_store(opts, cache.as_ref())
+                    .and_then(|store| Ok((store, user_key(&user)?)));
+                async move {
+                    let (store, user) = target?;
+                    store.remove_user(&lua, &user).await
+                }
+            })?,
+        )?;
+    }
+
+    let meta = lua.create_table()?;
+    meta.set(
+        MetaMethod::Call.name(),
+        lua.create_async_function(move |lua, (_, req, opts): (Table, Value, Table)| {
+            open(lua, cache.clone(), req, opts)
+        })?,
+    )?;
+    table.set_metatable(Some(meta))?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_session(lua: &Lua, opts: &str) -> Table {
+        let session = create_session_table(lua, None).expect("table");
+        let opts: Table = lua.load(opts).eval().expect("opts");
+        session.call((Value::Nil, opts)).expect("session")
+    }
+
+    /// Session tables are script data serialized to JSON: the depth guard
+    /// must fire before the byte cap can even be measured.
+    #[test]
+    fn save_rejects_a_session_nested_beyond_the_json_depth_bound() {
+        let lua = Lua::new();
+        let session = make_session(&lua, r#"{ secret = "0123456789abcdef" }"#);
+        let mut cur = lua.create_table().expect("table");
+        session.set("deep", cur.clone()).expect("set");
+        for _ in 0..128 {
+            let next = lua.create_table().expect("table");
+            cur.set("x", next.clone()).expect("set");
+            cur = next;
+        }
+        let save: mlua::Function = session.get("save").expect("method");
+        let resp = lua.create_table().expect("resp");
+        let err = save.call::<Value>((session, resp)).expect_err("too deep");
+        assert!(
+            err.to_string().contains("nested deeper than 128 levels"),
+            "got: {err}"
+        );
+    }
+
+    #[test]
+    fn save_rejects_reserved_names_oversize_and_unserializable_values() {
+        let lua = Lua::new();
+        let session = make_session(&lua, r#"{ secret = "0123456789abcdef" }"#);
+        let resp = lua.create_table().expect("resp");
+        let save: mlua::Function = session.get("save").expect("method");
+
+        // A data field named like a method would shadow it forever after.
+        session.set("save2", "ok").expect("set");
+        session.raw_set("clear", "shadowed").expect("set");
+        let err = save
+            .call::<()>((&session, &resp))
+            .expect_err("reserved name");
+        assert!(err.to_string().contains("reserved"), "got: {err}");
+        session.raw_set("clear", Value::Nil).expect("unset");
+
+        // Functions cannot travel in a cookie.
+        let f = lua.create_function(|_, ()| Ok(())).expect("fn");
+        session.set("cb", f).expect("set");
+        let err = save.call::<()>((&session, &resp)).expect_err("function");
+        assert!(err.to_string().contains("JSON-serializable"), "got: {err}");
+        session.set("cb", Value::Nil).expect("unset");
+
+        // The size ceiling reports itself instead of emitting a cookie
+        // browsers will silently drop.
+        session
+            .set("blob", "x".repeat(MAX_SESSION_JSON))
+            .expect("set");
+        let err = save.call::<()>((&session, &resp)).expect_err("oversize");
+        assert!(err.to_string().contains("bytes"), "got: {err}");
+
+        // And save demands the response table, not whatever was handy.
+        let err = save
+            .call::<()>((&session, "not a response"))
+            .expect_err("bad resp");
+        assert!(err.to_string().contains("response table"), "got: {err}");
+    }
+
+    #[test]
+    fn sessions_stay_http_only_and_empty_sessions_expire_the_cookie() {
+        let lua = Lua::new();
+        // A caller trying to un-HttpOnly the cookie loses that argument.
+        let session = make_session(
+            &lua,
+            r#"{ secret = "0123456789abcdef", cookie = { http_only = false, secure = true } }"#,
+        );
+        session.set("user", "ada").expect("set");
+        let resp = lua.create_table().expect("resp");
+        let save: mlua::Function = session.get("save").expect("method");
+        save.call::<()>((&session, &resp)).expect("save");
+        let cookies: mlua::AnyUserData = resp.get("cookies").expect("builder");
+        let values = cookies.borrow::<http::ResponseCookies>().expect("borrow");
+        let cookie = &values.values()[0];
+        assert!(cookie.contains("HttpOnly"), "got: {cookie}");
+        assert!(cookie.contains("Secure"), "got: {cookie}");
+
+        // clear() then save() writes the deletion cookie.
+        let clear: mlua::Function = session.get("clear").expect("method");
+        clear.call::<()>(&session).expect("clear");
+        let resp = lua.create_table().expect("resp");
+        save.call::<()>((&session, &resp)).expect("save");
+        let cookies: mlua::AnyUserData = resp.get("cookies").expect("builder");
+        let values = cookies.borrow::<http::ResponseCookies>().expect("borrow");
+        let cookie = &values.values()[0];
+        assert!(cookie.contains("Max-Age=0"), "got: {cookie}");
+    }
+
+    #[test]
+    fn a_short_secret_is_refused() {
+        let lua = Lua::new();
+        let session_fn = create_session_table(&lua, None).expect("table");
+        for opts in ["{}", r#"{ secret = "short" }"#] {
+            let opts: Table = lua.load(opts).eval().expect("opts");
+            let err = session_fn
+                .call::<Table>((Value::Nil, opts))
+                .expect_err("weak secret");
+            assert!(err.to_string().contains("secret"), "got: {err}");
+        }
+    }
+
+    #[test]
+    fn corrupt_payloads_start_an_empty_session_instead_of_failing() {
+        let lua = Lua::new();
+        let session = lua.create_table().expect("table");
+        // Verified-but-not-JSON (old secret sharing the name, tooling).
+        load_into(&lua, &session, "not json at all").expect("load");
+        load_into(&lua, &session, "[1,2,3]").expect("load");
+        assert_eq!(session.len().expect("len"), 0);
+    }
+}
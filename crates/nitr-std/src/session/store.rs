@@ -0,0 +1,385 @@
+//! Where stored sessions live: `store = "db"` or `store = "cache"`.
+//!
+//! The cookie carries a random token; the store is keyed by the token's
+//! SHA-256, so a leaked table or cache dump holds no usable cookie. That
+//! key is also the session's public id (`session:id()`), the value
+//! `nitr.session.revoke()` takes.
+//!
+//! The database store goes through the state's own `nitr.db` handle, so it
+//! works on SQLite and on Postgres alike, and creates its table on first
+//! use. Inside `db:transaction` its writes wait for the transaction to
+//! end: a session is not part of the transaction's work. The cache store
+//! is bounded, and per process unless the cache is on Redis: a restart or
+//! an eviction logs the user out, which fails closed.
+
+use mlua::{Lua, Table, Value};
+use sha2::{Digest as _, Sha256};
+
+use crate::Cache;
+
+/// The table behind `store = "db"`. The `_nitr_` prefix keeps it out of
+/// `nitr.db:changes()`.
+const TABLE: &str = "_nitr_sessions";
+
+/// Cache keys of the sessions behind `store = "cache"`.
+const CACHE_PREFIX: &str = "nitr:session:";
+
+/// One stored session.
+pub(crate) struct Record {
+    /// The owner bound by `session:regenerate(user)`, for `revoke_all`.
+    pub(crate) user: Option<String>,
+    /// The session fields, as a JSON object.
+    pub(crate) data: String,
+    /// Unix seconds after which the session is gone.
+    pub(crate) expires_at: i64,
+}
+
+/// Set once the session table exists, so each state checks only once.
+#[cfg(feature = "db")]
+struct TableReady;
+
+pub(crate) enum Store {
+    Db,
+    Cache(Cache),
+}
+
+impl Store {
+    /// The store named by a `store` option; `None` for cookie sessions.
+    pub(crate) fn from_name(name: &str, cache: Option<&Cache>) -> mlua::Result<Option<Self>> {
+        match name {
+            "cookie" => Ok(None),
+            "db" => Ok(Some(Store::Db)),
+            "cache" => match cache {
+                Some(cache) => Ok(Some(Store::Cache(cache.clone()))),
+                None => Err(mlua::Error::RuntimeError(
+                    "session store \"cache\" needs the `cache` std feature".into(),
+                )),
+            },
+            other => Err(mlua::Error::RuntimeError(format!(
+                "unknown session store `{other}`: expected \"cookie\", \"db\" or \"cache\""
+            ))),
+        }
+    }
+
+    /// The live session under `key`, if there is one.
+    pub(crate) async fn load(
+        &self,
+        lua: &Lua,
+        key: &str,
+        now: i64,
+    ) -> mlua::Result<Option<Record>> {
+        match self {
+            Store::Db => {
+                let rows = query(
+                    lua,
+                    format!(
+                        "SELECT user_id, data, expires_at FROM {TABLE} \
+                         WHERE id = ? AND expires_at > ?"
+                    ),
+                    [Value::String(lua.create_string(key)?), Value::Integer(now)],
+                )
+                .await?;
+                let Some(row) = rows.get::<Option<Table>>(1)? else {
+                    return Ok(None);
+                };
+                Ok(Some(Record {
+                    user: row.get("user_id")?,
+                    data: row.get("data")?,
+                    expires_at: row.get("expires_at")?,
+                }))
+            }
+            Store::Cache(cache) => {
+                let Some(bytes) = cache.get_raw(&cache_key(key)).await? else {
+                    return Ok(None);
+                };
+                let Ok(value) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+                    return Ok(None);
+                };
+                let record = Record {
+                    user: value["user"].as_str().map(str::to_owned),
+                    data: value["data"].as_str().unwrap_or("{}").to_owned(),
+                    expires_at: value["expires_at"].as_i64().unwrap_or(0),
+                };
+                Ok((record.expires_at > now).then_some(record))
+            }
+        }
+    }
+
+    /// Writes the session under `key`, replacing what was there.
+    pub(crate) async fn put(
+        &self,
+        lua: &Lua,
+        key: &str,
+        record: &Record,
+        now: i64,
+    ) -> mlua::Result<()> {
+        match self {
+            Store::Db => {
+                // NULLIF rather than a nil parameter: nils do not survive
+                // the trip through a Lua parameter table.
+                let sql = format!(
+                    "INSERT INTO {TABLE} (id, user_id, data, expires_at) \
+                     VALUES (?, NULLIF(?, ''), ?, ?) \
+                     ON CONFLICT (id) DO UPDATE SET user_id = excluded.user_id, \
+                     data = excluded.data, expires_at = excluded.expires_at"
+                );
+                let values = [
+                    Value::String(lua.create_string(key)?),
+                    Value::String(lua.create_string(record.user.as_deref().unwrap_or(""))?),
+                    Value::String(lua.create_string(&record.data)?),
+                    Value::Integer(record.expires_at),
+                ];
+                execute(lua, sql, values).await?;
+                Ok(())
+            }
+            Store::Cache(cache) => {
+                let value = serde_json::json!({
+                    "user": record.user,
+                    "data": record.data,
+                    "expires_at": record.expires_at,
+                });
+                let ttl = (record.expires_at - now).max(1) as u64;
+                cache
+                    .set_raw(cache_key(key), value.to_string().into_bytes(), Some(ttl))
+                    .await
+            }
+        }
+    }
+
+    /// Deletes the session under `key`, returning whether it existed.
+    pub(crate) async fn remove(&self, lua: &Lua, key: &str) -> mlua::Result<bool> {
+        match self {
+            Store::Db => {
+                let id = Value::String(lua.create_string(key)?);
+                let sql = format!("DELETE FROM {TABLE} WHERE id = ?");
+                match execute(lua, sql, [id.clone()]).await? {
+                    Some(deleted) => Ok(deleted > 0),
+                    // Held back by a transaction: what the delete will find.
+                    None => {
+                        let sql = format!("SELECT id FROM {TABLE} WHERE id = ?");
+                        Ok(query(lua, sql, [id]).await?.raw_len() > 0)
+                    }
+                }
+            }
+            Store::Cache(cache) => cache.remove(&cache_key(key)).await,
+        }
+    }
+
+    /// Deletes every session bound to `user`, returning how many.
+    pub(crate) async fn remove_user(&self, lua: &Lua, user: &str) -> mlua::Result<i64> {
+        match self {
+            Store::Db => {
+                let user = Value::String(lua.create_string(user)?);
+                let sql = format!("DELETE FROM {TABLE} WHERE user_id = ?");
+                match execute(lua, sql, [user.clone()]).await? {
+                    Some(deleted) => Ok(deleted),
+                    None => {
+                        let sql = format!("SELECT id FROM {TABLE} WHERE user_id = ?");
+                        Ok(query(lua, sql, [user]).await?.raw_len() as i64)
+                    }
+                }
+            }
+            // A scan rather than a per-user index: an index entry can be
+            // evicted while the sessions it lists survive it.
+            Store::Cache(cache) => {
+                let removed = cache
+                    .remove_matching(CACHE_PREFIX, |bytes| {
+                        serde_json::from_slice::<serde_json::Value>(bytes)
+                            .is_ok_and(|value| value["user"].as_str() == Some(user))
+                    })
+                    .await?;
+                Ok(removed as i64)
+            }
+        }
+    }
+
+    /// Drops expired rows. The cache expires its own entries.
+    pub(crate) async fn purge(&self, lua: &Lua, now: i64) -> mlua::Result<()> {
+        if let Store::Db = self {
+            let sql = format!("DELETE FROM {TABLE} WHERE expires_at <= ?");
+            execute(lua, sql, [Value::Integer(now)]).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The store key of a cookie token: its SHA-256, in hex.
+pub(crate) fn key_of(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn cache_key(key: &str) -> String {
+    format!("{CACHE_PREFIX}{key}")
+}
+
+/// Runs a write on `nitr.db`, returning the rows it changed — or `None`
+/// when an open transaction holds it back until it ends.
+#[cfg(feature = "db")]
+async fn execute<const N: usize>(
+    lua: &Lua,
+    sql: String,
+    values: [Value; N],
+) -> mlua::Result<Option<i64>> {
+    use crate::db::{QueryKind, When};
+    let db = database(lua).await?;
+    let params = params(lua, values)?;
+    let changed = crate::db::internal(
+        lua,
+        &db,
+        When::AfterTransaction,
+        QueryKind::Execute,
+        sql,
+        params,
+    );
+    match changed.await? {
+        Some(Value::Integer(changed)) => Ok(Some(changed)),
+        _ => Ok(None),
+    }
+}
+
+/// Runs a read on `nitr.db`; reads run at once, transaction or not.
+#[cfg(feature = "db")]
+async fn query<const N: usize>(lua: &Lua, sql: String, values: [Value; N]) -> mlua::Result<Table> {
+    use crate::db::{QueryKind, When};
+    let db = database(lua).await?;
+    let params = params(lua, values)?;
+    match crate::db::internal(lua, &db, When::Now, QueryKind::Query, sql, params).await? {
+        Some(Value::Table(rows)) => Ok(rows),
+        _ => lua.create_table(),
+    }
+}
+
+#[cfg(not(feature = "db"))]
+async fn execute<const N: usize>(_: &Lua, _: String, _: [Value; N]) -> mlua::Result<Option<i64>> {
+    Err(no_database())
+}
+
+#[cfg(not(feature = "db"))]
+async fn query<const N: usize>(_: &Lua, _: String, _: [Value; N]) -> mlua::Result<Table> {
+    Err(no_database())
+}
+
+#[cfg(feature = "db")]
+fn params<const N: usize>(
+    lua: &Lua,
+    values: [Value; N],
+) -> mlua::Result<Vec<crate::db::types::SqlValue>> {
+    crate::db::types::params_from_table(Some(&lua.create_sequence_from(values)?))
+}
+
+fn no_database() -> mlua::Error {
+    mlua::Error::RuntimeError(
+        "session store \"db\" needs the `db` builtin and a [database] section".into(),
+    )
+}
+
+/// The state's `nitr.db` handle, with the session table created on first
+/// use.
+#[cfg(feature = "db")]
+async fn database(lua: &Lua) -> mlua::Result<mlua::AnyUserData> {
+    let Value::UserData(db) = lua.globals().get::<Table>("nitr")?.get::<Value>("db")? else {
+        return Err(no_database());
+    };
+    if lua.app_data_ref::<TableReady>().is_none() {
+        // Two states (or processes) may race to create the table; on
+        // Postgres the loser can fail even with IF NOT EXISTS, and a second
+        // attempt then finds the table in place.
+        if create_table(lua, &db).await.is_err() {
+            create_table(lua, &db).await?;
+        }
+        // A table created inside a transaction goes if it rolls back, so
+        // the next use checks again.
+        if !crate::db::in_transaction(&db)? {
+            lua.set_app_data(TableReady);
+        }
+    }
+    Ok(db)
+}
+
+#[cfg(feature = "db")]
+async fn create_table(lua: &Lua, db: &mlua::AnyUserData) -> mlua::Result<()> {
+    use crate::db::{QueryKind, When};
+    // Plain types and syntax that SQLite and Postgres read the same way.
+    for sql in [
+        format!(
+            "CREATE TABLE IF NOT EXISTS {TABLE} (
+                 id         TEXT PRIMARY KEY,
+                 user_id    TEXT,
+                 data       TEXT NOT NULL,
+                 expires_at BIGINT NOT NULL
+             )"
+        ),
+        format!("CREATE INDEX IF NOT EXISTS {TABLE}_user ON {TABLE} (user_id)"),
+        format!("CREATE INDEX IF NOT EXISTS {TABLE}_expires ON {TABLE} (expires_at)"),
+    ] {
+        crate::db::internal(lua, db, When::Now, QueryKind::Execute, sql, vec![]).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CacheOptions;
+
+    #[test]
+    fn keys_are_the_token_digest() {
+        let key = key_of("token");
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, key_of("token"));
+        assert_ne!(key, key_of("token2"));
+    }
+
+    #[tokio::test]
+    async fn the_cache_store_expires_and_revokes_by_user() {
+        let lua = Lua::new();
+        let store = Store::Cache(Cache::new(CacheOptions::default()));
+        let record = |user: &str, expires_at| Record {
+            user: Some(user.into()),
+            data: r#"{"n":1}"#.into(),
+            expires_at,
+        };
+        store
+            .put(&lua, "a", &record("ada", 200), 100)
+            .await
+            .expect("put");
+        store
+            .put(&lua, "b", &record("ada", 200), 100)
+            .await
+            .expect("put");
+        store
+            .put(&lua, "c", &record("bob", 200), 100)
+            .await
+            .expect("put");
+
+        let loaded = store
+            .load(&lua, "a", 100)
+            .await
+            .expect("load")
+            .expect("live");
+        assert_eq!(loaded.user.as_deref(), Some("ada"));
+        assert_eq!(loaded.data, r#"{"n":1}"#);
+        // Past its expiry a record reads as absent, whatever the cache says.
+        assert!(store.load(&lua, "a", 200).await.expect("load").is_none());
+
+        assert_eq!(store.remove_user(&lua, "ada").await.expect("revoke"), 2);
+        assert!(store.load(&lua, "b", 100).await.expect("load").is_none());
+        assert!(store.remove(&lua, "c").await.expect("remove"));
+        assert!(!store.remove(&lua, "c").await.expect("remove"));
+    }
+
+    #[test]
+    fn unknown_and_unavailable_stores_are_named() {
+        assert!(matches!(Store::from_name("cookie", None), Ok(None)));
+        let err = Store::from_name("cache", None).err().expect("no cache");
+        assert!(err.to_string().contains("`cache`"), "got: {err}");
+        let err = Store::from_name("redis", None).err().expect("unknown");
+        assert!(
+            err.to_string().contains("unknown session store"),
+            "got: {err}"
+        );
+    }
+}
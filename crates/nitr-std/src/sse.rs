@@ -0,0 +1,501 @@
+//! `nitr.sse.channel`: Server-Sent Events fanned out by Rust.
+//!
+//! A `nitr.sse(function(send) ... end)` stream keeps its Lua state checked
+//! out for as long as the client stays connected, which is why
+//! `max_streams` exists. A channel stream does not: the handler returns
+//! `nitr.sse.channel(name)`, the server hands the connection to the
+//! channel and the state goes straight back to the pool. From then on Rust
+//! alone writes to the client: the events `nitr.sse.publish` sends to the
+//! channel, and a comment line every `heartbeat` so proxies keep the
+//! connection open.
+//!
+//! Each channel keeps its last `history` events, so a client that
+//! reconnects with `Last-Event-ID` gets what it missed (as far back as the
+//! history goes). A client too slow to keep up with the channel is
+//! disconnected rather than buffered for; its browser reconnects and
+//! resumes from the history. Event ids carry the hub's start time, so an
+//! id from before a restart replays the whole history instead of nothing,
+//! and one sequence runs across every channel, so an id from before a
+//! channel was forgotten and recreated is older than all its new events.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use mlua::{Lua, Table, UserData, Value};
+use tokio::sync::broadcast;
+
+/// The longest channel name, in bytes.
+const MAX_NAME_BYTES: usize = 256;
+
+/// What a client receives while nothing is published.
+const HEARTBEAT: &[u8] = b": keepalive\n\n";
+
+/// Limits for the channel hub.
+#[derive(Debug, Clone)]
+pub struct SseOptions {
+    /// Events each channel keeps for `Last-Event-ID` replay.
+    pub history: usize,
+    /// Events a subscriber may fall behind before it is disconnected.
+    pub buffer: usize,
+    /// Idle time after which a comment line is sent.
+    pub heartbeat: Duration,
+    /// Most channels held at once; past this the longest-idle channel
+    /// nobody is subscribed to is forgotten.
+    pub max_channels: usize,
+    /// Most clients subscribed at once, across every channel.
+    pub max_subscribers: usize,
+}
+
+impl Default for SseOptions {
+    fn default() -> Self {
+        Self {
+            history: 100,
+            buffer: 64,
+            heartbeat: Duration::from_secs(15),
+            max_channels: 1_000,
+            max_subscribers: 10_000,
+        }
+    }
+}
+
+/// One published event, already in its wire form.
+#[derive(Debug)]
+struct Event {
+    seq: u64,
+    frame: Bytes,
+}
+
+struct Channel {
+    tx: broadcast::Sender<Arc<Event>>,
+    history: VecDeque<Arc<Event>>,
+    last_used: Instant,
+}
+
+struct Hub {
+    opts: SseOptions,
+    /// Prefixes every event id, so ids from an earlier process are
+    /// recognized as such.
+    epoch: u64,
+    /// The next event's sequence number, shared by every channel so a
+    /// recreated channel never reuses an id.
+    next_seq: AtomicU64,
+    channels: Mutex<HashMap<Arc<str>, Channel>>,
+    subscribers: AtomicUsize,
+    /// Set once the server drains: streams end, new ones are refused.
+    closed: AtomicBool,
+}
+
+/// The channels behind `nitr.sse.channel`/`nitr.sse.publish`. Built once
+/// by the server and handed to every state.
+#[derive(Clone)]
+pub struct SseHub {
+    hub: Arc<Hub>,
+}
+
+impl std::fmt::Debug for SseHub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SseHub")
+            .field("opts", &self.hub.opts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SseHub {
+    /// A hub with no channels.
+    pub fn new(opts: SseOptions) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Self {
+            hub: Arc::new(Hub {
+                opts,
+                epoch,
+                next_seq: AtomicU64::new(1),
+                channels: Mutex::new(HashMap::new()),
+                subscribers: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Sends an event to every client of `channel` and records it in the
+    /// channel's history, returning its id. `data` goes out verbatim, one
+    /// `data:` line per line.
+    pub fn publish(&self, channel: &str, event: &str, data: &str) -> Result<String, String> {
+        check_name(channel)?;
+        if event.is_empty() || event.contains(['\r', '\n']) {
+            return Err(format!(
+                "an event name must be a non-empty single line, got {event:?}"
+            ));
+        }
+        let mut channels = self.channels();
+        let channel = self.channel(&mut channels, channel)?;
+        let seq = self.hub.next_seq.fetch_add(1, Ordering::Relaxed);
+        channel.last_used = Instant::now();
+        let id = self.id(seq);
+        let event = Arc::new(Event {
+            seq,
+            frame: frame(&id, event, data),
+        });
+        if channel.history.len() >= self.hub.opts.history {
+            channel.history.pop_front();
+        }
+        if self.hub.opts.history > 0 {
+            channel.history.push_back(event.clone());
+        }
+        // Sent under the lock, so a subscriber joining now sees the event
+        // either in its replay or on its receiver, never both or neither.
+        let _ = channel.tx.send(event);
+        Ok(id)
+    }
+
+    /// Ends every channel stream and refuses new ones.
+    pub fn close(&self) {
+        self.hub.closed.store(true, Ordering::Release);
+        self.channels().clear();
+    }
+
+    /// Joins `channel`, replaying what the history holds after
+    /// `last_event_id`.
+    fn subscribe(&self, name: &str, last_event_id: Option<&str>) -> Result<ChannelStream, String> {
+        check_name(name)?;
+        let mut channels = self.channels();
+        if self.hub.closed.load(Ordering::Acquire) {
+            return Err("the server is shutting down".into());
+        }
+        let max = self.hub.opts.max_subscribers;
+        let slot = Slot::take(&self.hub, max).ok_or_else(|| {
+            format!("{max} clients are already subscribed (`[sse] max_subscribers`)")
+        })?;
+        let after = last_event_id.and_then(|id| self.seq(id));
+        let channel = self.channel(&mut channels, name)?;
+        channel.last_used = Instant::now();
+        let replay = match (last_event_id, after) {
+            (None, _) => VecDeque::new(),
+            (Some(_), Some(after)) => channel
+                .history
+                .iter()
+                .filter(|event| event.seq > after)
+                .cloned()
+                .collect(),
+            // An id from another process (or garbage): everything held.
+            (Some(_), None) => channel.history.clone(),
+        };
+        Ok(ChannelStream {
+            replay,
+            rx: channel.tx.subscribe(),
+            heartbeat: self.hub.opts.heartbeat,
+            _slot: slot,
+        })
+    }
+
+    /// The named channel, created if needed. A new channel past
+    /// `max_channels` pushes out the longest-idle one without clients.
+    fn channel<'a>(
+        &self,
+        channels: &'a mut HashMap<Arc<str>, Channel>,
+        name: &str,
+    ) -> Result<&'a mut Channel, String> {
+        if !channels.contains_key(name) {
+            if channels.len() >= self.hub.opts.max_channels {
+                let idle = channels
+                    .iter()
+                    .filter(|(_, channel)| channel.tx.receiver_count() == 0)
+                    .min_by_key(|(_, channel)| channel.last_used)
+                    .map(|(name, _)| name.clone());
+                let Some(idle) = idle else {
+                    return Err(format!(
+                        "all {} channels have clients (`[sse] max_channels`)",
+                        self.hub.opts.max_channels
+                    ));
+                };
+                channels.remove(&idle);
+            }
+            channels.insert(
+                name.into(),
+                Channel {
+                    tx: broadcast::channel(self.hub.opts.buffer.max(1)).0,
+                    history: VecDeque::new(),
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        channels
+            .get_mut(name)
+            .ok_or_else(|| format!("channel `{name}` vanished"))
+    }
+
+    fn id(&self, seq: u64) -> String {
+        format!("{}-{seq}", self.hub.epoch)
+    }
+
+    /// The sequence number of one of this hub's ids; `None` for any other.
+    fn seq(&self, id: &str) -> Option<u64> {
+        let (epoch, seq) = id.trim().split_once('-')?;
+        (epoch.parse::<u64>().ok()? == self.hub.epoch)
+            .then(|| seq.parse().ok())
+            .flatten()
+    }
+
+    /// The channel table. Nothing panics while holding it, so a poisoned
+    /// lock still guards consistent data.
+    fn channels(&self) -> std::sync::MutexGuard<'_, HashMap<Arc<str>, Channel>> {
+        self.hub
+            .channels
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_BYTES {
+        return Err(format!(
+            "a channel name is 1 to {MAX_NAME_BYTES} bytes, got {}",
+            name.len()
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(format!(
+            "channel name {name:?} contains a control character"
+        ));
+    }
+    Ok(())
+}
+
+/// One event on the wire: `id:`, `event:`, then a `data:` line per line of
+/// `data` (any of the three SSE line endings splits).
+fn frame(id: &str, event: &str, data: &str) -> Bytes {
+    let mut out = format!("id: {id}\nevent: {event}\n");
+    for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+        out.push_str("data: ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+    Bytes::from(out)
+}
+
+/// A place among `max_subscribers`, given back when the stream ends.
+struct Slot(Arc<Hub>);
+
+impl Slot {
+    fn take(hub: &Arc<Hub>, max: usize) -> Option<Self> {
+        hub.subscribers
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(hub.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.subscribers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// One client's view of a channel: the replay first, then live events and
+/// heartbeats.
+pub struct ChannelStream {
+    replay: VecDeque<Arc<Event>>,
+    rx: broadcast::Receiver<Arc<Event>>,
+    heartbeat: Duration,
+    _slot: Slot,
+}
+
+impl ChannelStream {
+    /// The next bytes for the client, or `None` when the stream is over:
+    /// the client fell too far behind, or the server is shutting down.
+    pub async fn next(&mut self) -> Option<Bytes> {
+        if let Some(event) = self.replay.pop_front() {
+            return Some(event.frame.clone());
+        }
+        match tokio::time::timeout(self.heartbeat, self.rx.recv()).await {
+            Err(_) => Some(Bytes::from_static(HEARTBEAT)),
+            Ok(Ok(event)) => Some(event.frame.clone()),
+            Ok(Err(broadcast::error::RecvError::Lagged(missed))) => {
+                tracing::debug!("sse: dropping a client {missed} events behind; it will resume");
+                None
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) => None,
+        }
+    }
+}
+
+/// The body `nitr.sse.channel` puts in its response: the server turns it
+/// into a [`ChannelStream`] once the handler has returned.
+pub struct ChannelRequest {
+    hub: SseHub,
+    name: String,
+    last_event_id: Option<String>,
+}
+
+impl UserData for ChannelRequest {}
+
+impl ChannelRequest {
+    /// Joins the channel. Fails past `max_subscribers` or while the server
+    /// shuts down.
+    pub fn subscribe(&self) -> Result<ChannelStream, String> {
+        self.hub
+            .subscribe(&self.name, self.last_event_id.as_deref())
+    }
+}
+
+/// The hub, or the error explaining there is none.
+fn hub(hub: Option<&SseHub>) -> mlua::Result<&SseHub> {
+    hub.ok_or_else(|| {
+        mlua::Error::RuntimeError("nitr.sse channels are not available in this state".into())
+    })
+}
+
+/// Adds `channel` and `publish` to the `nitr.sse` table.
+pub(crate) fn register(lua: &Lua, sse: &Table, sse_hub: Option<SseHub>) -> mlua::Result<()> {
+    let shared = sse_hub.clone();
+    sse.set(
+        "channel",
+        lua.create_function(move |lua, (name, opts): (String, Option<Table>)| {
+            let hub = hub(shared.as_ref())?.clone();
+            check_name(&name).map_err(mlua::Error::RuntimeError)?;
+            let last_event_id = match opts {
+                Some(opts) => opts.get::<Option<String>>("last_event_id")?,
+                None => None,
+            };
+            let table = crate::http::response_table(lua, 200)?;
+            let headers = table.get::<Table>("headers")?;
+            headers.set("Content-Type", "text/event-stream")?;
+            headers.set("Cache-Control", "no-cache")?;
+            table.set(
+                "body",
+                ChannelRequest {
+                    hub,
+                    name,
+                    last_event_id,
+                },
+            )?;
+            Ok(table)
+        })?,
+    )?;
+
+    sse.set(
+        "publish",
+        lua.create_function(move |_, (name, event, data): (String, String, Value)| {
+            let data = crate::http::event_data(data)?;
+            hub(sse_hub.as_ref())?
+                .publish(&name, &event, &data)
+                .map_err(mlua::Error::RuntimeError)
+        })?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hub(history: usize, buffer: usize) -> SseHub {
+        SseHub::new(SseOptions {
+            history,
+            buffer,
+            heartbeat: Duration::from_millis(20),
+            ..Default::default()
+        })
+    }
+
+    async fn text(stream: &mut ChannelStream) -> String {
+        String::from_utf8(stream.next().await.expect("bytes").to_vec()).expect("utf-8")
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_live_events_and_heartbeats() {
+        let hub = hub(10, 8);
+        let mut stream = hub.subscribe("orders", None).expect("subscribe");
+        let id = hub
+            .publish("orders", "paid", "line 1\r\nline 2")
+            .expect("publish");
+        assert_eq!(
+            text(&mut stream).await,
+            format!("id: {id}\nevent: paid\ndata: line 1\ndata: line 2\n\n")
+        );
+        assert_eq!(text(&mut stream).await, ": keepalive\n\n");
+        assert!(hub.publish("orders", "a\nb", "x").is_err());
+        assert!(hub.publish("", "paid", "x").is_err());
+    }
+
+    #[tokio::test]
+    async fn a_reconnect_replays_what_the_history_holds() {
+        let hub = hub(3, 8);
+        let ids: Vec<String> = (1..=5)
+            .map(|n| hub.publish("feed", "n", &n.to_string()).expect("publish"))
+            .collect();
+
+        let mut resumed = hub.subscribe("feed", Some(&ids[2])).expect("subscribe");
+        assert!(text(&mut resumed).await.contains("data: 4\n"));
+        assert!(text(&mut resumed).await.contains("data: 5\n"));
+        assert_eq!(text(&mut resumed).await, ": keepalive\n\n");
+
+        // Older than the history, or from another process: all of it.
+        for id in [ids[0].as_str(), "1-1", "garbage"] {
+            let mut stream = hub.subscribe("feed", Some(id)).expect("subscribe");
+            assert!(text(&mut stream).await.contains("data: 3\n"), "{id}");
+        }
+        assert_eq!(hub.seq(&ids[4]), Some(5));
+    }
+
+    #[tokio::test]
+    async fn a_recreated_channel_does_not_reuse_ids() {
+        let hub = SseHub::new(SseOptions {
+            max_channels: 1,
+            heartbeat: Duration::from_millis(20),
+            ..Default::default()
+        });
+        hub.publish("a", "n", "1").expect("publish");
+        let seen = hub.publish("a", "n", "2").expect("publish");
+        // `b` pushes out the idle `a`, and `a` then comes back empty.
+        hub.publish("b", "n", "x").expect("evicts a");
+        assert!(!hub.channels().contains_key("a"));
+        let fresh = hub.publish("a", "n", "3").expect("recreates a");
+        assert_ne!(fresh, seen);
+
+        // The client that saw the old `a` reconnects and gets what is new.
+        let mut resumed = hub.subscribe("a", Some(&seen)).expect("subscribe");
+        assert!(text(&mut resumed).await.contains("data: 3\n"));
+        assert_eq!(text(&mut resumed).await, ": keepalive\n\n");
+    }
+
+    #[tokio::test]
+    async fn slow_clients_are_dropped_and_closing_ends_every_stream() {
+        let hub = hub(0, 2);
+        let mut slow = hub.subscribe("feed", None).expect("subscribe");
+        for n in 0..5 {
+            hub.publish("feed", "n", &n.to_string()).expect("publish");
+        }
+        assert!(slow.next().await.is_none(), "a lagging client is cut off");
+
+        let mut live = hub.subscribe("feed", None).expect("subscribe");
+        hub.close();
+        assert!(live.next().await.is_none());
+        assert!(hub.subscribe("feed", None).is_err());
+    }
+
+    #[test]
+    fn limits_bound_channels_and_subscribers() {
+        let hub = SseHub::new(SseOptions {
+            max_channels: 2,
+            max_subscribers: 2,
+            ..Default::default()
+        });
+        let a = hub.subscribe("a", None).expect("a");
+        let _b = hub.subscribe("b", None).expect("b");
+        assert!(hub.subscribe("a", None).is_err(), "max_subscribers");
+        // Both channels have clients: no room for a third.
+        assert!(hub.publish("c", "e", "x").is_err());
+        drop(a);
+        hub.publish("c", "e", "x")
+            .expect("the idle channel `a` makes room");
+        assert!(!hub.channels().contains_key("a"));
+    }
+}
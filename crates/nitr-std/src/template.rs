@@ -1,7 +1,7 @@
 use std::path::Path;
 use std::sync::Arc;
 
-use minijinja::{Environment, path_loader};
+use minijinja::{Environment, Value, context, path_loader};
 use mlua::{AnyUserData, ExternalResult, Lua, LuaSerdeExt, Table, UserData, UserDataMethods};
 
 pub(crate) struct LuaTemplate<'a>(Arc<Environment<'a>>);
@@ -15,12 +15,31 @@ impl UserData for LuaTemplate<'_> {
             let templ = templ_store
                 .get_template(file_path.as_str())
                 .into_lua_err()?;
-            let content = templ.render(data).into_lua_err()?;
+            // The request's CSP nonce, for `<script nonce="{{ csp_nonce }}">`.
+            // It always wins over a `csp_nonce` in the data, which could
+            // otherwise emit a nonce the header does not allow.
+            let nonce = lua.app_data_ref::<CspNonce>().and_then(|n| n.0.clone());
+            let data = Value::from_serialize(&data);
+            let content = match nonce {
+                Some(nonce) => templ.render(context! { csp_nonce => nonce, ..data }),
+                None => templ.render(data),
+            }
+            .into_lua_err()?;
             lua.to_value(&content)
         });
     }
 }
 
+/// The CSP nonce of the request this state is serving, if its
+/// `[headers]` policy made one.
+#[derive(Debug, Clone)]
+pub(crate) struct CspNonce(Option<String>);
+
+/// Records the current request's CSP nonce for templates to read.
+pub fn set_csp_nonce(lua: &Lua, nonce: Option<&str>) {
+    lua.set_app_data(CspNonce(nonce.map(str::to_string)));
+}
+
 /// Templating function support.
 pub(crate) fn create_template_fn(lua: &Lua, dir: &Path) -> mlua::Result<AnyUserData> {
     let mut env = Environment::new();
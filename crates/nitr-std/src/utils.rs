@@ -14,6 +14,17 @@ pub(crate) fn new_hmac<M: hmac::digest::KeyInit>(key: &[u8]) -> M {
     <M as hmac::digest::KeyInit>::new_from_slice(key).expect("HMAC accepts any key length")
 }
 
+/// Grants a streaming body a fresh execution budget after it waited on an
+/// event (a database change, a published message). Waiting is idle time,
+/// like waiting for a slow client, and must not count against the budget
+/// for producing the next chunk. Outside a stream there is no handle and
+/// the request's own deadline stands.
+pub(crate) fn grant_budget(lua: &Lua) {
+    if let Some(deadline) = lua.app_data_ref::<nitr_core::DeadlineHandle>() {
+        deadline.extend();
+    }
+}
+
 /// The deepest nesting a Lua value may have before it is serialized to
 /// JSON, deliberately mirroring serde_json's *deserialization* recursion
 /// limit so the two directions share one documented bound.
@@ -0,0 +1,1200 @@
+//! `nitr.webauthn`: passkey registration and login (WebAuthn Level 2
+//! ceremonies), verified in Rust.
+//!
+//! `nitr.webauthn({ rp_id, origins, ... })` describes the relying party.
+//! Each ceremony is two requests: `*_options(session, ...)` returns the
+//! JSON the browser passes to `navigator.credentials.create()`/`get()`
+//! (through `PublicKeyCredential.parse*OptionsFromJSON`) and remembers the
+//! challenge in the session; `verify_*(session, response)` takes the
+//! credential's `toJSON()` and checks it against that challenge, the
+//! allowed origins and the RP ID.
+//!
+//! Credentials come back as plain records (id, COSE public key, signature
+//! counter, ...) for the app to keep; Nitr stores nothing. Attestation is
+//! not requested: `none` and packed self-attestation are checked, other
+//! statements are accepted without evaluating their certificate chains
+//! and recorded as `none`.
+
+use std::sync::Arc;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64URL;
+use ciborium::Value as Cbor;
+use mlua::{Lua, LuaSerdeExt as _, Table, UserData, UserDataMethods, Value};
+use serde_json::{Value as Json, json};
+use sha2::{Digest as _, Sha256};
+use subtle::ConstantTimeEq as _;
+
+use crate::jwt::MIN_RSA_BITS;
+
+/// The session key holding the ceremony in progress.
+const CEREMONY_KEY: &str = "_webauthn";
+
+/// COSE algorithm identifiers, by the JOSE names the rest of Nitr uses.
+const ALGORITHMS: &[(&str, i64)] = &[("ES256", -7), ("EdDSA", -8), ("RS256", -257)];
+
+/// Longest user handle the specification allows.
+const MAX_USER_ID: usize = 64;
+
+// Authenticator data flags (WebAuthn §6.1).
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const BACKUP_ELIGIBLE: u8 = 0x08;
+const BACKED_UP: u8 = 0x10;
+const ATTESTED: u8 = 0x40;
+
+fn webauthn_err(msg: impl std::fmt::Display) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("webauthn: {msg}"))
+}
+
+/// The relying party, as configured by `nitr.webauthn(...)`.
+struct RelyingParty {
+    id: String,
+    name: String,
+    origins: Vec<String>,
+    /// `"required"`, `"preferred"` or `"discouraged"`.
+    user_verification: String,
+    /// Seconds a ceremony may take, and the browser's timeout.
+    timeout: u64,
+    /// Accepted COSE algorithms, in order of preference.
+    algorithms: Vec<i64>,
+}
+
+#[derive(Clone)]
+struct LuaRelyingParty(Arc<RelyingParty>);
+
+/// A credential public key, from its COSE encoding.
+enum PublicKey {
+    P256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+    Rsa(rsa::RsaPublicKey),
+}
+
+impl PublicKey {
+    /// Parses a COSE_Key (RFC 9053) into its algorithm and key.
+    fn from_cose(bytes: &[u8]) -> Result<(i64, Self), &'static str> {
+        let Ok(Cbor::Map(map)) = ciborium::from_reader::<Cbor, _>(bytes) else {
+            return Err("malformed public key");
+        };
+        let int = |key: i64| map_int(&map, key);
+        let bytes = |key: i64| match map_get(&map, key) {
+            Some(Cbor::Bytes(b)) => Ok(b.as_slice()),
+            _ => Err("malformed public key"),
+        };
+        let alg = int(3).ok_or("malformed public key")?;
+        let key = match (int(1), alg) {
+            // EC2 on P-256, uncompressed.
+            (Some(2), -7) if int(-1) == Some(1) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err("malformed public key");
+                }
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    p256::FieldBytes::from_slice(x),
+                    p256::FieldBytes::from_slice(y),
+                    false,
+                );
+                Self::P256(
+                    p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                        .map_err(|_| "malformed public key")?,
+                )
+            }
+            // OKP Ed25519.
+            (Some(1), -8) if int(-1) == Some(6) => {
+                let x: [u8; 32] = bytes(-2)?.try_into().map_err(|_| "malformed public key")?;
+                Self::Ed25519(
+                    ed25519_dalek::VerifyingKey::from_bytes(&x)
+                        .map_err(|_| "malformed public key")?,
+                )
+            }
+            (Some(3), -257) => {
+                use rsa::traits::PublicKeyParts as _;
+                let key = rsa::RsaPublicKey::new(
+                    rsa::BigUint::from_bytes_be(bytes(-1)?),
+                    rsa::BigUint::from_bytes_be(bytes(-2)?),
+                )
+                .map_err(|_| "malformed public key")?;
+                if key.n().bits() < MIN_RSA_BITS {
+                    return Err("RSA key too small");
+                }
+                Self::Rsa(key)
+            }
+            _ => return Err("unsupported algorithm"),
+        };
+        Ok((alg, key))
+    }
+
+    /// Checks a WebAuthn signature: DER for ECDSA, raw for EdDSA, PKCS#1
+    /// v1.5 for RSA.
+    fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+        use rsa::signature::Verifier as _;
+        match self {
+            Self::P256(key) => p256::ecdsa::Signature::from_der(sig)
+                .is_ok_and(|sig| key.verify(message, &sig).is_ok()),
+            Self::Ed25519(key) => ed25519_dalek::Signature::from_slice(sig)
+                .is_ok_and(|sig| key.verify_strict(message, &sig).is_ok()),
+            Self::Rsa(key) => rsa::pkcs1v15::Signature::try_from(sig).is_ok_and(|sig| {
+                rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key.clone())
+                    .verify(message, &sig)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+fn map_get(map: &[(Cbor, Cbor)], key: i64) -> Option<&Cbor> {
+    map.iter()
+        .find(|(k, _)| {
+            k.as_integer()
+                .is_some_and(|k| i128::from(k) == i128::from(key))
+        })
+        .map(|(_, v)| v)
+}
+
+fn map_int(map: &[(Cbor, Cbor)], key: i64) -> Option<i64> {
+    map_get(map, key)
+        .and_then(Cbor::as_integer)
+        .and_then(|i| i64::try_from(i).ok())
+}
+
+fn map_text<'a>(map: &'a [(Cbor, Cbor)], key: &str) -> Option<&'a Cbor> {
+    map.iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+/// The credential an attestation introduces (WebAuthn §6.5.1).
+struct AttestedCredential<'a> {
+    aaguid: &'a [u8],
+    id: &'a [u8],
+    public_key: &'a [u8],
+}
+
+/// Authenticator data (WebAuthn §6.1).
+struct AuthData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    credential: Option<AttestedCredential<'a>>,
+}
+
+impl<'a> AuthData<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        const MALFORMED: &str = "malformed authenticator data";
+        if data.len() < 37 {
+            return Err(MALFORMED);
+        }
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+        let credential = if flags & ATTESTED != 0 {
+            let rest = &data[37..];
+            if rest.len() < 18 {
+                return Err(MALFORMED);
+            }
+            let id_len = usize::from(u16::from_be_bytes([rest[16], rest[17]]));
+            let Some(id) = rest.get(18..18 + id_len) else {
+                return Err(MALFORMED);
+            };
+            // The COSE key's length is only known by decoding it;
+            // extensions may follow.
+            let mut key = &rest[18 + id_len..];
+            let before = key.len();
+            ciborium::from_reader::<Cbor, _>(&mut key).map_err(|_| MALFORMED)?;
+            let used = before - key.len();
+            Some(AttestedCredential {
+                aaguid: &rest[..16],
+                id,
+                public_key: &rest[18 + id_len..18 + id_len + used],
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            rp_id_hash: &data[..32],
+            flags,
+            sign_count,
+            credential,
+        })
+    }
+}
+
+/// A ceremony started by `*_options`, as kept in the session.
+struct Ceremony {
+    /// `"webauthn.create"` or `"webauthn.get"`.
+    kind: String,
+    challenge: String,
+    started: f64,
+    /// Registration: the user handle the credential is being made for.
+    user_id: Option<String>,
+}
+
+/// The decoded parts of a `PublicKeyCredential.toJSON()`.
+struct Response {
+    id: Vec<u8>,
+    client_data: Vec<u8>,
+    fields: Json,
+}
+
+impl Response {
+    fn parse(value: Value) -> Result<Self, &'static str> {
+        const MALFORMED: &str = "malformed response";
+        let json: Json = match value {
+            Value::String(s) => serde_json::from_slice(&s.as_bytes()).map_err(|_| MALFORMED)?,
+            Value::Table(t) => serde_json::to_value(&t).map_err(|_| MALFORMED)?,
+            _ => return Err(MALFORMED),
+        };
+        if json.get("type").and_then(Json::as_str) != Some("public-key") {
+            return Err(MALFORMED);
+        }
+        let id = json
+            .get("rawId")
+            .or_else(|| json.get("id"))
+            .and_then(Json::as_str)
+            .and_then(b64url)
+            .ok_or(MALFORMED)?;
+        let fields = json.get("response").cloned().ok_or(MALFORMED)?;
+        let client_data = fields
+            .get("clientDataJSON")
+            .and_then(Json::as_str)
+            .and_then(b64url)
+            .ok_or(MALFORMED)?;
+        Ok(Self {
+            id,
+            client_data,
+            fields,
+        })
+    }
+
+    /// A base64url field of `response`.
+    fn bytes(&self, name: &str) -> Result<Vec<u8>, &'static str> {
+        self.fields
+            .get(name)
+            .and_then(Json::as_str)
+            .and_then(b64url)
+            .ok_or("malformed response")
+    }
+}
+
+fn b64url(text: &str) -> Option<Vec<u8>> {
+    B64URL.decode(text.trim_end_matches('=')).ok()
+}
+
+fn unix_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+impl RelyingParty {
+    /// Starts a ceremony: a fresh challenge, remembered in the session in
+    /// place of any unfinished one.
+    fn begin(
+        &self,
+        lua: &Lua,
+        session: &Table,
+        kind: &str,
+        user_id: Option<&str>,
+    ) -> mlua::Result<String> {
+        let challenge = crate::csrf::new_token()?;
+        let entry = lua.create_table()?;
+        entry.set("type", kind)?;
+        entry.set("challenge", challenge.as_str())?;
+        entry.set("at", unix_now())?;
+        if let Some(user_id) = user_id {
+            entry.set("user", user_id)?;
+        }
+        session.raw_set(CEREMONY_KEY, entry)?;
+        Ok(challenge)
+    }
+
+    /// Ends the ceremony in progress, whatever the outcome: a challenge
+    /// answers one response.
+    fn take(&self, session: &Table, kind: &str) -> mlua::Result<Result<Ceremony, &'static str>> {
+        let Value::Table(entry) = session.raw_get::<Value>(CEREMONY_KEY)? else {
+            return Ok(Err("no ceremony in progress"));
+        };
+        session.raw_set(CEREMONY_KEY, Value::Nil)?;
+        let ceremony = Ceremony {
+            kind: entry.get("type")?,
+            challenge: entry.get("challenge")?,
+            started: entry.get("at")?,
+            user_id: entry.get("user")?,
+        };
+        if ceremony.kind != kind {
+            return Ok(Err("no ceremony in progress"));
+        }
+        if unix_now() - ceremony.started > self.timeout as f64 {
+            return Ok(Err("ceremony expired"));
+        }
+        Ok(Ok(ceremony))
+    }
+
+    /// The client data checks shared by both ceremonies (§7.1 steps 7-12,
+    /// §7.2 steps 10-15).
+    fn check_client_data(
+        &self,
+        response: &Response,
+        ceremony: &Ceremony,
+    ) -> Result<(), &'static str> {
+        let client: Json =
+            serde_json::from_slice(&response.client_data).map_err(|_| "malformed client data")?;
+        let field = |name: &str| client.get(name).and_then(Json::as_str).unwrap_or("");
+        if field("type") != ceremony.kind {
+            return Err("wrong ceremony type");
+        }
+        let challenge = field("challenge").trim_end_matches('=');
+        if !bool::from(challenge.as_bytes().ct_eq(ceremony.challenge.as_bytes())) {
+            return Err("challenge mismatch");
+        }
+        if !self.origins.iter().any(|o| o == field("origin")) {
+            return Err("origin not allowed");
+        }
+        if client.get("crossOrigin").and_then(Json::as_bool) == Some(true) {
+            return Err("cross-origin ceremony");
+        }
+        Ok(())
+    }
+
+    /// The authenticator data checks shared by both ceremonies.
+    fn check_auth_data(&self, auth: &AuthData<'_>) -> Result<(), &'static str> {
+        let rp_id_hash = Sha256::digest(self.id.as_bytes());
+        if !bool::from(auth.rp_id_hash.ct_eq(rp_id_hash.as_slice())) {
+            return Err("rp id mismatch");
+        }
+        if auth.flags & USER_PRESENT == 0 {
+            return Err("user not present");
+        }
+        if self.user_verification == "required" && auth.flags & USER_VERIFIED == 0 {
+            return Err("user not verified");
+        }
+        Ok(())
+    }
+
+    /// §7.1: verifies an attestation and returns the new credential record.
+    fn register(&self, response: &Response, ceremony: &Ceremony) -> Result<Json, &'static str> {
+        self.check_client_data(response, ceremony)?;
+        let Ok(Cbor::Map(attestation)) =
+            ciborium::from_reader::<Cbor, _>(response.bytes("attestationObject")?.as_slice())
+        else {
+            return Err("malformed attestation");
+        };
+        let (Some(Cbor::Text(fmt)), Some(Cbor::Bytes(auth_bytes)), Some(Cbor::Map(statement))) = (
+            map_text(&attestation, "fmt"),
+            map_text(&attestation, "authData"),
+            map_text(&attestation, "attStmt"),
+        ) else {
+            return Err("malformed attestation");
+        };
+        let auth = AuthData::parse(auth_bytes)?;
+        self.check_auth_data(&auth)?;
+        let Some(credential) = &auth.credential else {
+            return Err("malformed attestation");
+        };
+        if credential.id != response.id.as_slice() {
+            return Err("credential id mismatch");
+        }
+        let (alg, key) = PublicKey::from_cose(credential.public_key)?;
+        if !self.algorithms.contains(&alg) {
+            return Err("unsupported algorithm");
+        }
+        let attestation = match fmt.as_str() {
+            "none" if statement.is_empty() => "none",
+            "none" => return Err("invalid attestation"),
+            // Self attestation: signed by the credential key itself.
+            "packed" if map_text(statement, "x5c").is_none() => {
+                let sig = match map_text(statement, "sig") {
+                    Some(Cbor::Bytes(sig)) => sig,
+                    _ => return Err("invalid attestation"),
+                };
+                let stated = map_text(statement, "alg")
+                    .and_then(Cbor::as_integer)
+                    .and_then(|i| i64::try_from(i).ok());
+                let mut signed = auth_bytes.clone();
+                signed.extend_from_slice(&Sha256::digest(&response.client_data));
+                if stated != Some(alg) || !key.verify(&signed, sig) {
+                    return Err("invalid attestation");
+                }
+                "packed"
+            }
+            // Attestation was not asked for and these statements go
+            // unchecked, so the record claims none rather than a format
+            // nothing verified.
+            _ => "none",
+        };
+        let transports = response
+            .fields
+            .get("transports")
+            .filter(|t| t.is_array())
+            .cloned()
+            .unwrap_or_else(|| json!([]));
+        let aaguid: String = credential
+            .aaguid
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Ok(json!({
+            "id": B64URL.encode(credential.id),
+            "public_key": B64URL.encode(credential.public_key),
+            "alg": ALGORITHMS.iter().find(|(_, id)| *id == alg).map(|(name, _)| *name),
+            "sign_count": auth.sign_count,
+            "user_id": ceremony.user_id,
+            "transports": transports,
+            "aaguid": aaguid,
+            "attestation": attestation,
+            "backup_eligible": auth.flags & BACKUP_ELIGIBLE != 0,
+            "backed_up": auth.flags & BACKED_UP != 0,
+            "user_verified": auth.flags & USER_VERIFIED != 0,
+        }))
+    }
+
+    /// §7.2: verifies an assertion by `credential` (a stored record) and
+    /// returns what changed.
+    fn authenticate(
+        &self,
+        response: &Response,
+        ceremony: &Ceremony,
+        credential: &Json,
+    ) -> Result<Json, &'static str> {
+        let field = |name: &str| credential.get(name).and_then(Json::as_str);
+        let Some(stored_id) = field("id").and_then(b64url) else {
+            return Err("malformed credential");
+        };
+        if stored_id != response.id {
+            return Err("credential id mismatch");
+        }
+        // A discoverable credential names its user; it must be the one the
+        // record belongs to.
+        if let Some(handle) = response.fields.get("userHandle").and_then(Json::as_str)
+            && !handle.is_empty()
+            && let Some(user_id) = field("user_id")
+            && b64url(handle).as_deref() != Some(user_id.as_bytes())
+        {
+            return Err("user handle mismatch");
+        }
+        self.check_client_data(response, ceremony)?;
+        let auth_bytes = response.bytes("authenticatorData")?;
+        let auth = AuthData::parse(&auth_bytes)?;
+        self.check_auth_data(&auth)?;
+        let public_key = field("public_key")
+            .and_then(b64url)
+            .ok_or("malformed credential")?;
+        let (alg, key) = PublicKey::from_cose(&public_key)?;
+        if !self.algorithms.contains(&alg) {
+            return Err("unsupported algorithm");
+        }
+        let mut signed = auth_bytes.clone();
+        signed.extend_from_slice(&Sha256::digest(&response.client_data));
+        if !key.verify(&signed, &response.bytes("signature")?) {
+            return Err("invalid signature");
+        }
+        // §7.2 step 21: a counter that fails to advance suggests a cloned
+        // authenticator. Authenticators without a counter report 0.
+        // Records read back from storage may carry the count as a float.
+        let stored = credential
+            .get("sign_count")
+            .and_then(|n| n.as_u64().or_else(|| n.as_f64().map(|f| f as u64)))
+            .unwrap_or(0);
+        if (stored != 0 || auth.sign_count != 0) && u64::from(auth.sign_count) <= stored {
+            return Err("signature counter did not increase");
+        }
+        Ok(json!({
+            "id": field("id"),
+            "user_id": field("user_id"),
+            "sign_count": auth.sign_count,
+            "backed_up": auth.flags & BACKED_UP != 0,
+            "user_verified": auth.flags & USER_VERIFIED != 0,
+        }))
+    }
+
+    /// `excludeCredentials`/`allowCredentials` from credential records or
+    /// bare ids.
+    fn descriptors(list: Option<Table>) -> mlua::Result<Vec<Json>> {
+        let Some(list) = list else {
+            return Ok(Vec::new());
+        };
+        let mut out = Vec::new();
+        for item in list.sequence_values::<Value>() {
+            let (id, transports) = match item? {
+                Value::String(id) => (id.to_str()?.to_owned(), None),
+                Value::Table(record) => (
+                    record.get::<String>("id")?,
+                    record.get::<Option<Vec<String>>>("transports")?,
+                ),
+                other => {
+                    return Err(webauthn_err(format!(
+                        "credential lists take records or ids, got {}",
+                        other.type_name()
+                    )));
+                }
+            };
+            let mut descriptor = json!({ "type": "public-key", "id": id });
+            if let Some(transports) = transports.filter(|t| !t.is_empty()) {
+                descriptor["transports"] = json!(transports);
+            }
+            out.push(descriptor);
+        }
+        Ok(out)
+    }
+}
+
+/// `nil` plus a reason, like `jwt.verify`.
+fn reject(lua: &Lua, reason: &str) -> mlua::Result<(Value, Value)> {
+    Ok((Value::Nil, Value::String(lua.create_string(reason)?)))
+}
+
+impl UserData for LuaRelyingParty {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        // rp:registration_options(session, user, opts?) -> options
+        methods.add_method(
+            "registration_options",
+            |lua, rp, (session, user, opts): (Table, Table, Option<Table>)| {
+                let rp = &rp.0;
+                let user_id: String = user
+                    .get::<Option<String>>("id")?
+                    .filter(|id| !id.is_empty() && id.len() <= MAX_USER_ID)
+                    .ok_or_else(|| {
+                        webauthn_err(format!(
+                            "`user.id` must be 1 to {MAX_USER_ID} bytes: a stable, \
+                             non-personal id"
+                        ))
+                    })?;
+                let name: String = user
+                    .get::<Option<String>>("name")?
+                    .ok_or_else(|| webauthn_err("`user.name` is required"))?;
+                let display_name = user
+                    .get::<Option<String>>("display_name")?
+                    .unwrap_or_else(|| name.clone());
+                let (exclude, resident_key) = match &opts {
+                    Some(opts) => (
+                        RelyingParty::descriptors(opts.get("exclude")?)?,
+                        opts.get::<Option<String>>("resident_key")?,
+                    ),
+                    None => (Vec::new(), None),
+                };
+                let resident_key = resident_key.unwrap_or_else(|| "preferred".into());
+                if !["required", "preferred", "discouraged"].contains(&resident_key.as_str()) {
+                    return Err(webauthn_err(format!(
+                        "`resident_key` must be required, preferred or discouraged, got \
+                         `{resident_key}`"
+                    )));
+                }
+                let challenge = rp.begin(lua, &session, "webauthn.create", Some(&user_id))?;
+                let mut options = json!({
+                    "challenge": challenge,
+                    "rp": { "id": rp.id, "name": rp.name },
+                    "user": {
+                        "id": B64URL.encode(&user_id),
+                        "name": name,
+                        "displayName": display_name,
+                    },
+                    "pubKeyCredParams": rp
+                        .algorithms
+                        .iter()
+                        .map(|alg| json!({ "type": "public-key", "alg": alg }))
+                        .collect::<Vec<_>>(),
+                    "timeout": rp.timeout * 1000,
+                    "attestation": "none",
+                    "authenticatorSelection": {
+                        "residentKey": resident_key,
+                        "requireResidentKey": resident_key == "required",
+                        "userVerification": rp.user_verification,
+                    },
+                });
+                if !exclude.is_empty() {
+                    options["excludeCredentials"] = json!(exclude);
+                }
+                lua.to_value(&options)
+            },
+        );
+
+        // rp:verify_registration(session, response) -> credential | nil, reason
+        methods.add_method(
+            "verify_registration",
+            |lua, rp, (session, response): (Table, Value)| {
+                let ceremony = match rp.0.take(&session, "webauthn.create")? {
+                    Ok(ceremony) => ceremony,
+                    Err(reason) => return reject(lua, reason),
+                };
+                let result = Response::parse(response)
+                    .and_then(|response| rp.0.register(&response, &ceremony));
+                match result {
+                    Ok(credential) => Ok((lua.to_value(&credential)?, Value::Nil)),
+                    Err(reason) => reject(lua, reason),
+                }
+            },
+        );
+
+        // rp:authentication_options(session, opts?) -> options
+        methods.add_method(
+            "authentication_options",
+            |lua, rp, (session, opts): (Table, Option<Table>)| {
+                let rp = &rp.0;
+                let allow = match &opts {
+                    Some(opts) => RelyingParty::descriptors(opts.get("allow")?)?,
+                    None => Vec::new(),
+                };
+                let challenge = rp.begin(lua, &session, "webauthn.get", None)?;
+                let mut options = json!({
+                    "challenge": challenge,
+                    "rpId": rp.id,
+                    "timeout": rp.timeout * 1000,
+                    "userVerification": rp.user_verification,
+                });
+                if !allow.is_empty() {
+                    options["allowCredentials"] = json!(allow);
+                }
+                lua.to_value(&options)
+            },
+        );
+
+        // rp:verify_authentication(session, response, credential)
+        //   -> { id, user_id, sign_count, ... } | nil, reason
+        methods.add_method(
+            "verify_authentication",
+            |lua, rp, (session, response, credential): (Table, Value, Option<Table>)| {
+                let ceremony = match rp.0.take(&session, "webauthn.get")? {
+                    Ok(ceremony) => ceremony,
+                    Err(reason) => return reject(lua, reason),
+                };
+                let Some(credential) = credential else {
+                    return reject(lua, "unknown credential");
+                };
+                let credential: Json = lua.from_value(Value::Table(credential))?;
+                let result = Response::parse(response)
+                    .and_then(|response| rp.0.authenticate(&response, &ceremony, &credential));
+                match result {
+                    Ok(outcome) => Ok((lua.to_value(&outcome)?, Value::Nil)),
+                    Err(reason) => reject(lua, reason),
+                }
+            },
+        );
+    }
+}
+
+/// Builds `nitr.webauthn(opts)`.
+pub(crate) fn create_webauthn_fn(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, opts: Table| {
+        for pair in opts.pairs::<String, Value>() {
+            let (key, _) = pair?;
+            if ![
+                "rp_id",
+                "rp_name",
+                "origins",
+                "user_verification",
+                "timeout",
+                "algorithms",
+            ]
+            .contains(&key.as_str())
+            {
+                return Err(webauthn_err(format!("unknown option `{key}`")));
+            }
+        }
+        let id: String = opts
+            .get::<Option<String>>("rp_id")?
+            .filter(|id| !id.is_empty() && !id.contains(['/', ':']))
+            .ok_or_else(|| {
+                webauthn_err("`rp_id` is required: the site's domain, e.g. \"example.com\"")
+            })?;
+        let origins: Vec<String> = match opts.get::<Value>("origins")? {
+            Value::String(origin) => vec![origin.to_str()?.to_owned()],
+            Value::Table(list) => list.sequence_values().collect::<mlua::Result<_>>()?,
+            _ => Vec::new(),
+        };
+        if origins.is_empty() {
+            return Err(webauthn_err(
+                "`origins` is required, e.g. \"https://example.com\"",
+            ));
+        }
+        for origin in &origins {
+            // The origin's host must be the RP ID or a subdomain of it, over
+            // https (plain http only on localhost, as browsers allow).
+            let valid = origin.split_once("://").is_some_and(|(scheme, rest)| {
+                let host = rest.rsplit_once(':').map_or(rest, |(host, _)| host);
+                (scheme == "https" || (scheme == "http" && host == "localhost"))
+                    && !rest.contains(['/', '?', '#'])
+                    && (host == id || host.ends_with(&format!(".{id}")))
+            });
+            if !valid {
+                return Err(webauthn_err(format!(
+                    "origin `{origin}` must be https://{id} or one of its subdomains, \
+                     without a path"
+                )));
+            }
+        }
+        let user_verification = opts
+            .get::<Option<String>>("user_verification")?
+            .unwrap_or_else(|| "preferred".into());
+        if !["required", "preferred", "discouraged"].contains(&user_verification.as_str()) {
+            return Err(webauthn_err(format!(
+                "`user_verification` must be required, preferred or discouraged, got \
+                 `{user_verification}`"
+            )));
+        }
+        let timeout = opts.get::<Option<u64>>("timeout")?.unwrap_or(300);
+        if !(10..=3600).contains(&timeout) {
+            return Err(webauthn_err(format!(
+                "`timeout` must be 10 to 3600 seconds, got {timeout}"
+            )));
+        }
+        let algorithms = match opts.get::<Option<Vec<String>>>("algorithms")? {
+            None => ALGORITHMS.iter().map(|(_, id)| *id).collect(),
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    ALGORITHMS
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, id)| *id)
+                        .ok_or_else(|| {
+                            webauthn_err(format!(
+                                "unsupported algorithm `{name}` (supported: ES256, EdDSA, RS256)"
+                            ))
+                        })
+                })
+                .collect::<mlua::Result<Vec<_>>>()?,
+        };
+        if algorithms.is_empty() {
+            return Err(webauthn_err("the `algorithms` list is empty"));
+        }
+        Ok(LuaRelyingParty(Arc::new(RelyingParty {
+            name: opts
+                .get::<Option<String>>("rp_name")?
+                .unwrap_or_else(|| id.clone()),
+            id,
+            origins,
+            user_verification,
+            timeout,
+            algorithms,
+        })))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer as _;
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: "example.com".into(),
+            name: "Example".into(),
+            origins: vec!["https://example.com".into()],
+            user_verification: "preferred".into(),
+            timeout: 300,
+            algorithms: ALGORITHMS.iter().map(|(_, id)| *id).collect(),
+        }
+    }
+
+    fn ceremony(kind: &str, user_id: Option<&str>) -> Ceremony {
+        Ceremony {
+            kind: kind.into(),
+            challenge: "c2VydmVyLWNoYWxsZW5nZQ".into(),
+            started: unix_now(),
+            user_id: user_id.map(Into::into),
+        }
+    }
+
+    fn cbor(value: &Cbor) -> Vec<u8> {
+        let mut out = Vec::new();
+        ciborium::into_writer(value, &mut out).expect("cbor encodes");
+        out
+    }
+
+    fn cose(entries: Vec<(i64, Cbor)>) -> Vec<u8> {
+        cbor(&Cbor::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (Cbor::Integer(k.into()), v))
+                .collect(),
+        ))
+    }
+
+    fn ed25519_cose(key: &ed25519_dalek::SigningKey) -> Vec<u8> {
+        cose(vec![
+            (1, Cbor::Integer(1.into())),
+            (3, Cbor::Integer((-8).into())),
+            (-1, Cbor::Integer(6.into())),
+            (-2, Cbor::Bytes(key.verifying_key().to_bytes().to_vec())),
+        ])
+    }
+
+    fn p256_cose(key: &p256::ecdsa::SigningKey) -> Vec<u8> {
+        let point = key.verifying_key().to_encoded_point(false);
+        cose(vec![
+            (1, Cbor::Integer(2.into())),
+            (3, Cbor::Integer((-7).into())),
+            (-1, Cbor::Integer(1.into())),
+            (-2, Cbor::Bytes(point.x().expect("x coordinate").to_vec())),
+            (-3, Cbor::Bytes(point.y().expect("y coordinate").to_vec())),
+        ])
+    }
+
+    fn auth_data(
+        rp_id: &str,
+        flags: u8,
+        count: u32,
+        credential: Option<(&[u8], &[u8])>,
+    ) -> Vec<u8> {
+        let mut out = Sha256::digest(rp_id.as_bytes()).to_vec();
+        out.push(flags | if credential.is_some() { ATTESTED } else { 0 });
+        out.extend_from_slice(&count.to_be_bytes());
+        if let Some((id, key)) = credential {
+            out.extend_from_slice(&[0xAA; 16]);
+            out.extend_from_slice(&(id.len() as u16).to_be_bytes());
+            out.extend_from_slice(id);
+            out.extend_from_slice(key);
+        }
+        out
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({ "type": kind, "challenge": challenge, "origin": origin }))
+            .expect("client data")
+    }
+
+    fn response(id: &[u8], fields: Json) -> Response {
+        let lua = Lua::new();
+        let text = json!({
+            "id": B64URL.encode(id),
+            "rawId": B64URL.encode(id),
+            "type": "public-key",
+            "response": fields,
+        })
+        .to_string();
+        Response::parse(Value::String(lua.create_string(text).expect("string")))
+            .expect("response parses")
+    }
+
+    fn attestation(fmt: &str, auth: &[u8], statement: Vec<(&str, Cbor)>) -> String {
+        let statement = statement
+            .into_iter()
+            .map(|(k, v)| (Cbor::Text(k.into()), v))
+            .collect();
+        B64URL.encode(cbor(&Cbor::Map(vec![
+            (Cbor::Text("fmt".into()), Cbor::Text(fmt.into())),
+            (Cbor::Text("attStmt".into()), Cbor::Map(statement)),
+            (Cbor::Text("authData".into()), Cbor::Bytes(auth.to_vec())),
+        ])))
+    }
+
+    fn signed(auth: &[u8], client: &[u8]) -> Vec<u8> {
+        let mut out = auth.to_vec();
+        out.extend_from_slice(&Sha256::digest(client));
+        out
+    }
+
+    /// An Ed25519 passkey with `none` attestation, then a login whose
+    /// counter must keep rising.
+    #[test]
+    fn ed25519_credentials_register_and_authenticate() {
+        let rp = relying_party();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let id = b"credential-1";
+        let create = ceremony("webauthn.create", Some("user-1"));
+        let client = client_data("webauthn.create", &create.challenge, "https://example.com");
+        let auth = auth_data(
+            "example.com",
+            USER_PRESENT | USER_VERIFIED,
+            0,
+            Some((id, &ed25519_cose(&key))),
+        );
+        let reg = response(
+            id,
+            json!({
+                "clientDataJSON": B64URL.encode(&client),
+                "attestationObject": attestation("none", &auth, vec![]),
+                "transports": ["internal"],
+            }),
+        );
+        let record = rp.register(&reg, &create).expect("registers");
+        assert_eq!(record["alg"], "EdDSA");
+        assert_eq!(record["user_id"], "user-1");
+        assert_eq!(record["transports"], json!(["internal"]));
+        assert_eq!(record["user_verified"], true);
+        assert_eq!(record["attestation"], "none");
+
+        // A statement Nitr does not check is not claimed in the record.
+        let tpm = response(
+            id,
+            json!({
+                "clientDataJSON": B64URL.encode(&client),
+                "attestationObject": attestation("tpm", &auth, vec![
+                    ("ver", Cbor::Text("2.0".into())),
+                ]),
+            }),
+        );
+        let unchecked = rp.register(&tpm, &create).expect("registers");
+        assert_eq!(unchecked["attestation"], "none");
+
+        let login = |count: u32, handle: &str| {
+            let get = ceremony("webauthn.get", None);
+            let client = client_data("webauthn.get", &get.challenge, "https://example.com");
+            let auth = auth_data("example.com", USER_PRESENT, count, None);
+            let sig = key.sign(&signed(&auth, &client));
+            let assertion = response(
+                id,
+                json!({
+                    "clientDataJSON": B64URL.encode(&client),
+                    "authenticatorData": B64URL.encode(&auth),
+                    "signature": B64URL.encode(sig.to_bytes()),
+                    "userHandle": B64URL.encode(handle),
+                }),
+            );
+            rp.authenticate(&assertion, &get, &record)
+        };
+        let outcome = login(1, "user-1").expect("authenticates");
+        assert_eq!(outcome["sign_count"], 1);
+        assert_eq!(outcome["user_id"], "user-1");
+        let mut record = record.clone();
+        record["sign_count"] = json!(5.0);
+        let login = |count| {
+            let get = ceremony("webauthn.get", None);
+            let client = client_data("webauthn.get", &get.challenge, "https://example.com");
+            let auth = auth_data("example.com", USER_PRESENT, count, None);
+            let sig = key.sign(&signed(&auth, &client));
+            let assertion = response(
+                id,
+                json!({
+                    "clientDataJSON": B64URL.encode(&client),
+                    "authenticatorData": B64URL.encode(&auth),
+                    "signature": B64URL.encode(sig.to_bytes()),
+                }),
+            );
+            rp.authenticate(&assertion, &get, &record)
+        };
+        assert_eq!(
+            login(5).expect_err("replayed counter"),
+            "signature counter did not increase"
+        );
+        assert_eq!(login(6).expect("authenticates")["sign_count"], 6);
+    }
+
+    /// An ES256 key with packed self-attestation and DER signatures.
+    #[test]
+    fn es256_packed_self_attestation_verifies() {
+        let rp = relying_party();
+        let key = p256::ecdsa::SigningKey::from_slice(&[9; 32]).expect("p256 key");
+        let id = b"credential-2";
+        let create = ceremony("webauthn.create", Some("user-2"));
+        let client = client_data("webauthn.create", &create.challenge, "https://example.com");
+        let auth = auth_data("example.com", USER_PRESENT, 3, Some((id, &p256_cose(&key))));
+        let sig: p256::ecdsa::Signature = key.sign(&signed(&auth, &client));
+        let fields = |sig: &[u8]| {
+            json!({
+                "clientDataJSON": B64URL.encode(&client),
+                "attestationObject": attestation("packed", &auth, vec![
+                    ("alg", Cbor::Integer((-7).into())),
+                    ("sig", Cbor::Bytes(sig.to_vec())),
+                ]),
+            })
+        };
+        let der = sig.to_der();
+        let record = rp
+            .register(&response(id, fields(der.as_bytes())), &create)
+            .expect("registers");
+        assert_eq!(record["alg"], "ES256");
+        assert_eq!(record["attestation"], "packed");
+        assert_eq!(record["sign_count"], 3);
+        assert_eq!(
+            rp.register(&response(id, fields(&sig.to_bytes())), &create)
+                .expect_err("raw signature"),
+            "invalid attestation"
+        );
+
+        let get = ceremony("webauthn.get", None);
+        let client = client_data("webauthn.get", &get.challenge, "https://example.com");
+        let auth = auth_data("example.com", USER_PRESENT, 4, None);
+        let sig: p256::ecdsa::Signature = key.sign(&signed(&auth, &client));
+        let assertion = response(
+            id,
+            json!({
+                "clientDataJSON": B64URL.encode(&client),
+                "authenticatorData": B64URL.encode(&auth),
+                "signature": B64URL.encode(sig.to_der().as_bytes()),
+            }),
+        );
+        assert_eq!(
+            rp.authenticate(&assertion, &get, &record)
+                .expect("authenticates")["sign_count"],
+            4
+        );
+        let mut other = record.clone();
+        other["id"] = json!(B64URL.encode(b"someone-else"));
+        assert_eq!(
+            rp.authenticate(&assertion, &get, &other)
+                .expect_err("other credential"),
+            "credential id mismatch"
+        );
+    }
+
+    /// Each client-data and authenticator-data check rejects on its own.
+    #[test]
+    fn ceremonies_are_bound_to_challenge_origin_and_rp_id() {
+        let mut rp = relying_party();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let id = b"credential-3";
+        let create = ceremony("webauthn.create", Some("user-3"));
+        let attempt = |rp: &RelyingParty,
+                       kind: &str,
+                       challenge: &str,
+                       origin: &str,
+                       rp_id: &str,
+                       flags: u8| {
+            let client = client_data(kind, challenge, origin);
+            let auth = auth_data(rp_id, flags, 0, Some((id, &ed25519_cose(&key))));
+            let reg = response(
+                id,
+                json!({
+                    "clientDataJSON": B64URL.encode(&client),
+                    "attestationObject": attestation("none", &auth, vec![]),
+                }),
+            );
+            rp.register(&reg, &create).map(|_| ())
+        };
+        let challenge = create.challenge.as_str();
+        let ok = "https://example.com";
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.create",
+                challenge,
+                ok,
+                "example.com",
+                USER_PRESENT
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.get",
+                challenge,
+                ok,
+                "example.com",
+                USER_PRESENT
+            ),
+            Err("wrong ceremony type")
+        );
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.create",
+                "b3RoZXI",
+                ok,
+                "example.com",
+                USER_PRESENT
+            ),
+            Err("challenge mismatch")
+        );
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.create",
+                challenge,
+                "https://evil.com",
+                "example.com",
+                USER_PRESENT
+            ),
+            Err("origin not allowed")
+        );
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.create",
+                challenge,
+                ok,
+                "evil.com",
+                USER_PRESENT
+            ),
+            Err("rp id mismatch")
+        );
+        assert_eq!(
+            attempt(&rp, "webauthn.create", challenge, ok, "example.com", 0),
+            Err("user not present")
+        );
+        rp.user_verification = "required".into();
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.create",
+                challenge,
+                ok,
+                "example.com",
+                USER_PRESENT
+            ),
+            Err("user not verified")
+        );
+        rp.user_verification = "preferred".into();
+        rp.algorithms = vec![-7];
+        assert_eq!(
+            attempt(
+                &rp,
+                "webauthn.create",
+                challenge,
+                ok,
+                "example.com",
+                USER_PRESENT
+            ),
+            Err("unsupported algorithm")
+        );
+    }
+
+    #[test]
+    fn options_and_sessions_follow_the_ceremony() {
+        let lua = Lua::new();
+        lua.globals()
+            .set("webauthn", create_webauthn_fn(&lua).expect("webauthn fn"))
+            .expect("global");
+        let (challenge, stored, again): (String, String, Option<String>) = lua
+            .load(
+                r#"
+                local rp = webauthn({ rp_id = "example.com", origins = "https://login.example.com" })
+                local session = {}
+                local opts = rp:registration_options(session, { id = "u1", name = "ada" })
+                assert(opts.rp.id == "example.com" and opts.user.displayName == "ada")
+                assert(#opts.pubKeyCredParams == 3 and opts.timeout == 300000)
+                local stored = session._webauthn.challenge
+                local _, reason = rp:verify_registration(session, "{}")
+                assert(reason == "malformed response", reason)
+                return opts.challenge, stored, session._webauthn
+                "#,
+            )
+            .eval()
+            .expect("ceremony script");
+        assert_eq!(challenge, stored);
+        assert_eq!(again, None);
+
+        for (script, needle) in [
+            (
+                r#"webauthn({ origins = "https://example.com" })"#,
+                "`rp_id` is required",
+            ),
+            (
+                r#"webauthn({ rp_id = "example.com" })"#,
+                "`origins` is required",
+            ),
+            (
+                r#"webauthn({ rp_id = "example.com", origins = "https://example.com.evil.io" })"#,
+                "must be https://example.com",
+            ),
+            (
+                r#"webauthn({ rp_id = "localhost", origins = "http://localhost.evil.io" })"#,
+                "must be https://localhost",
+            ),
+            (
+                r#"webauthn({ rp_id = "example.com", origins = "http://example.com" })"#,
+                "must be https://example.com",
+            ),
+            (
+                r#"webauthn({ rp_id = "example.com", origins = "https://example.com", algorithms = { "HS256" } })"#,
+                "unsupported algorithm `HS256`",
+            ),
+        ] {
+            let err = lua.load(script).exec().expect_err(script).to_string();
+            assert!(err.contains(needle), "{script}: {err}");
+        }
+        lua.load(r#"webauthn({ rp_id = "localhost", origins = { "http://localhost:8080" } })"#)
+            .exec()
+            .expect("localhost over http");
+    }
+}
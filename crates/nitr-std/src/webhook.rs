@@ -0,0 +1,548 @@
+//! `nitr.webhook`: signature checks for inbound webhooks and signatures
+//! for outbound ones.
+//!
+//! `verify(req, opts)` reads the raw body once, in Rust, and checks it
+//! against the provider's scheme: Stripe (`Stripe-Signature`), GitHub
+//! (`X-Hub-Signature-256`), Slack (`X-Slack-Signature`) or Standard
+//! Webhooks (`webhook-id`/`webhook-timestamp`/`webhook-signature`). The
+//! MAC covers the bytes as received — never a re-serialized body — it is
+//! compared in constant time, and schemes that sign a timestamp refuse one
+//! outside the tolerance, so a captured delivery cannot be replayed later.
+//!
+//! `sign(payload, opts)` produces the Standard Webhooks headers for a
+//! webhook this application sends.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD as B64, URL_SAFE_NO_PAD as B64URL};
+use hmac::{Hmac, Mac as _};
+use mlua::{ExternalResult as _, Lua, LuaString, ObjectLike as _, Table, Value};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds a signed timestamp may be off from now, either way, unless the
+/// options say otherwise: the five minutes Stripe and Slack recommend.
+const DEFAULT_TOLERANCE: u64 = 300;
+
+/// Random bytes in a generated Standard Webhooks secret.
+const SECRET_BYTES: usize = 32;
+
+fn webhook_err(msg: impl std::fmt::Display) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("webhook: {msg}"))
+}
+
+/// A provider's signature scheme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scheme {
+    Stripe,
+    GitHub,
+    Slack,
+    Standard,
+}
+
+impl Scheme {
+    fn from_name(name: &str) -> mlua::Result<Self> {
+        match name {
+            "stripe" => Ok(Self::Stripe),
+            "github" => Ok(Self::GitHub),
+            "slack" => Ok(Self::Slack),
+            "standard-webhooks" => Ok(Self::Standard),
+            other => Err(webhook_err(format!(
+                "unknown scheme `{other}`: expected \"stripe\", \"github\", \"slack\" or \
+                 \"standard-webhooks\""
+            ))),
+        }
+    }
+
+    /// The MAC key a configured secret stands for: Standard Webhooks
+    /// secrets are base64 (after an optional `whsec_` prefix), every other
+    /// provider uses the secret string as is.
+    fn key(self, secret: &[u8]) -> mlua::Result<Vec<u8>> {
+        if self != Self::Standard {
+            return Ok(secret.to_vec());
+        }
+        standard_key(secret)
+    }
+}
+
+fn standard_key(secret: &[u8]) -> mlua::Result<Vec<u8>> {
+    let encoded = secret.strip_prefix(b"whsec_").unwrap_or(secret);
+    B64.decode(encoded)
+        .map_err(|_| webhook_err("a Standard Webhooks secret is base64, optionally after `whsec_`"))
+}
+
+/// Why a delivery was refused; the text is what `verify` returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reject {
+    Missing,
+    Malformed,
+    Expired,
+    Invalid,
+}
+
+impl Reject {
+    fn reason(self) -> &'static str {
+        match self {
+            Self::Missing => "missing signature header",
+            Self::Malformed => "malformed signature header",
+            Self::Expired => "timestamp outside the tolerance",
+            Self::Invalid => "invalid signature",
+        }
+    }
+}
+
+/// Checks one delivery, its headers looked up by lowercase name, against
+/// every key; any key may have signed it, so a provider-side secret
+/// rotation can list the old and the new secret.
+fn check(
+    scheme: Scheme,
+    header: &dyn Fn(&str) -> Option<String>,
+    body: &[u8],
+    keys: &[Vec<u8>],
+    tolerance: u64,
+    now: u64,
+) -> Result<(), Reject> {
+    let fresh = |timestamp: &str| -> Result<(), Reject> {
+        let timestamp: u64 = timestamp.trim().parse().map_err(|_| Reject::Malformed)?;
+        match now.abs_diff(timestamp) <= tolerance {
+            true => Ok(()),
+            false => Err(Reject::Expired),
+        }
+    };
+    // What was signed, and the candidate signatures (raw MAC bytes).
+    let (signed, signatures): (Vec<u8>, Vec<Vec<u8>>) = match scheme {
+        Scheme::Stripe => {
+            let value = header("stripe-signature").ok_or(Reject::Missing)?;
+            let mut timestamp = None;
+            let mut signatures = Vec::new();
+            for item in value.split(',') {
+                match item.trim().split_once('=') {
+                    Some(("t", t)) => timestamp = Some(t.to_string()),
+                    Some(("v1", sig)) => signatures.extend(hex_decode(sig)),
+                    _ => {}
+                }
+            }
+            let timestamp = timestamp.ok_or(Reject::Malformed)?;
+            if signatures.is_empty() {
+                return Err(Reject::Malformed);
+            }
+            fresh(&timestamp)?;
+            ([timestamp.as_bytes(), b".", body].concat(), signatures)
+        }
+        Scheme::GitHub => {
+            let value = header("x-hub-signature-256").ok_or(Reject::Missing)?;
+            let sig = value
+                .strip_prefix("sha256=")
+                .and_then(hex_decode)
+                .ok_or(Reject::Malformed)?;
+            (body.to_vec(), vec![sig])
+        }
+        Scheme::Slack => {
+            let value = header("x-slack-signature").ok_or(Reject::Missing)?;
+            let timestamp = header("x-slack-request-timestamp").ok_or(Reject::Missing)?;
+            let sig = value
+                .strip_prefix("v0=")
+                .and_then(hex_decode)
+                .ok_or(Reject::Malformed)?;
+            fresh(&timestamp)?;
+            (
+                [b"v0:", timestamp.trim().as_bytes(), b":", body].concat(),
+                vec![sig],
+            )
+        }
+        Scheme::Standard => {
+            let id = header("webhook-id").ok_or(Reject::Missing)?;
+            let timestamp = header("webhook-timestamp").ok_or(Reject::Missing)?;
+            let value = header("webhook-signature").ok_or(Reject::Missing)?;
+            // Space-separated `version,signature` pairs; only `v1` is
+            // HMAC-SHA256, other versions are not ours to check.
+            let signatures: Vec<Vec<u8>> = value
+                .split_whitespace()
+                .filter_map(|entry| entry.strip_prefix("v1,"))
+                .filter_map(|sig| B64.decode(sig).ok())
+                .collect();
+            if signatures.is_empty() {
+                return Err(Reject::Malformed);
+            }
+            fresh(&timestamp)?;
+            let timestamp = timestamp.trim();
+            (
+                [id.as_bytes(), b".", timestamp.as_bytes(), b".", body].concat(),
+                signatures,
+            )
+        }
+    };
+    for key in keys {
+        let mac: HmacSha256 = crate::utils::new_hmac(key);
+        let mac = mac.chain_update(&signed);
+        // `verify_slice` is the constant-time comparison.
+        if signatures
+            .iter()
+            .any(|sig| mac.clone().verify_slice(sig).is_ok())
+        {
+            return Ok(());
+        }
+    }
+    Err(Reject::Invalid)
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().as_bytes();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16);
+    text.chunks_exact(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some((digit(*hi)? * 16 + digit(*lo)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The Standard Webhooks `webhook-signature` value for one message.
+fn standard_signature(key: &[u8], id: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac: HmacSha256 = crate::utils::new_hmac(key);
+    mac.update(format!("{id}.{timestamp}.").as_bytes());
+    mac.update(body);
+    format!("v1,{}", B64.encode(mac.finalize().into_bytes()))
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn random_bytes<const N: usize>() -> mlua::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    getrandom::getrandom(&mut buf)
+        .map_err(|err| webhook_err(format!("failed to read OS entropy: {err}")))?;
+    Ok(buf)
+}
+
+/// The `secret` option: one string, or a list of them.
+fn secrets(opts: &Table) -> mlua::Result<Vec<Vec<u8>>> {
+    match opts.get::<Value>("secret")? {
+        Value::String(secret) => Ok(vec![secret.as_bytes().to_vec()]),
+        Value::Table(list) => {
+            let list = list
+                .sequence_values::<LuaString>()
+                .map(|secret| Ok(secret?.as_bytes().to_vec()))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            match list.is_empty() {
+                true => Err(webhook_err("the `secret` list is empty")),
+                false => Ok(list),
+            }
+        }
+        Value::Nil => Err(webhook_err("the `secret` option is required")),
+        other => Err(webhook_err(format!(
+            "`secret` must be a string or a list of strings, got {}",
+            other.type_name()
+        ))),
+    }
+}
+
+/// `nitr.webhook.verify(req, opts)`: the verified raw body, or nil and
+/// the reason.
+async fn verify(lua: Lua, req: Value, opts: Table) -> mlua::Result<(Value, Value)> {
+    let scheme = Scheme::from_name(
+        &opts
+            .get::<Option<String>>("scheme")?
+            .ok_or_else(|| webhook_err("the `scheme` option is required"))?,
+    )?;
+    let keys = secrets(&opts)?
+        .iter()
+        .map(|secret| scheme.key(secret))
+        .collect::<mlua::Result<Vec<_>>>()?;
+    let tolerance = opts
+        .get::<Option<u64>>("tolerance")?
+        .unwrap_or(DEFAULT_TOLERANCE);
+    let Value::UserData(ud) = req else {
+        return Err(webhook_err(format!(
+            "verify takes the request object, got {}",
+            req.type_name()
+        )));
+    };
+    let headers: Table = ud.get("headers")?;
+    let body: LuaString = ud.call_async_method("text", ()).await?;
+    let header = |name: &str| headers.get::<Option<String>>(name).ok().flatten();
+    match check(
+        scheme,
+        &header,
+        &body.as_bytes(),
+        &keys,
+        tolerance,
+        unix_now(),
+    ) {
+        Ok(()) => Ok((Value::String(body), Value::Nil)),
+        Err(reject) => Ok((
+            Value::Nil,
+            Value::String(lua.create_string(reject.reason())?),
+        )),
+    }
+}
+
+/// `nitr.webhook.sign(payload, opts)`: the Standard Webhooks headers for
+/// an outbound message, and the body they sign.
+fn sign(lua: &Lua, payload: Value, opts: Table) -> mlua::Result<(Table, LuaString)> {
+    let body = match payload {
+        Value::String(body) => body,
+        Value::Table(_) => {
+            crate::utils::check_json_depth(&payload)?;
+            lua.create_string(serde_json::to_string(&payload).into_lua_err()?)?
+        }
+        other => {
+            return Err(webhook_err(format!(
+                "sign takes a string or a table payload, got {}",
+                other.type_name()
+            )));
+        }
+    };
+    let secret: LuaString = opts
+        .get::<Option<LuaString>>("secret")?
+        .ok_or_else(|| webhook_err("the `secret` option is required"))?;
+    let key = standard_key(&secret.as_bytes())?;
+    let id = match opts.get::<Option<String>>("id")? {
+        Some(id) => id,
+        None => format!("msg_{}", B64URL.encode(random_bytes::<16>()?)),
+    };
+    let timestamp = opts
+        .get::<Option<u64>>("timestamp")?
+        .unwrap_or_else(unix_now);
+
+    let headers = lua.create_table()?;
+    headers.set(
+        "webhook-signature",
+        standard_signature(&key, &id, timestamp, &body.as_bytes()),
+    )?;
+    headers.set("webhook-id", id)?;
+    headers.set("webhook-timestamp", timestamp.to_string())?;
+    Ok((headers, body))
+}
+
+/// Builds the `nitr.webhook` table.
+pub(crate) fn create_webhook_table(lua: &Lua) -> mlua::Result<Table> {
+    let webhook = lua.create_table()?;
+    webhook.set(
+        "verify",
+        lua.create_async_function(|lua, (req, opts): (Value, Table)| verify(lua, req, opts))?,
+    )?;
+    webhook.set(
+        "sign",
+        lua.create_function(|lua, (payload, opts): (Value, Table)| sign(lua, payload, opts))?,
+    )?;
+    // A fresh secret to hand a subscriber, in the `whsec_` form both
+    // sides of Standard Webhooks read.
+    webhook.set(
+        "secret",
+        lua.create_function(|_, ()| {
+            Ok(format!(
+                "whsec_{}",
+                B64.encode(random_bytes::<SECRET_BYTES>()?)
+            ))
+        })?,
+    )?;
+    Ok(webhook)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn hmac_hex(key: &[u8], data: &[u8]) -> String {
+        let mut mac: HmacSha256 = crate::utils::new_hmac(key);
+        mac.update(data);
+        hex(&mac.finalize().into_bytes())
+    }
+
+    fn headers(pairs: &[(&str, String)]) -> impl Fn(&str) -> Option<String> + use<> {
+        let pairs: Vec<(String, String)> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        move |name| {
+            pairs
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        }
+    }
+
+    const NOW: u64 = 1_700_000_000;
+    const BODY: &[u8] = br#"{"id":"evt_1","type":"ping"}"#;
+
+    #[test]
+    fn stripe_signatures_check_the_timestamp_and_any_v1() {
+        let secret = b"whsec_stripe".to_vec();
+        let sig = hmac_hex(&secret, &[format!("{NOW}.").as_bytes(), BODY].concat());
+        let header = headers(&[(
+            "stripe-signature",
+            format!("t={NOW},v1={},v0=ignored,v1={sig}", hex(&[0; 32])),
+        )]);
+        let keys = [secret];
+        assert_eq!(
+            check(Scheme::Stripe, &header, BODY, &keys, 300, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            check(Scheme::Stripe, &header, BODY, &keys, 300, NOW + 301),
+            Err(Reject::Expired)
+        );
+        assert_eq!(
+            check(Scheme::Stripe, &header, b"{}", &keys, 300, NOW),
+            Err(Reject::Invalid)
+        );
+        let no_v1 = headers(&[("stripe-signature", format!("t={NOW}"))]);
+        assert_eq!(
+            check(Scheme::Stripe, &no_v1, BODY, &keys, 300, NOW),
+            Err(Reject::Malformed)
+        );
+        assert_eq!(
+            check(Scheme::Stripe, &headers(&[]), BODY, &keys, 300, NOW),
+            Err(Reject::Missing)
+        );
+    }
+
+    #[test]
+    fn github_and_slack_signatures_verify_the_raw_body() {
+        // GitHub's documented example: secret, payload and signature.
+        let github = headers(&[(
+            "x-hub-signature-256",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17".into(),
+        )]);
+        let keys = [b"It's a Secret to Everybody".to_vec()];
+        assert_eq!(
+            check(Scheme::GitHub, &github, b"Hello, World!", &keys, 0, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            check(Scheme::GitHub, &github, b"Hello, World! ", &keys, 0, NOW),
+            Err(Reject::Invalid)
+        );
+
+        // Slack signs `v0:<timestamp>:<body>`; a rotated secret list
+        // accepts either key.
+        let secret = b"8f742231b10e8888abcd99yyyzzz85a5".to_vec();
+        let base = [format!("v0:{NOW}:").as_bytes(), BODY].concat();
+        let slack = headers(&[
+            (
+                "x-slack-signature",
+                format!("v0={}", hmac_hex(&secret, &base)),
+            ),
+            ("x-slack-request-timestamp", NOW.to_string()),
+        ]);
+        let keys = [b"old-secret".to_vec(), secret];
+        assert_eq!(check(Scheme::Slack, &slack, BODY, &keys, 300, NOW), Ok(()));
+        assert_eq!(
+            check(Scheme::Slack, &slack, BODY, &keys, 300, NOW - 600),
+            Err(Reject::Expired)
+        );
+    }
+
+    #[test]
+    fn standard_webhooks_round_trip_through_sign() {
+        let lua = Lua::new();
+        let webhook = create_webhook_table(&lua).expect("table");
+        let secret: String = webhook
+            .get::<mlua::Function>("secret")
+            .expect("fn")
+            .call(())
+            .expect("secret");
+        assert!(secret.starts_with("whsec_"), "{secret}");
+
+        let opts = lua.create_table().expect("opts");
+        opts.set("secret", secret.as_str()).expect("set");
+        opts.set("id", "msg_1").expect("set");
+        opts.set("timestamp", NOW).expect("set");
+        let payload = lua.create_table().expect("payload");
+        payload.set("type", "ping").expect("set");
+        let (signed, body): (Table, String) = webhook
+            .get::<mlua::Function>("sign")
+            .expect("fn")
+            .call((payload, opts))
+            .expect("sign");
+        assert_eq!(body, r#"{"type":"ping"}"#);
+        let signed = |name: &str| signed.get::<Option<String>>(name).expect("header");
+        assert_eq!(signed("webhook-id").as_deref(), Some("msg_1"));
+        assert_eq!(signed("webhook-timestamp"), Some(NOW.to_string()));
+
+        let keys = [Scheme::Standard.key(secret.as_bytes()).expect("key")];
+        assert_eq!(
+            check(Scheme::Standard, &signed, body.as_bytes(), &keys, 300, NOW),
+            Ok(())
+        );
+        // The id is signed too: a replay under a new id fails.
+        let replayed = |name: &str| match name {
+            "webhook-id" => Some("msg_2".into()),
+            other => signed(other),
+        };
+        assert_eq!(
+            check(
+                Scheme::Standard,
+                &replayed,
+                body.as_bytes(),
+                &keys,
+                300,
+                NOW
+            ),
+            Err(Reject::Invalid)
+        );
+        assert_eq!(
+            check(
+                Scheme::Standard,
+                &signed,
+                body.as_bytes(),
+                &keys,
+                300,
+                NOW + 3600
+            ),
+            Err(Reject::Expired)
+        );
+    }
+
+    #[test]
+    fn the_standard_webhooks_reference_vector_verifies() {
+        // From the Standard Webhooks specification's test suite.
+        let key = Scheme::Standard
+            .key(b"whsec_MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw")
+            .expect("key");
+        let body = br#"{"test": 2432232314}"#;
+        let header = headers(&[
+            ("webhook-id", "msg_p5jXN8AQM9LWM0D4loKWxJek".into()),
+            ("webhook-timestamp", "1614265330".into()),
+            (
+                "webhook-signature",
+                "v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=".into(),
+            ),
+        ]);
+        assert_eq!(
+            check(Scheme::Standard, &header, body, &[key], 300, 1_614_265_330),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn misconfiguration_raises_instead_of_rejecting() {
+        let lua = Lua::new();
+        let webhook = create_webhook_table(&lua).expect("table");
+        lua.globals().set("webhook", webhook).expect("global");
+        for (script, needle) in [
+            (r#"webhook.sign("x", {})"#, "secret"),
+            (r#"webhook.sign("x", { secret = "whsec_!!" })"#, "base64"),
+            (r#"webhook.sign(42, { secret = "whsec_AAAA" })"#, "payload"),
+        ] {
+            let err = lua.load(script).exec().expect_err(script);
+            assert!(err.to_string().contains(needle), "{script}: {err}");
+        }
+        assert!(Scheme::from_name("paypal").is_err());
+        let opts = lua.create_table().expect("opts");
+        opts.set("secret", lua.create_table().expect("empty"))
+            .expect("set");
+        assert!(secrets(&opts).is_err());
+    }
+}
@@ -110,8 +110,9 @@ app:get("/open", function(req)
     return nitr.json({ contents = opened })
 end)
 
--- JWTs, HMAC-only: verify demands an explicit algorithm allow-list and
--- checks `exp`/`nbf` by default.
+-- JWTs, HMAC-signed here (`jwt.keyset` loads public keys for RS256/ES256/
+-- EdDSA tokens from an identity provider): verify demands an explicit
+-- algorithm allow-list and checks `exp`/`nbf` by default.
 app:post("/jwt", function(req)
     local token = nitr.crypto.jwt.sign(
         { sub = "ada", exp = nitr.time.now() + 3600 },
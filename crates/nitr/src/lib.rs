@@ -30,8 +30,10 @@ pub use nitr_core::{
     mount, nitr_table,
 };
 pub use nitr_http::{
-    CacheConfig, CompressionConfig, Config, CorsConfig, DatabaseConfig, FetchConfig, HealthConfig,
-    LimitsConfig, LogConfig, LogFormat, LuaConfig, RateLimitConfig, Server, ServerBuilder,
-    ShutdownConfig, StdConfig,
+    AccessConfig, CacheBackend, CacheConfig, CompressionConfig, Config, CorsConfig, DatabaseConfig,
+    FetchConfig, HeadersConfig, HeadersMount, HealthConfig, JobsConfig, KvConfig, LimitsConfig,
+    LogConfig, LogFormat, LuaConfig, PubSubConfig, RateLimitConfig, RedisConfig, RedisFallback,
+    ReplicaConfig, ScheduleConfig, ScheduleInfo, SearchConfig, Server, ServerBuilder,
+    ShutdownConfig, SlowConsumer, StdConfig,
 };
 pub use nitr_std::{Builtins, BuiltinsEnv};
@@ -264,3 +264,278 @@ return app
 
     server.stop().await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn committed_changes_reach_an_sse_subscriber() {
+    const APP: &str = r#"
+local app = nitr.app()
+
+app:get("/feed", function(req)
+    local changes = nitr.db:changes({ "orders" })
+    return nitr.sse(function(send)
+        -- Subscribed above; tell the client it can start writing.
+        send("ready", "")
+        for change in changes do
+            send("change", change)
+            if change.op == "delete" then return end
+        end
+    end)
+end)
+
+app:post("/orders", function(req)
+    nitr.db:execute("INSERT INTO notes (v) VALUES ('ignored')")
+    pcall(function()
+        nitr.db:transaction(function(tx)
+            tx:execute("INSERT INTO orders (total) VALUES (99)")
+            error("rolled back")
+        end)
+    end)
+    nitr.db:execute("INSERT INTO orders (total) VALUES (5)")
+    nitr.db:execute("DELETE FROM orders WHERE total = 5")
+    return nitr.text("ok")
+end)
+
+return app
+"#;
+
+    let mut server = TestServer::builder("db-changes")
+        .handler(APP)
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::DATABASE)
+        .database("t.db")
+        .seed_sql(
+            "CREATE TABLE orders (id INTEGER PRIMARY KEY, total INTEGER);
+             CREATE TABLE notes (id INTEGER PRIMARY KEY, v TEXT);",
+        )
+        .config(|cfg| {
+            cfg.workers = 2;
+            if let Some(db) = cfg.database.as_mut() {
+                db.changes = true;
+            }
+        })
+        .spawn()
+        .await;
+    let client = server.client().clone();
+
+    let mut feed = client.get(server.url("/feed")).send().await.expect("feed");
+    assert_eq!(feed.headers()["content-type"], "text/event-stream");
+    let mut body = String::new();
+    while !body.contains("event: ready") {
+        let chunk = feed.chunk().await.expect("chunk").expect("ready event");
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+
+    let resp = client
+        .post(server.url("/orders"))
+        .send()
+        .await
+        .expect("write");
+    assert_eq!(resp.status(), 200);
+
+    let rest = tokio::time::timeout(std::time::Duration::from_secs(5), feed.text())
+        .await
+        .expect("feed ends after the delete")
+        .expect("feed body");
+    let changes: Vec<serde_json::Value> = rest
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).expect("change json"))
+        .collect();
+    // The rolled-back insert and the unwatched table never show up.
+    assert_eq!(changes.len(), 2, "got: {rest}");
+    assert_eq!(changes[0]["table"], "orders");
+    assert_eq!(changes[0]["op"], "insert");
+    assert_eq!(changes[1]["op"], "delete");
+    assert_eq!(changes[0]["rowid"], changes[1]["rowid"]);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn full_text_search_ranks_highlights_and_sanitizes() {
+    const APP: &str = r#"
+local app = nitr.app()
+
+app:get("/search", function(req)
+    local rows = nitr.db:search("notes_search", req.query.q, {
+        snippet = { column = "body", tokens = 8 },
+        highlight = "title",
+        limit = 5,
+    })
+    return nitr.json({ count = #rows, rows = rows })
+end)
+
+return app
+"#;
+
+    let mut builder = TestServer::builder("db-search")
+        .handler(APP)
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::DATABASE)
+        .database("t.db")
+        .config(|cfg| {
+            cfg.workers = 1;
+            if let Some(db) = cfg.database.as_mut() {
+                db.search = vec![nitr::SearchConfig {
+                    name: "notes_search".into(),
+                    table: "notes".into(),
+                    key: "id".into(),
+                    columns: vec!["title".into(), "body".into()],
+                    tokenize: Some("porter unicode61".into()),
+                }];
+            }
+        });
+
+    // Seeded by hand rather than with `seed_sql`, which would run again
+    // at spawn.
+    let conn = rusqlite::Connection::open(builder.db_path()).expect("open");
+    conn.execute_batch(
+        "CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+         INSERT INTO notes (title, body) VALUES
+             ('Apple pie', 'bake the apples with <cinnamon>'),
+             ('Groceries', 'apples, pears and an apple crumble'),
+             ('Trip', 'pack the bags');",
+    )
+    .expect("seed");
+
+    // An index that was never built refuses the boot, like a pending
+    // migration.
+    let err = builder.try_build().await.expect_err("index not built");
+    assert!(err.to_string().contains("nitr migrate"), "got: {err}");
+    let indexes = vec![nitr::stdlib::SearchIndex {
+        name: "notes_search".into(),
+        table: "notes".into(),
+        key: "id".into(),
+        columns: vec!["title".into(), "body".into()],
+        tokenize: Some("porter unicode61".into()),
+    }];
+    nitr::stdlib::search::apply(&conn, &indexes).expect("build index");
+    drop(conn);
+
+    let mut server = builder.spawn().await;
+    let client = server.client().clone();
+    let search = |q: &'static str| {
+        let client = client.clone();
+        let url = server.url("/search");
+        async move {
+            client
+                .get(url)
+                .query(&[("q", q)])
+                .send()
+                .await
+                .expect("search")
+                .json::<serde_json::Value>()
+                .await
+                .expect("json")
+        }
+    };
+
+    // Stemmed, ranked, escaped around the markers.
+    let body = search("apple").await;
+    assert_eq!(body["count"], 2, "got: {body}");
+    let first = &body["rows"][0];
+    assert_eq!(first["title"], "Apple pie");
+    assert_eq!(first["highlight"], "<mark>Apple</mark> pie");
+    assert_eq!(
+        first["snippet"],
+        "bake the <mark>apples</mark> with &lt;cinnamon&gt;"
+    );
+    assert!(first["rank"].as_f64().expect("rank") < 0.0);
+
+    // FTS5 syntax in a search box is just text.
+    for q in ["\"", "apple\" OR", "NEAR(", "-", "*"] {
+        let body = search(q).await;
+        assert!(body["count"].is_number(), "{q}: {body}");
+    }
+    assert_eq!(search("pea*").await["count"], 1);
+
+    server.stop().await;
+}
+
+/// Reads changes on `orders` and `notes` made by the route's own writes:
+/// `/commit` writes inside a committed `db:transaction`, `/rollback` inside
+/// one that fails, and both end with an autocommit marker on `notes`.
+const TRANSACTION_CHANGES_APP: &str = r#"
+local app = nitr.app()
+
+local function collect(changes)
+    local seen = {}
+    for change in changes do
+        seen[#seen + 1] = change.table .. ":" .. change.op
+        if change.table == "notes" then break end
+    end
+    return nitr.json(seen)
+end
+
+app:post("/commit", function(req)
+    local changes = nitr.db:changes({ "orders", "notes" })
+    nitr.db:transaction(function(tx)
+        tx:execute("INSERT INTO orders (total) VALUES (1)")
+        tx:execute("UPDATE orders SET total = 2")
+    end)
+    nitr.db:execute("INSERT INTO notes (v) VALUES ('marker')")
+    return collect(changes)
+end)
+
+app:post("/rollback", function(req)
+    local changes = nitr.db:changes({ "orders", "notes" })
+    pcall(function()
+        nitr.db:transaction(function(tx)
+            tx:execute("INSERT INTO orders (total) VALUES (3)")
+            error("rolled back")
+        end)
+    end)
+    nitr.db:execute("INSERT INTO notes (v) VALUES ('marker')")
+    return collect(changes)
+end)
+
+return app
+"#;
+
+async fn transaction_changes_server(label: &str) -> TestServer {
+    TestServer::builder(label)
+        .handler(TRANSACTION_CHANGES_APP)
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::DATABASE)
+        .database("t.db")
+        .seed_sql(
+            "CREATE TABLE orders (id INTEGER PRIMARY KEY, total INTEGER);
+             CREATE TABLE notes (id INTEGER PRIMARY KEY, v TEXT);",
+        )
+        .config(|cfg| {
+            cfg.workers = 1;
+            if let Some(db) = cfg.database.as_mut() {
+                db.changes = true;
+            }
+        })
+        .spawn()
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_committed_transaction_publishes_its_changes() {
+    let mut server = transaction_changes_server("db-changes-commit").await;
+    let resp = server
+        .client()
+        .post(server.url("/commit"))
+        .send()
+        .await
+        .expect("commit");
+    let seen: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(
+        seen,
+        serde_json::json!(["orders:insert", "orders:update", "notes:insert"])
+    );
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_rolled_back_transaction_publishes_nothing() {
+    let mut server = transaction_changes_server("db-changes-rollback").await;
+    let resp = server
+        .client()
+        .post(server.url("/rollback"))
+        .send()
+        .await
+        .expect("rollback");
+    let seen: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(seen, serde_json::json!(["notes:insert"]));
+    server.stop().await;
+}
@@ -206,6 +206,7 @@ trait PipeOk: Sized {
     }
 }
 impl PipeOk for u16 {}
+impl PipeOk for u64 {}
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
 async fn the_outer_handle_refuses_to_run_inside_a_transaction() {
@@ -331,6 +332,28 @@ app:get("/uncacheable", function(req)
     return nitr.json({ ok = ok, err = tostring(err) })
 end)
 
+app:get("/hit", function(req)
+    return nitr.json({ n = nitr.cache:incr("hits", 1, { ttl = 60 }) })
+end)
+
+app:get("/lock", function(req)
+    return nitr.json({ won = nitr.cache:add("lock", req.id, { ttl = 60 }) })
+end)
+
+app:get("/tags", function(req)
+    nitr.cache:set("user:42:profile", { name = "ada" }, { tags = { "user:42" } })
+    nitr.cache:set("user:42:feed", { 1, 2 }, { tags = { "user:42", "feeds" } })
+    nitr.cache:set("user:7:feed", { 3 }, { tags = { "feeds" } })
+    local dropped = nitr.cache:invalidate_tag("user:42")
+    return nitr.json({
+        dropped = dropped,
+        profile = nitr.cache:get("user:42:profile"),
+        other = nitr.cache:get("user:7:feed"),
+        swapped = nitr.cache:cas("user:7:feed", { 3 }, { 3, 4 }),
+        stale = nitr.cache:cas("user:7:feed", { 3 }, { 5 }),
+    })
+end)
+
 return app
 "#;
 
@@ -380,6 +403,336 @@ async fn the_cache_is_shared_across_states_and_bounded() {
     srv.stop().await;
 }
 
+/// `incr` and `add` are atomic across pooled states, and tags drop whole
+/// families of derived entries at once.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cache_counters_locks_and_tags_hold_across_states() {
+    let mut srv = builder(CACHE_SCRIPT)
+        .std_features(&["json", "http", "cache"])
+        .spawn()
+        .await;
+
+    let mut requests = Vec::new();
+    for path in ["/hit", "/lock"] {
+        for _ in 0..20 {
+            let client = srv.client().clone();
+            let url = srv.url(path);
+            requests.push(tokio::spawn(async move {
+                client
+                    .get(url)
+                    .send()
+                    .await?
+                    .json::<serde_json::Value>()
+                    .await
+            }));
+        }
+    }
+    let (mut counts, mut winners) = (Vec::new(), 0);
+    for handle in requests {
+        let body = handle.await.expect("task").expect("request");
+        match body["n"].as_u64() {
+            Some(n) => counts.push(n),
+            None => winners += usize::from(body["won"] == true),
+        }
+    }
+    counts.sort_unstable();
+    assert_eq!(counts, (1..=20).collect::<Vec<_>>(), "no increment lost");
+    assert_eq!(winners, 1, "exactly one state takes the lock");
+
+    let body = srv.json("/tags").await;
+    assert_eq!(body["dropped"], 2);
+    assert_eq!(body["profile"], serde_json::Value::Null);
+    assert_eq!(body["other"], serde_json::json!([3]));
+    assert_eq!(body["swapped"], true);
+    assert_eq!(body["stale"], false);
+
+    srv.stop().await;
+}
+
+/// With `[cache] persist`, a graceful stop snapshots the cache and the next
+/// server starts warm from it.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_persisted_cache_survives_a_restart() {
+    let store = TestDir::new("data-io-cache");
+    let snapshot = store.join("cache.snapshot");
+    let spawn = || {
+        let snapshot = snapshot.clone();
+        builder(CACHE_SCRIPT)
+            .std_features(&["json", "http", "cache"])
+            .config(move |cfg| cfg.cache.persist = Some(snapshot))
+            .spawn()
+    };
+
+    let mut srv = spawn().await;
+    srv.json("/set?who=alice").await;
+    assert_eq!(srv.json("/hit").await["n"], 1);
+    srv.stop().await;
+    assert!(snapshot.exists(), "a graceful stop writes the snapshot");
+
+    let mut srv = spawn().await;
+    assert_eq!(srv.json("/get").await["value"]["who"], "alice");
+    assert_eq!(srv.json("/hit").await["n"], 2);
+    srv.stop().await;
+}
+
+// ---------------------------------------------------------------------------
+
+const KV_SCRIPT: &str = r#"
+local app = nitr.app()
+
+app:get("/write", function(req)
+    nitr.kv:set("flag:beta", { on = true })
+    nitr.kv:set("flag:dark", { on = false })
+    nitr.kv:set("flag:gone", 1, { ttl = 0.05 })
+    nitr.kv:set("other", "x")
+    return nitr.json({ ok = true })
+end)
+
+app:get("/hit", function(req)
+    return nitr.json({ n = nitr.kv:incr("hits") })
+end)
+
+app:get("/read", function(req)
+    local keys = {}
+    for _, entry in ipairs(nitr.kv:scan("flag:")) do
+        keys[#keys + 1] = entry.key
+    end
+    local page = nitr.kv:scan("flag:", { limit = 1, after = "flag:beta" })
+    return nitr.json({
+        beta = nitr.kv:get("flag:beta"),
+        hits = nitr.kv:get("hits"),
+        keys = keys,
+        page = page[1].key,
+        swapped = nitr.kv:cas("other", "x", "y"),
+        stale = nitr.kv:cas("other", "x", "z"),
+        other = nitr.kv:get("other"),
+    })
+end)
+
+return app
+"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn the_kv_store_is_shared_and_survives_a_restart() {
+    // Outlives both servers: the store is the one thing they share.
+    let store = TestDir::new("data-io-kv");
+    let kv_path = store.join("kv.db");
+    let spawn = || {
+        let kv_path = kv_path.clone();
+        builder(KV_SCRIPT)
+            .std_features(&["json", "http", "kv"])
+            .config(move |cfg| cfg.kv.path = Some(kv_path))
+            .spawn()
+    };
+
+    let mut srv = spawn().await;
+    srv.json("/write").await;
+    // Increments from every state land on one counter.
+    let mut requests = Vec::new();
+    for _ in 0..20 {
+        let client = srv.client().clone();
+        let url = srv.url("/hit");
+        requests.push(tokio::spawn(async move {
+            let body: serde_json::Value = client.get(url).send().await?.json().await?;
+            body["n"].as_u64().unwrap_or_default().pipe_ok()
+        }));
+    }
+    let mut seen = Vec::new();
+    for handle in requests {
+        seen.push(handle.await.expect("task").expect("request"));
+    }
+    seen.sort_unstable();
+    assert_eq!(seen, (1..=20).collect::<Vec<_>>());
+    srv.stop().await;
+
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let mut srv = spawn().await;
+    let body = srv.json("/read").await;
+    assert_eq!(body["beta"]["on"], true);
+    assert_eq!(body["hits"], 20);
+    // The expired key is gone from reads even before any sweep ran.
+    assert_eq!(body["keys"], serde_json::json!(["flag:beta", "flag:dark"]));
+    assert_eq!(body["page"], "flag:dark");
+    assert_eq!(body["swapped"], true);
+    assert_eq!(body["stale"], false);
+    assert_eq!(body["other"], "y");
+    srv.stop().await;
+}
+
+const JOBS_SCRIPT: &str = r#"
+local app = nitr.app()
+
+app:job("record", function(payload, job)
+    nitr.kv:set("recorded:" .. payload.n, job.attempt)
+end)
+
+-- Fails its first attempt, then succeeds on the retry.
+app:job("flaky", function(payload, job)
+    if job.attempt < 2 then error("not yet") end
+    nitr.kv:set("flaky", job.attempt)
+end)
+
+app:job("broken", function()
+    error("always broken")
+end)
+
+app:post("/enqueue", function(req)
+    local ids = {}
+    for n = 1, 5 do ids[#ids + 1] = nitr.jobs.enqueue("record", { n = n }) end
+    local first, created = nitr.jobs.enqueue("flaky", nil, { unique = "flaky" })
+    local again, created_again = nitr.jobs.enqueue("flaky", nil, { unique = "flaky" })
+    nitr.jobs.enqueue("broken", nil, { max_attempts = 2 })
+    return nitr.json({
+        ids = #ids, deduped = first == again, created = created, again = created_again,
+    })
+end)
+
+app:get("/state", function(req)
+    local recorded = 0
+    for _ in ipairs(nitr.kv:scan("recorded:")) do recorded = recorded + 1 end
+    return nitr.json({ recorded = recorded, flaky = nitr.kv:get("flaky") })
+end)
+
+return app
+"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn jobs_run_in_the_background_retry_and_dead_letter() {
+    let mut srv = builder(JOBS_SCRIPT)
+        .database("jobs.db")
+        .std_features(&["json", "http", "kv", "jobs"])
+        .config(|cfg| {
+            cfg.jobs.workers = Some(1);
+            cfg.jobs.poll_ms = 20;
+            cfg.jobs.backoff_ms = 10;
+        })
+        .spawn()
+        .await;
+    let body: serde_json::Value = srv
+        .client()
+        .post(srv.url("/enqueue"))
+        .send()
+        .await
+        .expect("enqueue")
+        .json()
+        .await
+        .expect("json");
+    assert_eq!(body["ids"], 5);
+    assert_eq!(body["deduped"], true);
+    assert_eq!(body["created"], true);
+    assert_eq!(body["again"], false);
+
+    let mut state = serde_json::Value::Null;
+    for _ in 0..200 {
+        state = srv.json("/state").await;
+        if state["recorded"] == 5 && state["flaky"] == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(state["recorded"], 5, "every job ran: {state}");
+    assert_eq!(state["flaky"], 2, "the failed attempt was retried: {state}");
+
+    // The broken job ends up dead, with the handler's error kept.
+    let queue = nitr::stdlib::Jobs::open(srv.db_path(), &Default::default(), Default::default())
+        .expect("open the queue");
+    let mut dead = Vec::new();
+    for _ in 0..200 {
+        dead = queue
+            .list(Some(nitr::stdlib::jobs::JobState::Dead), 10)
+            .expect("list");
+        if !dead.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(dead.len(), 1);
+    assert_eq!((dead[0].name.as_str(), dead[0].attempts), ("broken", 2));
+    let error = dead[0].last_error.as_deref().unwrap_or_default();
+    assert!(error.contains("always broken"), "got: {error}");
+    assert_eq!(
+        queue.list(None, 10).expect("list").len(),
+        1,
+        "finished jobs are gone"
+    );
+    drop(queue);
+    srv.stop().await;
+}
+
+// ---------------------------------------------------------------------------
+
+const SCHEDULE_SCRIPT: &str = r#"
+local app = nitr.app()
+
+app:every("50ms", function(run)
+    nitr.kv:incr("ticks:" .. nitr.cfg.instance)
+end, { name = "tick" })
+
+app:cron("@yearly", function() end)
+
+app:get("/ticks", function(req)
+    return nitr.json({ a = nitr.kv:get("ticks:a") or 0, b = nitr.kv:get("ticks:b") or 0 })
+end)
+
+return app
+"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn schedules_run_on_the_one_instance_holding_the_lease() {
+    let lease = |cfg: &mut nitr::Config| cfg.schedule.lease_ms = 300;
+    let a = builder(SCHEDULE_SCRIPT)
+        .database("shared.db")
+        .std_features(&["json", "http", "kv"])
+        .config_script(r#"return { instance = "a" }"#)
+        .config(lease)
+        .spawn()
+        .await;
+    let shared = a.db_path().to_path_buf();
+    let b = builder(SCHEDULE_SCRIPT)
+        .std_features(&["json", "http", "kv"])
+        .config_script(r#"return { instance = "b" }"#)
+        .config(lease)
+        .config(move |cfg| cfg.database = Some(nitr::DatabaseConfig::new(&shared)))
+        .spawn()
+        .await;
+
+    let mut ticks = serde_json::Value::Null;
+    for _ in 0..200 {
+        ticks = a.json("/ticks").await;
+        if ticks["a"].as_i64().unwrap_or(0) + ticks["b"].as_i64().unwrap_or(0) >= 4 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let ((holder, mut holder_srv), (standby, mut standby_srv)) = if ticks["a"] != 0 {
+        (("a", a), ("b", b))
+    } else {
+        (("b", b), ("a", a))
+    };
+    assert!(ticks[holder].as_i64().unwrap_or(0) >= 4, "ticks: {ticks}");
+    assert_eq!(ticks[standby], 0, "only the lease holder runs: {ticks}");
+
+    // The holder gives the lease up as it stops; the other takes over.
+    holder_srv.stop().await;
+    let stopped_at = standby_srv.json("/ticks").await[holder].clone();
+    for _ in 0..200 {
+        ticks = standby_srv.json("/ticks").await;
+        if ticks[standby].as_i64().unwrap_or(0) >= 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(
+        ticks[standby].as_i64().unwrap_or(0) >= 2,
+        "took over: {ticks}"
+    );
+    assert_eq!(
+        ticks[holder], stopped_at,
+        "the stopped instance ran nothing"
+    );
+    standby_srv.stop().await;
+}
+
 // ---------------------------------------------------------------------------
 
 const FETCH_SCRIPT: &str = r#"
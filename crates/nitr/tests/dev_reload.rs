@@ -111,3 +111,41 @@ async fn a_save_reloads_once_and_the_rebuilds_own_writes_do_not_loop() {
     let resp = server.client().get(&version).send().await.expect("get v2");
     assert_eq!(body(resp).await, "v2");
 }
+
+/// A reload re-reads the `[access] file` alongside the rebuilt pool, so
+/// blocking a client needs no restart.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_reload_rereads_the_access_file() {
+    let builder = TestServer::builder("dev-reload-access");
+    let rules = builder
+        .dir()
+        .write("access.rules", "# nobody blocked yet\n");
+    let server = builder
+        .handler(HANDLER_V1)
+        .config(move |cfg| {
+            cfg.dev_mode = true;
+            cfg.access.file = Some(rules.clone());
+        })
+        .spawn()
+        .await;
+
+    let version = server.url("/version");
+    let resp = server.client().get(&version).send().await.expect("get v1");
+    assert_eq!(resp.status(), 200);
+
+    // Block this client, then save the handler until the reload lands.
+    server.dir().write("access.rules", "deny 127.0.0.0/8\n");
+    let deadline = Instant::now() + Duration::from_secs(15);
+    loop {
+        server.dir().write("app.lua", HANDLER_V2);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let resp = server.client().get(&version).send().await.expect("poll");
+        if resp.status() == 403 {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the reload never picked up the access file"
+        );
+    }
+}
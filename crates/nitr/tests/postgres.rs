@@ -0,0 +1,209 @@
+//! End-to-end tests for the Postgres backend of `nitr.db`: the SQLite
+//! surface (placeholders, row values, transactions, savepoints,
+//! `query_async`), the Postgres `_nitr_migrations` ledger and the stored
+//! session table.
+//!
+//! They need a server: set `NITR_TEST_POSTGRES_URL` (for instance
+//! `postgres://postgres@127.0.0.1/nitr_test`) to run them. The tests work
+//! in a `nitr_test` schema they drop and recreate.
+
+// Each test binary uses a subset of the shared harness.
+#![allow(dead_code)]
+
+mod harness;
+
+use harness::TestServer;
+
+const SCHEMA: &str = "nitr_test";
+
+/// The URL under test, scoped to a freshly emptied [`SCHEMA`]; `None`
+/// skips the test.
+async fn scratch_url() -> Option<String> {
+    let Ok(url) = std::env::var("NITR_TEST_POSTGRES_URL") else {
+        eprintln!("skipped: NITR_TEST_POSTGRES_URL is not set");
+        return None;
+    };
+    let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+        .await
+        .expect("connect to NITR_TEST_POSTGRES_URL");
+    tokio::spawn(connection);
+    client
+        .batch_execute(&format!(
+            "DROP SCHEMA IF EXISTS {SCHEMA} CASCADE; CREATE SCHEMA {SCHEMA}"
+        ))
+        .await
+        .expect("reset the scratch schema");
+    let separator = if url.contains('?') { '&' } else { '?' };
+    Some(format!("{url}{separator}options=-csearch_path%3D{SCHEMA}"))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn postgres_runs_the_sqlite_surface_and_ledger() {
+    let Some(url) = scratch_url().await else {
+        return;
+    };
+
+    let builder = TestServer::builder("postgres").builtins(
+        nitr::Builtins::JSON
+            | nitr::Builtins::HTTP
+            | nitr::Builtins::DATABASE
+            | nitr::Builtins::TIME,
+    );
+    let migrations = builder.dir().join("migrations");
+    std::fs::create_dir_all(&migrations).expect("migrations dir");
+    std::fs::write(
+        migrations.join("001_items.sql"),
+        "CREATE TABLE items (
+             id      BIGSERIAL PRIMARY KEY,
+             name    TEXT NOT NULL UNIQUE,
+             qty     INTEGER NOT NULL,
+             price   NUMERIC(10, 2),
+             ratio   DOUBLE PRECISION,
+             active  BOOLEAN,
+             payload JSONB,
+             bytes   BYTEA,
+             created TIMESTAMPTZ NOT NULL DEFAULT '2024-05-06 07:08:09.5+00'
+         );",
+    )
+    .expect("write migration");
+    let mut db = nitr::DatabaseConfig::postgres(&url);
+    db.migrations_dir = Some(migrations.clone());
+    db.pool_size = 4;
+    let mut builder = builder.config(|cfg| cfg.database = Some(db)).handler(
+        r#"
+local app = nitr.app()
+
+app:get("/values", function(req)
+    nitr.db:execute("DELETE FROM items")
+    local inserted = nitr.db:execute(
+        "INSERT INTO items (name, qty, price, ratio, active, payload, bytes) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        { "bolt", 3, 2.5, 0.25, true, '{"k": 1}', "\0\1" })
+    local row = nitr.db:query_one("SELECT * FROM items WHERE name = ?1 AND qty = ?2", { "bolt", 3 })
+    return nitr.json({
+        inserted = inserted,
+        qty = math.type(row.qty),
+        price = row.price,
+        ratio = row.ratio,
+        active = row.active,
+        payload = row.payload,
+        bytes = #row.bytes,
+        created = row.created,
+        question = nitr.db:query_row("SELECT '?' AS q, ? AS n", { 7 }),
+    })
+end)
+
+app:get("/tx", function(req)
+    nitr.db:execute("DELETE FROM items")
+    nitr.db:transaction(function(tx)
+        tx:execute("INSERT INTO items (name, qty) VALUES (?, ?)", { "committed", 1 })
+        tx:transaction(function(sp)
+            sp:execute("INSERT INTO items (name, qty) VALUES (?, ?)", { "inner", 1 })
+        end)
+        pcall(function()
+            tx:transaction(function(sp)
+                sp:execute("INSERT INTO items (name, qty) VALUES (?, ?)", { "savepoint", 1 })
+                error("undo the savepoint")
+            end)
+        end)
+        local outer_ok, outer_err = pcall(function() return nitr.db:query("SELECT 1") end)
+        assert(not outer_ok and tostring(outer_err):find("transaction is open"))
+    end)
+    local ok = pcall(function()
+        nitr.db:transaction(function(tx)
+            tx:execute("INSERT INTO items (name, qty) VALUES (?, ?)", { "rolled back", 1 })
+            error("boom")
+        end)
+    end)
+    local names = {}
+    for _, row in ipairs(nitr.db:query("SELECT name FROM items ORDER BY name")) do
+        names[#names + 1] = row.name
+    end
+    local count = nitr.db:query_async("SELECT count(*) AS n FROM items", nil, "query_one"):send()
+    return nitr.json({ rolled_back = not ok, names = names, count = count.n })
+end)
+
+app:get("/errors", function(req)
+    local _, missing = pcall(function() return nitr.db:query("SELECT * FROM nope") end)
+    local _, none = pcall(function() return nitr.db:query_row("SELECT 1 WHERE false") end)
+    local _, many = pcall(function() return nitr.db:query_one("SELECT generate_series(1, 2)") end)
+    return nitr.json({ missing = tostring(missing), none = tostring(none), many = tostring(many) })
+end)
+
+app:get("/session", function(req)
+    local opts = { secret = "session-secret-0123456789", store = "db" }
+    local session = nitr.session(req, opts)
+    session:regenerate(7)
+    session.n = 1
+    local resp = nitr.json({})
+    session:save(resp)
+    local stored = nitr.db:query_one("SELECT user_id, expires_at FROM _nitr_sessions")
+    resp.body = nitr.json({
+        user = stored.user_id,
+        live = stored.expires_at > nitr.time.now(),
+        revoked = nitr.session.revoke_all(7, opts),
+    }).body
+    return resp
+end)
+
+return app
+"#,
+    );
+
+    // The pending migration stops the boot, as on SQLite.
+    let err = builder.try_build().await.expect_err("pending migration");
+    assert!(err.to_string().contains("001_items.sql"), "{err}");
+
+    let pool = nitr::stdlib::PgPool::connect(&url, 1).expect("pool");
+    let applied = pool.migrate(&migrations).await.expect("migrate");
+    assert_eq!(applied, vec!["001_items.sql"]);
+    assert!(pool.migrate(&migrations).await.expect("no-op").is_empty());
+    assert!(
+        pool.pending_migrations(&migrations)
+            .await
+            .expect("pending")
+            .is_empty()
+    );
+
+    let mut server = builder.spawn().await;
+
+    let values = server.json("/values").await;
+    assert_eq!(values["inserted"], 1);
+    assert_eq!(values["qty"], "integer");
+    assert_eq!(values["price"], 2.5);
+    assert_eq!(values["ratio"], 0.25);
+    assert_eq!(values["active"], true);
+    assert_eq!(values["payload"], r#"{"k": 1}"#);
+    assert_eq!(values["bytes"], 2);
+    assert_eq!(values["created"], "2024-05-06 07:08:09.500");
+    assert_eq!(values["question"]["q"], "?");
+    assert_eq!(values["question"]["n"], "7");
+
+    let tx = server.json("/tx").await;
+    assert_eq!(tx["rolled_back"], true);
+    assert_eq!(tx["names"], serde_json::json!(["committed", "inner"]));
+    assert_eq!(tx["count"], 2);
+
+    let errors = server.json("/errors").await;
+    let missing = errors["missing"].as_str().expect("missing");
+    assert!(missing.contains("\"nope\" does not exist"), "{missing}");
+    assert!(
+        errors["none"]
+            .as_str()
+            .is_some_and(|e| e.contains("Query returned no rows")),
+        "{errors}"
+    );
+    assert!(
+        errors["many"]
+            .as_str()
+            .is_some_and(|e| e.contains("more than one row")),
+        "{errors}"
+    );
+
+    // Stored sessions keep their table through the same handle.
+    let session = server.json("/session").await;
+    assert_eq!(session["user"], "7");
+    assert_eq!(session["live"], true);
+    assert_eq!(session["revoked"], 1);
+
+    server.stop().await;
+}
@@ -1,6 +1,6 @@
 //! End-to-end tests for phase-5 observability + protection: request ids
-//! (generated and trusted), the `nitr.log` builtin, rate limiting, and the
-//! URI/body size limits.
+//! (generated and trusted), the `nitr.log` builtin, rate limiting, the
+//! URI/body size limits, and client-IP access lists.
 
 // Each test binary uses a subset of the shared harness.
 #![allow(dead_code)]
@@ -147,3 +147,137 @@ async fn rate_limiting_answers_429_with_retry_after() {
 
     server.stop().await;
 }
+
+/// An app with route and static-mount access lists; `private` is the
+/// mount's directory.
+fn access_script(private: &std::path::Path) -> String {
+    // A long-bracket string needs no escaping, so Windows backslashes in
+    // the temp path survive intact.
+    format!(
+        r#"
+local app = nitr.app()
+
+-- Counts the requests that reach Lua; a denied one never does.
+hits = 0
+app:use(function(next)
+    return function(req)
+        hits = hits + 1
+        return next(req)
+    end
+end)
+
+app:get("/", function(req)
+    return nitr.text("home")
+end)
+
+app:get("/hits", function(req)
+    return nitr.text(tostring(hits))
+end)
+
+app:get("/admin/*", function(req)
+    return nitr.text("admin")
+end, {{ access = {{ "allow 10.0.0.0/8", "deny all" }} }})
+
+app:static("/private", [[{}]], {{ access = {{ "deny all" }} }})
+
+return app
+"#,
+        private.display()
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn access_lists_answer_403_before_lua() {
+    let b = TestServer::builder("protect-access");
+    b.dir().write("private/secret.txt", "secret");
+    let script = access_script(&b.dir().join("private"));
+    let mut server = b
+        .handler(script)
+        .builtins(nitr::Builtins::HTTP)
+        .config(|cfg| {
+            cfg.workers = 1;
+            cfg.access.rules = vec!["deny 192.0.2.66".into()];
+            cfg.rate_limit.trust_forwarded_for = true;
+        })
+        .spawn()
+        .await;
+    let get = |path: &str, client: &str| {
+        server
+            .client()
+            .get(server.url(path))
+            .header("x-forwarded-for", client)
+            .send()
+    };
+
+    let resp = get("/", "192.0.2.1").await.expect("GET /");
+    assert_eq!(resp.status(), 200);
+
+    // The server-wide list covers every path.
+    let resp = get("/", "192.0.2.66").await.expect("denied client");
+    assert_eq!(resp.status(), 403);
+    assert!(resp.headers().contains_key("x-request-id"));
+    assert_eq!(resp.text().await.expect("body"), "Forbidden");
+
+    // A route's list, keyed by the trusted client address; HEAD follows
+    // the GET route.
+    let resp = get("/admin/users", "192.0.2.1").await.expect("outsider");
+    assert_eq!(resp.status(), 403);
+    let resp = server
+        .client()
+        .head(server.url("/admin/users"))
+        .header("x-forwarded-for", "192.0.2.1")
+        .send()
+        .await
+        .expect("HEAD outsider");
+    assert_eq!(resp.status(), 403);
+    let resp = get("/admin/users", "10.4.0.9").await.expect("office");
+    assert_eq!(resp.text().await.expect("body"), "admin");
+
+    // A leftmost entry is whatever the client sent: claiming an office
+    // address in front of the real one buys nothing.
+    let resp = get("/admin/users", "10.4.0.9, 192.0.2.1")
+        .await
+        .expect("spoofed");
+    assert_eq!(resp.status(), 403);
+    let resp = get("/", "192.0.2.1, 192.0.2.66").await.expect("spoofed");
+    assert_eq!(resp.status(), 403);
+
+    // A static mount's list.
+    let resp = get("/private/secret.txt", "10.4.0.9").await.expect("mount");
+    assert_eq!(resp.status(), 403);
+
+    // Only the two allowed requests (and this one) reached Lua.
+    let resp = get("/hits", "192.0.2.1").await.expect("hits");
+    assert_eq!(resp.text().await.expect("body"), "3");
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_malformed_access_rule_fails_at_startup() {
+    let err = TestServer::builder("protect-access-bad")
+        .handler(
+            r#"
+local app = nitr.app()
+app:get("/", function(req) return nitr.text("x") end, { access = { "allow 10.0.0.1/8" } })
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::HTTP)
+        .config(|cfg| cfg.workers = 1)
+        .try_build()
+        .await
+        .expect_err("host bits past the prefix");
+    let err = err.to_string();
+    assert!(err.contains("app:get(\"/\", ...)"), "{err}");
+    assert!(err.contains("did you mean 10.0.0.0/8"), "{err}");
+
+    let err = TestServer::builder("protect-access-bad-server")
+        .handler(APP_SCRIPT)
+        .builtins(nitr::Builtins::HTTP)
+        .config(|cfg| cfg.access.rules = vec!["block 10.0.0.0/8".into()])
+        .try_build()
+        .await
+        .expect_err("unknown action");
+    assert!(err.to_string().contains("[access] rules"), "{err}");
+}
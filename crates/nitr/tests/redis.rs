@@ -0,0 +1,142 @@
+//! End-to-end tests for the Redis backend of `nitr.cache`: two servers
+//! standing in for two processes behind a balancer share entries, counters,
+//! locks and invalidations through one Redis (or Valkey) server.
+//!
+//! They need a server: set `NITR_TEST_REDIS_URL` (for instance
+//! `redis://127.0.0.1:6379/15`) to run them. Each run works under its own
+//! key prefix and clears it afterwards.
+
+// Each test binary uses a subset of the shared harness.
+#![allow(dead_code)]
+
+mod harness;
+
+use harness::TestServer;
+
+const SCRIPT: &str = r#"
+local app = nitr.app()
+
+app:get("/set", function(req)
+    nitr.cache:set("user:42", { name = req.query.name }, { ttl = 60, tags = { "user:42" } })
+    return nitr.json({ ok = true })
+end)
+
+app:get("/get", function(req)
+    return nitr.json({ value = nitr.cache:get("user:42") })
+end)
+
+app:get("/invalidate", function(req)
+    return nitr.json({ dropped = nitr.cache:invalidate_tag("user:42") })
+end)
+
+app:get("/hit", function(req)
+    return nitr.json({ n = nitr.cache:incr("hits", 1, { ttl = 60 }) })
+end)
+
+app:get("/lock", function(req)
+    return nitr.json({ won = nitr.cache:add("lock", req.id, { ttl = 60 }) })
+end)
+
+app:get("/swap", function(req)
+    nitr.cache:set("v", { a = 1, b = 2 })
+    return nitr.json({
+        swapped = nitr.cache:cas("v", { b = 2, a = 1 }, 3),
+        stale = nitr.cache:cas("v", { a = 1, b = 2 }, 4),
+        value = nitr.cache:get("v"),
+    })
+end)
+
+app:get("/remember", function(req)
+    local value = nitr.cache:remember("rates", { ttl = 60 }, function()
+        return { eur = 0.92 }
+    end)
+    return nitr.json({ value = value })
+end)
+
+app:get("/stats", function(req)
+    return nitr.json(nitr.cache:stats())
+end)
+
+app:get("/clear", function(req)
+    nitr.cache:clear()
+    return nitr.json({ ok = true })
+end)
+
+return app
+"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn two_processes_share_one_redis_cache() {
+    let Ok(url) = std::env::var("NITR_TEST_REDIS_URL") else {
+        eprintln!("skipped: NITR_TEST_REDIS_URL is not set");
+        return;
+    };
+    let prefix = format!("nitr-test-{}:", std::process::id());
+    let spawn = || {
+        let (url, prefix) = (url.clone(), prefix.clone());
+        TestServer::builder("redis")
+            .handler(SCRIPT)
+            .std_features(&["json", "http", "cache"])
+            .config(move |cfg| {
+                cfg.workers = 4;
+                cfg.cache.backend = nitr::CacheBackend::Redis;
+                let mut redis = nitr::RedisConfig::new(url);
+                redis.prefix = prefix;
+                cfg.cache.redis = Some(redis);
+            })
+            .spawn()
+    };
+    let mut a = spawn().await;
+    let mut b = spawn().await;
+
+    // Written by one process, read and invalidated by the other.
+    a.json("/set?name=ada").await;
+    assert_eq!(b.json("/get").await["value"]["name"], "ada");
+    assert_eq!(b.json("/invalidate").await["dropped"], 1);
+    assert_eq!(a.json("/get").await["value"], serde_json::Value::Null);
+
+    // Counters and locks are atomic across both.
+    let mut requests = Vec::new();
+    for path in ["/hit", "/lock"] {
+        for i in 0..20 {
+            let srv = if i % 2 == 0 { &a } else { &b };
+            let client = srv.client().clone();
+            let url = srv.url(path);
+            requests.push(tokio::spawn(async move {
+                client
+                    .get(url)
+                    .send()
+                    .await?
+                    .json::<serde_json::Value>()
+                    .await
+            }));
+        }
+    }
+    let (mut counts, mut winners) = (Vec::new(), 0);
+    for handle in requests {
+        let body = handle.await.expect("task").expect("request");
+        match body["n"].as_u64() {
+            Some(n) => counts.push(n),
+            None => winners += usize::from(body["won"] == true),
+        }
+    }
+    counts.sort_unstable();
+    assert_eq!(counts, (1..=20).collect::<Vec<_>>(), "no increment lost");
+    assert_eq!(winners, 1, "exactly one process takes the lock");
+
+    let body = a.json("/swap").await;
+    assert_eq!(body["swapped"], true);
+    assert_eq!(body["stale"], false);
+    assert_eq!(body["value"], 3);
+
+    assert_eq!(a.json("/remember").await["value"]["eur"], 0.92);
+    assert_eq!(b.json("/remember").await["value"]["eur"], 0.92);
+    let stats = b.json("/stats").await;
+    assert_eq!(stats["backend"], "redis");
+    assert!(stats["hits"].as_u64().expect("hits") > 0, "{stats}");
+
+    a.json("/clear").await;
+    assert_eq!(b.json("/get").await["value"], serde_json::Value::Null);
+    a.stop().await;
+    b.stop().await;
+}
@@ -1,6 +1,7 @@
 //! End-to-end tests for phase 11: the parts of HTTP that applications
-//! assume exist — ranges, compression, CORS, form and multipart bodies,
-//! conditional dynamic responses — plus the correctness audit.
+//! assume exist — ranges, compression, CORS, security headers, form and
+//! multipart bodies, conditional dynamic responses — plus the correctness
+//! audit.
 
 mod harness;
 
@@ -73,6 +74,11 @@ app:get("/article", function(req)
     return res
 end)
 
+-- The nonce the `[headers]` CSP names, when it has one.
+app:get("/nonce", function(req)
+    return nitr.text(req.csp_nonce or "none")
+end)
+
 -- Deliberately invalid: a 204 may not carry bytes.
 app:get("/bad-204", function(req)
     return { status = 204, body = "should not be here" }
@@ -396,6 +402,151 @@ async fn a_contradictory_cors_policy_fails_at_startup() {
     );
 }
 
+/// `[headers]` reaches every response but the probes — Lua, static files,
+/// and Nitr's own 404 and 405 — with a fresh CSP nonce per request and
+/// the `[[headers.mount]]` override under its prefix.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn security_headers_cover_every_response_but_the_probes() {
+    let b = builder();
+    let dir = static_dir(&b);
+    let mut srv = b
+        .config(move |cfg| {
+            cfg.static_files.dir = Some(dir);
+            cfg.static_files.mount = Some("/assets".into());
+            cfg.headers.enabled = true;
+            cfg.headers.csp = "script-src 'self' 'nonce-{nonce}'".into();
+            cfg.headers.mount = vec![nitr::HeadersMount {
+                path: "/assets".into(),
+                csp: Some("default-src 'none'".into()),
+                frame_options: Some(String::new()),
+                ..Default::default()
+            }];
+        })
+        .spawn()
+        .await;
+
+    let nonce = |resp: &reqwest::Response| {
+        let csp = resp.headers()["content-security-policy"]
+            .to_str()
+            .expect("csp")
+            .to_string();
+        csp.strip_prefix("script-src 'self' 'nonce-")
+            .and_then(|rest| rest.strip_suffix('\''))
+            .unwrap_or_else(|| panic!("unexpected CSP: {csp}"))
+            .to_string()
+    };
+
+    // The handler reads the same nonce the header names, fresh each time.
+    let resp = srv.get("/nonce").await;
+    assert_eq!(resp.headers()["x-content-type-options"], "nosniff");
+    assert_eq!(resp.headers()["x-frame-options"], "DENY");
+    let named = nonce(&resp);
+    assert_eq!(resp.text().await.expect("body"), named);
+    let again = srv.get("/nonce").await;
+    assert_ne!(nonce(&again), named);
+
+    // Nitr's own answers carry the policy too.
+    let missing = srv.get("/no-such-route").await;
+    assert_eq!(missing.status(), 404);
+    assert_eq!(missing.headers()["x-frame-options"], "DENY");
+    nonce(&missing);
+    let resp = srv
+        .client()
+        .delete(srv.url("/hello"))
+        .send()
+        .await
+        .expect("delete");
+    assert_eq!(resp.status(), 405);
+    assert_eq!(
+        resp.headers()["referrer-policy"],
+        "strict-origin-when-cross-origin"
+    );
+
+    // Static files get the mount's policy.
+    let asset = srv.get("/assets/data.txt").await;
+    assert_eq!(asset.status(), 200);
+    assert_eq!(
+        asset.headers()["content-security-policy"],
+        "default-src 'none'"
+    );
+    assert!(!asset.headers().contains_key("x-frame-options"));
+    assert_eq!(
+        asset.headers()["strict-transport-security"],
+        "max-age=31536000"
+    );
+
+    // The probes are infrastructure, not pages.
+    let probe = srv.get("/healthz").await;
+    assert_eq!(probe.status(), 200);
+    assert!(!probe.headers().contains_key("x-content-type-options"));
+
+    srv.stop().await;
+}
+
+/// Templates read the request's nonce as `csp_nonce`, so an inline script
+/// can carry it without the handler passing it along, and data cannot
+/// swap in one the header does not allow.
+#[cfg(feature = "template")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn templates_see_the_csp_nonce() {
+    let b = TestServer::builder("standards-nonce")
+        .handler(
+            r#"
+local app = nitr.app()
+app:get("/", function(req)
+    return nitr.html(nitr.template:render("page.html", { title = "hi" }))
+end)
+app:get("/forged", function(req)
+    return nitr.html(nitr.template:render("page.html", { title = "hi", csp_nonce = "forged" }))
+end)
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::HTTP | nitr::Builtins::TEMPLATE);
+    b.dir().write(
+        "views/page.html",
+        r#"<script nonce="{{ csp_nonce }}">{{ title }}</script>"#,
+    );
+    let views = b.dir().join("views");
+    let mut srv = b
+        .config(move |cfg| {
+            cfg.workers = 1;
+            cfg.templating.dir = Some(views);
+            cfg.headers.enabled = true;
+            cfg.headers.csp = "script-src 'nonce-{nonce}'".into();
+        })
+        .spawn()
+        .await;
+
+    let resp = srv.get("/").await;
+    let csp = resp.headers()["content-security-policy"]
+        .to_str()
+        .expect("csp")
+        .to_string();
+    let body = resp.text().await.expect("body");
+    let nonce = body
+        .strip_prefix("<script nonce=\"")
+        .and_then(|rest| rest.strip_suffix("\">hi</script>"))
+        .unwrap_or_else(|| panic!("unexpected page: {body}"));
+    assert_eq!(csp, format!("script-src 'nonce-{nonce}'"));
+
+    // The request's nonce wins over a `csp_nonce` in the data.
+    let resp = srv.get("/forged").await;
+    let csp = resp.headers()["content-security-policy"]
+        .to_str()
+        .expect("csp")
+        .to_string();
+    let body = resp.text().await.expect("body");
+    assert!(!body.contains("forged"), "{body}");
+    let nonce = body
+        .strip_prefix("<script nonce=\"")
+        .and_then(|rest| rest.strip_suffix("\">hi</script>"))
+        .unwrap_or_else(|| panic!("unexpected page: {body}"));
+    assert_eq!(csp, format!("script-src 'nonce-{nonce}'"));
+
+    srv.stop().await;
+}
+
 /// Form and multipart bodies, including an upload that never enters the
 /// Lua heap.
 #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
@@ -1,7 +1,7 @@
 //! End-to-end tests for the phase-14 standard library completion:
 //! `nitr.time`, `nitr.validate`, `nitr.base64`, `nitr.path`, `nitr.url`,
-//! CSRF middleware, signed-cookie sessions, and the `nitr.crypto`
-//! AEAD/JWT additions.
+//! CSRF middleware, signed-cookie and stored sessions, `[keys]` rings,
+//! webhook signatures, and the `nitr.crypto` AEAD/JWT additions.
 
 // Each test binary uses a subset of the shared harness.
 #![allow(dead_code)]
@@ -340,6 +340,369 @@ return app
     server.stop().await;
 }
 
+/// `[keys]`: sessions, CSRF cookies and sealed boxes made under the
+/// newest key, still accepted after a rotation, and refused once their
+/// key is retired.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn key_rings_rotate_without_logging_anyone_out() {
+    let keys = harness::TestDir::new("std-keys");
+    nitr::stdlib::keys::rotate(keys.path(), None).expect("first key");
+    let handler = r#"
+local app = nitr.app()
+local OPTS = { secret = nitr.keys }
+app:use(nitr.csrf({ secret = nitr.keys }))
+
+app:post("/login", function(req)
+    local session = nitr.session(req, OPTS)
+    session.user_id = 42
+    local resp = nitr.json({ ok = true })
+    session:save(resp)
+    return resp
+end)
+
+app:get("/me", function(req)
+    local session = nitr.session(req, OPTS)
+    return nitr.json({
+        user_id = session.user_id,
+        current = nitr.keys.current,
+        ids = nitr.keys.ids,
+        sealed = nitr.crypto.seal(nitr.keys, "the plan"),
+        opened = req.query.box and nitr.crypto.open(nitr.keys, req.query.box),
+        csrf = nitr.csrf.token(req),
+    })
+end)
+
+return app
+"#;
+    let spawn = |label: &str| {
+        TestServer::builder(label)
+            .handler(handler)
+            .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::CRYPTO)
+            .config(|cfg| {
+                cfg.workers = 1;
+                cfg.keys.dir = Some(keys.path().to_path_buf());
+            })
+            .spawn()
+    };
+
+    let mut server = spawn("std-keys-1").await;
+    let resp = server
+        .client()
+        .get(server.url("/me"))
+        .send()
+        .await
+        .expect("csrf cookie");
+    let csrf = cookie_pair(&resp);
+    assert!(csrf.starts_with("_csrf=1."), "got: {csrf}");
+    let body: serde_json::Value = resp.json().await.expect("json");
+    let csrf_token = body["csrf"].as_str().expect("token").to_string();
+    let old_box = body["sealed"].as_str().expect("sealed").to_string();
+    assert!(old_box.starts_with("1."), "got: {old_box}");
+
+    let resp = server
+        .client()
+        .post(server.url("/login"))
+        .header("cookie", &csrf)
+        .header("x-csrf-token", &csrf_token)
+        .send()
+        .await
+        .expect("login");
+    assert_eq!(resp.status(), 200);
+    let session = cookie_pair(&resp);
+    assert!(session.starts_with("session=1."), "got: {session}");
+    server.stop().await;
+
+    // Key 2 signs after the rotation; key-1 tokens keep working.
+    nitr::stdlib::keys::rotate(keys.path(), None).expect("second key");
+    let mut server = spawn("std-keys-2").await;
+    let me = |server: &TestServer| {
+        server
+            .client()
+            .get(server.url(&format!("/me?box={old_box}")))
+            .header("cookie", format!("{session}; {csrf}"))
+            .send()
+    };
+    let body: serde_json::Value = me(&server).await.expect("me").json().await.expect("json");
+    assert_eq!(body["user_id"], 42);
+    assert_eq!(body["current"], 2);
+    assert_eq!(body["ids"], serde_json::json!([2, 1]));
+    assert_eq!(body["opened"], "the plan");
+    assert!(
+        body["sealed"].as_str().is_some_and(|s| s.starts_with("2.")),
+        "{body}"
+    );
+    let resp = server
+        .client()
+        .post(server.url("/login"))
+        .header("cookie", &csrf)
+        .header("x-csrf-token", &csrf_token)
+        .send()
+        .await
+        .expect("login");
+    assert_eq!(resp.status(), 200, "a key-1 CSRF cookie still verifies");
+    assert!(cookie_pair(&resp).starts_with("session=2."));
+    server.stop().await;
+
+    // Retiring key 1 ends what it signed.
+    assert_eq!(
+        nitr::stdlib::keys::retire(keys.path(), 1).expect("retire"),
+        [1]
+    );
+    let mut server = spawn("std-keys-3").await;
+    let body: serde_json::Value = me(&server).await.expect("me").json().await.expect("json");
+    assert!(body["user_id"].is_null(), "{body}");
+    assert!(body["opened"].is_null(), "{body}");
+    server.stop().await;
+}
+
+/// Stored sessions: the cookie carries a token, the data lives in the
+/// database, login rotates the id, and revocation ends sessions whose
+/// cookies are still valid.
+#[cfg(feature = "db")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn stored_sessions_rotate_and_revoke() {
+    let mut server = TestServer::builder("std14-stored-session")
+        .handler(
+            r#"
+local app = nitr.app()
+local OPTS = { secret = "session-secret-0123456789", store = "db", ttl = 3600 }
+
+app:post("/visit", function(req)
+    local session = nitr.session(req, OPTS)
+    session.visits = (session.visits or 0) + 1
+    local resp = nitr.json({ ok = true })
+    session:save(resp)
+    return resp
+end)
+
+app:post("/login", function(req)
+    local session = nitr.session(req, OPTS)
+    local before = session:id()
+    session:regenerate(req.query.user)
+    session.user = req.query.user
+    local resp = nitr.json({ before = before, id = session:id() })
+    session:save(resp)
+    return resp
+end)
+
+app:get("/me", function(req)
+    local session = nitr.session(req, OPTS)
+    return nitr.json({ id = session:id(), user = session.user, visits = session.visits })
+end)
+
+app:post("/revoke", function(req)
+    return nitr.json({ revoked = nitr.session.revoke(req.query.id) })
+end)
+
+app:post("/revoke_all", function(req)
+    return nitr.json({ revoked = nitr.session.revoke_all(req.query.user) })
+end)
+
+app:get("/cookie_revoke", function(req)
+    local ok, err = pcall(nitr.session.revoke, "x", { store = "cookie" })
+    return nitr.json({ ok = ok, err = tostring(err) })
+end)
+
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::DATABASE)
+        .database("app.db")
+        .spawn()
+        .await;
+
+    let post = |path: &str, cookie: Option<&str>| {
+        let mut req = server.client().post(server.url(path));
+        if let Some(cookie) = cookie {
+            req = req.header("cookie", cookie.to_string());
+        }
+        req.send()
+    };
+    let resp = post("/visit", None).await.expect("visit");
+    let anonymous = cookie_pair(&resp);
+    post("/visit", Some(&anonymous)).await.expect("visit");
+
+    // The cookie holds a token, not the data.
+    let token = anonymous.trim_start_matches("session=");
+    assert!(!token.contains("visits"), "got: {token}");
+
+    // Login keeps the data and rotates the id; the pre-login cookie is dead.
+    let resp = post("/login?user=ada", Some(&anonymous))
+        .await
+        .expect("login");
+    let ada = cookie_pair(&resp);
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert!(
+        body["before"].is_string() && body["id"].is_string(),
+        "{body}"
+    );
+    assert_ne!(body["before"], body["id"]);
+    let me = |cookie: &str| {
+        server
+            .client()
+            .get(server.url("/me"))
+            .header("cookie", cookie.to_string())
+            .send()
+    };
+    let body: serde_json::Value = me(&ada).await.expect("me").json().await.expect("json");
+    assert_eq!(body["user"], "ada");
+    assert_eq!(body["visits"], 2);
+    let ada_id = body["id"].as_str().expect("id").to_string();
+    let body: serde_json::Value = me(&anonymous)
+        .await
+        .expect("me")
+        .json()
+        .await
+        .expect("json");
+    assert!(body["visits"].is_null(), "fixated: {body}");
+
+    // A second device, then "log out everywhere".
+    let resp = post("/login?user=ada", None).await.expect("login");
+    let phone = cookie_pair(&resp);
+    let resp = post("/revoke_all?user=ada", None)
+        .await
+        .expect("revoke_all");
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(body["revoked"], 2);
+    for cookie in [&ada, &phone] {
+        let body: serde_json::Value = me(cookie).await.expect("me").json().await.expect("json");
+        assert!(body["user"].is_null(), "still signed in: {body}");
+    }
+
+    // One session by id.
+    let resp = post("/login?user=bob", None).await.expect("login");
+    let bob = cookie_pair(&resp);
+    let body: serde_json::Value = me(&bob).await.expect("me").json().await.expect("json");
+    let bob_id = body["id"].as_str().expect("id").to_string();
+    assert_ne!(bob_id, ada_id);
+    let resp = post(&format!("/revoke?id={bob_id}"), None)
+        .await
+        .expect("revoke");
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(body["revoked"], true);
+    let body: serde_json::Value = me(&bob).await.expect("me").json().await.expect("json");
+    assert!(body["user"].is_null(), "still signed in: {body}");
+
+    let body = server.json("/cookie_revoke").await;
+    assert_eq!(body["ok"], false);
+    assert!(
+        body["err"]
+            .as_str()
+            .is_some_and(|e| e.contains("cannot be revoked")),
+        "{body}"
+    );
+
+    server.stop().await;
+}
+
+/// A stored session saved inside `db:transaction` is written once the
+/// transaction ends — it is not part of the transaction's work, so it
+/// neither fails on the busy connection nor rolls back with it.
+#[cfg(feature = "db")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn stored_sessions_save_inside_a_transaction() {
+    let mut server = TestServer::builder("std-session-tx")
+        .handler(
+            r#"
+local app = nitr.app()
+local OPTS = { secret = "session-secret-0123456789", store = "db", ttl = 3600 }
+
+app:post("/order", function(req)
+    nitr.db:execute("CREATE TABLE IF NOT EXISTS orders (id INTEGER PRIMARY KEY)")
+    local saved = {}
+    local ok, err = pcall(function()
+        nitr.db:transaction(function(tx)
+            local session = nitr.session(req, OPTS)
+            tx:execute("INSERT INTO orders DEFAULT VALUES")
+            session.orders = (session.orders or 0) + 1
+            session:save(saved)
+            if req.query.fail then
+                error("payment declined")
+            end
+        end)
+    end)
+    local resp = nitr.json({ ok = ok, err = err and tostring(err) })
+    resp.cookies = saved.cookies
+    return resp
+end)
+
+app:post("/revoke", function(req)
+    local revoked
+    nitr.db:transaction(function(tx)
+        revoked = nitr.session.revoke(req.query.id)
+    end)
+    return nitr.json({ revoked = revoked })
+end)
+
+app:get("/me", function(req)
+    local session = nitr.session(req, OPTS)
+    return nitr.json({
+        id = session:id(),
+        orders = session.orders,
+        placed = nitr.db:query_one("SELECT count(*) AS n FROM orders").n,
+    })
+end)
+
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::DATABASE)
+        .database("app.db")
+        .spawn()
+        .await;
+
+    let post = |path: &str, cookie: Option<&str>| {
+        let mut req = server.client().post(server.url(path));
+        if let Some(cookie) = cookie {
+            req = req.header("cookie", cookie.to_string());
+        }
+        req.send()
+    };
+    let me = |cookie: &str| {
+        server
+            .client()
+            .get(server.url("/me"))
+            .header("cookie", cookie.to_string())
+            .send()
+    };
+
+    // The first save also creates the session table, inside the transaction.
+    let resp = post("/order", None).await.expect("order");
+    let cookie = cookie_pair(&resp);
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(body["ok"], true, "{body}");
+    post("/order", Some(&cookie)).await.expect("order");
+    let body: serde_json::Value = me(&cookie).await.expect("me").json().await.expect("json");
+    assert_eq!(body["orders"], 2, "{body}");
+    assert_eq!(body["placed"], 2, "{body}");
+
+    // The order rolls back; the session write still lands.
+    let resp = post("/order?fail=1", Some(&cookie)).await.expect("order");
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(body["ok"], false, "{body}");
+    assert!(
+        body["err"]
+            .as_str()
+            .is_some_and(|e| e.contains("payment declined")),
+        "{body}"
+    );
+    let body: serde_json::Value = me(&cookie).await.expect("me").json().await.expect("json");
+    assert_eq!(body["orders"], 3, "{body}");
+    assert_eq!(body["placed"], 2, "{body}");
+
+    // Revocation inside a transaction reports what it will delete.
+    let id = body["id"].as_str().expect("id").to_string();
+    let resp = post(&format!("/revoke?id={id}"), None)
+        .await
+        .expect("revoke");
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(body["revoked"], true, "{body}");
+    let body: serde_json::Value = me(&cookie).await.expect("me").json().await.expect("json");
+    assert!(body["orders"].is_null(), "still signed in: {body}");
+
+    server.stop().await;
+}
+
 /// `nitr.crypto.seal`/`open` and `nitr.crypto.jwt` through a live handler.
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn aead_and_jwt_work_from_lua() {
@@ -384,3 +747,953 @@ return app
 
     server.stop().await;
 }
+
+/// `nitr.crypto.otp`: enrolment, TOTP codes made single-use by a step
+/// kept in `nitr.kv`, and argon2-hashed recovery codes.
+#[cfg(feature = "db")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn otp_codes_verify_once_with_the_step_in_kv() {
+    let mut server = TestServer::builder("std14-otp")
+        .handler(
+            r#"
+local app = nitr.app()
+local otp = nitr.crypto.otp
+local SECRET = otp.secret()
+
+-- The replay guard: the last accepted step, swapped in atomically so two
+-- states cannot both accept one code.
+local function login(code)
+    local last = nitr.kv:get("otp:ada")
+    local step, why = otp.verify(SECRET, code, { last_step = last })
+    if step and not nitr.kv:cas("otp:ada", last, step) then
+        return nil, "code already used"
+    end
+    return step, why
+end
+
+app:get("/otp", function(req)
+    local code = otp.totp(SECRET)
+    local first = login(code)
+    local _, replay = login(code)
+    local _, wrong = login(code == "000000" and "111111" or "000000")
+    return nitr.json({
+        uri = otp.uri(SECRET, { issuer = "Acme", account = "ada" }),
+        secret = SECRET,
+        accepted = first ~= nil,
+        replay = replay,
+        wrong = wrong,
+    })
+end)
+
+app:get("/recovery", function(req)
+    local codes, hashes = otp.recovery_codes(2)
+    local many = {}
+    for i = 1, 33 do many[i] = hashes[1] end
+    return nitr.json({
+        code = codes[2],
+        stored = hashes[2]:sub(1, 10),
+        used = otp.verify_recovery(string.upper(codes[2]:gsub("-", " ")), hashes),
+        unknown = otp.verify_recovery("aaaaa-aaaaa", hashes) == nil,
+        too_many = tostring(select(2, pcall(otp.verify_recovery, codes[1], many))),
+    })
+end)
+
+return app
+"#,
+        )
+        .std_features(&["json", "crypto", "kv"])
+        .database("app.db")
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let body = server.json("/otp").await;
+    let secret = body["secret"].as_str().expect("secret");
+    assert_eq!(secret.len(), 32, "{body}");
+    assert_eq!(
+        body["uri"],
+        format!("otpauth://totp/Acme:ada?secret={secret}&issuer=Acme")
+    );
+    assert_eq!(body["accepted"], true, "{body}");
+    assert_eq!(body["replay"], "code already used");
+    assert_eq!(body["wrong"], "invalid code");
+
+    let body = server.json("/recovery").await;
+    let code = body["code"].as_str().expect("code");
+    assert!(
+        code.len() == 11 && code.as_bytes()[5] == b'-',
+        "got: {code}"
+    );
+    assert_eq!(body["stored"], "$argon2id$");
+    assert_eq!(body["used"], 2);
+    assert_eq!(body["unknown"], true);
+    let err = body["too_many"].as_str().expect("refused");
+    assert!(err.contains("at most 32 hashes, got 33"), "{err}");
+
+    server.stop().await;
+}
+
+/// `nitr.crypto` public-key signatures: a license signed with a key read
+/// from a PEM file verifies outside Nitr, and the private key cannot leak
+/// into a response. Key files come from the app directory only, whether
+/// read while the app loads or from a handler.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn signatures_from_a_pem_key_verify_outside_nitr() {
+    use ed25519_dalek::Verifier as _;
+    use ed25519_dalek::pkcs8::EncodePrivateKey as _;
+
+    let key = ed25519_dalek::SigningKey::from_bytes(&[11; 32]);
+    let pem = key
+        .to_pkcs8_pem(ed25519_dalek::pkcs8::spki::der::pem::LineEnding::LF)
+        .expect("pem");
+    let builder = TestServer::builder("std14-keypair");
+    let path = builder.dir().write("signing.pem", pem.as_bytes());
+    let mut server = builder
+        .handler(
+            r#"
+local app = nitr.app()
+local crypto = nitr.crypto
+local KEY = crypto.private_key({ file = "signing.pem" })
+local b64 = function(s) return nitr.base64.encode(s, { url = true }) end
+
+app:get("/license", function(req)
+    local license = nitr.json:encode({ customer = "acme", seats = 5 })
+    return nitr.json({
+        license = license,
+        signature = b64(crypto.sign(KEY, license)),
+        public = KEY:public():export("jwk"),
+    })
+end)
+
+app:get("/leak", function(req)
+    return nitr.json({ key = KEY })
+end)
+
+app:get("/reload", function(req)
+    local ok, err = pcall(crypto.private_key, { file = nitr.cfg.key })
+    return nitr.json({
+        same = crypto.private_key({ file = "./signing.pem" }):public() == KEY:public(),
+        absolute = not ok and tostring(err),
+    })
+end)
+
+app:get("/agree", function(req)
+    local a, a_pub = crypto.keypair("x25519")
+    local b, b_pub = crypto.keypair("x25519")
+    return nitr.json({
+        agreed = crypto.shared_secret(a, b_pub) == crypto.shared_secret(b, a_pub),
+    })
+end)
+
+return app
+"#,
+        )
+        .config_script(format!(
+            "return {{ key = {:?} }}",
+            path.display().to_string()
+        ))
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::BASE64 | nitr::Builtins::CRYPTO)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let body = server.json("/license").await;
+    let license = body["license"].as_str().expect("license");
+    let signature = {
+        use base64::Engine as _;
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(body["signature"].as_str().expect("signature"))
+            .expect("base64url")
+    };
+    let signature = ed25519_dalek::Signature::from_slice(&signature).expect("64 bytes");
+    key.verifying_key()
+        .verify(license.as_bytes(), &signature)
+        .expect("signature verifies");
+    assert_eq!(body["public"]["crv"], "Ed25519", "{body}");
+    assert_eq!(body["public"].get("d"), None);
+
+    assert_eq!(server.get("/leak").await.status(), 500);
+    assert_eq!(server.json("/agree").await["agreed"], true);
+
+    let body = server.json("/reload").await;
+    assert_eq!(body["same"], true, "{body}");
+    let err = body["absolute"].as_str().expect("refused");
+    assert!(err.contains("is outside the app directory"), "{err}");
+
+    server.stop().await;
+}
+
+/// `jwt.keyset({ file = path })` reads key files from the app directory
+/// only: a `..` path or an absolute one is refused, even when it names a
+/// key inside the directory.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn keyset_files_are_confined_to_the_app_directory() {
+    use ed25519_dalek::pkcs8::EncodePublicKey as _;
+
+    let pem = ed25519_dalek::SigningKey::from_bytes(&[12; 32])
+        .verifying_key()
+        .to_public_key_pem(ed25519_dalek::pkcs8::spki::der::pem::LineEnding::LF)
+        .expect("pem");
+    let builder = TestServer::builder("std14-keyset-file");
+    let path = builder.dir().write("keys/verify.pem", pem.as_bytes());
+    let mut server = builder
+        .handler(
+            r#"
+local app = nitr.app()
+local keyset = nitr.crypto.jwt.keyset
+local KEYS = keyset({ file = "keys/verify.pem" })
+
+app:get("/", function(req)
+    local refused = {}
+    for _, file in ipairs({ "../keys/verify.pem", "keys/../../x.pem", nitr.cfg.absolute }) do
+        local ok, err = pcall(keyset, { file = file })
+        refused[#refused + 1] = not ok and tostring(err) or "read"
+    end
+    return nitr.json({ loaded = KEYS ~= nil, refused = refused })
+end)
+
+return app
+"#,
+        )
+        .config_script(format!(
+            "return {{ absolute = {:?} }}",
+            path.display().to_string()
+        ))
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::CRYPTO)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let body = server.json("/").await;
+    assert_eq!(body["loaded"], true, "{body}");
+    let refused = body["refused"].as_array().expect("refused");
+    assert_eq!(refused.len(), 3, "{body}");
+    for err in refused {
+        let err = err.as_str().expect("message");
+        assert!(err.contains("is outside the app directory"), "{err}");
+    }
+
+    server.stop().await;
+}
+
+/// `nitr.auth.jwt`: bearer tokens verified in Rust, RFC 6750 challenges,
+/// claims at `req.ctx.claims`, and per-route scopes from route options.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn jwt_middleware_guards_routes_with_rfc_6750_challenges() {
+    let mut server = TestServer::builder("std14-jwt-auth")
+        .handler(
+            r#"
+local app = nitr.app()
+local auth = nitr.auth.jwt({
+    key = "auth-secret", algorithms = { "HS256" }, iss = "nitr", realm = "api",
+})
+
+app:get("/token", function(req)
+    return nitr.text(nitr.crypto.jwt.sign({
+        sub = "42", iss = req.query.iss or "nitr", scope = req.query.scope,
+        exp = req.query.exp and tonumber(req.query.exp) or 4000000000,
+    }, "auth-secret"))
+end)
+
+app:get("/me", auth, function(req)
+    return nitr.json({ sub = req.ctx.claims.sub })
+end)
+
+app:get("/orders", auth, function(req)
+    return nitr.json({ ok = true })
+end, { scopes = { "orders:read" } })
+
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::CRYPTO)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let token = |query: &str| {
+        let url = server.url(&format!("/token?{query}"));
+        let client = server.client().clone();
+        async move {
+            let resp = client.get(url).send().await.expect("token");
+            resp.text().await.expect("token body")
+        }
+    };
+    let call = |path: &str, auth: Option<String>| {
+        let mut req = server.client().get(server.url(path));
+        if let Some(auth) = auth {
+            req = req.header("authorization", auth);
+        }
+        async move { req.send().await.expect("request") }
+    };
+    let challenge = |resp: &reqwest::Response| {
+        resp.headers()
+            .get("www-authenticate")
+            .map(|v| v.to_str().expect("header").to_owned())
+    };
+
+    // No token: a bare challenge, no error code.
+    let resp = call("/me", None).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(challenge(&resp).as_deref(), Some("Bearer realm=\"api\""));
+
+    // A good token reaches the handler, claims in hand.
+    let good = token("").await;
+    let resp = call("/me", Some(format!("Bearer {good}"))).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.json::<serde_json::Value>().await.expect("json")["sub"],
+        "42"
+    );
+
+    // Expired, wrong issuer, garbage: invalid_token with the reason.
+    for (query, reason) in [
+        ("exp=1000", "token expired"),
+        ("iss=elsewhere", "issuer mismatch"),
+    ] {
+        let bad = token(query).await;
+        let resp = call("/me", Some(format!("Bearer {bad}"))).await;
+        assert_eq!(resp.status(), 401, "{query}");
+        assert_eq!(
+            challenge(&resp).as_deref(),
+            Some(
+                format!(
+                    "Bearer realm=\"api\", error=\"invalid_token\", error_description=\"{reason}\""
+                )
+                .as_str()
+            )
+        );
+    }
+    let resp = call("/me", Some("Bearer ".into())).await;
+    assert_eq!(resp.status(), 400);
+    assert!(
+        challenge(&resp)
+            .expect("challenge")
+            .contains("invalid_request")
+    );
+
+    // Scopes: the route demands orders:read.
+    let resp = call("/orders", Some(format!("Bearer {good}"))).await;
+    assert_eq!(resp.status(), 403);
+    assert_eq!(
+        challenge(&resp).as_deref(),
+        Some("Bearer realm=\"api\", error=\"insufficient_scope\", scope=\"orders:read\"")
+    );
+    let scoped = token("scope=profile%20orders:read").await;
+    let resp = call("/orders", Some(format!("Bearer {scoped}"))).await;
+    assert_eq!(resp.status(), 200);
+
+    server.stop().await;
+}
+
+/// `nitr.crypto.jwt.keyset({ url = ... })`: the JWKS document is fetched
+/// under the `[fetch]` policy once, then served from the cache.
+#[cfg(feature = "fetch")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn jwks_key_sets_are_fetched_once_and_cached() {
+    // The identity provider: one `oct` key, and a count of JWKS fetches.
+    let mut idp = TestServer::builder("std14-jwks-idp")
+        .handler(
+            r#"
+local app = nitr.app()
+local fetches = 0
+
+app:get("/jwks", function(req)
+    fetches = fetches + 1
+    return nitr.json({ keys = {
+        { kty = "oct", kid = "k1", alg = "HS256", k = nitr.base64.encode("jwks-secret", { url = true }) },
+    } })
+end)
+
+app:get("/fetches", function(req)
+    return nitr.json({ n = fetches })
+end)
+
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::BASE64)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let mut server = TestServer::builder("std14-jwks")
+        .handler(
+            r#"
+local app = nitr.app()
+local jwt = nitr.crypto.jwt
+local keys = jwt.keyset({ url = nitr.cfg.jwks })
+
+app:get("/verify", function(req)
+    local token = jwt.sign({ sub = "42", iss = "idp", exp = 4000000000 }, "jwks-secret")
+    local opts = { jwks = keys, algorithms = { "HS256" }, iss = "idp" }
+    local claims, why = jwt.verify(token, opts)
+    local forged = jwt.sign({ sub = "1" }, "guess")
+    local _, forged_why = jwt.verify(forged, opts)
+    local _, iss_why = jwt.verify(token, { jwks = keys, algorithms = { "HS256" }, iss = "other" })
+    return nitr.json({ sub = claims and claims.sub, why = why, forged = forged_why, iss = iss_why })
+end)
+
+return app
+"#,
+        )
+        .config_script(format!(
+            "return {{ jwks = \"http://{}/jwks\" }}",
+            idp.addr()
+        ))
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::CRYPTO)
+        // The stub identity provider is on loopback.
+        .config(|cfg| {
+            cfg.workers = 1;
+            cfg.fetch.allow_private_networks = true;
+        })
+        .spawn()
+        .await;
+
+    for _ in 0..3 {
+        let body = server.json("/verify").await;
+        assert_eq!(body["sub"], "42", "{body}");
+        assert_eq!(body["forged"], "invalid signature");
+        assert_eq!(body["iss"], "issuer mismatch");
+    }
+    assert_eq!(idp.json("/fetches").await["n"], 1);
+
+    server.stop().await;
+    idp.stop().await;
+}
+
+/// `nitr.oidc` against a mock OpenID provider: discovery, the redirect
+/// with PKCE, the code exchange with client authentication, and the ID
+/// token checks; replayed, unsolicited and declined callbacks fail.
+#[cfg(feature = "fetch")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn oidc_logins_run_the_code_flow_against_the_provider() {
+    use ed25519_dalek::Signer as _;
+    use sha2::Digest as _;
+
+    let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+    let public = key.verifying_key().to_bytes();
+    let mut idp = TestServer::builder("std14-oidc-idp");
+    let idp_addr = idp.reserve();
+    let mut app = TestServer::builder("std14-oidc");
+    let app_addr = app.reserve();
+
+    // The provider: remembers what each code was issued for and checks
+    // the PKCE verifier and client credentials at the token endpoint.
+    let mut idp = idp
+        .handler(
+            r#"
+local app = nitr.app()
+local signer = nitr.ext.signer
+local b64 = function(s) return nitr.base64.encode(s, { url = true }) end
+local issuer = nitr.cfg.issuer
+local codes, issued = {}, 0
+
+app:get("/.well-known/openid-configuration", function(req)
+    return nitr.json({
+        issuer = issuer,
+        authorization_endpoint = issuer .. "/authorize",
+        token_endpoint = issuer .. "/token",
+        jwks_uri = issuer .. "/jwks",
+        id_token_signing_alg_values_supported = { "EdDSA" },
+        code_challenge_methods_supported = { "S256" },
+    })
+end)
+
+app:get("/jwks", function(req)
+    return nitr.json({ keys = {
+        { kty = "OKP", crv = "Ed25519", kid = "ed", alg = "EdDSA", x = b64(signer.public()) },
+    } })
+end)
+
+app:get("/authorize", function(req)
+    local q = req.query
+    if q.prompt == "deny" then
+        return nitr.redirect(q.redirect_uri .. "?error=access_denied&state=" .. q.state)
+    end
+    assert(q.response_type == "code" and q.code_challenge_method == "S256", "bad request")
+    assert(q.scope == "openid email", "scope: " .. q.scope)
+    issued = issued + 1
+    local code = "code" .. issued
+    codes[code] = { challenge = q.code_challenge, nonce = q.nonce, client = q.client_id }
+    return nitr.redirect(q.redirect_uri .. "?code=" .. code .. "&state=" .. q.state)
+end)
+
+app:post("/token", function(req)
+    local form = req:form()
+    local grant = codes[form.code]
+    codes[form.code] = nil
+    if not grant or b64(signer.sha256(form.code_verifier)) ~= grant.challenge then
+        return nitr.json({ error = "invalid_grant" }, 400)
+    end
+    if req.headers["authorization"] ~= "Basic " .. nitr.base64.encode("app:s3cret") then
+        return nitr.json({ error = "invalid_client" }, 401)
+    end
+    local header = b64(nitr.json:encode({ alg = "EdDSA", kid = "ed", typ = "JWT" }))
+    local claims = b64(nitr.json:encode({
+        iss = issuer, aud = grant.client, sub = "u-42", nonce = grant.nonce,
+        email = "ada@example.com", email_verified = true, exp = 4000000000,
+    }))
+    local input = header .. "." .. claims
+    return nitr.json({
+        access_token = "at-1", token_type = "Bearer", expires_in = 3600,
+        id_token = input .. "." .. b64(signer.sign(input)),
+    })
+end)
+
+return app
+"#,
+        )
+        .config_script(format!("return {{ issuer = \"http://{idp_addr}\" }}"))
+        .module("signer", move |lua| {
+            let signer = lua.create_table()?;
+            signer.set(
+                "public",
+                lua.create_function(move |lua, ()| lua.create_string(public))?,
+            )?;
+            let key = key.clone();
+            signer.set(
+                "sign",
+                lua.create_function(move |lua, data: mlua::LuaString| {
+                    lua.create_string(key.sign(&data.as_bytes()).to_bytes())
+                })?,
+            )?;
+            signer.set(
+                "sha256",
+                lua.create_function(|lua, data: mlua::LuaString| {
+                    lua.create_string(sha2::Sha256::digest(data.as_bytes()))
+                })?,
+            )?;
+            Ok(signer)
+        })
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::BASE64 | nitr::Builtins::HTTP)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let mut app = app
+        .handler(
+            r#"
+local app = nitr.app()
+local OPTS = { secret = "session-secret-0123456789" }
+local provider = nitr.oidc({
+    issuer = nitr.cfg.issuer,
+    client_id = "app",
+    client_secret = "s3cret",
+    redirect_uri = nitr.cfg.callback,
+    scopes = { "email" },
+})
+
+app:get("/login", function(req)
+    local session = nitr.session(req, OPTS)
+    local url = assert(provider:authorize(session, { prompt = req.query.prompt }))
+    local resp = nitr.redirect(url)
+    session:save(resp)
+    return resp
+end)
+
+app:get("/callback", function(req)
+    local session = nitr.session(req, OPTS)
+    local user, why = provider:callback(req, session)
+    local resp = nitr.json({ user = user, why = why })
+    session:save(resp)
+    return resp
+end)
+
+return app
+"#,
+        )
+        .config_script(format!(
+            "return {{ issuer = \"http://{idp_addr}\", callback = \"http://{app_addr}/callback\" }}"
+        ))
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::CRYPTO)
+        // The mock provider is on loopback.
+        .config(|cfg| {
+            cfg.workers = 1;
+            cfg.fetch.allow_private_networks = true;
+        })
+        .spawn()
+        .await;
+
+    let location = |resp: &reqwest::Response| -> String {
+        assert_eq!(resp.status(), 302, "{resp:?}");
+        resp.headers()["location"]
+            .to_str()
+            .expect("location")
+            .to_owned()
+    };
+    let client = app.client().clone();
+    let get = |url: String, cookie: Option<String>| {
+        let mut req = client.get(url);
+        if let Some(cookie) = cookie {
+            req = req.header("cookie", cookie);
+        }
+        async move { req.send().await.expect("request") }
+    };
+    // Login, through the provider, back to the callback with the session
+    // cookie that holds the login in progress.
+    let login = |prompt: &'static str| {
+        let login = get(app.url(&format!("/login?prompt={prompt}")), None);
+        async move {
+            let resp = login.await;
+            let cookie = cookie_pair(&resp);
+            let authorize = location(&resp);
+            assert!(authorize.starts_with(&format!("http://{idp_addr}/authorize?")));
+            assert!(
+                authorize.contains("code_challenge_method=S256"),
+                "{authorize}"
+            );
+            let callback = location(&get(authorize, None).await);
+            (callback, cookie)
+        }
+    };
+
+    let (callback, cookie) = login("consent").await;
+    let resp = get(callback.clone(), Some(cookie.clone())).await;
+    let spent = cookie_pair(&resp);
+    let body: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(body["why"], serde_json::Value::Null, "{body}");
+    assert_eq!(body["user"]["sub"], "u-42");
+    assert_eq!(body["user"]["email"], "ada@example.com");
+    assert_eq!(body["user"]["email_verified"], true);
+    assert_eq!(body["user"]["claims"]["aud"], "app");
+    assert_eq!(body["user"]["tokens"]["access_token"], "at-1");
+
+    // The same callback again: the saved session no longer has the
+    // state, and a replayed older cookie still does, but the provider
+    // redeems a code once.
+    for (cookie, why) in [
+        (spent, "state mismatch"),
+        (cookie, "token exchange failed: invalid_grant"),
+    ] {
+        let body: serde_json::Value = get(callback.clone(), Some(cookie))
+            .await
+            .json()
+            .await
+            .expect("json");
+        assert_eq!(body["why"], why, "{body}");
+    }
+    // A callback this browser never started.
+    let body: serde_json::Value = get(callback, None).await.json().await.expect("json");
+    assert_eq!(body["why"], "state mismatch", "{body}");
+
+    // The user declines at the provider.
+    let (callback, cookie) = login("deny").await;
+    let body: serde_json::Value = get(callback, Some(cookie))
+        .await
+        .json()
+        .await
+        .expect("json");
+    assert_eq!(body["why"], "provider error: access_denied", "{body}");
+
+    app.stop().await;
+    idp.stop().await;
+}
+
+/// `nitr.webauthn`: a passkey registered and used to log in, with a
+/// software Ed25519 authenticator standing in for the browser. Replays and
+/// stale counters fail.
+#[cfg(feature = "webauthn")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn webauthn_passkeys_register_and_log_in() {
+    use ed25519_dalek::Signer as _;
+    use sha2::Digest as _;
+
+    /// A CBOR major type and length header.
+    fn head(major: u8, len: usize) -> Vec<u8> {
+        match len {
+            0..24 => vec![major << 5 | len as u8],
+            24..256 => vec![major << 5 | 24, len as u8],
+            _ => vec![major << 5 | 25, (len >> 8) as u8, len as u8],
+        }
+    }
+    fn text(s: &str) -> Vec<u8> {
+        [head(3, s.len()), s.as_bytes().to_vec()].concat()
+    }
+    fn bytes(b: &[u8]) -> Vec<u8> {
+        [head(2, b.len()), b.to_vec()].concat()
+    }
+    let b64 = |b: &[u8]| {
+        use base64::Engine as _;
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(b)
+    };
+
+    let key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+    let credential_id = b"passkey-1";
+    // COSE_Key {1: 1 (OKP), 3: -8 (EdDSA), -1: 6 (Ed25519), -2: x}.
+    let cose = [
+        vec![0xA4, 0x01, 0x01, 0x03, 0x27, 0x20, 0x06, 0x21],
+        bytes(&key.verifying_key().to_bytes()),
+    ]
+    .concat();
+    let auth_data = |count: u32, attested: bool| {
+        let mut out = sha2::Sha256::digest(b"localhost").to_vec();
+        // User present and verified, plus attested credential data.
+        out.push(if attested { 0x45 } else { 0x05 });
+        out.extend_from_slice(&count.to_be_bytes());
+        if attested {
+            out.extend_from_slice(&[0; 16]);
+            out.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+            out.extend_from_slice(credential_id);
+            out.extend_from_slice(&cose);
+        }
+        out
+    };
+    let client_data = |kind: &str, challenge: &str| {
+        serde_json::json!({
+            "type": kind,
+            "challenge": challenge,
+            "origin": "http://localhost:8080",
+        })
+        .to_string()
+    };
+
+    let mut server = TestServer::builder("std14-webauthn")
+        .handler(
+            r#"
+local app = nitr.app()
+local OPTS = { secret = "session-secret-0123456789" }
+local rp = nitr.webauthn({
+    rp_id = "localhost",
+    rp_name = "Nitr test",
+    origins = { "http://localhost:8080" },
+    user_verification = "required",
+})
+local credentials = {}
+
+local function step(fn)
+    return function(req)
+        local session = nitr.session(req, OPTS)
+        local resp = nitr.json(fn(req, session))
+        session:save(resp)
+        return resp
+    end
+end
+
+app:post("/register/options", step(function(req, session)
+    return rp:registration_options(session, { id = "u-1", name = "ada" })
+end))
+
+app:post("/register", step(function(req, session)
+    local cred, why = rp:verify_registration(session, req:json())
+    if cred then credentials[cred.id] = cred end
+    return { credential = cred, why = why }
+end))
+
+app:post("/login/options", step(function(req, session)
+    local allow = {}
+    for _, cred in pairs(credentials) do allow[#allow + 1] = cred end
+    return rp:authentication_options(session, { allow = allow })
+end))
+
+app:post("/login", step(function(req, session)
+    local body = req:json()
+    local stored = credentials[body.id]
+    local ok, why = rp:verify_authentication(session, body, stored)
+    if ok then stored.sign_count = ok.sign_count end
+    return { ok = ok, why = why }
+end))
+
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::WEBAUTHN)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let client = server.client().clone();
+    let post = |path: &str, cookie: &str, body: serde_json::Value| {
+        let mut req = client.post(server.url(path)).json(&body);
+        if !cookie.is_empty() {
+            req = req.header("cookie", cookie);
+        }
+        async move {
+            let resp = req.send().await.expect("request");
+            let cookie = cookie_pair(&resp);
+            let body: serde_json::Value = resp.json().await.expect("json");
+            (cookie, body)
+        }
+    };
+
+    let (cookie, options) = post("/register/options", "", serde_json::json!({})).await;
+    assert_eq!(options["rp"]["id"], "localhost", "{options}");
+    assert_eq!(options["user"]["id"], b64(b"u-1"));
+    assert_eq!(
+        options["authenticatorSelection"]["userVerification"],
+        "required"
+    );
+    let challenge = options["challenge"].as_str().expect("challenge");
+    // attestationObject {"fmt": "none", "attStmt": {}, "authData": ...}.
+    let attestation = [
+        vec![0xA3],
+        text("fmt"),
+        text("none"),
+        text("attStmt"),
+        vec![0xA0],
+        text("authData"),
+        bytes(&auth_data(0, true)),
+    ]
+    .concat();
+    let registration = serde_json::json!({
+        "id": b64(credential_id),
+        "rawId": b64(credential_id),
+        "type": "public-key",
+        "response": {
+            "clientDataJSON": b64(client_data("webauthn.create", challenge).as_bytes()),
+            "attestationObject": b64(&attestation),
+            "transports": ["internal", "hybrid"],
+        },
+    });
+    let (spent, body) = post("/register", &cookie, registration.clone()).await;
+    let credential = &body["credential"];
+    assert_eq!(body["why"], serde_json::Value::Null, "{body}");
+    assert_eq!(credential["id"], b64(credential_id));
+    assert_eq!(credential["public_key"], b64(&cose));
+    assert_eq!(credential["alg"], "EdDSA");
+    assert_eq!(credential["user_id"], "u-1");
+    assert_eq!(credential["sign_count"], 0);
+    assert_eq!(credential["transports"][1], "hybrid");
+    // The challenge answered once.
+    let (_, body) = post("/register", &spent, registration).await;
+    assert_eq!(body["why"], "no ceremony in progress", "{body}");
+
+    let login = |count: u32| {
+        let (post, key, client_data, auth_data) = (&post, &key, &client_data, &auth_data);
+        async move {
+            let (cookie, options) = post("/login/options", "", serde_json::json!({})).await;
+            assert_eq!(options["rpId"], "localhost", "{options}");
+            assert_eq!(options["allowCredentials"][0]["id"], b64(credential_id));
+            let challenge = options["challenge"].as_str().expect("challenge");
+            let client = client_data("webauthn.get", challenge);
+            let auth = auth_data(count, false);
+            let mut signed = auth.clone();
+            signed.extend_from_slice(&sha2::Sha256::digest(client.as_bytes()));
+            let assertion = serde_json::json!({
+                "id": b64(credential_id),
+                "rawId": b64(credential_id),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": b64(client.as_bytes()),
+                    "authenticatorData": b64(&auth),
+                    "signature": b64(&key.sign(&signed).to_bytes()),
+                    "userHandle": b64(b"u-1"),
+                },
+            });
+            post("/login", &cookie, assertion).await.1
+        }
+    };
+    let body = login(1).await;
+    assert_eq!(body["why"], serde_json::Value::Null, "{body}");
+    assert_eq!(body["ok"]["user_id"], "u-1");
+    assert_eq!(body["ok"]["sign_count"], 1);
+    assert_eq!(body["ok"]["user_verified"], true);
+    // A cloned authenticator replaying an old counter.
+    let body = login(1).await;
+    assert_eq!(body["why"], "signature counter did not increase", "{body}");
+    assert_eq!(login(2).await["ok"]["sign_count"], 2);
+
+    server.stop().await;
+}
+
+/// `nitr.webhook`: a Standard Webhooks message signed by `sign` verifies
+/// against the raw body, a tampered or unsigned one is refused with a
+/// reason, and GitHub's documented example checks out.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn webhooks_verify_the_raw_body_and_sign_outbound_messages() {
+    let mut server = TestServer::builder("std14-webhook")
+        .handler(
+            r#"
+local app = nitr.app()
+local secret = "whsec_MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw"
+
+app:get("/sign", function(req)
+    local headers, body = nitr.webhook.sign({ event = "ping" }, { secret = secret })
+    return nitr.json({ headers = headers, body = body })
+end)
+
+app:post("/hook", function(req)
+    local body, why = nitr.webhook.verify(req, {
+        scheme = "standard-webhooks",
+        secret = { "whsec_AAAAAAAAAAAAAAAAAAAAAAAA", secret },
+    })
+    if not body then
+        return nitr.text(why, 401)
+    end
+    return nitr.text(body)
+end)
+
+app:post("/github", function(req)
+    local body, why = nitr.webhook.verify(req, {
+        scheme = "github",
+        secret = "It's a Secret to Everybody",
+    })
+    return nitr.json({ body = body, why = why })
+end)
+
+return app
+"#,
+        )
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP)
+        .config(|cfg| cfg.workers = 1)
+        .spawn()
+        .await;
+
+    let signed = server.json("/sign").await;
+    let body = signed["body"].as_str().expect("body").to_string();
+    assert_eq!(body, r#"{"event":"ping"}"#);
+    assert!(
+        signed["headers"]["webhook-id"]
+            .as_str()
+            .expect("id")
+            .starts_with("msg_"),
+        "{signed}"
+    );
+    let post = |body: String| {
+        let mut req = server.client().post(server.url("/hook")).body(body);
+        for name in ["webhook-id", "webhook-timestamp", "webhook-signature"] {
+            req = req.header(name, signed["headers"][name].as_str().expect(name));
+        }
+        req.send()
+    };
+
+    // Signed with the second secret of the list: accepted, raw body back.
+    let resp = post(body.clone()).await.expect("post");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.expect("text"), body);
+
+    // One changed byte: refused.
+    let resp = post(body.replace("ping", "pong")).await.expect("post");
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.text().await.expect("text"), "invalid signature");
+
+    // No signature headers at all: refused.
+    let resp = server
+        .client()
+        .post(server.url("/hook"))
+        .body(body)
+        .send()
+        .await
+        .expect("post");
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.text().await.expect("text"), "missing signature header");
+
+    let github = |signature: &'static str| {
+        server
+            .client()
+            .post(server.url("/github"))
+            .header("x-hub-signature-256", signature)
+            .body("Hello, World!")
+            .send()
+    };
+    let resp = github("sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17")
+        .await
+        .expect("post");
+    let result: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(result["body"], "Hello, World!", "{result}");
+    let resp = github("sha256=zz").await.expect("post");
+    let result: serde_json::Value = resp.json().await.expect("json");
+    assert_eq!(result["why"], "malformed signature header", "{result}");
+
+    server.stop().await;
+}
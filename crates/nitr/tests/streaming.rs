@@ -179,3 +179,167 @@ async fn streaming_bodies_end_to_end() {
 
     server.stop().await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn published_messages_reach_sse_subscribers_in_other_states() {
+    const APP: &str = r#"
+local app = nitr.app()
+
+app:get("/room/:id", function(req)
+    local sub = nitr.pubsub.subscribe({ "chat." .. req.params.id, "chat.all" })
+    return nitr.sse(function(send)
+        -- Subscribed above; tell the client it can start publishing.
+        send("ready", "")
+        for msg in sub do
+            send("message", msg)
+            if msg.data.text == "bye" then return end
+        end
+    end)
+end)
+
+app:post("/say/:topic", function(req)
+    local n = nitr.pubsub.publish("chat." .. req.params.topic, req:json())
+    return nitr.json({ delivered = n })
+end)
+
+return app
+"#;
+
+    let mut server = TestServer::builder("pubsub-sse")
+        .handler(APP)
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP | nitr::Builtins::PUBSUB)
+        .config(|cfg| cfg.workers = 3)
+        .spawn()
+        .await;
+    let client = server.client().clone();
+
+    let mut feed = client
+        .get(server.url("/room/7"))
+        .send()
+        .await
+        .expect("feed");
+    let mut body = String::new();
+    while !body.contains("event: ready") {
+        let chunk = feed.chunk().await.expect("chunk").expect("ready event");
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+
+    let say = async |topic: &str, text: &str| -> serde_json::Value {
+        client
+            .post(server.url(&format!("/say/{topic}")))
+            .body(serde_json::json!({ "text": text }).to_string())
+            .send()
+            .await
+            .expect("publish")
+            .json()
+            .await
+            .expect("json")
+    };
+    assert_eq!(say("7", "hello").await["delivered"], 1);
+    assert_eq!(say("8", "elsewhere").await["delivered"], 0);
+    assert_eq!(say("all", "bye").await["delivered"], 1);
+
+    let rest = tokio::time::timeout(Duration::from_secs(5), feed.text())
+        .await
+        .expect("feed ends after bye")
+        .expect("feed body");
+    let messages: Vec<serde_json::Value> = rest
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).expect("message json"))
+        .collect();
+    assert_eq!(messages.len(), 2, "got: {rest}");
+    assert_eq!(messages[0]["topic"], "chat.7");
+    assert_eq!(messages[0]["data"]["text"], "hello");
+    assert_eq!(messages[1]["topic"], "chat.all");
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn channel_streams_hold_no_state_and_resume_from_their_history() {
+    const APP: &str = r#"
+local app = nitr.app()
+
+app:get("/orders/feed", function(req)
+    return nitr.sse.channel("orders", { last_event_id = req.headers["last-event-id"] })
+end)
+
+app:post("/orders", function(req)
+    local id = nitr.sse.publish("orders", "created", req:json())
+    return nitr.json({ id = id })
+end)
+
+return app
+"#;
+
+    // One state and one stream slot: three live clients would starve the
+    // pool if a channel stream kept its state.
+    let mut server = TestServer::builder("sse-channel")
+        .handler(APP)
+        .builtins(nitr::Builtins::JSON | nitr::Builtins::HTTP)
+        .config(|cfg| {
+            cfg.workers = 1;
+            cfg.max_streams = Some(1);
+            cfg.sse.heartbeat_ms = 100;
+        })
+        .spawn()
+        .await;
+    let client = server.client().clone();
+
+    let mut feeds = Vec::new();
+    for _ in 0..3 {
+        let feed = client
+            .get(server.url("/orders/feed"))
+            .send()
+            .await
+            .expect("feed");
+        assert_eq!(feed.status(), 200);
+        assert_eq!(feed.headers()["content-type"], "text/event-stream");
+        feeds.push(feed);
+    }
+    let mut ids = Vec::new();
+    for n in 1..=2 {
+        let body: serde_json::Value = client
+            .post(server.url("/orders"))
+            .body(serde_json::json!({ "n": n }).to_string())
+            .send()
+            .await
+            .expect("publish")
+            .json()
+            .await
+            .expect("json");
+        ids.push(body["id"].as_str().expect("id").to_string());
+    }
+
+    for feed in &mut feeds {
+        let mut seen = String::new();
+        while !(seen.contains(r#"data: {"n":2}"#) && seen.contains(": keepalive")) {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), feed.chunk())
+                .await
+                .expect("an event or a heartbeat")
+                .expect("chunk")
+                .expect("stream open");
+            seen.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        assert!(
+            seen.contains(&format!("id: {}\nevent: created\n", ids[0])),
+            "{seen}"
+        );
+    }
+    drop(feeds);
+
+    // A reconnecting client gets what came after the id it last saw.
+    let mut resumed = client
+        .get(server.url("/orders/feed"))
+        .header("last-event-id", &ids[0])
+        .send()
+        .await
+        .expect("resume");
+    let chunk = resumed.chunk().await.expect("chunk").expect("replay");
+    let replay = String::from_utf8_lossy(&chunk);
+    assert!(replay.starts_with(&format!("id: {}\n", ids[1])), "{replay}");
+    drop(resumed);
+
+    server.stop().await;
+}
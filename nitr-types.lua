@@ -14,9 +14,11 @@ nitr = {}
 ---@field query table<string, string> Parsed query string; repeated keys keep the last value.
 ---@field headers table<string, string> Request headers, lowercase names.
 ---@field id string The request id (UUIDv7, echoed as `X-Request-ID`).
+---@field csp_nonce string? This request's Content-Security-Policy nonce, when the `[headers] csp` names `{nonce}`; templates see it as `csp_nonce`.
 ---@field remote_addr string Peer address (`"ip:port"`).
 ---@field uri table URI components: `scheme`, `host`, `port`, `path`, `authority`, `query`.
 ---@field cookies nitr.RequestCookies Parsed request cookies.
+---@field ctx table Per-request table shared along the chain: middleware leaves results here (`nitr.auth.jwt` sets `ctx.claims`).
 local Request = {}
 
 ---Reads and decodes the body as JSON. Errors on an empty or invalid body.
@@ -58,10 +60,16 @@ local RequestCookies = {}
 
 ---The verified value of a signed cookie, or nil when missing or tampered.
 ---@param name string
----@param secret string
+---@param secret string|nitr.KeyRing The signing secret, or `nitr.keys` to accept any key of the ring.
 ---@return string|nil
 function RequestCookies:verify(name, secret) end
 
+---The `[keys]` ring (`nitr.keys`): pass it wherever a signing secret or a `seal` key goes. The newest key signs and seals, prefixing its id to the result; any key still in the ring verifies and opens. Scripts see the ids, never the keys.
+---@class nitr.KeyRing
+---@field current integer The id of the newest key, the one that signs.
+---@field ids integer[] Every key id, newest first.
+local KeyRing = {}
+
 ---A response: `{ status, headers, body }` plus a cookie builder. Helpers build these; handlers may also build them by hand.
 ---@class nitr.Response
 ---@field status integer HTTP status code.
@@ -83,7 +91,7 @@ function ResponseCookies:set(name, value, opts) end
 ---Adds an HMAC-signed cookie, verifiable later with `req.cookies:verify`.
 ---@param name string
 ---@param value string
----@param secret string
+---@param secret string|nitr.KeyRing The signing secret, or `nitr.keys` to sign with its newest key.
 ---@param opts? table
 function ResponseCookies:set_signed(name, value, secret, opts) end
 
@@ -91,7 +99,7 @@ function ResponseCookies:set_signed(name, value, secret, opts) end
 ---@class nitr.App
 local App = {}
 
----Registers a GET route: `middleware..., handler` plus an optional trailing `{ on_error = fn }`. Paths take `:name` parameters and a trailing `*` catch-all.
+---Registers a GET route: `middleware..., handler` plus an optional trailing options table — `{ on_error = fn, access = { "allow 10.0.0.0/8", "deny all" } }`, and whatever the route's middleware reads (`scopes` for `nitr.auth.jwt`). Paths take `:name` parameters and a trailing `*` catch-all.
 ---@param path string
 ---@param ... fun(req: nitr.Request): nitr.Response|table
 function App:get(path, ...) end
@@ -126,15 +134,32 @@ function App:head(path, ...) end
 ---@param ... fun(req: nitr.Request): nitr.Response|table
 function App:options(path, ...) end
 
----Adds app-wide middleware: a factory `fn(next) -> fn(req)`. Must be called before any route.
----@param mw fun(next: fun): fun(req: nitr.Request): any
+---Adds app-wide middleware: a factory `fn(next, route) -> fn(req)`, called once per route with `route = { method, path, options }`. Must be called before any route.
+---@param mw fun(next: fun, route: table): fun(req: nitr.Request): any
 function App:use(mw) end
 
 ---Sets the app-wide error handler: `fn(err, req)` where `err` is the structured error (`kind`, `message`, `source`, `line`, `traceback`, ...).
 ---@param handler fun(err: table, req: nitr.Request): nitr.Response|table
 function App:on_error(handler) end
 
----Mounts a static directory, served in Rust. Options: `{ spa = boolean, cache_control = string }`.
+---Registers the handler for background jobs enqueued under `name` (`nitr.jobs.enqueue`): `fn(payload, job)`, where `job` has `id`, `name`, `attempt` and `max_attempts`. It runs on a pooled state under the request limits; raising an error fails the attempt.
+---@param name string
+---@param handler fun(payload: any, job: table)
+function App:job(name, handler) end
+
+---Runs `fn(run)` every `interval` (`"200ms"`, `"30s"`, `"5m"`, `"1h"`, `"1d"` or seconds), counted from server start; `run` has `name` and `scheduled_at` (ms). Runs on a pooled state under the request limits, a run still going makes the next one skip, and with a shared SQLite database only the process holding the `[schedule]` lease runs it. Options: `{ name = string, jitter = interval }`; the name defaults to the interval.
+---@param interval string|number
+---@param handler fun(run: table)
+---@param opts? table
+function App:every(interval, handler, opts) end
+
+---Runs `fn(run)` whenever a five-field cron expression (minute hour day-of-month month day-of-week, in UTC) matches; `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` also work. Otherwise as `app:every`; the name defaults to the expression.
+---@param expr string
+---@param handler fun(run: table)
+---@param opts? table
+function App:cron(expr, handler, opts) end
+
+---Mounts a static directory, served in Rust. Options: `{ spa = boolean, cache_control = string, access = string[] }`; `access` rules are checked in Rust before the request reaches Lua.
 ---@param mount string
 ---@param dir string
 ---@param opts? table
@@ -196,21 +221,126 @@ local Schema = {}
 ---@return table|nil _ The error, when validation failed.
 function Schema:check(value) end
 
----A stateless signed-cookie session from `nitr.session`. Assign fields directly (`session.user_id = 42`).
+---A session from `nitr.session`: in the signed cookie, or stored behind a token with `store`. Assign fields directly (`session.user_id = 42`).
 ---@class nitr.Session
 local Session = {}
 
----Serializes the session into a signed cookie on the response. An empty session deletes the cookie.
+---Writes the session (into the signed cookie, or to its store) and sets the cookie on the response. An empty session deletes the cookie and the stored row.
 ---@param resp nitr.Response|table
 function Session:save(resp) end
 
 ---Removes every field; `save` then writes the deletion cookie.
 function Session:clear() end
 
+---Stored sessions: rotates the id (call on login, against fixation) and binds the session to `user` for `revoke_all`; the old id is deleted on `save`. Does nothing on a cookie session.
+---@param user? string|integer
+function Session:regenerate(user) end
+
+---Stored sessions: the id `nitr.session.revoke` takes (a hash of the cookie token), or nil before the first save and on cookie sessions.
+---@return string|nil
+function Session:id() end
+
+---An OpenID provider from `nitr.oidc`. Both methods change the session: save it on the response.
+---@class nitr.OidcProvider
+local OidcProvider = {}
+
+---Starts a login: remembers a fresh `state`, `nonce` and PKCE verifier in the session and returns the provider's authorization URL to redirect to. The user has 10 minutes to come back; a session holds at most 3 logins in progress.
+---@param session nitr.Session
+---@param params? table Extra authorization parameters (`prompt`, `login_hint`, ...); the ones the flow sets itself are refused.
+---@return string|nil _ The URL.
+---@return string|nil _ Why the provider could not be discovered.
+function OidcProvider:authorize(session, params) end
+
+---Finishes a login on the `redirect_uri` route: checks and spends `state`, exchanges the code (with the PKCE verifier and client credentials), and verifies the ID token against the provider's JWKS, `iss`, `aud`, `exp` and `nonce`.
+---@param req nitr.Request
+---@param session nitr.Session
+---@return table|nil _ The user: `sub`, `email`, `email_verified`, `name`, `given_name`, `family_name`, `preferred_username`, `picture`, `locale` when present, every claim under `claims`, and the token response under `tokens`.
+---@return string|nil _ The rejection reason: `state mismatch`, `provider error: <code>` (a standard OAuth code such as `access_denied`, else `unknown`), `invalid id token: ...`, ...
+function OidcProvider:callback(req, session) end
+
+---An Ed25519, P-256 or X25519 private key from `nitr.crypto.keypair` or `nitr.crypto.private_key`. Opaque: it has no JSON form, and `tostring` names only the algorithm.
+---@class nitr.PrivateKey
+---@field alg string `"ed25519"`, `"p256"` or `"x25519"`.
+local PrivateKey = {}
+
+---The matching public key.
+---@return nitr.PublicKey
+function PrivateKey:public() end
+
+---Same as `nitr.crypto.sign(key, message)`.
+---@param message string
+---@return string
+function PrivateKey:sign(message) end
+
+---The key material, for storing it: `"pem"` (PKCS#8), `"der"`, `"raw"` (32 bytes) or `"jwk"` (a table).
+---@param format string
+---@return string|table
+function PrivateKey:export(format) end
+
+---A public key from a `nitr.PrivateKey` or `nitr.crypto.public_key`. Two keys compare equal with `==` when they are the same key.
+---@class nitr.PublicKey
+---@field alg string `"ed25519"`, `"p256"` or `"x25519"`.
+local PublicKey = {}
+
+---Same as `nitr.crypto.verify(key, message, sig)`.
+---@param message string
+---@param sig string
+---@return boolean
+function PublicKey:verify(message, sig) end
+
+---`"pem"` (SPKI), `"der"`, `"raw"` (32 bytes, or a 65-byte uncompressed point for P-256) or `"jwk"` (a table).
+---@param format string
+---@return string|table
+function PublicKey:export(format) end
+
+---A relying party from `nitr.webauthn`. The `*_options` methods start a ceremony in the session and the `verify_*` methods end it, whatever the outcome: save the session on each response. Responses are a credential's `toJSON()`, as a table or a JSON string.
+---@class nitr.WebAuthn
+local WebAuthn = {}
+
+---Starts registering a passkey: the options for `PublicKeyCredential.parseCreationOptionsFromJSON`, with a fresh challenge remembered in the session.
+---@param session nitr.Session
+---@param user table `id` (a stable, non-personal handle of 1 to 64 bytes), `name`, `display_name` (default `name`).
+---@param opts? table `exclude`: the user's existing credential records, so an authenticator is not registered twice; `resident_key` (`"required"`, `"preferred"` (default) or `"discouraged"`).
+---@return table
+function WebAuthn:registration_options(session, user, opts) end
+
+---Finishes a registration: checks the client data (type, challenge, origin), the RP ID hash, user presence and verification, and `none` or packed self-attestation; other statements go unchecked and the record says `none`.
+---@param session nitr.Session
+---@param response table|string
+---@return table|nil _ The credential record to store: `id`, `public_key`, `alg`, `sign_count`, `user_id`, `transports`, `aaguid`, `attestation`, `backup_eligible`, `backed_up`, `user_verified`.
+---@return string|nil _ The rejection reason (`challenge mismatch`, `origin not allowed`, `rp id mismatch`, ...).
+function WebAuthn:verify_registration(session, response) end
+
+---Starts a passkey login: the options for `PublicKeyCredential.parseRequestOptionsFromJSON`, with a fresh challenge remembered in the session.
+---@param session nitr.Session
+---@param opts? table `allow`: credential records or ids to offer; without it, discoverable credentials let the user pick.
+---@return table
+function WebAuthn:authentication_options(session, opts) end
+
+---Finishes a login against the stored record of the credential the response names (look it up by `response.id`; nil fails as unknown). Checks the client data, the RP ID hash, user presence and verification, the signature and the user handle, and that the signature counter increased.
+---@param session nitr.Session
+---@param response table|string
+---@param credential? table
+---@return table|nil _ `id`, `user_id`, `sign_count` (store it on the record), `backed_up`, `user_verified`.
+---@return string|nil _ The rejection reason (`invalid signature`, `signature counter did not increase`, ...).
+function WebAuthn:verify_authentication(session, response, credential) end
+
 ---A database transaction handle inside `nitr.db:transaction`; same query API as `nitr.db`, plus nesting via savepoints.
 ---@class nitr.Tx
 local Tx = {}
 
+---A `nitr.pubsub` subscription. Callable as an iterator (`for msg in sub do ... end`), which waits for each message and ends when the subscription does. Messages are `{ topic, data }`; after a gap from a full buffer, `{ lagged = n }` reports how many were dropped. Collecting the handle unsubscribes.
+---@class nitr.Subscription
+local Subscription = {}
+
+---Waits for the next message, or `{ lagged = n }`.
+---@param timeout? number Seconds to wait; without one, waits until a message arrives.
+---@return table|nil _ nil on timeout, or once the subscription ended.
+function Subscription:next(timeout) end
+
+---Unsubscribes; messages already buffered are still returned.
+function Subscription:close() end
+
 ---As a function: a JSON response (`nitr.json({ ok = true })`). Also the codec: `nitr.json:encode(v)` / `nitr.json:decode(s)`. (std feature: `json`)
 ---@class nitr.json
 ---@overload fun(value: any, status: integer?): nitr.Response
@@ -255,10 +385,23 @@ function nitr.status(code) end
 ---@return any
 function nitr.negotiate(req, offers) end
 
----A Server-Sent Events stream: `fn(send)` calls `send(event, data)`; table data is JSON-encoded. (std feature: `http`)
----@param fn fun(send: fun(event: string, data: any))
+---As a function: a Server-Sent Events stream. `fn(send)` calls `send(event, data)`, and table data is JSON-encoded. The stream keeps its state for as long as the client is connected (`max_streams`). A `channel` stream does not, and is served by Rust from events published to it. (std feature: `http`)
+---@class nitr.sse
+---@overload fun(fn: fun(send: fun(event: string, data: any))): nitr.Response
+nitr.sse = {}
+
+---Hands the connection to the channel. The state returns to the pool at once; Rust sends the published events and heartbeats (`[sse]`).
+---@param name string
+---@param opts? table `{ last_event_id? }`: pass `req.headers["last-event-id"]` to replay what a reconnecting client missed.
 ---@return nitr.Response
-function nitr.sse(fn) end
+function nitr.sse.channel(name, opts) end
+
+---Sends an event to every client of a channel and keeps it in the channel's history. Works from any handler, including a `db:on_change` callback.
+---@param channel string
+---@param event string
+---@param data any A string verbatim; anything else as JSON.
+---@return string _ The event id.
+function nitr.sse.publish(channel, event, data) end
 
 ---An error response: string body as text, table body as JSON. (std feature: `http`)
 ---@param code integer
@@ -272,7 +415,7 @@ function nitr.error(code, body) end
 ---@return string
 function nitr.etag(value, weak) end
 
----As a function: the CSRF middleware factory for `app:use` (signed double-submit cookie; unsafe methods must echo the token in `X-CSRF-Token` or a `_csrf` field). Options: `secret` (required), `cookie`, `header`, `field`, `cookie_opts`. (std feature: `http`)
+---As a function: the CSRF middleware factory for `app:use` (signed double-submit cookie; unsafe methods must echo the token in `X-CSRF-Token` or a `_csrf` field). Options: `secret` (required: at least 16 bytes, or `nitr.keys`), `cookie`, `header`, `field`, `cookie_opts`. (std feature: `http`)
 ---@class nitr.csrf
 ---@overload fun(opts: table): fun
 nitr.csrf = {}
@@ -282,11 +425,32 @@ nitr.csrf = {}
 ---@return string
 function nitr.csrf.token(req) end
 
----Loads (or starts) the stateless signed-cookie session. Options: `secret` (required), `name`, `max_age`, `cookie`. (std feature: `http`)
----@param req nitr.Request
+---As a function: loads (or starts) the request's session. Options: `secret` (required: at least 16 bytes, or `nitr.keys`), `name`, `max_age`, `cookie`, `store` (`"cookie"` by default, `"db"` or `"cache"`), `ttl` (stored idle lifetime in seconds, default a week), `sliding` (default true). Inside `db:transaction`, `"db"` store writes wait for the transaction to end. (std feature: `http`)
+---@class nitr.session
+---@overload fun(req: nitr.Request, opts: table): nitr.Session
+nitr.session = {}
+
+---Ends one stored session by its `session:id()`.
+---@param id string
+---@param opts? table `store` (default `"db"`).
+---@return boolean
+function nitr.session.revoke(id, opts) end
+
+---Ends every stored session bound to `user` by `session:regenerate(user)`: log out everywhere.
+---@param user string|integer
+---@param opts? table `store` (default `"db"`).
+---@return integer
+function nitr.session.revoke_all(user, opts) end
+
+---"Sign in with X": an OpenID Connect provider for the authorization-code flow with PKCE. Options: `issuer`, `client_id`, `redirect_uri` (required), `client_secret` (omit for a public client), `scopes` (default `{ "email", "profile" }`; `openid` is always asked for), `algorithms` (default: what the provider advertises), `leeway`. The endpoints and keys come from the issuer's discovery document, fetched under the `[fetch]` policy once per process. Needs the `fetch` Cargo feature too. (std feature: `crypto`)
 ---@param opts table
----@return nitr.Session
-function nitr.session(req, opts) end
+---@return nitr.OidcProvider
+function nitr.oidc(opts) end
+
+---Passkeys: a WebAuthn relying party. Options: `rp_id` (the site's domain, required), `origins` (required: https origins on `rp_id` or its subdomains; `http://localhost` for development), `rp_name` (default `rp_id`), `user_verification` (`"required"`, `"preferred"` (default) or `"discouraged"`), `timeout` (seconds per ceremony, default 300), `algorithms` (default `{ "ES256", "EdDSA", "RS256" }`). (std feature: `webauthn`)
+---@param opts table
+---@return nitr.WebAuthn
+function nitr.webauthn(opts) end
 
 ---Creates the application object the handler script must return.
 ---@return nitr.App
@@ -317,13 +481,13 @@ function nitr.await_all(handles) end
 ---The minijinja template engine, loading from `[templating] dir`. (std feature: `template`)
 nitr.template = {}
 
----Renders a template.
+---Renders a template. The request's CSP nonce is in scope as `csp_nonce`.
 ---@param name string
 ---@param data? table
 ---@return string
 function nitr.template:render(name, data) end
 
----The SQLite database (`database` in nitr.toml): WAL, busy timeout, foreign keys on. (std feature: `db`)
+---The database (`[database]` in nitr.toml): SQLite with WAL, busy timeout and foreign keys on, or Postgres with `url`. (std feature: `db`)
 nitr.db = {}
 
 ---Runs a statement.
@@ -362,6 +526,23 @@ function nitr.db:transaction(fn) end
 ---@return table _ A pending handle for `nitr.await_all`.
 function nitr.db:query_async(sql, params, kind) end
 
+---Subscribes to committed row changes (`[database] changes = true`): `for change in nitr.db:changes({"orders"}) do ... end`.
+---@param tables? string|string[]
+---@return fun(): table|nil _ Iterator yielding `{ table, op, rowid }` per committed change; `{ op = "lagged", missed }` after falling behind.
+function nitr.db:changes(tables) end
+
+---Calls `fn` for each committed change until it returns `false`.
+---@param tables? string|string[]
+---@param fn? fun(change: table): boolean
+function nitr.db:on_change(tables, fn) end
+
+---Full-text search over a `[[database.search]]` index. User text is searched as words, never parsed as FTS5 syntax.
+---@param index string
+---@param text string
+---@param opts? table `limit` (20), `offset`, `columns`, `snippet` / `highlight` (a column name or `{ column, open, close, ellipsis, tokens }`), `html` (true: escape around the marks), `raw` (FTS5 query syntax).
+---@return table[] _ Content rows, best first, plus `rank`, `snippet`, `highlight`.
+function nitr.db:search(index, text, opts) end
+
 ---Structured logging into the request span. Fields become real keys in JSON log output. (std feature: `log`)
 nitr.log = {}
 
@@ -422,20 +603,57 @@ function nitr.crypto.password_hash(password) end
 function nitr.crypto.password_verify(password, hash) end
 
 ---Authenticated encryption (XChaCha20-Poly1305); printable token.
----@param key string Exactly 32 bytes.
+---@param key string|nitr.KeyRing Exactly 32 bytes, or `nitr.keys`: its newest key seals and the token carries its id.
 ---@param plaintext string
 ---@param aad? string
 ---@return string
 function nitr.crypto.seal(key, plaintext, aad) end
 
 ---Opens a sealed token; nil on any tampering.
----@param key string
+---@param key string|nitr.KeyRing
 ---@param sealed string
 ---@param aad? string
 ---@return string|nil
 function nitr.crypto.open(key, sealed, aad) end
 
----HMAC JWTs (HS256/384/512). Verification demands an explicit algorithm allow-list; `exp`/`nbf` are checked by default. (std feature: `crypto`)
+---A new random key pair.
+---@param alg string `"ed25519"`, `"p256"` (ECDSA) or `"x25519"` (key agreement).
+---@return nitr.PrivateKey
+---@return nitr.PublicKey
+function nitr.crypto.keypair(alg) end
+
+---Loads a private key.
+---@param source string|table PEM (PKCS#8, or SEC1 `EC PRIVATE KEY`) or JWK text, a JWK table, or `{ file = path }` relative to the app directory.
+---@param opts? table `{ format = "der" }`, or `{ format = "raw", alg = ... }` for binary keys.
+---@return nitr.PrivateKey
+function nitr.crypto.private_key(source, opts) end
+
+---Loads a public key; private keys are refused.
+---@param source string|table PEM (SPKI) or JWK text, a JWK table, or `{ file = path }` relative to the app directory.
+---@param opts? table As for `private_key`; raw P-256 keys are SEC1 points.
+---@return nitr.PublicKey
+function nitr.crypto.public_key(source, opts) end
+
+---Signs a message; the verifier needs only the public key.
+---@param key nitr.PrivateKey
+---@param message string
+---@return string _ Raw bytes: 64 for Ed25519, `r || s` for P-256 (ECDSA over SHA-256, deterministic).
+function nitr.crypto.sign(key, message) end
+
+---Checks a signature; malformed signatures are false.
+---@param key nitr.PublicKey
+---@param message string
+---@param sig string P-256 signatures may also be DER, as OpenSSL makes them.
+---@return boolean
+function nitr.crypto.verify(key, message, sig) end
+
+---X25519 key agreement. Hash the result with context before using it as a key; low-order public keys are refused.
+---@param private nitr.PrivateKey
+---@param public nitr.PublicKey
+---@return string _ 32 raw bytes.
+function nitr.crypto.shared_secret(private, public) end
+
+---JWTs. `sign` makes HMAC tokens (HS256/384/512); `verify` also checks RS256/384/512, PS256/384/512, ES256 and EdDSA against public keys from `keyset`. Verification demands an explicit algorithm allow-list; `exp`/`nbf` are checked by default, `iss`/`aud` when given. (std feature: `crypto`)
 nitr.crypto.jwt = {}
 
 ---Signs a token.
@@ -445,15 +663,67 @@ nitr.crypto.jwt = {}
 ---@return string
 function nitr.crypto.jwt.sign(claims, key, opts) end
 
----Verifies a token.
+---Loads verification keys once. A JWKS URL is cached and fetched again early when a token names an unknown `kid`.
+---@param source string|table PEM, JWK or JWKS text or table; `{ file, kid?, alg? }` (a path relative to the app directory, which it may not leave); `{ text, kid?, alg? }`; or `{ url, refresh? }` for a JWKS fetched under the `[fetch]` policy (refresh in seconds, default 3600).
+---@return userdata
+function nitr.crypto.jwt.keyset(source) end
+
+---Verifies a token; keys are chosen by the header's `kid`.
 ---@param token string
----@param key string
----@param opts table `{ algorithms = {...}, leeway? }` — the allow-list is required.
+---@param key string|userdata An HMAC secret or a `keyset`; may instead be `opts.jwks`.
+---@param opts table `{ algorithms = {...}, leeway?, iss?, aud? }` — the allow-list is required; `iss`/`aud` take a string or a list.
 ---@return table|nil _ The claims.
 ---@return string|nil _ The rejection reason.
 function nitr.crypto.jwt.verify(token, key, opts) end
 
----`Authorization` header parsing. (std feature: `crypto`)
+---One-time passwords for two-factor login: TOTP (RFC 6238) and HOTP (RFC 4226) from a base32 secret, compared in constant time. Code options: `algorithm` (`"SHA1"` by default, `"SHA256"`, `"SHA512"`), `digits` (6 to 8, default 6), `period` (seconds, default 30). Codes are single-use only if the caller keeps `verify`'s step, e.g. with `nitr.kv:cas`. (std feature: `crypto`)
+nitr.crypto.otp = {}
+
+---A new random shared secret.
+---@param bytes? integer 16 to 64, default 20.
+---@return string _ Base32.
+function nitr.crypto.otp.secret(bytes) end
+
+---The `otpauth://` URI to show as a QR code for enrolment.
+---@param secret string
+---@param opts table `{ issuer, account }` plus code options; a `counter` makes an HOTP URI.
+---@return string
+function nitr.crypto.otp.uri(secret, opts) end
+
+---The current TOTP code.
+---@param secret string
+---@param opts? table Code options; `time` (Unix seconds) instead of now.
+---@return string
+function nitr.crypto.otp.totp(secret, opts) end
+
+---The HOTP code for a counter.
+---@param secret string
+---@param counter integer
+---@param opts? table
+---@return string
+function nitr.crypto.otp.hotp(secret, counter, opts) end
+
+---Checks a code.
+---@param secret string
+---@param code string
+---@param opts? table Code options; `window` (steps either side of now, default 1, at most 10); `last_step`, the step of the previous accepted code; `time`; or `counter` to check HOTP codes from there up to `window` ahead.
+---@return integer|nil _ The matched TOTP step (or HOTP counter): store it.
+---@return string|nil _ `invalid code` or `code already used`.
+function nitr.crypto.otp.verify(secret, code, opts) end
+
+---New recovery codes.
+---@param n? integer 1 to 32, default 10.
+---@return string[] _ The codes, as `xxxxx-xxxxx`: show them once.
+---@return string[] _ Their argon2id hashes, to store.
+function nitr.crypto.otp.recovery_codes(n) end
+
+---Checks a recovery code against the stored hashes.
+---@param code string As typed: case, spaces and dashes are ignored.
+---@param hashes string[] At most 32.
+---@return integer|nil _ The index of the matching hash, which the caller deletes.
+function nitr.crypto.otp.verify_recovery(code, hashes) end
+
+---`Authorization` header parsing, and the bearer-token middleware. (std feature: `crypto`)
 nitr.auth = {}
 
 ---Basic credentials, or nil.
@@ -467,7 +737,12 @@ function nitr.auth.basic(req) end
 ---@return string|nil
 function nitr.auth.bearer(req) end
 
----The bounded TTL+LRU cache shared by every state. Entries are plain data; per-process, so not a session store. (std feature: `cache`)
+---Bearer-token middleware: verifies the JWT in Rust, answers RFC 6750 `401`/`400`/`403` challenges, and sets `req.ctx.claims`. A route's options `{ scopes = ... }` add required scopes, checked against the token's `scope`/`scp` claim.
+---@param opts table `{ jwks = keyset | key = secret, algorithms, iss?, aud?, leeway?, realm?, optional?, scopes? }`
+---@return fun(next: fun, route: table): fun(req: nitr.Request): any
+function nitr.auth.jwt(opts) end
+
+---The bounded TTL+LRU cache shared by every state. Entries are plain data; per-process, so a restart empties it unless `[cache] persist` snapshots it to disk. With `[cache] backend = "redis"` the same API runs against a Redis server every process shares. (std feature: `cache`)
 nitr.cache = {}
 
 ---The cached value, or nil.
@@ -475,30 +750,139 @@ nitr.cache = {}
 ---@return any
 function nitr.cache:get(key) end
 
----Stores a value (ttl in seconds).
+---Stores a value.
+---@param key string
+---@param value any
+---@param opts? table `{ ttl?, tags? }`: ttl in seconds; up to 32 tags for `invalidate_tag`.
+function nitr.cache:set(key, value, opts) end
+
+---Stores a value only if the key is absent: one state wins.
 ---@param key string
 ---@param value any
----@param ttl? integer
-function nitr.cache:set(key, value, ttl) end
+---@param opts? table `{ ttl?, tags? }`
+---@return boolean _ Whether it stored.
+function nitr.cache:add(key, value, opts) end
+
+---Atomically adds to an integer, counting a missing key from 0; an existing key keeps its expiry.
+---@param key string
+---@param by? integer Default 1.
+---@param opts? table `{ ttl? }`, applied only when the key is created.
+---@return integer
+function nitr.cache:incr(key, by, opts) end
+
+---Atomic compare-and-swap; values compare as data.
+---@param key string
+---@param expected any nil for "absent".
+---@param new any nil deletes.
+---@param opts? table `{ ttl?, tags? }`
+---@return boolean _ Whether it swapped.
+function nitr.cache:cas(key, expected, new, opts) end
 
 ---Removes a key.
 ---@param key string
+---@return boolean _ Whether the key existed.
 function nitr.cache:delete(key) end
 
+---Drops every entry stored with the tag.
+---@param tag string
+---@return integer _ How many entries went.
+function nitr.cache:invalidate_tag(tag) end
+
 ---Empties the cache.
 function nitr.cache:clear() end
 
----The cached value, or `fn()`'s result, stored and returned.
+---The cached value, or `fn()`'s result, stored and returned. Concurrent misses for one key run `fn` once; the other callers wait for its result.
 ---@param key string
----@param ttl integer|nil
+---@param opts? table `{ ttl?, stale?, tags? }`: for `stale` seconds past its ttl the old value is served while one caller refreshes it (or if the refresh fails).
 ---@param fn fun(): any
 ---@return any
-function nitr.cache:remember(key, ttl, fn) end
+function nitr.cache:remember(key, opts, fn) end
 
----Hit/miss/entry counters.
+---`backend` (`"memory"` or `"redis"`), hit/miss counters and `coalesced`: `remember` calls answered by another caller's computation. In memory also entries, bytes and evictions; with Redis, `fallbacks`: calls answered without the server.
 ---@return table
 function nitr.cache:stats() end
 
+---A durable key-value store in a Nitr-owned SQLite table (`[kv] path`, else the `[database]` file), shared by every state and surviving restarts. Values are plain data; `ttl` is in seconds and expired keys read as absent. (std feature: `kv`)
+nitr.kv = {}
+
+---The stored value, or nil.
+---@param key string
+---@return any
+function nitr.kv:get(key) end
+
+---Stores a value.
+---@param key string
+---@param value any
+---@param opts? table `{ ttl? }`; without one the key never expires.
+function nitr.kv:set(key, value, opts) end
+
+---Removes a key.
+---@param key string
+---@return boolean _ Whether the key existed.
+function nitr.kv:delete(key) end
+
+---Atomically adds to an integer, counting a missing key from 0; an existing key keeps its expiry.
+---@param key string
+---@param by? integer Default 1.
+---@param opts? table `{ ttl? }`, applied only when the key is created.
+---@return integer
+function nitr.kv:incr(key, by, opts) end
+
+---Atomic compare-and-swap.
+---@param key string
+---@param expected any nil for "absent".
+---@param new any nil deletes.
+---@param opts? table `{ ttl? }`
+---@return boolean _ Whether it swapped.
+function nitr.kv:cas(key, expected, new, opts) end
+
+---Resets a key's expiry.
+---@param key string
+---@param ttl? number nil makes the key permanent.
+---@return boolean _ Whether the key exists.
+function nitr.kv:expire(key, ttl) end
+
+---Lists live keys by prefix.
+---@param prefix string
+---@param opts? table `{ limit?, after? }`: limit defaults to 100 (at most 1000); `after` is the last key of the previous page.
+---@return table _ `{ { key, value }, ... }` in key order.
+function nitr.kv:scan(prefix, opts) end
+
+---A durable background job queue in a Nitr-owned SQLite table (`[jobs] path`, else the `[database]` file). The server runs due jobs with the `app:job` handlers on at most `[jobs] workers` states; a failed attempt is retried with doubling backoff, and a job out of attempts is kept as dead for `nitr jobs retry`. (std feature: `jobs`)
+nitr.jobs = {}
+
+---Queues a job and returns at once.
+---@param name string
+---@param payload any Plain data, up to 1 MiB of JSON.
+---@param opts? table `{ delay?, unique?, max_attempts? }`: delay in seconds; while a job with the same `unique` key is queued or running, no second one is added.
+---@return integer _ The job id.
+---@return boolean _ Whether it was added, false when a unique key matched.
+function nitr.jobs.enqueue(name, payload, opts) end
+
+---Removes a job that has not started.
+---@param id integer
+---@return boolean _ Whether a queued job was removed.
+function nitr.jobs.cancel(id) end
+
+---An in-process publish/subscribe hub shared by every state. Topics are dot-separated (`chat.room42`); patterns use `*` for one segment and a trailing `**` for one or more. Payloads are plain data, JSON-serialized. Each subscriber has a bounded buffer (`[pubsub] buffer`); a slow one misses messages or is disconnected (`on_full`). Not shared between processes. (std feature: `pubsub`)
+nitr.pubsub = {}
+
+---Publishes a message; never waits on a subscriber.
+---@param topic string
+---@param data any
+---@return integer _ How many subscribers it was queued for.
+function nitr.pubsub.publish(topic, data) end
+
+---Subscribes from this moment on.
+---@param topics string|string[] One pattern or a list.
+---@param opts? table `{ on_full? }`: `"drop"` or `"disconnect"`, defaulting to `[pubsub] on_full`.
+---@return nitr.Subscription
+function nitr.pubsub.subscribe(topics, opts) end
+
+---`subscribers`, `published`, `delivered`, `dropped` and `disconnected` counters.
+---@return table
+function nitr.pubsub.stats() end
+
 ---Safe clocks and time formatting (UTC), so scripts never need the `os` library for a date. (std feature: `time`)
 nitr.time = {}
 
@@ -683,6 +1067,30 @@ function nitr.test.before_each(fn) end
 ---@param fn fun()
 function nitr.test.after_each(fn) end
 
+---Webhook signatures. `verify` reads the raw body once, in Rust, and checks its HMAC-SHA256 in constant time under the provider's scheme; schemes that sign a timestamp (all but GitHub) refuse one outside the tolerance. `sign` makes Standard Webhooks headers for outbound deliveries. (std feature: `http`)
+nitr.webhook = {}
+
+---Verifies an inbound delivery. The body is consumed: decode the returned string, not `req:json()`.
+---@param req nitr.Request
+---@param opts table `{ scheme, secret, tolerance? }`: `scheme` is `"stripe"`, `"github"`, `"slack"` or `"standard-webhooks"`; `secret` a string or a list (any may match, for rotation); `tolerance` in seconds, default 300.
+---@return string|nil _ The verified raw body.
+---@return string|nil _ The rejection reason.
+function nitr.webhook.verify(req, opts) end
+
+---Signs an outbound Standard Webhooks message.
+---@param payload string|table A table is JSON-encoded.
+---@param opts table `{ secret, id?, timestamp? }`: a `whsec_` secret; `id` defaults to a random `msg_` id, `timestamp` to now.
+---@return table _ The `webhook-id`, `webhook-timestamp` and `webhook-signature` headers.
+---@return string _ The body they sign.
+function nitr.webhook.sign(payload, opts) end
+
+---A new random `whsec_` secret to share with a subscriber.
+---@return string
+function nitr.webhook.secret() end
+
+---The `nitr.KeyRing` loaded from `[keys]` (a `dir` of `<id>.key` files, or an `env` variable of `id:key` pairs), shared by every state; absent without `[keys]`. `nitr keys rotate` writes the next key, and a reload picks it up.
+nitr.keys = {}
+
 ---The configuration snapshot returned by `config.lua` (nil without one). (set when a `config_script` is configured)
 nitr.cfg = {}
 